@@ -1,15 +1,29 @@
 use crate::{
-    admin::liveready::{
-        accept_health_request,
-        accept_readiness_request,
-        LiveReadyRequestSnd,
+    admin::{
+        dashboard::{
+            accept_dashboard_request,
+            AdminBody,
+            DASHBOARD_PATH,
+        },
+        liveready::{
+            accept_health_request,
+            accept_readiness_request,
+            LiveReadyRequestSnd,
+        },
+        upstreams::{
+            accept_upstreams_request,
+            UPSTREAMS_PATH,
+        },
     },
     database::types::{
         GenericBytes,
         RequestBus,
     },
 };
-use http_body_util::Full;
+use http_body_util::{
+    BodyExt,
+    Full,
+};
 use hyper::{
     body::Bytes,
     Request,
@@ -41,7 +55,18 @@ use std::{
 };
 
 use crate::{
-    admin::methods::execute_method,
+    admin::{
+        auth::{
+            bearer_token,
+            token_role,
+        },
+        error::AdminError,
+        methods::{
+            execute_method,
+            BlutgangRpcMethod,
+        },
+        AdminState,
+    },
     balancer::format::incoming_to_value,
     Rpc,
     Settings,
@@ -68,6 +93,7 @@ macro_rules! get_response {
         $rpc_list_rwlock:expr,
         $poverty_list_rwlock:expr,
         $config:expr,
+        $state:expr,
         $cache:expr,
     ) => {{
         // Execute the request and store it into rx
@@ -76,6 +102,7 @@ macro_rules! get_response {
             $rpc_list_rwlock,
             $poverty_list_rwlock,
             Arc::clone(&$config),
+            $state,
             $cache.clone(),
         ).await {
             Ok(rx) => rx,
@@ -102,9 +129,10 @@ async fn forward_body<K, V>(
     poverty_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
     cache: RequestBus<K, V>,
     config: Arc<RwLock<Settings>>,
+    state: &AdminState,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible>
 where
-    K: GenericBytes,
+    K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes,
 {
     // Get the id of the request and set it to 0 for caching
@@ -115,7 +143,15 @@ where
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(tx, id, rpc_list_rwlock, poverty_list_rwlock, config, cache,);
+    let rax = get_response!(
+        tx,
+        id,
+        rpc_list_rwlock,
+        poverty_list_rwlock,
+        config,
+        state,
+        cache,
+    );
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
@@ -136,19 +172,70 @@ pub async fn accept_admin_request<K, V>(
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: RequestBus<K, V>,
     config: Arc<RwLock<Settings>>,
+    state: AdminState,
     liveness_request_tx: LiveReadyRequestSnd,
-) -> Result<hyper::Response<Full<Bytes>>, Infallible>
+) -> Result<hyper::Response<AdminBody>, Infallible>
 where
-    K: GenericBytes,
+    K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes,
 {
-    if tx.uri().path() == "/ready" {
-        return accept_readiness_request(liveness_request_tx).await;
+    let response = if tx.uri().path() == "/ready" {
+        accept_readiness_request(liveness_request_tx, &rpc_list_rwlock).await
     } else if tx.uri().path() == "/health" {
-        return accept_health_request(liveness_request_tx).await;
-    }
+        accept_health_request(liveness_request_tx, &rpc_list_rwlock, &poverty_list_rwlock).await
+    } else if tx.uri().path().starts_with(UPSTREAMS_PATH) {
+        accept_upstreams_request(tx, &rpc_list_rwlock, &poverty_list_rwlock, &config, &state).await
+    } else if tx.uri().path().starts_with(DASHBOARD_PATH) {
+        return Ok(accept_dashboard_request(
+            &tx,
+            &rpc_list_rwlock,
+            &poverty_list_rwlock,
+            &config,
+            &state,
+        ));
+    } else {
+        accept_rpc_request(
+            tx,
+            &rpc_list_rwlock,
+            &poverty_list_rwlock,
+            cache,
+            config,
+            &state,
+        )
+        .await
+    };
+
+    response.map(|response| response.map(BodyExt::boxed_unsync))
+}
+
+/// Accept a JSON-RPC request to the admin namespace
+async fn accept_rpc_request<K, V>(
+    tx: Request<hyper::body::Incoming>,
+    rpc_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list_rwlock: &Arc<RwLock<Vec<Rpc>>>,
+    cache: RequestBus<K, V>,
+    config: Arc<RwLock<Settings>>,
+    state: &AdminState,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes,
+{
+    let (role, json_limits) = {
+        let config = config.read().unwrap();
+        (
+            token_role(bearer_token(tx.headers()), &config),
+            config.json_limits,
+        )
+    };
+    let Some(role) = role else {
+        return Ok(hyper::Response::builder()
+            .status(401)
+            .body(Full::new(Bytes::from("Unauthorized or invalid token")))
+            .unwrap());
+    };
 
-    let mut tx = match incoming_to_value(tx).await {
+    let mut tx = match incoming_to_value(tx, &json_limits).await {
         Ok(res) => res,
         Err(err) => {
             tracing::error!(?err, "Admin request malformed");
@@ -190,9 +277,28 @@ where
         });
     }
 
+    let is_write =
+        BlutgangRpcMethod::try_from(tx["method"].as_str()).is_ok_and(|method| method.is_write());
+    if is_write && !role.can_write() {
+        return Ok(hyper::Response::builder()
+            .status(403)
+            .body(Full::new(Bytes::from(
+                AdminError::InsufficientRole.to_string(),
+            )))
+            .unwrap());
+    }
+
     // Send the request off to be processed
     let time = Instant::now();
-    let response = forward_body(tx, &rpc_list_rwlock, &poverty_list_rwlock, cache, config).await;
+    let response = forward_body(
+        tx,
+        rpc_list_rwlock,
+        poverty_list_rwlock,
+        cache,
+        config,
+        state,
+    )
+    .await;
     let time = time.elapsed();
     tracing::info!(?time, "Request time");
 
@@ -202,7 +308,10 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::admin::methods::BlutgangRpcMethod;
+    use crate::database::{
+        eviction::CacheEviction,
+        hot_cache::HotCache,
+    };
     use crate::database_processing;
     use jsonwebtoken::DecodingKey;
     use sled::Config;
@@ -222,7 +331,13 @@ mod tests {
         let cache = Config::tmp().unwrap();
         let cache = Db::open_with_config(&cache).unwrap();
         let (db_tx, db_rx) = mpsc::unbounded_channel();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
 
         db_tx
     }
@@ -250,6 +365,7 @@ mod tests {
             &poverty_list,
             cache.clone(),
             settings,
+            &AdminState::default(),
         )
         .await;
 
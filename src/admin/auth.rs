@@ -0,0 +1,161 @@
+//! Authentication of admin requests.
+//!
+//! With `tokens` set, every admin request except `/ready`, `/health` and the
+//! dashboard page needs an `Authorization: Bearer <token>` header with one of
+//! them. Each token has a role:
+//!
+//! - `read` can call methods and endpoints that only report on Blutgang,
+//! - `write` can also change settings, the cache and the RPC lists.
+//!
+//! Tokens are only kept as hashes, which are compared in constant time against
+//! every configured token, so neither the comparison nor the position of the
+//! token in the config leaks through timing.
+//!
+//! Without `tokens`, every request gets the `write` role. `readonly` applies on
+//! top of either. With `jwt` enabled, requests also need a JWT: JSON-RPC ones
+//! carry it in their body, so they need both a token and a JWT if both are
+//! set. The other endpoints carry their JWT in the `Authorization` header,
+//! where a token takes its place instead.
+
+use crate::Settings;
+
+use std::fmt;
+
+use hyper::{
+    header,
+    HeaderMap,
+};
+use jsonwebtoken::{
+    decode,
+    Validation,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// What a token allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminRole {
+    Read,
+    Write,
+}
+
+impl AdminRole {
+    pub fn can_write(&self) -> bool {
+        *self == AdminRole::Write
+    }
+}
+
+/// A bearer token from the config.
+#[derive(Clone)]
+pub struct AdminToken {
+    hash: blake3::Hash,
+    pub role: AdminRole,
+}
+
+impl AdminToken {
+    pub fn new(token: &str, role: AdminRole) -> Self {
+        Self {
+            hash: blake3::hash(token.as_bytes()),
+            role,
+        }
+    }
+}
+
+impl fmt::Debug for AdminToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AdminToken {{ token: HIDDEN, role: {:?} }}", self.role)
+    }
+}
+
+/// JWTs of HTTP endpoints only have to be valid, the route says what they're for.
+#[derive(Debug, Deserialize)]
+struct Claims {
+    #[allow(dead_code)]
+    exp: usize,
+}
+
+/// Returns the token in the `Authorization` header, if any.
+pub fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Returns the role `token` has among the configured tokens, or `write` if
+/// there are none. `None` if it's not one of them.
+pub fn token_role(token: Option<&str>, config: &Settings) -> Option<AdminRole> {
+    if config.admin.tokens.is_empty() {
+        return Some(AdminRole::Write);
+    }
+
+    let hash = blake3::hash(token?.as_bytes());
+    // No short-circuiting, every token gets compared
+    config.admin.tokens.iter().fold(None, |role, candidate| {
+        if candidate.hash == hash {
+            Some(candidate.role)
+        } else {
+            role
+        }
+    })
+}
+
+/// Returns true if `token` is a valid JWT.
+fn valid_jwt(token: &str, config: &Settings) -> bool {
+    match decode::<Claims>(token, &config.admin.key, &Validation::default()) {
+        Ok(_) => true,
+        Err(err) => {
+            tracing::error!(?err, "JWT Auth error");
+            false
+        }
+    }
+}
+
+/// Returns the role of a request to an HTTP endpoint presenting `token`, or
+/// `None` if it's not authorized.
+pub fn authorize(token: Option<&str>, config: &Settings) -> Option<AdminRole> {
+    if config.admin.tokens.is_empty() && config.admin.jwt {
+        return valid_jwt(token?, config).then_some(AdminRole::Write);
+    }
+    token_role(token, config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorize() {
+        let mut config = Settings::default();
+        assert_eq!(authorize(None, &config), Some(AdminRole::Write));
+
+        config.admin.jwt = true;
+        assert_eq!(authorize(None, &config), None);
+        assert_eq!(authorize(Some("nonsense"), &config), None);
+
+        // Tokens take the place of JWTs
+        config.admin.tokens = vec![
+            AdminToken::new("reader", AdminRole::Read),
+            AdminToken::new("writer", AdminRole::Write),
+        ];
+        assert_eq!(authorize(None, &config), None);
+        assert_eq!(authorize(Some("reader"), &config), Some(AdminRole::Read));
+        assert_eq!(authorize(Some("writer"), &config), Some(AdminRole::Write));
+        assert_eq!(authorize(Some("writer2"), &config), None);
+        assert_eq!(token_role(Some("reader"), &config), Some(AdminRole::Read));
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Basic abc".parse().unwrap());
+        assert_eq!(bearer_token(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer abc ".parse().unwrap());
+        assert_eq!(bearer_token(&headers), Some("abc"));
+    }
+}
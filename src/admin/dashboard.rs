@@ -0,0 +1,363 @@
+//! Live status dashboard for operators without a metrics stack.
+//!
+//! With `dashboard` enabled, the admin listener serves:
+//!
+//! - `GET /dashboard` - a single page showing the health, latency and share
+//!   of traffic of every RPC, the cache hit rate and recent errors,
+//! - `GET /dashboard/events` - the data behind it as server-sent events, one
+//!   `DashboardSnapshot` every `DASHBOARD_INTERVAL`.
+//!
+//! The page itself contains no data, so it's always served. With `jwt` or
+//! `tokens` enabled, the events need a token, either as an `Authorization: Bearer`
+//! header or a `token` query parameter, as browsers can't set headers on
+//! event streams. Open the page with `?token=<token>` and it passes it on.
+
+use crate::{
+    admin::{
+        auth::{
+            authorize,
+            bearer_token,
+        },
+        AdminState,
+    },
+    balancer::cache_metrics::lookup_counts,
+    database::expiry::now_ms,
+    health::events::RpcState,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    convert::Infallible,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::stream;
+use http_body_util::{
+    combinators::UnsyncBoxBody,
+    BodyExt,
+    Full,
+    StreamBody,
+};
+use hyper::{
+    body::{
+        Bytes,
+        Frame,
+    },
+    header,
+    Request,
+    Response,
+    StatusCode,
+};
+use serde::Serialize;
+use tokio::time::sleep;
+
+/// Path the page is served at.
+pub const DASHBOARD_PATH: &str = "/dashboard";
+/// Path the events are served at.
+const EVENTS_PATH: &str = "/dashboard/events";
+
+/// Time between two events.
+const DASHBOARD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Max number of recent errors shown.
+const RECENT_ERRORS: usize = 20;
+
+const PAGE: &str = include_str!("dashboard.html");
+
+/// Body of admin responses, which can be streamed.
+pub type AdminBody = UnsyncBoxBody<Bytes, Infallible>;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DashboardRpc {
+    pub name: String,
+    /// `active` or `poverty`
+    pub pool: &'static str,
+    pub state: RpcState,
+    pub drained: bool,
+    pub banned: bool,
+    pub latency_ms: f64,
+    pub in_flight: u64,
+    pub calls: u64,
+    pub errors: u64,
+    /// Fraction of all calls sent to RPCs that went to this one
+    pub share: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct CacheSummary {
+    pub hits: u64,
+    pub misses: u64,
+    /// `None` until something got looked up
+    pub hit_rate: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecentError {
+    pub at_ms: u64,
+    pub rpc_name: String,
+    pub message: String,
+}
+
+/// Everything the dashboard shows at some point in time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DashboardSnapshot {
+    pub timestamp_ms: u64,
+    pub rpcs: Vec<DashboardRpc>,
+    pub cache: CacheSummary,
+    /// Newest first
+    pub recent_errors: Vec<RecentError>,
+}
+
+fn dashboard_rpc(rpc: &Rpc, pool: &'static str) -> DashboardRpc {
+    let stats = rpc.stats.snapshot();
+    let (calls, errors) = stats
+        .methods
+        .values()
+        .fold((0, 0), |(calls, errors), counts| {
+            (calls + counts.success + counts.error, errors + counts.error)
+        });
+
+    let state = match pool {
+        "poverty" => RpcState::Ejected,
+        _ if rpc.status.is_erroring => RpcState::Erroring,
+        _ => RpcState::Healthy,
+    };
+
+    DashboardRpc {
+        name: rpc.name.clone(),
+        pool,
+        state,
+        drained: rpc.status.drained,
+        banned: rpc.status.is_banned(),
+        latency_ms: rpc.status.latency / 1_000_000.0,
+        in_flight: stats.in_flight,
+        calls,
+        errors,
+        share: 0.0,
+    }
+}
+
+/// Collect what the dashboard shows right now.
+pub fn snapshot(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    state: &AdminState,
+) -> DashboardSnapshot {
+    let mut rpcs = Vec::new();
+    let mut recent_errors = Vec::new();
+    for (pool, list) in [("active", rpc_list), ("poverty", poverty_list)] {
+        for rpc in list.read().unwrap_or_else(|e| e.into_inner()).iter() {
+            rpcs.push(dashboard_rpc(rpc, pool));
+            if let Some(error) = rpc.stats.snapshot().last_error {
+                recent_errors.push(RecentError {
+                    at_ms: error.at_ms,
+                    rpc_name: rpc.name.clone(),
+                    message: error.message,
+                });
+            }
+        }
+    }
+
+    let total: u64 = rpcs.iter().map(|rpc| rpc.calls).sum();
+    if total > 0 {
+        for rpc in &mut rpcs {
+            rpc.share = rpc.calls as f64 / total as f64;
+        }
+    }
+
+    recent_errors.extend(
+        state
+            .health_events
+            .events(None)
+            .into_iter()
+            .filter(|event| event.to != RpcState::Healthy)
+            .map(|event| {
+                RecentError {
+                    at_ms: event.timestamp_ms,
+                    rpc_name: event.rpc_name,
+                    message: format!("{}: {}", event.to.as_str(), event.reason),
+                }
+            }),
+    );
+    recent_errors.extend(state.health_events.rejected().into_iter().map(|rejected| {
+        RecentError {
+            at_ms: rejected.timestamp_ms,
+            rpc_name: "admin".to_string(),
+            message: format!("{} rolled back: {}", rejected.change, rejected.reason),
+        }
+    }));
+    recent_errors.sort_by(|a, b| b.at_ms.cmp(&a.at_ms));
+    recent_errors.truncate(RECENT_ERRORS);
+
+    let (hits, misses) = lookup_counts();
+    let lookups = hits + misses;
+
+    DashboardSnapshot {
+        timestamp_ms: now_ms(),
+        rpcs,
+        cache: CacheSummary {
+            hits,
+            misses,
+            hit_rate: (lookups > 0).then(|| hits as f64 / lookups as f64),
+        },
+        recent_errors,
+    }
+}
+
+fn respond(status: StatusCode, content_type: &str, body: &'static str) -> Response<AdminBody> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Full::new(Bytes::from_static(body.as_bytes())).boxed_unsync())
+        .unwrap()
+}
+
+/// Returns the token in the `Authorization` header, or the `token` query parameter.
+fn request_token<B>(tx: &Request<B>) -> Option<&str> {
+    bearer_token(tx.headers()).or_else(|| {
+        tx.uri()
+            .query()?
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("token="))
+    })
+}
+
+/// Stream a snapshot every `DASHBOARD_INTERVAL` until the client goes away.
+fn events(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    state: AdminState,
+) -> AdminBody {
+    let events = stream::unfold(true, move |first| {
+        let rpc_list = Arc::clone(&rpc_list);
+        let poverty_list = Arc::clone(&poverty_list);
+        let state = state.clone();
+        async move {
+            if !first {
+                sleep(DASHBOARD_INTERVAL).await;
+            }
+            let snapshot = snapshot(&rpc_list, &poverty_list, &state);
+            let event = format!(
+                "data: {}\n\n",
+                serde_json::to_string(&snapshot).unwrap_or_default()
+            );
+            Some((Ok::<_, Infallible>(Frame::data(Bytes::from(event))), false))
+        }
+    });
+
+    StreamBody::new(events).boxed_unsync()
+}
+
+/// Accept a request to `DASHBOARD_PATH` or below.
+pub fn accept_dashboard_request<B>(
+    tx: &Request<B>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+) -> Response<AdminBody> {
+    if !config.read().unwrap().admin.dashboard {
+        return respond(StatusCode::NOT_FOUND, "text/plain", "Dashboard disabled");
+    }
+
+    match tx.uri().path().trim_end_matches('/') {
+        DASHBOARD_PATH => respond(StatusCode::OK, "text/html; charset=utf-8", PAGE),
+        EVENTS_PATH => {
+            if authorize(request_token(tx), &config.read().unwrap()).is_none() {
+                return respond(
+                    StatusCode::UNAUTHORIZED,
+                    "text/plain",
+                    "Unauthorized or invalid token",
+                );
+            }
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(header::CONTENT_TYPE, "text/event-stream")
+                .header(header::CACHE_CONTROL, "no-cache")
+                .body(events(
+                    Arc::clone(rpc_list),
+                    Arc::clone(poverty_list),
+                    state.clone(),
+                ))
+                .unwrap()
+        }
+        _ => respond(StatusCode::NOT_FOUND, "text/plain", "Not found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::events::HealthEvents;
+
+    fn rpc(url: &str) -> Rpc {
+        Rpc::new(url.parse().unwrap(), None, 5, 1000, 10.0)
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let active = rpc("http://a.example");
+        let ejected = rpc("http://b.example");
+        let ok: Result<String, String> = Ok(r#"{"result":"0x1"}"#.into());
+        let failed: Result<String, String> = Err("connection refused".into());
+        for _ in 0..3 {
+            active
+                .stats
+                .record("eth_blockNumber", Duration::from_millis(1), &ok);
+        }
+        ejected
+            .stats
+            .record("eth_call", Duration::from_millis(1), &failed);
+
+        let rpc_list = Arc::new(RwLock::new(vec![active]));
+        let poverty_list = Arc::new(RwLock::new(vec![ejected]));
+        let state = AdminState {
+            health_events: Arc::new(HealthEvents::new(16)),
+            ..Default::default()
+        };
+        let snapshot = snapshot(&rpc_list, &poverty_list, &state);
+
+        assert_eq!(snapshot.rpcs.len(), 2);
+        assert_eq!(snapshot.rpcs[0].state, RpcState::Healthy);
+        assert_eq!(snapshot.rpcs[0].calls, 3);
+        assert_eq!(snapshot.rpcs[0].share, 0.75);
+        assert_eq!(snapshot.rpcs[1].state, RpcState::Ejected);
+        assert_eq!(snapshot.rpcs[1].errors, 1);
+        assert_eq!(snapshot.recent_errors.len(), 1);
+        assert_eq!(snapshot.recent_errors[0].message, "connection refused");
+
+        // Rejected admin changes show up with their reason
+        state
+            .health_events
+            .reject("blutgang_set_ttl", "ttl must be greater than 0", now_ms());
+        let snapshot = snapshot(&rpc_list, &poverty_list, &state);
+        assert_eq!(snapshot.recent_errors.len(), 2);
+        assert!(snapshot.recent_errors.iter().any(|error| {
+            error.rpc_name == "admin"
+                && error.message == "blutgang_set_ttl rolled back: ttl must be greater than 0"
+        }));
+    }
+
+    #[test]
+    fn test_request_token() {
+        let tx = Request::get("/dashboard/events?a=b&token=abc")
+            .body(())
+            .unwrap();
+        assert_eq!(request_token(&tx), Some("abc"));
+
+        let tx = Request::get("/dashboard/events?token=abc")
+            .header(header::AUTHORIZATION, "Bearer def")
+            .body(())
+            .unwrap();
+        assert_eq!(request_token(&tx), Some("def"));
+
+        let tx = Request::get("/dashboard/events").body(()).unwrap();
+        assert_eq!(request_token(&tx), None);
+    }
+}
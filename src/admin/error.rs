@@ -12,8 +12,22 @@ pub enum AdminError {
     ParseError,
     #[error("Admin namespace is set to read-only")]
     WriteProtectionEnabled,
+    #[error("Token is not allowed to make changes")]
+    InsufficientRole,
     #[error("Could not access shared resource")]
     Inaccessible,
     #[error("Request out of bounds")]
     OutOfBounds,
+    #[error("RPC is on the blocklist")]
+    Blocklisted,
+    #[error("RPC is already in the active pool")]
+    DuplicateRpc,
+    #[error("Change failed validation and was rolled back: {0}")]
+    ValidationFailed(String),
+    #[error("Cache index is disabled, set `cache_index_size` to purge by method or block")]
+    CacheIndexDisabled,
+    #[error("Cache snapshots are disabled, set `snapshot_dir` to enable them")]
+    SnapshotsDisabled,
+    #[error("Cache snapshot failed, check the logs for why")]
+    SnapshotFailed,
 }
@@ -1,5 +1,6 @@
 use std::{
     net::SocketAddr,
+    path::PathBuf,
     sync::{
         Arc,
         RwLock,
@@ -14,6 +15,7 @@ use crate::{
             LiveReadyRequestSnd,
             LiveReadyUpdateRecv,
         },
+        AdminState,
     },
     database::types::{
         GenericBytes,
@@ -40,6 +42,7 @@ macro_rules! accept_admin {
         $poverty_list_rwlock:expr,
         $cache:expr,
         $config:expr,
+        $state:expr,
         $liveness_request_tx:expr,
     ) => {
         // Bind the incoming connection to our service
@@ -54,6 +57,7 @@ macro_rules! accept_admin {
                         Arc::clone($poverty_list_rwlock),
                         $cache.clone(),
                         Arc::clone($config),
+                        $state.clone(),
                         $liveness_request_tx.clone(),
                     );
                     response
@@ -71,11 +75,12 @@ async fn admin_api_server<K, V>(
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: RequestBus<K, V>,
     config: Arc<RwLock<Settings>>,
+    state: AdminState,
     address: SocketAddr,
     liveness_request_tx: LiveReadyRequestSnd,
-) -> Result<(), Box<dyn std::error::Error>>
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
 where
-    K: GenericBytes + 'static,
+    K: GenericBytes + From<[u8; 32]> + 'static,
     V: GenericBytes + 'static,
 {
     // Create a listener and bind to it
@@ -94,6 +99,7 @@ where
         let poverty_list_rwlock_clone = Arc::clone(&poverty_list_rwlock);
         let cache_clone = cache.clone();
         let config_clone = Arc::clone(&config);
+        let state_clone = state.clone();
         let liveness_request_tx_clone = liveness_request_tx.clone();
 
         // Spawn a tokio task to serve multiple connections concurrently
@@ -104,12 +110,95 @@ where
                 &poverty_list_rwlock_clone,
                 &cache_clone,
                 &config_clone,
+                &state_clone,
                 &liveness_request_tx_clone,
             );
         });
     }
 }
 
+/// Serve the admin API on the unix socket at `path`, created with `mode` permissions.
+///
+/// Replaces a socket left behind at `path` by a previous run.
+#[cfg(unix)]
+#[allow(clippy::too_many_arguments)]
+async fn admin_socket_server<K, V>(
+    rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    cache: RequestBus<K, V>,
+    config: Arc<RwLock<Settings>>,
+    state: AdminState,
+    path: PathBuf,
+    mode: u32,
+    liveness_request_tx: LiveReadyRequestSnd,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    K: GenericBytes + From<[u8; 32]> + 'static,
+    V: GenericBytes + 'static,
+{
+    use std::os::unix::fs::{
+        FileTypeExt,
+        PermissionsExt,
+    };
+    use tokio::net::UnixListener;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!("{} exists and is not a socket", path.display()).into());
+        }
+        std::fs::remove_file(&path)?;
+    }
+
+    let listener = UnixListener::bind(&path)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+    tracing::info!("Bound admin API to socket: {}", path.display());
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        tracing::info!("Admin connection on socket: {}", path.display());
+
+        let io = TokioIo::new(stream);
+
+        let rpc_list_rwlock_clone = Arc::clone(&rpc_list_rwlock);
+        let poverty_list_rwlock_clone = Arc::clone(&poverty_list_rwlock);
+        let cache_clone = cache.clone();
+        let config_clone = Arc::clone(&config);
+        let state_clone = state.clone();
+        let liveness_request_tx_clone = liveness_request_tx.clone();
+
+        tokio::task::spawn(async move {
+            accept_admin!(
+                io,
+                &rpc_list_rwlock_clone,
+                &poverty_list_rwlock_clone,
+                &cache_clone,
+                &config_clone,
+                &state_clone,
+                &liveness_request_tx_clone,
+            );
+        });
+    }
+}
+
+#[cfg(not(unix))]
+#[allow(clippy::too_many_arguments)]
+async fn admin_socket_server<K, V>(
+    _rpc_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    _poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
+    _cache: RequestBus<K, V>,
+    _config: Arc<RwLock<Settings>>,
+    _state: AdminState,
+    _path: PathBuf,
+    _mode: u32,
+    _liveness_request_tx: LiveReadyRequestSnd,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    K: GenericBytes + From<[u8; 32]> + 'static,
+    V: GenericBytes + 'static,
+{
+    Err("admin sockets are only supported on unix".into())
+}
+
 /// Used for listening to admin requests as its own tokio task.
 /// Also used for k8s liveness/readiness probes.
 ///
@@ -119,29 +208,114 @@ pub async fn listen_for_admin_requests<K, V>(
     poverty_list_rwlock: Arc<RwLock<Vec<Rpc>>>,
     cache: RequestBus<K, V>,
     config: Arc<RwLock<Settings>>,
+    state: AdminState,
     liveness_receiver: LiveReadyUpdateRecv,
-) -> Result<(), Box<dyn std::error::Error>>
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
 where
-    K: GenericBytes + 'static,
+    K: GenericBytes + From<[u8; 32]> + 'static,
     V: GenericBytes + 'static,
 {
-    let address;
-    {
+    let (tcp, address, socket, socket_mode) = {
         let config_guard = config.read().unwrap();
-        address = config_guard.admin.address;
+        (
+            config_guard.admin.tcp,
+            config_guard.admin.address,
+            config_guard.admin.socket.clone(),
+            config_guard.admin.socket_mode,
+        )
+    };
+    if !tcp && socket.is_none() {
+        return Err("admin API has neither TCP nor a socket to listen on".into());
     }
 
     // Spawn thread for monitoring the current liveness status of Blutgang
     let (liveness_request_tx, liveness_request_rx) = mpsc::channel(16);
     tokio::spawn(liveness_monitor(liveness_receiver, liveness_request_rx));
 
-    admin_api_server(
-        rpc_list_rwlock,
-        poverty_list_rwlock,
-        cache,
-        config,
-        address,
-        liveness_request_tx,
-    )
-    .await
+    let tcp_server = async {
+        if !tcp {
+            return Ok(());
+        }
+        admin_api_server(
+            Arc::clone(&rpc_list_rwlock),
+            Arc::clone(&poverty_list_rwlock),
+            cache.clone(),
+            Arc::clone(&config),
+            state.clone(),
+            address,
+            liveness_request_tx.clone(),
+        )
+        .await
+    };
+    let socket_server = async {
+        let Some(path) = socket else {
+            return Ok(());
+        };
+        admin_socket_server(
+            Arc::clone(&rpc_list_rwlock),
+            Arc::clone(&poverty_list_rwlock),
+            cache.clone(),
+            Arc::clone(&config),
+            state.clone(),
+            path,
+            socket_mode,
+            liveness_request_tx.clone(),
+        )
+        .await
+    };
+
+    tokio::try_join!(tcp_server, socket_server).map(|_| ())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::UnixStream,
+    };
+
+    #[tokio::test]
+    async fn test_admin_socket_server() {
+        let path = std::env::temp_dir().join(format!("blutgang-admin-{}.sock", std::process::id()));
+        // Left behind by a previous run
+        drop(std::os::unix::net::UnixListener::bind(&path));
+
+        let (cache, _cache_rx) = mpsc::unbounded_channel();
+        let (liveness_request_tx, _liveness_request_rx) = mpsc::channel(1);
+        let server = tokio::spawn(admin_socket_server::<Vec<u8>, Vec<u8>>(
+            Arc::new(RwLock::new(Vec::new())),
+            Arc::new(RwLock::new(Vec::new())),
+            cache,
+            Arc::new(RwLock::new(Settings::default())),
+            AdminState::default(),
+            path.clone(),
+            0o600,
+            liveness_request_tx,
+        ));
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        stream
+            .write_all(b"GET /dashboard HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        // Only accepting once permissions are set
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
 }
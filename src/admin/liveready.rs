@@ -6,7 +6,13 @@ use std::{
     },
 };
 
+use crate::Rpc;
+
 use http_body_util::Full;
+use serde_json::{
+    json,
+    Value,
+};
 
 use tokio::sync::{
     mpsc,
@@ -68,20 +74,21 @@ macro_rules! ok {
     };
 }
 
-macro_rules! partial_ok {
+macro_rules! nok {
     () => {
         Ok(hyper::Response::builder()
-            .status(202)
-            .body(Full::new(Bytes::from("RPC")))
+            .status(503)
+            .body(Full::new(Bytes::from("NOK")))
             .unwrap())
     };
 }
 
-macro_rules! nok {
-    () => {
+macro_rules! json_response {
+    ($status:expr, $body:expr) => {
         Ok(hyper::Response::builder()
-            .status(503)
-            .body(Full::new(Bytes::from("NOK")))
+            .status($status)
+            .header("content-type", "application/json")
+            .body(Full::new(Bytes::from($body.to_string())))
             .unwrap())
     };
 }
@@ -138,8 +145,60 @@ pub(in crate::r#admin) async fn liveness_monitor(
     liveness_request_processor(liveness_request_receiver, liveness_status).await;
 }
 
+/// Status of a single RPC as reported by `/health`
+fn rpc_status(rpc: &Rpc, healthy: bool) -> Value {
+    json!({
+        "name": rpc.name,
+        "healthy": healthy,
+        "latency_ms": rpc.status.latency / 1_000_000.0,
+        "head": rpc.status.head,
+        "is_erroring": rpc.status.is_erroring,
+        "is_syncing": rpc.status.is_syncing,
+        "is_timed_out": rpc.status.is_timed_out,
+        "consecutive_failures": rpc.status.failed_checks,
+        "modules": rpc.capabilities.modules,
+        "client_version": rpc.capabilities.client_version,
+    })
+}
+
+/// Status of blutgang and every RPC as reported by `/health`
+pub(super) fn health_status(
+    health: HealthState,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Value {
+    let status = match health {
+        HealthState::Healthy => "healthy",
+        HealthState::MissingRpcs => "missing_rpcs",
+        HealthState::Unhealthy => "unhealthy",
+    };
+
+    let mut rpcs: Vec<Value> = rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .map(|rpc| rpc_status(rpc, true))
+        .collect();
+    rpcs.extend(
+        poverty_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|rpc| rpc_status(rpc, false)),
+    );
+
+    json!({
+        "status": status,
+        "rpcs": rpcs,
+    })
+}
+
+/// Returns `200` once blutgang is done setting up and has at least one healthy RPC.
+///
+/// All RPCs serve the same requests, so the whole active pool counts as a single group.
 pub async fn accept_readiness_request(
     liveness_request_sender: LiveReadyRequestSnd,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
     let (tx, rx) = oneshot::channel();
 
@@ -152,15 +211,23 @@ pub async fn accept_readiness_request(
         }
     };
 
-    if rax.readiness == ReadinessState::Ready {
+    let has_healthy = !rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .is_empty();
+
+    if rax.readiness == ReadinessState::Ready && has_healthy {
         return ok!();
     }
 
     nok!()
 }
 
+/// Returns the health of blutgang, with the status of every RPC as JSON.
 pub async fn accept_health_request(
     liveness_request_sender: LiveReadyRequestSnd,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
     let (tx, rx) = oneshot::channel();
 
@@ -173,11 +240,13 @@ pub async fn accept_health_request(
         }
     };
 
-    match rax.health {
-        HealthState::Healthy => ok!(),
-        HealthState::MissingRpcs => partial_ok!(),
-        HealthState::Unhealthy => nok!(),
-    }
+    let status = match rax.health {
+        HealthState::Healthy => 200,
+        HealthState::MissingRpcs => 202,
+        HealthState::Unhealthy => 503,
+    };
+
+    json_response!(status, health_status(rax.health, rpc_list, poverty_list))
 }
 
 /// Sink used to immediately discard request in cases where admin is disabled
@@ -244,15 +313,27 @@ mod tests {
             request_recv,
             liveness_status.clone(),
         ));
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default()]));
 
-        let response = accept_readiness_request(request_snd.clone()).await.unwrap();
+        let response = accept_readiness_request(request_snd.clone(), &rpc_list)
+            .await
+            .unwrap();
         assert_eq!(response.status(), 200);
 
+        // Not ready without any healthy RPCs
+        let empty_list = Arc::new(RwLock::new(Vec::new()));
+        let response = accept_readiness_request(request_snd.clone(), &empty_list)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+
         // Testing with readiness set to Setup
         let (tx, _rx) = oneshot::channel();
         request_snd.send(tx).await.unwrap();
         liveness_status.write().unwrap().readiness = ReadinessState::Setup;
-        let response = accept_readiness_request(request_snd).await.unwrap();
+        let response = accept_readiness_request(request_snd, &rpc_list)
+            .await
+            .unwrap();
         assert_eq!(response.status(), 503);
     }
 
@@ -269,25 +350,60 @@ mod tests {
             liveness_status.clone(),
         ));
 
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default()]));
+        let poverty_list = Arc::new(RwLock::new(vec![Rpc::default()]));
+
         // Test with healthy state
-        let response = accept_health_request(request_snd.clone()).await.unwrap();
+        let response = accept_health_request(request_snd.clone(), &rpc_list, &poverty_list)
+            .await
+            .unwrap();
         assert_eq!(response.status(), 200);
 
         // Test with MissingRpcs state
         let (tx, _rx) = oneshot::channel();
         request_snd.send(tx).await.unwrap();
         liveness_status.write().unwrap().health = HealthState::MissingRpcs;
-        let response = accept_health_request(request_snd.clone()).await.unwrap();
+        let response = accept_health_request(request_snd.clone(), &rpc_list, &poverty_list)
+            .await
+            .unwrap();
         assert_eq!(response.status(), 202);
 
         // Test with Unhealthy state
         let (tx, _rx) = oneshot::channel();
         request_snd.send(tx).await.unwrap();
         liveness_status.write().unwrap().health = HealthState::Unhealthy;
-        let response = accept_health_request(request_snd).await.unwrap();
+        let response = accept_health_request(request_snd, &rpc_list, &poverty_list)
+            .await
+            .unwrap();
         assert_eq!(response.status(), 503);
     }
 
+    #[test]
+    fn test_health_status_json() {
+        let mut lagging = Rpc::default();
+        lagging.status.is_erroring = true;
+        lagging.status.failed_checks = 3;
+
+        let mut healthy = Rpc::default();
+        healthy.status.head = 100;
+        healthy.status.latency = 2_500_000.0;
+
+        let rpc_list = Arc::new(RwLock::new(vec![healthy]));
+        let poverty_list = Arc::new(RwLock::new(vec![lagging]));
+
+        let status = health_status(HealthState::MissingRpcs, &rpc_list, &poverty_list);
+        assert_eq!(status["status"], "missing_rpcs");
+
+        let rpcs = status["rpcs"].as_array().unwrap();
+        assert_eq!(rpcs.len(), 2);
+        assert_eq!(rpcs[0]["healthy"], true);
+        assert_eq!(rpcs[0]["head"], 100);
+        assert_eq!(rpcs[0]["latency_ms"], 2.5);
+        assert_eq!(rpcs[1]["healthy"], false);
+        assert_eq!(rpcs[1]["is_erroring"], true);
+        assert_eq!(rpcs[1]["consecutive_failures"], 3);
+    }
+
     #[tokio::test]
     async fn test_liveness_update_sink_discards_updates() {
         let (update_snd, update_recv) = mpsc::channel(10);
@@ -1,28 +1,79 @@
 use crate::{
-    admin::error::AdminError,
-    database::types::{
-        GenericBytes,
-        RequestBus,
+    admin::{
+        error::AdminError,
+        schema::openapi,
+        AdminState,
     },
+    balancer::{
+        blocklist::Blocklist,
+        heatmap::RequestHeatmap,
+        processing::cache_key,
+        selection::select::{
+            algorithm,
+            set_algorithm,
+            SelectionAlgorithm,
+        },
+        shadow::ShadowRpc,
+    },
+    database::{
+        accept::db_batch,
+        expiry::{
+            expiry,
+            now_ms,
+        },
+        serialization::{
+            cached_field,
+            decode_cached,
+        },
+        types::{
+            Batch,
+            GenericBytes,
+            RequestBus,
+        },
+    },
+    db_clear,
+    db_export,
     db_flush,
+    db_get,
+    db_import,
+    health::{
+        capabilities::spawn_capability_probe,
+        events::HealthEvents,
+    },
+    rpc::stats::distinct_rpcs,
+    websocket::connections::WsConnections,
     Rpc,
     Settings,
 };
 
 use std::{
     fmt,
+    path::{
+        Component,
+        Path,
+        PathBuf,
+    },
     sync::{
         Arc,
         RwLock,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
+use rust_tracing::deps::metrics;
+use serde::Serialize;
 use serde_json::{
     json,
     Value,
     Value::Null,
 };
+use tokio::time::timeout;
+
+#[cfg(feature = "xxhash")]
+use zerocopy::AsBytes;
 
 #[derive(Debug, thiserror::Error)]
 #[error("failed to convert method to `BlutgangRpcMethod`:\n\ngot: {0:?}\nexpected:\n{1:#?}")]
@@ -45,6 +96,11 @@ pub enum BlutgangRpcMethod {
     Quit,
     RpcList,
     FlushCache,
+    ClearCache,
+    PurgeCache,
+    InspectCache,
+    ExportCache,
+    ImportCache,
     Config,
     PovertyList,
     Ttl,
@@ -55,11 +111,25 @@ pub enum BlutgangRpcMethod {
     AddToPovertyList,
     RemoveFromRpcList,
     RemoveFromPovertyList,
+    HealthEvents,
+    RequestHeatmap,
+    WsConnections,
+    RpcStats,
+    SlaReport,
+    ShadowStats,
+    Selection,
+    SetSelection,
+    GetSchema,
 }
 impl BlutgangRpcMethod {
     const BLUTGANG_QUIT: &str = "blutgang_quit";
     const BLUTGANG_RPC_LIST: &str = "blutgang_rpc_list";
     const BLUTGANG_FLUSH_CACHE: &str = "blutgang_flush_cache";
+    const BLUTGANG_CLEAR_CACHE: &str = "blutgang_clear_cache";
+    const BLUTGANG_PURGE_CACHE: &str = "blutgang_purge_cache";
+    const BLUTGANG_INSPECT_CACHE: &str = "blutgang_inspect_cache";
+    const BLUTGANG_EXPORT_CACHE: &str = "blutgang_export_cache";
+    const BLUTGANG_IMPORT_CACHE: &str = "blutgang_import_cache";
     const BLUTGANG_CONFIG: &str = "blutgang_config";
     const BLUTGANG_POVERTY_LIST: &str = "blutgang_poverty_list";
     const BLUTGANG_TTL: &str = "blutgang_ttl";
@@ -70,11 +140,25 @@ impl BlutgangRpcMethod {
     const BLUTGANG_ADD_TO_POVERTY_LIST: &str = "blutgang_add_to_poverty_list";
     const BLUTGANG_REMOVE_FROM_RPC_LIST: &str = "blutgang_remove_from_rpc_list";
     const BLUTGANG_REMOVE_FROM_POVERTY_LIST: &str = "blutgang_remove_from_poverty_list";
-
-    const BLUTGANG_ALL: &[&str; 13] = &[
+    const BLUTGANG_HEALTH_EVENTS: &str = "blutgang_health_events";
+    const BLUTGANG_REQUEST_HEATMAP: &str = "blutgang_request_heatmap";
+    const BLUTGANG_WS_CONNECTIONS: &str = "blutgang_ws_connections";
+    const BLUTGANG_RPC_STATS: &str = "blutgang_rpc_stats";
+    const BLUTGANG_SLA_REPORT: &str = "blutgang_sla_report";
+    const BLUTGANG_SHADOW_STATS: &str = "blutgang_shadow_stats";
+    const BLUTGANG_SELECTION: &str = "blutgang_selection";
+    const BLUTGANG_SET_SELECTION: &str = "blutgang_set_selection";
+    const BLUTGANG_GET_SCHEMA: &str = "blutgang_getSchema";
+
+    pub(super) const BLUTGANG_ALL: &[&str; 27] = &[
         Self::BLUTGANG_QUIT,
         Self::BLUTGANG_RPC_LIST,
         Self::BLUTGANG_FLUSH_CACHE,
+        Self::BLUTGANG_CLEAR_CACHE,
+        Self::BLUTGANG_PURGE_CACHE,
+        Self::BLUTGANG_INSPECT_CACHE,
+        Self::BLUTGANG_EXPORT_CACHE,
+        Self::BLUTGANG_IMPORT_CACHE,
         Self::BLUTGANG_CONFIG,
         Self::BLUTGANG_POVERTY_LIST,
         Self::BLUTGANG_TTL,
@@ -85,6 +169,15 @@ impl BlutgangRpcMethod {
         Self::BLUTGANG_ADD_TO_POVERTY_LIST,
         Self::BLUTGANG_REMOVE_FROM_RPC_LIST,
         Self::BLUTGANG_REMOVE_FROM_POVERTY_LIST,
+        Self::BLUTGANG_HEALTH_EVENTS,
+        Self::BLUTGANG_REQUEST_HEATMAP,
+        Self::BLUTGANG_WS_CONNECTIONS,
+        Self::BLUTGANG_RPC_STATS,
+        Self::BLUTGANG_SLA_REPORT,
+        Self::BLUTGANG_SHADOW_STATS,
+        Self::BLUTGANG_SELECTION,
+        Self::BLUTGANG_SET_SELECTION,
+        Self::BLUTGANG_GET_SCHEMA,
     ];
 
     /// Useful for circumventing lifetimes associated with `let` bindings.
@@ -93,6 +186,11 @@ impl BlutgangRpcMethod {
             Self::Quit => Self::BLUTGANG_QUIT,
             Self::RpcList => Self::BLUTGANG_RPC_LIST,
             Self::FlushCache => Self::BLUTGANG_FLUSH_CACHE,
+            Self::ClearCache => Self::BLUTGANG_CLEAR_CACHE,
+            Self::PurgeCache => Self::BLUTGANG_PURGE_CACHE,
+            Self::InspectCache => Self::BLUTGANG_INSPECT_CACHE,
+            Self::ExportCache => Self::BLUTGANG_EXPORT_CACHE,
+            Self::ImportCache => Self::BLUTGANG_IMPORT_CACHE,
             Self::Config => Self::BLUTGANG_CONFIG,
             Self::PovertyList => Self::BLUTGANG_POVERTY_LIST,
             Self::Ttl => Self::BLUTGANG_TTL,
@@ -103,8 +201,37 @@ impl BlutgangRpcMethod {
             Self::AddToPovertyList => Self::BLUTGANG_ADD_TO_POVERTY_LIST,
             Self::RemoveFromRpcList => Self::BLUTGANG_REMOVE_FROM_RPC_LIST,
             Self::RemoveFromPovertyList => Self::BLUTGANG_REMOVE_FROM_POVERTY_LIST,
+            Self::HealthEvents => Self::BLUTGANG_HEALTH_EVENTS,
+            Self::RequestHeatmap => Self::BLUTGANG_REQUEST_HEATMAP,
+            Self::WsConnections => Self::BLUTGANG_WS_CONNECTIONS,
+            Self::RpcStats => Self::BLUTGANG_RPC_STATS,
+            Self::SlaReport => Self::BLUTGANG_SLA_REPORT,
+            Self::ShadowStats => Self::BLUTGANG_SHADOW_STATS,
+            Self::Selection => Self::BLUTGANG_SELECTION,
+            Self::SetSelection => Self::BLUTGANG_SET_SELECTION,
+            Self::GetSchema => Self::BLUTGANG_GET_SCHEMA,
         }
     }
+
+    /// Returns true if the method changes anything, and so is write protected.
+    pub const fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Quit
+                | Self::FlushCache
+                | Self::ClearCache
+                | Self::PurgeCache
+                | Self::ExportCache
+                | Self::ImportCache
+                | Self::SetTtl
+                | Self::SetHealthCheckTtl
+                | Self::AddToRpcList
+                | Self::AddToPovertyList
+                | Self::RemoveFromRpcList
+                | Self::RemoveFromPovertyList
+                | Self::SetSelection
+        )
+    }
 }
 impl TryFrom<Option<&str>> for BlutgangRpcMethod {
     type Error = Error<Option<String>>;
@@ -113,6 +240,11 @@ impl TryFrom<Option<&str>> for BlutgangRpcMethod {
             Some(Self::BLUTGANG_QUIT) => Ok(Self::Quit),
             Some(Self::BLUTGANG_RPC_LIST) => Ok(Self::RpcList),
             Some(Self::BLUTGANG_FLUSH_CACHE) => Ok(Self::FlushCache),
+            Some(Self::BLUTGANG_CLEAR_CACHE) => Ok(Self::ClearCache),
+            Some(Self::BLUTGANG_PURGE_CACHE) => Ok(Self::PurgeCache),
+            Some(Self::BLUTGANG_INSPECT_CACHE) => Ok(Self::InspectCache),
+            Some(Self::BLUTGANG_EXPORT_CACHE) => Ok(Self::ExportCache),
+            Some(Self::BLUTGANG_IMPORT_CACHE) => Ok(Self::ImportCache),
             Some(Self::BLUTGANG_CONFIG) => Ok(Self::Config),
             Some(Self::BLUTGANG_POVERTY_LIST) => Ok(Self::PovertyList),
             Some(Self::BLUTGANG_TTL) => Ok(Self::Ttl),
@@ -123,6 +255,15 @@ impl TryFrom<Option<&str>> for BlutgangRpcMethod {
             Some(Self::BLUTGANG_ADD_TO_POVERTY_LIST) => Ok(Self::AddToPovertyList),
             Some(Self::BLUTGANG_REMOVE_FROM_RPC_LIST) => Ok(Self::RemoveFromRpcList),
             Some(Self::BLUTGANG_REMOVE_FROM_POVERTY_LIST) => Ok(Self::RemoveFromPovertyList),
+            Some(Self::BLUTGANG_HEALTH_EVENTS) => Ok(Self::HealthEvents),
+            Some(Self::BLUTGANG_REQUEST_HEATMAP) => Ok(Self::RequestHeatmap),
+            Some(Self::BLUTGANG_WS_CONNECTIONS) => Ok(Self::WsConnections),
+            Some(Self::BLUTGANG_RPC_STATS) => Ok(Self::RpcStats),
+            Some(Self::BLUTGANG_SLA_REPORT) => Ok(Self::SlaReport),
+            Some(Self::BLUTGANG_SHADOW_STATS) => Ok(Self::ShadowStats),
+            Some(Self::BLUTGANG_SELECTION) => Ok(Self::Selection),
+            Some(Self::BLUTGANG_SET_SELECTION) => Ok(Self::SetSelection),
+            Some(Self::BLUTGANG_GET_SCHEMA) => Ok(Self::GetSchema),
             _ => Err(Error::new(value.map(ToString::to_string))),
         }
     }
@@ -145,6 +286,11 @@ impl<'de> serde::Deserialize<'de> for BlutgangRpcMethod {
             Self::BLUTGANG_QUIT => Ok(Self::Quit),
             Self::BLUTGANG_RPC_LIST => Ok(Self::RpcList),
             Self::BLUTGANG_FLUSH_CACHE => Ok(Self::FlushCache),
+            Self::BLUTGANG_CLEAR_CACHE => Ok(Self::ClearCache),
+            Self::BLUTGANG_PURGE_CACHE => Ok(Self::PurgeCache),
+            Self::BLUTGANG_INSPECT_CACHE => Ok(Self::InspectCache),
+            Self::BLUTGANG_EXPORT_CACHE => Ok(Self::ExportCache),
+            Self::BLUTGANG_IMPORT_CACHE => Ok(Self::ImportCache),
             Self::BLUTGANG_CONFIG => Ok(Self::Config),
             Self::BLUTGANG_POVERTY_LIST => Ok(Self::PovertyList),
             Self::BLUTGANG_TTL => Ok(Self::Ttl),
@@ -155,6 +301,15 @@ impl<'de> serde::Deserialize<'de> for BlutgangRpcMethod {
             Self::BLUTGANG_ADD_TO_POVERTY_LIST => Ok(Self::AddToPovertyList),
             Self::BLUTGANG_REMOVE_FROM_RPC_LIST => Ok(Self::RemoveFromRpcList),
             Self::BLUTGANG_REMOVE_FROM_POVERTY_LIST => Ok(Self::RemoveFromPovertyList),
+            Self::BLUTGANG_HEALTH_EVENTS => Ok(Self::HealthEvents),
+            Self::BLUTGANG_REQUEST_HEATMAP => Ok(Self::RequestHeatmap),
+            Self::BLUTGANG_WS_CONNECTIONS => Ok(Self::WsConnections),
+            Self::BLUTGANG_RPC_STATS => Ok(Self::RpcStats),
+            Self::BLUTGANG_SLA_REPORT => Ok(Self::SlaReport),
+            Self::BLUTGANG_SHADOW_STATS => Ok(Self::ShadowStats),
+            Self::BLUTGANG_SELECTION => Ok(Self::Selection),
+            Self::BLUTGANG_SET_SELECTION => Ok(Self::SetSelection),
+            Self::BLUTGANG_GET_SCHEMA => Ok(Self::GetSchema),
             _ => Err(serde::de::Error::unknown_variant(s, Self::BLUTGANG_ALL)),
         }
     }
@@ -166,10 +321,11 @@ pub async fn execute_method<K, V>(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     config: Arc<RwLock<Settings>>,
+    state: &AdminState,
     cache: RequestBus<K, V>,
 ) -> Result<Value, AdminError>
 where
-    K: GenericBytes,
+    K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes,
 {
     let method = tx["method"].as_str().try_into();
@@ -177,8 +333,9 @@ where
 
     // Check if write protection is enabled
     let write_protection_enabled = config.read().unwrap().admin.readonly;
+    let validate_changes = config.read().unwrap().admin.validate_changes;
 
-    match method {
+    let result = match method {
         Ok(BlutgangRpcMethod::Quit) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
@@ -194,6 +351,38 @@ where
                 admin_flush_cache(cache).await
             }
         }
+        Ok(BlutgangRpcMethod::ClearCache) => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_clear_cache(cache, state).await
+            }
+        }
+        Ok(BlutgangRpcMethod::PurgeCache) => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_purge_cache(cache, state, tx["params"].as_array()).await
+            }
+        }
+        Ok(BlutgangRpcMethod::InspectCache) => {
+            admin_inspect_cache(cache, &config, state, tx["params"].as_array()).await
+        }
+        // Exports can overwrite any file Blutgang has access to
+        Ok(BlutgangRpcMethod::ExportCache) => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_export_cache(cache, &config, state, tx["params"].as_array()).await
+            }
+        }
+        Ok(BlutgangRpcMethod::ImportCache) => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_import_cache(cache, &config, state, tx["params"].as_array()).await
+            }
+        }
         Ok(BlutgangRpcMethod::Config) => admin_config(config),
         Ok(BlutgangRpcMethod::PovertyList) => admin_list_rpc(poverty_list),
         Ok(BlutgangRpcMethod::Ttl) => admin_blutgang_ttl(config),
@@ -202,46 +391,92 @@ where
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_blutgang_set_ttl(config, tx["params"].as_array())
+                admin_blutgang_set_ttl(config, tx["params"].as_array(), validate_changes)
             }
         }
         Ok(BlutgangRpcMethod::SetHealthCheckTtl) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_blutgang_set_health_check_ttl(config, tx["params"].as_array())
+                admin_blutgang_set_health_check_ttl(
+                    config,
+                    tx["params"].as_array(),
+                    validate_changes,
+                )
             }
         }
         Ok(BlutgangRpcMethod::AddToRpcList) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(rpc_list, tx["params"].as_array())
+                let ttl = config.read().unwrap().ttl;
+                admin_add_rpc(
+                    rpc_list,
+                    tx["params"].as_array(),
+                    &state.blocklist,
+                    validate_changes,
+                    ttl,
+                )
+                .await
             }
         }
         Ok(BlutgangRpcMethod::AddToPovertyList) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_add_rpc(poverty_list, tx["params"].as_array())
+                // RPCs in the poverty list are expected to be unhealthy
+                admin_add_rpc(
+                    poverty_list,
+                    tx["params"].as_array(),
+                    &state.blocklist,
+                    false,
+                    0,
+                )
+                .await
             }
         }
         Ok(BlutgangRpcMethod::RemoveFromRpcList) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_remove_rpc(rpc_list, tx["params"].as_array())
+                admin_remove_rpc(rpc_list, tx["params"].as_array(), validate_changes)
             }
         }
         Ok(BlutgangRpcMethod::RemoveFromPovertyList) => {
             if write_protection_enabled {
                 Err(AdminError::WriteProtectionEnabled)
             } else {
-                admin_remove_rpc(poverty_list, tx["params"].as_array())
+                admin_remove_rpc(poverty_list, tx["params"].as_array(), false)
+            }
+        }
+        Ok(BlutgangRpcMethod::HealthEvents) => {
+            admin_health_events(&state.health_events, tx["params"].as_array())
+        }
+        Ok(BlutgangRpcMethod::RequestHeatmap) => {
+            admin_request_heatmap(&state.heatmap, tx["params"].as_array())
+        }
+        Ok(BlutgangRpcMethod::WsConnections) => admin_ws_connections(&state.ws_connections),
+        Ok(BlutgangRpcMethod::RpcStats) => admin_rpc_stats(rpc_list, poverty_list),
+        Ok(BlutgangRpcMethod::SlaReport) => admin_sla_report(rpc_list, poverty_list),
+        Ok(BlutgangRpcMethod::ShadowStats) => admin_shadow_stats(&state.shadow_list),
+        Ok(BlutgangRpcMethod::Selection) => admin_selection(),
+        Ok(BlutgangRpcMethod::SetSelection) => {
+            if write_protection_enabled {
+                Err(AdminError::WriteProtectionEnabled)
+            } else {
+                admin_set_selection(rpc_list, poverty_list, tx["params"].as_array())
             }
         }
+        Ok(BlutgangRpcMethod::GetSchema) => admin_get_schema(),
         Err(err) => Err(AdminError::InvalidMethod(err)),
+    };
+
+    if let Err(AdminError::ValidationFailed(reason)) = &result {
+        let change = tx["method"].as_str().unwrap_or_default();
+        state.health_events.reject(change, reason, now_ms());
     }
+
+    result
 }
 
 /// Quit Blutgang upon receiving this method
@@ -278,6 +513,291 @@ where
     Ok(rx)
 }
 
+/// Deletes every cached response, in the DB and in memory
+async fn admin_clear_cache<K, V>(
+    cache: RequestBus<K, V>,
+    state: &AdminState,
+) -> Result<Value, AdminError>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    let time = Instant::now();
+    db_clear!(cache)
+        .await
+        .map_err(|_| AdminError::Inaccessible)?;
+    state.cache_index.clear();
+    state.log_cache.clear();
+    let time = time.elapsed();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("Cache cleared in {:?}", time),
+    });
+
+    Ok(rx)
+}
+
+/// Parse a block number given as a number or a hex string.
+fn parse_block(block: &Value) -> Result<Option<u64>, AdminError> {
+    match block {
+        Null => Ok(None),
+        Value::Number(block) => block.as_u64().map(Some).ok_or(AdminError::ParseError),
+        Value::String(block) => {
+            let hex = block.strip_prefix("0x").ok_or(AdminError::ParseError)?;
+            u64::from_str_radix(hex, 16)
+                .map(Some)
+                .map_err(|_| AdminError::ParseError)
+        }
+        _ => Err(AdminError::ParseError),
+    }
+}
+
+/// Deletes the cached responses to a method, within a block range, with a field, or all of them:
+/// - param[0] - object with at least one of `method`, `fromBlock`, `toBlock`, and `field`
+///
+/// `field` is a `.` separated path into the response, e.g. `result.blockHash`,
+/// and matches entries where it equals `value`.
+///
+/// Only entries in the cache index can be found. Logs cached in memory are
+/// dropped along with purged blocks or `eth_getLogs` entries.
+async fn admin_purge_cache<K, V>(
+    cache: RequestBus<K, V>,
+    state: &AdminState,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes,
+{
+    let filter = params
+        .and_then(|params| params.first())
+        .and_then(Value::as_object)
+        .ok_or(AdminError::InvalidParams)?;
+
+    let method = match filter.get("method") {
+        Some(Value::String(method)) => Some(method.as_str()),
+        Some(Null) | None => None,
+        Some(_) => return Err(AdminError::ParseError),
+    };
+    let from = parse_block(filter.get("fromBlock").unwrap_or(&Null))?;
+    let to = parse_block(filter.get("toBlock").unwrap_or(&Null))?;
+    let blocks =
+        (from.is_some() || to.is_some()).then(|| from.unwrap_or(0)..=to.unwrap_or(u64::MAX));
+    let field = match filter.get("field") {
+        Some(Value::String(field)) => Some((field.as_str(), filter.get("value").unwrap_or(&Null))),
+        Some(Null) | None => None,
+        Some(_) => return Err(AdminError::ParseError),
+    };
+
+    // Purging everything is what `blutgang_clear_cache` is for
+    if method.is_none() && blocks.is_none() && field.is_none() {
+        return Err(AdminError::InvalidParams);
+    }
+    if !state.cache_index.is_enabled() {
+        return Err(AdminError::CacheIndexDisabled);
+    }
+
+    let keys = match field {
+        Some((field, value)) => {
+            let mut keys = Vec::new();
+            for key in state.cache_index.find(method, blocks.clone()) {
+                let entry = db_get!(cache, key.into()).map_err(|_| AdminError::Inaccessible)?;
+                let matches = entry.is_some_and(|mut entry| {
+                    cached_field(&mut entry, field).ok().flatten().as_ref() == Some(value)
+                });
+                if matches {
+                    keys.push(key);
+                }
+            }
+            state.cache_index.remove(&keys);
+            keys
+        }
+        None => state.cache_index.purge(method, blocks.clone()),
+    };
+    let purged = keys.len();
+
+    let mut batch = Batch::with_capacity(purged);
+    keys.into_iter().for_each(|key| batch.delete(key.into()));
+    db_batch(&cache, batch)
+        .await
+        .await
+        .map_err(|_| AdminError::Inaccessible)?;
+
+    if blocks.is_some() || method.is_none_or(|method| method == "eth_getLogs") {
+        state.log_cache.clear();
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "purged": purged,
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Responds with what's cached for a request:
+/// - param[0] - the request, as sent by clients. Its `id` is ignored.
+async fn admin_inspect_cache<K, V>(
+    cache: RequestBus<K, V>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes,
+{
+    let mut request = params
+        .and_then(|params| params.first())
+        .filter(|request| request["method"].is_string())
+        .cloned()
+        .ok_or(AdminError::InvalidParams)?;
+    // Cached requests are hashed without their id
+    request["id"] = Null;
+
+    let tx_hash = {
+        let config_guard = config.read().map_err(|_| AdminError::Inaccessible)?;
+        cache_key(&request, &config_guard.cache_policies, &state.named_numbers)
+    };
+
+    let entry = db_get!(cache, tx_hash.as_bytes().to_owned().into())
+        .map_err(|_| AdminError::Inaccessible)?;
+    let expires_at = entry.as_deref().and_then(expiry);
+    // Expired entries decode to `None`
+    let response = entry.and_then(|mut entry| decode_cached(&mut entry).ok().flatten());
+
+    let indexed = <[u8; 32]>::try_from(&tx_hash.as_bytes()[..])
+        .ok()
+        .and_then(|key| state.cache_index.get(&key));
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "key": tx_hash
+                .as_bytes()
+                .iter()
+                .fold("0x".to_string(), |key, byte| format!("{key}{byte:02x}")),
+            "cached": response.is_some(),
+            "expiresAt": expires_at,
+            "response": response,
+            "method": indexed.as_ref().map(|entry| entry.method.clone()),
+            "block": indexed.and_then(|entry| entry.block),
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Parse the name of a snapshot file, the only param of snapshot methods, into
+/// its path under `snapshot_dir`.
+///
+/// Names can't leave `snapshot_dir`, so absolute paths and `..` are rejected.
+fn parse_snapshot_path(
+    config: &Arc<RwLock<Settings>>,
+    params: Option<&Vec<Value>>,
+) -> Result<PathBuf, AdminError> {
+    let snapshot_dir = config
+        .read()
+        .map_err(|_| AdminError::Inaccessible)?
+        .admin
+        .snapshot_dir
+        .clone()
+        .ok_or(AdminError::SnapshotsDisabled)?;
+
+    let name = params
+        .and_then(|params| params.first())
+        .and_then(Value::as_str)
+        .map(Path::new)
+        .filter(|name| {
+            name.components().count() > 0
+                && name
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+        })
+        .ok_or(AdminError::InvalidParams)?;
+
+    Ok(snapshot_dir.join(name))
+}
+
+/// Writes a snapshot of the cache to a file in `snapshot_dir`:
+/// - param[0] - name of the snapshot file, overwritten if it exists.
+async fn admin_export_cache<K, V>(
+    cache: RequestBus<K, V>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    let path = parse_snapshot_path(config, params)?;
+
+    let time = Instant::now();
+    let exported = db_export!(cache, path, state.head_cache.clone())
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .ok_or(AdminError::SnapshotFailed)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "entries": snapshot_entries(&exported),
+            "time": format!("{:?}", time.elapsed()),
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Inserts every entry of a snapshot file into the cache, keeping what's
+/// already cached under other keys:
+/// - param[0] - name of the snapshot file in `snapshot_dir`.
+async fn admin_import_cache<K, V>(
+    cache: RequestBus<K, V>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    let path = parse_snapshot_path(config, params)?;
+
+    let time = Instant::now();
+    let imported = db_import!(cache, path, state.head_cache.clone())
+        .await
+        .map_err(|_| AdminError::Inaccessible)?
+        .ok_or(AdminError::SnapshotFailed)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "entries": snapshot_entries(&imported),
+            "time": format!("{:?}", time.elapsed()),
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Number of entries a snapshot export or import covered, as sent back by the DB.
+fn snapshot_entries(entries: &[u8]) -> u64 {
+    entries
+        .try_into()
+        .map(u64::from_be_bytes)
+        .unwrap_or_default()
+}
+
 /// Respond with the config we started blutgang with
 fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
     let guard = config.read().unwrap();
@@ -285,7 +805,7 @@ fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
         "id": Null,
         "jsonrpc": "2.0",
         "result": {
-            "address": guard.address,
+            "address": guard.addresses,
             "do_clear": guard.do_clear,
             "health_check": guard.health_check,
             "admin": {
@@ -300,45 +820,231 @@ fn admin_config(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError> {
     Ok(rx)
 }
 
+/// Apply `change` to the value behind `lock`, then check the result with `validate`.
+///
+/// If validation fails, the value is rolled back to what it was before the change.
+fn apply_validated<T, R>(
+    lock: &RwLock<T>,
+    change: impl FnOnce(&mut T) -> Result<R, AdminError>,
+    validate: impl FnOnce(&T) -> Result<(), String>,
+) -> Result<R, AdminError>
+where
+    T: Clone,
+{
+    let mut guard = lock.write().map_err(|_| AdminError::Inaccessible)?;
+    let previous = guard.clone();
+
+    let result = change(&mut guard)?;
+    if let Err(reason) = validate(&guard) {
+        *guard = previous;
+        tracing::warn!(%reason, "Admin change failed validation! Rolled back.");
+        return Err(AdminError::ValidationFailed(reason));
+    }
+
+    Ok(result)
+}
+
+/// The active pool needs at least one RPC to serve requests.
+fn validate_rpc_list(rpc_list: &[Rpc]) -> Result<(), String> {
+    if rpc_list.is_empty() {
+        return Err("no RPCs left in the active pool".to_string());
+    }
+
+    Ok(())
+}
+
+fn validate_settings(settings: &Settings) -> Result<(), String> {
+    if settings.ttl == 0 {
+        return Err("ttl must be greater than 0".to_string());
+    }
+    if settings.health_check_ttl == 0 {
+        return Err("health_check_ttl must be greater than 0".to_string());
+    }
+
+    Ok(())
+}
+
+/// Check that a new RPC responds to `eth_blockNumber` within `ttl` ms.
+async fn probe_rpc(rpc: &Rpc, ttl: u128) -> Result<(), String> {
+    match timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        rpc.block_number(),
+    )
+    .await
+    {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("{} failed to respond: {}", rpc.name, e)),
+        Err(_) => Err(format!("{} timed out", rpc.name)),
+    }
+}
+
+/// RPC as listed by `blutgang_rpc_list` and `blutgang_poverty_list`.
+#[derive(Debug, Serialize)]
+pub struct RpcListEntry {
+    pub name: String,
+    pub max_consecutive: u32,
+    pub last_error: u64,
+    /// Method families the RPC is quarantined from, see `quarantine`.
+    pub quarantined: Vec<&'static str>,
+}
+
+impl From<&Rpc> for RpcListEntry {
+    fn from(rpc: &Rpc) -> Self {
+        RpcListEntry {
+            name: rpc.name.clone(),
+            max_consecutive: rpc.max_consecutive,
+            last_error: rpc.status.last_error,
+            quarantined: rpc
+                .status
+                .quarantined
+                .iter()
+                .map(|family| family.as_str())
+                .collect(),
+        }
+    }
+}
+
 /// List generic Fn to retrieve RPCs from an Arc<RwLock<Vec<Rpc>>>
 /// Used for `blutgang_rpc_list` and `blutgang_poverty_list`
 fn admin_list_rpc(rpc_list: &Arc<RwLock<Vec<Rpc>>>) -> Result<Value, AdminError> {
     // Read the RPC list, handling errors
     let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
-
-    // Prepare a formatted string for the RPC list
-    let mut rpc_list_str = String::new();
-    rpc_list_str.push('[');
-
-    // Iterate over the RPC list and format each RPC
-    for rpc in rpc_list.iter() {
-        rpc_list_str.push_str(&format!(
-            "{{\"name\": \"{}\", \"max_consecutive\": {}, \"last_error\": {}}}",
-            rpc.name, rpc.max_consecutive, rpc.status.last_error
-        ));
-    }
-
-    // Complete the formatted RPC list string
-    rpc_list_str.push(']');
+    let entries: Vec<RpcListEntry> = rpc_list.iter().map(RpcListEntry::from).collect();
 
     // Create a JSON response
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": rpc_list_str,
+        "result": entries,
     });
 
     Ok(rx)
 }
 
+/// Reject RPCs whose `url` or `ws_url` is on the `blocklist`.
+pub(super) fn check_blocklist(
+    blocklist: &Blocklist,
+    url: &url::Url,
+    ws_url: Option<&url::Url>,
+) -> Result<(), AdminError> {
+    if blocklist.blocks_endpoint(url)
+        || ws_url.is_some_and(|ws_url| blocklist.blocks_endpoint(ws_url))
+    {
+        tracing::warn!(
+            "Refusing to add blocklisted RPC {}",
+            url.host_str().unwrap_or_default()
+        );
+        metrics::counter!("blocklist_rejected_total", "kind" => "endpoint").increment(1);
+        return Err(AdminError::Blocklisted);
+    }
+
+    Ok(())
+}
+
+/// Push `new_rpc` to the end of the list, returning its index.
+///
+/// If `validate` is set, the RPC has to respond within `ttl` ms to get added.
+/// Its capabilities are discovered once it's in, see `capabilities`.
+pub(super) async fn push_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    mut new_rpc: Rpc,
+    validate: bool,
+    ttl: u128,
+) -> Result<usize, AdminError> {
+    // Not in the config, so reloading it shouldn't remove it
+    new_rpc.runtime = true;
+
+    // Probe before taking the lock so we don't block requests while waiting
+    let probe = if validate {
+        probe_rpc(&new_rpc, ttl).await
+    } else {
+        Ok(())
+    };
+    let added = new_rpc.clone();
+
+    let index = apply_validated(
+        rpc_list,
+        |rpc_list| {
+            rpc_list.push(new_rpc);
+            Ok(rpc_list.len() - 1)
+        },
+        |_| probe,
+    )?;
+    spawn_capability_probe(rpc_list, &added, ttl);
+
+    Ok(index)
+}
+
+/// Remove the RPC at `index`.
+///
+/// If `validate` is set, the last RPC in the list can't be removed.
+fn remove_rpc_at(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    index: usize,
+    validate: bool,
+) -> Result<Rpc, AdminError> {
+    apply_validated(
+        rpc_list,
+        |rpc_list| {
+            // Check if index exists before removing
+            if index >= rpc_list.len() {
+                return Err(AdminError::OutOfBounds);
+            }
+
+            // Finally, remove the index
+            Ok(rpc_list.remove(index))
+        },
+        |rpc_list| {
+            if validate {
+                validate_rpc_list(rpc_list)
+            } else {
+                Ok(())
+            }
+        },
+    )
+}
+
+/// Remove the RPC with the admin API `id`, see `Rpc::id`.
+///
+/// If `validate` is set, the last RPC in the list can't be removed.
+pub(super) fn remove_rpc_by_id(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    id: &str,
+    validate: bool,
+) -> Result<Rpc, AdminError> {
+    apply_validated(
+        rpc_list,
+        |rpc_list| {
+            let index = rpc_list
+                .iter()
+                .position(|rpc| rpc.id() == id)
+                .ok_or(AdminError::OutOfBounds)?;
+            Ok(rpc_list.remove(index))
+        },
+        |rpc_list| {
+            if validate {
+                validate_rpc_list(rpc_list)
+            } else {
+                Ok(())
+            }
+        },
+    )
+}
+
 /// Pushes an RPC to the end of the list:
 /// - param[0] - RPC url
 /// - param[1] - max_consecutive
 /// - param[2] - ma_len
 /// - param[3] - ma_len
-fn admin_add_rpc(
+///
+/// If `validate` is set, the RPC has to respond within `ttl` ms to get added.
+/// RPCs on the `blocklist` are rejected.
+async fn admin_add_rpc(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     params: Option<&Vec<Value>>,
+    blocklist: &Blocklist,
+    validate: bool,
+    ttl: u128,
 ) -> Result<Value, AdminError> {
     let params = match params {
         Some(params) => params,
@@ -365,79 +1071,291 @@ fn admin_add_rpc(
         }
     };
 
-    let max_consecutive = params[2]
-        .to_string()
-        .replace('\"', "")
-        .parse::<u32>()
-        .unwrap_or(0);
-    let mut delta = params[3]
-        .to_string()
-        .replace('\"', "")
-        .parse::<u64>()
-        .unwrap_or(0);
-    let ma_len = params[4]
-        .to_string()
-        .replace('\"', "")
-        .parse::<f64>()
-        .unwrap_or(0.0);
+    let max_consecutive = params[2]
+        .to_string()
+        .replace('\"', "")
+        .parse::<u32>()
+        .unwrap_or(0);
+    let mut delta = params[3]
+        .to_string()
+        .replace('\"', "")
+        .parse::<u64>()
+        .unwrap_or(0);
+    let ma_len = params[4]
+        .to_string()
+        .replace('\"', "")
+        .parse::<f64>()
+        .unwrap_or(0.0);
+
+    if delta != 0 {
+        delta = 1_000_000 / delta;
+    }
+
+    let url: url::Url = rpc.parse().map_err(|_| AdminError::ParseError)?;
+    let ws_url: Option<url::Url> = match ws_url {
+        Some(ws_url) => Some(ws_url.parse().map_err(|_| AdminError::ParseError)?),
+        None => None,
+    };
+
+    check_blocklist(blocklist, &url, ws_url.as_ref())?;
+
+    let new_rpc = Rpc::new(url, ws_url, max_consecutive, delta.into(), ma_len);
+    push_rpc(rpc_list, new_rpc, validate, ttl).await?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": format!("RPC: {}, max_consecutive: {}, ma: {}", rpc, max_consecutive, ma_len),
+    });
+
+    Ok(rx)
+}
+
+/// Remove RPC at a specified index, return the url of the removed RPC:
+/// - param[0] - RPC index
+///
+/// If `validate` is set, the last RPC in the list can't be removed.
+fn admin_remove_rpc(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    params: Option<&Vec<Value>>,
+    validate: bool,
+) -> Result<Value, AdminError> {
+    let params = match params {
+        Some(params) => params,
+        None => return Err(AdminError::InvalidParams),
+    };
+
+    if params.len() != 1 {
+        return Err(AdminError::InvalidLen);
+    }
+
+    let index = match params[0].to_string().replace('\"', "").parse::<u64>() {
+        Ok(index) => index,
+        Err(_) => return Err(AdminError::ParseError),
+    };
+
+    let removed = remove_rpc_at(rpc_list, index as usize, validate)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": removed.name,
+    });
+
+    Ok(rx)
+}
+
+/// Responds with the recorded RPC health transitions, oldest first:
+/// - param[0] - optional, only return events for the RPC with this name
+fn admin_health_events(
+    health_events: &HealthEvents,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let rpc_name = match params.and_then(|params| params.first()) {
+        Some(Value::String(rpc_name)) => Some(rpc_name.as_str()),
+        Some(Null) | None => None,
+        Some(_) => return Err(AdminError::ParseError),
+    };
+
+    let events =
+        serde_json::to_value(health_events.events(rpc_name)).map_err(|_| AdminError::ParseError)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": events,
+    });
+
+    Ok(rx)
+}
+
+/// Responds with the most queried contracts and functions, hottest first:
+/// - param[0] - optional, how many entries to return. Defaults to 100.
+fn admin_request_heatmap(
+    heatmap: &RequestHeatmap,
+    params: Option<&Vec<Value>>,
+) -> Result<Value, AdminError> {
+    let limit = match params.and_then(|params| params.first()) {
+        Some(Value::Number(limit)) => {
+            limit
+                .as_u64()
+                .and_then(|limit| usize::try_from(limit).ok())
+                .ok_or(AdminError::ParseError)?
+        }
+        Some(Null) | None => 100,
+        Some(_) => return Err(AdminError::ParseError),
+    };
+
+    let entries = serde_json::to_value(heatmap.top(limit)).map_err(|_| AdminError::ParseError)?;
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": entries,
+    });
+
+    Ok(rx)
+}
+
+/// Responds with the open WebSocket connections, busiest first, and how past connections were closed
+fn admin_ws_connections(ws_connections: &WsConnections) -> Result<Value, AdminError> {
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": {
+            "connections": ws_connections.connections(),
+            "closes": ws_connections.closes(),
+        },
+    });
+
+    Ok(rx)
+}
+
+/// Responds with what every RPC has been through, to debug how they get picked
+fn admin_rpc_stats(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    let mut entries = Vec::new();
+    for (pool, list) in [("active", rpc_list), ("poverty", poverty_list)] {
+        let list = list.read().map_err(|_| AdminError::Inaccessible)?;
+        entries.extend(list.iter().enumerate().map(|(id, rpc)| {
+            json!({
+                "id": id,
+                "name": rpc.name,
+                "pool": pool,
+                "latency": rpc.status.latency,
+                "latency_data": rpc.status.latency_data,
+                "consecutive": rpc.consecutive,
+                "max_consecutive": rpc.max_consecutive,
+                "stats": rpc.stats.snapshot(),
+            })
+        }));
+    }
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": entries,
+    });
+
+    Ok(rx)
+}
+
+/// Responds with how reliably every RPC has been serving requests, across restarts
+fn admin_sla_report(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<Value, AdminError> {
+    // Copies of an RPC share their statistics, only report them once
+    let entries: Vec<Value> = distinct_rpcs(&[rpc_list.as_ref(), poverty_list.as_ref()])
+        .iter()
+        .map(|rpc| {
+            json!({
+                "name": rpc.name,
+                "report": rpc.stats.sla_report(),
+            })
+        })
+        .collect();
+
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": entries,
+    });
+
+    Ok(rx)
+}
 
-    if delta != 0 {
-        delta = 1_000_000 / delta;
-    }
+/// Responds with how the responses of every canary compared to the ones clients got
+fn admin_shadow_stats(shadow_list: &[ShadowRpc]) -> Result<Value, AdminError> {
+    let entries: Vec<Value> = shadow_list
+        .iter()
+        .map(|shadow| {
+            json!({
+                "name": shadow.rpc.name,
+                "percent": shadow.percent,
+                "agreement": shadow.agreement(),
+                "stats": shadow.rpc.stats.snapshot(),
+            })
+        })
+        .collect();
 
-    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
+    let rx = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": entries,
+    });
 
-    rpc_list.push(Rpc::new(
-        rpc.parse().unwrap(),
-        ws_url.map(|ws_url| ws_url.parse().unwrap()),
-        max_consecutive,
-        delta.into(),
-        ma_len,
-    ));
+    Ok(rx)
+}
 
+/// Responds with the algorithm RPCs are picked with, and the ones it can be switched to
+fn admin_selection() -> Result<Value, AdminError> {
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": format!("RPC: {}, max_consecutive: {}, ma: {}", rpc, max_consecutive, ma_len),
+        "result": {
+            "algorithm": algorithm(),
+            "algorithms": SelectionAlgorithm::ALL,
+        },
     });
 
     Ok(rx)
 }
 
-/// Remove RPC at a specified index, return the url of the removed RPC:
-/// - param[0] - RPC index
-fn admin_remove_rpc(
+/// Switch the selection algorithm, and optionally the `max_consecutive` of every RPC
+fn admin_set_selection(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     params: Option<&Vec<Value>>,
 ) -> Result<Value, AdminError> {
-    let params = match params {
-        Some(params) => params,
-        None => return Err(AdminError::InvalidParams),
-    };
-
-    if params.len() != 1 {
+    let params = params.ok_or(AdminError::InvalidParams)?;
+    if params.is_empty() || params.len() > 2 {
         return Err(AdminError::InvalidLen);
     }
 
-    let index = match params[0].to_string().replace('\"', "").parse::<u64>() {
-        Ok(index) => index,
-        Err(_) => return Err(AdminError::ParseError),
+    let new_algorithm: SelectionAlgorithm =
+        serde_json::from_value(params[0].clone()).map_err(|_| AdminError::ParseError)?;
+    let max_consecutive = match params.get(1) {
+        Some(Value::Number(max_consecutive)) => {
+            Some(
+                max_consecutive
+                    .as_u64()
+                    .and_then(|max_consecutive| u32::try_from(max_consecutive).ok())
+                    .ok_or(AdminError::ParseError)?,
+            )
+        }
+        Some(Null) | None => None,
+        Some(_) => return Err(AdminError::ParseError),
     };
 
-    let mut rpc_list = rpc_list.write().map_err(|_| AdminError::Inaccessible)?;
-
-    // Check if index exists before removing
-    if index as usize >= rpc_list.len() {
-        return Err(AdminError::OutOfBounds);
+    if let Some(max_consecutive) = max_consecutive {
+        for list in [rpc_list, poverty_list] {
+            let mut list = list.write().map_err(|_| AdminError::Inaccessible)?;
+            for rpc in list.iter_mut() {
+                rpc.max_consecutive = max_consecutive;
+                rpc.consecutive = 0;
+                rpc.patched = true;
+            }
+        }
     }
+    set_algorithm(new_algorithm);
+    tracing::info!(
+        algorithm = new_algorithm.as_str(),
+        ?max_consecutive,
+        "Switched selection algorithm"
+    );
+
+    admin_selection()
+}
 
-    // Finally, remove the index
-    let removed: Rpc = rpc_list.remove(index as usize);
-
+/// Responds with the OpenAPI document describing the admin HTTP surface
+fn admin_get_schema() -> Result<Value, AdminError> {
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": removed.name,
+        "result": openapi(),
     });
 
     Ok(rx)
@@ -474,6 +1392,7 @@ fn admin_blutgang_ttl(config: Arc<RwLock<Settings>>) -> Result<Value, AdminError
 fn admin_blutgang_set_health_check_ttl(
     config: Arc<RwLock<Settings>>,
     params: Option<&Vec<Value>>,
+    validate: bool,
 ) -> Result<Value, AdminError> {
     let params = match params {
         Some(params) => params,
@@ -489,13 +1408,25 @@ fn admin_blutgang_set_health_check_ttl(
         Err(_) => return Err(AdminError::ParseError),
     };
 
-    let mut guard = config.write().unwrap();
-    guard.health_check_ttl = health_check_ttl;
+    let health_check_ttl = apply_validated(
+        &config,
+        |config| {
+            config.health_check_ttl = health_check_ttl;
+            Ok(config.health_check_ttl)
+        },
+        |config| {
+            if validate {
+                validate_settings(config)
+            } else {
+                Ok(())
+            }
+        },
+    )?;
 
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": guard.health_check_ttl,
+        "result": health_check_ttl,
     });
 
     Ok(rx)
@@ -506,6 +1437,7 @@ fn admin_blutgang_set_health_check_ttl(
 fn admin_blutgang_set_ttl(
     config: Arc<RwLock<Settings>>,
     params: Option<&Vec<Value>>,
+    validate: bool,
 ) -> Result<Value, AdminError> {
     let params = match params {
         Some(params) => params,
@@ -521,13 +1453,25 @@ fn admin_blutgang_set_ttl(
         Err(_) => return Err(AdminError::ParseError),
     };
 
-    let mut guard = config.write().unwrap();
-    guard.ttl = ttl as u128;
+    let ttl = apply_validated(
+        &config,
+        |config| {
+            config.ttl = ttl as u128;
+            Ok(config.ttl)
+        },
+        |config| {
+            if validate {
+                validate_settings(config)
+            } else {
+                Ok(())
+            }
+        },
+    )?;
 
     let rx = json!({
         "id": Null,
         "jsonrpc": "2.0",
-        "result": guard.ttl,
+        "result": ttl,
     });
 
     Ok(rx)
@@ -536,7 +1480,15 @@ fn admin_blutgang_set_ttl(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::balancer::cache_index::CacheIndex;
+    use crate::database::{
+        accept::db_insert,
+        eviction::CacheEviction,
+        hot_cache::HotCache,
+    };
     use crate::database_processing;
+    use crate::health::quarantine::MethodFamily;
+    use crate::websocket::connections::CloseInitiator;
     use jsonwebtoken::DecodingKey;
     use sled::Config;
     use sled::Db;
@@ -577,7 +1529,13 @@ mod tests {
         let cache = Config::tmp().unwrap();
         let cache = Db::open_with_config(&cache).unwrap();
         let (db_tx, db_rx) = mpsc::unbounded_channel();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
 
         db_tx
     }
@@ -588,19 +1546,29 @@ mod tests {
         // Arrange
         let cache = create_test_cache();
         let tx = json!({ "id":1,"method": BlutgangRpcMethod::RpcList });
+        let rpc_list = create_test_rpc_list();
+        rpc_list.write().unwrap()[0].status.quarantined = vec![MethodFamily::Logs];
 
         // Act
         let result = execute_method(
             tx,
-            &create_test_rpc_list(),
+            &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
 
         // Assert
-        assert!(result.is_ok());
+        let result = result.unwrap();
+        let entries = result["result"].as_array().unwrap();
+        assert_eq!(entries.len(), rpc_list.read().unwrap().len());
+        assert_eq!(
+            entries[0]["name"],
+            rpc_list.read().unwrap()[0].name.as_str()
+        );
+        assert_eq!(entries[0]["quarantined"], json!(["logs"]));
     }
 
     #[tokio::test]
@@ -616,6 +1584,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -637,6 +1606,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -658,6 +1628,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -679,6 +1650,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -700,6 +1672,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -721,6 +1694,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -745,6 +1719,7 @@ mod tests {
             &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -754,6 +1729,35 @@ mod tests {
         assert!(rpc_list.read().unwrap().len() == len + 1);
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_add_to_rpc_list_blocklisted() {
+        let cache = create_test_cache();
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::AddToRpcList, "params": ["http://rpc.example.com", Null, 5, 10, 0.5] });
+
+        let rpc_list = create_test_rpc_list();
+        let len = rpc_list.read().unwrap().len();
+
+        let state = AdminState::default();
+        state
+            .blocklist
+            .update(r#"{"endpoints": ["example.com"]}"#)
+            .unwrap();
+
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            config,
+            &state,
+            cache,
+        )
+        .await;
+
+        assert!(matches!(result, Err(AdminError::Blocklisted)));
+        assert_eq!(rpc_list.read().unwrap().len(), len);
+    }
+
     #[tokio::test]
     #[serial_test::serial]
     async fn test_execute_method_add_to_rpc_list_no_ws() {
@@ -770,6 +1774,7 @@ mod tests {
             &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -804,6 +1809,7 @@ mod tests {
             &rpc_list,
             &create_test_poverty_list(),
             create_test_settings_config(),
+            &AdminState::default(),
             cache.clone(),
         )
         .await;
@@ -821,6 +1827,7 @@ mod tests {
             &rpc_list,
             &binding,
             create_test_settings_config(),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -846,6 +1853,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -872,6 +1880,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -898,6 +1907,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
+            &AdminState::default(),
             cache.clone(),
         )
         .await;
@@ -912,6 +1922,7 @@ mod tests {
             &create_test_rpc_list(),
             &create_test_poverty_list(),
             Arc::clone(&config),
+            &AdminState::default(),
             cache,
         )
         .await;
@@ -919,4 +1930,567 @@ mod tests {
         // Assert
         assert!(result.is_ok());
     }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_health_events() {
+        // Arrange
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState {
+            health_events: Arc::new(HealthEvents::new(16)),
+            ..Default::default()
+        };
+
+        // Move the RPC to the poverty list and back
+        let rpc = rpc_list.read().unwrap()[0].clone();
+        state.health_events.observe(&[rpc.clone()], &[], 1);
+        state.health_events.observe(&[], &[rpc.clone()], 2);
+        state.health_events.observe(&[rpc.clone()], &[], 3);
+
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::HealthEvents, "params": [rpc.name] });
+
+        // Act
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await
+        .unwrap();
+
+        // Assert
+        let events = result["result"].as_array().unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["to"], "ejected");
+        assert_eq!(events[1]["reason"], "readmitted");
+        assert_eq!(events[1]["timestamp_ms"], 3);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_request_heatmap() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState {
+            heatmap: Arc::new(RequestHeatmap::new(16)),
+            ..Default::default()
+        };
+
+        let call =
+            |to: &str| json!({"method": "eth_call", "params": [{"to": to, "data": "0x70a08231"}]});
+        state.heatmap.record(&call("0x01"));
+        state.heatmap.record(&call("0x02"));
+        state.heatmap.record(&call("0x02"));
+
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::RequestHeatmap, "params": [1] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await
+        .unwrap();
+
+        let entries = result["result"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["contract"], "0x02");
+        assert_eq!(entries[0]["selector"], "0x70a08231");
+        assert_eq!(entries[0]["count"], 2);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_ws_connections() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState::default();
+
+        let connection = state.ws_connections.open(None);
+        connection.received(42);
+        state
+            .ws_connections
+            .open(None)
+            .closed(CloseInitiator::Client, 1000);
+
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::WsConnections, "params": [] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await
+        .unwrap();
+
+        let connections = result["result"]["connections"].as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0]["id"], connection.id());
+        assert_eq!(connections[0]["bytes_in"], 42);
+        assert_eq!(
+            result["result"]["closes"],
+            json!([{ "initiator": "client", "code": 1000, "count": 1 }])
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_set_selection() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState::default();
+
+        // Keep the current algorithm, other tests pick RPCs too
+        let current = algorithm();
+        let tx = json!({
+            "id": 1,
+            "method": BlutgangRpcMethod::SetSelection,
+            "params": [current, 7],
+        });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache.clone(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["algorithm"], current.as_str());
+        assert_eq!(result["result"]["algorithms"].as_array().unwrap().len(), 3);
+        assert_eq!(rpc_list.read().unwrap()[0].max_consecutive, 7);
+        assert_eq!(poverty_list.read().unwrap()[0].max_consecutive, 7);
+
+        let tx = json!({
+            "id": 1,
+            "method": BlutgangRpcMethod::SetSelection,
+            "params": ["fastest_first"],
+        });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::ParseError)));
+        assert_eq!(algorithm(), current);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_rpc_stats() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState::default();
+
+        // Copies share their stats
+        let rpc = rpc_list.read().unwrap()[0].clone();
+        let failed: Result<String, String> = Err("connection refused".to_string());
+        rpc.stats
+            .record("eth_call", Duration::from_millis(20), &failed);
+        let _in_flight = rpc.stats.start_call();
+
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::RpcStats, "params": [] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await
+        .unwrap();
+
+        let entries = result["result"].as_array().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0]["pool"], "active");
+        assert_eq!(entries[0]["stats"]["in_flight"], 1);
+        assert_eq!(entries[0]["stats"]["methods"]["eth_call"]["error"], 1);
+        assert_eq!(
+            entries[0]["stats"]["last_error"]["message"],
+            "connection refused"
+        );
+        assert_eq!(entries[1]["pool"], "poverty");
+        assert_eq!(entries[1]["stats"]["in_flight"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_execute_method_shadow_stats() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let state = AdminState {
+            shadow_list: Arc::new(vec![ShadowRpc::new(Rpc::default(), 5.0)]),
+            ..Default::default()
+        };
+
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::ShadowStats, "params": [] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &poverty_list,
+            create_test_settings_config(),
+            &state,
+            cache,
+        )
+        .await
+        .unwrap();
+
+        let entries = result["result"].as_array().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0]["percent"], 5.0);
+        assert_eq!(entries[0]["agreement"]["agreed"], 0);
+        assert_eq!(entries[0]["stats"]["in_flight"], 0);
+    }
+
+    #[cfg(not(feature = "xxhash"))]
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_cache_admin() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let state = AdminState {
+            cache_index: Arc::new(CacheIndex::new(10)),
+            ..Default::default()
+        };
+
+        let request = json!({ "jsonrpc": "2.0", "id": null, "method": "eth_getBalance", "params": ["0x01", "0x10"] });
+        let key = *cache_key(&request, &Default::default(), &state.named_numbers).as_bytes();
+        let response = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#.to_vec();
+        let _ = db_insert(&cache, key.to_vec(), response).await.await;
+        state.cache_index.record(key, "eth_getBalance", Some(16));
+
+        let execute = |method: BlutgangRpcMethod, params: Value| {
+            execute_method(
+                json!({ "id": 1, "method": method, "params": params }),
+                &rpc_list,
+                &poverty_list,
+                config.clone(),
+                &state,
+                cache.clone(),
+            )
+        };
+
+        // The id of the inspected request doesn't matter
+        let mut inspected = request.clone();
+        inspected["id"] = json!(7);
+        let result = execute(BlutgangRpcMethod::InspectCache, json!([inspected]))
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["cached"], true);
+        assert_eq!(result["result"]["response"]["result"], "0x1");
+        assert_eq!(result["result"]["method"], "eth_getBalance");
+        assert_eq!(result["result"]["block"], 16);
+        assert_eq!(result["result"]["expiresAt"], Null);
+
+        // Purging needs a filter
+        assert!(execute(BlutgangRpcMethod::PurgeCache, json!([{}]))
+            .await
+            .is_err());
+        let result = execute(
+            BlutgangRpcMethod::PurgeCache,
+            json!([{ "method": "eth_getCode" }]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["purged"], 0);
+        let result = execute(
+            BlutgangRpcMethod::PurgeCache,
+            json!([{ "field": "result", "value": "0x2" }]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["purged"], 0);
+        let result = execute(
+            BlutgangRpcMethod::PurgeCache,
+            json!([{ "field": "result", "value": "0x1", "toBlock": 15 }]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["purged"], 0);
+        let result = execute(
+            BlutgangRpcMethod::PurgeCache,
+            json!([{ "fromBlock": "0x10", "toBlock": 20, "field": "result", "value": "0x1" }]),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result["result"]["purged"], 1);
+
+        let result = execute(BlutgangRpcMethod::InspectCache, json!([request]))
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["cached"], false);
+        assert_eq!(result["result"]["method"], Null);
+
+        // Clearing doesn't need the index
+        let _ = db_insert(&cache, key.to_vec(), b"{}".to_vec()).await.await;
+        execute(BlutgangRpcMethod::ClearCache, json!([]))
+            .await
+            .unwrap();
+        assert_eq!(db_get!(cache, key.to_vec()).unwrap(), None);
+
+        // Write protection applies to clearing and purging
+        config.write().unwrap().admin.readonly = true;
+        assert!(execute(BlutgangRpcMethod::ClearCache, json!([]))
+            .await
+            .is_err());
+        assert!(execute(
+            BlutgangRpcMethod::PurgeCache,
+            json!([{ "method": "eth_getBalance" }])
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_cache_snapshot() {
+        let source = create_test_cache();
+        let target = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        let state = AdminState::default();
+        let name = format!("blutgang-admin-{}.snapshot", std::process::id());
+        let path = std::env::temp_dir().join(&name);
+
+        let execute =
+            |method: BlutgangRpcMethod, cache: RequestBus<Vec<u8>, Vec<u8>>, name: &str| {
+                execute_method(
+                    json!({ "id": 1, "method": method, "params": [name] }),
+                    &rpc_list,
+                    &poverty_list,
+                    config.clone(),
+                    &state,
+                    cache,
+                )
+            };
+
+        let _ = db_insert(&source, vec![1; 32], b"{}".to_vec()).await.await;
+
+        // Disabled until there's a directory to keep snapshots in
+        assert!(matches!(
+            execute(BlutgangRpcMethod::ExportCache, source.clone(), &name).await,
+            Err(AdminError::SnapshotsDisabled)
+        ));
+        config.write().unwrap().admin.snapshot_dir = Some(std::env::temp_dir());
+
+        let result = execute(BlutgangRpcMethod::ExportCache, source.clone(), &name)
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["entries"], 1);
+
+        let result = execute(BlutgangRpcMethod::ImportCache, target.clone(), &name)
+            .await
+            .unwrap();
+        assert_eq!(result["result"]["entries"], 1);
+        assert_eq!(db_get!(target, vec![1; 32]).unwrap(), Some(b"{}".to_vec()));
+
+        // Snapshots stay in `snapshot_dir`
+        for name in [
+            "",
+            "/etc/passwd",
+            "../escape.snapshot",
+            "a/../../escape.snapshot",
+        ] {
+            assert!(matches!(
+                execute(BlutgangRpcMethod::ExportCache, source.clone(), name).await,
+                Err(AdminError::InvalidParams)
+            ));
+        }
+
+        // Imports write to the cache, exports to the disk
+        config.write().unwrap().admin.readonly = true;
+        assert!(
+            execute(BlutgangRpcMethod::ImportCache, target.clone(), &name)
+                .await
+                .is_err()
+        );
+        assert!(
+            execute(BlutgangRpcMethod::ExportCache, source.clone(), &name)
+                .await
+                .is_err()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_get_schema() {
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::GetSchema, "params": [] });
+        let result = execute_method(
+            tx,
+            &create_test_rpc_list(),
+            &create_test_poverty_list(),
+            create_test_settings_config(),
+            &AdminState::default(),
+            create_test_cache(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(result["result"]["openapi"], "3.1.0");
+        assert!(result["result"]["paths"]["/health"].is_object());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_every_method_conforms() {
+        use crate::admin::schema::conforms;
+
+        let document = openapi();
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let poverty_list = create_test_poverty_list();
+        let config = create_test_settings_config();
+        config.write().unwrap().admin.snapshot_dir = Some(std::env::temp_dir());
+        let state = AdminState {
+            cache_index: Arc::new(CacheIndex::new(10)),
+            ..Default::default()
+        };
+        let snapshot = format!("blutgang-conforms-{}.snapshot", std::process::id());
+        let request =
+            json!({ "jsonrpc": "2.0", "id": null, "method": "eth_chainId", "params": [] });
+
+        for name in BlutgangRpcMethod::BLUTGANG_ALL {
+            // No catch-all, so new methods can't go untested
+            let params = match BlutgangRpcMethod::try_from(Some(*name)).unwrap() {
+                // Exits the process
+                BlutgangRpcMethod::Quit => continue,
+                BlutgangRpcMethod::RpcList
+                | BlutgangRpcMethod::FlushCache
+                | BlutgangRpcMethod::ClearCache
+                | BlutgangRpcMethod::Config
+                | BlutgangRpcMethod::PovertyList
+                | BlutgangRpcMethod::Ttl
+                | BlutgangRpcMethod::HealthCheckTtl
+                | BlutgangRpcMethod::WsConnections
+                | BlutgangRpcMethod::RpcStats
+                | BlutgangRpcMethod::SlaReport
+                | BlutgangRpcMethod::ShadowStats
+                | BlutgangRpcMethod::Selection
+                | BlutgangRpcMethod::GetSchema => json!([]),
+                BlutgangRpcMethod::PurgeCache => json!([{ "method": "eth_chainId" }]),
+                BlutgangRpcMethod::InspectCache => json!([request]),
+                BlutgangRpcMethod::ExportCache | BlutgangRpcMethod::ImportCache => {
+                    json!([snapshot])
+                }
+                BlutgangRpcMethod::SetTtl | BlutgangRpcMethod::SetHealthCheckTtl => json!([1000]),
+                BlutgangRpcMethod::AddToRpcList | BlutgangRpcMethod::AddToPovertyList => {
+                    json!(["http://added.com", null, 5, 10, 0.5])
+                }
+                BlutgangRpcMethod::RemoveFromRpcList | BlutgangRpcMethod::RemoveFromPovertyList => {
+                    json!([0])
+                }
+                BlutgangRpcMethod::HealthEvents => json!([null]),
+                BlutgangRpcMethod::RequestHeatmap => json!([10]),
+                // Keep the current algorithm, other tests pick RPCs too
+                BlutgangRpcMethod::SetSelection => json!([algorithm()]),
+            };
+
+            let tx = json!({ "jsonrpc": "2.0", "id": 1, "method": name, "params": params });
+            let result = execute_method(
+                tx,
+                &rpc_list,
+                &poverty_list,
+                config.clone(),
+                &state,
+                cache.clone(),
+            )
+            .await
+            .unwrap_or_else(|err| panic!("{name} failed: {err}"));
+
+            let schema = &document["components"]["schemas"][format!("{name}_response")];
+            assert!(
+                conforms(&result, schema, &document),
+                "{name} doesn't conform to its schema: {result}"
+            );
+        }
+
+        std::fs::remove_file(std::env::temp_dir().join(&snapshot)).unwrap();
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_execute_method_validation_rollback() {
+        let cache = create_test_cache();
+        let rpc_list = create_test_rpc_list();
+        let config = create_test_settings_config();
+        config.write().unwrap().admin.validate_changes = true;
+        let ttl = config.read().unwrap().ttl;
+        let state = AdminState {
+            health_events: Arc::new(HealthEvents::new(16)),
+            ..Default::default()
+        };
+
+        // Unreachable RPCs don't get added
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::AddToRpcList, "params": ["http://127.0.0.1:1", Null, 5, 10, 0.5] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            &state,
+            cache.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::ValidationFailed(_))));
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+
+        // The last RPC in the active pool can't be removed
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::RemoveFromRpcList, "params": [0] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            &state,
+            cache.clone(),
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::ValidationFailed(_))));
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+
+        // A ttl of 0 gets rolled back
+        let tx = json!({ "id":1,"method": BlutgangRpcMethod::SetTtl, "params": [0] });
+        let result = execute_method(
+            tx,
+            &rpc_list,
+            &create_test_poverty_list(),
+            Arc::clone(&config),
+            &state,
+            cache,
+        )
+        .await;
+        assert!(matches!(result, Err(AdminError::ValidationFailed(_))));
+        assert_eq!(config.read().unwrap().ttl, ttl);
+
+        // Every rejection is reported with its reason
+        let rejected = state.health_events.rejected();
+        assert_eq!(rejected.len(), 3);
+        assert_eq!(rejected[1].change, "blutgang_remove_from_rpc_list");
+        assert_eq!(rejected[1].reason, "no RPCs left in the active pool");
+        assert_eq!(rejected[2].reason, "ttl must be greater than 0");
+    }
 }
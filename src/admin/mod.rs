@@ -6,7 +6,50 @@
 //! For detailed notes on how to use it, please check the wiki.
 
 mod accept;
+pub mod auth;
+mod dashboard;
 mod error;
 pub mod listener;
 pub mod liveready;
 mod methods;
+pub mod prometheus;
+mod schema;
+mod upstreams;
+
+use crate::{
+    balancer::{
+        blocklist::Blocklist,
+        cache_index::CacheIndex,
+        heatmap::RequestHeatmap,
+        logs::LogCache,
+        shadow::ShadowRpc,
+    },
+    health::{
+        events::HealthEvents,
+        safe_block::NamedBlocknumbers,
+    },
+    websocket::connections::WsConnections,
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+/// State collected while serving requests that the admin API reports on.
+#[derive(Debug, Clone, Default)]
+pub struct AdminState {
+    pub health_events: Arc<HealthEvents>,
+    pub heatmap: Arc<RequestHeatmap>,
+    pub blocklist: Arc<Blocklist>,
+    pub ws_connections: Arc<WsConnections>,
+    pub log_cache: Arc<LogCache>,
+    pub cache_index: Arc<CacheIndex>,
+    pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
+    pub shadow_list: Arc<Vec<ShadowRpc>>,
+    /// Keys of unfinalized responses by block, which snapshots carry along.
+    pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>,
+}
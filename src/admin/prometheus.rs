@@ -0,0 +1,190 @@
+//! Prometheus endpoint for the metrics we record.
+//!
+//! With `metrics.enabled`, everything recorded through `metrics` is served at
+//! `/metrics` on `metrics.address`, in the Prometheus text format. Among others:
+//!
+//! - `requests_total{method, result}` and `request_duration_secs{method}` for
+//!   requests from clients,
+//! - `rpc_latency_secs{rpc_name}` and `rpc_selected_total{rpc_name}` for RPCs,
+//! - `rpc_method_duration_secs{method, rpc_name}` and
+//!   `rpc_method_errors_total{method, rpc_name, kind}` for each method an RPC
+//!   served, which can be summed over `rpc_name` for a per method view,
+//! - `cache_lookups_total{method, result}` for cache hit rates,
+//! - `ws_connections`, `ws_user_subs_total` and `ws_node_subs_total` for WS.
+//!
+//! Histograms ending in `_secs` get latency buckets, so they can be aggregated
+//! across instances.
+
+use std::{
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header,
+    server::conn::http1,
+    service::service_fn,
+    Response,
+    StatusCode,
+};
+use hyper_util_blutgang::rt::TokioIo;
+use metrics_exporter_prometheus::{
+    Matcher,
+    PrometheusBuilder,
+    PrometheusHandle,
+};
+use rust_tracing::deps::metrics;
+use tokio::{
+    net::TcpListener,
+    time::sleep,
+};
+
+/// Path metrics are served at.
+const METRICS_PATH: &str = "/metrics";
+
+/// Buckets of `_secs` histograms, from 1ms to 30s.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Time between cleanups of histogram data that's already been rendered.
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Install the recorder backing the endpoint, returning a handle to render it.
+///
+/// Returns `None` if a recorder is already installed, in which case metrics
+/// are exported by that one instead.
+pub fn install_recorder() -> Option<PrometheusHandle> {
+    let builder = match PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("_secs".to_string()), LATENCY_BUCKETS)
+    {
+        Ok(builder) => builder,
+        Err(err) => {
+            tracing::error!(?err, "Invalid metrics buckets");
+            return None;
+        }
+    };
+
+    let recorder = builder.build_recorder();
+    let handle = recorder.handle();
+    if metrics::set_global_recorder(recorder).is_err() {
+        tracing::warn!("A metrics recorder is already installed! Not serving metrics ourselves.");
+        return None;
+    }
+
+    Some(handle)
+}
+
+fn respond(path: &str, handle: &PrometheusHandle) -> Response<Full<Bytes>> {
+    if path != METRICS_PATH {
+        return Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(handle.render())))
+        .unwrap()
+}
+
+/// Serve the metrics rendered by `handle` at `address`.
+pub async fn serve_metrics(handle: PrometheusHandle, address: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving metrics at: {}{}", address, METRICS_PATH);
+
+    // Without this, histograms grow until the next scrape that never comes
+    let upkeep = handle.clone();
+    tokio::task::spawn(async move {
+        loop {
+            sleep(UPKEEP_INTERVAL).await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let io = TokioIo::new(stream);
+        let handle = handle.clone();
+
+        tokio::task::spawn(async move {
+            let service = service_fn(|req| {
+                let response = respond(req.uri().path(), &handle);
+                async move { Ok::<_, std::convert::Infallible>(response) }
+            });
+            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
+                tracing::error!(?err, "error serving metrics connection");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::stats::record_method_metrics;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn test_respond() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("_secs".to_string()), LATENCY_BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            metrics::counter!("requests_total", "method" => "eth_call", "result" => "ok")
+                .increment(2);
+            metrics::histogram!("rpc_latency_secs", "rpc_name" => "a").record(0.02);
+        });
+
+        let response = respond(METRICS_PATH, &handle);
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(r#"requests_total{method="eth_call",result="ok"} 2"#));
+        assert!(body.contains(r#"rpc_latency_secs_bucket{rpc_name="a",le="0.025"} 1"#));
+
+        assert_eq!(respond("/", &handle).status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_method_metrics() {
+        let recorder = PrometheusBuilder::new()
+            .set_buckets_for_metric(Matcher::Suffix("_secs".to_string()), LATENCY_BUCKETS)
+            .unwrap()
+            .build_recorder();
+        let handle = recorder.handle();
+        metrics::with_local_recorder(&recorder, || {
+            let ok: Result<String, String> = Ok(r#"{"jsonrpc":"2.0","id":1,"result":[]}"#.into());
+            record_method_metrics("a", "eth_getLogs", Duration::from_millis(20), &ok);
+            let limited: Result<String, String> =
+                Ok(r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32005,"message":"limit"}}"#.into());
+            record_method_metrics("b", "eth_getLogs", Duration::from_secs(2), &limited);
+            let failed: Result<String, String> = Err("connection refused".into());
+            record_method_metrics("b", "eth_getLogs", Duration::from_secs(10), &failed);
+        });
+
+        let response = respond(METRICS_PATH, &handle);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains(
+            r#"rpc_method_duration_secs_bucket{method="eth_getLogs",rpc_name="a",le="0.025"} 1"#
+        ));
+        assert!(
+            body.contains(r#"rpc_method_duration_secs_count{method="eth_getLogs",rpc_name="b"} 2"#)
+        );
+        assert!(body.contains(
+            r#"rpc_method_errors_total{method="eth_getLogs",rpc_name="b",kind="jsonrpc"} 1"#
+        ));
+        assert!(body.contains(
+            r#"rpc_method_errors_total{method="eth_getLogs",rpc_name="b",kind="transport"} 1"#
+        ));
+        assert!(!body.contains(r#"rpc_method_errors_total{method="eth_getLogs",rpc_name="a""#));
+    }
+}
@@ -0,0 +1,1144 @@
+//! OpenAPI description of the admin HTTP surface.
+//!
+//! Covers the admin JSON-RPC namespace, `/ready` and `/health`, so clients
+//! and validators can be generated instead of written by hand. Metrics are
+//! exported over OTLP and aren't part of it.
+//!
+//! Schemas of response types live next to their `JsonSchema` impls here, and
+//! every admin method has to be described in `method_schema`, so adding one
+//! without documenting it doesn't compile. Tests call every method and check
+//! that what it returns conforms to the schema, see `admin::methods`.
+
+use crate::{
+    admin::methods::{
+        BlutgangRpcMethod,
+        RpcListEntry,
+    },
+    balancer::{
+        heatmap::HeatmapEntry,
+        selection::select::SelectionAlgorithm,
+        shadow::Agreement,
+    },
+    config::system::VERSION_STR,
+    health::{
+        events::{
+            HealthEvent,
+            RpcState,
+        },
+        quarantine::MethodFamily,
+    },
+    rpc::stats::{
+        SlaReport,
+        StatsSnapshot,
+        LATENCY_BUCKETS_MS,
+    },
+    websocket::connections::{
+        CloseCount,
+        CloseInitiator,
+        ConnectionInfo,
+    },
+};
+
+use serde_json::{
+    json,
+    Map,
+    Value,
+};
+
+/// Types that can describe their JSON representation.
+pub trait JsonSchema {
+    /// Name the schema is registered under in `components/schemas`.
+    const NAME: &'static str;
+
+    fn json_schema() -> Value;
+
+    fn schema_ref() -> Value {
+        json!({ "$ref": format!("#/components/schemas/{}", Self::NAME) })
+    }
+}
+
+impl JsonSchema for RpcState {
+    const NAME: &'static str = "RpcState";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "string",
+            "enum": RpcState::ALL.map(|state| state.as_str()),
+        })
+    }
+}
+
+impl JsonSchema for HealthEvent {
+    const NAME: &'static str = "HealthEvent";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["timestamp_ms", "rpc_name", "from", "to", "reason"],
+            "properties": {
+                "timestamp_ms": { "type": "integer" },
+                "rpc_name": { "type": "string" },
+                "from": RpcState::schema_ref(),
+                "to": RpcState::schema_ref(),
+                "reason": { "type": "string" },
+            },
+        })
+    }
+}
+
+impl JsonSchema for RpcListEntry {
+    const NAME: &'static str = "RpcListEntry";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["name", "max_consecutive", "last_error", "quarantined"],
+            "properties": {
+                "name": { "type": "string" },
+                "max_consecutive": { "type": "integer" },
+                "last_error": { "type": "integer" },
+                "quarantined": {
+                    "type": "array",
+                    "items": { "type": "string", "enum": MethodFamily::ALL.map(|family| family.as_str()) },
+                },
+            },
+        })
+    }
+}
+
+impl JsonSchema for HeatmapEntry {
+    const NAME: &'static str = "HeatmapEntry";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["method", "contract", "selector", "count"],
+            "properties": {
+                "method": { "type": "string" },
+                "contract": {
+                    "type": "string",
+                    "description": "Address of the called contract, or `create` for deployments",
+                },
+                "selector": { "type": ["string", "null"] },
+                "count": { "type": "integer" },
+            },
+        })
+    }
+}
+
+impl JsonSchema for ConnectionInfo {
+    const NAME: &'static str = "WsConnection";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": [
+                "id",
+                "peer",
+                "connected_ms",
+                "messages_in",
+                "messages_out",
+                "bytes_in",
+                "bytes_out",
+                "messages_in_per_sec",
+                "messages_out_per_sec",
+                "subscriptions",
+            ],
+            "properties": {
+                "id": { "type": "integer" },
+                "peer": { "type": ["string", "null"], "description": "Client address" },
+                "connected_ms": { "type": "integer" },
+                "messages_in": { "type": "integer" },
+                "messages_out": { "type": "integer" },
+                "bytes_in": { "type": "integer" },
+                "bytes_out": { "type": "integer" },
+                "messages_in_per_sec": { "type": "number" },
+                "messages_out_per_sec": { "type": "number" },
+                "subscriptions": { "type": "integer" },
+            },
+        })
+    }
+}
+
+impl JsonSchema for CloseCount {
+    const NAME: &'static str = "WsCloseCount";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["initiator", "code", "count"],
+            "properties": {
+                "initiator": {
+                    "type": "string",
+                    "enum": [CloseInitiator::Client.as_str(), CloseInitiator::Server.as_str()],
+                },
+                "code": { "type": "integer", "description": "WebSocket close status code" },
+                "count": { "type": "integer" },
+            },
+        })
+    }
+}
+
+impl JsonSchema for Agreement {
+    const NAME: &'static str = "ShadowAgreement";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["agreed", "disagreed", "errored", "skipped"],
+            "properties": {
+                "agreed": { "type": "integer", "description": "Same result as the one the client got" },
+                "disagreed": { "type": "integer", "description": "Different result than the one the client got" },
+                "errored": { "type": "integer", "description": "Errored or timed out" },
+                "skipped": { "type": "integer", "description": "The client got an error, nothing to compare" },
+            },
+        })
+    }
+}
+
+impl JsonSchema for StatsSnapshot {
+    const NAME: &'static str = "RpcStats";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["in_flight", "compute_units", "latency_histogram", "methods", "last_error", "http_statuses", "jsonrpc_errors"],
+            "properties": {
+                "in_flight": { "type": "integer", "description": "Calls waiting on the RPC" },
+                "compute_units": { "type": "integer", "description": "Compute units spent since startup" },
+                "latency_histogram": {
+                    "type": "array",
+                    "minItems": LATENCY_BUCKETS_MS.len() + 1,
+                    "maxItems": LATENCY_BUCKETS_MS.len() + 1,
+                    "items": {
+                        "type": "object",
+                        "required": ["le_ms", "count"],
+                        "properties": {
+                            "le_ms": {
+                                "type": ["number", "null"],
+                                "description": "Upper bound of the bucket, null for the slowest one",
+                            },
+                            "count": { "type": "integer", "description": "Calls in this bucket, not cumulative" },
+                        },
+                    },
+                },
+                "methods": {
+                    "type": "object",
+                    "description": "Calls by method",
+                    "additionalProperties": {
+                        "type": "object",
+                        "required": ["success", "error"],
+                        "properties": {
+                            "success": { "type": "integer" },
+                            "error": { "type": "integer", "description": "Failed calls and JSON-RPC errors" },
+                        },
+                    },
+                },
+                "last_error": {
+                    "type": ["object", "null"],
+                    "required": ["message", "at_ms"],
+                    "properties": {
+                        "message": { "type": "string" },
+                        "at_ms": { "type": "integer", "description": "Unix time in ms" },
+                    },
+                },
+                "http_statuses": http_statuses_schema(),
+                "jsonrpc_errors": jsonrpc_errors_schema(),
+            },
+        })
+    }
+}
+
+fn http_statuses_schema() -> Value {
+    json!({
+        "type": "object",
+        "description": "Responses by HTTP status, `error` for requests that got none",
+        "additionalProperties": { "type": "integer" },
+    })
+}
+
+fn jsonrpc_errors_schema() -> Value {
+    json!({
+        "type": "object",
+        "description": "JSON-RPC errors by code, `other` for non-standard codes",
+        "additionalProperties": { "type": "integer" },
+    })
+}
+
+impl JsonSchema for SlaReport {
+    const NAME: &'static str = "SlaReport";
+
+    fn json_schema() -> Value {
+        json!({
+            "type": "object",
+            "required": ["requests", "failures", "availability", "http_statuses", "jsonrpc_errors"],
+            "properties": {
+                "requests": { "type": "integer", "description": "HTTP requests sent" },
+                "failures": { "type": "integer", "description": "Requests that got no response, or a 5xx one" },
+                "availability": {
+                    "type": ["number", "null"],
+                    "description": "Share of requests that didn't fail, null until one is sent",
+                },
+                "http_statuses": http_statuses_schema(),
+                "jsonrpc_errors": jsonrpc_errors_schema(),
+            },
+        })
+    }
+}
+
+/// RPC as returned by the `/admin/rpc` endpoints.
+fn upstream_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["id", "name", "max_consecutive", "max_per_second", "emergency", "groups", "drained", "banned_until_ms"],
+        "properties": {
+            "id": {
+                "type": "string",
+                "description": "Hash of the url, stays the same as RPCs get removed or reordered",
+            },
+            "name": { "type": "string" },
+            "max_consecutive": { "type": "integer" },
+            "max_per_second": { "type": "integer" },
+            "emergency": { "type": "boolean" },
+            "groups": { "type": "array", "items": { "type": "string" } },
+            "drained": { "type": "boolean" },
+            "banned_until_ms": {
+                "type": ["integer", "null"],
+                "description": "Unix time in ms the RPC is banned until, null if it isn't",
+            },
+        },
+    })
+}
+
+fn new_upstream_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["url"],
+        "additionalProperties": false,
+        "properties": {
+            "url": { "type": "string" },
+            "ws_url": { "type": ["string", "null"] },
+            "max_consecutive": { "type": "integer", "minimum": 0 },
+            "max_per_second": { "type": "integer", "minimum": 0 },
+            "ma_length": { "type": "number" },
+            "emergency": { "type": "boolean" },
+            "ws_transport": { "type": "boolean" },
+            "batch_window_ms": { "type": "integer", "minimum": 0 },
+            "batch_max_size": { "type": "integer", "minimum": 1 },
+            "groups": { "type": "array", "items": { "type": "string" } },
+        },
+    })
+}
+
+fn upstream_changes_schema() -> Value {
+    json!({
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "max_consecutive": { "type": "integer", "minimum": 0 },
+            "max_per_second": { "type": "integer", "minimum": 0 },
+            "emergency": { "type": "boolean" },
+            "groups": { "type": "array", "items": { "type": "string" } },
+        },
+    })
+}
+
+/// Status of blutgang and every RPC, as returned by `/health`.
+fn health_schema() -> Value {
+    json!({
+        "type": "object",
+        "required": ["status", "rpcs"],
+        "properties": {
+            "status": {
+                "type": "string",
+                "enum": ["healthy", "missing_rpcs", "unhealthy"],
+            },
+            "rpcs": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": [
+                        "name",
+                        "healthy",
+                        "latency_ms",
+                        "head",
+                        "is_erroring",
+                        "is_syncing",
+                        "is_timed_out",
+                        "consecutive_failures",
+                        "modules",
+                        "client_version",
+                    ],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "healthy": { "type": "boolean" },
+                        "latency_ms": { "type": "number" },
+                        "head": { "type": "integer" },
+                        "is_erroring": { "type": "boolean" },
+                        "is_syncing": { "type": "boolean" },
+                        "is_timed_out": { "type": "boolean", "description": "Last health check timed out" },
+                        "consecutive_failures": { "type": "integer" },
+                        "modules": { "type": ["array", "null"], "items": { "type": "string" } },
+                        "client_version": { "type": ["string", "null"] },
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Returns a summary, the params and the result schema of `method`.
+fn method_schema(method: &BlutgangRpcMethod) -> (&'static str, Value, Value) {
+    let string = json!({ "type": "string" });
+    let integer = json!({ "type": "integer" });
+    let none = json!({ "type": "array", "maxItems": 0 });
+    let add_rpc = json!({
+        "type": "array",
+        "prefixItems": [
+            { "type": "string", "description": "RPC url" },
+            { "type": ["string", "null"], "description": "WS url" },
+            { "type": "integer", "description": "max_consecutive" },
+            { "type": "integer", "description": "Max requests per second" },
+            { "type": "number", "description": "ma_length" },
+        ],
+        "minItems": 5,
+        "maxItems": 5,
+    });
+    let index = json!({
+        "type": "array",
+        "prefixItems": [{ "type": "integer", "description": "RPC index" }],
+        "minItems": 1,
+        "maxItems": 1,
+    });
+    let ms = json!({
+        "type": "array",
+        "prefixItems": [{ "type": "integer", "description": "Time in ms" }],
+        "minItems": 1,
+        "maxItems": 1,
+    });
+
+    let algorithms = SelectionAlgorithm::ALL.map(|algorithm| algorithm.as_str());
+    let selection = json!({
+        "type": "object",
+        "required": ["algorithm", "algorithms"],
+        "properties": {
+            "algorithm": { "type": "string", "enum": algorithms },
+            "algorithms": { "type": "array", "items": { "type": "string", "enum": algorithms } },
+        },
+    });
+
+    let snapshot = json!({
+        "type": "array",
+        "prefixItems": [{ "type": "string", "description": "Name of the snapshot file in `snapshot_dir`" }],
+        "minItems": 1,
+        "maxItems": 1,
+    });
+    let snapshot_result = json!({
+        "type": "object",
+        "required": ["entries", "time"],
+        "properties": {
+            "entries": { "type": "integer", "description": "Entries exported or imported" },
+            "time": { "type": "string" },
+        },
+    });
+
+    match method {
+        BlutgangRpcMethod::Quit => ("Flush the cache and exit", none, json!({ "type": "null" })),
+        BlutgangRpcMethod::RpcList => {
+            (
+                "Active RPCs",
+                none,
+                json!({ "type": "array", "items": RpcListEntry::schema_ref() }),
+            )
+        }
+        BlutgangRpcMethod::FlushCache => ("Flush the cache to disk", none, string),
+        BlutgangRpcMethod::ClearCache => ("Delete every cached response", none, string),
+        BlutgangRpcMethod::PurgeCache => {
+            let block =
+                json!({ "type": ["integer", "string"], "description": "Number or hex string" });
+            (
+                "Delete the cached responses to a method, within a block range, with a field, or all of them",
+                json!({
+                    "type": "array",
+                    "prefixItems": [{
+                        "type": "object",
+                        "properties": {
+                            "method": { "type": "string" },
+                            "fromBlock": block,
+                            "toBlock": block,
+                            "field": {
+                                "type": "string",
+                                "description": "Dot separated path into the response, e.g. result.blockHash",
+                            },
+                            "value": { "description": "Value of `field` in purged responses" },
+                        },
+                    }],
+                    "minItems": 1,
+                    "maxItems": 1,
+                }),
+                json!({
+                    "type": "object",
+                    "required": ["purged"],
+                    "properties": {
+                        "purged": { "type": "integer", "description": "Entries deleted" },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::InspectCache => {
+            (
+                "What's cached for a request",
+                json!({
+                    "type": "array",
+                    "prefixItems": [{ "type": "object", "description": "The request, as sent by clients" }],
+                    "minItems": 1,
+                    "maxItems": 1,
+                }),
+                json!({
+                    "type": "object",
+                    "required": ["key", "cached", "expiresAt", "response", "method", "block"],
+                    "properties": {
+                        "key": { "type": "string", "description": "Cache key, hex encoded" },
+                        "cached": { "type": "boolean" },
+                        "expiresAt": { "type": ["integer", "null"], "description": "Unix time in ms" },
+                        "response": { "type": ["object", "null"] },
+                        "method": { "type": ["string", "null"], "description": "From the cache index" },
+                        "block": { "type": ["integer", "null"], "description": "From the cache index" },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::ExportCache => {
+            (
+                "Write a snapshot of the cache to a file",
+                snapshot,
+                snapshot_result,
+            )
+        }
+        BlutgangRpcMethod::ImportCache => {
+            (
+                "Insert every entry of a snapshot file into the cache",
+                snapshot,
+                snapshot_result,
+            )
+        }
+        BlutgangRpcMethod::Config => {
+            (
+                "Settings Blutgang is running with",
+                none,
+                json!({
+                    "type": "object",
+                    "properties": {
+                        "address": { "type": "array", "items": { "type": "string" } },
+                        "do_clear": { "type": "boolean" },
+                        "health_check": { "type": "boolean" },
+                        "admin": {
+                            "type": "object",
+                            "properties": {
+                                "enabled": { "type": "boolean" },
+                                "readonly": { "type": "boolean" },
+                            },
+                        },
+                        "ttl": { "type": "integer" },
+                        "health_check_ttl": { "type": "integer" },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::PovertyList => {
+            (
+                "RPCs removed from the active pool",
+                none,
+                json!({ "type": "array", "items": RpcListEntry::schema_ref() }),
+            )
+        }
+        BlutgangRpcMethod::Ttl => ("Request TTL in ms", none, integer),
+        BlutgangRpcMethod::HealthCheckTtl => ("Health check TTL in ms", none, integer),
+        BlutgangRpcMethod::SetTtl => ("Set the request TTL", ms, integer),
+        BlutgangRpcMethod::SetHealthCheckTtl => ("Set the health check TTL", ms, integer),
+        BlutgangRpcMethod::AddToRpcList => ("Add an RPC to the active pool", add_rpc, string),
+        BlutgangRpcMethod::AddToPovertyList => ("Add an RPC to the poverty list", add_rpc, string),
+        BlutgangRpcMethod::RemoveFromRpcList => {
+            (
+                "Remove an RPC from the active pool, returns its name",
+                index,
+                string,
+            )
+        }
+        BlutgangRpcMethod::RemoveFromPovertyList => {
+            (
+                "Remove an RPC from the poverty list, returns its name",
+                index,
+                string,
+            )
+        }
+        BlutgangRpcMethod::HealthEvents => {
+            (
+                "RPC health transitions, oldest first",
+                json!({
+                    "type": "array",
+                    "prefixItems": [{ "type": ["string", "null"], "description": "Only events for this RPC" }],
+                    "maxItems": 1,
+                }),
+                json!({ "type": "array", "items": HealthEvent::schema_ref() }),
+            )
+        }
+        BlutgangRpcMethod::RequestHeatmap => {
+            (
+                "Most queried contracts and functions, hottest first",
+                json!({
+                    "type": "array",
+                    "prefixItems": [{ "type": ["integer", "null"], "description": "Max entries, 100 by default" }],
+                    "maxItems": 1,
+                }),
+                json!({ "type": "array", "items": HeatmapEntry::schema_ref() }),
+            )
+        }
+        BlutgangRpcMethod::WsConnections => {
+            (
+                "Open WebSocket connections, busiest first, and how past connections were closed",
+                none,
+                json!({
+                    "type": "object",
+                    "required": ["connections", "closes"],
+                    "properties": {
+                        "connections": { "type": "array", "items": ConnectionInfo::schema_ref() },
+                        "closes": { "type": "array", "items": CloseCount::schema_ref() },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::RpcStats => {
+            (
+                "Statistics of every RPC, active ones first",
+                none,
+                json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id", "name", "pool", "latency", "latency_data", "consecutive", "max_consecutive", "stats"],
+                        "properties": {
+                            "id": { "type": "integer", "description": "Index in its pool" },
+                            "name": { "type": "string" },
+                            "pool": { "type": "string", "enum": ["active", "poverty"] },
+                            "latency": { "type": "number", "description": "Moving average used for selection, in ns" },
+                            "latency_data": { "type": "array", "items": { "type": "number" } },
+                            "consecutive": { "type": "integer" },
+                            "max_consecutive": { "type": "integer" },
+                            "stats": StatsSnapshot::schema_ref(),
+                        },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::SlaReport => {
+            (
+                "How reliably every RPC has been serving requests, across restarts",
+                none,
+                json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "report"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "report": SlaReport::schema_ref(),
+                        },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::ShadowStats => {
+            (
+                "How the responses of every canary compared to the ones clients got",
+                none,
+                json!({
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["name", "percent", "agreement", "stats"],
+                        "properties": {
+                            "name": { "type": "string" },
+                            "percent": { "type": "number", "description": "Share of read requests mirrored to it" },
+                            "agreement": Agreement::schema_ref(),
+                            "stats": StatsSnapshot::schema_ref(),
+                        },
+                    },
+                }),
+            )
+        }
+        BlutgangRpcMethod::Selection => {
+            (
+                "Algorithm RPCs are picked with, and the ones it can be switched to",
+                none,
+                selection,
+            )
+        }
+        BlutgangRpcMethod::SetSelection => {
+            (
+                "Switch the algorithm RPCs are picked with",
+                json!({
+                    "type": "array",
+                    "prefixItems": [
+                        { "type": "string", "enum": algorithms },
+                        { "type": ["integer", "null"], "description": "max_consecutive of every RPC" },
+                    ],
+                    "minItems": 1,
+                    "maxItems": 2,
+                }),
+                selection,
+            )
+        }
+        BlutgangRpcMethod::GetSchema => ("This document", none, json!({ "type": "object" })),
+    }
+}
+
+/// Request and response of a single admin method, in the shape of a JSON-RPC call.
+fn method_call_schemas(method: &BlutgangRpcMethod) -> (Value, Value) {
+    let (summary, params, result) = method_schema(method);
+
+    let request = json!({
+        "type": "object",
+        "description": summary,
+        "required": ["method"],
+        "properties": {
+            "jsonrpc": { "const": "2.0" },
+            "id": {},
+            "method": { "const": method.as_str() },
+            "params": params,
+            "token": { "type": "string", "description": "JWT, if enabled" },
+        },
+    });
+    let response = json!({
+        "type": "object",
+        "required": ["jsonrpc", "id"],
+        "properties": {
+            "jsonrpc": { "const": "2.0" },
+            "id": {},
+            "result": result,
+            "error": { "type": "string" },
+        },
+    });
+
+    (request, response)
+}
+
+/// OpenAPI document describing the admin HTTP surface.
+pub fn openapi() -> Value {
+    let methods: Vec<BlutgangRpcMethod> = BlutgangRpcMethod::BLUTGANG_ALL
+        .iter()
+        .filter_map(|method| BlutgangRpcMethod::try_from(Some(*method)).ok())
+        .collect();
+
+    let mut schemas = Map::new();
+    schemas.insert(RpcState::NAME.to_string(), RpcState::json_schema());
+    schemas.insert(HealthEvent::NAME.to_string(), HealthEvent::json_schema());
+    schemas.insert(RpcListEntry::NAME.to_string(), RpcListEntry::json_schema());
+    schemas.insert(HeatmapEntry::NAME.to_string(), HeatmapEntry::json_schema());
+    schemas.insert(
+        ConnectionInfo::NAME.to_string(),
+        ConnectionInfo::json_schema(),
+    );
+    schemas.insert(CloseCount::NAME.to_string(), CloseCount::json_schema());
+    schemas.insert(Agreement::NAME.to_string(), Agreement::json_schema());
+    schemas.insert(
+        StatsSnapshot::NAME.to_string(),
+        StatsSnapshot::json_schema(),
+    );
+    schemas.insert(SlaReport::NAME.to_string(), SlaReport::json_schema());
+    schemas.insert("Health".to_string(), health_schema());
+    schemas.insert("Upstream".to_string(), upstream_schema());
+
+    let mut requests = Vec::with_capacity(methods.len());
+    let mut responses = Vec::with_capacity(methods.len());
+    for method in &methods {
+        let (request, response) = method_call_schemas(method);
+        let request_name = format!("{}_request", method.as_str());
+        let response_name = format!("{}_response", method.as_str());
+
+        requests.push(json!({ "$ref": format!("#/components/schemas/{}", request_name) }));
+        responses.push(json!({ "$ref": format!("#/components/schemas/{}", response_name) }));
+        schemas.insert(request_name, request);
+        schemas.insert(response_name, response);
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Blutgang admin API",
+            "version": VERSION_STR,
+        },
+        "paths": {
+            "/": {
+                "post": {
+                    "summary": "Call an admin method",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "oneOf": requests },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Method result",
+                            "content": {
+                                "application/json": {
+                                    "schema": { "oneOf": responses },
+                                },
+                            },
+                        },
+                        "401": { "description": "Malformed request or invalid JWT" },
+                    },
+                },
+            },
+            "/ready": {
+                "get": {
+                    "summary": "Readiness probe",
+                    "responses": {
+                        "200": { "description": "Set up and serving with at least one healthy RPC" },
+                        "503": { "description": "Not ready" },
+                    },
+                },
+            },
+            "/health": {
+                "get": {
+                    "summary": "Health of Blutgang and every RPC",
+                    "responses": {
+                        "200": {
+                            "description": "Healthy",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Health" } } },
+                        },
+                        "202": {
+                            "description": "Some RPCs are not following the head",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Health" } } },
+                        },
+                        "503": {
+                            "description": "Unhealthy",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Health" } } },
+                        },
+                    },
+                },
+            },
+            "/dashboard": {
+                "get": {
+                    "summary": "Live status dashboard",
+                    "responses": {
+                        "200": { "description": "Dashboard page", "content": { "text/html": {} } },
+                        "404": { "description": "Dashboard disabled" },
+                    },
+                },
+            },
+            "/dashboard/events": {
+                "get": {
+                    "summary": "Server-sent events with the data behind the dashboard, every second",
+                    "parameters": [{ "name": "token", "in": "query", "required": false, "schema": { "type": "string" } }],
+                    "responses": {
+                        "200": { "description": "Event stream", "content": { "text/event-stream": {} } },
+                        "401": { "description": "Invalid JWT" },
+                        "404": { "description": "Dashboard disabled" },
+                    },
+                },
+            },
+            "/admin/rpc": {
+                "get": {
+                    "summary": "List the RPCs in the active pool",
+                    "responses": {
+                        "200": {
+                            "description": "RPCs, by id",
+                            "content": { "application/json": { "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Upstream" } } } },
+                        },
+                    },
+                },
+                "post": {
+                    "summary": "Add an RPC to the active pool",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": new_upstream_schema() } },
+                    },
+                    "responses": {
+                        "201": {
+                            "description": "Added RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "400": { "description": "Invalid RPC" },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only, or the RPC is on the blocklist" },
+                        "409": { "description": "An RPC with this url is already in the active pool" },
+                        "422": { "description": "RPC failed validation" },
+                    },
+                },
+            },
+            "/admin/rpc/{id}": {
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "patch": {
+                    "summary": "Change the limits and route groups of an RPC",
+                    "requestBody": {
+                        "required": true,
+                        "content": { "application/json": { "schema": upstream_changes_schema() } },
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Changed RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "400": { "description": "Invalid changes" },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                    },
+                },
+                "delete": {
+                    "summary": "Remove an RPC from the active pool",
+                    "responses": {
+                        "200": {
+                            "description": "Removed RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                        "422": { "description": "Removing the last RPC failed validation" },
+                    },
+                },
+            },
+            "/admin/rpc/{id}/drain": {
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "post": {
+                    "summary": "Take an RPC out of rotation until it's restored, letting requests it's serving complete",
+                    "responses": {
+                        "200": {
+                            "description": "Drained RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                    },
+                },
+                "delete": {
+                    "summary": "Put a drained RPC back into rotation",
+                    "responses": {
+                        "200": {
+                            "description": "Restored RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                    },
+                },
+            },
+            "/admin/rpc/{id}/ban": {
+                "parameters": [{ "name": "id", "in": "path", "required": true, "schema": { "type": "string" } }],
+                "post": {
+                    "summary": "Take an RPC out of rotation for a while",
+                    "parameters": [{
+                        "name": "duration",
+                        "in": "query",
+                        "required": true,
+                        "description": "Seconds until the RPC is back in rotation",
+                        "schema": { "type": "integer", "minimum": 1 },
+                    }],
+                    "responses": {
+                        "200": {
+                            "description": "Banned RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "400": { "description": "Missing or invalid duration" },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                    },
+                },
+                "delete": {
+                    "summary": "Lift the ban of an RPC early",
+                    "responses": {
+                        "200": {
+                            "description": "Restored RPC",
+                            "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Upstream" } } },
+                        },
+                        "401": { "description": "Invalid JWT" },
+                        "403": { "description": "Read-only" },
+                        "404": { "description": "No RPC with this id" },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": schemas,
+        },
+    })
+}
+
+/// Checks `value` against the subset of JSON Schema we use.
+#[cfg(test)]
+pub(super) fn conforms(value: &Value, schema: &Value, document: &Value) -> bool {
+    if let Some(reference) = schema["$ref"].as_str() {
+        let name = reference.trim_start_matches("#/components/schemas/");
+        return conforms(value, &document["components"]["schemas"][name], document);
+    }
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            return false;
+        }
+    }
+    if !schema["const"].is_null() && schema["const"] != *value {
+        return false;
+    }
+
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(kind) => vec![kind.as_str()],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => return true,
+    };
+    let type_matches = types.iter().any(|kind| {
+        match *kind {
+            "null" => value.is_null(),
+            "boolean" => value.is_boolean(),
+            "integer" => value.is_u64() || value.is_i64(),
+            "number" => value.is_number(),
+            "string" => value.is_string(),
+            "array" => value.is_array(),
+            "object" => value.is_object(),
+            _ => false,
+        }
+    });
+    if !type_matches {
+        return false;
+    }
+
+    match value {
+        Value::Object(object) => {
+            let required = schema["required"].as_array().cloned().unwrap_or_default();
+            let properties = &schema["properties"];
+            required
+                .iter()
+                .filter_map(Value::as_str)
+                .all(|key| object.contains_key(key))
+                && object.iter().all(|(key, value)| {
+                    properties
+                        .get(key)
+                        .is_some_and(|property| conforms(value, property, document))
+                        || properties.is_null()
+                })
+        }
+        Value::Array(items) if !schema["items"].is_null() => {
+            items
+                .iter()
+                .all(|item| conforms(item, &schema["items"], document))
+        }
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        admin::liveready::{
+            health_status,
+            HealthState,
+        },
+        balancer::{
+            heatmap::HeatmapKey,
+            shadow::ShadowRpc,
+        },
+        health::events::HealthEvents,
+        websocket::connections::WsConnections,
+        Rpc,
+    };
+
+    use std::sync::{
+        Arc,
+        RwLock,
+    };
+
+    #[test]
+    fn test_every_method_documented() {
+        let document = openapi();
+        let schemas = &document["components"]["schemas"];
+
+        for method in BlutgangRpcMethod::BLUTGANG_ALL {
+            assert!(
+                schemas[format!("{}_request", method)].is_object(),
+                "{} is missing from the schema",
+                method
+            );
+        }
+
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "blutgang_ttl", "params": [] });
+        assert!(conforms(
+            &request,
+            &schemas["blutgang_ttl_request"],
+            &document
+        ));
+        assert!(!conforms(
+            &request,
+            &schemas["blutgang_set_ttl_request"],
+            &document
+        ));
+    }
+
+    #[test]
+    fn test_types_conform() {
+        let document = openapi();
+        let schema = |name: &str| document["components"]["schemas"][name].clone();
+
+        let events = HealthEvents::new(4);
+        let mut rpc = Rpc::default();
+        events.observe(&[rpc.clone()], &[], 0);
+        rpc.status.is_syncing = true;
+        events.observe(&[], &[rpc], 1);
+        let event = serde_json::to_value(&events.events(None)[0]).unwrap();
+        assert!(conforms(&event, &schema(HealthEvent::NAME), &document));
+
+        let mut quarantined = Rpc::default();
+        quarantined.status.quarantined = vec![MethodFamily::State];
+        let listed = serde_json::to_value(RpcListEntry::from(&quarantined)).unwrap();
+        assert!(conforms(&listed, &schema(RpcListEntry::NAME), &document));
+
+        let entry = serde_json::to_value(HeatmapEntry {
+            key: HeatmapKey {
+                method: "eth_call".to_string(),
+                contract: "create".to_string(),
+                selector: None,
+            },
+            count: 3,
+        })
+        .unwrap();
+        assert!(conforms(&entry, &schema(HeatmapEntry::NAME), &document));
+
+        let wrong = json!({ "method": "eth_call", "contract": 1, "selector": null, "count": 3 });
+        assert!(!conforms(&wrong, &schema(HeatmapEntry::NAME), &document));
+
+        let ws_connections = Arc::new(WsConnections::default());
+        let connection = ws_connections.open(Some("127.0.0.1:4000".parse().unwrap()));
+        connection.received(10);
+        connection.closed(CloseInitiator::Server, 1009);
+        let info = serde_json::to_value(&ws_connections.connections()[0]).unwrap();
+        assert!(conforms(&info, &schema(ConnectionInfo::NAME), &document));
+        let close = serde_json::to_value(&ws_connections.closes()[0]).unwrap();
+        assert!(conforms(&close, &schema(CloseCount::NAME), &document));
+
+        let rpc = Rpc::default();
+        let failed: Result<String, String> = Err("connection refused".to_string());
+        rpc.stats
+            .record("eth_call", std::time::Duration::from_millis(20), &failed);
+        let stats = serde_json::to_value(rpc.stats.snapshot()).unwrap();
+        assert!(conforms(&stats, &schema(StatsSnapshot::NAME), &document));
+
+        let agreement = serde_json::to_value(ShadowRpc::new(rpc, 10.0).agreement()).unwrap();
+        assert!(conforms(&agreement, &schema(Agreement::NAME), &document));
+
+        let mut erroring = Rpc::default();
+        erroring.status.is_erroring = true;
+        let health = health_status(
+            HealthState::MissingRpcs,
+            &Arc::new(RwLock::new(vec![Rpc::default()])),
+            &Arc::new(RwLock::new(vec![erroring])),
+        );
+        assert!(conforms(&health, &schema("Health"), &document));
+    }
+}
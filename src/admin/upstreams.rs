@@ -0,0 +1,831 @@
+//! HTTP endpoints for managing the active RPC list at runtime.
+//!
+//! Rotating providers otherwise means editing the config and restarting,
+//! dropping every WS client along the way. Next to the JSON-RPC admin
+//! methods, the admin listener serves:
+//!
+//! - `GET /admin/rpc` - list the RPCs in the active pool, with their ids
+//! - `POST /admin/rpc` - add an RPC, see `NewRpc`
+//! - `PATCH /admin/rpc/{id}` - change the limits and route groups of an RPC,
+//!   see `RpcChanges`
+//! - `DELETE /admin/rpc/{id}` - remove an RPC
+//! - `POST /admin/rpc/{id}/drain` - take an RPC out of rotation until it's
+//!   restored with `DELETE /admin/rpc/{id}/drain`
+//! - `POST /admin/rpc/{id}/ban?duration=<secs>` - take an RPC out of rotation
+//!   for `duration` seconds, or until `DELETE /admin/rpc/{id}/ban`
+//!
+//! Drained and banned RPCs stay in the active pool so they keep their history
+//! and health checks, they just don't get picked for new requests. Requests
+//! they're already serving complete as usual.
+//!
+//! RPCs are only listed and added in the active pool, but ids also find RPCs
+//! that have dropped to the poverty list, so they can be changed, taken out
+//! of rotation and removed before they come back.
+//!
+//! RPCs added here stay when the config is reloaded, and so do limits and groups
+//! changed here, see `config::reload`.
+//!
+//! Ids are hashed from the url of the RPC, so they stay the same as RPCs get
+//! removed or reordered, and across restarts. An RPC can only be added once.
+//!
+//! With `jwt` or `tokens` enabled, requests need an `Authorization: Bearer <token>`
+//! header, see `auth`. Changes need a `write` token, are rejected while the
+//! admin namespace is `readonly`, and validated like the JSON-RPC ones when
+//! `validate_changes` is set. Bodies are held to the `request_limits` of the
+//! balancer.
+
+use crate::{
+    admin::{
+        auth::{
+            authorize,
+            bearer_token,
+        },
+        error::AdminError,
+        methods::{
+            check_blocklist,
+            push_rpc,
+            remove_rpc_by_id,
+        },
+        AdminState,
+    },
+    balancer::request_limits::RequestLimitError,
+    database::expiry::now_ms,
+    rpc::micro_batch::DEFAULT_MAX_SIZE,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    convert::Infallible,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use http_body_util::{
+    BodyExt,
+    Full,
+    LengthLimitError,
+    Limited,
+};
+use hyper::{
+    body::Bytes,
+    header,
+    Method,
+    Request,
+    StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Path the endpoints are served under.
+pub const UPSTREAMS_PATH: &str = "/admin/rpc";
+
+/// Body of `POST /admin/rpc`, fields match the ones of `[[rpc]]` in the config.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewRpc {
+    url: String,
+    ws_url: Option<String>,
+    #[serde(default)]
+    max_consecutive: u32,
+    #[serde(default)]
+    max_per_second: u64,
+    // Falls back to the global `ma_length`
+    ma_length: Option<f64>,
+    #[serde(default)]
+    emergency: bool,
+    #[serde(default)]
+    ws_transport: bool,
+    #[serde(default)]
+    batch_window_ms: u64,
+    batch_max_size: Option<usize>,
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+/// Body of `PATCH /admin/rpc/{id}`, only the fields present are changed.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RpcChanges {
+    /// How many requests in a row the RPC can get, its weight in selection.
+    max_consecutive: Option<u32>,
+    /// Rate limit of the RPC, 0 for none.
+    max_per_second: Option<u64>,
+    /// Whether the RPC is only used when no primary RPC is available.
+    emergency: Option<bool>,
+    /// Route groups of the RPC, see `Route`.
+    groups: Option<Vec<String>>,
+}
+
+fn to_min_time_delta(max_per_second: u64) -> u128 {
+    match max_per_second {
+        0 => 0,
+        max_per_second => (1_000_000 / max_per_second).into(),
+    }
+}
+
+/// Ways to take an RPC out of rotation, `/admin/rpc/{id}/{action}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Rotation {
+    Drain,
+    Ban,
+}
+
+impl Rotation {
+    fn from_path(action: &str) -> Option<Self> {
+        match action {
+            "drain" => Some(Rotation::Drain),
+            "ban" => Some(Rotation::Ban),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the `duration` query parameter in seconds.
+fn ban_duration(query: Option<&str>) -> Result<u64, AdminError> {
+    query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("duration="))
+        .and_then(|duration| duration.parse::<u64>().ok())
+        .filter(|duration| *duration != 0)
+        .ok_or(AdminError::InvalidParams)
+}
+
+fn describe(rpc: &Rpc) -> Value {
+    let max_per_second = match rpc.min_time_delta {
+        0 => 0,
+        delta => 1_000_000 / delta,
+    };
+
+    json!({
+        "id": rpc.id(),
+        "name": rpc.name,
+        "max_consecutive": rpc.max_consecutive,
+        "max_per_second": max_per_second,
+        "emergency": rpc.emergency,
+        "groups": rpc.groups,
+        "drained": rpc.status.drained,
+        "banned_until_ms": rpc.status.is_banned().then_some(rpc.status.banned_until_ms),
+    })
+}
+
+fn error_status(err: &AdminError) -> StatusCode {
+    match err {
+        AdminError::WriteProtectionEnabled
+        | AdminError::InsufficientRole
+        | AdminError::Blocklisted => StatusCode::FORBIDDEN,
+        AdminError::OutOfBounds => StatusCode::NOT_FOUND,
+        AdminError::DuplicateRpc => StatusCode::CONFLICT,
+        AdminError::ValidationFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        AdminError::Inaccessible => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+fn respond(status: StatusCode, body: Value) -> hyper::Response<Full<Bytes>> {
+    hyper::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+fn respond_error(status: StatusCode, message: impl ToString) -> hyper::Response<Full<Bytes>> {
+    respond(status, json!({"error": message.to_string()}))
+}
+
+/// Accept a request to `UPSTREAMS_PATH` or below.
+pub async fn accept_upstreams_request(
+    tx: Request<hyper::body::Incoming>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let (parts, body) = tx.into_parts();
+
+    let (role, json_limits, request_limits) = {
+        let config = config.read().unwrap();
+        (
+            authorize(bearer_token(&parts.headers), &config),
+            config.json_limits,
+            config.request_limits,
+        )
+    };
+    let Some(role) = role else {
+        return Ok(respond_error(
+            StatusCode::UNAUTHORIZED,
+            "Unauthorized or invalid token",
+        ));
+    };
+    if !role.can_write() && parts.method != Method::GET {
+        return Ok(respond_error(
+            StatusCode::FORBIDDEN,
+            AdminError::InsufficientRole,
+        ));
+    }
+
+    if let Err(err) = request_limits.check_content_length(&parts.headers) {
+        return Ok(respond_error(StatusCode::PAYLOAD_TOO_LARGE, err));
+    }
+    let body = match Limited::new(body, request_limits.body_limit())
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(err) if err.is::<LengthLimitError>() => {
+            let err = RequestLimitError::BodyTooLarge(request_limits.max_body_bytes);
+            return Ok(respond_error(StatusCode::PAYLOAD_TOO_LARGE, err));
+        }
+        Err(err) => {
+            tracing::error!(?err, "Admin request malformed");
+            return Ok(respond_error(
+                StatusCode::BAD_REQUEST,
+                "Invalid request body",
+            ));
+        }
+    };
+    if let Err(err) = json_limits.check(&body) {
+        return Ok(respond_error(StatusCode::PAYLOAD_TOO_LARGE, err));
+    }
+
+    let response = handle(
+        &parts.method,
+        parts
+            .uri
+            .path_and_query()
+            .map(|path| path.as_str())
+            .unwrap_or_default(),
+        &body,
+        rpc_list,
+        poverty_list,
+        config,
+        state,
+    )
+    .await;
+
+    if let Err(AdminError::ValidationFailed(reason)) = &response {
+        let change = format!("{} {}", parts.method, parts.uri.path());
+        state.health_events.reject(&change, reason, now_ms());
+    }
+
+    Ok(match response {
+        Ok((status, body)) => respond(status, body),
+        Err(err) => respond_error(error_status(&err), err),
+    })
+}
+
+/// Route a request to the handler for its method and path.
+async fn handle(
+    method: &Method,
+    path_and_query: &str,
+    body: &[u8],
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    config: &Arc<RwLock<Settings>>,
+    state: &AdminState,
+) -> Result<(StatusCode, Value), AdminError> {
+    let (path, query) = match path_and_query.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (path_and_query, None),
+    };
+
+    let (id, rotation) = match path.trim_end_matches('/').strip_prefix(UPSTREAMS_PATH) {
+        Some("") => (None, None),
+        Some(rest) => {
+            let mut segments = rest.strip_prefix('/').unwrap_or_default().split('/');
+            let id = segments
+                .next()
+                .filter(|id| !id.is_empty())
+                .ok_or(AdminError::OutOfBounds)?;
+            let rotation = match segments.next() {
+                Some(action) => Some(Rotation::from_path(action).ok_or(AdminError::OutOfBounds)?),
+                None => None,
+            };
+            if segments.next().is_some() {
+                return Err(AdminError::OutOfBounds);
+            }
+            (Some(id), rotation)
+        }
+        None => return Err(AdminError::OutOfBounds),
+    };
+
+    let (readonly, validate, ttl, ma_length) = {
+        let config = config.read().map_err(|_| AdminError::Inaccessible)?;
+        (
+            config.admin.readonly,
+            config.admin.validate_changes,
+            config.ttl,
+            config.ma_length,
+        )
+    };
+    if readonly && *method != Method::GET {
+        return Err(AdminError::WriteProtectionEnabled);
+    }
+
+    if let (Some(id), Some(rotation)) = (id, rotation) {
+        return set_rotation(method, id, rotation, query, rpc_list, poverty_list);
+    }
+
+    match (method, id) {
+        (&Method::GET, None) => {
+            let rpc_list = rpc_list.read().map_err(|_| AdminError::Inaccessible)?;
+            let rpcs: Vec<Value> = rpc_list.iter().map(describe).collect();
+            Ok((StatusCode::OK, rpcs.into()))
+        }
+        (&Method::POST, None) => {
+            let new_rpc: NewRpc = serde_json::from_slice(body).map_err(|err| {
+                tracing::warn!(?err, "Invalid RPC to add");
+                AdminError::ParseError
+            })?;
+
+            let url: url::Url = new_rpc.url.parse().map_err(|_| AdminError::ParseError)?;
+            let ws_url: Option<url::Url> = match new_rpc.ws_url {
+                Some(ws_url) => Some(ws_url.parse().map_err(|_| AdminError::ParseError)?),
+                None => None,
+            };
+            check_blocklist(&state.blocklist, &url, ws_url.as_ref())?;
+
+            let mut rpc = Rpc::new(
+                url,
+                ws_url,
+                new_rpc.max_consecutive,
+                to_min_time_delta(new_rpc.max_per_second),
+                new_rpc.ma_length.unwrap_or(ma_length),
+            );
+            rpc.emergency = new_rpc.emergency;
+            rpc.groups = new_rpc.groups;
+            if new_rpc.ws_transport {
+                rpc.enable_ws_transport();
+            }
+            rpc.enable_micro_batching(
+                Duration::from_millis(new_rpc.batch_window_ms),
+                new_rpc.batch_max_size.unwrap_or(DEFAULT_MAX_SIZE),
+            );
+            // Ids are derived from the url, so they'd be ambiguous
+            for list in [rpc_list, poverty_list] {
+                if list
+                    .read()
+                    .map_err(|_| AdminError::Inaccessible)?
+                    .iter()
+                    .any(|existing| existing.same_url(&rpc))
+                {
+                    return Err(AdminError::DuplicateRpc);
+                }
+            }
+            let (id, description) = (rpc.id(), describe(&rpc));
+
+            push_rpc(rpc_list, rpc, validate, ttl).await?;
+            tracing::info!(%id, name = %description["name"], "Added RPC through the admin API");
+
+            Ok((StatusCode::CREATED, description))
+        }
+        (&Method::PATCH, Some(id)) => {
+            let changes: RpcChanges = serde_json::from_slice(body).map_err(|err| {
+                tracing::warn!(?err, "Invalid RPC changes");
+                AdminError::ParseError
+            })?;
+
+            let changed = with_rpc(rpc_list, poverty_list, id, |rpc| {
+                if let Some(max_consecutive) = changes.max_consecutive {
+                    rpc.max_consecutive = max_consecutive;
+                }
+                if let Some(max_per_second) = changes.max_per_second {
+                    rpc.min_time_delta = to_min_time_delta(max_per_second);
+                }
+                if let Some(emergency) = changes.emergency {
+                    rpc.emergency = emergency;
+                }
+                if let Some(groups) = changes.groups {
+                    rpc.groups = groups;
+                }
+                rpc.patched = true;
+                tracing::info!(id, name = %rpc.name, "Changed RPC through the admin API");
+                describe(rpc)
+            })?;
+
+            Ok((StatusCode::OK, changed))
+        }
+        (&Method::DELETE, Some(id)) => {
+            // Only the active pool has to keep an RPC, like `blutgang_remove_from_poverty_list`
+            let removed = match remove_rpc_by_id(rpc_list, id, validate) {
+                Err(AdminError::OutOfBounds) => remove_rpc_by_id(poverty_list, id, false)?,
+                removed => removed?,
+            };
+            tracing::info!(id, name = %removed.name, "Removed RPC through the admin API");
+
+            Ok((StatusCode::OK, describe(&removed)))
+        }
+        _ => Err(AdminError::InvalidParams),
+    }
+}
+
+/// Runs `f` on the RPC with the admin API `id`, see `Rpc::id`, in the active
+/// pool or the poverty list.
+fn with_rpc<T>(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    id: &str,
+    f: impl FnOnce(&mut Rpc) -> T,
+) -> Result<T, AdminError> {
+    for list in [rpc_list, poverty_list] {
+        let mut list = list.write().map_err(|_| AdminError::Inaccessible)?;
+        if let Some(rpc) = list.iter_mut().find(|rpc| rpc.id() == id) {
+            return Ok(f(rpc));
+        }
+    }
+    Err(AdminError::OutOfBounds)
+}
+
+/// Take the RPC with `id` out of rotation with `POST`, or put it back with `DELETE`.
+fn set_rotation(
+    method: &Method,
+    id: &str,
+    rotation: Rotation,
+    query: Option<&str>,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+) -> Result<(StatusCode, Value), AdminError> {
+    // Parse before taking the lock
+    let banned_until_ms = match (method, rotation) {
+        (&Method::POST, Rotation::Ban) => {
+            now_ms().saturating_add(ban_duration(query)?.saturating_mul(1000))
+        }
+        (&Method::POST, Rotation::Drain) | (&Method::DELETE, _) => 0,
+        _ => return Err(AdminError::InvalidParams),
+    };
+
+    let changed = with_rpc(rpc_list, poverty_list, id, |rpc| {
+        match (method, rotation) {
+            (&Method::POST, Rotation::Drain) => {
+                rpc.status.drained = true;
+                tracing::info!(id, name = %rpc.name, "Drained RPC through the admin API");
+            }
+            (&Method::POST, Rotation::Ban) => {
+                rpc.status.banned_until_ms = banned_until_ms;
+                tracing::info!(id, name = %rpc.name, banned_until_ms, "Banned RPC through the admin API");
+            }
+            (_, Rotation::Drain) => {
+                rpc.status.drained = false;
+                tracing::info!(id, name = %rpc.name, "Restored drained RPC through the admin API");
+            }
+            (_, Rotation::Ban) => {
+                rpc.status.banned_until_ms = 0;
+                tracing::info!(id, name = %rpc.name, "Lifted ban of RPC through the admin API");
+            }
+        }
+        describe(rpc)
+    })?;
+
+    Ok((StatusCode::OK, changed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::HeaderMap;
+
+    fn settings(readonly: bool) -> Arc<RwLock<Settings>> {
+        let mut config = Settings::default();
+        config.admin.readonly = readonly;
+        Arc::new(RwLock::new(config))
+    }
+
+    fn rpc_list() -> Arc<RwLock<Vec<Rpc>>> {
+        Arc::new(RwLock::new(vec![Rpc::new(
+            "http://one.example".parse().unwrap(),
+            None,
+            5,
+            0,
+            1.0,
+        )]))
+    }
+
+    fn path(rpc_list: &RwLock<Vec<Rpc>>, index: usize, action: &str) -> String {
+        format!(
+            "{}/{}{}",
+            UPSTREAMS_PATH,
+            rpc_list.read().unwrap()[index].id(),
+            action
+        )
+    }
+
+    #[tokio::test]
+    async fn test_manage_rpcs() {
+        let config = settings(false);
+        let rpc_list = rpc_list();
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let state = AdminState::default();
+
+        let body = json!({"url": "http://two.example", "max_per_second": 100, "emergency": true, "groups": ["archive"]});
+        let (status, added) = handle(
+            &Method::POST,
+            UPSTREAMS_PATH,
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(added["id"], rpc_list.read().unwrap()[1].id());
+        assert_eq!(added["max_per_second"], 100);
+        assert_eq!(added["groups"], json!(["archive"]));
+        assert!(rpc_list.read().unwrap()[1].emergency);
+
+        // Removing the first RPC doesn't change the id of the second
+        let second = path(&rpc_list, 1, "");
+        let (_, removed) = handle(
+            &Method::DELETE,
+            &path(&rpc_list, 0, ""),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(removed["name"], "http://one.example/");
+
+        let body = json!({"max_consecutive": 10, "emergency": false, "groups": []});
+        let (_, changed) = handle(
+            &Method::PATCH,
+            &second,
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(changed["max_consecutive"], 10);
+        // Untouched
+        assert_eq!(changed["max_per_second"], 100);
+        assert!(!rpc_list.read().unwrap()[0].emergency);
+        assert!(rpc_list.read().unwrap()[0].groups.is_empty());
+        assert!(rpc_list.read().unwrap()[0].patched);
+
+        let (_, listed) = handle(
+            &Method::GET,
+            "/admin/rpc/",
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(listed.as_array().unwrap().len(), 1);
+        assert_eq!(listed[0]["max_consecutive"], 10);
+
+        let body = json!({"url": "http://two.example"});
+        let err = handle(
+            &Method::POST,
+            UPSTREAMS_PATH,
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let config = settings(false);
+        let rpc_list = rpc_list();
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let state = AdminState::default();
+
+        let err = handle(
+            &Method::DELETE,
+            "/admin/rpc/3",
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::NOT_FOUND);
+
+        let body = json!({"max_consecutive": 1, "weight": 2});
+        let err = handle(
+            &Method::PATCH,
+            &path(&rpc_list, 0, ""),
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+
+        let config = settings(true);
+        let err = handle(
+            &Method::DELETE,
+            &path(&rpc_list, 0, ""),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::FORBIDDEN);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rotation() {
+        let config = settings(false);
+        let rpc_list = rpc_list();
+        let poverty_list = Arc::new(RwLock::new(Vec::new()));
+        let state = AdminState::default();
+
+        let (_, drained) = handle(
+            &Method::POST,
+            &path(&rpc_list, 0, "/drain"),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(drained["drained"], true);
+        assert!(!rpc_list.read().unwrap()[0].status.is_selectable());
+
+        let (_, restored) = handle(
+            &Method::DELETE,
+            &path(&rpc_list, 0, "/drain"),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(restored["drained"], false);
+        assert!(rpc_list.read().unwrap()[0].status.is_selectable());
+
+        let (_, banned) = handle(
+            &Method::POST,
+            &path(&rpc_list, 0, "/ban?duration=60"),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        let banned_until_ms = banned["banned_until_ms"].as_u64().unwrap();
+        assert!(banned_until_ms > now_ms() + 59_000);
+        assert!(!rpc_list.read().unwrap()[0].status.is_selectable());
+
+        // The ban is over once its time has passed
+        rpc_list.write().unwrap()[0].status.banned_until_ms = now_ms() - 1;
+        assert!(rpc_list.read().unwrap()[0].status.is_selectable());
+
+        for action in ["/ban", "/ban?duration=0"] {
+            let ban = path(&rpc_list, 0, action);
+            let err = handle(
+                &Method::POST,
+                &ban,
+                &[],
+                &rpc_list,
+                &poverty_list,
+                &config,
+                &state,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(error_status(&err), StatusCode::BAD_REQUEST);
+        }
+
+        let err = handle(
+            &Method::POST,
+            &path(&rpc_list, 0, "/pause"),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_poverty_list() {
+        let mut config = Settings::default();
+        config.admin.validate_changes = true;
+        let config = Arc::new(RwLock::new(config));
+        let rpc_list = rpc_list();
+        let poverty_list = Arc::new(RwLock::new(vec![Rpc::new(
+            "http://poor.example".parse().unwrap(),
+            None,
+            5,
+            0,
+            1.0,
+        )]));
+        let state = AdminState::default();
+
+        let (_, drained) = handle(
+            &Method::POST,
+            &path(&poverty_list, 0, "/drain"),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(drained["drained"], true);
+        assert!(poverty_list.read().unwrap()[0].status.drained);
+
+        let body = json!({"max_consecutive": 10});
+        let (_, changed) = handle(
+            &Method::PATCH,
+            &path(&poverty_list, 0, ""),
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(changed["max_consecutive"], 10);
+
+        // Already known, just not in the active pool
+        let body = json!({"url": "http://poor.example"});
+        let err = handle(
+            &Method::POST,
+            UPSTREAMS_PATH,
+            body.to_string().as_bytes(),
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(error_status(&err), StatusCode::CONFLICT);
+
+        // The active pool still has its RPC
+        let (_, removed) = handle(
+            &Method::DELETE,
+            &path(&poverty_list, 0, ""),
+            &[],
+            &rpc_list,
+            &poverty_list,
+            &config,
+            &state,
+        )
+        .await
+        .unwrap();
+        assert_eq!(removed["name"], "http://poor.example/");
+        assert!(poverty_list.read().unwrap().is_empty());
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_authorized() {
+        let authorized = |headers: &HeaderMap, config: &Settings| {
+            authorize(bearer_token(headers), config).is_some()
+        };
+        let mut config = Settings::default();
+        let mut headers = HeaderMap::new();
+        assert!(authorized(&headers, &config));
+
+        config.admin.jwt = true;
+        assert!(!authorized(&headers, &config));
+        headers.insert(header::AUTHORIZATION, "Bearer nonsense".parse().unwrap());
+        assert!(!authorized(&headers, &config));
+    }
+}
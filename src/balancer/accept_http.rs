@@ -1,25 +1,133 @@
 use crate::{
     balancer::{
+        api_keys::ApiKey,
+        batch::{
+            answer_from,
+            batch_body,
+            error_response,
+            take_dependencies,
+            waves,
+        },
+        cache_control::CacheControl,
+        cache_metrics::{
+            method_label,
+            record_lookup,
+        },
+        checksum::{
+            checksum,
+            content_checksum,
+            request_checksum,
+            CONTENT_CHECKSUM_HEADER,
+            REQUEST_CHECKSUM_HEADER,
+            RESPONSE_CHECKSUM_HEADER,
+        },
+        compute_units::{
+            charge_client,
+            charge_upstream,
+        },
+        connection_limits::ConnectionPermit,
+        content_encoding::{
+            with_upstream_body,
+            UpstreamBody,
+        },
+        downgrade::cached_response,
         format::{
             incoming_to_value,
-            replace_block_tags,
+            rewrite_block_tags,
+        },
+        heatmap::RequestHeatmap,
+        json_limits::passthrough_response,
+        local_methods::{
+            local_http_response,
+            LocalMethods,
+        },
+        logs::{
+            LogQuery,
+            Segment,
         },
         processing::{
             cache_query,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        rate_limit::{
+            CallLimiter,
+            RequestCounters,
+        },
+        request_limits::RequestLimitError,
+        response_diff::{
+            compare_with_peer,
+            pick_peer,
+        },
+        selection::select::{
+            pick_routed,
+            Route,
+        },
+        shadow::{
+            mirror,
+            sample,
+            ShadowRpc,
+        },
+        singleflight::{
+            with_id,
+            Flight,
+        },
+        slow_requests::{
+            log_slow_request,
+            params_hash,
+            SlowRequest,
+        },
+        stale::{
+            stale_http_response,
+            stale_response,
+        },
+        trace_context::request_span,
+        validation::validate_request,
     },
     cache_error,
-    database::types::GenericBytes,
+    config::types::{
+        ComputeUnitSettings,
+        DowngradeSettings,
+        JsonLimits,
+        LocalMethodSettings,
+        LogLimits,
+        MethodFilterSettings,
+        PrivacySettings,
+        RequestLimits,
+        ResponseDiffSettings,
+        SlowRequestSettings,
+        StaleServeSettings,
+    },
+    database::{
+        serialization::decode_cached,
+        types::GenericBytes,
+    },
     db_get,
+    health::{
+        consensus::Consensus,
+        safe_block::NamedBlocknumbers,
+    },
+    invalid_params,
+    method_not_allowed,
     no_rpc_available,
     print_cache_error,
-    rpc::types::Rpc,
+    rate_limited,
+    request_too_large,
+    rpc::{
+        engine::{
+            engine_authorized,
+            is_engine_method,
+        },
+        types::{
+            jsonrpc_error_codes,
+            Rpc,
+        },
+    },
     rpc_response,
     timed_out,
+    unauthorized,
     websocket::{
+        connections::WsConnections,
         server::serve_websocket,
         types::{
             IncomingResponse,
@@ -36,32 +144,55 @@ use tokio::sync::{
     watch,
 };
 
-use serde_json::Value;
-
-// Select either blake3 or xxhash based on the features
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
 #[cfg(feature = "xxhash")]
 use zerocopy::AsBytes; // Impls AsBytes trait for u64
 
-use http_body_util::Full;
+use futures::{
+    future::join_all,
+    stream,
+    StreamExt,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+    LengthLimitError,
+    Limited,
+};
 use hyper::{
     body::Bytes,
-    header::HeaderValue,
+    header::{
+        HeaderValue,
+        ACCEPT_ENCODING,
+        AUTHORIZATION,
+        ORIGIN,
+    },
     Request,
 };
+use serde_json::{
+    json,
+    Value,
+};
+
+use rust_tracing::deps::metrics;
+
 use hyper_tungstenite::{
     is_upgrade_request,
+    tungstenite::protocol::WebSocketConfig,
     upgrade,
 };
 
 use tokio::time::timeout;
+use tracing::{
+    Instrument,
+    Span,
+};
 
 use std::{
     convert::Infallible,
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
     sync::{
         Arc,
         RwLock,
@@ -80,6 +211,19 @@ pub struct ConnectionParams {
     channels: RequestChannels,
     sub_data: Arc<SubscriptionData>,
     config: Arc<RwLock<Settings>>,
+    heatmap: Arc<RequestHeatmap>,
+    ws_connections: Arc<WsConnections>,
+    peer: Option<SocketAddr>,
+    client_ip: Option<IpAddr>,
+    counters: Option<Arc<RequestCounters>>,
+    api_key: Option<Arc<ApiKey>>,
+    /// Limits calls of the client are charged to, set once it's known.
+    limiter: Option<CallLimiter>,
+    /// The request carries a valid engine token, see `engine`.
+    engine_authorized: bool,
+    permit: Option<Arc<ConnectionPermit>>,
+    consensus: Option<Arc<Consensus>>,
+    shadow_list: Option<Arc<Vec<ShadowRpc>>>,
 }
 
 impl ConnectionParams {
@@ -88,20 +232,152 @@ impl ConnectionParams {
         channels: RequestChannels,
         sub_data: &Arc<SubscriptionData>,
         config: &Arc<RwLock<Settings>>,
+        heatmap: &Arc<RequestHeatmap>,
+        ws_connections: &Arc<WsConnections>,
     ) -> Self {
         ConnectionParams {
             rpc_list: rpc_list_rwlock.clone(),
             channels,
             sub_data: sub_data.clone(),
             config: config.clone(),
+            heatmap: heatmap.clone(),
+            ws_connections: ws_connections.clone(),
+            peer: None,
+            client_ip: None,
+            counters: None,
+            api_key: None,
+            limiter: None,
+            engine_authorized: false,
+            permit: None,
+            consensus: None,
+            shadow_list: None,
         }
     }
+
+    /// Set the address of the client the connection is from.
+    pub fn with_peer(mut self, peer: SocketAddr) -> Self {
+        self.peer = Some(peer);
+        self
+    }
+
+    /// Count requests in `counters` for rate limits.
+    pub fn with_counters(mut self, counters: &Arc<RequestCounters>) -> Self {
+        self.counters = Some(counters.clone());
+        self
+    }
+
+    /// Count the connection against connection limits until it's closed.
+    pub fn with_permit(mut self, permit: ConnectionPermit) -> Self {
+        self.permit = Some(Arc::new(permit));
+        self
+    }
+
+    /// Answer trivial methods from `consensus` if enabled.
+    pub fn with_consensus(mut self, consensus: &Arc<Consensus>) -> Self {
+        self.consensus = Some(consensus.clone());
+        self
+    }
+
+    /// Mirror read requests to the canaries in `shadow_list`.
+    pub fn with_shadow_list(mut self, shadow_list: &Arc<Vec<ShadowRpc>>) -> Self {
+        self.shadow_list = Some(shadow_list.clone());
+        self
+    }
+
+    /// Answers trivial methods with `settings`, if we keep track of `consensus`.
+    fn local_methods(&self, settings: LocalMethodSettings) -> Option<LocalMethods> {
+        self.consensus
+            .as_ref()
+            .map(|consensus| LocalMethods::new(consensus.clone(), self.rpc_list.clone(), settings))
+    }
+
+    /// Returns the block numbers tags refer to right now, as far as the RPCs
+    /// agree on them.
+    fn block_tags(
+        &self,
+        named_numbers: &RwLock<NamedBlocknumbers>,
+        max_head_age_ms: u64,
+    ) -> NamedBlocknumbers {
+        let named_numbers = *named_numbers.read().unwrap_or_else(|e| e.into_inner());
+        match &self.consensus {
+            Some(consensus) => consensus.block_tags(&named_numbers, max_head_age_ms),
+            None => named_numbers,
+        }
+    }
+
+    /// Returns true if the API key of the request, if any, can call `method`,
+    /// and the request is authenticated for it if it's an `engine_*` method.
+    fn allows_method(&self, method: Option<&str>) -> bool {
+        (self.engine_authorized || !is_engine_method(method))
+            && self
+                .api_key
+                .as_ref()
+                .is_none_or(|api_key| api_key.allows_method(method))
+    }
+
+    /// Charge `units` compute units to the client, see `compute_units`.
+    async fn charge_compute_units(
+        &self,
+        settings: &ComputeUnitSettings,
+        units: u64,
+    ) -> Result<(), u64> {
+        match &self.counters {
+            Some(counters) => {
+                charge_client(
+                    settings,
+                    counters,
+                    self.api_key.as_deref(),
+                    self.client_ip,
+                    units,
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Route groups the API key of the request limits it to, if any.
+    fn groups(&self) -> Option<&[String]> {
+        self.api_key
+            .as_ref()
+            .map(|api_key| api_key.groups.as_slice())
+    }
 }
 
+#[derive(Clone)]
 pub struct RequestParams {
     pub ttl: u128,
     pub max_retries: u32,
     pub header_check: bool,
+    pub validate_requests: bool,
+    pub debug_checksums: bool,
+    pub allow_no_cache: bool,
+    pub json_limits: JsonLimits,
+    pub request_limits: RequestLimits,
+    pub log_limits: LogLimits,
+    pub privacy: Arc<PrivacySettings>,
+    pub downgrade: Arc<DowngradeSettings>,
+    pub method_filter: Arc<MethodFilterSettings>,
+    pub compute_units: Arc<ComputeUnitSettings>,
+    pub response_diff: Arc<ResponseDiffSettings>,
+    pub stale_serve: StaleServeSettings,
+    pub local_methods: LocalMethodSettings,
+    pub slow_requests: SlowRequestSettings,
+    pub cache_control: CacheControl,
+    /// Block numbers tags were pinned to when the request came in, if any.
+    pub pinned_tags: Option<NamedBlocknumbers>,
+}
+
+impl RequestParams {
+    /// Returns the block numbers to resolve tags in the request with.
+    fn block_numbers(&self, named_numbers: &RwLock<NamedBlocknumbers>) -> NamedBlocknumbers {
+        self.pinned_tags.unwrap_or_else(|| {
+            *named_numbers.read().unwrap_or_else(|e| {
+                // Handle the case where the RwLock is poisoned
+                e.into_inner()
+            })
+        })
+    }
 }
 
 #[derive(Debug)]
@@ -141,10 +417,11 @@ macro_rules! accept {
     (
         $io:expr,
         $cache_args:expr,
-        $connection_params:expr
+        $connection_params:expr,
+        $draining:expr
     ) => {
         // Bind the incoming connection to our service
-        if let Err(err) = http1::Builder::new()
+        let connection = http1::Builder::new()
             // `service_fn` converts our function in a `Service`
             .serve_connection(
                 $io,
@@ -154,9 +431,19 @@ macro_rules! accept {
                     response
                 }),
             )
-            .with_upgrades()
-            .await
-        {
+            .with_upgrades();
+        tokio::pin!(connection);
+
+        let mut draining = $draining;
+        let result = tokio::select! {
+            result = connection.as_mut() => result,
+            _ = draining.wait_for(|draining| *draining) => {
+                // Let the request in flight finish, then close
+                connection.as_mut().graceful_shutdown();
+                connection.await
+            }
+        };
+        if let Err(err) = result {
             tracing::error!(?err, "Error serving connection");
         }
     };
@@ -169,31 +456,85 @@ macro_rules! get_response {
         $cache_args:expr,
         $tx_hash:expr,
         $rpc_position:expr,
+        $cache_status:expr,
         $id:expr,
         $con_params:expr,
         $ttl:expr,
-        $max_retries:expr
+        $max_retries:expr,
+        $json_limits:expr,
+        $pinned_rpc:expr,
+        $request_checksum:expr,
+        $cache_control:expr,
+        $method_filter:expr,
+        $stale_serve:expr
     ) => {
-        match db_get!($cache_args.cache, $tx_hash.as_bytes().to_owned().into()) {
-            Ok(Some(mut rax)) => {
+        // Expired or undecodable entries count as misses, and so does everything if the client asked
+        let cached = if $cache_control.no_cache {
+            Ok(None)
+        } else {
+            async { db_get!($cache_args.cache, $tx_hash.as_bytes().to_owned().into()) }
+                .instrument(tracing::info_span!("cache_lookup"))
+                .await
+                .map(|rax| rax.and_then(|mut rax| decode_cached(rax.as_mut()).ok().flatten()))
+        };
+        if !$cache_control.no_cache {
+            record_lookup($tx["method"].as_str(), matches!(cached, Ok(Some(_))));
+        }
+        $cache_status = match &cached {
+            _ if $cache_control.no_cache => "bypass",
+            Ok(Some(_)) => "hit",
+            _ => "miss",
+        };
+        Span::current().record("cache", $cache_status);
+
+        match cached {
+            Ok(Some(mut cached)) => {
                 $rpc_position = None;
                 // Reconstruct ID
-                let mut cached: Value = simd_json::serde::from_slice(rax.as_mut()).unwrap();
-
                 cached["id"] = $id.into();
                 cached.to_string()
             }
             Ok(_) => {
-                fetch_from_rpc!(
-                    $tx,
-                    $cache_args,
-                    $tx_hash,
-                    $rpc_position,
-                    $id,
-                    $con_params,
-                    $ttl,
-                    $max_retries
-                )
+                // Wait on an identical request that's already being fetched instead of sending another
+                let (leader, shared) = match $cache_args
+                    .in_flight
+                    .join($tx_hash.as_bytes(), $tx["method"].as_str())
+                {
+                    Flight::Leader(leader) => (Some(leader), None),
+                    Flight::Follower(mut follower) => (None, follower.recv().await.ok()),
+                    Flight::Alone => (None, None),
+                };
+
+                match shared {
+                    Some(rax) => {
+                        $rpc_position = None;
+                        $cache_status = "shared";
+                        Span::current().record("cache", $cache_status);
+                        with_id(&rax, $id)
+                    }
+                    None => {
+                        let rax = fetch_from_rpc!(
+                            $tx,
+                            $cache_args,
+                            $tx_hash,
+                            $rpc_position,
+                            $id,
+                            $con_params,
+                            $ttl,
+                            $max_retries,
+                            $json_limits,
+                            $pinned_rpc,
+                            $request_checksum,
+                            $cache_control,
+                            $method_filter,
+                            $stale_serve
+                        );
+                        if let Some(leader) = leader {
+                            leader.complete(&rax);
+                        }
+                        rax
+                    }
+                }
             }
             Err(_) => {
                 // If anything errors send an rpc request and see if it works, if not then gg
@@ -214,7 +555,13 @@ macro_rules! fetch_from_rpc {
         $id:expr,
         $con_params:expr,
         $ttl:expr,
-        $max_retries:expr
+        $max_retries:expr,
+        $json_limits:expr,
+        $pinned_rpc:expr,
+        $request_checksum:expr,
+        $cache_control:expr,
+        $method_filter:expr,
+        $stale_serve:expr
     ) => {{
         // Kinda jank but set the id back to what it was before
         $tx["id"] = $id.into();
@@ -226,31 +573,66 @@ macro_rules! fetch_from_rpc {
             // Get the next Rpc in line.
             let mut rpc;
             {
+                let _selection = tracing::info_span!("select_rpc").entered();
                 let mut rpc_list_guard = $con_params.rpc_list.write().unwrap_or_else(|e| {
                     // Handle the case where the RwLock is poisoned
                     e.into_inner()
                 });
 
-                (rpc, $rpc_position) = pick(&mut rpc_list_guard);
+                // Stick to the pinned RPC unless it's gone, or already timed out on us
+                let pinned: Option<&str> = $pinned_rpc;
+                let pinned = pinned.filter(|_| retries == 0).and_then(|name| {
+                    rpc_list_guard
+                        .iter()
+                        .position(|rpc| rpc.name == name && rpc.status.is_selectable())
+                });
+                (rpc, $rpc_position) = match pinned {
+                    Some(position) => (rpc_list_guard[position].clone(), Some(position)),
+                    None => {
+                        let route = Route::new($tx["method"].as_str(), $con_params.groups())
+                            .with_filter(&$method_filter);
+                        pick_routed(&mut rpc_list_guard, route)
+                    }
+                };
             }
-            tracing::info!(rpc.name, "Forwarding to");
-
-            // Check if we have any RPCs in the list, if not return error
+            // Check if we have any RPCs in the list, if not serve what we have or return error
             if $rpc_position == None {
-                return (no_rpc_available!(), None);
+                let stale = stale_response(
+                    &$tx,
+                    $tx_hash.as_bytes().to_owned().into(),
+                    $id,
+                    &$cache_args,
+                    &$stale_serve,
+                    &$cache_control,
+                )
+                .await;
+                return match stale {
+                    Some(stale) => (stale_http_response(stale), None),
+                    None => (no_rpc_available!(), None),
+                };
             }
 
+            Span::current().record("rpc", rpc.name.as_str());
+            tracing::debug!(rpc = %rpc.name, retries, "Forwarding to");
+
             // Send the request. And return a timeout if it takes too long
             //
             // Check if it contains any errors or if its `latest` and insert it if it isn't
             match timeout(
                 Duration::from_millis($ttl.try_into().unwrap()),
-                rpc.send_request($tx.clone()),
+                rpc.send_request_traced($tx.clone(), $request_checksum.as_deref())
+                    .instrument(tracing::info_span!("upstream_call", rpc = %rpc.name, retries)),
             )
             .await
             {
                 Ok(rxa) => {
                     rx = rxa.unwrap();
+
+                    // Don't parse responses that could exhaust the parser, or cache them
+                    if let Err(err) = $json_limits.check(rx.as_bytes()) {
+                        tracing::warn!(%err, rpc.name, "RPC response exceeds JSON limits, passing it through");
+                        return (passthrough_response(rx), $rpc_position);
+                    }
                     break;
                 }
                 Err(_) => {
@@ -265,8 +647,21 @@ macro_rules! fetch_from_rpc {
             }
         }
 
-        // Don't cache responses that contain errors or missing trie nodes
-        cache_query(&mut rx, $tx, $tx_hash, &$cache_args);
+        if let Some(request_checksum) = $request_checksum.as_deref() {
+            tracing::info!(
+                rpc.name,
+                %request_checksum,
+                upstream_checksum = %checksum(rx.as_bytes()),
+                content_checksum = ?content_checksum(&rx),
+                "Received upstream response"
+            );
+        }
+
+        // Don't cache responses that contain errors or missing trie nodes,
+        // or that the client asked us not to
+        if !$cache_control.no_store {
+            cache_query(&mut rx, $tx, $tx_hash, &$cache_args).await;
+        }
 
         rx
     }};
@@ -278,7 +673,7 @@ pub async fn forward_body<K, V>(
     tx: Request<hyper::body::Incoming>,
     con_params: &ConnectionParams,
     cache_args: CacheArgs<K, V>,
-    params: RequestParams,
+    mut params: RequestParams,
 ) -> (
     Result<hyper::Response<Full<Bytes>>, Infallible>,
     Option<usize>,
@@ -303,8 +698,269 @@ where
         );
     }
 
+    // Applies to every request in the body
+    params.cache_control = CacheControl::from_headers(tx.headers()).allowed(params.allow_no_cache);
+
+    // Turn away oversized bodies before reading them, or once they turn out to be
+    if let Err(err) = params.request_limits.check_content_length(tx.headers()) {
+        return (request_too_large!(err), None);
+    }
+    let body_limit = params.request_limits.body_limit();
+    let tx = tx.map(|body| Limited::new(body, body_limit));
+
     // Convert incoming body to serde value
-    let mut tx = incoming_to_value(tx).await.unwrap();
+    let mut tx = match incoming_to_value(tx, &params.json_limits).await {
+        Ok(tx) => tx,
+        Err(err) if err.is::<LengthLimitError>() => {
+            let err = RequestLimitError::BodyTooLarge(params.request_limits.max_body_bytes);
+            return (request_too_large!(err), None);
+        }
+        Err(err) => {
+            tracing::warn!(%err, "Failed to read request body");
+            return (
+                Ok(hyper::Response::builder()
+                    .status(400)
+                    .body(Full::new(Bytes::from("Failed to read request body")))
+                    .unwrap()),
+                None,
+            );
+        }
+    };
+
+    if let Value::Array(items) = &tx {
+        if let Err(err) = params.request_limits.check_batch(items.len()) {
+            return (request_too_large!(err), None);
+        }
+
+        // The request was charged as one call when it came in, the rest of the batch counts too
+        if let (Some(limiter), true) = (&con_params.limiter, items.len() > 1) {
+            if let Err((retry_after, message)) = limiter.charge(items.len() as u64 - 1).await {
+                return (rate_limited!(retry_after, message), None);
+            }
+        }
+    }
+
+    // Every call counts, whether it's cached or not
+    let units = params.compute_units.request_cost(&tx);
+    if let Err(retry_after) = con_params
+        .charge_compute_units(&params.compute_units, units)
+        .await
+    {
+        return (
+            rate_limited!(retry_after, "compute unit budget exceeded"),
+            None,
+        );
+    }
+
+    if let Value::Array(items) = tx {
+        return forward_batch(items, con_params, cache_args, params).await;
+    }
+
+    params.cache_control = params
+        .cache_control
+        .merge(CacheControl::take(&mut tx).allowed(params.allow_no_cache));
+    if !params.method_filter.check(tx["method"].as_str())
+        || !con_params.allows_method(tx["method"].as_str())
+    {
+        let method = tx["method"].as_str().unwrap_or_default();
+        return (method_not_allowed!(tx["id"].clone(), method), None);
+    }
+    let log_limits = params
+        .log_limits
+        .check(&tx, &params.block_numbers(&cache_args.named_numbers));
+    if let Err(err) = log_limits {
+        return (invalid_params!(tx["id"].clone(), err), None);
+    }
+
+    match routed_log_query(&tx, &cache_args, &params) {
+        Some(query) => forward_logs(tx, query, con_params, cache_args, params, None).await,
+        None => forward_request(tx, con_params, cache_args, params, None).await,
+    }
+}
+
+/// Returns the `eth_getLogs` range query `tx` makes if it has to go through
+/// `forward_logs`, to use the log cache or to be fetched in chunks.
+fn routed_log_query<K, V>(
+    tx: &Value,
+    cache_args: &CacheArgs<K, V>,
+    params: &RequestParams,
+) -> Option<LogQuery>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let use_cache = cache_args.log_cache.is_enabled() && !params.cache_control.bypasses();
+    if !use_cache && params.log_limits.max_block_range == 0 {
+        return None;
+    }
+
+    LogQuery::parse(tx, &params.block_numbers(&cache_args.named_numbers))
+        .filter(|query| use_cache || params.log_limits.needs_chunking(query))
+}
+
+/// Answer an `eth_getLogs` range query from the log cache where we can,
+/// and fetch only the blocks we're missing, in chunks if they span too many.
+///
+/// Queries pinned to an RPC with `pinned_rpc` fetch every chunk from it, and
+/// skip the log cache, which mixes logs from every RPC.
+async fn forward_logs<K, V>(
+    tx: Value,
+    query: LogQuery,
+    con_params: &ConnectionParams,
+    cache_args: CacheArgs<K, V>,
+    params: RequestParams,
+    pinned_rpc: Option<String>,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let start = Instant::now();
+    let id = tx.get("id").cloned().unwrap_or(Value::Null);
+    let finalized = *cache_args.finalized_rx.borrow();
+    let segments = if params.cache_control.no_cache || pinned_rpc.is_some() {
+        vec![Segment::Missing {
+            from: query.from,
+            to: query.to,
+        }]
+    } else {
+        cache_args.log_cache.plan(&query)
+    };
+    let segments: Vec<Segment> = segments
+        .into_iter()
+        .flat_map(|segment| {
+            match segment {
+                Segment::Missing { from, to } => {
+                    params
+                        .log_limits
+                        .chunk(from, to)
+                        .into_iter()
+                        .map(|(from, to)| Segment::Missing { from, to })
+                        .collect()
+                }
+                cached => vec![cached],
+            }
+        })
+        .collect();
+
+    // Fetch every missing range concurrently
+    let fetch_start = Instant::now();
+    let missing = segments.iter().filter_map(|segment| {
+        match segment {
+            Segment::Missing { from, to } => Some((*from, *to)),
+            Segment::Cached(_) => None,
+        }
+    });
+    let fetched = join_all(missing.map(|(from, to)| {
+        // Send the request as is if we have nothing, so it can still hit the regular cache
+        let item = if (from, to) == (query.from, query.to) {
+            tx.clone()
+        } else {
+            LogQuery::narrow(&tx, from, to)
+        };
+        let cache_args = cache_args.clone();
+        let params = params.clone();
+        let pinned_rpc = pinned_rpc.clone();
+
+        async move {
+            let (response, _) =
+                forward_batch_item(item, con_params, cache_args, params, pinned_rpc).await;
+            (from, to, response)
+        }
+    }))
+    .await;
+    let upstream_latency = (!fetched.is_empty()).then(|| fetch_start.elapsed());
+    let cache_status = match (fetched.len(), segments.len()) {
+        _ if params.cache_control.no_cache => "bypass",
+        (0, _) => "hit",
+        (fetched, segments) if fetched < segments => "partial",
+        _ => "miss",
+    };
+
+    let mut fetched = fetched.into_iter();
+    let mut logs = Vec::new();
+    let mut error = None;
+    for segment in segments {
+        match segment {
+            Segment::Cached(cached) => logs.extend(cached),
+            Segment::Missing { .. } => {
+                let (from, to, response) =
+                    fetched.next().expect("every missing range has a response");
+                match response["result"].as_array() {
+                    Some(result) => {
+                        if !params.cache_control.no_store && pinned_rpc.is_none() {
+                            cache_args
+                                .log_cache
+                                .insert(&query, from, to, finalized, result);
+                        }
+                        logs.extend(result.iter().cloned());
+                    }
+                    // Errors are passed on as they are
+                    None => {
+                        error = Some(response);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let body = error.unwrap_or_else(|| {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "result": logs,
+        })
+    });
+
+    // Chunks are logged on their own, this covers the request as a whole
+    if params.slow_requests.enabled {
+        log_slow_request(
+            &params.slow_requests,
+            SlowRequest {
+                method: "eth_getLogs",
+                params_hash: &params_hash(&tx),
+                rpc: pinned_rpc.as_deref(),
+                cache: cache_status,
+                latency: start.elapsed(),
+                upstream_latency,
+            },
+        );
+    }
+
+    let res = hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap();
+
+    // RPC latencies are updated per range
+    (Ok(res), None)
+}
+
+/// Get the response to a single request from the cache or an RPC.
+///
+/// If `pinned_rpc` is set, the request goes to the RPC with that name if it's
+/// still available.
+async fn forward_request<K, V>(
+    mut tx: Value,
+    con_params: &ConnectionParams,
+    cache_args: CacheArgs<K, V>,
+    params: RequestParams,
+    pinned_rpc: Option<&str>,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let start = Instant::now();
 
     // Get the id of the request and set it to 0 for caching
     //
@@ -313,50 +969,465 @@ where
     // and does not impact the request result.
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
-    // Hash the request with either blake3 or xxhash depending on the enabled feature
-    let tx_hash;
-    #[cfg(not(feature = "xxhash"))]
-    {
-        tx_hash = hash(tx.to_string().as_bytes());
+    let mut cache_control = params
+        .cache_control
+        .merge(CacheControl::take(&mut tx).allowed(params.allow_no_cache));
+    if cache_control.no_cache {
+        metrics::counter!("cache_bypass_total", "directive" => "no-cache").increment(1);
+    }
+    if cache_control.no_store {
+        metrics::counter!("cache_bypass_total", "directive" => "no-store").increment(1);
     }
-    #[cfg(feature = "xxhash")]
+
+    // The Engine API drives the node, its responses are never reused
+    if is_engine_method(tx["method"].as_str()) {
+        cache_control = CacheControl {
+            no_cache: true,
+            no_store: true,
+        };
+    }
+
+    // Reject malformed or oversized requests before they reach the RPCs
+    if let Err(err) = params.request_limits.check_params(&tx) {
+        return (invalid_params!(id, err), None);
+    }
+    if params.validate_requests {
+        if let Err(reason) = validate_request(&tx) {
+            return (invalid_params!(id, reason), None);
+        }
+    }
+
+    con_params.heatmap.record(&tx);
+
+    // Answer what we already know without asking any RPC
+    if let Some(result) = con_params
+        .local_methods(params.local_methods)
+        .and_then(|local_methods| local_methods.result(tx["method"].as_str()))
     {
-        tx_hash = xxh3_64(tx.to_string().as_bytes());
+        return (local_http_response(id, result), None);
     }
 
+    // Serve the cheaper variant if the RPCs are too slow, unless we have the full response cached
+    let downgrade = {
+        let rpc_list = con_params
+            .rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        params.downgrade.check(&tx, &rpc_list)
+    };
+    let downgrade = match downgrade {
+        Some(downgrade)
+            if cache_control.no_cache || cached_response(&tx, &cache_args).await.is_none() =>
+        {
+            downgrade.apply(&mut tx);
+            Some(downgrade)
+        }
+        _ => None,
+    };
+
+    // Hash the request with either blake3 or xxhash depending on the enabled feature
+    let tx_hash = cache_args.cache_key(&tx);
+
     // RPC used to get the response, we use it to update the latency for it later.
     let mut rpc_position;
+    // How the cache was used, for the slow request log
+    let mut cache_status;
+
+    // Checksum the request as the client sent it, before we rewrite any tags
+    let request_checksum = params.debug_checksums.then(|| request_checksum(&tx));
+    let params_hash = params.slow_requests.enabled.then(|| params_hash(&tx));
 
     // Rewrite named block parameters if possible
-    let mut tx = replace_block_tags(&mut tx, &cache_args.named_numbers);
+    let mut block_numbers = params.block_numbers(&cache_args.named_numbers);
+    // `safe` is left for the RPC to resolve unless tags are pinned
+    if params.pinned_tags.is_none() {
+        block_numbers.safe = 0;
+    }
+    let mut tx = rewrite_block_tags(&mut tx, &block_numbers);
+
+    // Has to be read before `tx` gets moved into `get_response!`
+    let private = params.privacy.applies_to(tx["method"].as_str());
+    let method = method_label(tx["method"].as_str());
+    let units = params.compute_units.cost(tx["method"].as_str());
+    Span::current().record("method", method.as_str());
+
+    // Canaries get the same request the RPCs do
+    let mirrors = con_params
+        .shadow_list
+        .as_ref()
+        .map(|shadow_list| sample(shadow_list, &tx))
+        .unwrap_or_default();
+    // So do the RPCs we compare responses of
+    let diffed = params.response_diff.sample(tx["method"].as_str());
+    let forwarded_tx = (!mirrors.is_empty() || diffed).then(|| tx.clone());
 
     // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(
+    let fetch_start = Instant::now();
+    let mut rax = get_response!(
         tx,
         cache_args,
         tx_hash,
         rpc_position,
+        cache_status,
         id,
         con_params,
         params.ttl,
-        params.max_retries
+        params.max_retries,
+        params.json_limits,
+        pinned_rpc,
+        request_checksum,
+        cache_control,
+        params.method_filter,
+        params.stale_serve
     );
+    let upstream_latency = rpc_position.map(|_| fetch_start.elapsed());
+
+    if let Some(forwarded_tx) = forwarded_tx {
+        for sampled in mirrors {
+            tokio::spawn(mirror(
+                sampled,
+                forwarded_tx.clone(),
+                rax.clone(),
+                params.ttl,
+            ));
+        }
+
+        // Cached responses could be from any RPC
+        let peers = rpc_position.filter(|_| diffed).and_then(|rpc_position| {
+            let rpc_list = con_params
+                .rpc_list
+                .read()
+                .unwrap_or_else(|e| e.into_inner());
+            pick_peer(
+                &rpc_list,
+                rpc_position,
+                forwarded_tx["method"].as_str().unwrap_or_default(),
+            )
+        });
+        if let Some((served, peer)) = peers {
+            tokio::spawn(compare_with_peer(
+                forwarded_tx,
+                served,
+                rax.clone(),
+                peer,
+                params.response_diff.clone(),
+                params.ttl,
+            ));
+        }
+    }
+
+    // Charge the RPC that served the call, if any
+    if let Some(rpc_position) = rpc_position {
+        let rpc_list = con_params
+            .rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        if let Some(rpc) = rpc_list.get(rpc_position) {
+            charge_upstream(rpc, units);
+        }
+    }
+
+    // Put back the transactions we have and let the client know what it got
+    if let Some(downgrade) = downgrade {
+        rax = downgrade.complete(rax, &cache_args).await;
+    }
+
+    let result = match jsonrpc_error_codes(&rax).is_empty() {
+        true => "ok",
+        false => "error",
+    };
+    metrics::counter!("requests_total", "method" => method.clone(), "result" => result)
+        .increment(1);
+    metrics::histogram!("request_duration_secs", "method" => method.clone())
+        .record(start.elapsed().as_secs_f64());
+
+    if let Some(params_hash) = params_hash {
+        let rpc_name = rpc_position.and_then(|rpc_position| {
+            con_params
+                .rpc_list
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(rpc_position)
+                .map(|rpc| rpc.name.clone())
+        });
+        log_slow_request(
+            &params.slow_requests,
+            SlowRequest {
+                method: &method,
+                params_hash: &params_hash,
+                rpc: rpc_name.as_deref(),
+                cache: cache_status,
+                latency: start.elapsed(),
+                upstream_latency,
+            },
+        );
+    }
+
+    // Hide the size and timing of responses to sensitive methods
+    if private {
+        rax = params.privacy.pad(rax);
+        tokio::time::sleep(params.privacy.delay(start.elapsed())).await;
+    }
+
+    // Build the response
+    let mut res = hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*");
+
+    // Checksum exactly what we serve, so it can be compared with what the RPC sent
+    if let Some(request_checksum) = request_checksum {
+        let content_checksum = content_checksum(&rax).unwrap_or_default();
+        let response_checksum = checksum(rax.as_bytes());
+        tracing::info!(
+            %request_checksum,
+            %content_checksum,
+            %response_checksum,
+            cached = rpc_position.is_none(),
+            "Serving response"
+        );
+
+        res = res
+            .header(REQUEST_CHECKSUM_HEADER, request_checksum)
+            .header(CONTENT_CHECKSUM_HEADER, content_checksum)
+            .header(RESPONSE_CHECKSUM_HEADER, response_checksum);
+    }
 
     // Convert rx to bytes and but it in a Buf
     let body = hyper::body::Bytes::from(rax);
 
     // Put it in a http_body_util::Full
-    let body = Full::new(body);
+    let res = res.body(Full::new(body)).unwrap();
 
-    // Build the response
+    (Ok(res), rpc_position)
+}
+
+/// Forward a single batch item and return its response, along with the name
+/// of the RPC that served it.
+async fn forward_batch_item<K, V>(
+    item: Value,
+    con_params: &ConnectionParams,
+    cache_args: CacheArgs<K, V>,
+    params: RequestParams,
+    pinned_rpc: Option<String>,
+) -> (Value, Option<String>)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let id = item.get("id").cloned().unwrap_or(Value::Null);
+    if !item.is_object() {
+        return (error_response(id, -32600, "invalid request"), None);
+    }
+
+    let time = Instant::now();
+    let json_limits = params.json_limits;
+    let (response, rpc_position) =
+        forward_request(item, con_params, cache_args, params, pinned_rpc.as_deref()).await;
+
+    let rpc_name = rpc_position.and_then(|rpc_position| {
+        update_rpc_latency(&con_params.rpc_list, rpc_position, time.elapsed());
+        con_params
+            .rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(rpc_position)
+            .map(|rpc| rpc.name.clone())
+    });
+
+    (response_value(response, id, &json_limits).await, rpc_name)
+}
+
+/// Read the JSON-RPC response in `response`, and give it back its `id`.
+///
+/// Responses exceeding `json_limits` were passed through as is, and can't be
+/// put in a batch without parsing them, so they're answered with an error.
+async fn response_value(
+    response: Result<hyper::Response<Full<Bytes>>, Infallible>,
+    id: Value,
+    json_limits: &JsonLimits,
+) -> Value {
+    let body = match response {
+        Ok(response) => {
+            match response.into_body().collect().await {
+                Ok(body) => body.to_bytes(),
+                Err(never) => match never {},
+            }
+        }
+        Err(never) => match never {},
+    };
+
+    if let Err(err) = json_limits.check(&body) {
+        return error_response(
+            id,
+            -32006,
+            format!("response too large for a batch: {}", err),
+        );
+    }
+
+    // Errors that aren't JSON-RPC responses get wrapped in one
+    let mut response = match serde_json::from_slice::<Value>(&body) {
+        Ok(response) if response.is_object() => response,
+        _ => error_response(id.clone(), -32000, String::from_utf8_lossy(&body)),
+    };
+    // Restore the original id, which might not have been a number
+    response["id"] = id;
+
+    response
+}
+
+/// Route a batch item like a request sent on its own, with its own cache
+/// control and through the log cache if it's a log query.
+///
+/// Items pinned to an RPC skip the log cache, which mixes logs from every RPC,
+/// but are still fetched in chunks if they span too many blocks.
+async fn route_batch_item<K, V>(
+    mut item: Value,
+    con_params: &ConnectionParams,
+    cache_args: CacheArgs<K, V>,
+    mut params: RequestParams,
+    pinned_rpc: Option<String>,
+) -> (Value, Option<String>)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    params.cache_control = params
+        .cache_control
+        .merge(CacheControl::take(&mut item).allowed(params.allow_no_cache));
+    if !params.method_filter.check(item["method"].as_str())
+        || !con_params.allows_method(item["method"].as_str())
+    {
+        let id = item.get("id").cloned().unwrap_or(Value::Null);
+        let method = item["method"].as_str().unwrap_or_default();
+        return (
+            error_response(id, -32601, format!("method not allowed: {}", method)),
+            None,
+        );
+    }
+    let log_limits = params
+        .log_limits
+        .check(&item, &params.block_numbers(&cache_args.named_numbers));
+    if let Err(err) = log_limits {
+        let id = item.get("id").cloned().unwrap_or(Value::Null);
+        return (
+            error_response(id, -32602, format!("invalid params: {}", err)),
+            None,
+        );
+    }
+
+    let log_query = match &pinned_rpc {
+        Some(_) => {
+            LogQuery::parse(&item, &params.block_numbers(&cache_args.named_numbers))
+                .filter(|query| params.log_limits.needs_chunking(query))
+        }
+        None => routed_log_query(&item, &cache_args, &params),
+    };
+
+    match log_query {
+        Some(query) => {
+            let id = item.get("id").cloned().unwrap_or(Value::Null);
+            let json_limits = params.json_limits;
+            let (response, _) = forward_logs(
+                item,
+                query,
+                con_params,
+                cache_args,
+                params,
+                pinned_rpc.clone(),
+            )
+            .await;
+            // Chunks went to the pinned RPC, so items depending on this one still can
+            (response_value(response, id, &json_limits).await, pinned_rpc)
+        }
+        None => forward_batch_item(item, con_params, cache_args, params, pinned_rpc).await,
+    }
+}
+
+/// Forward the items of a JSON-RPC batch and respond with an array of their responses.
+///
+/// Independent items are forwarded concurrently, up to `max_batch_concurrency`
+/// at a time, each routed on its own. Items with a dependency hint wait for the
+/// item they depend on, and go to the same RPC. See `batch` for details.
+async fn forward_batch<K, V>(
+    mut items: Vec<Value>,
+    con_params: &ConnectionParams,
+    cache_args: CacheArgs<K, V>,
+    params: RequestParams,
+) -> (
+    Result<hyper::Response<Full<Bytes>>, Infallible>,
+    Option<usize>,
+)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let start = Instant::now();
+    // Padding the items on their own is undone by putting them in the batch
+    let private = params.privacy.applies_to_batch(&items);
+
+    let body = if items.is_empty() {
+        Some(error_response(
+            Value::Null,
+            -32600,
+            "invalid request: empty batch",
+        ))
+    } else {
+        let dependencies = take_dependencies(&mut items);
+        let mut responses: Vec<Value> = vec![Value::Null; items.len()];
+        let mut served_by: Vec<Option<String>> = vec![None; items.len()];
+
+        for wave in waves(&dependencies) {
+            let wave_responses: Vec<_> = stream::iter(wave.iter().map(|&index| {
+                let item = items[index].clone();
+                let dependency = dependencies[index];
+                let answer = dependency.and_then(|dependency| {
+                    answer_from(&items[dependency], &responses[dependency], &item)
+                });
+                let pinned_rpc = dependency.and_then(|dependency| served_by[dependency].clone());
+                let cache_args = cache_args.clone();
+                let params = params.clone();
+
+                async move {
+                    match answer {
+                        Some(answer) => (answer, pinned_rpc),
+                        None => {
+                            route_batch_item(item, con_params, cache_args, params, pinned_rpc).await
+                        }
+                    }
+                }
+            }))
+            // In order, so responses line up with the wave
+            .buffered(params.request_limits.batch_concurrency())
+            .collect()
+            .await;
+
+            for (&index, (response, rpc_name)) in wave.iter().zip(wave_responses) {
+                responses[index] = response;
+                served_by[index] = rpc_name;
+            }
+        }
+
+        batch_body(&items, responses)
+    };
+
+    // Nothing goes back for a batch of notifications
+    let mut body = body.map(|body| body.to_string()).unwrap_or_default();
+
+    // Hide the size and timing of batches with sensitive methods
+    if private {
+        body = params.privacy.pad(body);
+        tokio::time::sleep(params.privacy.delay(start.elapsed())).await;
+    }
     let res = hyper::Response::builder()
         .status(200)
         .header("Content-Type", "application/json")
         .header("Access-Control-Allow-Origin", "*")
-        .body(body)
+        .body(Full::new(Bytes::from(body)))
         .unwrap();
 
-    (Ok(res), rpc_position)
+    // RPC latencies are updated per item
+    (Ok(res), None)
 }
 
 /// Forward the request to *a* RPC picked by the algo set by the user.
@@ -365,18 +1436,101 @@ where
 /// In case of a timeout, returns an error.
 pub async fn accept_request<K, V>(
     mut tx: Request<hyper::body::Incoming>,
-    connection_params: ConnectionParams,
+    mut connection_params: ConnectionParams,
     cache_args: CacheArgs<K, V>,
 ) -> Result<hyper::Response<Full<Bytes>>, Infallible>
 where
     K: GenericBytes + From<[u8; 32]> + 'static,
     V: GenericBytes + From<Vec<u8>> + 'static,
 {
+    // Answer CORS preflights ourselves, and remember where the request is from
+    let (cors, compression, rate_limit, api_keys) = {
+        let config_guard = connection_params.config.read().unwrap();
+        // Compressing padded responses would undo the padding
+        let compression = (config_guard.privacy.pad_bucket_bytes == 0)
+            .then(|| config_guard.response_compression.clone());
+        (
+            config_guard.cors.clone(),
+            compression,
+            config_guard.rate_limit.clone(),
+            config_guard.api_keys.clone(),
+        )
+    };
+    if cors.is_preflight(&tx) {
+        return Ok(cors.preflight(tx.headers()));
+    }
+    let origin = tx.headers().get(ORIGIN).cloned();
+    let accept_encoding = tx.headers().get(ACCEPT_ENCODING).cloned();
+
+    // Turn away clients without a valid key, or over their rate limit or their key's
+    let api_key = match api_keys.authenticate(tx.headers(), tx.uri().path()) {
+        Ok(api_key) => api_key,
+        Err(reason) => {
+            metrics::counter!("unauthorized_total").increment(1);
+            let response: Result<hyper::Response<Full<Bytes>>, Infallible> = unauthorized!(reason);
+            let mut response = match response {
+                Ok(response) => response,
+                Err(never) => match never {},
+            };
+            cors.apply(origin.as_ref(), response.headers_mut());
+            return Ok(response);
+        }
+    };
+    let client_ip = rate_limit.client_ip(connection_params.peer, tx.headers());
+    connection_params.limiter = connection_params
+        .counters
+        .as_ref()
+        .map(|counters| CallLimiter::new(counters, &rate_limit, api_key.as_ref(), client_ip));
+    // Every request counts as a call, including the ones opening WebSockets
+    let limited = match &connection_params.limiter {
+        Some(limiter) => limiter.charge(1).await,
+        None => Ok(()),
+    };
+    if let Err((retry_after, message)) = limited {
+        let response: Result<hyper::Response<Full<Bytes>>, Infallible> =
+            rate_limited!(retry_after, message);
+        let mut response = match response {
+            Ok(response) => response,
+            Err(never) => match never {},
+        };
+        cors.apply(origin.as_ref(), response.headers_mut());
+        return Ok(response);
+    }
+    connection_params.api_key = api_key;
+    connection_params.client_ip = client_ip;
+    connection_params.engine_authorized = tx.headers().contains_key(AUTHORIZATION) && {
+        let rpc_list = connection_params
+            .rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        engine_authorized(tx.headers(), &rpc_list)
+    };
+
     // Check if the request is a websocket upgrade request.
     if is_upgrade_request(&tx) {
         tracing::info!("Received WS upgrade request");
 
-        if !connection_params.config.read().unwrap().is_ws {
+        let (
+            is_ws,
+            ws_settings,
+            method_filter,
+            local_methods,
+            log_limits,
+            request_limits,
+            compute_units,
+        ) = {
+            let config_guard = connection_params.config.read().unwrap();
+            (
+                config_guard.is_ws,
+                config_guard.ws_connection,
+                config_guard.method_filter.clone(),
+                connection_params.local_methods(config_guard.local_methods),
+                config_guard.log_limits,
+                config_guard.request_limits,
+                config_guard.compute_units.clone(),
+            )
+        };
+        if !is_ws {
             return rpc_response!(
                 500,
                 Full::new(Bytes::from(
@@ -385,7 +1539,36 @@ where
             );
         }
 
-        let (response, websocket) = match upgrade(&mut tx, None) {
+        // Calls over WebSockets aren't checked one by one
+        if connection_params
+            .api_key
+            .as_ref()
+            .is_some_and(|api_key| api_key.is_restricted())
+        {
+            return rpc_response!(
+                403,
+                Full::new(Bytes::from(
+                    "{code:-32007, message:\"error: API key can't open WebSockets!\"}".to_string(),
+                ))
+            );
+        }
+
+        if connection_params.ws_connections.is_draining() {
+            return rpc_response!(
+                503,
+                Full::new(Bytes::from(
+                    "{code:-32005, message:\"error: Shutting down, not accepting WebSockets!\"}"
+                        .to_string(),
+                ))
+            );
+        }
+
+        let ws_config = WebSocketConfig {
+            max_message_size: Some(ws_settings.max_message_size),
+            max_frame_size: Some(ws_settings.max_message_size),
+            ..Default::default()
+        };
+        let (response, websocket) = match upgrade(&mut tx, Some(ws_config)) {
             Ok((response, websocket)) => (response, websocket),
             Err(e) => {
                 tracing::error!(?e, "Websocket upgrade error");
@@ -397,13 +1580,29 @@ where
         };
 
         // Spawn a task to handle the websocket connection.
+        let connection = connection_params
+            .ws_connections
+            .open(connection_params.peer);
+        // The connection is still open for as long as the WebSocket is
+        let permit = connection_params.permit.clone();
         tokio::task::spawn(async move {
+            let _permit = permit;
             if let Err(e) = serve_websocket(
                 websocket,
                 connection_params.channels.incoming_tx,
                 connection_params.channels.outgoing_rx,
                 connection_params.sub_data.clone(),
                 cache_args.to_owned(),
+                connection_params.heatmap.clone(),
+                connection,
+                ws_settings,
+                method_filter,
+                local_methods,
+                log_limits,
+                request_limits,
+                connection_params.engine_authorized,
+                compute_units,
+                connection_params.limiter,
             )
             .await
             {
@@ -418,6 +1617,7 @@ where
     // Send request
     let response: Result<hyper::Response<Full<Bytes>>, Infallible>;
     let rpc_position: Option<usize>;
+    let upstream_body: Option<UpstreamBody>;
 
     // RequestParams from config
     let params = {
@@ -426,6 +1626,28 @@ where
             ttl: config_guard.ttl,
             max_retries: config_guard.max_retries,
             header_check: config_guard.header_check,
+            validate_requests: config_guard.validate_requests,
+            debug_checksums: config_guard.debug_checksums,
+            allow_no_cache: config_guard.allow_no_cache,
+            json_limits: config_guard.json_limits,
+            request_limits: config_guard.request_limits,
+            log_limits: config_guard.log_limits,
+            privacy: config_guard.privacy.clone(),
+            downgrade: config_guard.downgrade.clone(),
+            method_filter: config_guard.method_filter.clone(),
+            compute_units: config_guard.compute_units.clone(),
+            response_diff: config_guard.response_diff.clone(),
+            stale_serve: config_guard.stale_serve,
+            local_methods: config_guard.local_methods,
+            slow_requests: config_guard.slow_requests,
+            cache_control: CacheControl::default(),
+            // Once for the whole request, so batch items and retries see the same blocks
+            pinned_tags: config_guard.pin_block_tags.then(|| {
+                connection_params.block_tags(
+                    &cache_args.named_numbers,
+                    config_guard.local_methods.max_head_age_ms,
+                )
+            }),
         }
     };
 
@@ -434,10 +1656,14 @@ where
     //
     // Also handle cache insertions.
     let time = Instant::now();
-    (response, rpc_position) = forward_body(tx, &connection_params, cache_args, params).await;
+    let span = request_span(tx.headers(), connection_params.peer);
+    ((response, rpc_position), upstream_body) = with_upstream_body(
+        forward_body(tx, &connection_params, cache_args, params).instrument(span.clone()),
+    )
+    .await;
 
     let time = time.elapsed();
-    tracing::info!(?time, "Request time");
+    span.in_scope(|| tracing::info!(latency_ms = time.as_secs_f64() * 1000.0, "Request served"));
 
     // `rpc_position` is an Option<> that either contains the index of the RPC
     // we forwarded our request to, or is None if the result was cached.
@@ -448,5 +1674,16 @@ where
         update_rpc_latency(&connection_params.rpc_list, rpc_position, time);
     }
 
-    response
+    let mut response = match response {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+    cors.apply(origin.as_ref(), response.headers_mut());
+    if let Some(compression) = compression {
+        response = compression
+            .compress(accept_encoding.as_ref(), response, upstream_body)
+            .await;
+    }
+
+    Ok(response)
 }
@@ -0,0 +1,399 @@
+//! Certificates from an ACME CA, like Let's Encrypt.
+//!
+//! With `domains` set in `[blutgang.tls.acme]`, Blutgang gets a certificate for
+//! them on its own, and renews it `renew_before_days` before it expires. The
+//! domains are validated with TLS-ALPN-01: the CA connects to port 443 of each
+//! domain and expects a challenge certificate, which the listener serves to it
+//! while the order is pending. No other port has to be open.
+//!
+//! The account and the certificate are kept in `cache_dir`, so restarts reuse
+//! them. The account and the certificate key are only readable by us. Until the first certificate is issued, a self-signed one is served.
+//! Failed orders are retried every `RETRY_INTERVAL`, while the current
+//! certificate keeps being served.
+
+use crate::{
+    balancer::tls::{
+        certified_key_from_pem,
+        CertResolver,
+        TlsError,
+    },
+    config::types::AcmeSettings,
+};
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use instant_acme::{
+    Account,
+    AccountCredentials,
+    AuthorizationStatus,
+    ChallengeType,
+    Identifier,
+    NewAccount,
+    NewOrder,
+    OrderStatus,
+};
+use rcgen::{
+    CertificateParams,
+    CustomExtension,
+    DistinguishedName,
+    KeyPair,
+};
+use rust_tracing::deps::metrics;
+use tokio::time::sleep;
+use tokio_rustls::rustls::sign::CertifiedKey;
+
+const ACCOUNT_FILE: &str = "account.json";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Time between checks of whether the certificate needs renewing.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Time before retrying a failed order.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Max times the order is polled while the CA validates it.
+const MAX_POLLS: u32 = 10;
+/// Max times we ask for the certificate once the order is finalized, a second apart.
+const MAX_CERTIFICATE_POLLS: u32 = 60;
+/// Permissions of files with secrets in them, the account and the key.
+const SECRET_MODE: u32 = 0o600;
+/// Permissions of the certificate.
+const CERT_MODE: u32 = 0o644;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AcmeError {
+    #[error(transparent)]
+    Acme(#[from] instant_acme::Error),
+
+    #[error(transparent)]
+    Certificate(#[from] rcgen::Error),
+
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error("failed to write {path}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+
+    #[error("no TLS-ALPN-01 challenge offered for {0}")]
+    NoChallenge(String),
+
+    #[error("order failed: {0}")]
+    Order(String),
+}
+
+/// Write `contents` to `path` with `mode` permissions on unix.
+fn write(path: &Path, contents: &[u8], mode: u32) -> Result<(), AcmeError> {
+    let write = || {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{
+                OpenOptionsExt,
+                PermissionsExt,
+            };
+
+            options.mode(mode);
+            let mut file = options.open(path)?;
+            // The mode only applies to files we create
+            file.set_permissions(std::fs::Permissions::from_mode(mode))?;
+            file.write_all(contents)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = mode;
+            options.open(path)?.write_all(contents)
+        }
+    };
+    write().map_err(|err| {
+        AcmeError::Io {
+            path: path.to_path_buf(),
+            err,
+        }
+    })
+}
+
+fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+/// Returns when the first certificate in `pem` expires, in seconds since the epoch.
+fn expires_at(pem: &[u8]) -> Option<i64> {
+    let (_, pem) = x509_parser::pem::parse_x509_pem(pem).ok()?;
+    let cert = pem.parse_x509().ok()?;
+    Some(cert.validity().not_after.timestamp())
+}
+
+/// Returns true if a certificate expiring at `expires_at` has to be renewed now.
+fn needs_renewal(expires_at: Option<i64>, renew_before_days: u64, now: i64) -> bool {
+    match expires_at {
+        Some(expires_at) => now >= expires_at - renew_before_days as i64 * 24 * 60 * 60,
+        None => true,
+    }
+}
+
+/// Returns the cached certificate, or a self-signed one for `domains` if
+/// there's none yet.
+pub fn initial_certified_key(settings: &AcmeSettings) -> Result<CertifiedKey, AcmeError> {
+    let cert = std::fs::read(settings.cache_dir.join(CERT_FILE));
+    let key = std::fs::read(settings.cache_dir.join(KEY_FILE));
+    if let (Ok(cert), Ok(key)) = (cert, key) {
+        match certified_key_from_pem(&cert, &key, CERT_FILE) {
+            Ok(certified_key) => return Ok(certified_key),
+            Err(err) => tracing::warn!(%err, "Ignoring cached ACME certificate"),
+        }
+    }
+
+    let key_pair = KeyPair::generate()?;
+    let cert = CertificateParams::new(settings.domains.clone())?.self_signed(&key_pair)?;
+    Ok(certified_key_from_pem(
+        cert.pem().as_bytes(),
+        key_pair.serialize_pem().as_bytes(),
+        "self-signed certificate",
+    )?)
+}
+
+/// Certificate answering the TLS-ALPN-01 challenge for `domain`.
+fn challenge_certified_key(domain: &str, digest: &[u8]) -> Result<CertifiedKey, AcmeError> {
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = params.self_signed(&key_pair)?;
+    Ok(certified_key_from_pem(
+        cert.pem().as_bytes(),
+        key_pair.serialize_pem().as_bytes(),
+        "challenge certificate",
+    )?)
+}
+
+/// Load the cached account, or create one.
+async fn account(settings: &AcmeSettings) -> Result<Account, AcmeError> {
+    let path = settings.cache_dir.join(ACCOUNT_FILE);
+    if let Ok(credentials) = std::fs::read_to_string(&path) {
+        let credentials: AccountCredentials = serde_json::from_str(&credentials)?;
+        return Ok(Account::from_credentials(credentials).await?);
+    }
+
+    let contact: Vec<&str> = settings.contact.iter().map(String::as_str).collect();
+    let (account, credentials) = Account::create(
+        &NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: true,
+            only_return_existing: false,
+        },
+        &settings.directory,
+        None,
+    )
+    .await?;
+    write(
+        &path,
+        serde_json::to_string(&credentials)?.as_bytes(),
+        SECRET_MODE,
+    )?;
+    tracing::info!(directory = settings.directory, "Created ACME account");
+
+    Ok(account)
+}
+
+/// Order a certificate for `domains`, answering the challenges through
+/// `resolver`. Returns the PEM certificate chain and private key.
+async fn order_certificate(
+    settings: &AcmeSettings,
+    resolver: &CertResolver,
+) -> Result<(String, String), AcmeError> {
+    let account = account(settings).await?;
+    let identifiers: Vec<Identifier> = settings
+        .domains
+        .iter()
+        .cloned()
+        .map(Identifier::Dns)
+        .collect();
+    let mut order = account
+        .new_order(&NewOrder {
+            identifiers: &identifiers,
+        })
+        .await?;
+
+    let mut ready = Vec::new();
+    for authorization in order.authorizations().await? {
+        match authorization.status {
+            AuthorizationStatus::Pending => {}
+            AuthorizationStatus::Valid => continue,
+            status => return Err(AcmeError::Order(format!("authorization is {status:?}"))),
+        }
+
+        let Identifier::Dns(domain) = &authorization.identifier;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|challenge| challenge.r#type == ChallengeType::TlsAlpn01)
+            .ok_or_else(|| AcmeError::NoChallenge(domain.clone()))?;
+
+        let key_authorization = order.key_authorization(challenge);
+        resolver.add_challenge(
+            domain,
+            challenge_certified_key(domain, key_authorization.digest().as_ref())?,
+        );
+        ready.push(challenge.url.clone());
+    }
+
+    for url in &ready {
+        order.set_challenge_ready(url).await?;
+    }
+
+    // Wait for the CA to validate every domain
+    let mut delay = Duration::from_millis(500);
+    let mut polls = 0;
+    let (ready, status) = loop {
+        sleep(delay).await;
+        let state = order.refresh().await?;
+        polls += 1;
+        if !matches!(state.status, OrderStatus::Pending) || polls == MAX_POLLS {
+            break (
+                matches!(state.status, OrderStatus::Ready),
+                format!("{:?}", state.status),
+            );
+        }
+        delay *= 2;
+    };
+    resolver.clear_challenges();
+    if !ready {
+        return Err(AcmeError::Order(format!("order is {status}")));
+    }
+
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(settings.domains.clone())?;
+    params.distinguished_name = DistinguishedName::new();
+    let csr = params.serialize_request(&key_pair)?;
+    order.finalize(csr.der()).await?;
+
+    let mut polls = 0;
+    let chain = loop {
+        match order.certificate().await? {
+            Some(chain) => break chain,
+            None if polls == MAX_CERTIFICATE_POLLS => {
+                return Err(AcmeError::Order(
+                    "certificate wasn't issued in time".to_string(),
+                ));
+            }
+            None => sleep(Duration::from_secs(1)).await,
+        }
+        polls += 1;
+    };
+
+    Ok((chain, key_pair.serialize_pem()))
+}
+
+/// Get a certificate, and renew it whenever it's about to expire.
+pub async fn manage_certificates(settings: AcmeSettings, resolver: Arc<CertResolver>) {
+    let cert_path = settings.cache_dir.join(CERT_FILE);
+    loop {
+        let expires_at = std::fs::read(&cert_path)
+            .ok()
+            .and_then(|pem| expires_at(&pem));
+        if !needs_renewal(expires_at, settings.renew_before_days, now_secs()) {
+            sleep(CHECK_INTERVAL).await;
+            continue;
+        }
+
+        tracing::info!(domains = ?settings.domains, "Ordering certificate from ACME CA");
+        let issued = order_certificate(&settings, &resolver)
+            .await
+            .and_then(|(chain, key)| {
+                let certified_key =
+                    certified_key_from_pem(chain.as_bytes(), key.as_bytes(), "issued certificate")?;
+                // Key first, so the cached pair is never a new cert with an old key
+                write(
+                    &settings.cache_dir.join(KEY_FILE),
+                    key.as_bytes(),
+                    SECRET_MODE,
+                )?;
+                write(&cert_path, chain.as_bytes(), CERT_MODE)?;
+                Ok(certified_key)
+            });
+
+        match issued {
+            Ok(certified_key) => {
+                resolver.set(certified_key);
+                tracing::info!(domains = ?settings.domains, "Got certificate from ACME CA");
+                metrics::counter!("acme_orders_total", "status" => "ok").increment(1);
+            }
+            Err(err) => {
+                resolver.clear_challenges();
+                tracing::error!(%err, "Failed to get certificate from ACME CA! Retrying later.");
+                metrics::counter!("acme_orders_total", "status" => "failed").increment(1);
+                sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_needs_renewal() {
+        let day = 24 * 60 * 60;
+        assert!(needs_renewal(None, 30, 0));
+        assert!(!needs_renewal(Some(31 * day), 30, 0));
+        assert!(needs_renewal(Some(30 * day), 30, 0));
+        assert!(needs_renewal(Some(0), 0, day));
+    }
+
+    #[test]
+    fn test_initial_certified_key() {
+        let settings = AcmeSettings {
+            domains: vec!["rpc.example.com".to_string()],
+            cache_dir: std::env::temp_dir().join("blutgang-acme-surely-missing"),
+            ..Default::default()
+        };
+        let certified_key = initial_certified_key(&settings).unwrap();
+        assert_eq!(certified_key.cert.len(), 1);
+
+        let key_pair = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(settings.domains.clone())
+            .unwrap()
+            .self_signed(&key_pair)
+            .unwrap();
+        assert!(expires_at(cert.pem().as_bytes()).unwrap() > now_secs());
+        assert!(expires_at(b"not a certificate").is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("blutgang-acme-{}", std::process::id()));
+        std::fs::write(&path, b"old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write(&path, b"secret", SECRET_MODE).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, SECRET_MODE);
+        assert_eq!(std::fs::read(&path).unwrap(), b"secret");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
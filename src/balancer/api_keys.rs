@@ -0,0 +1,351 @@
+//! API keys for clients.
+//!
+//! With `keys` set in `[blutgang.api_keys]`, clients can send a key in the
+//! `header` header, or as the path of their requests, like `POST /<key>`. Only
+//! paths that look like keys count, other paths are served as if there was no
+//! key. Each key has its own:
+//!
+//! - rate limit, applied instead of the per-IP one (see `rate_limit`),
+//! - quota of `quota` requests every `quota_window_ms`,
+//! - budget of `compute_units` every `quota_window_ms` (see `compute_units`),
+//! - `methods` it can call, with `eth_*` style wildcards,
+//! - route `groups`, only RPCs in at least one of them get its requests.
+//!
+//! Limits and quotas are counted in the counter store once per call, like rate
+//! limits. Keys are only kept as hashes, compared in constant time against
+//! every configured key like admin tokens.
+//!
+//! Requests with an unknown key are turned away, and so are requests without
+//! one if `required` is set. Calls over WebSockets aren't checked against
+//! methods and groups one by one, so keys limited to them can't open them.
+
+use crate::{
+    balancer::{
+        method_filter::method_matches,
+        rate_limit::{
+            count_units,
+            take_calls,
+            RequestCounters,
+        },
+    },
+    config::{
+        headers::resolve_secret,
+        types::{
+            ApiKeySettings,
+            RateLimitSettings,
+        },
+    },
+};
+
+use std::{
+    fmt,
+    sync::Arc,
+};
+
+use hyper::header::HeaderMap;
+use serde::{
+    de,
+    Deserialize,
+    Deserializer,
+};
+
+/// Read `key` like a secret, and keep only its hash.
+fn hash_key<'de, D>(deserializer: D) -> Result<blake3::Hash, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let key = toml::Value::deserialize(deserializer)?;
+    let key = resolve_secret("key", &key).map_err(de::Error::custom)?;
+    if key.is_empty() {
+        return Err(de::Error::custom("API key is empty"));
+    }
+    Ok(blake3::hash(key.as_bytes()))
+}
+
+/// Shortest path taken for a key, see `looks_like_key`.
+const MIN_PATH_KEY_LEN: usize = 16;
+
+/// Returns true if `path` is a single segment of at least `MIN_PATH_KEY_LEN`
+/// URL-safe characters, like the keys we hand out.
+fn looks_like_key(path: &str) -> bool {
+    path.len() >= MIN_PATH_KEY_LEN
+        && path
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~'))
+}
+
+fn default_quota_window_ms() -> u64 {
+    86_400_000
+}
+
+/// An API key from the config, and what it allows.
+#[derive(Clone, Deserialize)]
+pub struct ApiKey {
+    /// Shows up in logs and metrics instead of the key.
+    pub name: String,
+    #[serde(rename = "key", deserialize_with = "hash_key")]
+    hash: blake3::Hash,
+    /// Requests per second. `0` disables the limit.
+    #[serde(default)]
+    pub requests_per_second: u64,
+    /// Requests at once. `0` allows `requests_per_second`.
+    #[serde(default)]
+    pub burst: u64,
+    /// Requests every `quota_window_ms`. `0` disables the quota.
+    #[serde(default)]
+    pub quota: u64,
+    #[serde(default = "default_quota_window_ms")]
+    pub quota_window_ms: u64,
+    /// Compute units every `quota_window_ms`. `0` disables the budget.
+    #[serde(default)]
+    pub compute_units: u64,
+    /// Methods the key can call, every method if empty.
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Route groups requests go to, see `Route`. Every RPC if empty.
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ApiKey")
+            .field("name", &self.name)
+            .field("key", &"HIDDEN")
+            .field("requests_per_second", &self.requests_per_second)
+            .field("burst", &self.burst)
+            .field("quota", &self.quota)
+            .field("quota_window_ms", &self.quota_window_ms)
+            .field("compute_units", &self.compute_units)
+            .field("methods", &self.methods)
+            .field("groups", &self.groups)
+            .finish()
+    }
+}
+
+impl ApiKey {
+    /// Returns true if the key can call `method`.
+    pub fn allows_method(&self, method: Option<&str>) -> bool {
+        if self.methods.is_empty() {
+            return true;
+        }
+        let Some(method) = method else {
+            return false;
+        };
+        self.methods
+            .iter()
+            .any(|pattern| method_matches(pattern, method))
+    }
+
+    /// Returns true if calls with this key have to be checked one by one.
+    pub fn is_restricted(&self) -> bool {
+        !self.methods.is_empty() || !self.groups.is_empty()
+    }
+
+    /// Count `calls` calls with this key against its rate limit.
+    ///
+    /// Returns the seconds until the client can retry if it's over it.
+    pub async fn check_rate_limit(
+        &self,
+        counters: &RequestCounters,
+        calls: u64,
+    ) -> Result<(), u64> {
+        if self.requests_per_second == 0 {
+            return Ok(());
+        }
+        let settings = RateLimitSettings {
+            requests_per_second: self.requests_per_second,
+            burst: self.burst,
+            ..Default::default()
+        };
+        take_calls(
+            counters,
+            &format!("key:{}", self.name),
+            calls,
+            settings.requests_per_second,
+            settings.burst(),
+            "key",
+        )
+        .await
+    }
+
+    /// Count `calls` calls with this key against its quota.
+    ///
+    /// Returns the seconds until the quota resets if it's used up.
+    pub async fn check_quota(&self, counters: &RequestCounters, calls: u64) -> Result<(), u64> {
+        if self.quota == 0 {
+            return Ok(());
+        }
+        count_units(
+            counters,
+            &format!("quota:{}", self.name),
+            calls,
+            self.quota,
+            self.quota_window_ms,
+            "quota",
+        )
+        .await
+    }
+}
+
+impl ApiKeySettings {
+    pub fn enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Key sent in `headers`, or as `path` if it looks like one.
+    fn presented<'a>(&self, headers: &'a HeaderMap, path: &'a str) -> Option<&'a str> {
+        let key = match headers.get(self.header.as_str()) {
+            Some(key) => key.to_str().ok()?,
+            None => Some(path.trim_matches('/')).filter(|path| looks_like_key(path))?,
+        };
+        (!key.is_empty()).then_some(key)
+    }
+
+    /// Find the key a request with `headers` to `path` was sent with.
+    ///
+    /// Returns `None` for requests without a key, and why the request has to
+    /// be turned away if it can't be served.
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        path: &str,
+    ) -> Result<Option<Arc<ApiKey>>, &'static str> {
+        if !self.enabled() {
+            return Ok(None);
+        }
+
+        let Some(presented) = self.presented(headers, path) else {
+            return match self.required {
+                true => Err("missing API key"),
+                false => Ok(None),
+            };
+        };
+
+        let hash = blake3::hash(presented.as_bytes());
+        // No short-circuiting, every key gets compared
+        self.keys
+            .iter()
+            .fold(None, |found, key| {
+                if key.hash == hash {
+                    Some(key.clone())
+                } else {
+                    found
+                }
+            })
+            .map(Some)
+            .ok_or("invalid API key")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::counters::MemoryCounters;
+    use hyper::header::HeaderValue;
+
+    fn settings() -> ApiKeySettings {
+        toml::from_str(
+            r#"
+            required = true
+
+            [[keys]]
+            name = "indexers"
+            key = "indexer-key"
+            quota = 2
+            methods = ["eth_getLogs", "eth_get*"]
+            groups = ["archive"]
+
+            [[keys]]
+            name = "wallets"
+            key = "wallet-key-0123456789"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let settings = settings();
+        assert_eq!(settings.header, "x-api-key");
+        assert!(format!("{:?}", settings).contains("HIDDEN"));
+
+        let mut headers = HeaderMap::new();
+        let key = settings
+            .authenticate(&headers, "/wallet-key-0123456789")
+            .unwrap();
+        assert_eq!(key.unwrap().name, "wallets");
+
+        headers.insert("x-api-key", HeaderValue::from_static("indexer-key"));
+        let key = settings.authenticate(&headers, "/").unwrap();
+        assert_eq!(key.unwrap().name, "indexers");
+
+        headers.insert("x-api-key", HeaderValue::from_static("nonsense"));
+        assert_eq!(
+            settings.authenticate(&headers, "/").unwrap_err(),
+            "invalid API key"
+        );
+        assert_eq!(
+            settings.authenticate(&HeaderMap::new(), "/").unwrap_err(),
+            "missing API key"
+        );
+
+        // Optional keys
+        let settings = ApiKeySettings {
+            required: false,
+            ..settings
+        };
+        assert!(settings
+            .authenticate(&HeaderMap::new(), "/")
+            .unwrap()
+            .is_none());
+        assert!(settings
+            .authenticate(&HeaderMap::new(), "/nonsense-0123456789")
+            .is_err());
+        // Paths that aren't keys fall through
+        for path in ["/nonsense", "/v1/mainnet", "/health"] {
+            assert!(settings
+                .authenticate(&HeaderMap::new(), path)
+                .unwrap()
+                .is_none());
+        }
+        assert!(ApiKeySettings::default()
+            .authenticate(&headers, "/nonsense")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_allows_method() {
+        let settings = settings();
+        let (indexers, wallets) = (&settings.keys[0], &settings.keys[1]);
+
+        assert!(indexers.allows_method(Some("eth_getLogs")));
+        assert!(indexers.allows_method(Some("eth_getBalance")));
+        assert!(!indexers.allows_method(Some("eth_sendRawTransaction")));
+        assert!(!indexers.allows_method(None));
+        assert!(indexers.is_restricted());
+
+        assert!(wallets.allows_method(Some("eth_sendRawTransaction")));
+        assert!(!wallets.is_restricted());
+    }
+
+    #[tokio::test]
+    async fn test_check_quota() {
+        let settings = settings();
+        let counters = RequestCounters::Memory(MemoryCounters::default());
+
+        let indexers = &settings.keys[0];
+        assert!(indexers.check_quota(&counters, 1).await.is_ok());
+        assert!(indexers.check_quota(&counters, 1).await.is_ok());
+        let retry_after = indexers.check_quota(&counters, 1).await.unwrap_err();
+        assert!((1..=86_400).contains(&retry_after));
+
+        // No quota, no rate limit
+        let wallets = &settings.keys[1];
+        for _ in 0..10 {
+            assert!(wallets.check_quota(&counters, 1).await.is_ok());
+            assert!(wallets.check_rate_limit(&counters, 1).await.is_ok());
+        }
+    }
+}
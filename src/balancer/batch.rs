@@ -0,0 +1,255 @@
+//! JSON-RPC batches with dependency hints.
+//!
+//! Items in a batch are independent by default and get forwarded
+//! concurrently. Clients can mark an item as depending on an earlier one by
+//! setting `blutgang_dependsOn` to the id of that item:
+//!
+//! ```json
+//! [
+//!   {"jsonrpc": "2.0", "id": 1, "method": "eth_getBlockByNumber", "params": ["0x10", true]},
+//!   {"jsonrpc": "2.0", "id": 2, "method": "eth_getBlockReceipts", "params": ["0x10"], "blutgang_dependsOn": 1}
+//! ]
+//! ```
+//!
+//! Dependent items are forwarded after the item they depend on, to the same
+//! RPC that served it, so indexers don't stitch together data from nodes that
+//! disagree. If the response to the earlier item already holds what a later one
+//! asks for, like a transaction of a block fetched with full transactions, the
+//! later item is answered from it without another round trip.
+//!
+//! Every item is routed on its own, as if it was sent by itself: it can be
+//! served from the cache, carry its own `blutgang_cacheControl`, be answered
+//! from the log cache, or go to a different RPC than the rest of the batch.
+//! Responses are put back in the order of the items, with their original ids.
+//! Items without an id are notifications, which get forwarded but, as
+//! JSON-RPC has it, don't get a response.
+
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Field clients use to mark the item a batch item depends on.
+pub const DEPENDS_ON: &str = "blutgang_dependsOn";
+
+/// Remove dependency hints from `items`, returning the position of the item each item depends on.
+///
+/// Hints have to be removed before the items reach the RPCs or get hashed for
+/// the cache. Hints pointing at unknown ids, or at items that don't come
+/// earlier in the batch, are ignored.
+pub fn take_dependencies(items: &mut [Value]) -> Vec<Option<usize>> {
+    let mut dependencies = Vec::with_capacity(items.len());
+
+    for index in 0..items.len() {
+        let hint = items[index]
+            .as_object_mut()
+            .and_then(|item| item.remove(DEPENDS_ON));
+
+        let dependency = hint.and_then(|id| {
+            items[..index]
+                .iter()
+                .position(|item| item.get("id") == Some(&id))
+        });
+        dependencies.push(dependency);
+    }
+
+    dependencies
+}
+
+/// Group items into waves, where every item comes in a later wave than the item it depends on.
+///
+/// Items in the same wave can be forwarded concurrently.
+pub fn waves(dependencies: &[Option<usize>]) -> Vec<Vec<usize>> {
+    let mut depths: Vec<usize> = Vec::with_capacity(dependencies.len());
+    let mut waves: Vec<Vec<usize>> = Vec::new();
+
+    for (index, dependency) in dependencies.iter().enumerate() {
+        // Dependencies always come earlier, so their depth is already known
+        let depth = dependency.map_or(0, |dependency| depths[dependency] + 1);
+        depths.push(depth);
+
+        if waves.len() <= depth {
+            waves.resize_with(depth + 1, Vec::new);
+        }
+        waves[depth].push(index);
+    }
+
+    waves
+}
+
+/// Returns true if two hashes are the same, ignoring the case of hex digits.
+fn same_hash(a: Option<&str>, b: Option<&str>) -> bool {
+    matches!((a, b), (Some(a), Some(b)) if a.eq_ignore_ascii_case(b))
+}
+
+/// Answer `item` from `response`, the response to the item it depends on.
+///
+/// Returns `None` if `response` doesn't hold what `item` asks for.
+pub fn answer_from(dependency: &Value, response: &Value, item: &Value) -> Option<Value> {
+    let result = response.get("result")?;
+    let params = item["params"].as_array();
+    let param = |index: usize| params.and_then(|params| params.get(index));
+
+    let answer = match item["method"].as_str()? {
+        // Transaction of a block fetched with full transactions
+        "eth_getTransactionByHash" => {
+            let hash = param(0)?.as_str();
+            result["transactions"]
+                .as_array()?
+                .iter()
+                .find(|tx| same_hash(tx["hash"].as_str(), hash))?
+                .clone()
+        }
+        // Block we already have, as long as it was fetched with the same level of detail
+        "eth_getBlockByHash" => {
+            match dependency["method"].as_str()? {
+                "eth_getBlockByNumber" | "eth_getBlockByHash" => {}
+                _ => return None,
+            }
+            let full = |params: Option<&Value>| params.and_then(Value::as_bool).unwrap_or(false);
+            if full(param(1)) != full(dependency["params"].get(1)) {
+                return None;
+            }
+            if !same_hash(result["hash"].as_str(), param(0)?.as_str()) {
+                return None;
+            }
+            result.clone()
+        }
+        _ => return None,
+    };
+
+    Some(json!({
+        "jsonrpc": "2.0",
+        "id": item.get("id").cloned().unwrap_or(Value::Null),
+        "result": answer,
+    }))
+}
+
+/// Returns true if `item` is a notification, which doesn't get a response.
+pub fn is_notification(item: &Value) -> bool {
+    item.as_object()
+        .is_some_and(|item| !item.contains_key("id"))
+}
+
+/// Body of the response to a batch of `items`, given the response to every item.
+///
+/// Returns `None` if every item was a notification, in which case nothing
+/// should be sent back.
+pub fn batch_body(items: &[Value], responses: Vec<Value>) -> Option<Value> {
+    let responses: Vec<Value> = items
+        .iter()
+        .zip(responses)
+        .filter(|(item, _)| !is_notification(item))
+        .map(|(_, response)| response)
+        .collect();
+
+    (!responses.is_empty()).then_some(Value::Array(responses))
+}
+
+/// JSON-RPC error response for a single batch item.
+pub fn error_response(id: Value, code: i64, message: impl Into<String>) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": {
+            "code": code,
+            "message": message.into(),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block() -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "number": "0x10",
+                "hash": "0xABCD",
+                "transactions": [
+                    { "hash": "0x01", "nonce": "0x0" },
+                    { "hash": "0x02", "nonce": "0x1" },
+                ],
+            },
+        })
+    }
+
+    #[test]
+    fn test_take_dependencies() {
+        let mut items = vec![
+            json!({"id": 1, "method": "eth_getBlockByNumber"}),
+            json!({"id": "receipts", "method": "eth_getBlockReceipts", "blutgang_dependsOn": 1}),
+            json!({"id": 3, "method": "eth_call", "blutgang_dependsOn": "receipts"}),
+            // Unknown ids and forward references are ignored
+            json!({"id": 4, "method": "eth_call", "blutgang_dependsOn": 99}),
+            json!({"id": 5, "method": "eth_call", "blutgang_dependsOn": 6}),
+            json!({"id": 6, "method": "eth_call"}),
+        ];
+
+        assert_eq!(
+            take_dependencies(&mut items),
+            vec![None, Some(0), Some(1), None, None, None]
+        );
+        assert!(items.iter().all(|item| item.get(DEPENDS_ON).is_none()));
+    }
+
+    #[test]
+    fn test_batch_body() {
+        let items = vec![
+            json!({"id": 1, "method": "eth_blockNumber"}),
+            json!({"method": "eth_sendRawTransaction"}),
+            // Not a notification, just invalid
+            json!(5),
+            json!({"id": null, "method": "eth_chainId"}),
+        ];
+        let responses = vec![json!("a"), json!("b"), json!("c"), json!("d")];
+        assert_eq!(batch_body(&items, responses), Some(json!(["a", "c", "d"])));
+
+        let notifications = vec![json!({"method": "eth_sendRawTransaction"})];
+        assert_eq!(batch_body(&notifications, vec![json!("a")]), None);
+    }
+
+    #[test]
+    fn test_waves() {
+        assert_eq!(
+            waves(&[None, Some(0), Some(1), None, Some(0)]),
+            vec![vec![0, 3], vec![1, 4], vec![2]]
+        );
+        assert!(waves(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_answer_from() {
+        let dependency = json!({"method": "eth_getBlockByNumber", "params": ["0x10", true]});
+
+        let tx = json!({"id": 7, "method": "eth_getTransactionByHash", "params": ["0x02"]});
+        assert_eq!(
+            answer_from(&dependency, &block(), &tx).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 7, "result": { "hash": "0x02", "nonce": "0x1" }})
+        );
+
+        let missing = json!({"id": 7, "method": "eth_getTransactionByHash", "params": ["0x03"]});
+        assert!(answer_from(&dependency, &block(), &missing).is_none());
+
+        let by_hash = json!({"id": 8, "method": "eth_getBlockByHash", "params": ["0xabcd", true]});
+        assert_eq!(
+            answer_from(&dependency, &block(), &by_hash).unwrap()["result"]["number"],
+            "0x10"
+        );
+
+        // Different level of detail than what we have
+        let hashes_only =
+            json!({"id": 8, "method": "eth_getBlockByHash", "params": ["0xabcd", false]});
+        assert!(answer_from(&dependency, &block(), &hashes_only).is_none());
+
+        // Errors don't answer anything
+        let error = error_response(json!(1), -32000, "header not found");
+        assert!(answer_from(&dependency, &error, &tx).is_none());
+
+        let receipts = json!({"id": 9, "method": "eth_getBlockReceipts", "params": ["0x10"]});
+        assert!(answer_from(&dependency, &block(), &receipts).is_none());
+    }
+}
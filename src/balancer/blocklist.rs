@@ -0,0 +1,325 @@
+//! Externally maintained blocklist.
+//!
+//! Operators can point Blutgang at a JSON file listing known-bad provider
+//! endpoints and client IP ranges:
+//!
+//! ```json
+//! {
+//!   "endpoints": ["bad-provider.io", "https://rpc.example.com"],
+//!   "clients": ["203.0.113.0/24", "2001:db8::/32", "198.51.100.7"]
+//! }
+//! ```
+//!
+//! The file is fetched on an interval. Clients connecting from a listed range
+//! are dropped, and RPCs on a listed host (or any of its subdomains) can't be
+//! added through the admin namespace.
+
+use crate::{
+    config::types::BlocklistSettings,
+    database::expiry::now_ms,
+};
+
+use std::{
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+use serde::Deserialize;
+use url::Url;
+
+/// Longest we wait on the blocklist URL before counting the sync as failed.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum BlocklistError {
+    Fetch(reqwest::Error),
+    Parse(serde_json::Error),
+    InvalidRange(String),
+}
+
+impl fmt::Display for BlocklistError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlocklistError::Fetch(e) => write!(f, "failed to fetch blocklist: {}", e),
+            BlocklistError::Parse(e) => write!(f, "failed to parse blocklist: {}", e),
+            BlocklistError::InvalidRange(range) => write!(f, "invalid IP range: {}", range),
+        }
+    }
+}
+
+impl std::error::Error for BlocklistError {}
+
+/// CIDR range of IP addresses. A bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpRange {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpRange {
+    type Err = BlocklistError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || BlocklistError::InvalidRange(s.to_string());
+
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse::<u8>().map_err(|_| invalid())?,
+            None => max_prefix,
+        };
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = BlocklistError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Blocklist as it's published.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct BlocklistFile {
+    endpoints: Vec<String>,
+    clients: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    /// Lowercase hosts
+    endpoints: Vec<String>,
+    clients: Vec<IpRange>,
+}
+
+impl Entries {
+    fn parse(body: &str) -> Result<Self, BlocklistError> {
+        let file: BlocklistFile = serde_json::from_str(body).map_err(BlocklistError::Parse)?;
+
+        // Entries can be bare hosts or full URLs
+        let endpoints = file
+            .endpoints
+            .iter()
+            .map(|endpoint| {
+                Url::parse(endpoint)
+                    .ok()
+                    .and_then(|url| url.host_str().map(str::to_string))
+                    .unwrap_or_else(|| endpoint.trim().to_string())
+                    .to_lowercase()
+            })
+            .filter(|host| !host.is_empty())
+            .collect();
+        let clients = file
+            .clients
+            .iter()
+            .map(|range| range.parse())
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self { endpoints, clients })
+    }
+}
+
+/// Shared, periodically replaced blocklist.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    entries: RwLock<Entries>,
+}
+
+impl Blocklist {
+    /// Replace the blocklist with the one published in `body`.
+    ///
+    /// The current list is kept if `body` isn't a valid blocklist.
+    pub fn update(&self, body: &str) -> Result<(), BlocklistError> {
+        let entries = Entries::parse(body)?;
+        tracing::debug!(
+            endpoints = entries.endpoints.len(),
+            clients = entries.clients.len(),
+            "Updated blocklist"
+        );
+
+        metrics::gauge!("blocklist_entries", "kind" => "endpoints")
+            .set(entries.endpoints.len() as f64);
+        metrics::gauge!("blocklist_entries", "kind" => "clients").set(entries.clients.len() as f64);
+
+        *self.entries.write().unwrap_or_else(|e| e.into_inner()) = entries;
+        Ok(())
+    }
+
+    /// Returns true if clients connecting from `ip` should be dropped.
+    pub fn blocks_client(&self, ip: IpAddr) -> bool {
+        self.entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clients
+            .iter()
+            .any(|range| range.contains(ip))
+    }
+
+    /// Returns true if `url` points at a blocklisted host or one of its subdomains.
+    pub fn blocks_endpoint(&self, url: &Url) -> bool {
+        let Some(host) = url.host_str().map(str::to_lowercase) else {
+            return false;
+        };
+
+        self.entries
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .endpoints
+            .iter()
+            .any(|blocked| {
+                host == *blocked
+                    || host
+                        .strip_suffix(blocked.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+    }
+}
+
+async fn fetch(client: &reqwest::Client, url: &str) -> Result<String, BlocklistError> {
+    client
+        .get(url)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(BlocklistError::Fetch)?
+        .text()
+        .await
+        .map_err(BlocklistError::Fetch)
+}
+
+/// Fetch the blocklist from `settings.url` every `settings.sync_interval_ms`.
+///
+/// Failed syncs keep the previous list.
+pub async fn sync_blocklist(blocklist: Arc<Blocklist>, settings: BlocklistSettings) {
+    let client = match reqwest::Client::builder().timeout(FETCH_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(err) => {
+            tracing::error!(
+                ?err,
+                "Failed to build blocklist client! Blocklist disabled."
+            );
+            return;
+        }
+    };
+    let mut interval =
+        tokio::time::interval(Duration::from_millis(settings.sync_interval_ms.max(1)));
+
+    loop {
+        interval.tick().await;
+
+        match fetch(&client, &settings.url)
+            .await
+            .and_then(|body| blocklist.update(&body))
+        {
+            Ok(()) => {
+                metrics::counter!("blocklist_syncs_total", "status" => "ok").increment(1);
+                metrics::gauge!("blocklist_last_sync_timestamp_ms").set(now_ms() as f64);
+            }
+            Err(err) => {
+                tracing::warn!(%err, "Blocklist sync failed! Keeping the previous list.");
+                metrics::counter!("blocklist_syncs_total", "status" => "error").increment(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_range() {
+        let range: IpRange = "203.0.113.0/24".parse().unwrap();
+        assert!(range.contains("203.0.113.77".parse().unwrap()));
+        assert!(!range.contains("203.0.114.1".parse().unwrap()));
+        // IPv4 clients on a dual stack listener show up as mapped IPv6 addresses
+        assert!(range.contains("::ffff:203.0.113.5".parse().unwrap()));
+
+        let single: IpRange = "198.51.100.7".parse().unwrap();
+        assert!(single.contains("198.51.100.7".parse().unwrap()));
+        assert!(!single.contains("198.51.100.8".parse().unwrap()));
+
+        let v6: IpRange = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!v6.contains("2001:db9::1".parse().unwrap()));
+
+        let everything: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("not-an-ip".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let blocklist = Blocklist::default();
+        let url = |url: &str| Url::parse(url).unwrap();
+        assert!(!blocklist.blocks_endpoint(&url("https://eth.bad-provider.io")));
+
+        blocklist
+            .update(
+                r#"{
+                    "endpoints": ["Bad-Provider.io", "https://rpc.example.com/v1/key"],
+                    "clients": ["203.0.113.0/24"]
+                }"#,
+            )
+            .unwrap();
+
+        assert!(blocklist.blocks_endpoint(&url("https://bad-provider.io")));
+        assert!(blocklist.blocks_endpoint(&url("wss://eth.bad-provider.io/ws")));
+        assert!(blocklist.blocks_endpoint(&url("http://rpc.example.com:8545")));
+        assert!(!blocklist.blocks_endpoint(&url("https://notbad-provider.io")));
+        assert!(!blocklist.blocks_endpoint(&url("https://example.com")));
+
+        assert!(blocklist.blocks_client("203.0.113.9".parse().unwrap()));
+        assert!(!blocklist.blocks_client("127.0.0.1".parse().unwrap()));
+
+        // A bad range fails the whole update and keeps the previous list
+        assert!(blocklist
+            .update(r#"{"clients": ["203.0.113.0/99"]}"#)
+            .is_err());
+        assert!(blocklist.blocks_client("203.0.113.9".parse().unwrap()));
+
+        blocklist.update("{}").unwrap();
+        assert!(!blocklist.blocks_client("203.0.113.9".parse().unwrap()));
+    }
+}
@@ -0,0 +1,166 @@
+//! Per-request cache control.
+//!
+//! Clients can tell us how to use the cache for their requests, either for a
+//! whole HTTP request with a header, or for a single request or batch item
+//! with a field:
+//!
+//! ```json
+//! {"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0x..", "0x10"], "blutgang_cache": "no-cache"}
+//! ```
+//!
+//! - `no-cache` skips reading from the cache and fetches a fresh response,
+//!   which is still cached,
+//! - `no-store` keeps the response out of the cache.
+//!
+//! Both can be set at once, separated by commas. Unknown directives are ignored.
+//!
+//! Operators can turn off `no-cache` with `allow_no_cache`, so callers can't
+//! send every request upstream. `no-store` is always honored.
+
+use hyper::HeaderMap;
+use serde_json::Value;
+
+/// Header clients use to set cache directives for every request in the body.
+pub const CACHE_CONTROL_HEADER: &str = "x-blutgang-cache";
+/// Field clients use to set cache directives for a single request.
+pub const CACHE_CONTROL_FIELD: &str = "blutgang_cache";
+
+/// How a request may use the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheControl {
+    /// Don't answer from the cache.
+    pub no_cache: bool,
+    /// Don't cache the response.
+    pub no_store: bool,
+}
+
+impl CacheControl {
+    /// Parse comma separated directives.
+    pub fn parse(directives: &str) -> Self {
+        let mut control = Self::default();
+        for directive in directives.split(',') {
+            let directive = directive.trim();
+            if directive.eq_ignore_ascii_case("no-cache") {
+                control.no_cache = true;
+            } else if directive.eq_ignore_ascii_case("no-store") {
+                control.no_store = true;
+            }
+        }
+        control
+    }
+
+    /// Directives set with the cache control header.
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get_all(CACHE_CONTROL_HEADER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .map(Self::parse)
+            .fold(Self::default(), Self::merge)
+    }
+
+    /// Remove the cache control field from `tx`, returning its directives.
+    ///
+    /// The field has to be removed before the request reaches the RPCs or
+    /// gets hashed for the cache.
+    pub fn take(tx: &mut Value) -> Self {
+        tx.as_object_mut()
+            .and_then(|tx| tx.remove(CACHE_CONTROL_FIELD))
+            .as_ref()
+            .and_then(Value::as_str)
+            .map(Self::parse)
+            .unwrap_or_default()
+    }
+
+    /// Directives of both `self` and `other`.
+    pub fn merge(self, other: Self) -> Self {
+        Self {
+            no_cache: self.no_cache || other.no_cache,
+            no_store: self.no_store || other.no_store,
+        }
+    }
+
+    /// Drop `no-cache` unless clients are allowed to skip the cache.
+    pub fn allowed(self, allow_no_cache: bool) -> Self {
+        Self {
+            no_cache: self.no_cache && allow_no_cache,
+            ..self
+        }
+    }
+
+    /// Returns true if the request can't use the cache as usual.
+    pub fn bypasses(&self) -> bool {
+        self.no_cache || self.no_store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(CacheControl::parse(""), CacheControl::default());
+        assert_eq!(
+            CacheControl::parse("no-cache"),
+            CacheControl {
+                no_cache: true,
+                no_store: false
+            }
+        );
+        assert_eq!(
+            CacheControl::parse(" No-Store , no-cache,max-age=0"),
+            CacheControl {
+                no_cache: true,
+                no_store: true
+            }
+        );
+        assert!(!CacheControl::parse("max-age=0").bypasses());
+    }
+
+    #[test]
+    fn test_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert!(!CacheControl::from_headers(&headers).bypasses());
+
+        headers.append(CACHE_CONTROL_HEADER, HeaderValue::from_static("no-cache"));
+        headers.append(CACHE_CONTROL_HEADER, HeaderValue::from_static("no-store"));
+        let control = CacheControl::from_headers(&headers);
+        assert!(control.no_cache && control.no_store);
+    }
+
+    #[test]
+    fn test_take() {
+        let mut tx = json!({"id": 1, "method": "eth_chainId", "blutgang_cache": "no-store"});
+        assert_eq!(
+            CacheControl::take(&mut tx),
+            CacheControl {
+                no_cache: false,
+                no_store: true
+            }
+        );
+        assert_eq!(tx, json!({"id": 1, "method": "eth_chainId"}));
+
+        // Fields that aren't strings are still removed
+        let mut tx = json!({"id": 1, "method": "eth_chainId", "blutgang_cache": true});
+        assert_eq!(CacheControl::take(&mut tx), CacheControl::default());
+        assert!(tx.get(CACHE_CONTROL_FIELD).is_none());
+
+        assert_eq!(CacheControl::take(&mut json!([])), CacheControl::default());
+    }
+
+    #[test]
+    fn test_allowed() {
+        let control = CacheControl::parse("no-cache, no-store");
+        assert_eq!(control.allowed(true), control);
+        assert_eq!(
+            control.allowed(false),
+            CacheControl {
+                no_cache: false,
+                no_store: true
+            }
+        );
+    }
+}
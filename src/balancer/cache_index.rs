@@ -0,0 +1,284 @@
+//! Index of what's in the cache, for purging entries.
+//!
+//! Cache keys are hashes of requests, so the DB alone can't tell which
+//! entries belong to a method or block. To purge those, we keep the method
+//! and block number of every entry we cache in memory.
+//!
+//! The index is capped at `capacity` entries, dropping the oldest ones first,
+//! and starts out empty, so entries cached before startup or a while ago might
+//! not be found. Clearing the whole cache always works.
+//!
+//! Indexed entries are also counted per method, and exported as the
+//! `cache_keys_by_method{method}` gauge, see `cache_metrics`.
+
+use crate::balancer::cache_metrics::method_label;
+
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    ops::RangeInclusive,
+    sync::Mutex,
+};
+
+use rust_tracing::deps::metrics;
+
+/// What we know about a cache entry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub method: String,
+    pub block: Option<u64>,
+    // Position in the insertion order, tells apart re-inserted keys
+    seq: u64,
+}
+
+impl IndexEntry {
+    fn matches(&self, method: Option<&str>, blocks: Option<&RangeInclusive<u64>>) -> bool {
+        method.is_none_or(|method| self.method == method)
+            && blocks.is_none_or(|blocks| self.block.is_some_and(|block| blocks.contains(&block)))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    seq: u64,
+    entries: HashMap<[u8; 32], IndexEntry>,
+    // Keys in the order they were indexed, oldest first
+    order: VecDeque<(u64, [u8; 32])>,
+    // Number of entries per method label
+    methods: HashMap<String, usize>,
+}
+
+/// Add `delta` to the number of entries for `method` in `methods`.
+fn count(methods: &mut HashMap<String, usize>, method: &str, delta: isize) {
+    let label = method_label(Some(method));
+    let count = methods.entry(label.clone()).or_default();
+    *count = count.saturating_add_signed(delta);
+    metrics::gauge!("cache_keys_by_method", "method" => label).set(*count as f64);
+}
+
+/// Methods and blocks of cached entries, keyed by cache key.
+#[derive(Debug, Default)]
+pub struct CacheIndex {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl CacheIndex {
+    /// Index up to `capacity` entries. `0` disables the index.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Record that the response to a request for `method` at `block` was cached under `key`.
+    pub fn record(&self, key: [u8; 32], method: &str, block: Option<u64>) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.seq += 1;
+        let seq = inner.seq;
+
+        let Inner {
+            entries, methods, ..
+        } = &mut *inner;
+        if let Some(replaced) = entries.insert(
+            key,
+            IndexEntry {
+                method: method.to_string(),
+                block,
+                seq,
+            },
+        ) {
+            count(methods, &replaced.method, -1);
+        }
+        count(methods, method, 1);
+        inner.order.push_back((seq, key));
+
+        while inner.entries.len() > self.capacity {
+            let Some((seq, oldest)) = inner.order.pop_front() else {
+                break;
+            };
+            // Skip keys that got re-indexed or purged since
+            if inner
+                .entries
+                .get(&oldest)
+                .is_some_and(|entry| entry.seq == seq)
+            {
+                let Inner {
+                    entries, methods, ..
+                } = &mut *inner;
+                if let Some(dropped) = entries.remove(&oldest) {
+                    count(methods, &dropped.method, -1);
+                }
+            }
+        }
+
+        // Purges leave their keys behind in `order`, don't let them pile up
+        if inner.order.len() > self.capacity.saturating_mul(2) {
+            let Inner { entries, order, .. } = &mut *inner;
+            order.retain(|(seq, key)| entries.get(key).is_some_and(|entry| entry.seq == *seq));
+        }
+    }
+
+    /// Returns what we know about the entry cached under `key`.
+    pub fn get(&self, key: &[u8; 32]) -> Option<IndexEntry> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.entries.get(key).cloned()
+    }
+
+    /// Remove the entries for `method` and within `blocks`, returning their keys.
+    ///
+    /// Filters that are `None` match everything. Entries without a block
+    /// number never match a block range.
+    pub fn purge(
+        &self,
+        method: Option<&str>,
+        blocks: Option<RangeInclusive<u64>>,
+    ) -> Vec<[u8; 32]> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let Inner {
+            entries, methods, ..
+        } = &mut *inner;
+
+        let mut purged = Vec::new();
+        entries.retain(|key, entry| {
+            let matches = entry.matches(method, blocks.as_ref());
+            if matches {
+                purged.push(*key);
+                count(methods, &entry.method, -1);
+            }
+            !matches
+        });
+
+        purged
+    }
+
+    /// Returns the keys of the entries `purge` would remove, without removing them.
+    pub fn find(&self, method: Option<&str>, blocks: Option<RangeInclusive<u64>>) -> Vec<[u8; 32]> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.matches(method, blocks.as_ref()))
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Remove the entries under `keys`.
+    pub fn remove(&self, keys: &[[u8; 32]]) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let Inner {
+            entries, methods, ..
+        } = &mut *inner;
+        for key in keys {
+            if let Some(removed) = entries.remove(key) {
+                count(methods, &removed.method, -1);
+            }
+        }
+    }
+
+    /// Forget every entry.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.entries.clear();
+        inner.order.clear();
+        for (label, count) in inner.methods.iter_mut() {
+            *count = 0;
+            metrics::gauge!("cache_keys_by_method", "method" => label.clone()).set(0.0);
+        }
+    }
+
+    /// Number of indexed entries.
+    pub fn indexed(&self) -> usize {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.entries.len()
+    }
+
+    /// Number of indexed entries per method label, see `cache_metrics`.
+    pub fn counts_by_method(&self) -> HashMap<String, usize> {
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .methods
+            .iter()
+            .filter(|(_, count)| **count > 0)
+            .map(|(label, count)| (label.clone(), *count))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_purge() {
+        let index = CacheIndex::new(10);
+        index.record([1; 32], "eth_getBalance", Some(10));
+        index.record([2; 32], "eth_getBalance", Some(20));
+        index.record([3; 32], "eth_getCode", Some(15));
+        index.record([4; 32], "eth_chainId", None);
+
+        assert_eq!(index.get(&[1; 32]).unwrap().method, "eth_getBalance");
+        assert_eq!(index.get(&[4; 32]).unwrap().block, None);
+
+        // Both filters have to match
+        assert_eq!(
+            index.purge(Some("eth_getBalance"), Some(5..=15)),
+            vec![[1; 32]]
+        );
+        assert!(index.get(&[1; 32]).is_none());
+
+        // Entries without a block don't match ranges
+        let mut purged = index.purge(None, Some(0..=u64::MAX));
+        purged.sort();
+        assert_eq!(purged, vec![[2; 32], [3; 32]]);
+
+        assert_eq!(
+            index.counts_by_method(),
+            HashMap::from([("eth_chainId".to_string(), 1)])
+        );
+        assert_eq!(index.purge(Some("eth_chainId"), None), vec![[4; 32]]);
+        assert_eq!(index.indexed(), 0);
+        assert!(index.counts_by_method().is_empty());
+    }
+
+    #[test]
+    fn test_index_capacity() {
+        let index = CacheIndex::new(2);
+        index.record([1; 32], "eth_getBalance", None);
+        index.record([2; 32], "eth_getBalance", None);
+        // Re-indexing keeps the entry, and makes it the newest
+        index.record([1; 32], "eth_getCode", None);
+        index.record([3; 32], "eth_getBalance", None);
+
+        assert_eq!(index.indexed(), 2);
+        assert!(index.get(&[2; 32]).is_none());
+        assert_eq!(index.get(&[1; 32]).unwrap().method, "eth_getCode");
+        assert_eq!(
+            index.counts_by_method(),
+            HashMap::from([
+                ("eth_getBalance".to_string(), 1),
+                ("eth_getCode".to_string(), 1),
+            ])
+        );
+
+        index.clear();
+        assert_eq!(index.indexed(), 0);
+        assert!(index.counts_by_method().is_empty());
+
+        // Disabled index holds nothing
+        let index = CacheIndex::new(0);
+        index.record([1; 32], "eth_getBalance", None);
+        assert!(index.get(&[1; 32]).is_none());
+    }
+}
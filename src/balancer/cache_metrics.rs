@@ -0,0 +1,90 @@
+//! Per method cache metrics.
+//!
+//! The DB only sees hashed keys, so it can count hits and misses but not what
+//! they were for. To tune cache policies we also need to know which methods
+//! are worth caching, so requests are counted by method on their way through:
+//!
+//! - `cache_lookups_total{method, result}`, with `result` being `hit` or `miss`,
+//! - `cache_writes_total{method}` and `cache_write_bytes_total{method}` for
+//!   responses we cache, after encoding and compression,
+//! - `cache_keys_by_method{method}`, the entries of each method in the cache
+//!   index, which only holds the newest `cache_index_size` entries, see
+//!   `cache_index`.
+//!
+//! The total number of keys in the DB is exported as `cache_keys`, counted
+//! every few minutes by the database task.
+//!
+//! Methods are client input, so anything we don't know is counted as `other`
+//! to keep the number of series bounded.
+//!
+//! Lookups are also counted in process, for the admin dashboard to show a hit
+//! rate without a metrics backend.
+
+use crate::balancer::validation::method_params;
+
+use std::sync::atomic::{
+    AtomicU64,
+    Ordering,
+};
+
+use rust_tracing::deps::metrics;
+
+/// Label for methods we don't know.
+const OTHER: &str = "other";
+
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the label requests for `method` are counted under.
+pub fn method_label(method: Option<&str>) -> String {
+    method
+        .filter(|method| method_params(method).is_some())
+        .unwrap_or(OTHER)
+        .to_string()
+}
+
+/// Count a cache lookup for `method`.
+pub fn record_lookup(method: Option<&str>, hit: bool) {
+    let result = if hit {
+        HITS.fetch_add(1, Ordering::Relaxed);
+        "hit"
+    } else {
+        MISSES.fetch_add(1, Ordering::Relaxed);
+        "miss"
+    };
+    metrics::counter!(
+        "cache_lookups_total",
+        "method" => method_label(method),
+        "result" => result
+    )
+    .increment(1);
+}
+
+/// Returns the number of cache hits and misses since startup.
+pub fn lookup_counts() -> (u64, u64) {
+    (HITS.load(Ordering::Relaxed), MISSES.load(Ordering::Relaxed))
+}
+
+/// Count a response to `method` written to the cache as `bytes` bytes.
+pub fn record_write(method: Option<&str>, bytes: usize) {
+    let method = method_label(method);
+    metrics::counter!("cache_writes_total", "method" => method.clone()).increment(1);
+    metrics::counter!("cache_write_bytes_total", "method" => method).increment(bytes as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_label() {
+        assert_eq!(
+            method_label(Some("eth_getBlockByNumber")),
+            "eth_getBlockByNumber"
+        );
+        assert_eq!(method_label(Some("eth_getLogs")), "eth_getLogs");
+        // Unbounded client input
+        assert_eq!(method_label(Some("eth_getBlockByNumber2")), OTHER);
+        assert_eq!(method_label(None), OTHER);
+    }
+}
@@ -0,0 +1,250 @@
+//! Canonical form of requests, used for cache keys.
+//!
+//! Client libraries encode the same request in different ways. One sends a
+//! checksummed address and `0x01`, another a lowercase address and `0x1`, and
+//! some pretty print their JSON or order object keys differently. Hashing
+//! requests as they come in would give each of those its own cache entry, so
+//! we hash a canonical form instead:
+//!
+//! - hex strings are lowercased,
+//! - quantities lose their leading zeros, for params we know are quantities,
+//! - object keys are sorted and there is no insignificant whitespace.
+//!
+//! Leading zeros are only stripped where we know the value is a number. Data,
+//! addresses, and hashes keep them, since `0x00` and `0x` are different bytes.
+//!
+//! The canonical form is only used for hashing, RPCs get requests as sent.
+
+use crate::balancer::validation::{
+    is_hex,
+    method_params,
+    Param,
+};
+
+use serde_json::Value;
+
+/// Fields of call, transaction and filter objects that hold quantities.
+const QUANTITY_FIELDS: &[&str] = &[
+    "blockNumber",
+    "chainId",
+    "fromBlock",
+    "gas",
+    "gasPrice",
+    "maxFeePerBlobGas",
+    "maxFeePerGas",
+    "maxPriorityFeePerGas",
+    "nonce",
+    "toBlock",
+    "type",
+    "value",
+];
+
+/// Returns the canonical string we hash to get the cache key of `tx`.
+pub fn canonical_key(tx: &Value) -> String {
+    canonical_string(&canonicalize(tx))
+}
+
+/// Returns the canonical string of `params` we don't know the types of.
+///
+/// Only hex strings are lowercased, since quantities can't be told apart from data.
+pub fn canonical_params(params: &Value) -> String {
+    let mut params = params.clone();
+    lowercase_hex(&mut params);
+    canonical_string(&params)
+}
+
+/// Serialize `value` without whitespace, with object keys in sorted order.
+pub fn canonical_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_sorted(value, &mut out);
+    out
+}
+
+/// Returns `tx` with its params in canonical form.
+fn canonicalize(tx: &Value) -> Value {
+    let mut tx = tx.clone();
+    let expected = tx["method"].as_str().and_then(method_params);
+
+    let Some(params) = tx.get_mut("params") else {
+        return tx;
+    };
+    lowercase_hex(params);
+
+    if let (Some(expected), Some(params)) = (expected, params.as_array_mut()) {
+        for (param, value) in expected.params.iter().zip(params.iter_mut()) {
+            canonicalize_param(*param, value);
+        }
+    }
+
+    tx
+}
+
+/// Lowercase every hex string in `value`.
+fn lowercase_hex(value: &mut Value) {
+    match value {
+        Value::String(s) if is_hex(s, None) => s.make_ascii_lowercase(),
+        Value::Array(items) => items.iter_mut().for_each(lowercase_hex),
+        Value::Object(object) => object.values_mut().for_each(lowercase_hex),
+        _ => {}
+    }
+}
+
+fn canonicalize_param(param: Param, value: &mut Value) {
+    match param {
+        Param::Quantity => canonicalize_quantity(value),
+        // Either a number, a tag, or an EIP-1898 block object
+        Param::Block => {
+            if let Some(number) = value.get_mut("blockNumber") {
+                canonicalize_quantity(number);
+            } else {
+                canonicalize_quantity(value);
+            }
+        }
+        Param::Object => {
+            if let Value::Object(object) = value {
+                for (key, field) in object.iter_mut() {
+                    if QUANTITY_FIELDS.contains(&key.as_str()) {
+                        canonicalize_quantity(field);
+                    }
+                }
+            }
+        }
+        Param::Address | Param::Hash | Param::Data | Param::Bool => {}
+    }
+}
+
+/// Strip leading zeros from a hex quantity. Anything else, like block tags, is left as is.
+fn canonicalize_quantity(value: &mut Value) {
+    let Value::String(s) = value else {
+        return;
+    };
+    if s.len() <= 2 || !is_hex(s, None) {
+        return;
+    }
+
+    let digits = s[2..].trim_start_matches('0');
+    *s = if digits.is_empty() {
+        "0x0".to_string()
+    } else {
+        format!("0x{}", digits)
+    };
+}
+
+/// Serialize `value` into `out`, see `canonical_string`.
+///
+/// `serde_json` maps keep insertion order if another crate in the tree enables
+/// `preserve_order`, so we don't rely on how they happen to be ordered.
+fn write_sorted(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(object) => {
+            let mut entries: Vec<_> = object.iter().collect();
+            entries.sort_unstable_by(|a, b| a.0.cmp(b.0));
+
+            out.push('{');
+            for (i, (key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(key.clone()).to_string());
+                out.push(':');
+                write_sorted(value, out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_sorted(item, out);
+            }
+            out.push(']');
+        }
+        _ => out.push_str(&value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn same_key(a: &str, b: &str) -> bool {
+        let a: Value = serde_json::from_str(a).unwrap();
+        let b: Value = serde_json::from_str(b).unwrap();
+        canonical_key(&a) == canonical_key(&b)
+    }
+
+    #[test]
+    fn test_canonical_equivalent_requests() {
+        // Address case and quantity encoding
+        assert!(same_key(
+            r#"{"jsonrpc":"2.0","id":null,"method":"eth_getBalance","params":["0xAbCd000000000000000000000000000000000001","0x0010"]}"#,
+            r#"{"jsonrpc":"2.0","id":null,"method":"eth_getBalance","params":["0xabcd000000000000000000000000000000000001","0x10"]}"#,
+        ));
+        assert!(same_key(
+            r#"{"method":"eth_getBlockByNumber","params":["0x00",false]}"#,
+            r#"{"method":"eth_getBlockByNumber","params":["0x0",false]}"#,
+        ));
+
+        // Whitespace and key order, including inside params
+        assert!(same_key(
+            r#"{ "method" : "eth_call", "params" : [ { "to": "0xAB", "gas": "0x0100" }, "latest" ] }"#,
+            r#"{"params":[{"gas":"0x100","to":"0xab"},"latest"],"method":"eth_call"}"#,
+        ));
+
+        // EIP-1898 block objects and filter ranges
+        assert!(same_key(
+            r#"{"method":"eth_getCode","params":["0x01",{"blockNumber":"0x01"}]}"#,
+            r#"{"method":"eth_getCode","params":["0x01",{"blockNumber":"0x1"}]}"#,
+        ));
+        assert!(same_key(
+            r#"{"method":"eth_getLogs","params":[{"fromBlock":"0x0A","toBlock":"latest"}]}"#,
+            r#"{"method":"eth_getLogs","params":[{"toBlock":"latest","fromBlock":"0xa"}]}"#,
+        ));
+    }
+
+    #[test]
+    fn test_canonical_keeps_data() {
+        // Leading zeros are significant in data, addresses and hashes
+        assert!(!same_key(
+            r#"{"method":"eth_sendRawTransaction","params":["0x00ab"]}"#,
+            r#"{"method":"eth_sendRawTransaction","params":["0xab"]}"#,
+        ));
+        assert!(!same_key(
+            r#"{"method":"eth_call","params":[{"data":"0x0001"},"latest"]}"#,
+            r#"{"method":"eth_call","params":[{"data":"0x1"},"latest"]}"#,
+        ));
+        // Unknown methods only get their hex lowercased
+        assert!(!same_key(
+            r#"{"method":"debug_foo","params":["0x01"]}"#,
+            r#"{"method":"debug_foo","params":["0x1"]}"#,
+        ));
+        assert!(same_key(
+            r#"{"method":"debug_foo","params":["0xAB"]}"#,
+            r#"{"method":"debug_foo","params":["0xab"]}"#,
+        ));
+
+        // Different requests still get different keys
+        assert!(!same_key(
+            r#"{"method":"eth_getBlockByNumber","params":["0x10",false]}"#,
+            r#"{"method":"eth_getBlockByNumber","params":["0x10",true]}"#,
+        ));
+    }
+
+    #[test]
+    fn test_canonical_format() {
+        let tx = json!({"method": "eth_getBlockByNumber", "params": ["0x0010", false], "id": null});
+        assert_eq!(
+            canonical_key(&tx),
+            r#"{"id":null,"method":"eth_getBlockByNumber","params":["0x10",false]}"#
+        );
+        // Block tags and non-hex strings are left alone
+        let tx = json!({"method": "eth_getBalance", "params": ["0xAB", "Latest"]});
+        assert_eq!(
+            canonical_key(&tx),
+            r#"{"method":"eth_getBalance","params":["0xab","Latest"]}"#
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! Request and response checksums for tracking down corrupted data.
+//!
+//! When a client reports a response with bad data, it's hard to tell whether
+//! the RPC sent it like that, it got mangled in our cache, or on its way to the
+//! client. With `debug_checksums` enabled we checksum every request and
+//! response at each hop, and log them along with the request checksum so the
+//! hops can be lined up:
+//!
+//! - the request checksum is sent upstream in a header, and back to the client,
+//! - the content of responses is checksummed when received from an RPC and
+//!   again when served, so a mismatch between the two points at the cache,
+//! - the exact bytes served are checksummed too, so clients can check for
+//!   corruption in transit.
+//!
+//! Content checksums ignore the `id` and formatting of responses, which we
+//! rewrite, so the same response always has the same content checksum.
+
+use crate::balancer::canonical::{
+    canonical_key,
+    canonical_string,
+};
+
+use serde_json::Value;
+
+/// Header holding the checksum of the canonical request, sent upstream and to the client.
+pub const REQUEST_CHECKSUM_HEADER: &str = "x-blutgang-request-checksum";
+/// Header holding the checksum of the response content, excluding its `id`.
+pub const CONTENT_CHECKSUM_HEADER: &str = "x-blutgang-content-checksum";
+/// Header holding the checksum of the exact response body bytes.
+pub const RESPONSE_CHECKSUM_HEADER: &str = "x-blutgang-response-checksum";
+
+/// Hex encoded blake3 hash of `bytes`.
+pub fn checksum(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex().to_string()
+}
+
+/// Checksum of a request, independent of its `id` and how it's encoded.
+pub fn request_checksum(tx: &Value) -> String {
+    let mut tx = tx.clone();
+    if let Some(tx) = tx.as_object_mut() {
+        tx.remove("id");
+    }
+
+    checksum(canonical_key(&tx).as_bytes())
+}
+
+/// Checksum of what a response says, independent of its `id` and formatting.
+///
+/// Returns `None` if `response` isn't JSON.
+pub fn content_checksum(response: &str) -> Option<String> {
+    let mut response: Value = serde_json::from_str(response).ok()?;
+    if let Some(response) = response.as_object_mut() {
+        response.remove("id");
+    }
+
+    Some(checksum(canonical_string(&response).as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_request_checksum() {
+        let a = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0xAB", "latest"]});
+        let b = json!({"method": "eth_getBalance", "params": ["0xab", "latest"], "id": 9, "jsonrpc": "2.0"});
+        assert_eq!(request_checksum(&a), request_checksum(&b));
+
+        let c = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_getBalance", "params": ["0xab", "0x1"]});
+        assert_ne!(request_checksum(&a), request_checksum(&c));
+        assert_eq!(request_checksum(&a).len(), 64);
+    }
+
+    #[test]
+    fn test_content_checksum() {
+        let upstream = r#"{"jsonrpc":"2.0","id":7,"result":{"b":"0x2","a":"0x1"}}"#;
+        let served = r#"{ "id": 1, "jsonrpc": "2.0", "result": { "a": "0x1", "b": "0x2" } }"#;
+        assert_eq!(content_checksum(upstream), content_checksum(served));
+
+        let corrupted = r#"{"jsonrpc":"2.0","id":1,"result":{"a":"0x1","b":"0x3"}}"#;
+        assert_ne!(content_checksum(upstream), content_checksum(corrupted));
+
+        assert_eq!(content_checksum("not json"), None);
+        // Raw bytes do depend on formatting
+        assert_ne!(checksum(upstream.as_bytes()), checksum(served.as_bytes()));
+    }
+}
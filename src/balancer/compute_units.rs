@@ -0,0 +1,211 @@
+//! Compute unit accounting.
+//!
+//! Paid providers bill methods by weight instead of by request, an
+//! `eth_getLogs` costing many times what an `eth_blockNumber` does.
+//! `[blutgang.compute_units]` gives methods a cost in compute units, so we can
+//! mirror their pricing and keep clients within budgets:
+//!
+//! - API keys spend their `compute_units` every `quota_window_ms`,
+//! - clients without a key spend `ip_budget` every `window_ms`, per client IP, or /64 for IPv6 clients.
+//!
+//! Like rate limits, budgets are kept in the counter store (see `counters`),
+//! and requests with a key only count against the key. A request is charged
+//! once for every call in it before it's forwarded, cached or not, and gets a
+//! 429 with `Retry-After` if it doesn't fit in what's left of the budget.
+//!
+//! Calls that actually reach an RPC are charged to it too, and to its monthly
+//! quota if it has one (see `quota`), so we know how much of it we've used.
+//! Usage is exported as `compute_units_total` per API key,
+//! `ip_compute_units_total` per client IP spending an `ip_budget`, and
+//! `upstream_compute_units_total` per RPC. Calls over WebSockets are charged
+//! one by one, like batch items.
+
+use crate::{
+    balancer::{
+        api_keys::ApiKey,
+        method_filter::method_matches,
+        rate_limit::{
+            client_key,
+            count_units,
+            RequestCounters,
+        },
+    },
+    config::types::ComputeUnitSettings,
+    Rpc,
+};
+
+use std::net::IpAddr;
+
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+
+impl ComputeUnitSettings {
+    /// Cost of a call to `method`.
+    ///
+    /// Methods listed as they are take precedence over wildcards, and longer
+    /// wildcards over shorter ones.
+    pub fn cost(&self, method: Option<&str>) -> u64 {
+        let Some(method) = method else {
+            return self.default_cost;
+        };
+        if let Some(cost) = self.costs.get(method) {
+            return *cost;
+        }
+        self.costs
+            .iter()
+            .filter(|(pattern, _)| method_matches(pattern, method))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map_or(self.default_cost, |(_, cost)| *cost)
+    }
+
+    /// Cost of `request`, a single call or a batch of them.
+    pub fn request_cost(&self, request: &Value) -> u64 {
+        match request {
+            Value::Array(items) => {
+                items
+                    .iter()
+                    .map(|item| self.cost(item["method"].as_str()))
+                    .sum()
+            }
+            request => self.cost(request["method"].as_str()),
+        }
+    }
+}
+
+/// Charge `units` to the budget of `api_key`, or of `client_ip` for requests
+/// without one.
+///
+/// Returns the seconds until the budget resets if `units` don't fit in it.
+pub async fn charge_client(
+    settings: &ComputeUnitSettings,
+    counters: &RequestCounters,
+    api_key: Option<&ApiKey>,
+    client_ip: Option<IpAddr>,
+    units: u64,
+) -> Result<(), u64> {
+    let name = api_key.map_or_else(String::new, |api_key| api_key.name.clone());
+    metrics::counter!("compute_units_total", "api_key" => name).increment(units);
+
+    match (api_key, client_ip) {
+        (Some(api_key), _) if api_key.compute_units != 0 => {
+            count_units(
+                counters,
+                &format!("cu:key:{}", api_key.name),
+                units,
+                api_key.compute_units,
+                api_key.quota_window_ms,
+                "compute_units",
+            )
+            .await
+        }
+        (None, Some(client_ip)) if settings.ip_budget != 0 => {
+            let client = client_key(client_ip);
+            metrics::counter!("ip_compute_units_total", "client_ip" => client.clone())
+                .increment(units);
+            count_units(
+                counters,
+                &format!("cu:ip:{}", client),
+                units,
+                settings.ip_budget,
+                settings.window_ms,
+                "compute_units",
+            )
+            .await
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Charge `units` to `rpc`, for a call it served.
+pub fn charge_upstream(rpc: &Rpc, units: u64) {
+    rpc.stats.add_compute_units(units);
+    rpc.charge_quota(units);
+    metrics::counter!("upstream_compute_units_total", "rpc_name" => rpc.name.clone())
+        .increment(units);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::counters::MemoryCounters;
+    use serde_json::json;
+
+    fn settings() -> ComputeUnitSettings {
+        toml::from_str(
+            r#"
+            default_cost = 10
+            ip_budget = 100
+
+            [costs]
+            eth_blockNumber = 1
+            "eth_get*" = 20
+            "eth_getLogs" = 75
+            "eth_getBlock*" = 16
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_cost() {
+        let settings = settings();
+        assert_eq!(settings.cost(Some("eth_blockNumber")), 1);
+        assert_eq!(settings.cost(Some("eth_getLogs")), 75);
+        assert_eq!(settings.cost(Some("eth_getBlockByNumber")), 16);
+        assert_eq!(settings.cost(Some("eth_getBalance")), 20);
+        assert_eq!(settings.cost(Some("eth_call")), 10);
+        assert_eq!(settings.cost(None), 10);
+
+        let batch = json!([
+            {"method": "eth_blockNumber"},
+            {"method": "eth_getLogs"},
+            {"method": "eth_call"},
+        ]);
+        assert_eq!(settings.request_cost(&batch), 86);
+        assert_eq!(settings.request_cost(&json!({"method": "eth_getLogs"})), 75);
+    }
+
+    #[tokio::test]
+    async fn test_charge_client() {
+        let settings = settings();
+        let counters = RequestCounters::Memory(MemoryCounters::default());
+        let ip = "10.0.0.1".parse().unwrap();
+
+        assert!(charge_client(&settings, &counters, None, Some(ip), 75)
+            .await
+            .is_ok());
+        assert!(charge_client(&settings, &counters, None, Some(ip), 25)
+            .await
+            .is_ok());
+        let retry_after = charge_client(&settings, &counters, None, Some(ip), 1)
+            .await
+            .unwrap_err();
+        assert!((1..=86_400).contains(&retry_after));
+
+        // Keys have their own budget, or none
+        let key: ApiKey = toml::from_str(
+            r#"
+            name = "indexers"
+            key = "indexer-key"
+            compute_units = 150
+            "#,
+        )
+        .unwrap();
+        assert!(
+            charge_client(&settings, &counters, Some(&key), Some(ip), 150)
+                .await
+                .is_ok()
+        );
+        assert!(charge_client(&settings, &counters, Some(&key), Some(ip), 1)
+            .await
+            .is_err());
+
+        let mut unlimited = key.clone();
+        unlimited.compute_units = 0;
+        assert!(
+            charge_client(&settings, &counters, Some(&unlimited), Some(ip), 1000)
+                .await
+                .is_ok()
+        );
+    }
+}
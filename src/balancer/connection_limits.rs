@@ -0,0 +1,246 @@
+//! Bounds on simultaneous client connections.
+//!
+//! `[blutgang.connection_limits]` caps how many connections can be open at
+//! once, in total and from any single client IP, so one client can't exhaust
+//! our file descriptors or tasks by opening connections and never closing
+//! them. Connections over either cap get dropped right after being accepted.
+//!
+//! Connections are counted from the address of the client, which is the one
+//! sent by a trusted proxy with the PROXY protocol (see `proxy_protocol`).
+//! WebSockets keep counting against the limits of the connection they were
+//! upgraded from for as long as they're open. Unix socket connections have no
+//! client IP, so only the global cap applies to them. Neither does the per IP
+//! cap apply to connections from `trusted_proxies` we don't get a client
+//! address from, since they're shared by every client behind the proxy.
+//!
+//! On shutdown, the same count tells us when every connection has finished.
+
+use crate::config::types::ConnectionLimits;
+
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConnectionLimitError {
+    TooManyConnections(usize),
+    TooManyFromClient(usize),
+}
+
+impl ConnectionLimitError {
+    /// Label of the error in metrics.
+    fn reason(&self) -> &'static str {
+        match self {
+            ConnectionLimitError::TooManyConnections(_) => "global",
+            ConnectionLimitError::TooManyFromClient(_) => "ip",
+        }
+    }
+}
+
+impl fmt::Display for ConnectionLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectionLimitError::TooManyConnections(max) => {
+                write!(f, "more than {} open connections", max)
+            }
+            ConnectionLimitError::TooManyFromClient(max) => {
+                write!(f, "more than {} open connections from client", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectionLimitError {}
+
+#[derive(Debug, Default)]
+struct OpenConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+/// Open client connections, in total and per IP.
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    open: Mutex<OpenConnections>,
+}
+
+impl ConnectionTracker {
+    /// Count a connection from `ip` if it's within `limits`, counting it as
+    /// rejected if not.
+    ///
+    /// The connection is counted until the returned permit is dropped.
+    pub fn acquire(
+        self: &Arc<Self>,
+        limits: &ConnectionLimits,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionPermit, ConnectionLimitError> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+
+        let from_client = ip.map_or(0, |ip| open.per_ip.get(&ip).copied().unwrap_or(0));
+        let result = if limits.max_connections != 0 && open.total >= limits.max_connections {
+            Err(ConnectionLimitError::TooManyConnections(
+                limits.max_connections,
+            ))
+        } else if ip.is_some()
+            && limits.max_connections_per_ip != 0
+            && from_client >= limits.max_connections_per_ip
+        {
+            Err(ConnectionLimitError::TooManyFromClient(
+                limits.max_connections_per_ip,
+            ))
+        } else {
+            Ok(())
+        };
+        if let Err(err) = result {
+            metrics::counter!("connections_rejected_total", "reason" => err.reason()).increment(1);
+            return Err(err);
+        }
+
+        open.total += 1;
+        if let Some(ip) = ip {
+            *open.per_ip.entry(ip).or_default() += 1;
+        }
+        metrics::gauge!("connections_open").set(open.total as f64);
+
+        Ok(ConnectionPermit {
+            tracker: Arc::clone(self),
+            ip,
+        })
+    }
+
+    /// Number of open connections.
+    pub fn len(&self) -> usize {
+        self.open.lock().unwrap_or_else(|e| e.into_inner()).total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Completes once every connection is closed.
+    pub async fn closed(&self) {
+        while !self.is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        open.total = open.total.saturating_sub(1);
+        if let Some(ip) = ip {
+            if let Some(count) = open.per_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    open.per_ip.remove(&ip);
+                }
+            }
+        }
+        metrics::gauge!("connections_open").set(open.total as f64);
+    }
+}
+
+/// A connection counted by a `ConnectionTracker`, until it's dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    tracker: Arc<ConnectionTracker>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.tracker.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Number of open connections, in total and from `ip`.
+    fn open(tracker: &ConnectionTracker, ip: IpAddr) -> (usize, usize) {
+        let open = tracker.open.lock().unwrap();
+        (open.total, open.per_ip.get(&ip).copied().unwrap_or(0))
+    }
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+        }
+    }
+
+    #[test]
+    fn test_acquire() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let (a, b): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+        let first = tracker.acquire(&limits(), Some(a)).unwrap();
+        let second = tracker.acquire(&limits(), Some(a)).unwrap();
+        assert_eq!(
+            tracker.acquire(&limits(), Some(a)).unwrap_err(),
+            ConnectionLimitError::TooManyFromClient(2)
+        );
+        assert_eq!(open(&tracker, a), (2, 2));
+
+        let other = tracker.acquire(&limits(), Some(b)).unwrap();
+        assert_eq!(
+            tracker.acquire(&limits(), Some(b)).unwrap_err(),
+            ConnectionLimitError::TooManyConnections(3)
+        );
+        assert_eq!(
+            tracker.acquire(&limits(), None).unwrap_err(),
+            ConnectionLimitError::TooManyConnections(3)
+        );
+
+        // Closing connections frees them up
+        drop(first);
+        assert_eq!(open(&tracker, a), (2, 1));
+        let _third = tracker.acquire(&limits(), Some(a)).unwrap();
+        drop((second, other));
+        assert_eq!(open(&tracker, b), (1, 0));
+        assert!(!tracker.open.lock().unwrap().per_ip.contains_key(&b));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        let ip = "10.0.0.1".parse().unwrap();
+        let permits: Vec<_> = (0..100)
+            .map(|_| {
+                tracker
+                    .acquire(&ConnectionLimits::default(), Some(ip))
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(open(&tracker, ip), (100, 100));
+        drop(permits);
+        assert_eq!(open(&tracker, ip), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn test_closed() {
+        let tracker = Arc::new(ConnectionTracker::default());
+        tracker.closed().await;
+
+        let permit = tracker.acquire(&ConnectionLimits::default(), None).unwrap();
+        assert_eq!(tracker.len(), 1);
+        let closing = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            drop(permit);
+        });
+        tokio::time::timeout(Duration::from_secs(1), tracker.closed())
+            .await
+            .unwrap();
+        assert!(tracker.is_empty());
+        closing.await.unwrap();
+    }
+}
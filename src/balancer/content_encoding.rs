@@ -0,0 +1,381 @@
+//! Compression of responses to clients.
+//!
+//! With `encodings` set in `[blutgang.response_compression]`, responses at
+//! least `min_bytes` long are compressed with the encoding the client weighs
+//! highest in `Accept-Encoding`, ties going to the first one in `encodings`.
+//! Big `eth_getLogs` and block responses shrink a lot, which saves on egress.
+//!
+//! Responses over `BLOCKING_BYTES` are compressed on a blocking thread, so
+//! they don't hold up other requests on the same worker.
+//!
+//! RPCs with `compression` set send compressed responses. When the client
+//! accepts the encoding an RPC used and the response reaches the client as the
+//! RPC sent it, the compressed body is passed through instead of compressing
+//! it again, see `with_upstream_body`.
+//!
+//! Compression would undo response padding, so it's skipped while
+//! `[blutgang.privacy]` pads responses.
+
+use crate::config::types::{
+    ContentEncoding,
+    ResponseCompressionSettings,
+};
+
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::Write,
+};
+
+use flate2::{
+    write::GzEncoder,
+    Compression,
+};
+use http_body_util::{
+    BodyExt,
+    Full,
+};
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderValue,
+        CONTENT_ENCODING,
+        VARY,
+    },
+    Response,
+};
+use rust_tracing::deps::metrics;
+
+/// Brotli quality, a good trade-off for responses compressed on the fly.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+const ZSTD_LEVEL: i32 = 3;
+/// Size past which responses are compressed on a blocking thread.
+const BLOCKING_BYTES: usize = 256 * 1024;
+
+impl ContentEncoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+            Self::Zstd => "zstd",
+        }
+    }
+
+    fn compress(self, body: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                encoder.finish()
+            }
+            Self::Brotli => {
+                let mut encoder =
+                    brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW);
+                encoder.write_all(body)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+            Self::Zstd => zstd::stream::encode_all(body, ZSTD_LEVEL),
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Compressed body of the last RPC response to the request being served.
+    static UPSTREAM_BODY: RefCell<Option<UpstreamBody>>;
+}
+
+/// Response body as an RPC sent it, before we decompressed it.
+#[derive(Debug, Clone)]
+pub struct UpstreamBody {
+    encoding: HeaderValue,
+    body: Bytes,
+    decoded: blake3::Hash,
+}
+
+impl UpstreamBody {
+    /// Returns true if `body` is what this decompresses to.
+    fn matches(&self, body: &[u8]) -> bool {
+        blake3::hash(body) == self.decoded
+    }
+}
+
+/// Remember the `body` compressed with `encoding` an RPC sent, which
+/// decompressed to `decoded`, so it can be passed through to the client.
+///
+/// Does nothing outside of `with_upstream_body`.
+pub fn record_upstream_body(encoding: &str, body: Bytes, decoded: &str) {
+    let Ok(encoding) = HeaderValue::from_str(encoding) else {
+        return;
+    };
+    let _ = UPSTREAM_BODY.try_with(|upstream| {
+        *upstream.borrow_mut() = Some(UpstreamBody {
+            encoding,
+            body,
+            decoded: blake3::hash(decoded.as_bytes()),
+        });
+    });
+}
+
+/// Run `future`, returning the last compressed RPC response it got as well.
+///
+/// Only responses received on the task running `future` are seen.
+pub async fn with_upstream_body<F: Future>(future: F) -> (F::Output, Option<UpstreamBody>) {
+    UPSTREAM_BODY
+        .scope(RefCell::new(None), async {
+            let output = future.await;
+            (output, UPSTREAM_BODY.with(|upstream| upstream.take()))
+        })
+        .await
+}
+
+/// Weight `accept_encoding` gives the encoding called `encoding`, from 0 to 1.
+fn weight(accept_encoding: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for entry in accept_encoding.split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+
+        if name.eq_ignore_ascii_case(encoding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = q;
+        }
+    }
+    wildcard
+}
+
+impl ResponseCompressionSettings {
+    /// Encoding to compress a response to a client sending `accept_encoding` with.
+    fn negotiate(&self, accept_encoding: &str) -> Option<ContentEncoding> {
+        let mut best = None;
+        let mut best_weight = 0.0;
+        for &encoding in &self.encodings {
+            let weight = weight(accept_encoding, encoding.as_str());
+            if weight > best_weight {
+                best = Some(encoding);
+                best_weight = weight;
+            }
+        }
+        best
+    }
+
+    /// Compress `response` for a client sending `accept_encoding`, if it's worth it.
+    ///
+    /// `upstream` is passed through as is if the client accepts its encoding
+    /// and it decompresses to `response`.
+    pub async fn compress(
+        &self,
+        accept_encoding: Option<&HeaderValue>,
+        response: Response<Full<Bytes>>,
+        upstream: Option<UpstreamBody>,
+    ) -> Response<Full<Bytes>> {
+        if response.headers().contains_key(CONTENT_ENCODING) {
+            return response;
+        }
+        let accept_encoding =
+            accept_encoding.and_then(|accept_encoding| accept_encoding.to_str().ok());
+        let upstream = upstream.filter(|upstream| {
+            accept_encoding.is_some_and(|accept_encoding| {
+                upstream
+                    .encoding
+                    .to_str()
+                    .is_ok_and(|encoding| weight(accept_encoding, encoding) > 0.0)
+            })
+        });
+        let encoding = accept_encoding.and_then(|accept_encoding| self.negotiate(accept_encoding));
+        if upstream.is_none() && encoding.is_none() {
+            return response;
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let body = match body.collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(never) => match never {},
+        };
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+        if let Some(upstream) = upstream.filter(|upstream| upstream.matches(&body)) {
+            metrics::counter!("response_compression_passthrough_total").increment(1);
+            parts.headers.insert(CONTENT_ENCODING, upstream.encoding);
+            return Response::from_parts(parts, Full::new(upstream.body));
+        }
+        let Some(encoding) = encoding else {
+            return Response::from_parts(parts, Full::new(body));
+        };
+        if body.len() < self.min_bytes {
+            return Response::from_parts(parts, Full::new(body));
+        }
+
+        let compressed = if body.len() > BLOCKING_BYTES {
+            let body = body.clone();
+            tokio::task::spawn_blocking(move || encoding.compress(&body))
+                .await
+                .unwrap_or_else(|err| Err(std::io::Error::other(err)))
+        } else {
+            encoding.compress(&body)
+        };
+
+        match compressed {
+            Ok(compressed) if compressed.len() < body.len() => {
+                metrics::counter!("response_compression_saved_bytes_total", "encoding" => encoding.as_str())
+                    .increment((body.len() - compressed.len()) as u64);
+                parts.headers.insert(
+                    CONTENT_ENCODING,
+                    HeaderValue::from_static(encoding.as_str()),
+                );
+                Response::from_parts(parts, Full::new(Bytes::from(compressed)))
+            }
+            Ok(_) => Response::from_parts(parts, Full::new(body)),
+            Err(err) => {
+                tracing::warn!(%err, encoding = encoding.as_str(), "Failed to compress response");
+                Response::from_parts(parts, Full::new(body))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn settings() -> ResponseCompressionSettings {
+        ResponseCompressionSettings {
+            encodings: vec![
+                ContentEncoding::Zstd,
+                ContentEncoding::Brotli,
+                ContentEncoding::Gzip,
+            ],
+            min_bytes: 64,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let settings = settings();
+        assert_eq!(
+            settings.negotiate("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            settings.negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(settings.negotiate("*"), Some(ContentEncoding::Zstd));
+        assert_eq!(
+            settings.negotiate("*, zstd;q=0"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(settings.negotiate("identity"), None);
+        assert_eq!(settings.negotiate(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_compress() {
+        let body = format!("[{}]", ["\"0xdeadbeef\""; 100].join(","));
+        let response = |body: &str| Response::new(Full::new(Bytes::from(body.to_string())));
+
+        let compressed = settings()
+            .compress(
+                Some(&HeaderValue::from_static("gzip")),
+                response(&body),
+                None,
+            )
+            .await;
+        assert_eq!(compressed.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(compressed.headers()[VARY], "accept-encoding");
+        let compressed = compressed.into_body().collect().await.unwrap().to_bytes();
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, body);
+
+        // Big enough to be compressed on a blocking thread
+        let big = format!("[{}]", ["\"0xdeadbeef\""; BLOCKING_BYTES / 10].join(","));
+        let compressed = settings()
+            .compress(
+                Some(&HeaderValue::from_static("zstd")),
+                response(&big),
+                None,
+            )
+            .await;
+        assert_eq!(compressed.headers()[CONTENT_ENCODING], "zstd");
+        let compressed = compressed.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(
+            zstd::stream::decode_all(&compressed[..]).unwrap(),
+            big.as_bytes()
+        );
+
+        // Too small to bother
+        let small = settings()
+            .compress(
+                Some(&HeaderValue::from_static("gzip")),
+                response("[]"),
+                None,
+            )
+            .await;
+        assert!(!small.headers().contains_key(CONTENT_ENCODING));
+
+        // Not accepted by the client
+        let plain = settings().compress(None, response(&body), None).await;
+        assert!(!plain.headers().contains_key(CONTENT_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn test_pass_through() {
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#;
+        let response = |body: &str| Response::new(Full::new(Bytes::from(body.to_string())));
+        let compressed = Bytes::from(ContentEncoding::Gzip.compress(body.as_bytes()).unwrap());
+
+        let ((), upstream) = with_upstream_body(async {
+            record_upstream_body("gzip", compressed.clone(), body);
+        })
+        .await;
+        assert!(upstream.is_some());
+
+        // Passed through even without encodings of our own, and under `min_bytes`
+        let passed = ResponseCompressionSettings::default()
+            .compress(
+                Some(&HeaderValue::from_static("gzip, br")),
+                response(body),
+                upstream.clone(),
+            )
+            .await;
+        assert_eq!(passed.headers()[CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            passed.into_body().collect().await.unwrap().to_bytes(),
+            compressed
+        );
+
+        // Not if the client doesn't take gzip, or the response changed on the way
+        let plain = ResponseCompressionSettings::default()
+            .compress(
+                Some(&HeaderValue::from_static("br")),
+                response(body),
+                upstream.clone(),
+            )
+            .await;
+        assert!(!plain.headers().contains_key(CONTENT_ENCODING));
+        let changed = ResponseCompressionSettings::default()
+            .compress(
+                Some(&HeaderValue::from_static("gzip")),
+                response(r#"{"jsonrpc":"2.0","id":2,"result":"0x1"}"#),
+                upstream,
+            )
+            .await;
+        assert!(!changed.headers().contains_key(CONTENT_ENCODING));
+
+        // Nothing is recorded outside of `with_upstream_body`
+        record_upstream_body("gzip", compressed, body);
+    }
+}
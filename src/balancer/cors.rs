@@ -0,0 +1,182 @@
+//! CORS for browser dApps.
+//!
+//! Browsers only let pages from other origins read our responses if we say
+//! they can, and send an `OPTIONS` preflight first for JSON requests. With
+//! `allowed_origins` set in `[blutgang.cors]`, preflights from allowed origins
+//! get answered directly, and every response to them gets the headers
+//! browsers look for. Requests from other origins are still served, browsers
+//! just won't hand the response to the page.
+
+use crate::config::types::CorsSettings;
+
+use http_body_util::Full;
+use hyper::{
+    body::Bytes,
+    header::{
+        HeaderMap,
+        HeaderValue,
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS,
+        ORIGIN,
+        VARY,
+    },
+    Method,
+    Request,
+    Response,
+    StatusCode,
+};
+
+/// Returns true if `origin` matches `pattern`, which can be `*` or have a
+/// `*.` wildcard in front of its host.
+fn origin_matches(pattern: &str, origin: &str) -> bool {
+    if pattern == "*" || pattern.eq_ignore_ascii_case(origin) {
+        return true;
+    }
+
+    let Some((scheme, domain)) = pattern.split_once("://*.") else {
+        return false;
+    };
+    origin
+        .split_once("://")
+        .is_some_and(|(origin_scheme, host)| {
+            origin_scheme.eq_ignore_ascii_case(scheme)
+                && host.len() > domain.len()
+                && host.as_bytes()[host.len() - domain.len() - 1] == b'.'
+                && host[host.len() - domain.len()..].eq_ignore_ascii_case(domain)
+        })
+}
+
+impl CorsSettings {
+    pub fn enabled(&self) -> bool {
+        !self.allowed_origins.is_empty()
+    }
+
+    /// `Access-Control-Allow-Origin` for requests from `origin`, if it's allowed.
+    fn allow_origin(&self, origin: Option<&HeaderValue>) -> Option<HeaderValue> {
+        if self.allowed_origins.iter().any(|pattern| pattern == "*") {
+            return Some(HeaderValue::from_static("*"));
+        }
+
+        let origin = origin?;
+        let origin_str = origin.to_str().ok()?;
+        self.allowed_origins
+            .iter()
+            .any(|pattern| origin_matches(pattern, origin_str))
+            .then(|| origin.clone())
+    }
+
+    /// Returns true if `request` is a preflight we should answer ourselves.
+    pub fn is_preflight<B>(&self, request: &Request<B>) -> bool {
+        self.enabled() && request.method() == Method::OPTIONS
+    }
+
+    /// Answer the preflight with `headers`.
+    pub fn preflight(&self, headers: &HeaderMap) -> Response<Full<Bytes>> {
+        let mut response = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        self.apply(headers.get(ORIGIN), response.headers_mut());
+
+        if !response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return response;
+        }
+
+        let response_headers = response.headers_mut();
+        if let Ok(methods) = HeaderValue::from_str(&self.allowed_methods.join(", ")) {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        let allowed_headers = if self.allowed_headers.iter().any(|header| header == "*") {
+            // Echo what was asked for, `*` isn't understood by every browser
+            headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else {
+            HeaderValue::from_str(&self.allowed_headers.join(", ")).ok()
+        };
+        if let Some(allowed_headers) = allowed_headers {
+            response_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+        }
+        response_headers.insert(ACCESS_CONTROL_MAX_AGE, self.max_age_secs.into());
+
+        response
+    }
+
+    /// Add the CORS headers for a request from `origin` to `headers`.
+    pub fn apply(&self, origin: Option<&HeaderValue>, headers: &mut HeaderMap) {
+        if !self.enabled() {
+            return;
+        }
+
+        if let Some(allow_origin) = self.allow_origin(origin) {
+            // Allowing one origin out of several makes the response depend on it
+            if allow_origin != "*" {
+                headers.append(VARY, HeaderValue::from_static("origin"));
+            }
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_origin_matches() {
+        assert!(origin_matches("*", "https://app.example.com"));
+        assert!(origin_matches(
+            "https://app.example.com",
+            "https://APP.example.com"
+        ));
+        assert!(origin_matches(
+            "https://*.example.com",
+            "https://app.example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://example.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "https://badexample.com"
+        ));
+        assert!(!origin_matches(
+            "https://*.example.com",
+            "http://app.example.com"
+        ));
+    }
+
+    #[test]
+    fn test_preflight() {
+        let settings = CorsSettings {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            ..Default::default()
+        };
+
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, HeaderValue::from_static("https://app.example.com"));
+        let response = settings.preflight(&headers);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, POST, OPTIONS"
+        );
+        assert_eq!(response.headers()[ACCESS_CONTROL_MAX_AGE], "86400");
+        assert_eq!(response.headers()[VARY], "origin");
+
+        headers.insert(ORIGIN, HeaderValue::from_static("https://evil.example.com"));
+        let response = settings.preflight(&headers);
+        assert!(response.headers().is_empty());
+
+        // Disabled
+        let mut response_headers = HeaderMap::new();
+        CorsSettings::default().apply(headers.get(ORIGIN), &mut response_headers);
+        assert!(response_headers.is_empty());
+    }
+}
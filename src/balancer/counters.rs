@@ -0,0 +1,628 @@
+//! Storage for rate limit and quota counters.
+//!
+//! Quotas are fixed windows: every increment is added to the count of the
+//! window `now` falls in, and the count resets once the window is over.
+//!
+//! Rate limits are token buckets, so clients can't get twice their burst
+//! through around the end of a window. A bucket is stored as the time it's
+//! full again: taking tokens pushes it back, and it can't be pushed further
+//! than `burst` tokens into the future.
+//!
+//! Where the counts live is up to the backend:
+//! - `memory` keeps them per instance, and they're lost on restart.
+//! - `cache` stores them in the cache DB so they survive restarts.
+//! - `redis` shares them between every instance pointed at the same Redis,
+//!   so multi instance deployments enforce limits across all of them.
+
+use crate::{
+    config::types::{
+        CounterBackend,
+        CounterSettings,
+    },
+    database::{
+        accept::db_insert,
+        expiry::now_ms,
+        redis::{
+            RedisClient,
+            RedisError,
+        },
+        types::{
+            GenericBytes,
+            RequestBus,
+        },
+    },
+    db_get,
+};
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    mem,
+    sync::Mutex,
+    time::{
+        Duration,
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+/// Start a new generation of in-memory counters once we're tracking this many.
+const MAX_MEMORY_COUNTERS: usize = 100_000;
+
+/// Takes tokens from a bucket stored in Redis, see `take_tokens`.
+///
+/// Uses the clock of Redis so instances with skewed clocks agree on buckets.
+const TAKE_TOKENS_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local full_at = math.max(tonumber(redis.call('GET', KEYS[1]) or 0), now)
+local new_full_at = full_at + tonumber(ARGV[1])
+local wait = new_full_at - tonumber(ARGV[2]) - now
+if wait > 0 then
+    return wait
+end
+redis.call('SET', KEYS[1], string.format('%d', new_full_at), 'PX', math.ceil((new_full_at - now) / 1000) + 1)
+return 0
+"#;
+
+/// Adds `ARGV[1]` to a counter stored in Redis, see `RedisCounters::increment`.
+///
+/// Sets the expiry in the same step, so a counter can't be left without one.
+const INCREMENT_SCRIPT: &str = r#"
+local count = redis.call('INCRBY', KEYS[1], ARGV[1])
+if redis.call('PTTL', KEYS[1]) < 0 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return count
+"#;
+
+#[derive(Debug)]
+pub enum CounterError {
+    Cache,
+    Redis(RedisError),
+}
+
+impl fmt::Display for CounterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CounterError::Cache => write!(f, "failed to access counter in the cache"),
+            CounterError::Redis(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CounterError {}
+
+impl From<RedisError> for CounterError {
+    fn from(e: RedisError) -> Self {
+        CounterError::Redis(e)
+    }
+}
+
+/// Returns when the `window_ms` long window `now` falls in ends.
+pub fn window_end(now: u64, window_ms: u64) -> u64 {
+    let window_ms = window_ms.max(1);
+    now - now % window_ms + window_ms
+}
+
+/// Current unix time in µs
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_micros() as u64)
+        .unwrap_or_default()
+}
+
+/// Returns how long it takes to refill `tokens` tokens at `per_second`, in µs.
+fn refill_us(tokens: u64, per_second: u64) -> u64 {
+    (1_000_000 / per_second.max(1))
+        .max(1)
+        .saturating_mul(tokens)
+}
+
+/// Take `amount` tokens at `now` from a bucket that's full again at `full_at`,
+/// see `Counters::take`. Times are in µs.
+///
+/// Returns when the bucket is full again after taking them, or how long until
+/// there are enough tokens if there aren't.
+fn take_tokens(
+    full_at: u64,
+    now: u64,
+    amount: u64,
+    per_second: u64,
+    burst: u64,
+) -> Result<u64, u64> {
+    let full_at = full_at
+        .max(now)
+        .saturating_add(refill_us(amount, per_second));
+    let allowed_at = full_at.saturating_sub(refill_us(burst, per_second));
+    if allowed_at > now {
+        return Err(allowed_at - now);
+    }
+    Ok(full_at)
+}
+
+/// Storage for windowed counters.
+pub trait Counters {
+    /// Add `amount` to the counter for `key` in the current `window_ms` long window.
+    ///
+    /// Returns the count in the current window after the increment.
+    fn increment(
+        &self,
+        key: &str,
+        amount: u64,
+        window_ms: u64,
+    ) -> impl Future<Output = Result<u64, CounterError>> + Send;
+
+    /// Take `amount` tokens from the bucket for `key`, which holds up to
+    /// `burst` tokens and gets `per_second` of them back every second.
+    ///
+    /// Returns how long until there are enough tokens if there aren't, in
+    /// which case none are taken.
+    fn take(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+    ) -> impl Future<Output = Result<Option<Duration>, CounterError>> + Send;
+}
+
+/// In-memory entries, forgotten once nobody touched them for a while.
+///
+/// New entries go to `current`. Once it's full, it becomes `previous` and
+/// whatever `previous` held is dropped. Entries get moved back to `current`
+/// when they're touched, so only ones nobody touched for a whole generation
+/// get dropped, without ever scanning for them.
+#[derive(Debug)]
+struct Generations<V> {
+    current: HashMap<String, V>,
+    previous: HashMap<String, V>,
+}
+
+impl<V> Default for Generations<V> {
+    fn default() -> Self {
+        Self {
+            current: HashMap::new(),
+            previous: HashMap::new(),
+        }
+    }
+}
+
+impl<V> Generations<V> {
+    /// Returns the entry for `key`, inserting `default` if there's none.
+    ///
+    /// Also returns the generation dropped to make room for it, if any, which
+    /// is best dropped after releasing any locks.
+    fn entry(
+        &mut self,
+        key: &str,
+        default: impl FnOnce() -> V,
+    ) -> (&mut V, Option<HashMap<String, V>>) {
+        let mut dropped = None;
+        if !self.current.contains_key(key) {
+            let value = self.previous.remove(key).unwrap_or_else(default);
+            if self.current.len() >= MAX_MEMORY_COUNTERS {
+                dropped = Some(mem::replace(
+                    &mut self.previous,
+                    mem::take(&mut self.current),
+                ));
+            }
+            self.current.insert(key.to_string(), value);
+        }
+
+        (self.current.get_mut(key).unwrap(), dropped)
+    }
+}
+
+/// Counters local to this instance.
+#[derive(Debug, Default)]
+pub struct MemoryCounters {
+    // key -> (window end, count)
+    windows: Mutex<Generations<(u64, u64)>>,
+    // key -> when the bucket is full again, in µs
+    buckets: Mutex<Generations<u64>>,
+}
+
+impl MemoryCounters {
+    fn increment_at(&self, key: &str, amount: u64, window_ms: u64, now: u64) -> u64 {
+        let end = window_end(now, window_ms);
+        let (count, _dropped) = {
+            let mut windows = self.windows.lock().unwrap_or_else(|e| e.into_inner());
+            let ((window, count), dropped) = windows.entry(key, || (end, 0));
+            if *window != end {
+                *window = end;
+                *count = 0;
+            }
+            *count = count.saturating_add(amount);
+            (*count, dropped)
+        };
+
+        count
+    }
+
+    fn take_at(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+        now: u64,
+    ) -> Option<u64> {
+        let (wait, _dropped) = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+            let (full_at, dropped) = buckets.entry(key, || 0);
+            let wait = match take_tokens(*full_at, now, amount, per_second, burst) {
+                Ok(new_full_at) => {
+                    *full_at = new_full_at;
+                    None
+                }
+                Err(wait) => Some(wait),
+            };
+            (wait, dropped)
+        };
+
+        wait
+    }
+}
+
+impl Counters for MemoryCounters {
+    async fn increment(&self, key: &str, amount: u64, window_ms: u64) -> Result<u64, CounterError> {
+        Ok(self.increment_at(key, amount, window_ms, now_ms()))
+    }
+
+    async fn take(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+    ) -> Result<Option<Duration>, CounterError> {
+        Ok(self
+            .take_at(key, amount, per_second, burst, now_us())
+            .map(Duration::from_micros))
+    }
+}
+
+/// Counters stored in the cache DB.
+///
+/// Increments are serialized within the instance, but are not atomic across
+/// instances sharing a DB. Use Redis for that.
+#[derive(Debug)]
+pub struct CacheCounters<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    cache: RequestBus<K, V>,
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl<K, V> CacheCounters<K, V>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    pub fn new(cache: RequestBus<K, V>) -> Self {
+        Self {
+            cache,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Counters are prefixed so they can't collide with cached requests.
+    fn db_key(key: &str) -> [u8; 32] {
+        *blake3::hash(format!("blutgang_counter:{}", key).as_bytes()).as_bytes()
+    }
+
+    async fn increment_at(
+        &self,
+        key: &str,
+        amount: u64,
+        window_ms: u64,
+        now: u64,
+    ) -> Result<u64, CounterError> {
+        let _guard = self.lock.lock().await;
+        let db_key = Self::db_key(key);
+        let end = window_end(now, window_ms);
+
+        let stored = db_get!(self.cache, db_key.into()).map_err(|_| CounterError::Cache)?;
+        // Stored as the window end followed by the count
+        let count = match stored.as_deref().map(|stored| stored.split_at_checked(8)) {
+            Some(Some((window, count))) if window == end.to_be_bytes() => {
+                u64::from_be_bytes(count.try_into().map_err(|_| CounterError::Cache)?)
+            }
+            _ => 0,
+        }
+        .saturating_add(amount);
+
+        let mut entry = end.to_be_bytes().to_vec();
+        entry.extend_from_slice(&count.to_be_bytes());
+        drop(db_insert(&self.cache, db_key.into(), entry.into()).await);
+
+        Ok(count)
+    }
+
+    async fn take_at(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+        now: u64,
+    ) -> Result<Option<u64>, CounterError> {
+        let _guard = self.lock.lock().await;
+        let db_key = Self::db_key(&format!("bucket:{}", key));
+
+        let stored = db_get!(self.cache, db_key.into()).map_err(|_| CounterError::Cache)?;
+        let full_at = stored
+            .as_deref()
+            .and_then(|stored| stored.try_into().ok())
+            .map_or(0, u64::from_be_bytes);
+
+        match take_tokens(full_at, now, amount, per_second, burst) {
+            Ok(full_at) => {
+                let entry = full_at.to_be_bytes().to_vec();
+                drop(db_insert(&self.cache, db_key.into(), entry.into()).await);
+                Ok(None)
+            }
+            Err(wait) => Ok(Some(wait)),
+        }
+    }
+}
+
+impl<K, V> Counters for CacheCounters<K, V>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    async fn increment(&self, key: &str, amount: u64, window_ms: u64) -> Result<u64, CounterError> {
+        self.increment_at(key, amount, window_ms, now_ms()).await
+    }
+
+    async fn take(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+    ) -> Result<Option<Duration>, CounterError> {
+        Ok(self
+            .take_at(key, amount, per_second, burst, now_us())
+            .await?
+            .map(Duration::from_micros))
+    }
+}
+
+/// Counters shared between instances through Redis.
+#[derive(Debug)]
+pub struct RedisCounters {
+    client: RedisClient,
+}
+
+impl RedisCounters {
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        Ok(Self {
+            client: RedisClient::new(url)?,
+        })
+    }
+}
+
+impl Counters for RedisCounters {
+    async fn increment(&self, key: &str, amount: u64, window_ms: u64) -> Result<u64, CounterError> {
+        let end = window_end(now_ms(), window_ms);
+        let redis_key = format!("blutgang:counter:{}:{}", key, end);
+        let amount_str = amount.to_string();
+        let window_ms = window_ms.max(1).to_string();
+
+        let count = self
+            .client
+            .command(&[
+                b"EVAL",
+                INCREMENT_SCRIPT.as_bytes(),
+                b"1",
+                redis_key.as_bytes(),
+                amount_str.as_bytes(),
+                window_ms.as_bytes(),
+            ])
+            .await?
+            .as_integer()?;
+
+        Ok(count.max(0) as u64)
+    }
+
+    async fn take(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+    ) -> Result<Option<Duration>, CounterError> {
+        let redis_key = format!("blutgang:bucket:{}", key);
+        let cost = refill_us(amount, per_second).to_string();
+        let capacity = refill_us(burst, per_second).to_string();
+
+        let wait = self
+            .client
+            .command(&[
+                b"EVAL",
+                TAKE_TOKENS_SCRIPT.as_bytes(),
+                b"1",
+                redis_key.as_bytes(),
+                cost.as_bytes(),
+                capacity.as_bytes(),
+            ])
+            .await?
+            .as_integer()?;
+
+        Ok((wait > 0).then(|| Duration::from_micros(wait as u64)))
+    }
+}
+
+/// Counter backend selected in the config.
+#[derive(Debug)]
+pub enum CounterStore<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    Memory(MemoryCounters),
+    Cache(CacheCounters<K, V>),
+    Redis(RedisCounters),
+}
+
+impl<K, V> CounterStore<K, V>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    pub fn new(settings: &CounterSettings, cache: RequestBus<K, V>) -> Result<Self, RedisError> {
+        Ok(match settings.backend {
+            CounterBackend::Memory => CounterStore::Memory(MemoryCounters::default()),
+            CounterBackend::Cache => CounterStore::Cache(CacheCounters::new(cache)),
+            CounterBackend::Redis => CounterStore::Redis(RedisCounters::new(&settings.redis_url)?),
+        })
+    }
+}
+
+impl<K, V> Counters for CounterStore<K, V>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    async fn increment(&self, key: &str, amount: u64, window_ms: u64) -> Result<u64, CounterError> {
+        match self {
+            CounterStore::Memory(counters) => counters.increment(key, amount, window_ms).await,
+            CounterStore::Cache(counters) => counters.increment(key, amount, window_ms).await,
+            CounterStore::Redis(counters) => counters.increment(key, amount, window_ms).await,
+        }
+    }
+
+    async fn take(
+        &self,
+        key: &str,
+        amount: u64,
+        per_second: u64,
+        burst: u64,
+    ) -> Result<Option<Duration>, CounterError> {
+        match self {
+            CounterStore::Memory(counters) => counters.take(key, amount, per_second, burst).await,
+            CounterStore::Cache(counters) => counters.take(key, amount, per_second, burst).await,
+            CounterStore::Redis(counters) => counters.take(key, amount, per_second, burst).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        eviction::CacheEviction,
+        hot_cache::HotCache,
+    };
+    use crate::database_processing;
+    use sled::{
+        Config,
+        Db,
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_window_end() {
+        assert_eq!(window_end(0, 1000), 1000);
+        assert_eq!(window_end(999, 1000), 1000);
+        assert_eq!(window_end(1000, 1000), 2000);
+        assert_eq!(window_end(5, 0), 6);
+    }
+
+    #[test]
+    fn test_memory_counters() {
+        let counters = MemoryCounters::default();
+
+        assert_eq!(counters.increment_at("a", 1, 1000, 100), 1);
+        assert_eq!(counters.increment_at("a", 2, 1000, 900), 3);
+        assert_eq!(counters.increment_at("b", 5, 1000, 900), 5);
+
+        // New window
+        assert_eq!(counters.increment_at("a", 1, 1000, 1000), 1);
+    }
+
+    #[test]
+    fn test_take_tokens() {
+        // 10 tokens a second, so one every 100ms, up to 5 at once
+        let full_at = take_tokens(0, 1_000_000, 5, 10, 5).unwrap();
+        assert_eq!(full_at, 1_500_000);
+        assert_eq!(take_tokens(full_at, 1_000_000, 1, 10, 5), Err(100_000));
+
+        // Tokens come back over time rather than all at once
+        let full_at = take_tokens(full_at, 1_100_000, 1, 10, 5).unwrap();
+        assert_eq!(take_tokens(full_at, 1_100_000, 1, 10, 5), Err(100_000));
+        assert_eq!(take_tokens(full_at, 1_150_000, 2, 10, 5), Err(150_000));
+        assert!(take_tokens(full_at, 1_300_000, 2, 10, 5).is_ok());
+
+        // More than the bucket holds never gets through
+        assert!(take_tokens(0, 1_000_000, 6, 10, 5).is_err());
+    }
+
+    #[test]
+    fn test_memory_buckets() {
+        let counters = MemoryCounters::default();
+
+        assert_eq!(counters.take_at("a", 2, 10, 2, 1_000_000), None);
+        assert_eq!(counters.take_at("a", 1, 10, 2, 1_000_000), Some(100_000));
+        assert_eq!(counters.take_at("b", 1, 10, 2, 1_000_000), None);
+        assert_eq!(counters.take_at("a", 1, 10, 2, 1_100_000), None);
+    }
+
+    #[test]
+    fn test_generations() {
+        let mut generations = Generations::default();
+        for i in 0..MAX_MEMORY_COUNTERS {
+            *generations.entry(&i.to_string(), || 0).0 = i;
+        }
+        assert!(generations.previous.is_empty());
+
+        // Full, the next new entry starts a new generation
+        let (_, dropped) = generations.entry("new", || 0);
+        assert!(dropped.is_some_and(|dropped| dropped.is_empty()));
+        assert_eq!(generations.current.len(), 1);
+
+        // Entries in use move to the new generation
+        assert_eq!(*generations.entry("7", || 0).0, 7);
+        assert_eq!(generations.current.len(), 2);
+        assert!(!generations.previous.contains_key("7"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_counters() {
+        let cache = Db::open_with_config(&Config::tmp().unwrap()).unwrap();
+        let (db_tx, db_rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
+        let counters = CacheCounters::<[u8; 32], Vec<u8>>::new(db_tx);
+
+        assert_eq!(counters.increment_at("a", 1, 1000, 100).await.unwrap(), 1);
+        assert_eq!(counters.increment_at("a", 2, 1000, 900).await.unwrap(), 3);
+        assert_eq!(counters.increment_at("b", 1, 1000, 900).await.unwrap(), 1);
+        assert_eq!(counters.increment_at("a", 1, 1000, 1500).await.unwrap(), 1);
+
+        assert_eq!(
+            counters.take_at("a", 2, 10, 2, 1_000_000).await.unwrap(),
+            None
+        );
+        assert_eq!(
+            counters.take_at("a", 1, 10, 2, 1_000_000).await.unwrap(),
+            Some(100_000)
+        );
+        assert_eq!(
+            counters.take_at("a", 1, 10, 2, 1_100_000).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Latency budget aware method downgrades.
+//!
+//! Blocks with full transactions are among the heaviest responses RPCs
+//! serve, and the first to get slow when the pool is under load. Dashboards
+//! polling the head usually prefer fast approximate data over slow exact data.
+//!
+//! When the fastest eligible RPC is slower than the configured budget, full
+//! block requests are sent as their header-only variant instead. Transactions
+//! we already have cached are put back into the block, the rest stay hashes,
+//! and the response is annotated so clients can tell it was downgraded:
+//!
+//! ```json
+//! {
+//!   "jsonrpc": "2.0",
+//!   "id": 1,
+//!   "result": { "number": "0x10", "transactions": [{ "hash": "0x01", ... }, "0x02"] },
+//!   "blutgang_downgraded": { "reason": "latency_budget", "budgetMs": 300, "latencyMs": 512, "missingTransactions": 1 }
+//! }
+//! ```
+//!
+//! Requests whose full response is already cached are never downgraded.
+
+use crate::{
+    balancer::{
+        processing::CacheArgs,
+        selection::select::{
+            argsort_eligible,
+            Route,
+        },
+    },
+    config::types::DowngradeSettings,
+    database::{
+        serialization::decode_cached,
+        types::GenericBytes,
+    },
+    db_get,
+    rpc::types::Rpc,
+};
+
+#[cfg(feature = "xxhash")]
+use zerocopy::AsBytes;
+
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Field downgraded responses are annotated with.
+pub const DOWNGRADED: &str = "blutgang_downgraded";
+
+/// A request that got downgraded, and why.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Downgrade {
+    budget_ms: u64,
+    latency_ms: f64,
+}
+
+/// Returns true if `tx` asks for a block with full transactions.
+fn is_full_block(tx: &Value) -> bool {
+    matches!(
+        tx["method"].as_str(),
+        Some("eth_getBlockByNumber" | "eth_getBlockByHash")
+    ) && tx["params"][1].as_bool() == Some(true)
+}
+
+/// Average latency of the fastest RPC eligible for selection, in ms.
+fn fastest_latency_ms(rpc_list: &[Rpc]) -> Option<f64> {
+    argsort_eligible(rpc_list, None, Route::default())
+        .first()
+        // Latencies are tracked in ns
+        .map(|&index| rpc_list[index].status.latency / 1_000_000.0)
+}
+
+impl DowngradeSettings {
+    /// Returns the downgrade to apply to `tx` if the RPCs are too slow to serve it within budget.
+    pub fn check(&self, tx: &Value, rpc_list: &[Rpc]) -> Option<Downgrade> {
+        if self.full_blocks_budget_ms == 0 || !is_full_block(tx) {
+            return None;
+        }
+
+        let latency_ms = fastest_latency_ms(rpc_list)?;
+        (latency_ms > self.full_blocks_budget_ms as f64).then_some(Downgrade {
+            budget_ms: self.full_blocks_budget_ms,
+            latency_ms,
+        })
+    }
+}
+
+impl Downgrade {
+    /// Rewrite `tx` into its header-only variant.
+    pub fn apply(&self, tx: &mut Value) {
+        tx["params"][1] = Value::Bool(false);
+
+        if let Some(method) = tx["method"].as_str() {
+            metrics::counter!("requests_downgraded_total", "method" => method.to_string())
+                .increment(1);
+        }
+    }
+
+    /// Fill in cached transactions and annotate the response to a downgraded request.
+    ///
+    /// Error responses are returned as is.
+    pub async fn complete<K, V>(&self, response: String, cache_args: &CacheArgs<K, V>) -> String
+    where
+        K: GenericBytes + From<[u8; 32]>,
+        V: GenericBytes + From<Vec<u8>>,
+    {
+        let Ok(mut response) = serde_json::from_str::<Value>(&response) else {
+            return response;
+        };
+        let Some(transactions) = response["result"]["transactions"].as_array_mut() else {
+            return response.to_string();
+        };
+
+        let mut missing = 0;
+        for tx in transactions.iter_mut() {
+            let Some(tx_hash) = tx.as_str() else {
+                continue;
+            };
+            match cached_transaction(tx_hash, cache_args).await {
+                Some(full) => *tx = full,
+                None => missing += 1,
+            }
+        }
+
+        response[DOWNGRADED] = json!({
+            "reason": "latency_budget",
+            "budgetMs": self.budget_ms,
+            "latencyMs": self.latency_ms.round() as u64,
+            "missingTransactions": missing,
+        });
+        response.to_string()
+    }
+}
+
+/// Returns the cached response to `tx`, if any.
+///
+/// `tx` has to be shaped like the requests that get cached, with a `null` id.
+pub async fn cached_response<K, V>(tx: &Value, cache_args: &CacheArgs<K, V>) -> Option<Value>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let tx_hash = cache_args.cache_key(tx);
+    db_get!(cache_args.cache, tx_hash.as_bytes().to_owned().into())
+        .ok()
+        .flatten()
+        .and_then(|mut rax| decode_cached(rax.as_mut()).ok().flatten())
+}
+
+/// Returns the cached transaction with `tx_hash`, if any.
+async fn cached_transaction<K, V>(tx_hash: &str, cache_args: &CacheArgs<K, V>) -> Option<Value>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let tx = json!({
+        "jsonrpc": "2.0",
+        "id": null,
+        "method": "eth_getTransactionByHash",
+        "params": [tx_hash],
+    });
+
+    cached_response(&tx, cache_args)
+        .await
+        .map(|mut response| response["result"].take())
+        .filter(Value::is_object)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        balancer::processing::cache_query,
+        config::types::CachePolicy,
+    };
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    fn rpc(latency_ms: f64) -> Rpc {
+        let mut rpc = Rpc::default();
+        rpc.status.latency = latency_ms * 1_000_000.0;
+        rpc
+    }
+
+    #[test]
+    fn test_downgrade_check() {
+        let settings = DowngradeSettings {
+            full_blocks_budget_ms: 300,
+        };
+        let full = json!({"method": "eth_getBlockByNumber", "params": ["latest", true]});
+        let slow = [rpc(500.0), rpc(400.0)];
+
+        assert_eq!(
+            settings.check(&full, &slow),
+            Some(Downgrade {
+                budget_ms: 300,
+                latency_ms: 400.0,
+            })
+        );
+        // One fast RPC is enough
+        assert!(settings.check(&full, &[rpc(500.0), rpc(100.0)]).is_none());
+        // Header-only requests and other methods are left alone
+        let header = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
+        assert!(settings.check(&header, &slow).is_none());
+        let balance = json!({"method": "eth_getBalance", "params": ["0x01", "latest"]});
+        assert!(settings.check(&balance, &slow).is_none());
+        // Disabled
+        assert!(DowngradeSettings::default().check(&full, &slow).is_none());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_downgrade_complete() {
+        let mut cache_args = CacheArgs::default();
+        // Transactions are only cached with a policy
+        cache_args.policies = Arc::new(HashMap::from([(
+            "eth_getTransactionByHash".to_string(),
+            CachePolicy::Forever,
+        )]));
+        let downgrade = Downgrade {
+            budget_ms: 300,
+            latency_ms: 512.4,
+        };
+
+        let mut tx = json!({"method": "eth_getBlockByHash", "params": ["0xab", true]});
+        downgrade.apply(&mut tx);
+        assert_eq!(tx["params"][1], false);
+
+        // Cache one of the two transactions
+        let cached_tx = json!({
+            "jsonrpc": "2.0",
+            "id": null,
+            "method": "eth_getTransactionByHash",
+            "params": ["0x01"],
+        });
+        let mut rx =
+            r#"{"jsonrpc":"2.0","id":1,"result":{"hash":"0x01","nonce":"0x0"}}"#.to_string();
+        let tx_hash = cache_args.cache_key(&cached_tx);
+        cache_query(&mut rx, cached_tx, tx_hash, &cache_args).await;
+
+        let response =
+            r#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x10","transactions":["0x01","0x02"]}}"#;
+        let response: Value =
+            serde_json::from_str(&downgrade.complete(response.to_string(), &cache_args).await)
+                .unwrap();
+        assert_eq!(
+            response["result"]["transactions"],
+            json!([{"hash": "0x01", "nonce": "0x0"}, "0x02"])
+        );
+        assert_eq!(
+            response[DOWNGRADED],
+            json!({"reason": "latency_budget", "budgetMs": 300, "latencyMs": 512, "missingTransactions": 1})
+        );
+
+        // Errors aren't touched
+        let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"oops"}}"#;
+        assert_eq!(
+            downgrade.complete(error.to_string(), &cache_args).await,
+            serde_json::from_str::<Value>(error).unwrap().to_string()
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
+    config::types::JsonLimits,
     rpc::method::EthRpcMethod,
     NamedBlocknumbers,
 };
 use http_body_util::BodyExt;
 use hyper::{
-    body::Incoming,
+    body::Body,
     Request,
 };
 use memchr::memmem;
@@ -120,11 +121,45 @@ pub fn get_block_number_from_request(
     Some(block_number)
 }
 
+/// Return the number of the block a json-rpc response belongs to, for responses
+/// that include one like blocks, transactions, receipts and logs.
+///
+/// For arrays of logs we return the highest block number, as any reorg that
+/// touches one of them has to touch that block too.
+pub fn get_block_number_from_response(rx: &Value) -> Option<u64> {
+    fn block_number(item: &Value) -> Option<u64> {
+        let number = item["blockNumber"].as_str().or(item["number"].as_str())?;
+        u64::from_str_radix(number.strip_prefix("0x")?, 16).ok()
+    }
+
+    match &rx["result"] {
+        Value::Array(items) => items.iter().filter_map(block_number).max(),
+        result => block_number(result),
+    }
+}
+
 /// Replaces block tags with a hex number and return the request
+///
+/// `safe` is left as is, it's only rewritten when tags are pinned.
 pub fn replace_block_tags(
     tx: &mut Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
 ) -> Value {
+    let named_blocknumbers = NamedBlocknumbers {
+        safe: 0,
+        ..*named_blocknumbers.read().unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+    };
+
+    rewrite_block_tags(tx, &named_blocknumbers)
+}
+
+/// Replaces the `latest`, `safe` and `finalized` tags with the numbers in
+/// `named_blocknumbers` and return the request. Tags we don't know the number
+/// of, `0`, are left as is.
+pub fn rewrite_block_tags(tx: &mut Value, named_blocknumbers: &NamedBlocknumbers) -> Value {
     // Return if `params` is not a thing
     let params = tx["params"].as_array();
     if params.map_or(true, |p| p.is_empty()) {
@@ -139,52 +174,50 @@ pub fn replace_block_tags(
     // Extract the block number parameter
     let block_number = tx["params"][position].to_string().replace('\"', "");
 
-    // Check if the block number is a named tag
-    let nn = has_named_number(&block_number);
-    if nn != NamedNumber::Null {
-        let rwlock_guard = named_blocknumbers.read().unwrap_or_else(|e| {
-            // Handle the case where the RwLock is poisoned
-            e.into_inner()
-        });
-
-        // Replace the named block tag with its corresponding hex value
-        match nn {
-            NamedNumber::Latest => {
-                if rwlock_guard.latest != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
-                }
-            }
-            NamedNumber::Finalized => {
-                if rwlock_guard.finalized != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
-                }
-            }
-            _ => (),
-        }
+    // Replace the named block tag with its corresponding hex value
+    let number = match has_named_number(&block_number) {
+        NamedNumber::Latest => named_blocknumbers.latest,
+        NamedNumber::Safe => named_blocknumbers.safe,
+        NamedNumber::Finalized => named_blocknumbers.finalized,
+        _ => 0,
+    };
+    if number != 0 {
+        tx["params"][position] = json!(format!("0x{:x}", number));
     }
 
     tx.to_owned()
 }
 
-/// *Converts* a hyper `Incoming` request to a `serde_json::Value`.
-pub async fn incoming_to_value(tx: Request<Incoming>) -> Result<Value, hyper::Error> {
+/// *Converts* a hyper request to a `serde_json::Value`.
+///
+/// Requests exceeding `json_limits` are treated as invalid JSON.
+pub async fn incoming_to_value<B>(
+    tx: Request<B>,
+    json_limits: &JsonLimits,
+) -> Result<Value, B::Error>
+where
+    B: Body + std::fmt::Debug,
+{
     tracing::debug!(?tx, "Incoming request");
 
+    // Insane error handling
+    let invalid = json!({
+        "id": Null,
+        "jsonrpc": "2.0",
+        "result": "Invalid JSON",
+    });
+
     let tx = tx.collect().await?.to_bytes().clone();
+    if let Err(err) = json_limits.check(&tx) {
+        tracing::warn!(%err, "Request exceeds JSON limits");
+        return Ok(invalid);
+    }
+
     let mut tx = from_utf8(&tx).unwrap().to_owned();
 
     let ret = match unsafe { from_str(&mut tx) } {
         Ok(ret) => ret,
-        Err(_) => {
-            // Insane error handling
-            let ret = json!({
-                "id": Null,
-                "jsonrpc": "2.0",
-                "result": "Invalid JSON",
-            });
-
-            return Ok(ret);
-        }
+        Err(_) => return Ok(invalid),
     };
 
     Ok(ret)
@@ -220,6 +253,21 @@ mod tests {
         assert_eq!(has_named_number("0"), NamedNumber::Null);
     }
 
+    #[test]
+    fn get_block_number_from_response_test() {
+        let receipt = json!({"id": 1, "result": {"blockNumber": "0x1b4", "status": "0x1"}});
+        assert_eq!(get_block_number_from_response(&receipt), Some(436));
+
+        let block = json!({"id": 1, "result": {"number": "0x10", "hash": "0x00"}});
+        assert_eq!(get_block_number_from_response(&block), Some(16));
+
+        let logs = json!({"id": 1, "result": [{"blockNumber": "0x2"}, {"blockNumber": "0x5"}]});
+        assert_eq!(get_block_number_from_response(&logs), Some(5));
+
+        let balance = json!({"id": 1, "result": "0x10"});
+        assert_eq!(get_block_number_from_response(&balance), None);
+    }
+
     #[test]
     fn get_block_number_from_request_test() {
         // Set up a fake NamedBlocknumbers
@@ -476,6 +524,13 @@ mod tests {
 
             assert_eq!(a, expected);
         }
+
+        // `safe` is only rewritten when pinned
+        let mut tx = json!({
+            "method": EthRpcMethod::GetBalance,
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "safe"]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
     }
 
     #[test]
@@ -517,6 +572,36 @@ mod tests {
         assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
     }
 
+    #[test]
+    fn rewrite_pinned_block_tags_test() {
+        let pinned = NamedBlocknumbers {
+            latest: 0x20,
+            safe: 0x18,
+            finalized: 0x10,
+            ..Default::default()
+        };
+
+        for (tag, number) in [("latest", "0x20"), ("safe", "0x18"), ("finalized", "0x10")] {
+            let mut tx = json!({
+                "method": EthRpcMethod::GetBalance,
+                "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", tag]
+            });
+            let expected = json!({
+                "method": EthRpcMethod::GetBalance,
+                "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", number]
+            });
+
+            assert_eq!(rewrite_block_tags(&mut tx, &pinned), expected);
+        }
+
+        // Not pinned
+        let mut tx = json!({
+            "method": EthRpcMethod::GetBalance,
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "pending"]
+        });
+        assert_eq!(rewrite_block_tags(&mut tx, &pinned), tx);
+    }
+
     #[test]
     fn handle_non_string_block_number_test() {
         let named_blocknumbers = dummy_named_blocknumbers();
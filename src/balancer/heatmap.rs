@@ -0,0 +1,294 @@
+//! Request heatmap.
+//!
+//! Counts how often each contract and function gets called through
+//! `eth_call`, `eth_estimateGas` and `eth_sendRawTransaction`, so operators
+//! can see what's worth pre-caching and which backends need archive depth.
+//!
+//! Targets of `eth_sendRawTransaction` are recovered by decoding just enough
+//! of the signed transaction RLP to get its `to` and `data` fields.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Key used for transactions that create contracts.
+const CONTRACT_CREATION: &str = "create";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+pub struct HeatmapKey {
+    pub method: String,
+    /// Address of the called contract, or `create` for deployments
+    pub contract: String,
+    /// 4-byte function selector, if the call had calldata
+    pub selector: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HeatmapEntry {
+    #[serde(flatten)]
+    pub key: HeatmapKey,
+    pub count: u64,
+}
+
+/// Decode a `0x` prefixed hex string.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 + bytes.len() * 2);
+    hex.push_str("0x");
+    for byte in bytes {
+        hex.push_str(&format!("{:02x}", byte));
+    }
+    hex
+}
+
+/// Split the RLP item at the start of `data` into its payload and whatever follows it.
+fn rlp_item(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&prefix, rest) = data.split_first()?;
+
+    let (offset, len) = match prefix {
+        // Single byte
+        0x00..=0x7f => return Some((&data[..1], rest)),
+        // Short string or list
+        0x80..=0xb7 => (0, (prefix - 0x80) as usize),
+        0xc0..=0xf7 => (0, (prefix - 0xc0) as usize),
+        // Long string or list, the length of the length comes first
+        0xb8..=0xbf | 0xf8..=0xff => {
+            let len_of_len = if prefix >= 0xf8 {
+                prefix - 0xf7
+            } else {
+                prefix - 0xb7
+            } as usize;
+            let len_bytes = rest.get(..len_of_len)?;
+            if len_of_len > 8 {
+                return None;
+            }
+            let len = len_bytes
+                .iter()
+                .fold(0u64, |len, byte| (len << 8) | *byte as u64);
+            (len_of_len, usize::try_from(len).ok()?)
+        }
+    };
+
+    let end = offset.checked_add(len)?;
+    Some((rest.get(offset..end)?, &rest[end..]))
+}
+
+/// Returns the `to` and `data` fields of a signed raw transaction.
+///
+/// `to` is `None` for contract creations.
+fn decode_raw_transaction(raw: &[u8]) -> Option<(Option<Vec<u8>>, Vec<u8>)> {
+    // Typed transactions (EIP-2718) are prefixed by their type
+    let (to_index, rlp) = match raw.first()? {
+        0x00..=0x7f => {
+            let index = if raw[0] == 0x01 { 4 } else { 5 };
+            (index, &raw[1..])
+        }
+        _ => (3, raw),
+    };
+
+    let (mut fields, _) = rlp_item(rlp)?;
+    let mut items = Vec::with_capacity(to_index + 3);
+    while !fields.is_empty() && items.len() <= to_index + 2 {
+        let (item, rest) = rlp_item(fields)?;
+        items.push(item);
+        fields = rest;
+    }
+
+    let to = items.get(to_index)?;
+    let data = items.get(to_index + 2)?;
+    let to = (!to.is_empty()).then(|| to.to_vec());
+
+    Some((to, data.to_vec()))
+}
+
+/// Returns what we count `tx` under in the heatmap, if it's a call we track.
+fn heatmap_key(tx: &Value) -> Option<HeatmapKey> {
+    let method = tx["method"].as_str()?;
+
+    let (contract, data) = match method {
+        "eth_call" | "eth_estimateGas" => {
+            let call = &tx["params"][0];
+            let contract = call["to"]
+                .as_str()
+                .map_or(CONTRACT_CREATION.to_string(), str::to_lowercase);
+            let data = call["input"]
+                .as_str()
+                .or(call["data"].as_str())
+                .and_then(decode_hex)
+                .unwrap_or_default();
+            (contract, data)
+        }
+        "eth_sendRawTransaction" => {
+            let raw = decode_hex(tx["params"][0].as_str()?)?;
+            let (to, data) = decode_raw_transaction(&raw)?;
+            let contract = to.map_or(CONTRACT_CREATION.to_string(), |to| encode_hex(&to));
+            (contract, data)
+        }
+        _ => return None,
+    };
+
+    Some(HeatmapKey {
+        method: method.to_string(),
+        contract,
+        selector: data.get(..4).map(encode_hex),
+    })
+}
+
+/// Bounded count of calls per contract and function.
+#[derive(Debug, Default)]
+pub struct RequestHeatmap {
+    capacity: usize,
+    counts: Mutex<HashMap<HeatmapKey, u64>>,
+}
+
+impl RequestHeatmap {
+    /// Track up to `capacity` distinct targets. `0` disables the heatmap.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count `tx` if it's a call we track.
+    ///
+    /// Once full, the least queried target makes room for new ones.
+    pub fn record(&self, tx: &Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Some(key) = heatmap_key(tx) else {
+            return;
+        };
+
+        let mut counts = self.counts.lock().unwrap_or_else(|e| e.into_inner());
+        if !counts.contains_key(&key) && counts.len() >= self.capacity {
+            let coldest = counts
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, _)| key.clone());
+            if let Some(coldest) = coldest {
+                counts.remove(&coldest);
+            }
+        }
+
+        *counts.entry(key).or_insert(0) += 1;
+    }
+
+    /// Returns the `limit` most queried targets, hottest first.
+    pub fn top(&self, limit: usize) -> Vec<HeatmapEntry> {
+        let mut entries: Vec<HeatmapEntry> = self
+            .counts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .map(|(key, count)| {
+                HeatmapEntry {
+                    key: key.clone(),
+                    count: *count,
+                }
+            })
+            .collect();
+
+        entries.sort_by(|a, b| b.count.cmp(&a.count));
+        entries.truncate(limit);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // EIP-1559 transfer(address,uint256) call to 0xdac17f958d2ee523a2206206994597c13d831ec7
+    const RAW_1559: &str = "0x02f8b10180843b9aca00850ba43b74008301117094dac17f958d2ee523a22062\
+        06994597c13d831ec780b844a9059cbb00000000000000000000000011111111\
+        1111111111111111111111111111111100000000000000000000000000000000\
+        00000000000000000000000000000001c080a0aaaaaaaaaaaaaaaaaaaaaaaaaa\
+        aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa0bbbbbbbbbbbbbbbbbbbbbbbb\
+        bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+
+    // Legacy contract creation with `0x6080` as init code
+    const RAW_LEGACY_CREATE: &str = "0xd3808504a817c800830186a08080826080258080";
+
+    #[test]
+    fn test_decode_raw_transaction() {
+        let (to, data) = decode_raw_transaction(&decode_hex(RAW_1559).unwrap()).unwrap();
+        assert_eq!(
+            encode_hex(&to.unwrap()),
+            "0xdac17f958d2ee523a2206206994597c13d831ec7"
+        );
+        assert_eq!(encode_hex(&data[..4]), "0xa9059cbb");
+
+        let (to, data) = decode_raw_transaction(&decode_hex(RAW_LEGACY_CREATE).unwrap()).unwrap();
+        assert!(to.is_none());
+        assert_eq!(data, [0x60, 0x80]);
+
+        assert!(decode_raw_transaction(&[0x02, 0xf8]).is_none());
+    }
+
+    #[test]
+    fn test_heatmap_key() {
+        let call = json!({
+            "method": "eth_call",
+            "params": [{"to": "0xDAC17F958D2ee523a2206206994597C13D831ec7", "data": "0x70a08231000000"}, "latest"]
+        });
+        assert_eq!(
+            heatmap_key(&call),
+            Some(HeatmapKey {
+                method: "eth_call".to_string(),
+                contract: "0xdac17f958d2ee523a2206206994597c13d831ec7".to_string(),
+                selector: Some("0x70a08231".to_string()),
+            })
+        );
+
+        let raw = json!({"method": "eth_sendRawTransaction", "params": [RAW_1559]});
+        assert_eq!(
+            heatmap_key(&raw).unwrap().selector.as_deref(),
+            Some("0xa9059cbb")
+        );
+
+        assert!(heatmap_key(&json!({"method": "eth_blockNumber", "params": []})).is_none());
+    }
+
+    #[test]
+    fn test_heatmap_bounded() {
+        let heatmap = RequestHeatmap::new(2);
+        let call = |to: &str| json!({"method": "eth_call", "params": [{"to": to}, "latest"]});
+
+        heatmap.record(&call("0x01"));
+        heatmap.record(&call("0x01"));
+        heatmap.record(&call("0x02"));
+        // Evicts 0x02, the coldest
+        heatmap.record(&call("0x03"));
+
+        let top = heatmap.top(10);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0].key.contract, "0x01");
+        assert_eq!(top[0].count, 2);
+        assert_eq!(top[1].key.contract, "0x03");
+        assert_eq!(top[1].key.selector, None);
+        assert_eq!(heatmap.top(1).len(), 1);
+
+        // Disabled heatmap records nothing
+        let heatmap = RequestHeatmap::new(0);
+        heatmap.record(&call("0x01"));
+        assert!(heatmap.top(10).is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+//! Bounds on JSON payloads.
+//!
+//! Before handing client requests and RPC responses to the parser, we do a
+//! cheap single pass over the raw bytes and reject payloads that are nested
+//! too deep, contain huge arrays, or consist of too many tokens. This keeps
+//! pathological payloads from exhausting memory or CPU in the parser.
+//!
+//! Client requests exceeding the limits are rejected. RPC responses exceeding
+//! them are legitimate, just big, so they're passed through to the client as
+//! is, without parsing or caching them.
+
+use crate::config::types::JsonLimits;
+
+use std::{
+    convert::Infallible,
+    fmt,
+};
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use rust_tracing::deps::metrics;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum JsonLimitError {
+    TooDeep(usize),
+    ArrayTooLong(usize),
+    TooManyTokens(usize),
+}
+
+impl fmt::Display for JsonLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonLimitError::TooDeep(max) => write!(f, "JSON nested deeper than {}", max),
+            JsonLimitError::ArrayTooLong(max) => {
+                write!(f, "JSON array longer than {} elements", max)
+            }
+            JsonLimitError::TooManyTokens(max) => write!(f, "JSON has more than {} tokens", max),
+        }
+    }
+}
+
+impl std::error::Error for JsonLimitError {}
+
+/// Returns true if `limit` is set and `value` exceeds it. `0` means unlimited.
+fn exceeds(value: usize, limit: usize) -> bool {
+    limit != 0 && value > limit
+}
+
+impl JsonLimits {
+    /// Check if `bytes` stays within the limits.
+    ///
+    /// This does not validate the JSON, only bounds it so it's safe to parse.
+    pub fn check(&self, bytes: &[u8]) -> Result<(), JsonLimitError> {
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut in_scalar = false;
+        let mut tokens = 0;
+        // Number of commas in each array we're in, `None` for objects
+        let mut containers: Vec<Option<usize>> = Vec::new();
+
+        for &byte in bytes {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if byte == b'\\' {
+                    escaped = true;
+                } else if byte == b'"' {
+                    in_string = false;
+                }
+                continue;
+            }
+
+            let is_scalar = byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'+' | b'.');
+            let starts_token = match byte {
+                b'"' => {
+                    in_string = true;
+                    true
+                }
+                b'{' | b'[' => {
+                    containers.push(if byte == b'[' { Some(0) } else { None });
+                    if exceeds(containers.len(), self.max_depth) {
+                        return Err(JsonLimitError::TooDeep(self.max_depth));
+                    }
+                    true
+                }
+                b'}' | b']' => {
+                    containers.pop();
+                    false
+                }
+                b',' => {
+                    // An array with n commas has n + 1 elements
+                    if let Some(Some(commas)) = containers.last_mut() {
+                        *commas += 1;
+                        if exceeds(*commas + 1, self.max_array_len) {
+                            return Err(JsonLimitError::ArrayTooLong(self.max_array_len));
+                        }
+                    }
+                    false
+                }
+                _ => is_scalar && !in_scalar,
+            };
+            in_scalar = is_scalar;
+
+            if starts_token {
+                tokens += 1;
+                if exceeds(tokens, self.max_tokens) {
+                    return Err(JsonLimitError::TooManyTokens(self.max_tokens));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Hand `response` to the client as the RPC sent it.
+///
+/// For responses exceeding the limits, which we can't look into.
+pub fn passthrough_response(response: String) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    metrics::counter!("responses_passed_through_total").increment(1);
+    Ok(hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(response)))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_depth: usize, max_array_len: usize, max_tokens: usize) -> JsonLimits {
+        JsonLimits {
+            max_depth,
+            max_array_len,
+            max_tokens,
+        }
+    }
+
+    #[test]
+    fn test_within_limits() {
+        let request = br#"{"jsonrpc":"2.0","id":1,"method":"eth_getBalance","params":["0x388c818ca8b9251b393131c08a736a67ccb19297","latest"]}"#;
+        assert!(limits(2, 2, 13).check(request).is_ok());
+        assert!(JsonLimits::default().check(request).is_ok());
+        assert!(limits(0, 0, 0).check(request).is_ok());
+    }
+
+    #[test]
+    fn test_depth() {
+        let nested = "[".repeat(10) + &"]".repeat(10);
+        assert!(limits(10, 0, 0).check(nested.as_bytes()).is_ok());
+        assert_eq!(
+            limits(9, 0, 0).check(nested.as_bytes()),
+            Err(JsonLimitError::TooDeep(9))
+        );
+
+        // Brackets in strings don't count
+        assert!(limits(1, 0, 0).check(br#"{"a":"[[[[{{{{"}"#).is_ok());
+        assert!(limits(1, 0, 0).check(br#"{"a":"\"[[[["}"#).is_ok());
+    }
+
+    #[test]
+    fn test_array_len() {
+        assert!(limits(0, 3, 0).check(b"[1,2,3]").is_ok());
+        assert!(limits(0, 3, 0).check(b"[[1,2,3],[1,2,3],[]]").is_ok());
+        assert!(limits(0, 1, 0).check(b"[[]]").is_ok());
+        assert!(limits(0, 1, 0).check(b"[]").is_ok());
+        assert_eq!(
+            limits(0, 3, 0).check(b"[1,2,3,4]"),
+            Err(JsonLimitError::ArrayTooLong(3))
+        );
+        // Commas in objects and strings don't count
+        assert!(limits(0, 1, 0).check(br#"[{"a":1,"b":"1,2,3"}]"#).is_ok());
+    }
+
+    #[test]
+    fn test_tokens() {
+        // `{`, `"a"`, `[`, `1`, `true`, `null`
+        let input = br#"{"a":[1, true, null]}"#;
+        assert!(limits(0, 0, 6).check(input).is_ok());
+        assert_eq!(
+            limits(0, 0, 5).check(input),
+            Err(JsonLimitError::TooManyTokens(5))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_passthrough_response() {
+        use http_body_util::BodyExt;
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":[[[[1]]]]}"#;
+        let response = passthrough_response(body.to_string()).unwrap();
+        assert_eq!(response.status(), 200);
+
+        let served = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(served, body.as_bytes());
+    }
+}
@@ -0,0 +1,277 @@
+//! Client-facing listeners.
+//!
+//! Blutgang listens on every address in `address`, so dual-stack hosts can
+//! serve IPv4 and IPv6 clients from one instance, and optionally on a unix
+//! socket at `socket`, so co-located services can skip TCP entirely.
+//!
+//! Connections from every listener are served the same way, TLS included.
+//! Connections over the socket have no peer address, so the client blocklist
+//! doesn't apply to them.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{
+        Path,
+        PathBuf,
+    },
+    pin::Pin,
+    task::{
+        Context,
+        Poll,
+    },
+};
+
+use socket2::{
+    Domain,
+    Protocol,
+    Socket,
+    Type,
+};
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncWrite,
+        ReadBuf,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::mpsc,
+};
+
+#[cfg(unix)]
+use tokio::net::{
+    UnixListener,
+    UnixStream,
+};
+
+/// Connection accepted on one of the listeners.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Self::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+}
+
+/// Accepted connection, with the address of the peer if it has one.
+pub type Accepted = io::Result<(ClientStream, Option<SocketAddr>)>;
+
+/// Bind the unix socket at `path` with `mode` permissions, replacing a socket
+/// left behind by a previous run.
+#[cfg(unix)]
+fn bind_socket(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{
+        FileTypeExt,
+        PermissionsExt,
+    };
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Bind the TCP listener on `addr`.
+///
+/// IPv6 listeners only take IPv6 connections, so `0.0.0.0` and `::` can be
+/// bound on the same port for dual-stack hosts.
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    TcpListener::from_std(socket.into())
+}
+
+/// Bind every address in `addresses`, and the unix socket at `socket` if set.
+///
+/// Binding happens before returning, so a taken port stops us from starting.
+/// Connections are then accepted in the background and sent through the
+/// returned channel, which yields an error if any listener fails.
+pub fn bind_listeners(
+    addresses: &[SocketAddr],
+    socket: Option<PathBuf>,
+    socket_mode: u32,
+) -> io::Result<mpsc::Receiver<Accepted>> {
+    if addresses.is_empty() && socket.is_none() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "neither an address nor a socket to listen on",
+        ));
+    }
+
+    let (tx, rx) = mpsc::channel(64);
+
+    for &addr in addresses {
+        let listener = bind_tcp(addr)?;
+        tracing::info!(?addr, "Bound to");
+
+        let tx = tx.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let accepted = listener
+                    .accept()
+                    .await
+                    .map(|(stream, peer)| (ClientStream::Tcp(stream), Some(peer)));
+                let failed = accepted.is_err();
+                if tx.send(accepted).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+    }
+
+    if let Some(path) = socket {
+        #[cfg(unix)]
+        {
+            let listener = bind_socket(&path, socket_mode)?;
+            tracing::info!("Bound to socket: {}", path.display());
+
+            tokio::task::spawn(async move {
+                loop {
+                    let accepted = listener
+                        .accept()
+                        .await
+                        .map(|(stream, _)| (ClientStream::Unix(stream), None));
+                    let failed = accepted.is_err();
+                    if tx.send(accepted).await.is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = (path, socket_mode);
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "client sockets are only supported on unix",
+            ));
+        }
+    }
+
+    Ok(rx)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use tokio::io::{
+        AsyncReadExt,
+        AsyncWriteExt,
+    };
+
+    #[tokio::test]
+    async fn test_bind_listeners() {
+        assert!(bind_listeners(&[], None, 0o600).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("blutgang-client-{}.sock", std::process::id()));
+        // A socket left behind by a previous run gets replaced
+        drop(std::os::unix::net::UnixListener::bind(&path));
+
+        // Find a free port
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut rx = bind_listeners(&[addr], Some(path.clone()), 0o600).unwrap();
+
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        let (mut stream, peer) = rx.recv().await.unwrap().unwrap();
+        assert!(matches!(stream, ClientStream::Tcp(_)));
+        assert_eq!(peer.unwrap().ip(), addr.ip());
+        tcp.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        let _unix = UnixStream::connect(&path).await.unwrap();
+        let (stream, peer) = rx.recv().await.unwrap().unwrap();
+        assert!(matches!(stream, ClientStream::Unix(_)));
+        assert!(peer.is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Answering trivial methods ourselves.
+//!
+//! `eth_blockNumber`, `eth_chainId`, `net_version` and `web3_clientVersion`
+//! are some of the most frequent calls clients make, and we already know the
+//! answer to all of them. With `[blutgang.local_methods]` enabled, they're
+//! answered from the `consensus` the health check maintains, over HTTP and
+//! WebSockets alike, without touching any RPC:
+//!
+//! - `eth_blockNumber` returns the consensus head, as long as we agreed on it
+//!   less than `max_head_age_ms` ago.
+//! - `eth_chainId` and `net_version` return the IDs most RPCs reported.
+//! - `web3_clientVersion` returns the most common client version of the RPCs.
+//!
+//! Anything we don't know yet goes to the RPCs as usual.
+
+use crate::{
+    config::types::LocalMethodSettings,
+    database::expiry::now_ms,
+    health::consensus::Consensus,
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Returns the result of `method` if we can answer it ourselves.
+pub fn local_result(
+    method: Option<&str>,
+    consensus: &Consensus,
+    rpc_list: &[Rpc],
+    settings: &LocalMethodSettings,
+) -> Option<Value> {
+    if !settings.enabled {
+        return None;
+    }
+
+    let method = method?;
+    let result = match method {
+        "eth_blockNumber" => {
+            let head = consensus.head()?;
+            if now_ms().saturating_sub(head.updated_ms) > settings.max_head_age_ms {
+                return None;
+            }
+            format!("0x{:x}", head.number)
+        }
+        "eth_chainId" => format!("0x{:x}", consensus.chain_id()?),
+        "net_version" => consensus.net_version()?,
+        "web3_clientVersion" => client_version(rpc_list)?,
+        _ => return None,
+    };
+
+    metrics::counter!("local_responses_total", "method" => method.to_string()).increment(1);
+    Some(result.into())
+}
+
+/// Everything needed to answer trivial methods, for connections that outlive
+/// a single request.
+#[derive(Clone)]
+pub struct LocalMethods {
+    consensus: Arc<Consensus>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    settings: LocalMethodSettings,
+}
+
+impl LocalMethods {
+    pub fn new(
+        consensus: Arc<Consensus>,
+        rpc_list: Arc<RwLock<Vec<Rpc>>>,
+        settings: LocalMethodSettings,
+    ) -> Self {
+        Self {
+            consensus,
+            rpc_list,
+            settings,
+        }
+    }
+
+    /// Returns the result of `method` if we can answer it ourselves.
+    pub fn result(&self, method: Option<&str>) -> Option<Value> {
+        let rpc_list = self.rpc_list.read().unwrap_or_else(|e| e.into_inner());
+        local_result(method, &self.consensus, &rpc_list, &self.settings)
+    }
+}
+
+/// Build the WS response to request `id` with `result`.
+pub fn local_ws_response(id: Value, result: Value) -> String {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": result,
+    })
+    .to_string()
+}
+
+/// Build the HTTP response to request `id` with `result`.
+pub fn local_http_response(
+    id: u64,
+    result: Value,
+) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    let body = json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": result,
+    });
+
+    Ok(hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap())
+}
+
+/// Returns the client version most RPCs in `rpc_list` reported.
+fn client_version(rpc_list: &[Rpc]) -> Option<String> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for version in rpc_list
+        .iter()
+        .filter_map(|rpc| rpc.capabilities.client_version.as_deref())
+    {
+        *counts.entry(version).or_default() += 1;
+    }
+
+    // Break ties by name so the answer doesn't flip between calls
+    counts
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+        .map(|(version, _)| version.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::health::consensus::ConsensusHead;
+
+    fn rpc(client_version: Option<&str>) -> Rpc {
+        let mut rpc = Rpc::new(
+            url::Url::parse("http://localhost:8545").unwrap(),
+            None,
+            5,
+            0,
+            5.0,
+        );
+        rpc.capabilities.client_version = client_version.map(str::to_string);
+        rpc
+    }
+
+    #[test]
+    fn test_local_result() {
+        let consensus = Consensus::default();
+        let settings = LocalMethodSettings {
+            enabled: true,
+            max_head_age_ms: 10_000,
+        };
+        let rpc_list = vec![
+            rpc(Some("Geth/v1.14.0")),
+            rpc(Some("Geth/v1.14.0")),
+            rpc(Some("Reth/v1.0.0")),
+            rpc(None),
+        ];
+
+        // Nothing we don't know yet
+        assert!(local_result(Some("eth_blockNumber"), &consensus, &rpc_list, &settings).is_none());
+        assert!(local_result(Some("eth_chainId"), &consensus, &rpc_list, &settings).is_none());
+
+        consensus.store(
+            Some(ConsensusHead {
+                number: 0x100,
+                hash: None,
+                updated_ms: now_ms(),
+            }),
+            None,
+            Some(1),
+            Some("1".to_string()),
+        );
+        assert_eq!(
+            local_result(Some("eth_blockNumber"), &consensus, &rpc_list, &settings),
+            Some("0x100".into())
+        );
+        assert_eq!(
+            local_result(Some("eth_chainId"), &consensus, &rpc_list, &settings),
+            Some("0x1".into())
+        );
+        assert_eq!(
+            local_result(Some("net_version"), &consensus, &rpc_list, &settings),
+            Some("1".into())
+        );
+        assert_eq!(
+            local_result(Some("web3_clientVersion"), &consensus, &rpc_list, &settings),
+            Some("Geth/v1.14.0".into())
+        );
+        assert!(local_result(Some("eth_getBalance"), &consensus, &rpc_list, &settings).is_none());
+        assert!(local_result(None, &consensus, &rpc_list, &settings).is_none());
+
+        // Heads we agreed on too long ago go to the RPCs
+        consensus.store(
+            Some(ConsensusHead {
+                number: 0x100,
+                hash: None,
+                updated_ms: now_ms() - 60_000,
+            }),
+            None,
+            Some(1),
+            Some("1".to_string()),
+        );
+        assert!(local_result(Some("eth_blockNumber"), &consensus, &rpc_list, &settings).is_none());
+        assert!(local_result(Some("eth_chainId"), &consensus, &rpc_list, &settings).is_some());
+
+        // And nothing if disabled
+        let disabled = LocalMethodSettings::default();
+        assert!(local_result(Some("eth_chainId"), &consensus, &rpc_list, &disabled).is_none());
+    }
+
+    #[test]
+    fn test_local_methods() {
+        let consensus = Arc::new(Consensus::default());
+        consensus.store(None, None, Some(1), Some("1".to_string()));
+        let rpc_list = Arc::new(RwLock::new(vec![rpc(Some("Geth/v1.14.0"))]));
+        let local = LocalMethods::new(
+            consensus,
+            rpc_list.clone(),
+            LocalMethodSettings {
+                enabled: true,
+                max_head_age_ms: 10_000,
+            },
+        );
+
+        assert_eq!(local.result(Some("eth_chainId")), Some("0x1".into()));
+        // Reads the RPCs as they are now
+        rpc_list.write().unwrap()[0].capabilities.client_version = Some("Reth/v1.0.0".into());
+        assert_eq!(
+            local.result(Some("web3_clientVersion")),
+            Some("Reth/v1.0.0".into())
+        );
+
+        let response: Value =
+            serde_json::from_str(&local_ws_response("a".into(), "0x1".into())).unwrap();
+        assert_eq!(
+            response,
+            json!({"jsonrpc": "2.0", "id": "a", "result": "0x1"})
+        );
+    }
+}
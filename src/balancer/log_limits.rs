@@ -0,0 +1,243 @@
+//! Bounds on log queries.
+//!
+//! A single `eth_getLogs` from block 0 to `latest` can tie up every RPC we
+//! have. `[blutgang.log_limits]` caps the block range, addresses and topics
+//! of `eth_getLogs` and `eth_newFilter` calls. Queries over the caps get an
+//! invalid params error, except `eth_getLogs` ranges with `oversized` set to
+//! `chunk`: those are split into `max_block_range` long chunks fetched
+//! concurrently, as long as it takes no more than `max_chunks` of them.
+//!
+//! Chunks go through the same path as the log cache (see `logs`), so cached
+//! ranges are reused and only missing chunks get fetched.
+//! WebSocket calls are held to the same limits, but their chunks are fetched
+//! one after another, without the log cache.
+
+use crate::{
+    balancer::logs::{
+        resolve_block,
+        LogQuery,
+    },
+    config::types::{
+        LogLimits,
+        OversizedLogQuery,
+    },
+    health::safe_block::NamedBlocknumbers,
+};
+
+use std::fmt;
+
+use serde_json::Value;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LogLimitError {
+    RangeTooLarge(u64),
+    TooManyAddresses(usize),
+    TooManyTopics(usize),
+}
+
+impl fmt::Display for LogLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogLimitError::RangeTooLarge(max) => {
+                write!(f, "block range larger than {} blocks", max)
+            }
+            LogLimitError::TooManyAddresses(max) => write!(f, "more than {} addresses", max),
+            LogLimitError::TooManyTopics(max) => write!(f, "more than {} topics", max),
+        }
+    }
+}
+
+impl std::error::Error for LogLimitError {}
+
+/// Number of values in `value`, which can be a single one or an array of them.
+fn count(value: &Value) -> usize {
+    match value {
+        Value::Null => 0,
+        Value::Array(values) => values.len(),
+        _ => 1,
+    }
+}
+
+impl LogLimits {
+    /// Returns true if `eth_getLogs` ranges over `max_block_range` get chunked.
+    fn chunks(&self) -> bool {
+        self.max_block_range != 0 && self.oversized == OversizedLogQuery::Chunk
+    }
+
+    /// Longest range `method` can query, `0` if there's no limit.
+    fn max_range(&self, method: &str) -> u64 {
+        if method != "eth_getLogs" || !self.chunks() {
+            return self.max_block_range;
+        }
+        self.max_block_range.saturating_mul(self.max_chunks)
+    }
+
+    /// Check `tx` if it's a log query.
+    pub fn check(&self, tx: &Value, named: &NamedBlocknumbers) -> Result<(), LogLimitError> {
+        let method = match tx["method"].as_str() {
+            Some(method @ ("eth_getLogs" | "eth_newFilter")) => method,
+            _ => return Ok(()),
+        };
+        let Some(filter) = tx["params"].get(0).filter(|filter| filter.is_object()) else {
+            return Ok(());
+        };
+
+        if self.max_addresses != 0 && count(&filter["address"]) > self.max_addresses {
+            return Err(LogLimitError::TooManyAddresses(self.max_addresses));
+        }
+        let topics = filter["topics"]
+            .as_array()
+            .map_or(0, |topics| topics.iter().map(count).sum());
+        if self.max_topics != 0 && topics > self.max_topics {
+            return Err(LogLimitError::TooManyTopics(self.max_topics));
+        }
+
+        let max_range = self.max_range(method);
+        if max_range == 0 || filter.get("blockHash").is_some() {
+            return Ok(());
+        }
+        // Pending blocks aren't a range we can bound
+        let (Some(from), Some(to)) = (
+            resolve_block(filter.get("fromBlock"), named),
+            resolve_block(filter.get("toBlock"), named),
+        ) else {
+            return Ok(());
+        };
+        if to >= from && to - from >= max_range {
+            return Err(LogLimitError::RangeTooLarge(max_range));
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if `query` has to be fetched in chunks.
+    pub fn needs_chunking(&self, query: &LogQuery) -> bool {
+        self.chunks() && query.to - query.from >= self.max_block_range
+    }
+
+    /// Split blocks `from` through `to` into chunks no longer than `max_block_range`.
+    pub fn chunk(&self, from: u64, to: u64) -> Vec<(u64, u64)> {
+        if !self.chunks() {
+            return vec![(from, to)];
+        }
+
+        let mut chunks = Vec::new();
+        let mut start = from;
+        loop {
+            let end = start.saturating_add(self.max_block_range - 1).min(to);
+            chunks.push((start, end));
+            if end == to {
+                return chunks;
+            }
+            start = end + 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn named() -> NamedBlocknumbers {
+        NamedBlocknumbers {
+            latest: 1000,
+            earliest: 0,
+            safe: 990,
+            finalized: 980,
+            pending: 1001,
+        }
+    }
+
+    fn limits(oversized: OversizedLogQuery) -> LogLimits {
+        LogLimits {
+            max_block_range: 100,
+            max_chunks: 3,
+            max_addresses: 2,
+            max_topics: 3,
+            oversized,
+        }
+    }
+
+    fn logs(method: &str, filter: Value) -> Value {
+        json!({"method": method, "params": [filter]})
+    }
+
+    #[test]
+    fn test_check_range() {
+        let reject = limits(OversizedLogQuery::Reject);
+        let chunk = limits(OversizedLogQuery::Chunk);
+        let named = named();
+
+        let within = logs(
+            "eth_getLogs",
+            json!({"fromBlock": "0x385", "toBlock": "latest"}),
+        );
+        assert!(reject.check(&within, &named).is_ok());
+
+        let oversized = logs("eth_getLogs", json!({"fromBlock": "0x0"}));
+        assert_eq!(
+            reject.check(&oversized, &named),
+            Err(LogLimitError::RangeTooLarge(100))
+        );
+        assert_eq!(
+            chunk.check(&oversized, &named),
+            Err(LogLimitError::RangeTooLarge(300))
+        );
+        let chunkable = logs(
+            "eth_getLogs",
+            json!({"fromBlock": "0x300", "toBlock": "latest"}),
+        );
+        assert!(chunk.check(&chunkable, &named).is_ok());
+
+        // Filters can't be chunked
+        let filter = logs("eth_newFilter", json!({"fromBlock": "0x300"}));
+        assert!(chunk.check(&filter, &named).is_err());
+
+        let by_hash = logs("eth_getLogs", json!({"blockHash": "0xabc"}));
+        assert!(reject.check(&by_hash, &named).is_ok());
+        let pending = logs(
+            "eth_getLogs",
+            json!({"fromBlock": "0x0", "toBlock": "pending"}),
+        );
+        assert!(reject.check(&pending, &named).is_ok());
+    }
+
+    #[test]
+    fn test_check_filter() {
+        let limits = limits(OversizedLogQuery::Reject);
+        let named = named();
+
+        let addresses = logs(
+            "eth_getLogs",
+            json!({"address": ["0x01", "0x02", "0x03"], "blockHash": "0xabc"}),
+        );
+        assert_eq!(
+            limits.check(&addresses, &named),
+            Err(LogLimitError::TooManyAddresses(2))
+        );
+
+        let topics = logs(
+            "eth_newFilter",
+            json!({"topics": ["0x01", null, ["0x02", "0x03", "0x04"]], "blockHash": "0xabc"}),
+        );
+        assert_eq!(
+            limits.check(&topics, &named),
+            Err(LogLimitError::TooManyTopics(3))
+        );
+
+        let fine = logs(
+            "eth_getLogs",
+            json!({"address": "0x01", "topics": [null, "0x01"], "blockHash": "0xabc"}),
+        );
+        assert!(limits.check(&fine, &named).is_ok());
+    }
+
+    #[test]
+    fn test_chunk() {
+        let limits = limits(OversizedLogQuery::Chunk);
+        assert_eq!(limits.chunk(0, 99), [(0, 99)]);
+        assert_eq!(limits.chunk(0, 250), [(0, 99), (100, 199), (200, 250)]);
+        assert_eq!(LogLimits::default().chunk(0, u64::MAX), [(0, u64::MAX)]);
+    }
+}
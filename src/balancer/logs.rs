@@ -0,0 +1,436 @@
+//! Range cache for `eth_getLogs`.
+//!
+//! Log queries are the most expensive calls we proxy, and clients rarely ask
+//! for the exact same range twice. Indexers page through history, dashboards
+//! poll a sliding window, so the regular cache keyed on the whole request
+//! almost never hits.
+//!
+//! Here logs are kept per filter (addresses and topics), along with the block
+//! ranges we fetched them for. A query overlapping ranges we have is answered
+//! from them, and only the blocks we're missing are fetched from the RPCs.
+//!
+//! Only finalized blocks are cached, so reorgs never invalidate what we have.
+//! Anything past the finalized block is always fetched.
+
+use crate::{
+    balancer::canonical::canonical_key,
+    health::safe_block::NamedBlocknumbers,
+};
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// `eth_getLogs` query we can serve from the range cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogQuery {
+    /// Canonical form of the filter without its block range.
+    filter: String,
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Part of the answer to a query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    /// Logs we have for a range.
+    Cached(Vec<Value>),
+    /// Range we have to fetch.
+    Missing { from: u64, to: u64 },
+}
+
+/// Resolve a block param of a filter to a number.
+///
+/// Returns `None` for `pending` and anything we can't parse.
+pub fn resolve_block(block: Option<&Value>, named: &NamedBlocknumbers) -> Option<u64> {
+    match block.and_then(Value::as_str).unwrap_or("latest") {
+        "latest" => Some(named.latest),
+        "safe" => Some(named.safe),
+        "finalized" => Some(named.finalized),
+        "earliest" => Some(named.earliest),
+        "pending" => None,
+        number => u64::from_str_radix(number.strip_prefix("0x")?, 16).ok(),
+    }
+}
+
+impl LogQuery {
+    /// Returns the query `tx` makes, if it's an `eth_getLogs` call over a block range.
+    ///
+    /// Filters by block hash aren't range queries and are left to the regular cache.
+    pub fn parse(tx: &Value, named: &NamedBlocknumbers) -> Option<Self> {
+        if tx["method"].as_str() != Some("eth_getLogs") {
+            return None;
+        }
+
+        let mut filter = tx["params"].get(0)?.as_object()?.clone();
+        if filter.contains_key("blockHash") {
+            return None;
+        }
+
+        let from = resolve_block(filter.remove("fromBlock").as_ref(), named)?;
+        let to = resolve_block(filter.remove("toBlock").as_ref(), named)?;
+        if from > to {
+            return None;
+        }
+
+        let filter = canonical_key(&json!({
+            "method": "eth_getLogs",
+            "params": [filter],
+        }));
+
+        Some(Self { filter, from, to })
+    }
+
+    /// Returns `tx` narrowed down to blocks `from` through `to`.
+    pub fn narrow(tx: &Value, from: u64, to: u64) -> Value {
+        let mut tx = tx.clone();
+        tx["params"][0]["fromBlock"] = format!("0x{:x}", from).into();
+        tx["params"][0]["toBlock"] = format!("0x{:x}", to).into();
+        tx
+    }
+}
+
+/// Block number and index of a log, used to keep logs in order.
+fn log_position(log: &Value) -> Option<(u64, u64)> {
+    let number = |field: &str| {
+        log[field]
+            .as_str()
+            .and_then(|hex| hex.strip_prefix("0x"))
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+    };
+
+    Some((number("blockNumber")?, number("logIndex").unwrap_or(0)))
+}
+
+/// Logs of a filter over a range of blocks.
+#[derive(Debug)]
+struct CachedRange {
+    from: u64,
+    to: u64,
+    logs: Vec<Value>,
+}
+
+#[derive(Debug, Default)]
+struct FilterEntry {
+    /// Sorted and disjoint.
+    ranges: Vec<CachedRange>,
+    last_used: u64,
+}
+
+impl FilterEntry {
+    fn len(&self) -> usize {
+        self.ranges.iter().map(|range| range.logs.len()).sum()
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    filters: HashMap<String, FilterEntry>,
+    /// Logs held across all filters.
+    len: usize,
+    clock: u64,
+}
+
+/// Logs of finalized blocks, by filter and block range.
+#[derive(Debug, Default)]
+pub struct LogCache {
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+impl LogCache {
+    /// Hold up to `capacity` logs. `0` disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(Inner::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Split `query` into ranges we have logs for and ranges we need to fetch, in block order.
+    pub fn plan(&self, query: &LogQuery) -> Vec<Segment> {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let mut segments = Vec::new();
+        let mut next = query.from;
+
+        if let Some(entry) = inner.filters.get_mut(&query.filter) {
+            entry.last_used = clock;
+
+            for range in &entry.ranges {
+                if range.to < next || range.from > query.to {
+                    continue;
+                }
+                if range.from > next {
+                    segments.push(Segment::Missing {
+                        from: next,
+                        to: range.from - 1,
+                    });
+                }
+
+                let from = range.from.max(next);
+                let to = range.to.min(query.to);
+                let logs = range
+                    .logs
+                    .iter()
+                    .filter(|log| {
+                        log_position(log).is_some_and(|(block, _)| (from..=to).contains(&block))
+                    })
+                    .cloned()
+                    .collect();
+                segments.push(Segment::Cached(logs));
+
+                metrics::counter!("log_cache_blocks_reused_total").increment(to - from + 1);
+                if to == query.to {
+                    return segments;
+                }
+                next = to + 1;
+            }
+        }
+
+        segments.push(Segment::Missing {
+            from: next,
+            to: query.to,
+        });
+        segments
+    }
+
+    /// Store `logs` fetched for blocks `from` through `to` of the filter of `query`.
+    ///
+    /// Blocks past `finalized` aren't stored.
+    pub fn insert(&self, query: &LogQuery, from: u64, to: u64, finalized: u64, logs: &[Value]) {
+        let to = to.min(finalized);
+        if !self.is_enabled() || from > to {
+            return;
+        }
+
+        let mut logs: Vec<Value> = logs
+            .iter()
+            .filter(|log| log_position(log).is_some_and(|(block, _)| (from..=to).contains(&block)))
+            .cloned()
+            .collect();
+        if logs.len() > self.capacity {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.clock += 1;
+        let clock = inner.clock;
+
+        let entry = inner.filters.entry(query.filter.clone()).or_default();
+        entry.last_used = clock;
+        let before = entry.len();
+
+        // Merge with every range we overlap or touch, so ranges don't fragment
+        let (mut merged, kept): (Vec<CachedRange>, Vec<CachedRange>) =
+            entry.ranges.drain(..).partition(|range| {
+                range.from <= to.saturating_add(1) && range.to.saturating_add(1) >= from
+            });
+        let mut range = CachedRange {
+            from,
+            to,
+            logs: Vec::new(),
+        };
+        for other in &mut merged {
+            range.from = range.from.min(other.from);
+            range.to = range.to.max(other.to);
+            logs.extend(other.logs.drain(..).filter(|log| {
+                log_position(log).is_some_and(|(block, _)| !(from..=to).contains(&block))
+            }));
+        }
+        logs.sort_by_key(|log| log_position(log).unwrap_or_default());
+        range.logs = logs;
+
+        entry.ranges = kept;
+        let position = entry
+            .ranges
+            .partition_point(|other| other.from < range.from);
+        entry.ranges.insert(position, range);
+
+        let after = entry.len();
+        inner.len = inner.len - before + after;
+        self.evict(&mut inner);
+    }
+
+    /// Drop every cached log.
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner.filters.clear();
+        inner.len = 0;
+    }
+
+    /// Drop the least recently used filters until we're within capacity.
+    fn evict(&self, inner: &mut Inner) {
+        while inner.len > self.capacity {
+            let Some(coldest) = inner
+                .filters
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(filter, _)| filter.clone())
+            else {
+                break;
+            };
+
+            if let Some(entry) = inner.filters.remove(&coldest) {
+                inner.len -= entry.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named() -> NamedBlocknumbers {
+        NamedBlocknumbers {
+            latest: 120,
+            earliest: 0,
+            safe: 110,
+            finalized: 100,
+            pending: 121,
+        }
+    }
+
+    fn log(block: u64, index: u64) -> Value {
+        json!({
+            "blockNumber": format!("0x{:x}", block),
+            "logIndex": format!("0x{:x}", index),
+        })
+    }
+
+    fn query(from: &str, to: &str) -> LogQuery {
+        let tx = json!({
+            "method": "eth_getLogs",
+            "params": [{ "address": "0xAB", "fromBlock": from, "toBlock": to }],
+        });
+        LogQuery::parse(&tx, &named()).unwrap()
+    }
+
+    #[test]
+    fn test_parse_log_query() {
+        let q = query("0x0a", "finalized");
+        assert_eq!((q.from, q.to), (10, 100));
+
+        // Same filter regardless of range and encoding
+        let tx = json!({"method": "eth_getLogs", "params": [{ "address": "0xab" }]});
+        let latest = LogQuery::parse(&tx, &named()).unwrap();
+        assert_eq!((latest.from, latest.to), (120, 120));
+        assert_eq!(latest.filter, q.filter);
+
+        let pending = json!({"method": "eth_getLogs", "params": [{ "toBlock": "pending" }]});
+        assert!(LogQuery::parse(&pending, &named()).is_none());
+        let by_hash = json!({"method": "eth_getLogs", "params": [{ "blockHash": "0x01" }]});
+        assert!(LogQuery::parse(&by_hash, &named()).is_none());
+        let backwards =
+            json!({"method": "eth_getLogs", "params": [{ "fromBlock": "0x2", "toBlock": "0x1" }]});
+        assert!(LogQuery::parse(&backwards, &named()).is_none());
+
+        let narrowed = LogQuery::narrow(&tx, 16, 31);
+        assert_eq!(narrowed["params"][0]["fromBlock"], "0x10");
+        assert_eq!(narrowed["params"][0]["toBlock"], "0x1f");
+        assert_eq!(narrowed["params"][0]["address"], "0xab");
+    }
+
+    #[test]
+    fn test_log_cache_partial_reuse() {
+        let cache = LogCache::new(100);
+        let q = query("0x0", "0x64");
+
+        // Nothing cached yet
+        assert_eq!(cache.plan(&q), vec![Segment::Missing { from: 0, to: 100 }]);
+
+        cache.insert(&q, 10, 20, 100, &[log(12, 0), log(15, 1), log(30, 0)]);
+        cache.insert(&q, 40, 50, 100, &[]);
+
+        assert_eq!(
+            cache.plan(&query("0xf", "0x2d")),
+            vec![
+                Segment::Cached(vec![log(15, 1)]),
+                Segment::Missing { from: 21, to: 39 },
+                Segment::Cached(vec![]),
+            ]
+        );
+        assert_eq!(
+            cache.plan(&query("0x0", "0x3c")),
+            vec![
+                Segment::Missing { from: 0, to: 9 },
+                Segment::Cached(vec![log(12, 0), log(15, 1)]),
+                Segment::Missing { from: 21, to: 39 },
+                Segment::Cached(vec![]),
+                Segment::Missing { from: 51, to: 60 },
+            ]
+        );
+
+        // Filling the gap merges the ranges
+        cache.insert(&q, 21, 39, 100, &[log(25, 3), log(25, 2)]);
+        assert_eq!(
+            cache.plan(&query("0xa", "0x32")),
+            vec![Segment::Cached(vec![
+                log(12, 0),
+                log(15, 1),
+                log(25, 2),
+                log(25, 3)
+            ])]
+        );
+        assert_eq!(
+            cache.inner.lock().unwrap().filters[&q.filter].ranges.len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_log_cache_finalized_only() {
+        let cache = LogCache::new(100);
+        let q = query("0x5a", "0x78");
+
+        cache.insert(&q, 90, 120, 100, &[log(95, 0), log(110, 0)]);
+        assert_eq!(
+            cache.plan(&q),
+            vec![
+                Segment::Cached(vec![log(95, 0)]),
+                Segment::Missing { from: 101, to: 120 },
+            ]
+        );
+
+        // Nothing finalized at all
+        cache.insert(&q, 101, 120, 100, &[log(110, 0)]);
+        assert_eq!(cache.inner.lock().unwrap().len, 1);
+    }
+
+    #[test]
+    fn test_log_cache_eviction() {
+        let cache = LogCache::new(3);
+        let a = query("0x0", "0xa");
+        let tx = json!({"method": "eth_getLogs", "params": [{ "address": "0xcd", "fromBlock": "0x0", "toBlock": "0xa" }]});
+        let b = LogQuery::parse(&tx, &named()).unwrap();
+
+        cache.insert(&a, 0, 10, 100, &[log(1, 0), log(2, 0)]);
+        cache.insert(&b, 0, 10, 100, &[log(1, 0), log(2, 0)]);
+
+        // `a` was used least recently, so it had to go
+        assert_eq!(cache.plan(&a), vec![Segment::Missing { from: 0, to: 10 }]);
+        assert_eq!(cache.inner.lock().unwrap().len, 2);
+
+        // Disabled
+        let disabled = LogCache::default();
+        disabled.insert(&a, 0, 10, 100, &[log(1, 0)]);
+        assert_eq!(
+            disabled.plan(&a),
+            vec![Segment::Missing { from: 0, to: 10 }]
+        );
+    }
+}
@@ -0,0 +1,122 @@
+//! Methods blocked at the proxy.
+//!
+//! `[blutgang.method_filter]` keeps dangerous or unwanted methods, like
+//! `admin_*`, `personal_*` or `debug_setHead`, from ever reaching the RPCs.
+//! Methods not on a non-empty `allow` list, or on the `deny` list, get a
+//! "method not allowed" error, over HTTP and WebSockets alike.
+//!
+//! Route groups can have filters of their own under `groups`, which only
+//! apply to RPCs in the group: requests for methods a group blocks just skip
+//! its RPCs. Group filters only apply to HTTP requests.
+//!
+//! Patterns match methods exactly, or by prefix with a trailing `*`.
+
+use crate::{
+    balancer::cache_metrics::method_label,
+    config::types::{
+        MethodFilter,
+        MethodFilterSettings,
+    },
+    Rpc,
+};
+
+use rust_tracing::deps::metrics;
+
+/// Returns true if `pattern` matches `method`, see `method_filter`.
+pub fn method_matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+impl MethodFilter {
+    /// Returns true if the filter lets `method` through.
+    pub fn allows(&self, method: Option<&str>) -> bool {
+        let Some(method) = method else {
+            return self.allow.is_empty();
+        };
+        (self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| method_matches(pattern, method)))
+            && !self
+                .deny
+                .iter()
+                .any(|pattern| method_matches(pattern, method))
+    }
+}
+
+impl MethodFilterSettings {
+    /// Returns true if `method` can be forwarded at all, counting it if not.
+    pub fn check(&self, method: Option<&str>) -> bool {
+        let allowed = self.global.allows(method);
+        if !allowed {
+            metrics::counter!("methods_blocked_total", "method" => method_label(method))
+                .increment(1);
+        }
+        allowed
+    }
+
+    /// Returns true if the groups of `rpc` let `method` through.
+    pub fn serves(&self, rpc: &Rpc, method: Option<&str>) -> bool {
+        rpc.groups.iter().all(|group| {
+            self.groups
+                .get(group)
+                .is_none_or(|filter| filter.allows(method))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> MethodFilterSettings {
+        toml::from_str(
+            r#"
+            deny = ["admin_*", "personal_*", "debug_setHead"]
+
+            [groups.public]
+            allow = ["eth_*", "net_version"]
+            deny = ["eth_sendRawTransaction"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check() {
+        let settings = settings();
+        assert!(settings.check(Some("eth_call")));
+        assert!(settings.check(Some("debug_traceTransaction")));
+        assert!(!settings.check(Some("admin_addPeer")));
+        assert!(!settings.check(Some("debug_setHead")));
+        assert!(settings.check(None));
+
+        let settings = MethodFilterSettings {
+            global: MethodFilter {
+                allow: vec!["eth_*".to_string()],
+                deny: Vec::new(),
+            },
+            ..Default::default()
+        };
+        assert!(settings.check(Some("eth_call")));
+        assert!(!settings.check(Some("net_version")));
+        assert!(!settings.check(None));
+    }
+
+    #[test]
+    fn test_serves() {
+        let settings = settings();
+        let mut rpc = Rpc::default();
+        assert!(settings.serves(&rpc, Some("debug_traceTransaction")));
+
+        rpc.groups = vec!["archive".to_string(), "public".to_string()];
+        assert!(settings.serves(&rpc, Some("eth_call")));
+        assert!(settings.serves(&rpc, Some("net_version")));
+        assert!(!settings.serves(&rpc, Some("eth_sendRawTransaction")));
+        assert!(!settings.serves(&rpc, Some("debug_traceTransaction")));
+    }
+}
@@ -8,7 +8,42 @@
 //! and processing incoming data.
 
 pub mod accept_http;
+pub mod acme;
+pub mod api_keys;
+pub mod batch;
+pub mod blocklist;
+pub mod cache_control;
+pub mod cache_index;
+pub mod cache_metrics;
+pub mod canonical;
+pub mod checksum;
+pub mod compute_units;
+pub mod connection_limits;
+pub mod content_encoding;
+pub mod cors;
+pub mod counters;
+pub mod downgrade;
 pub mod format;
+pub mod heatmap;
+pub mod json_limits;
+pub mod listeners;
+pub mod local_methods;
+pub mod log_limits;
+pub mod logs;
+pub mod method_filter;
+pub mod privacy;
 pub mod processing;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod request_limits;
+pub mod response_diff;
 mod response_errors;
 pub mod selection;
+pub mod shadow;
+pub mod singleflight;
+pub mod slow_requests;
+pub mod stale;
+pub mod tls;
+pub mod trace_context;
+pub mod validation;
+pub mod warming;
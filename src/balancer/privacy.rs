@@ -0,0 +1,157 @@
+//! Response padding and timing normalization.
+//!
+//! TLS hides what's in a response, but not how big it is or how long it took.
+//! For some methods that's enough to tell what was queried, for example which
+//! account a balance belongs to, or whether anyone asked for it recently
+//! because it was served from the cache.
+//!
+//! When enabled, responses to sensitive methods are padded with trailing
+//! whitespace up to a size bucket, and held back until the next timing
+//! bucket, so responses in the same bucket all look alike. Batches are
+//! padded and delayed as a whole if any of their items is sensitive.
+
+use crate::config::types::PrivacySettings;
+
+use std::time::Duration;
+
+use serde_json::Value;
+
+impl PrivacySettings {
+    /// Returns true if responses to `method` should be padded and delayed.
+    pub fn applies_to(&self, method: Option<&str>) -> bool {
+        if self.pad_bucket_bytes == 0 && self.timing_bucket_ms == 0 {
+            return false;
+        }
+
+        self.methods.is_empty()
+            || method.is_some_and(|method| self.methods.iter().any(|m| m == method))
+    }
+
+    /// Returns true if the response to the batch of `items` should be padded and delayed.
+    pub fn applies_to_batch(&self, items: &[Value]) -> bool {
+        items
+            .iter()
+            .any(|item| self.applies_to(item["method"].as_str()))
+    }
+
+    /// Size `len` bytes get padded to.
+    ///
+    /// Buckets double in size, starting at `pad_bucket_bytes`, so padding
+    /// never more than doubles the size of a response.
+    fn padded_len(&self, len: usize) -> usize {
+        if self.pad_bucket_bytes == 0 {
+            return len;
+        }
+
+        let buckets = len.div_ceil(self.pad_bucket_bytes).max(1);
+        buckets
+            .checked_next_power_of_two()
+            .and_then(|buckets| buckets.checked_mul(self.pad_bucket_bytes))
+            .unwrap_or(len)
+    }
+
+    /// Pad `body` with whitespace, which JSON parsers ignore.
+    pub fn pad(&self, mut body: String) -> String {
+        let padded_len = self.padded_len(body.len());
+        body.extend(std::iter::repeat_n(' ', padded_len - body.len()));
+        body
+    }
+
+    /// How much longer to wait before responding to a request that came in `elapsed` ago.
+    pub fn delay(&self, elapsed: Duration) -> Duration {
+        if self.timing_bucket_ms == 0 {
+            return Duration::ZERO;
+        }
+
+        let bucket = self.timing_bucket_ms as u128;
+        let elapsed_ms = elapsed.as_millis();
+        let target = elapsed_ms.div_ceil(bucket).max(1) * bucket;
+
+        Duration::from_millis(target as u64).saturating_sub(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(pad_bucket_bytes: usize, timing_bucket_ms: u64) -> PrivacySettings {
+        PrivacySettings {
+            pad_bucket_bytes,
+            timing_bucket_ms,
+            methods: vec!["eth_getBalance".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let privacy = settings(256, 0);
+        assert!(privacy.applies_to(Some("eth_getBalance")));
+        assert!(!privacy.applies_to(Some("eth_blockNumber")));
+        assert!(!privacy.applies_to(None));
+
+        let all = PrivacySettings {
+            methods: Vec::new(),
+            ..settings(256, 0)
+        };
+        assert!(all.applies_to(Some("eth_blockNumber")));
+
+        // Disabled unless one of the mitigations is on
+        assert!(!PrivacySettings::default().applies_to(Some("eth_getBalance")));
+    }
+
+    #[test]
+    fn test_applies_to_batch() {
+        let privacy = settings(256, 0);
+        let batch = |methods: &[&str]| -> Vec<Value> {
+            methods
+                .iter()
+                .map(|method| serde_json::json!({ "id": 1, "method": method }))
+                .collect()
+        };
+
+        assert!(privacy.applies_to_batch(&batch(&["eth_blockNumber", "eth_getBalance"])));
+        assert!(!privacy.applies_to_batch(&batch(&["eth_blockNumber", "eth_chainId"])));
+        assert!(!privacy.applies_to_batch(&[]));
+    }
+
+    #[test]
+    fn test_padding() {
+        let privacy = settings(256, 0);
+        assert_eq!(privacy.padded_len(0), 256);
+        assert_eq!(privacy.padded_len(256), 256);
+        assert_eq!(privacy.padded_len(257), 512);
+        assert_eq!(privacy.padded_len(700), 1024);
+        assert_eq!(privacy.padded_len(1025), 2048);
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.to_string();
+        let padded = privacy.pad(body.clone());
+        assert_eq!(padded.len(), 256);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&padded).unwrap(),
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        );
+
+        assert_eq!(settings(0, 100).pad(body.clone()), body);
+    }
+
+    #[test]
+    fn test_delay() {
+        let privacy = settings(0, 100);
+        assert_eq!(
+            privacy.delay(Duration::from_millis(30)),
+            Duration::from_millis(70)
+        );
+        assert_eq!(privacy.delay(Duration::from_millis(100)), Duration::ZERO);
+        assert_eq!(
+            privacy.delay(Duration::from_millis(101)),
+            Duration::from_millis(99)
+        );
+        assert_eq!(privacy.delay(Duration::ZERO), Duration::from_millis(100));
+
+        assert_eq!(
+            settings(256, 0).delay(Duration::from_millis(30)),
+            Duration::ZERO
+        );
+    }
+}
@@ -1,24 +1,46 @@
 use crate::{
     balancer::{
-        format::get_block_number_from_request,
+        cache_index::CacheIndex,
+        cache_metrics::record_write,
+        canonical::canonical_key,
+        format::{
+            get_block_number_from_request,
+            get_block_number_from_response,
+        },
+        logs::LogCache,
         selection::cache_rules::{
             cache_method,
             cache_result,
+            negative_result,
         },
+        singleflight::InFlight,
+    },
+    config::types::{
+        CacheCompressionSettings,
+        CacheExpirySettings,
+        CachePolicy,
     },
     database::{
-        accept::db_insert,
+        expiry::{
+            now_ms,
+            with_expiry,
+        },
+        serialization::CacheFormat,
         types::{
             GenericBytes,
             RequestBus,
         },
+        writer::CacheWriter,
     },
     health::safe_block::NamedBlocknumbers,
     Rpc,
 };
 
 use std::{
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
     sync::{
         Arc,
         RwLock,
@@ -29,8 +51,13 @@ use std::{
 use tokio::sync::watch;
 
 use blake3::Hash;
+// Select either blake3 or xxhash based on the features
+#[cfg(not(feature = "xxhash"))]
+use blake3::hash;
+use rust_tracing::deps::metrics;
 use serde_json::Value;
-use simd_json::to_vec;
+#[cfg(feature = "xxhash")]
+use xxhash_rust::xxh3::xxh3_64;
 
 #[derive(Clone)]
 pub struct CacheArgs<K, V>
@@ -42,12 +69,86 @@ where
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
     pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
     pub cache: RequestBus<K, V>,
+    pub writer: CacheWriter<K, V>,
+    pub format: CacheFormat,
+    pub compression: CacheCompressionSettings,
+    pub expiry: CacheExpirySettings,
+    pub policies: Arc<HashMap<String, CachePolicy>>,
+    pub block_time_ms: u64,
+    pub log_cache: Arc<LogCache>,
+    pub index: Arc<CacheIndex>,
+    /// Entries being fetched right now, so concurrent misses share one fetch.
+    pub in_flight: Arc<InFlight>,
+}
+
+impl<K, V> CacheArgs<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    /// Returns the cache policy configured for the method of `tx`, if any.
+    pub fn policy(&self, tx: &Value) -> Option<CachePolicy> {
+        let method = tx["method"].as_str()?;
+        self.policies.get(method).copied()
+    }
+
+    /// Returns what we hash to get the cache key of `tx`, see [`cache_key_input`].
+    pub fn cache_key_input(&self, tx: &Value) -> String {
+        cache_key_input(tx, &self.policies, &self.named_numbers)
+    }
+
+    /// Returns the key `tx` is cached under, see [`cache_key`].
+    pub fn cache_key(&self, tx: &Value) -> CacheKey {
+        cache_key(tx, &self.policies, &self.named_numbers)
+    }
+
+    /// Returns when an entry for `tx` inserted at `now` should expire.
+    fn expires_at(&self, tx: &Value, now: u64) -> Option<u64> {
+        match self.policy(tx) {
+            Some(CachePolicy::Forever) => None,
+            Some(CachePolicy::Ttl(ttl_ms)) => {
+                CacheExpirySettings {
+                    ttl_ms,
+                    ..self.expiry
+                }
+                .expires_at(now)
+            }
+            // Unreachable after the next block anyway, so let it get cleaned up
+            Some(CachePolicy::Block) => Some(now.saturating_add(self.block_time_ms.max(1))),
+            Some(CachePolicy::Never) | None => self.expiry.expires_at(now),
+        }
+    }
+
+    /// Cap `expires_at` for entries belonging to block `num` if it isn't finalized yet.
+    ///
+    /// Finalized entries can't reorg, so they keep whatever expiry they had.
+    fn cap_unfinalized(&self, expires_at: Option<u64>, num: Option<u64>, now: u64) -> Option<u64> {
+        let finalized = *self.finalized_rx.borrow();
+        if self.expiry.unfinalized_ttl_ms == 0 || num.is_none_or(|num| num <= finalized) {
+            return expires_at;
+        }
+
+        let unfinalized_expiry = CacheExpirySettings {
+            ttl_ms: self.expiry.unfinalized_ttl_ms,
+            ..self.expiry
+        }
+        .expires_at(now);
+
+        match (expires_at, unfinalized_expiry) {
+            (Some(expires_at), Some(unfinalized)) => Some(expires_at.min(unfinalized)),
+            (expires_at, unfinalized) => expires_at.or(unfinalized),
+        }
+    }
 }
 
 impl CacheArgs<[u8; 32], Vec<u8>> {
     #[cfg(test)]
     /// **Note:** This should only be used for testing!
     pub fn default() -> Self {
+        use crate::database::{
+            eviction::CacheEviction,
+            hot_cache::HotCache,
+        };
         use crate::database_processing;
 
         use sled::{
@@ -61,17 +162,83 @@ impl CacheArgs<[u8; 32], Vec<u8>> {
         let cache = Db::open_with_config(&cache).unwrap();
 
         let (db_tx, db_rx) = mpsc::unbounded_channel();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
 
         CacheArgs {
             finalized_rx: watch::channel(0).1,
             named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
             head_cache: Arc::new(RwLock::new(BTreeMap::new())),
-            cache: db_tx,
+            cache: db_tx.clone(),
+            // Lets tests read what they cached right away
+            writer: CacheWriter::Direct(db_tx),
+            format: CacheFormat::default(),
+            compression: CacheCompressionSettings::default(),
+            expiry: CacheExpirySettings::default(),
+            policies: Arc::new(HashMap::new()),
+            block_time_ms: 12500,
+            log_cache: Arc::new(LogCache::default()),
+            index: Arc::new(CacheIndex::default()),
+            in_flight: Arc::new(InFlight::default()),
         }
     }
 }
 
+/// Returns what we hash to get the cache key of `tx`.
+///
+/// Requests are put in canonical form first, so equivalent requests that
+/// are encoded differently share an entry. Requests for methods cached
+/// until the next block are keyed on the current head, so a new block
+/// makes previous entries unreachable.
+pub fn cache_key_input(
+    tx: &Value,
+    policies: &HashMap<String, CachePolicy>,
+    named_numbers: &RwLock<NamedBlocknumbers>,
+) -> String {
+    let key = canonical_key(tx);
+    let policy = tx["method"]
+        .as_str()
+        .and_then(|method| policies.get(method));
+    match policy {
+        Some(CachePolicy::Block) => {
+            let latest = named_numbers
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .latest;
+            format!("{}@{}", key, latest)
+        }
+        _ => key,
+    }
+}
+
+/// Hash of a [`cache_key_input`], blake3 or xxhash depending on the enabled feature.
+#[cfg(not(feature = "xxhash"))]
+pub type CacheKey = Hash;
+#[cfg(feature = "xxhash")]
+pub type CacheKey = u64;
+
+/// Returns the key `tx` is cached under, the hash of its [`cache_key_input`].
+pub fn cache_key(
+    tx: &Value,
+    policies: &HashMap<String, CachePolicy>,
+    named_numbers: &RwLock<NamedBlocknumbers>,
+) -> CacheKey {
+    let key_input = cache_key_input(tx, policies, named_numbers);
+    #[cfg(not(feature = "xxhash"))]
+    {
+        hash(key_input.as_bytes())
+    }
+    #[cfg(feature = "xxhash")]
+    {
+        xxh3_64(key_input.as_bytes())
+    }
+}
+
 // TODO: we should find a way to check values directly and not convert Value to str
 //
 // @makemake -- Here's an intermediate solution to step towards the above todo which
@@ -90,10 +257,63 @@ pub async fn cache_query<K, V>(
     K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes + From<Vec<u8>>,
 {
-    if can_cache(method.to_string(), rx) {
-        // Insert the response hash into the head_cache
+    // `null` results are only cached briefly, since whatever wasn't there might show up any moment.
+    // They could also come from a malfunctioning node, which is another reason to keep it short.
+    let negative = cache_args.expiry.negative_ttl_ms != 0 && negative_result(rx);
+
+    // Policies only decide how long entries live, the blacklists still apply
+    let policy = cache_args.policy(&method);
+    let cacheable = match policy {
+        Some(CachePolicy::Never) => false,
+        _ if negative => cache_method(method.to_string()),
+        _ => can_cache(method.to_string(), rx),
+    };
+
+    if cacheable {
+        let now = now_ms();
+        let expires_at = if negative {
+            CacheExpirySettings {
+                ttl_ms: cache_args.expiry.negative_ttl_ms,
+                ..cache_args.expiry
+            }
+            .expires_at(now)
+        } else {
+            cache_args.expires_at(&method, now)
+        };
+
+        let method_name = method["method"].as_str().unwrap_or_default().to_string();
         let num = get_block_number_from_request(method, &cache_args.named_numbers);
 
+        // Without a policy we only cache requests for a specific block, unless they expire soon anyway
+        if num.is_none() && policy.is_none() && !negative {
+            return;
+        }
+
+        // Replace the id with Value::Null and insert the request.
+        //
+        // In some cases the response might not contain an ID like in
+        // https://github.com/rainshowerLabs/blutgang/issues/88.
+        // In this case we just skip inserting it into the DB as its an error.
+        //
+        // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
+        let mut rx_value: Value = match unsafe { simd_json::serde::from_str(rx) } {
+            Ok(rx_value) => rx_value,
+            Err(err) => {
+                tracing::debug!(?err, "Not caching a response that isn't valid JSON");
+                return;
+            }
+        };
+        if let Some(id) = rx_value.get_mut("id") {
+            *id = Value::Null;
+        } else {
+            return;
+        }
+
+        // Receipts, transactions and logs can belong to a block the request
+        // doesn't mention, so we track them under whichever block is higher.
+        let num = num.max(get_block_number_from_response(&rx_value));
+        let expires_at = cache_args.cap_unfinalized(expires_at, num, now);
+
         // Insert the key of the request we made into our `head_cache`
         // so we can invalidate it and remove it from the DB if it reorgs.
         if let Some(num) = num {
@@ -104,30 +324,24 @@ pub async fn cache_query<K, V>(
                     .or_default()
                     .push(tx_hash.as_bytes().to_owned().into());
             }
+        }
 
-            // Replace the id with Value::Null and insert the request.
-            //
-            // In some cases the response might not contain an ID like in
-            // https://github.com/rainshowerLabs/blutgang/issues/88.
-            // In this case we just skip inserting it into the DB as its an error.
-            //
-            // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
-            let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
-            if let Some(id) = rx_value.get_mut("id") {
-                *id = Value::Null;
-            } else {
-                return;
-            }
+        cache_args
+            .index
+            .record(*tx_hash.as_bytes(), &method_name, num);
 
-            drop(
-                db_insert(
-                    &cache_args.cache.clone(),
-                    tx_hash.as_bytes().to_owned().into(),
-                    to_vec(&rx_value).unwrap().into(),
-                )
-                .await,
-            );
+        let mut entry = cache_args
+            .compression
+            .compress(cache_args.format.encode(&rx_value));
+        if let Some(expires_at) = expires_at {
+            entry = with_expiry(entry, expires_at);
         }
+        record_write(Some(&method_name), entry.len());
+
+        // Clients don't wait for the write
+        cache_args
+            .writer
+            .write(tx_hash.as_bytes().to_owned().into(), entry.into());
     }
 }
 
@@ -147,14 +361,24 @@ pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize,
             rpc_position
         };
         rpc_list_guard[index].update_latency(time.as_nanos() as f64);
+        metrics::histogram!("rpc_latency_secs", "rpc_name" => rpc_list_guard[index].name.clone())
+            .record(time.as_secs_f64());
         rpc_list_guard[index].last_used = time.as_micros();
-        tracing::info!("LA {}", rpc_list_guard[index].status.latency);
+        tracing::debug!(
+            rpc = %rpc_list_guard[index].name,
+            latency = rpc_list_guard[index].status.latency,
+            "Updated RPC latency"
+        );
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::{
+        database::{
+            expiry::strip_expiry,
+            serialization::decode_cached,
+        },
         db_get,
         rpc::method::EthRpcMethod,
     };
@@ -213,6 +437,167 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cache_query_policies() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.policies = Arc::new(HashMap::from([
+            ("eth_chainId".to_string(), CachePolicy::Forever),
+            ("eth_gasPrice".to_string(), CachePolicy::Ttl(3000)),
+            ("eth_maxPriorityFeePerGas".to_string(), CachePolicy::Block),
+            ("eth_getBlockByNumber".to_string(), CachePolicy::Never),
+            ("eth_blockNumber".to_string(), CachePolicy::Forever),
+            ("eth_getBalance".to_string(), CachePolicy::Forever),
+        ]));
+
+        let cache_rx = |method: Value, rx: &str| {
+            let cache_args = cache_args.clone();
+            let mut rx = rx.to_string();
+            async move {
+                let tx_hash = blake3::hash(cache_args.cache_key_input(&method).as_bytes());
+                cache_query(&mut rx, method, tx_hash, &cache_args).await;
+                db_get!(cache_args.cache, tx_hash.as_bytes().to_owned()).unwrap()
+            }
+        };
+        let cache = |method: Value| cache_rx(method, r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#);
+
+        // Cached even though there is no block number in the request
+        assert!(cache(json!({"method": "eth_chainId", "params": []}))
+            .await
+            .is_some());
+        assert!(cache(json!({"method": "eth_gasPrice", "params": []}))
+            .await
+            .is_some());
+        assert!(
+            cache(json!({"method": "eth_getBlockByNumber", "params": ["0x10", false]}))
+                .await
+                .is_none()
+        );
+
+        // Policies don't get around the blacklists
+        assert!(cache(json!({"method": "eth_blockNumber", "params": []}))
+            .await
+            .is_none());
+        assert!(
+            cache(json!({"method": "eth_getBalance", "params": ["0x01", "latest"]}))
+                .await
+                .is_none()
+        );
+
+        let error = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"x"}}"#;
+        assert!(
+            cache_rx(json!({"method": "eth_gasPrice", "params": [1]}), error)
+                .await
+                .is_none()
+        );
+
+        // Block policies are keyed on the head
+        let tx = json!({"method": "eth_maxPriorityFeePerGas", "params": []});
+        let before = cache_args.cache_key_input(&tx);
+        assert!(cache(tx.clone()).await.is_some());
+        cache_args.named_numbers.write().unwrap().latest += 1;
+        assert_ne!(before, cache_args.cache_key_input(&tx));
+
+        // Expiry follows the policy
+        let now = 1_000_000;
+        assert_eq!(
+            cache_args.expires_at(&json!({"method": "eth_chainId"}), now),
+            None
+        );
+        let gas_price = cache_args
+            .expires_at(&json!({"method": "eth_gasPrice"}), now)
+            .unwrap();
+        assert!((now + 2700..=now + 3300).contains(&gas_price));
+        assert_eq!(
+            cache_args.expires_at(&tx, now),
+            Some(now + cache_args.block_time_ms)
+        );
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cache_query_negative() {
+        let mut cache_args = CacheArgs::default();
+        cache_args.expiry.jitter = 0.0;
+
+        let cache = |cache_args: &CacheArgs<[u8; 32], Vec<u8>>, method: Value, rx: &str| {
+            let cache_args = cache_args.clone();
+            let mut rx = rx.to_string();
+            async move {
+                let tx_hash = blake3::hash(cache_args.cache_key_input(&method).as_bytes());
+                cache_query(&mut rx, method, tx_hash, &cache_args).await;
+                db_get!(cache_args.cache, tx_hash.as_bytes().to_owned()).unwrap()
+            }
+        };
+        let null = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        let receipt = json!({"method": "eth_getTransactionReceipt", "params": ["0x01"]});
+
+        // Disabled by default
+        assert!(cache(&cache_args, receipt.clone(), null).await.is_none());
+
+        cache_args.expiry.negative_ttl_ms = 500;
+        let mut entry = cache(&cache_args, receipt.clone(), null).await.unwrap();
+        let cached = decode_cached(&mut entry.clone()).unwrap().unwrap();
+        assert_eq!(cached["result"], Value::Null);
+
+        // Expires after `negative_ttl_ms`
+        assert!(strip_expiry(&mut entry, now_ms() + 1000).is_none());
+
+        // Errors and `latest` requests still aren't cached
+        let error =
+            r#"{"jsonrpc":"2.0","id":1,"result":null,"error":{"code":-32000,"message":"x"}}"#;
+        let balance = json!({"method": "eth_getBalance", "params": ["0x01", "0x10"]});
+        assert!(cache(&cache_args, balance, error).await.is_none());
+        let block = json!({"method": "eth_getBlockByNumber", "params": ["latest", false]});
+        assert!(cache(&cache_args, block, null).await.is_none());
+    }
+
+    #[test]
+    fn test_negative_result() {
+        assert!(negative_result(r#"{"jsonrpc":"2.0","id":1,"result":null}"#));
+        assert!(negative_result(r#"{ "id": 1, "result" : null }"#));
+        assert!(!negative_result(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#
+        ));
+        assert!(!negative_result(
+            r#"{"jsonrpc":"2.0","id":1,"result":{"to":null}}"#
+        ));
+        assert!(!negative_result(
+            r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32000}}"#
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cap_unfinalized() {
+        let (finalized_tx, finalized_rx) = watch::channel(100);
+        let mut cache_args = CacheArgs::default();
+        cache_args.finalized_rx = finalized_rx;
+        cache_args.expiry.jitter = 0.0;
+        cache_args.expiry.unfinalized_ttl_ms = 1000;
+
+        // Finalized or unknown blocks keep their expiry
+        assert_eq!(cache_args.cap_unfinalized(None, Some(100), 0), None);
+        assert_eq!(cache_args.cap_unfinalized(Some(5000), None, 0), Some(5000));
+
+        // Unfinalized blocks expire after at most `unfinalized_ttl_ms`
+        assert_eq!(cache_args.cap_unfinalized(None, Some(101), 0), Some(1000));
+        assert_eq!(
+            cache_args.cap_unfinalized(Some(5000), Some(101), 0),
+            Some(1000)
+        );
+        assert_eq!(
+            cache_args.cap_unfinalized(Some(500), Some(101), 0),
+            Some(500)
+        );
+
+        // Moves with the finalized block
+        finalized_tx.send(101).unwrap();
+        assert_eq!(cache_args.cap_unfinalized(None, Some(101), 0), None);
+
+        cache_args.expiry.unfinalized_ttl_ms = 0;
+        assert_eq!(cache_args.cap_unfinalized(None, Some(200), 0), None);
+    }
+
     #[tokio::test]
     async fn test_update_rpc_latency() {
         let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
@@ -0,0 +1,177 @@
+//! PROXY protocol headers from load balancers in front of us.
+//!
+//! TCP load balancers can't add `X-Forwarded-For`, so they send the address
+//! of the client in a PROXY protocol header before anything else on the
+//! connection. With `proxy_protocol` set in `[blutgang.rate_limit]`, every
+//! connection from a trusted proxy has to start with one, in either the text
+//! (v1) or binary (v2) format.
+
+use std::{
+    io,
+    net::{
+        IpAddr,
+        Ipv4Addr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+};
+
+use tokio::io::{
+    AsyncRead,
+    AsyncReadExt,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+/// Longest v1 header, including the line ending.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {reason}"),
+    )
+}
+
+/// Parse the v1 header `line`, without its line ending.
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let mut fields = line.split(' ');
+    if fields.next() != Some("PROXY") {
+        return Err(invalid("missing PROXY"));
+    }
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(invalid("unknown protocol")),
+    }
+
+    let source = fields
+        .next()
+        .and_then(|source| source.parse::<IpAddr>().ok())
+        .ok_or_else(|| invalid("bad source address"))?;
+    let source_port = fields
+        .nth(1)
+        .and_then(|port| port.parse::<u16>().ok())
+        .ok_or_else(|| invalid("bad source port"))?;
+
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+/// Parse the v2 addresses of `family` in `addresses`.
+fn parse_v2(command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match command {
+        // LOCAL, like health checks from the proxy itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown command")),
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 if addresses.len() >= 12 => {
+            let source = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(source.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 if addresses.len() >= 36 => {
+            let source = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(source.into(), port)))
+        }
+        0x11 | 0x21 => Err(invalid("truncated addresses")),
+        // Unix sockets and unspecified, nothing we can use
+        _ => Ok(None),
+    }
+}
+
+/// Read the PROXY protocol header at the start of `stream`.
+///
+/// Returns the address of the client, or `None` if the proxy didn't say.
+/// Reads exactly the header, leaving the rest of the stream untouched.
+pub async fn read_proxy_header<S>(stream: &mut S) -> io::Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                return Err(invalid("too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("not text"))?;
+        return parse_v1(line);
+    }
+
+    if prefix != V2_SIGNATURE[..5] {
+        return Err(invalid("missing header"));
+    }
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("bad signature"));
+    }
+    let (version_command, family) = (header[7], header[8]);
+    if version_command >> 4 != 2 {
+        return Err(invalid("unknown version"));
+    }
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+    let mut addresses = vec![0; len];
+    stream.read_exact(&mut addresses).await?;
+
+    parse_v2(version_command & 0xf, family, &addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_v1() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\nPOST / HTTP/1.1";
+        let source = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(stream, b"POST / HTTP/1.1");
+
+        let mut stream: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 443\r\n";
+        let source = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:51234".parse().unwrap()));
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut stream).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"POST / HTTP/1.1\r\n";
+        assert!(read_proxy_header(&mut stream).await.is_err());
+
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+        assert!(read_proxy_header(&mut long.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([203, 0, 113, 7, 10, 0, 0, 1]);
+        header.extend(51234u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header.extend(b"POST");
+
+        let mut stream = header.as_slice();
+        let source = read_proxy_header(&mut stream).await.unwrap();
+        assert_eq!(source, Some("203.0.113.7:51234".parse().unwrap()));
+        assert_eq!(stream, b"POST");
+
+        // LOCAL
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(
+            read_proxy_header(&mut header.as_slice()).await.unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,364 @@
+//! Per-client rate limiting.
+//!
+//! With `requests_per_second` set in `[blutgang.rate_limit]`, every client IP
+//! gets a token bucket of `burst` calls, refilled at `requests_per_second`.
+//! Buckets are kept in the counter store (see `counters`), so limits hold
+//! across instances sharing a Redis. Clients over their limit get a 429 with
+//! `Retry-After`, or an error for calls over WebSockets.
+//!
+//! Every call counts: each item of a batch, each call over a WebSocket, and
+//! opening the WebSocket. IPv6 clients are limited by their /64, since that's
+//! usually what a single host gets.
+//!
+//! Behind a proxy, every request looks like it's from the proxy. Proxies in
+//! `trusted_proxies` are trusted to tell us who the client is, through
+//! `X-Forwarded-For` or the PROXY protocol (see `proxy_protocol`).
+
+use crate::{
+    balancer::{
+        api_keys::ApiKey,
+        compute_units::charge_client,
+        counters::{
+            window_end,
+            CounterStore,
+            Counters,
+        },
+    },
+    config::types::{
+        ComputeUnitSettings,
+        RateLimitSettings,
+    },
+    database::expiry::now_ms,
+};
+
+use std::{
+    net::{
+        IpAddr,
+        Ipv6Addr,
+        SocketAddr,
+    },
+    sync::Arc,
+};
+
+use hyper::header::HeaderMap;
+use rust_tracing::deps::metrics;
+
+/// Counter store requests are counted in.
+pub type RequestCounters = CounterStore<[u8; 32], Vec<u8>>;
+
+const FORWARDED_FOR: &str = "x-forwarded-for";
+
+impl RateLimitSettings {
+    pub fn enabled(&self) -> bool {
+        self.requests_per_second != 0
+    }
+
+    pub fn burst(&self) -> u64 {
+        if self.burst == 0 {
+            self.requests_per_second
+        } else {
+            self.burst
+        }
+    }
+
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+
+    /// IP of the client a request from `peer` with `headers` is from.
+    ///
+    /// Walks `X-Forwarded-For` from the right, skipping our own proxies, as
+    /// only the entries they added can be trusted.
+    pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let peer = peer?.ip();
+        if !self.is_trusted_proxy(peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        for forwarded in headers.get_all(FORWARDED_FOR).iter().rev() {
+            let Ok(forwarded) = forwarded.to_str() else {
+                return Some(client);
+            };
+            for hop in forwarded.rsplit(',') {
+                match hop.trim().parse::<IpAddr>() {
+                    Ok(hop) => {
+                        client = hop;
+                        if !self.is_trusted_proxy(hop) {
+                            return Some(client);
+                        }
+                    }
+                    Err(_) => return Some(client),
+                }
+            }
+        }
+
+        Some(client)
+    }
+}
+
+/// Key the limits of the client at `ip` are counted under.
+pub fn client_key(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let prefix = u128::from(ip) & !(u128::from(u64::MAX));
+            format!("{}/64", Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// Take `calls` calls from the bucket of `client`, which gets `per_second` of
+/// them back every second, up to `burst`. `kind` labels the metric of calls
+/// over the limit.
+///
+/// Returns the seconds until the client can retry if it's over its limit.
+/// Calls are let through if the counters can't be reached.
+pub async fn take_calls(
+    counters: &RequestCounters,
+    client: &str,
+    calls: u64,
+    per_second: u64,
+    burst: u64,
+    kind: &'static str,
+) -> Result<(), u64> {
+    match counters.take(client, calls, per_second, burst).await {
+        Ok(None) => Ok(()),
+        Ok(Some(wait)) => {
+            metrics::counter!("rate_limited_total", "kind" => kind).increment(1);
+            Err(wait.as_secs_f64().ceil().max(1.0) as u64)
+        }
+        Err(err) => {
+            tracing::warn!(%err, "Failed to count request, not rate limiting it");
+            Ok(())
+        }
+    }
+}
+
+/// Count `units` against `client`, which can use `limit` of them every
+/// `window_ms`. `kind` labels the metric of requests over the limit.
+///
+/// Returns the seconds until the window is over if the client is over its
+/// limit. Requests are let through if the counters can't be reached.
+pub async fn count_units(
+    counters: &RequestCounters,
+    client: &str,
+    units: u64,
+    limit: u64,
+    window_ms: u64,
+    kind: &'static str,
+) -> Result<(), u64> {
+    let count = match counters.increment(client, units, window_ms).await {
+        Ok(count) => count,
+        Err(err) => {
+            tracing::warn!(%err, "Failed to count request, not rate limiting it");
+            return Ok(());
+        }
+    };
+
+    if count <= limit {
+        return Ok(());
+    }
+
+    metrics::counter!("rate_limited_total", "kind" => kind).increment(1);
+    let now = now_ms();
+    Err((window_end(now, window_ms) - now).div_ceil(1000).max(1))
+}
+
+/// Count `calls` calls from `ip`, see `take_calls`.
+pub async fn check_rate_limit(
+    settings: &RateLimitSettings,
+    counters: &RequestCounters,
+    ip: IpAddr,
+    calls: u64,
+) -> Result<(), u64> {
+    take_calls(
+        counters,
+        &format!("ip:{}", client_key(ip)),
+        calls,
+        settings.requests_per_second,
+        settings.burst(),
+        "ip",
+    )
+    .await
+}
+
+/// Limits of a client, to charge each of its calls to.
+#[derive(Clone)]
+pub struct CallLimiter {
+    counters: Arc<RequestCounters>,
+    settings: Arc<RateLimitSettings>,
+    api_key: Option<Arc<ApiKey>>,
+    client_ip: Option<IpAddr>,
+}
+
+impl CallLimiter {
+    pub fn new(
+        counters: &Arc<RequestCounters>,
+        settings: &Arc<RateLimitSettings>,
+        api_key: Option<&Arc<ApiKey>>,
+        client_ip: Option<IpAddr>,
+    ) -> Self {
+        Self {
+            counters: counters.clone(),
+            settings: settings.clone(),
+            api_key: api_key.cloned(),
+            client_ip,
+        }
+    }
+
+    /// Charge `calls` calls to the rate limit of the client, and the quota of its key.
+    ///
+    /// Returns the seconds until the client can retry, and why, if it's over either.
+    pub async fn charge(&self, calls: u64) -> Result<(), (u64, &'static str)> {
+        match (&self.api_key, self.client_ip) {
+            (Some(api_key), _) => {
+                api_key
+                    .check_rate_limit(&self.counters, calls)
+                    .await
+                    .map_err(|retry_after| (retry_after, "rate limit exceeded"))?;
+                api_key
+                    .check_quota(&self.counters, calls)
+                    .await
+                    .map_err(|retry_after| (retry_after, "quota exceeded"))
+            }
+            (None, Some(client_ip)) if self.settings.enabled() => {
+                check_rate_limit(&self.settings, &self.counters, client_ip, calls)
+                    .await
+                    .map_err(|retry_after| (retry_after, "rate limit exceeded"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Charge `units` compute units to the budget of the client, see `compute_units`.
+    ///
+    /// Returns the seconds until the budget resets if they don't fit in it.
+    pub async fn charge_compute_units(
+        &self,
+        settings: &ComputeUnitSettings,
+        units: u64,
+    ) -> Result<(), u64> {
+        charge_client(
+            settings,
+            &self.counters,
+            self.api_key.as_deref(),
+            self.client_ip,
+            units,
+        )
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::counters::MemoryCounters;
+    use hyper::header::HeaderValue;
+
+    fn settings() -> RateLimitSettings {
+        RateLimitSettings {
+            requests_per_second: 2,
+            burst: 4,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+            proxy_protocol: false,
+        }
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let settings = settings();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            FORWARDED_FOR,
+            HeaderValue::from_static("198.51.100.1, 203.0.113.7, 10.0.0.2"),
+        );
+
+        // Untrusted peers can't pick their own address
+        let peer = Some("203.0.113.9:1234".parse().unwrap());
+        assert_eq!(
+            settings.client_ip(peer, &headers),
+            Some("203.0.113.9".parse().unwrap())
+        );
+
+        let peer = Some("10.0.0.1:1234".parse().unwrap());
+        assert_eq!(
+            settings.client_ip(peer, &headers),
+            Some("203.0.113.7".parse().unwrap())
+        );
+        assert_eq!(
+            settings.client_ip(peer, &HeaderMap::new()),
+            Some("10.0.0.1".parse().unwrap())
+        );
+        assert_eq!(settings.client_ip(None, &headers), None);
+    }
+
+    #[test]
+    fn test_client_key() {
+        assert_eq!(client_key("203.0.113.7".parse().unwrap()), "203.0.113.7");
+        assert_eq!(
+            client_key("::ffff:203.0.113.7".parse().unwrap()),
+            "203.0.113.7"
+        );
+        // Same /64, same client
+        assert_eq!(
+            client_key("2001:db8:1:2:aaaa::1".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            client_key("2001:db8:1:2:bbbb::2".parse().unwrap()),
+            "2001:db8:1:2::/64"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_rate_limit() {
+        let settings = settings();
+        let counters = RequestCounters::Memory(MemoryCounters::default());
+        let ip = "203.0.113.7".parse().unwrap();
+
+        // A whole burst at once, then nothing until the bucket refills
+        assert!(check_rate_limit(&settings, &counters, ip, 3).await.is_ok());
+        assert!(check_rate_limit(&settings, &counters, ip, 1).await.is_ok());
+        assert_eq!(check_rate_limit(&settings, &counters, ip, 1).await, Err(1));
+
+        // Other addresses in the same /64 share the bucket
+        let a = "2001:db8::1".parse().unwrap();
+        let b = "2001:db8::2".parse().unwrap();
+        assert!(check_rate_limit(&settings, &counters, a, 4).await.is_ok());
+        assert!(check_rate_limit(&settings, &counters, b, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_call_limiter() {
+        let counters = Arc::new(RequestCounters::Memory(MemoryCounters::default()));
+        let settings = Arc::new(settings());
+        let limiter = CallLimiter::new(
+            &counters,
+            &settings,
+            None,
+            Some("203.0.113.7".parse().unwrap()),
+        );
+
+        assert!(limiter.charge(4).await.is_ok());
+        assert_eq!(limiter.charge(1).await, Err((1, "rate limit exceeded")));
+
+        // Without an address to limit, nothing is
+        let limiter = CallLimiter::new(&counters, &settings, None, None);
+        assert!(limiter.charge(100).await.is_ok());
+
+        // Compute units come out of the budget of the client
+        let budget = ComputeUnitSettings {
+            ip_budget: 10,
+            ..Default::default()
+        };
+        let limiter = CallLimiter::new(
+            &counters,
+            &settings,
+            None,
+            Some("203.0.113.8".parse().unwrap()),
+        );
+        assert!(limiter.charge_compute_units(&budget, 10).await.is_ok());
+        assert!(limiter.charge_compute_units(&budget, 1).await.is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Bounds on the size of client requests.
+//!
+//! `[blutgang.request_limits]` caps the size of request bodies, the number of
+//! requests in a batch, and the length of any single string in `params`, like
+//! the calldata of an `eth_call`. Bodies are checked against their
+//! `Content-Length` before we read them, and cut off while reading if they
+//! don't have one, so oversized requests never get buffered or parsed.
+
+use crate::config::types::RequestLimits;
+
+use std::fmt;
+
+use hyper::header::{
+    HeaderMap,
+    CONTENT_LENGTH,
+};
+use serde_json::Value;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestLimitError {
+    BodyTooLarge(usize),
+    BatchTooLong(usize),
+    ParamTooLong(usize),
+}
+
+impl fmt::Display for RequestLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestLimitError::BodyTooLarge(max) => {
+                write!(f, "request body larger than {} bytes", max)
+            }
+            RequestLimitError::BatchTooLong(max) => {
+                write!(f, "batch of more than {} requests", max)
+            }
+            RequestLimitError::ParamTooLong(max) => {
+                write!(f, "parameter longer than {} bytes", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RequestLimitError {}
+
+/// Returns true if `limit` is set and `value` exceeds it. `0` means unlimited.
+fn exceeds(value: usize, limit: usize) -> bool {
+    limit != 0 && value > limit
+}
+
+/// Length of the longest string in `value`.
+fn longest_string(value: &Value) -> usize {
+    match value {
+        Value::String(string) => string.len(),
+        Value::Array(values) => values.iter().map(longest_string).max().unwrap_or(0),
+        Value::Object(values) => values.values().map(longest_string).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+impl RequestLimits {
+    /// Most bytes of a body we're willing to read.
+    pub fn body_limit(&self) -> usize {
+        match self.max_body_bytes {
+            0 => usize::MAX,
+            max => max,
+        }
+    }
+
+    /// Check the `Content-Length` in `headers`, if there's one.
+    pub fn check_content_length(&self, headers: &HeaderMap) -> Result<(), RequestLimitError> {
+        let length = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        match length {
+            Some(length) if exceeds(length, self.max_body_bytes) => {
+                Err(RequestLimitError::BodyTooLarge(self.max_body_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Most requests of a batch we forward at the same time.
+    pub fn batch_concurrency(&self) -> usize {
+        match self.max_batch_concurrency {
+            0 => usize::MAX,
+            max => max,
+        }
+    }
+
+    /// Check a batch of `len` requests.
+    pub fn check_batch(&self, len: usize) -> Result<(), RequestLimitError> {
+        if exceeds(len, self.max_batch_len) {
+            return Err(RequestLimitError::BatchTooLong(self.max_batch_len));
+        }
+        Ok(())
+    }
+
+    /// Check the `params` of `request`.
+    pub fn check_params(&self, request: &Value) -> Result<(), RequestLimitError> {
+        if self.max_param_bytes == 0 {
+            return Ok(());
+        }
+        match request.get("params") {
+            Some(params) if exceeds(longest_string(params), self.max_param_bytes) => {
+                Err(RequestLimitError::ParamTooLong(self.max_param_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+    use serde_json::json;
+
+    fn limits() -> RequestLimits {
+        RequestLimits {
+            max_body_bytes: 1024,
+            max_batch_len: 2,
+            max_param_bytes: 10,
+            max_batch_concurrency: 4,
+        }
+    }
+
+    #[test]
+    fn test_check_content_length() {
+        let mut headers = HeaderMap::new();
+        assert!(limits().check_content_length(&headers).is_ok());
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("1024"));
+        assert!(limits().check_content_length(&headers).is_ok());
+
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("1025"));
+        assert_eq!(
+            limits().check_content_length(&headers),
+            Err(RequestLimitError::BodyTooLarge(1024))
+        );
+
+        let unlimited = RequestLimits {
+            max_body_bytes: 0,
+            ..limits()
+        };
+        assert!(unlimited.check_content_length(&headers).is_ok());
+        assert_eq!(unlimited.body_limit(), usize::MAX);
+    }
+
+    #[test]
+    fn test_check_batch() {
+        assert!(limits().check_batch(2).is_ok());
+        assert_eq!(
+            limits().check_batch(3),
+            Err(RequestLimitError::BatchTooLong(2))
+        );
+
+        assert_eq!(limits().batch_concurrency(), 4);
+        let unlimited = RequestLimits {
+            max_batch_concurrency: 0,
+            ..limits()
+        };
+        assert_eq!(unlimited.batch_concurrency(), usize::MAX);
+    }
+
+    #[test]
+    fn test_check_params() {
+        let call = |data: &str| {
+            json!({
+                "method": "eth_call",
+                "params": [{"to": "0x01", "data": data}, "latest"],
+            })
+        };
+        assert!(limits().check_params(&call("0x12345678")).is_ok());
+        assert_eq!(
+            limits().check_params(&call("0x1234567890")),
+            Err(RequestLimitError::ParamTooLong(10))
+        );
+        assert!(limits()
+            .check_params(&json!({"method": "eth_blockNumber"}))
+            .is_ok());
+    }
+}
@@ -0,0 +1,347 @@
+//! Comparing responses of different RPCs.
+//!
+//! Providers can agree on blocks while serving subtly different data, like
+//! logs missing from `eth_getLogs` or different `eth_estimateGas` results.
+//! With `[blutgang.response_diff]` enabled, requests for the configured methods
+//! served by an RPC are sent to a second, randomly picked one as well, once the
+//! client has its response. Both responses are compared field by field, and
+//! every difference is logged as a warning with the path it's at, like
+//! `result[3].topics[1]`.
+//!
+//! Fields nodes fill in differently, like `totalDifficulty`, are left out of
+//! the comparison, and hex strings are compared regardless of case.
+//!
+//! Only `sample_percent` of those requests get compared, and only for methods
+//! that can be mirrored to canaries, since sending anything else a second time
+//! could change state.
+
+use crate::{
+    balancer::{
+        cache_metrics::method_label,
+        method_filter::method_matches,
+        shadow::mirrorable,
+    },
+    config::types::ResponseDiffSettings,
+    Rpc,
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use rand::{
+    seq::SliceRandom,
+    Rng,
+};
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+use tokio::time::timeout;
+
+/// A field the two responses have different values for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    /// Where it is in the response, like `result[3].topics[1]`.
+    pub path: String,
+    /// `None` if it's missing from the response.
+    pub left: Option<Value>,
+    pub right: Option<Value>,
+}
+
+impl ResponseDiffSettings {
+    /// Returns true if responses to `method` should be compared.
+    pub fn applies_to(&self, method: Option<&str>) -> bool {
+        self.enabled
+            && mirrorable(method)
+            && method.is_some_and(|method| {
+                self.methods
+                    .iter()
+                    .any(|pattern| method_matches(pattern, method))
+            })
+    }
+
+    /// Returns true if this response to `method` should be compared, sampling
+    /// `sample_percent` of the ones `applies_to`.
+    pub fn sample(&self, method: Option<&str>) -> bool {
+        // `max` drops NaN
+        let share = self.sample_percent.max(0.0).min(100.0) / 100.0;
+        self.applies_to(method) && rand::thread_rng().gen_bool(share)
+    }
+}
+
+/// Returns the RPC at `served` in `rpc_list`, along with another RPC that can
+/// serve `method` to compare it with.
+pub fn pick_peer(rpc_list: &[Rpc], served: usize, method: &str) -> Option<(Rpc, Rpc)> {
+    let candidates: Vec<&Rpc> = rpc_list
+        .iter()
+        .enumerate()
+        .filter(|(position, rpc)| {
+            *position != served && rpc.status.is_selectable() && rpc.capabilities.supports(method)
+        })
+        .map(|(_, rpc)| rpc)
+        .collect();
+
+    let peer = candidates.choose(&mut rand::thread_rng())?;
+    Some((rpc_list.get(served)?.clone(), (*peer).clone()))
+}
+
+/// Leave out `ignore_fields` and the fields of the JSON-RPC envelope other
+/// than the result or error, and lowercase hex strings.
+fn normalize(response: &mut Value, ignore_fields: &[String]) {
+    if let Value::Object(envelope) = response {
+        envelope.retain(|key, _| key == "result" || key == "error");
+    }
+    normalize_value(response, ignore_fields);
+}
+
+fn normalize_value(value: &mut Value, ignore_fields: &[String]) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|key, _| !ignore_fields.contains(key));
+            for value in map.values_mut() {
+                normalize_value(value, ignore_fields);
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                normalize_value(item, ignore_fields);
+            }
+        }
+        Value::String(string) if string.starts_with("0x") => {
+            string.make_ascii_lowercase();
+        }
+        _ => (),
+    }
+}
+
+/// Collect the differences between `left` and `right` under `path`, up to
+/// `max` of them.
+fn diff_values(
+    left: Option<&Value>,
+    right: Option<&Value>,
+    path: &str,
+    max: usize,
+    differences: &mut Vec<Difference>,
+) {
+    if differences.len() >= max {
+        return;
+    }
+
+    match (left, right) {
+        (Some(Value::Object(left)), Some(Value::Object(right))) => {
+            let mut keys: Vec<&String> = left.keys().chain(right.keys()).collect();
+            keys.sort();
+            keys.dedup();
+            for key in keys {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                diff_values(left.get(key), right.get(key), &path, max, differences);
+            }
+        }
+        (Some(Value::Array(left)), Some(Value::Array(right))) => {
+            for index in 0..left.len().max(right.len()) {
+                let path = format!("{}[{}]", path, index);
+                diff_values(left.get(index), right.get(index), &path, max, differences);
+            }
+        }
+        (left, right) if left != right => {
+            differences.push(Difference {
+                path: path.to_string(),
+                left: left.cloned(),
+                right: right.cloned(),
+            });
+        }
+        _ => (),
+    }
+}
+
+/// Returns the differences between the responses `left` and `right`, after
+/// normalizing them.
+pub fn diff(mut left: Value, mut right: Value, settings: &ResponseDiffSettings) -> Vec<Difference> {
+    normalize(&mut left, &settings.ignore_fields);
+    normalize(&mut right, &settings.ignore_fields);
+
+    let mut differences = Vec::new();
+    diff_values(
+        Some(&left),
+        Some(&right),
+        "",
+        settings.max_differences.max(1),
+        &mut differences,
+    );
+    differences
+}
+
+/// Send `tx` to `peer`, and log how its response differs from `response`,
+/// which `served` responded with.
+pub async fn compare_with_peer(
+    tx: Value,
+    served: Rpc,
+    response: String,
+    peer: Rpc,
+    settings: Arc<ResponseDiffSettings>,
+    ttl: u128,
+) {
+    let method = method_label(tx["method"].as_str());
+    let peer_response = timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        peer.send_request(tx),
+    )
+    .await
+    .ok()
+    .and_then(Result::ok)
+    .and_then(|response| serde_json::from_str::<Value>(&response).ok());
+    let (Some(peer_response), Ok(response)) =
+        (peer_response, serde_json::from_str::<Value>(&response))
+    else {
+        return;
+    };
+
+    let differences = diff(response, peer_response, &settings);
+    let result = match differences.is_empty() {
+        true => "same",
+        false => "different",
+    };
+    metrics::counter!("response_diffs_total", "method" => method.clone(), "result" => result)
+        .increment(1);
+
+    for difference in differences {
+        tracing::warn!(
+            method,
+            served = %served.name,
+            peer = %peer.name,
+            path = difference.path,
+            served_value = ?difference.left,
+            peer_value = ?difference.right,
+            "RPC responses differ"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn settings() -> ResponseDiffSettings {
+        ResponseDiffSettings {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_applies_to() {
+        let settings = ResponseDiffSettings {
+            methods: vec!["eth_getLogs".to_string(), "debug_*".to_string()],
+            ..settings()
+        };
+        assert!(settings.applies_to(Some("eth_getLogs")));
+        assert!(settings.applies_to(Some("debug_traceTransaction")));
+        assert!(!settings.applies_to(Some("eth_call")));
+        assert!(!settings.applies_to(None));
+
+        // Sending anything but reads twice could change state
+        let everything = ResponseDiffSettings {
+            methods: vec!["*".to_string()],
+            ..settings()
+        };
+        assert!(everything.applies_to(Some("eth_call")));
+        assert!(!everything.applies_to(Some("eth_sendRawTransaction")));
+        assert!(!everything.applies_to(Some("mev_sendBundle")));
+
+        let disabled = ResponseDiffSettings::default();
+        assert!(!disabled.applies_to(Some("eth_getLogs")));
+    }
+
+    #[test]
+    fn test_sample() {
+        let sampled = |sample_percent| {
+            ResponseDiffSettings {
+                sample_percent,
+                ..settings()
+            }
+            .sample(Some("eth_getLogs"))
+        };
+        assert!(sampled(100.0));
+        assert!(!sampled(0.0));
+        assert!(!sampled(f64::NAN));
+        assert!(sampled(1000.0));
+    }
+
+    #[test]
+    fn test_diff_same() {
+        // Different ids, envelopes, hex case and node specific fields
+        let left = json!({"jsonrpc": "2.0", "id": 1, "result": {"hash": "0xABCD", "number": "0x1", "totalDifficulty": "0x0"}});
+        let right =
+            json!({"jsonrpc": "2.0", "id": 7, "result": {"hash": "0xabcd", "number": "0x1"}});
+        assert!(diff(left, right, &settings()).is_empty());
+    }
+
+    #[test]
+    fn test_diff_missing_log() {
+        let log = |index: &str| json!({"logIndex": index, "topics": ["0x01"], "data": "0x"});
+        let left = json!({"jsonrpc": "2.0", "id": 1, "result": [log("0x0"), log("0x1")]});
+        let right = json!({"jsonrpc": "2.0", "id": 1, "result": [log("0x0")]});
+
+        assert_eq!(
+            diff(left, right, &settings()),
+            vec![Difference {
+                path: "result[1]".to_string(),
+                left: Some(log("0x1")),
+                right: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_diff_values() {
+        let left = json!({"id": 1, "result": "0x5208"});
+        let right = json!({"id": 1, "result": "0x5209"});
+        assert_eq!(
+            diff(left, right, &settings()),
+            vec![Difference {
+                path: "result".to_string(),
+                left: Some(json!("0x5208")),
+                right: Some(json!("0x5209")),
+            }]
+        );
+
+        // Errors on one side only
+        let left = json!({"id": 1, "result": "0x5208"});
+        let right = json!({"id": 1, "error": {"code": -32000, "message": "execution reverted"}});
+        let differences = diff(left, right, &settings());
+        assert_eq!(differences.len(), 2);
+        assert_eq!(differences[0].path, "error");
+        assert_eq!(differences[1].path, "result");
+    }
+
+    #[test]
+    fn test_diff_max_differences() {
+        let left = json!({"id": 1, "result": ["0x1", "0x2", "0x3"]});
+        let right = json!({"id": 1, "result": ["0x4", "0x5", "0x6"]});
+        let settings = ResponseDiffSettings {
+            max_differences: 2,
+            ..settings()
+        };
+        assert_eq!(diff(left, right, &settings).len(), 2);
+    }
+
+    #[test]
+    fn test_pick_peer() {
+        let mut rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        rpc_list[0].name = "served".to_string();
+        rpc_list[1].name = "syncing".to_string();
+        rpc_list[1].status.is_syncing = true;
+        rpc_list[2].name = "peer".to_string();
+
+        let (served, peer) = pick_peer(&rpc_list, 0, "eth_getLogs").unwrap();
+        assert_eq!(served.name, "served");
+        assert_eq!(peer.name, "peer");
+
+        // Nobody to compare with
+        assert!(pick_peer(&rpc_list[..2], 0, "eth_getLogs").is_none());
+    }
+}
@@ -48,6 +48,130 @@ macro_rules! cache_error {
     };
 }
 
+#[macro_export]
+macro_rules! invalid_params {
+    (
+        $id:expr,
+        $reason:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": $id,
+                    "error": {
+                        "code": -32602,
+                        "message": format!("invalid params: {}", $reason),
+                    },
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! request_too_large {
+    (
+        $reason:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(413)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": -32600,
+                        "message": format!("invalid request: {}", $reason),
+                    },
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! method_not_allowed {
+    (
+        $id:expr,
+        $method:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(200)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": $id,
+                    "error": {
+                        "code": -32601,
+                        "message": format!("method not allowed: {}", $method),
+                    },
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! unauthorized {
+    (
+        $reason:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(401)
+            .header("Content-Type", "application/json")
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": -32007,
+                        "message": $reason,
+                    },
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
+#[macro_export]
+macro_rules! rate_limited {
+    (
+        $retry_after_secs:expr
+    ) => {
+        $crate::rate_limited!($retry_after_secs, "rate limit exceeded")
+    };
+    (
+        $retry_after_secs:expr,
+        $message:expr
+    ) => {
+        Ok(hyper::Response::builder()
+            .status(429)
+            .header("Content-Type", "application/json")
+            .header("Retry-After", $retry_after_secs)
+            .body(Full::new(Bytes::from(
+                serde_json::json!({
+                    "jsonrpc": "2.0",
+                    "id": null,
+                    "error": {
+                        "code": -32005,
+                        "message": $message,
+                    },
+                })
+                .to_string(),
+            )))
+            .unwrap())
+    };
+}
+
 #[macro_export]
 macro_rules! rpc_response {
     (
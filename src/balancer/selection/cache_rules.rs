@@ -2,7 +2,10 @@ use memchr::memmem;
 
 use crate::{
     balancer::format::NamedNumber,
-    rpc::method::EthRpcMethod,
+    rpc::{
+        engine::ENGINE_PREFIX,
+        method::EthRpcMethod,
+    },
 };
 
 // Return true if we are supposed to be caching the input.
@@ -25,6 +28,7 @@ pub fn cache_method<M: AsRef<str>>(rx: M) -> bool {
         EthRpcMethod::GetTransactionCount.as_ref(),
         EthRpcMethod::Subscribe.as_ref(),
         EthRpcMethod::Unsubscribe.as_ref(),
+        ENGINE_PREFIX,
     ];
     // rx should look something like `{"id":1,"jsonrpc":"2.0","method":"eth_call","params":...`
     // Even tho rx should look like the example above, its still a valid request if the method
@@ -61,3 +65,91 @@ pub fn cache_result(rx: &str) -> bool {
 
     true
 }
+
+/// Returns true if `rx` is a successful response with a `null` result, like a
+/// receipt that isn't available yet.
+pub fn negative_result(rx: &str) -> bool {
+    // If no-cache feature is on, return false
+    #[cfg(feature = "no-cache")]
+    return false;
+
+    // Skip parsing anything that can't be one, `null` results are tiny
+    if rx.len() > 1024 || memmem::find(rx.as_bytes(), b"null").is_none() {
+        return false;
+    }
+
+    match serde_json::from_str::<serde_json::Value>(rx) {
+        Ok(rx) => {
+            rx.get("result").is_some_and(|result| result.is_null()) && rx.get("error").is_none()
+        }
+        Err(_) => false,
+    }
+}
+
+/// Methods that only read chain data.
+const READ_METHODS: &[&str] = &[
+    "eth_blobBaseFee",
+    "eth_blockNumber",
+    "eth_call",
+    "eth_chainId",
+    "eth_createAccessList",
+    "eth_estimateGas",
+    "eth_feeHistory",
+    "eth_gasPrice",
+    "eth_maxPriorityFeePerGas",
+    "eth_protocolVersion",
+    "eth_syncing",
+    "net_listening",
+    "net_peerCount",
+    "net_version",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+/// Prefixes of methods that only read chain data.
+const READ_PREFIXES: &[&str] = &["eth_get", "debug_trace", "debug_getRaw", "trace_"];
+/// Methods matching `READ_PREFIXES` that depend on state kept by the RPC serving them.
+const STATEFUL_METHODS: &[&str] = &["eth_getFilterChanges", "eth_getFilterLogs", "eth_getWork"];
+
+/// Returns true if `method` only reads chain data, so sending it again, or to
+/// another RPC, can't change anything.
+///
+/// Unknown methods are assumed to change state.
+pub fn is_read_method(method: &str) -> bool {
+    if STATEFUL_METHODS.contains(&method) {
+        return false;
+    }
+
+    READ_METHODS.contains(&method)
+        || READ_PREFIXES
+            .iter()
+            .any(|prefix| method.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_read_method() {
+        for method in [
+            "eth_getLogs",
+            "eth_call",
+            "debug_traceTransaction",
+            "trace_block",
+        ] {
+            assert!(is_read_method(method), "{method}");
+        }
+        for method in [
+            "eth_sendRawTransaction",
+            "eth_sendBundle",
+            "mev_sendBundle",
+            "flashbots_getBundleStats",
+            "wallet_sendCalls",
+            "eth_getFilterChanges",
+            "eth_subscribe",
+            "engine_newPayloadV3",
+        ] {
+            assert!(!is_read_method(method), "{method}");
+        }
+    }
+}
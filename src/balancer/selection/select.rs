@@ -1,16 +1,113 @@
-use crate::Rpc;
-use std::time::SystemTime;
+use crate::{
+    config::types::MethodFilterSettings,
+    health::quarantine::MethodFamily,
+    rpc::engine::is_engine_method,
+    Rpc,
+};
+use rust_tracing::deps::metrics;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    sync::atomic::{
+        AtomicU8,
+        Ordering,
+    },
+    time::SystemTime,
+};
+
+/// RPCs a request can be routed to.
+///
+/// `engine_*` methods go to the engine group and everything else to the other RPCs, see `engine`.
+/// Requests limited to route `groups` only go to RPCs in at least one of them, and RPCs in groups
+/// whose method filter blocks the method are skipped, see `method_filter`. RPCs that reported not
+/// supporting the namespace of the method are skipped too, see `capabilities`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Route<'a> {
+    method: Option<&'a str>,
+    engine: bool,
+    groups: Option<&'a [String]>,
+    filter: Option<&'a MethodFilterSettings>,
+}
 
-// Generic entry point fn to select the next rpc and return its position
-pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    // If len is 1, return the only element
-    if list.len() == 1 {
-        return (list[0].clone(), Some(0));
-    } else if list.is_empty() {
+impl<'a> Route<'a> {
+    pub fn new(method: Option<&'a str>, groups: Option<&'a [String]>) -> Self {
+        Self {
+            method,
+            engine: is_engine_method(method),
+            groups: groups.filter(|groups| !groups.is_empty()),
+            filter: None,
+        }
+    }
+
+    /// Skip RPCs whose groups block the method in `filter`.
+    pub fn with_filter(mut self, filter: &'a MethodFilterSettings) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Returns true if requests on this route can go to `rpc`.
+    fn admits(&self, rpc: &Rpc) -> bool {
+        rpc.is_engine() == self.engine
+            && !rpc.quota_stopped()
+            && (self.engine
+                || self
+                    .method
+                    .is_none_or(|method| rpc.capabilities.supports(method)))
+            && self
+                .groups
+                .is_none_or(|groups| groups.iter().any(|group| rpc.groups.contains(group)))
+            && self
+                .filter
+                .is_none_or(|filter| filter.serves(rpc, self.method))
+    }
+}
+
+// Generic entry point fn to select the next rpc for `method` and return its position
+pub fn pick(list: &mut [Rpc], method: Option<&str>) -> (Rpc, Option<usize>) {
+    pick_routed(list, Route::new(method, None))
+}
+
+// Same as `pick`, but only picks RPCs on `route`
+pub fn pick_routed(list: &mut [Rpc], route: Route) -> (Rpc, Option<usize>) {
+    // Return None if the list is empty or if every node of the route is syncing or out of rotation
+    if !list
+        .iter()
+        .any(|rpc| route.admits(rpc) && rpc.status.is_selectable())
+    {
         return (Rpc::default(), None);
     }
 
-    algo(list)
+    // If len is 1, return the only element
+    let (rpc, index) = if list.len() == 1 {
+        (list[0].clone(), Some(0))
+    } else if route.engine {
+        engine_primary(list, route)
+    } else {
+        algo(
+            list,
+            route.method.and_then(MethodFamily::from_method),
+            route,
+        )
+    };
+
+    if index.is_some() {
+        metrics::counter!("rpc_selected_total", "rpc_name" => rpc.name.clone()).increment(1);
+    }
+    (rpc, index)
+}
+
+// The Engine API isn't balanced, every call goes to the first healthy RPC of the engine
+// group in config order so a single execution client follows the chain, see `engine`
+fn engine_primary(list: &[Rpc], route: Route) -> (Rpc, Option<usize>) {
+    match list
+        .iter()
+        .position(|rpc| route.admits(rpc) && rpc.status.is_selectable())
+    {
+        Some(index) => (list[index].clone(), Some(index)),
+        None => (Rpc::default(), None),
+    }
 }
 
 // Sorting algo
@@ -24,20 +121,124 @@ pub fn argsort(data: &[Rpc]) -> Vec<usize> {
     indices
 }
 
-// Selection algorithms
+// Returns true if there's a primary (non fallback) node on `route` that can serve requests
+fn route_primaries_available(data: &[Rpc], route: Route) -> bool {
+    data.iter()
+        .any(|rpc| route.admits(rpc) && !rpc.is_fallback() && rpc.status.is_selectable())
+}
+
+// Returns true if there's a primary (non fallback) node that can serve requests
+pub fn primaries_available(data: &[Rpc]) -> bool {
+    route_primaries_available(data, Route::default())
+}
+
+// Same as `argsort`, but skips nodes that are not eligible for selection
 //
-// Selected via features. selection-weighed-round-robin is a default feature.
-// In order to have custom algos, you must add and enable the feature,
-// as well as modify the cfg of the default algo to accomodate your new feature.
+// Only nodes on `route` are eligible, see `Route`.
+// Fallback nodes, emergency ones or ones out of quota, are only eligible when no primary node on the route is.
+// Nodes quarantined from `family` are skipped too, unless that would leave nothing to pick.
+pub fn argsort_eligible(data: &[Rpc], family: Option<MethodFamily>, route: Route) -> Vec<usize> {
+    let mut indices = argsort(data);
+    let primaries_available = route_primaries_available(data, route);
+    indices.retain(|&index| {
+        route.admits(&data[index])
+            && data[index].status.is_selectable()
+            && (!primaries_available || !data[index].is_fallback())
+    });
+
+    if let Some(family) = family {
+        let clean: Vec<usize> = indices
+            .iter()
+            .copied()
+            .filter(|&index| !data[index].status.quarantined.contains(&family))
+            .collect();
+        if !clean.is_empty() {
+            indices = clean;
+        }
+    }
+
+    indices
+}
+
+/// Algorithm RPCs are picked with.
+///
+/// The `selection-*` features pick the one used on startup, and it can be
+/// switched at runtime through `blutgang_set_selection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionAlgorithm {
+    /// Fastest RPC that's under both its `max_consecutive` and `max_per_second`
+    WeightedRoundRobin,
+    /// Any eligible RPC, at random
+    Random,
+    /// Fastest RPC, or the second fastest once it hits `max_consecutive`.
+    /// Doesn't account for `max_per_second`.
+    OldWeightedRoundRobin,
+}
+
+impl SelectionAlgorithm {
+    pub const ALL: [SelectionAlgorithm; 3] = [
+        SelectionAlgorithm::WeightedRoundRobin,
+        SelectionAlgorithm::Random,
+        SelectionAlgorithm::OldWeightedRoundRobin,
+    ];
+
+    /// Algorithm used on startup.
+    pub const DEFAULT: SelectionAlgorithm = if cfg!(feature = "selection-random") {
+        SelectionAlgorithm::Random
+    } else if cfg!(feature = "old-weighted-round-robin") {
+        SelectionAlgorithm::OldWeightedRoundRobin
+    } else {
+        SelectionAlgorithm::WeightedRoundRobin
+    };
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SelectionAlgorithm::WeightedRoundRobin => "weighted_round_robin",
+            SelectionAlgorithm::Random => "random",
+            SelectionAlgorithm::OldWeightedRoundRobin => "old_weighted_round_robin",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| *algorithm as u8 == value)
+            .unwrap_or(Self::DEFAULT)
+    }
+}
+
+// Shared by everything picking RPCs, so switching applies to the next pick
+static ALGORITHM: AtomicU8 = AtomicU8::new(SelectionAlgorithm::DEFAULT as u8);
+
+/// Returns the algorithm RPCs are currently picked with.
+pub fn algorithm() -> SelectionAlgorithm {
+    SelectionAlgorithm::from_u8(ALGORITHM.load(Ordering::Relaxed))
+}
+
+/// Pick RPCs with `algorithm` from now on.
+pub fn set_algorithm(algorithm: SelectionAlgorithm) {
+    ALGORITHM.store(algorithm as u8, Ordering::Relaxed);
+}
+
+// Selection algorithms
 //
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    not(feature = "selection-random"),
-    not(feature = "old-weighted-round-robin"),
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+// In order to have custom algos, add them to `SelectionAlgorithm` and dispatch to them here.
+fn algo(list: &mut [Rpc], family: Option<MethodFamily>, route: Route) -> (Rpc, Option<usize>) {
+    match algorithm() {
+        SelectionAlgorithm::WeightedRoundRobin => weighted_round_robin(list, family, route),
+        SelectionAlgorithm::Random => random(list, family, route),
+        SelectionAlgorithm::OldWeightedRoundRobin => old_weighted_round_robin(list, family, route),
+    }
+}
+
+fn weighted_round_robin(
+    list: &mut [Rpc],
+    family: Option<MethodFamily>,
+    route: Route,
+) -> (Rpc, Option<usize>) {
     // Sort by latency
-    let indices = argsort(list);
+    let indices = argsort_eligible(list, family, route);
 
     let time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -68,28 +269,26 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     (list[choice].clone(), Some(choice))
 }
 
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    feature = "selection-random"
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn random(list: &mut [Rpc], family: Option<MethodFamily>, route: Route) -> (Rpc, Option<usize>) {
     use rand::Rng;
 
+    let indices = argsort_eligible(list, family, route);
+
     let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..list.len());
+    let index = indices[rng.gen_range(0..indices.len())];
     (list[index].clone(), Some(index))
 }
 
-#[cfg(all(
-    feature = "selection-weighed-round-robin",
-    feature = "old-weighted-round-robin",
-))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn old_weighted_round_robin(
+    list: &mut [Rpc],
+    family: Option<MethodFamily>,
+    route: Route,
+) -> (Rpc, Option<usize>) {
     // Sort by latency
-    let indices = argsort(list);
+    let indices = argsort_eligible(list, family, route);
 
     // Picks the second fastest one if the fastest one has maxed out
-    if list[indices[0]].max_consecutive <= list[indices[0]].consecutive {
+    if indices.len() > 1 && list[indices[0]].max_consecutive <= list[indices[0]].consecutive {
         list[indices[1]].consecutive = 1;
         list[indices[0]].consecutive = 0;
         return (list[indices[1]].clone(), Some(indices[1]));
@@ -144,25 +343,344 @@ mod tests {
 
         let mut rpc_list = vec![rpc1, rpc2, rpc3];
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&mut rpc_list, None);
         println!("rpc: {:?}", rpc);
         assert_eq!(rpc.status.latency, 3.0);
         assert_eq!(index, Some(0));
 
         rpc_list[0].status.latency = 10000.0;
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&mut rpc_list, None);
         println!("rpc index: {:?}", index);
         assert_eq!(rpc.status.latency, 5.0);
         assert_eq!(index, Some(2));
 
         rpc_list[2].status.latency = 100000.0;
 
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&mut rpc_list, None);
         assert_eq!(rpc.status.latency, 7.0);
         assert_eq!(index, Some(1));
     }
 
+    // Syncing nodes should never get picked
+    #[test]
+    fn test_pick_skips_syncing() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.status.is_syncing = true;
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        rpc3.status.latency = 5.0;
+        rpc3.max_consecutive = 10;
+        rpc3.status.is_syncing = true;
+
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+
+        let (rpc, index) = pick(&mut rpc_list, None);
+        assert_eq!(rpc.status.latency, 7.0);
+        assert_eq!(index, Some(1));
+
+        // If everything is syncing we have nothing to pick
+        rpc_list[1].status.is_syncing = true;
+        let (_, index) = pick(&mut rpc_list, None);
+        assert_eq!(index, None);
+
+        // Same goes for a single syncing node
+        let (_, index) = pick(&mut rpc_list[..1], None);
+        assert_eq!(index, None);
+    }
+
+    // Every algorithm can be switched to, and picks from the eligible nodes
+    #[test]
+    fn test_algorithms() {
+        for algorithm in SelectionAlgorithm::ALL {
+            assert_eq!(SelectionAlgorithm::from_u8(algorithm as u8), algorithm);
+        }
+
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+        rpc1.status.latency = 1.0;
+        rpc1.max_consecutive = 1;
+        rpc2.status.latency = 2.0;
+        rpc2.max_consecutive = 1;
+        rpc3.status.is_syncing = true;
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+
+        // Second fastest once the fastest hits max_consecutive
+        let (_, index) = old_weighted_round_robin(&mut rpc_list, None, Route::default());
+        assert_eq!(index, Some(0));
+        let (_, index) = old_weighted_round_robin(&mut rpc_list, None, Route::default());
+        assert_eq!(index, Some(1));
+
+        for _ in 0..10 {
+            let (_, index) = random(&mut rpc_list, None, Route::default());
+            assert!(matches!(index, Some(0) | Some(1)));
+        }
+    }
+
+    // Drained and banned nodes are out of rotation, bans expire on their own
+    #[test]
+    fn test_pick_skips_out_of_rotation() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.status.drained = true;
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        rpc3.status.latency = 5.0;
+        rpc3.max_consecutive = 10;
+        rpc3.status.banned_until_ms = u64::MAX;
+
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+
+        let (rpc, index) = pick(&mut rpc_list, None);
+        assert_eq!(rpc.status.latency, 7.0);
+        assert_eq!(index, Some(1));
+
+        // Expired ban
+        rpc_list[2].status.banned_until_ms = 1;
+        rpc_list[1].status.drained = true;
+        let (rpc, index) = pick(&mut rpc_list, None);
+        assert_eq!(rpc.status.latency, 5.0);
+        assert_eq!(index, Some(2));
+    }
+
+    // Quarantined nodes only get skipped for their method family
+    #[test]
+    fn test_pick_skips_quarantined() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.status.quarantined = vec![MethodFamily::Logs];
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        let rpc_list = vec![rpc1, rpc2];
+
+        assert_eq!(
+            argsort_eligible(&rpc_list, Some(MethodFamily::Logs), Route::default()),
+            [1]
+        );
+        assert_eq!(
+            argsort_eligible(&rpc_list, Some(MethodFamily::State), Route::default()),
+            [0, 1]
+        );
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [0, 1]);
+
+        // Better to serve from a quarantined node than from nothing
+        assert_eq!(
+            argsort_eligible(&rpc_list[..1], Some(MethodFamily::Logs), Route::default()),
+            [0]
+        );
+    }
+
+    // Emergency nodes only get picked once no primary node is left
+    #[test]
+    fn test_pick_emergency() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.emergency = true;
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        rpc3.status.latency = 5.0;
+        rpc3.max_consecutive = 10;
+        rpc3.emergency = true;
+
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+
+        assert!(primaries_available(&rpc_list));
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [1]);
+        let (_, index) = pick(&mut rpc_list, None);
+        assert_eq!(index, Some(1));
+
+        rpc_list[1].status.is_syncing = true;
+        assert!(!primaries_available(&rpc_list));
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [0, 2]);
+        let (rpc, _) = pick(&mut rpc_list, None);
+        assert!(rpc.emergency);
+    }
+
+    // RPCs out of quota are deprioritized like emergency nodes, or skipped
+    #[test]
+    fn test_pick_quota() {
+        use crate::rpc::quota::{
+            ProviderQuota,
+            QuotaExhausted,
+        };
+
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.quota = ProviderQuota {
+            monthly_quota: 10,
+            ..Default::default()
+        };
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        let mut rpc_list = vec![rpc1, rpc2];
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [0, 1]);
+
+        rpc_list[0].charge_quota(10);
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [1]);
+        rpc_list[1].status.is_syncing = true;
+        assert_eq!(argsort_eligible(&rpc_list, None, Route::default()), [0]);
+
+        rpc_list[0].quota.quota_exhausted = QuotaExhausted::Stop;
+        assert!(argsort_eligible(&rpc_list, None, Route::default()).is_empty());
+        let (_, index) = pick(&mut rpc_list, None);
+        assert_eq!(index, None);
+    }
+
+    // Engine API methods only go to the engine group, and nothing else does
+    #[test]
+    fn test_pick_engine_group() {
+        use crate::rpc::engine::EngineSecret;
+
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.engine = Some(EngineSecret::from_hex(&"ab".repeat(32)).unwrap());
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        let mut rpc_list = vec![rpc1, rpc2];
+
+        let (_, index) = pick(&mut rpc_list, Some("eth_blockNumber"));
+        assert_eq!(index, Some(1));
+        let (rpc, index) = pick(&mut rpc_list, Some("engine_forkchoiceUpdatedV3"));
+        assert_eq!(index, Some(0));
+        assert!(rpc.is_engine());
+
+        // No falling back to the other group
+        let (_, index) = pick(&mut rpc_list[1..], Some("engine_newPayloadV3"));
+        assert_eq!(index, None);
+        let (_, index) = pick(&mut rpc_list[..1], Some("eth_call"));
+        assert_eq!(index, None);
+    }
+
+    // Engine API methods stick to the primary, whatever its latency
+    #[test]
+    fn test_pick_engine_primary() {
+        use crate::rpc::engine::EngineSecret;
+
+        let secret = EngineSecret::from_hex(&"ab".repeat(32)).unwrap();
+        let mut rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        for (rpc, latency) in rpc_list.iter_mut().zip([9.0, 1.0, 5.0]) {
+            rpc.status.latency = latency;
+            rpc.max_consecutive = 1;
+            rpc.engine = Some(secret.clone());
+        }
+
+        for _ in 0..5 {
+            let (_, index) = pick(&mut rpc_list, Some("engine_newPayloadV3"));
+            assert_eq!(index, Some(0));
+        }
+
+        // The next one takes over while the primary is down
+        rpc_list[0].status.is_syncing = true;
+        let (_, index) = pick(&mut rpc_list, Some("engine_forkchoiceUpdatedV3"));
+        assert_eq!(index, Some(1));
+    }
+
+    // Requests limited to route groups only go to RPCs in one of them
+    #[test]
+    fn test_pick_route_groups() {
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+        let mut rpc3 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+        rpc2.groups = vec!["archive".to_string()];
+
+        rpc3.status.latency = 5.0;
+        rpc3.max_consecutive = 10;
+        rpc3.groups = vec!["archive".to_string(), "trace".to_string()];
+
+        let mut rpc_list = vec![rpc1, rpc2, rpc3];
+
+        let archive = ["archive".to_string()];
+        let (_, index) = pick_routed(&mut rpc_list, Route::new(None, Some(&archive)));
+        assert_eq!(index, Some(2));
+        assert_eq!(
+            argsort_eligible(&rpc_list, None, Route::new(None, Some(&archive))),
+            [2, 1]
+        );
+
+        // No groups means any RPC
+        let (_, index) = pick_routed(&mut rpc_list, Route::new(None, Some(&[])));
+        assert_eq!(index, Some(0));
+
+        let debug = ["debug".to_string()];
+        let (_, index) = pick_routed(&mut rpc_list, Route::new(None, Some(&debug)));
+        assert_eq!(index, None);
+
+        // Groups blocking the method get skipped
+        let filter: MethodFilterSettings =
+            toml::from_str("[groups.trace]\ndeny = [\"eth_getLogs\"]").unwrap();
+        let route = Route::new(Some("eth_getLogs"), Some(&archive)).with_filter(&filter);
+        let (_, index) = pick_routed(&mut rpc_list, route);
+        assert_eq!(index, Some(1));
+    }
+
+    // RPCs that don't expose a namespace don't get its methods
+    #[test]
+    fn test_pick_capabilities() {
+        use crate::rpc::types::Capabilities;
+
+        let mut rpc1 = Rpc::default();
+        let mut rpc2 = Rpc::default();
+
+        rpc1.status.latency = 3.0;
+        rpc1.max_consecutive = 10;
+        rpc1.capabilities = Capabilities {
+            modules: Some(vec!["eth".to_string(), "net".to_string()]),
+            client_version: None,
+        };
+
+        // Unknown modules, so anything goes
+        rpc2.status.latency = 7.0;
+        rpc2.max_consecutive = 10;
+
+        let mut rpc_list = vec![rpc1, rpc2];
+
+        let (_, index) = pick(&mut rpc_list, Some("eth_call"));
+        assert_eq!(index, Some(0));
+        let (_, index) = pick(&mut rpc_list, Some("trace_block"));
+        assert_eq!(index, Some(1));
+        let (_, index) = pick(&mut rpc_list[..1], Some("debug_traceTransaction"));
+        assert_eq!(index, None);
+    }
+
     // Test max_delay when picking rpcs
     #[test]
     fn test_pick_max_delay() {
@@ -189,13 +707,13 @@ mod tests {
         let mut rpc_list = vec![rpc1, rpc2, rpc3];
 
         // Pick rpc3 becauese rpc1 does not meet last used requirements
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&mut rpc_list, None);
         println!("rpc: {:?}", rpc);
         assert_eq!(rpc.status.latency, 5.0);
         assert_eq!(index, Some(2));
 
         // pick rpc2 because rpc3 was just used
-        let (rpc, index) = pick(&mut rpc_list);
+        let (rpc, index) = pick(&mut rpc_list, None);
         println!("rpc index: {:?}", index);
         assert_eq!(rpc.status.latency, 7.0);
         assert_eq!(index, Some(1));
@@ -0,0 +1,296 @@
+//! Mirroring traffic to canary RPCs.
+//!
+//! A new node or provider can be tried under real load before it joins
+//! rotation, by giving its `[[rpc]]` entry a `shadow_percent`. Such RPCs never
+//! serve clients. Instead, that share of read requests is also sent to them
+//! once the client has its response, and what they respond is discarded.
+//!
+//! How long they took ends up in their RPC stats, and whether their result
+//! agreed with the one the client got is counted per RPC. Both are reported by
+//! the `blutgang_shadow_stats` admin method, and as `shadow_requests_total`.
+//!
+//! Only requests that read chain data are mirrored, so anything that changes
+//! state, depends on state kept by the RPC that served it, or that we don't
+//! know, like transactions, subscriptions, filters and bundles, never is.
+//!
+//! Each canary has at most `MAX_IN_FLIGHT` mirrored requests waiting on it.
+//! Requests sampled while it's at that are dropped, so a slow canary can't
+//! pile up tasks.
+
+use crate::{
+    balancer::selection::cache_rules::is_read_method,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use rand::Rng;
+use rust_tracing::deps::metrics;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::{
+    sync::{
+        OwnedSemaphorePermit,
+        Semaphore,
+    },
+    time::timeout,
+};
+
+/// Max number of mirrored requests waiting on a canary at once.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// How the response of a canary compared to the one the client got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Agreed,
+    Disagreed,
+    /// The canary errored or timed out.
+    Errored,
+    /// The client got an error, so there was nothing to compare against.
+    Skipped,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Agreed => "agreed",
+            Self::Disagreed => "disagreed",
+            Self::Errored => "errored",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct AgreementCounts {
+    agreed: AtomicU64,
+    disagreed: AtomicU64,
+    errored: AtomicU64,
+    skipped: AtomicU64,
+}
+
+/// Number of mirrored requests with each outcome.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Agreement {
+    pub agreed: u64,
+    pub disagreed: u64,
+    pub errored: u64,
+    pub skipped: u64,
+}
+
+/// RPC requests are mirrored to, instead of being served by.
+#[derive(Debug, Clone)]
+pub struct ShadowRpc {
+    pub rpc: Rpc,
+    /// Share of read requests mirrored to it, from 0 to 100.
+    pub percent: f64,
+    counts: Arc<AgreementCounts>,
+    in_flight: Arc<Semaphore>,
+}
+
+/// A request sampled to be mirrored to a canary, counted against its
+/// `MAX_IN_FLIGHT` until dropped.
+#[derive(Debug)]
+pub struct Mirror {
+    shadow: ShadowRpc,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl ShadowRpc {
+    pub fn new(rpc: Rpc, percent: f64) -> Self {
+        Self {
+            rpc,
+            percent: percent.clamp(0.0, 100.0),
+            counts: Arc::default(),
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Returns how mirrored requests compared so far.
+    pub fn agreement(&self) -> Agreement {
+        Agreement {
+            agreed: self.counts.agreed.load(Ordering::Relaxed),
+            disagreed: self.counts.disagreed.load(Ordering::Relaxed),
+            errored: self.counts.errored.load(Ordering::Relaxed),
+            skipped: self.counts.skipped.load(Ordering::Relaxed),
+        }
+    }
+
+    fn record(&self, outcome: Outcome) {
+        let count = match outcome {
+            Outcome::Agreed => &self.counts.agreed,
+            Outcome::Disagreed => &self.counts.disagreed,
+            Outcome::Errored => &self.counts.errored,
+            Outcome::Skipped => &self.counts.skipped,
+        };
+        count.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!(
+            "shadow_requests_total",
+            "rpc_name" => self.rpc.name.clone(),
+            "outcome" => outcome.as_str()
+        )
+        .increment(1);
+    }
+}
+
+/// Returns true if requests for `method` can be mirrored.
+pub fn mirrorable(method: Option<&str>) -> bool {
+    method.is_some_and(is_read_method)
+}
+
+/// Returns the canaries in `shadow_list` `tx` should be mirrored to, skipping
+/// ones that already have `MAX_IN_FLIGHT` requests waiting on them.
+pub fn sample(shadow_list: &[ShadowRpc], tx: &Value) -> Vec<Mirror> {
+    if shadow_list.is_empty() || !mirrorable(tx["method"].as_str()) {
+        return Vec::new();
+    }
+
+    let mut rng = rand::thread_rng();
+    shadow_list
+        .iter()
+        .filter(|shadow| rng.gen_bool(shadow.percent / 100.0))
+        .filter_map(|shadow| {
+            let Ok(permit) = shadow.in_flight.clone().try_acquire_owned() else {
+                metrics::counter!("shadow_requests_dropped_total", "rpc_name" => shadow.rpc.name.clone())
+                    .increment(1);
+                return None;
+            };
+            Some(Mirror {
+                shadow: shadow.clone(),
+                _permit: permit,
+            })
+        })
+        .collect()
+}
+
+/// Compare the response of a canary, `None` if it failed, with `primary`, the
+/// response the client got.
+fn compare(primary: &Value, shadow: Option<&Value>) -> Outcome {
+    if primary.get("error").is_some() || primary.get("result").is_none() {
+        return Outcome::Skipped;
+    }
+
+    match shadow {
+        Some(shadow) if shadow.get("error").is_none() && shadow.get("result").is_some() => {
+            if shadow["result"] == primary["result"] {
+                Outcome::Agreed
+            } else {
+                Outcome::Disagreed
+            }
+        }
+        _ => Outcome::Errored,
+    }
+}
+
+/// Send `tx` to the canary of `mirror`, and record how its response compares
+/// to `primary`, the response the client got.
+pub async fn mirror(mirror: Mirror, tx: Value, primary: String, ttl: u128) {
+    let shadow = &mirror.shadow;
+    let method = tx["method"].as_str().unwrap_or_default().to_string();
+    let start = Instant::now();
+    let response = timeout(
+        Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+        shadow.rpc.send_request(tx),
+    )
+    .await
+    .ok()
+    .and_then(Result::ok)
+    .and_then(|response| serde_json::from_str::<Value>(&response).ok());
+    metrics::histogram!("shadow_response_time_secs", "rpc_name" => shadow.rpc.name.clone())
+        .record(start.elapsed().as_secs_f64());
+
+    let Ok(primary) = serde_json::from_str::<Value>(&primary) else {
+        return;
+    };
+    let outcome = compare(&primary, response.as_ref());
+    if outcome == Outcome::Disagreed {
+        tracing::debug!(rpc = %shadow.rpc.name, method, "Canary disagreed with served response");
+    }
+    shadow.record(outcome);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_mirrorable() {
+        assert!(mirrorable(Some("eth_call")));
+        assert!(mirrorable(Some("eth_getLogs")));
+        assert!(!mirrorable(Some("eth_sendRawTransaction")));
+        assert!(!mirrorable(Some("eth_signTypedData_v4")));
+        assert!(!mirrorable(Some("eth_subscribe")));
+        assert!(!mirrorable(Some("eth_getFilterChanges")));
+        assert!(!mirrorable(Some("engine_forkchoiceUpdatedV3")));
+        // Methods we don't know could change state
+        assert!(!mirrorable(Some("mev_sendBundle")));
+        assert!(!mirrorable(Some("wallet_sendCalls")));
+        assert!(!mirrorable(None));
+    }
+
+    #[test]
+    fn test_sample() {
+        let always = ShadowRpc::new(Rpc::default(), 100.0);
+        let never = ShadowRpc::new(Rpc::default(), 0.0);
+        let shadow_list = vec![always, never];
+
+        let call = json!({"jsonrpc": "2.0", "id": 0, "method": "eth_call", "params": []});
+        assert_eq!(sample(&shadow_list, &call).len(), 1);
+
+        let send = json!({"jsonrpc": "2.0", "id": 0, "method": "eth_sendRawTransaction", "params": ["0x00"]});
+        assert!(sample(&shadow_list, &send).is_empty());
+
+        // Requests past the in flight cap are dropped until others finish
+        let mirrors: Vec<_> = (0..MAX_IN_FLIGHT)
+            .flat_map(|_| sample(&shadow_list, &call))
+            .collect();
+        assert_eq!(mirrors.len(), MAX_IN_FLIGHT);
+        assert_eq!(sample(&shadow_list, &call).len(), 0);
+        drop(mirrors);
+        assert_eq!(sample(&shadow_list, &call).len(), 1);
+    }
+
+    #[test]
+    fn test_compare() {
+        let primary = json!({"jsonrpc": "2.0", "id": 0, "result": "0x1"});
+        let same = json!({"jsonrpc": "2.0", "id": 1, "result": "0x1"});
+        let different = json!({"jsonrpc": "2.0", "id": 0, "result": "0x2"});
+        let error = json!({"jsonrpc": "2.0", "id": 0, "error": {"code": -32000, "message": "missing trie node"}});
+
+        assert_eq!(compare(&primary, Some(&same)), Outcome::Agreed);
+        assert_eq!(compare(&primary, Some(&different)), Outcome::Disagreed);
+        assert_eq!(compare(&primary, Some(&error)), Outcome::Errored);
+        assert_eq!(compare(&primary, None), Outcome::Errored);
+        assert_eq!(compare(&error, Some(&same)), Outcome::Skipped);
+    }
+
+    #[test]
+    fn test_record() {
+        let shadow = ShadowRpc::new(Rpc::default(), 10.0);
+        // Copies share their counts
+        shadow.clone().record(Outcome::Agreed);
+        shadow.record(Outcome::Agreed);
+        shadow.record(Outcome::Errored);
+        assert_eq!(
+            shadow.agreement(),
+            Agreement {
+                agreed: 2,
+                errored: 1,
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Request coalescing.
+//!
+//! When a new block comes in, lots of clients tend to ask for the same thing
+//! at once, before any of them had the chance to get it cached. Instead of
+//! sending every one of those requests upstream, the first one goes out and
+//! the rest wait for its response.
+//!
+//! The same goes for entries that just expired. Flights are keyed by cache key
+//! and shared by HTTP and WS requests and cache warming, so an expired entry
+//! is only fetched again once no matter where the requests come from.
+
+use crate::rpc::engine::is_engine_method;
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+use tokio::sync::broadcast;
+
+/// Returns true if identical concurrent `method` calls can share a response.
+///
+/// Filter creation is excluded, since clients sharing a filter would eat
+/// each other's changes, and so is the Engine API, which drives the node.
+fn coalescable(method: Option<&str>) -> bool {
+    !method.is_some_and(|method| method.starts_with("eth_new")) && !is_engine_method(method)
+}
+
+/// Requests currently being fetched from an RPC, by cache key.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    calls: Mutex<HashMap<Vec<u8>, broadcast::Sender<String>>>,
+}
+
+/// Role of a request in fetching the response for its key.
+pub enum Flight<'a> {
+    /// Nobody is fetching this key, the request has to fetch it and share the response.
+    Leader(Leader<'a>),
+    /// Another request is already fetching this key.
+    Follower(broadcast::Receiver<String>),
+    /// The request can't share its response.
+    Alone,
+}
+
+impl InFlight {
+    /// Join the flight for `key`, or start one if there's none.
+    pub fn join(&self, key: &[u8], method: Option<&str>) -> Flight<'_> {
+        if !coalescable(method) {
+            return Flight::Alone;
+        }
+
+        let mut calls = self.calls.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(tx) = calls.get(key) {
+            metrics::counter!("requests_coalesced_total").increment(1);
+            return Flight::Follower(tx.subscribe());
+        }
+
+        let (tx, _) = broadcast::channel(1);
+        calls.insert(key.to_vec(), tx);
+        Flight::Leader(Leader {
+            in_flight: self,
+            key: key.to_vec(),
+        })
+    }
+}
+
+/// Request fetching the response for `key` on behalf of everyone waiting on it.
+///
+/// If the leader is dropped without completing, say because every RPC timed
+/// out, followers are let go and have to fetch the response themselves.
+pub struct Leader<'a> {
+    in_flight: &'a InFlight,
+    key: Vec<u8>,
+}
+
+impl Leader<'_> {
+    /// Share `response` with every follower.
+    pub fn complete(self, response: &str) {
+        let tx = self
+            .in_flight
+            .calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+        if let Some(tx) = tx {
+            let _ = tx.send(response.to_string());
+        }
+    }
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        self.in_flight
+            .calls
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.key);
+    }
+}
+
+/// Replace the id of a shared `response` with the one of the request receiving it.
+pub fn with_id(response: &str, id: impl Into<Value>) -> String {
+    match serde_json::from_str::<Value>(response) {
+        Ok(mut response) if response.is_object() => {
+            response["id"] = id.into();
+            response.to_string()
+        }
+        _ => response.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_followers_share_response() {
+        let in_flight = InFlight::default();
+
+        let Flight::Leader(leader) = in_flight.join(b"key", Some("eth_getBlockByNumber")) else {
+            panic!("first request should lead");
+        };
+        let Flight::Follower(mut follower) = in_flight.join(b"key", Some("eth_getBlockByNumber"))
+        else {
+            panic!("second request should follow");
+        };
+        // Other keys aren't affected
+        assert!(matches!(in_flight.join(b"other", None), Flight::Leader(_)));
+
+        leader.complete(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#);
+        let response = follower.recv().await.unwrap();
+        assert_eq!(
+            serde_json::from_str::<Value>(&with_id(&response, 7)).unwrap(),
+            serde_json::json!({"jsonrpc": "2.0", "id": 7, "result": "0x1"})
+        );
+        // WS ids can be anything
+        assert_eq!(
+            serde_json::from_str::<Value>(&with_id(&response, "abc")).unwrap()["id"],
+            "abc"
+        );
+
+        // Completed flights are gone
+        assert!(matches!(in_flight.join(b"key", None), Flight::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn test_dropped_leader_releases_followers() {
+        let in_flight = InFlight::default();
+
+        let leader = in_flight.join(b"key", None);
+        let Flight::Follower(mut follower) = in_flight.join(b"key", None) else {
+            panic!("second request should follow");
+        };
+
+        drop(leader);
+        assert!(follower.recv().await.is_err());
+        assert!(in_flight.calls.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_filters_not_coalesced() {
+        let in_flight = InFlight::default();
+        assert!(matches!(
+            in_flight.join(b"key", Some("eth_newFilter")),
+            Flight::Alone
+        ));
+        assert!(matches!(
+            in_flight.join(b"key", Some("engine_getPayloadV3")),
+            Flight::Alone
+        ));
+        assert!(in_flight.calls.lock().unwrap().is_empty());
+    }
+}
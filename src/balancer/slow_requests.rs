@@ -0,0 +1,133 @@
+//! Logging slow requests.
+//!
+//! Latency histograms tell us the p99 got worse, not which calls made it so.
+//! With `[blutgang.slow_requests]` enabled, every request that took longer
+//! than `threshold_ms` from start to finish, or that the RPCs took longer than
+//! `upstream_threshold_ms` to answer, is logged as a warning with:
+//!
+//! - its method, and a hash of its params to tell identical calls apart,
+//! - the RPC that served it, if any,
+//! - whether it was served from cache,
+//! - how long it took overall, and upstream.
+//!
+//! Only `sample_percent` of slow requests are logged, so a slow RPC can't
+//! flood the logs. It's checked to be from 0 to 100 when the config is loaded.
+//!
+//! Chunked `eth_getLogs` requests are logged per chunk, and once more end to end.
+
+use crate::{
+    balancer::{
+        canonical::canonical_key,
+        checksum::checksum,
+    },
+    config::types::SlowRequestSettings,
+};
+
+use std::time::Duration;
+
+use rand::Rng;
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+
+/// A request that was served, as far as the slow request log cares.
+#[derive(Debug, Clone)]
+pub struct SlowRequest<'a> {
+    pub method: &'a str,
+    /// Hash of the params, see `params_hash`.
+    pub params_hash: &'a str,
+    /// Name of the RPC that served it, `None` if it didn't reach one.
+    pub rpc: Option<&'a str>,
+    /// `hit`, `shared`, `miss` or `bypass`, or `partial` for `eth_getLogs`
+    /// served partly from the log cache.
+    pub cache: &'static str,
+    /// How long it took from start to finish.
+    pub latency: Duration,
+    /// How long it took to get the response from the RPCs, retries included.
+    pub upstream_latency: Option<Duration>,
+}
+
+/// Hash of the params of `tx`, independent of how they're encoded.
+pub fn params_hash(tx: &Value) -> String {
+    let mut hash = checksum(canonical_key(&tx["params"]).as_bytes());
+    // Enough to tell calls apart in logs
+    hash.truncate(16);
+    hash
+}
+
+impl SlowRequestSettings {
+    /// Returns true if a request that took `latency`, `upstream_latency` of
+    /// which waiting on the RPCs, is slow.
+    pub fn is_slow(&self, latency: Duration, upstream_latency: Option<Duration>) -> bool {
+        let upstream_slow = upstream_latency.is_some_and(|upstream_latency| {
+            self.upstream_threshold_ms != 0
+                && upstream_latency > Duration::from_millis(self.upstream_threshold_ms)
+        });
+
+        upstream_slow || latency > Duration::from_millis(self.threshold_ms)
+    }
+}
+
+/// Log `request` if it's slow, and sampled.
+pub fn log_slow_request(settings: &SlowRequestSettings, request: SlowRequest) {
+    if !settings.enabled || !settings.is_slow(request.latency, request.upstream_latency) {
+        return;
+    }
+
+    metrics::counter!("slow_requests_total", "method" => request.method.to_string()).increment(1);
+    if !rand::thread_rng().gen_bool(settings.sample_percent / 100.0) {
+        return;
+    }
+
+    tracing::warn!(
+        method = request.method,
+        params_hash = request.params_hash,
+        rpc = request.rpc,
+        cache = request.cache,
+        latency_ms = request.latency.as_millis() as u64,
+        upstream_latency_ms = request
+            .upstream_latency
+            .map(|upstream_latency| upstream_latency.as_millis() as u64),
+        "Slow request"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_is_slow() {
+        let settings = SlowRequestSettings {
+            enabled: true,
+            threshold_ms: 1000,
+            upstream_threshold_ms: 500,
+            sample_percent: 100.0,
+        };
+        let ms = Duration::from_millis;
+
+        assert!(!settings.is_slow(ms(200), None));
+        assert!(!settings.is_slow(ms(200), Some(ms(100))));
+        assert!(settings.is_slow(ms(1500), None));
+        assert!(settings.is_slow(ms(800), Some(ms(700))));
+
+        // Only end to end latency counts without an upstream threshold
+        let settings = SlowRequestSettings {
+            upstream_threshold_ms: 0,
+            ..settings
+        };
+        assert!(!settings.is_slow(ms(800), Some(ms(700))));
+        assert!(settings.is_slow(ms(1500), Some(ms(1400))));
+    }
+
+    #[test]
+    fn test_params_hash() {
+        let a = json!({"id": 1, "method": "eth_getLogs", "params": [{"address": "0xAB", "fromBlock": "0x1"}]});
+        let b = json!({"id": 7, "method": "eth_getLogs", "params": [{"fromBlock": "0x1", "address": "0xab"}]});
+        let c = json!({"id": 1, "method": "eth_getLogs", "params": [{"address": "0xab", "fromBlock": "0x2"}]});
+
+        assert_eq!(params_hash(&a), params_hash(&b));
+        assert_ne!(params_hash(&a), params_hash(&c));
+        assert_eq!(params_hash(&a).len(), 16);
+    }
+}
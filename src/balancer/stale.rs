@@ -0,0 +1,185 @@
+//! Serving stale responses when no RPC is available.
+//!
+//! If every RPC that could serve a request is down, answering from what we
+//! have in the cache beats answering with an error. With
+//! `[blutgang.stale_serve]` enabled, requests for cacheable methods that no RPC
+//! can take get their cached response even if it expired, as long as it did
+//! less than `max_stale_ms` ago. Entries dropped because their block reorged
+//! are gone for good, and never served.
+//!
+//! Stale responses are marked with a `blutgang_stale` field, which makes it
+//! into batches too, and with an `x-blutgang-stale` header. Requests that skip
+//! the cache with `no-cache` get an error as usual.
+
+use crate::{
+    balancer::{
+        cache_control::CacheControl,
+        cache_metrics::method_label,
+        processing::CacheArgs,
+        selection::cache_rules::cache_method,
+    },
+    config::types::{
+        CachePolicy,
+        StaleServeSettings,
+    },
+    database::{
+        serialization::decode_stale,
+        types::GenericBytes,
+    },
+    db_get,
+};
+
+use std::convert::Infallible;
+
+use http_body_util::Full;
+use hyper::body::Bytes;
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+
+/// Header marking stale responses.
+pub const STALE_HEADER: &str = "x-blutgang-stale";
+/// Field marking stale responses.
+pub const STALE_FIELD: &str = "blutgang_stale";
+
+/// Returns true if responses to `tx` can be in the cache at all.
+fn cacheable<K, V>(tx: &Value, cache_args: &CacheArgs<K, V>) -> bool
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    match cache_args.policy(tx) {
+        Some(CachePolicy::Never) => false,
+        Some(_) => true,
+        None => cache_method(tx.to_string()),
+    }
+}
+
+/// Returns the cached response to `tx` under `key` with `id`, marked as stale,
+/// if `settings` let us serve it.
+pub async fn stale_response<K, V>(
+    tx: &Value,
+    key: K,
+    id: u64,
+    cache_args: &CacheArgs<K, V>,
+    settings: &StaleServeSettings,
+    cache_control: &CacheControl,
+) -> Option<Value>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    if !settings.enabled || cache_control.no_cache || !cacheable(tx, cache_args) {
+        return None;
+    }
+
+    let mut stale = db_get!(cache_args.cache, key)
+        .ok()
+        .flatten()
+        .and_then(|mut rax| {
+            decode_stale(rax.as_mut(), settings.max_stale_ms)
+                .ok()
+                .flatten()
+        })?;
+    stale["id"] = id.into();
+    stale[STALE_FIELD] = true.into();
+
+    let method = method_label(tx["method"].as_str());
+    tracing::warn!(method, "No RPC available, serving stale response");
+    metrics::counter!("stale_responses_total", "method" => method).increment(1);
+    Some(stale)
+}
+
+/// Build the HTTP response for a stale response.
+pub fn stale_http_response(stale: Value) -> Result<hyper::Response<Full<Bytes>>, Infallible> {
+    Ok(hyper::Response::builder()
+        .status(200)
+        .header("Content-Type", "application/json")
+        .header("Access-Control-Allow-Origin", "*")
+        .header(STALE_HEADER, "true")
+        .body(Full::new(Bytes::from(stale.to_string())))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        accept::db_insert,
+        expiry::{
+            now_ms,
+            with_expiry,
+        },
+        serialization::CacheFormat,
+    };
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        sync::Arc,
+    };
+
+    #[tokio::test]
+    async fn test_stale_response() {
+        let mut cache_args = CacheArgs::default();
+        let tx = json!({"jsonrpc": "2.0", "id": 0, "method": "eth_getBalance", "params": ["0x0", "0x10"]});
+        let key = [1u8; 32];
+        let cached = json!({"jsonrpc": "2.0", "id": null, "result": "0x1"});
+        let expired = with_expiry(CacheFormat::Json.encode(&cached), now_ms() - 60_000);
+        let _ = db_insert(&cache_args.cache, key, expired).await.await;
+
+        let settings = StaleServeSettings {
+            enabled: true,
+            max_stale_ms: 0,
+        };
+        let no_control = CacheControl::default();
+        let stale = stale_response(&tx, key, 7, &cache_args, &settings, &no_control)
+            .await
+            .unwrap();
+        assert_eq!(stale["id"], 7);
+        assert_eq!(stale["result"], "0x1");
+        assert_eq!(stale[STALE_FIELD], true);
+
+        // Unless it's too old, disabled, or the client wants a fresh response
+        let too_old = StaleServeSettings {
+            max_stale_ms: 30_000,
+            ..settings
+        };
+        assert!(
+            stale_response(&tx, key, 7, &cache_args, &too_old, &no_control)
+                .await
+                .is_none()
+        );
+        let disabled = StaleServeSettings::default();
+        assert!(
+            stale_response(&tx, key, 7, &cache_args, &disabled, &no_control)
+                .await
+                .is_none()
+        );
+        let no_cache = CacheControl {
+            no_cache: true,
+            no_store: false,
+        };
+        assert!(
+            stale_response(&tx, key, 7, &cache_args, &settings, &no_cache)
+                .await
+                .is_none()
+        );
+
+        // Or never cached in the first place
+        cache_args.policies = Arc::new(HashMap::from([(
+            "eth_getBalance".to_string(),
+            CachePolicy::Never,
+        )]));
+        assert!(
+            stale_response(&tx, key, 7, &cache_args, &settings, &no_control)
+                .await
+                .is_none()
+        );
+        let latest = json!({"jsonrpc": "2.0", "id": 0, "method": "eth_blockNumber", "params": []});
+        cache_args.policies = Arc::new(HashMap::new());
+        assert!(
+            stale_response(&latest, key, 7, &cache_args, &settings, &no_control)
+                .await
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! TLS termination on the client-facing listener.
+//!
+//! With `cert` set in `[blutgang.tls]`, every connection to `address` has to
+//! complete a TLS handshake first, and then gets served as usual, WebSocket
+//! upgrades included.
+//!
+//! The certificate is loaded on startup, and failing to load it stops us from
+//! starting. Afterwards, the files are checked every `reload_interval_ms`, and
+//! a changed certificate is used for every new connection. If it fails to load,
+//! say because the key was swapped out before the certificate, the old one is
+//! kept until the next check.
+//!
+//! Instead of files, the certificate can come from an ACME CA, see `acme`.
+
+use crate::config::types::TlsSettings;
+
+use std::{
+    collections::HashMap,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use rust_tracing::deps::metrics;
+use tokio::time::interval;
+use tokio_rustls::{
+    rustls::{
+        crypto::{
+            ring,
+            CryptoProvider,
+        },
+        server::{
+            ClientHello,
+            ResolvesServerCert,
+        },
+        sign::CertifiedKey,
+        ServerConfig,
+    },
+    TlsAcceptor,
+};
+
+/// Longest a client gets to complete its handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// ALPN protocol of ACME TLS-ALPN-01 challenges.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("failed to read {path}: {err}")]
+    Io { path: PathBuf, err: std::io::Error },
+
+    #[error("no certificates in {0}")]
+    NoCertificates(String),
+
+    #[error("no private key in {0}")]
+    NoKey(String),
+
+    #[error(transparent)]
+    Rustls(#[from] tokio_rustls::rustls::Error),
+}
+
+/// Hands out the current certificate to every handshake, or the certificate
+/// of a pending ACME challenge to the CA validating it.
+#[derive(Debug)]
+pub struct CertResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new(key: CertifiedKey) -> Self {
+        Self {
+            key: RwLock::new(Arc::new(key)),
+            challenges: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Use `key` for every handshake from now on.
+    pub fn set(&self, key: CertifiedKey) {
+        *self.key.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+    }
+
+    /// Answer TLS-ALPN-01 challenges for `domain` with `key`.
+    pub fn add_challenge(&self, domain: &str, key: CertifiedKey) {
+        self.challenges
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(domain.to_string(), Arc::new(key));
+    }
+
+    pub fn clear_challenges(&self) {
+        self.challenges
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let challenge = client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN));
+        if challenge {
+            let domain = client_hello.server_name()?;
+            return self
+                .challenges
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(domain)
+                .cloned();
+        }
+
+        Some(Arc::clone(
+            &self.key.read().unwrap_or_else(|e| e.into_inner()),
+        ))
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|err| {
+        TlsError::Io {
+            path: path.to_path_buf(),
+            err,
+        }
+    })
+}
+
+/// Parse a PEM certificate chain and its PEM private key. `source` names
+/// where they're from in errors.
+pub fn certified_key_from_pem(
+    cert: &[u8],
+    key: &[u8],
+    source: &str,
+) -> Result<CertifiedKey, TlsError> {
+    let certs = rustls_pemfile::certs(&mut &cert[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| TlsError::NoCertificates(source.to_string()))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificates(source.to_string()));
+    }
+
+    let key = rustls_pemfile::private_key(&mut &key[..])
+        .ok()
+        .flatten()
+        .ok_or_else(|| TlsError::NoKey(source.to_string()))?;
+    let key = provider().key_provider.load_private_key(key)?;
+
+    Ok(CertifiedKey::new(certs, key))
+}
+
+/// Load the certificate chain and key in `settings`.
+pub fn load_certified_key(settings: &TlsSettings) -> Result<CertifiedKey, TlsError> {
+    let cert = read(&settings.cert)?;
+    let key = read(&settings.key)?;
+    certified_key_from_pem(&cert, &key, &settings.cert.display().to_string())
+}
+
+/// Build an acceptor handing out the certificate of `resolver`.
+pub fn acceptor(resolver: Arc<CertResolver>) -> Result<TlsAcceptor, TlsError> {
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+    // We only speak HTTP/1.1, and answer ACME challenges if there are any
+    config.alpn_protocols = vec![b"http/1.1".to_vec(), ACME_TLS_ALPN.to_vec()];
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn modified(settings: &TlsSettings) -> [Option<SystemTime>; 2] {
+    [&settings.cert, &settings.key].map(|path| {
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    })
+}
+
+/// Reload the certificate into `resolver` whenever its files change.
+pub async fn watch_certificates(settings: TlsSettings, resolver: Arc<CertResolver>) {
+    if settings.reload_interval_ms == 0 {
+        return;
+    }
+
+    let mut ticker = interval(Duration::from_millis(settings.reload_interval_ms));
+    let mut last_modified = modified(&settings);
+    loop {
+        ticker.tick().await;
+        let modified = modified(&settings);
+        if modified == last_modified {
+            continue;
+        }
+
+        match load_certified_key(&settings) {
+            Ok(key) => {
+                resolver.set(key);
+                last_modified = modified;
+                tracing::info!(cert = ?settings.cert, "Reloaded TLS certificate");
+                metrics::counter!("tls_reloads_total", "status" => "ok").increment(1);
+            }
+            Err(err) => {
+                // Try again on the next tick, the files might be mid-update
+                tracing::error!(%err, "Failed to reload TLS certificate! Keeping the current one.");
+                metrics::counter!("tls_reloads_total", "status" => "failed").increment(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_certified_key_errors() {
+        let dir = std::env::temp_dir();
+        let empty = dir.join(format!("blutgang-tls-empty-{}", std::process::id()));
+        std::fs::write(&empty, "").unwrap();
+
+        let settings = TlsSettings {
+            cert: dir.join("blutgang-tls-surely-missing.pem"),
+            key: empty.clone(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            load_certified_key(&settings),
+            Err(TlsError::Io { .. })
+        ));
+
+        let settings = TlsSettings {
+            cert: empty.clone(),
+            ..settings
+        };
+        assert!(matches!(
+            load_certified_key(&settings),
+            Err(TlsError::NoCertificates(_))
+        ));
+
+        std::fs::remove_file(&empty).unwrap();
+    }
+}
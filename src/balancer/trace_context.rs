@@ -0,0 +1,120 @@
+//! Distributed tracing of requests.
+//!
+//! Every request gets a `request` span, with spans for the cache lookup,
+//! picking an RPC, and each call to an RPC under it, so slow requests show
+//! where their time went. Spans are exported over OTLP by `rust_tracing`,
+//! configured through the usual `OTEL_*` environment variables.
+//!
+//! Requests coming with a W3C `traceparent` header continue that trace, and
+//! calls to RPCs over HTTP pass it on, so the trace follows the request
+//! through clients, Blutgang and RPCs alike.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+};
+
+use opentelemetry::{
+    global,
+    propagation::{
+        Extractor,
+        Injector,
+    },
+    Context,
+};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Propagate trace context in W3C `traceparent` headers.
+pub fn install_propagator() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+}
+
+struct HeaderExtractor<'a>(&'a hyper::HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Headers to send along with a call, see `inject_context`.
+#[derive(Debug, Default)]
+pub struct TraceHeaders(HashMap<String, String>);
+
+impl Injector for TraceHeaders {
+    fn set(&mut self, key: &str, value: String) {
+        self.0.insert(key.to_string(), value);
+    }
+}
+
+impl TraceHeaders {
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0.iter()
+    }
+}
+
+/// Returns the trace context the client sent in `headers`, if any.
+fn extract_context(headers: &hyper::HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Returns the headers passing `cx` on.
+fn inject_context(cx: &Context) -> TraceHeaders {
+    let mut headers = TraceHeaders::default();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(cx, &mut headers));
+    headers
+}
+
+/// Span for a request with `headers` from `client`, continuing the trace of
+/// the client.
+///
+/// The method, the RPC it went to and whether it hit the cache
+/// are recorded on it as they become known.
+pub fn request_span(headers: &hyper::HeaderMap, client: Option<SocketAddr>) -> Span {
+    let span = tracing::info_span!(
+        "request",
+        client = client.map(tracing::field::display),
+        method = tracing::field::Empty,
+        rpc = tracing::field::Empty,
+        cache = tracing::field::Empty,
+    );
+    span.set_parent(extract_context(headers));
+    span
+}
+
+/// Headers passing the current trace on to an RPC.
+pub fn trace_headers() -> TraceHeaders {
+    inject_context(&Span::current().context())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn test_propagation() {
+        install_propagator();
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("traceparent", TRACEPARENT.parse().unwrap());
+        let cx = extract_context(&headers);
+
+        let injected = inject_context(&cx);
+        assert_eq!(
+            injected.0.get("traceparent").map(String::as_str),
+            Some(TRACEPARENT)
+        );
+
+        // Nothing to pass on without a trace
+        let cx = extract_context(&hyper::HeaderMap::new());
+        assert!(inject_context(&cx).iter().next().is_none());
+    }
+}
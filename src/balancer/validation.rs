@@ -0,0 +1,240 @@
+//! Validation of request parameters for well known methods.
+//!
+//! Obviously malformed requests would get rejected by the RPC anyway, but
+//! only after eating into our quota and usually with a less helpful error.
+//! When enabled, we check the params of the methods below before forwarding
+//! and return an `invalid params` error right away.
+//!
+//! Validation is intentionally lenient. We only check params we know the shape
+//! of and never reject anything the RPCs would accept.
+
+use serde_json::Value;
+
+/// Expected format of a single parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Param {
+    /// 20 byte hex string
+    Address,
+    /// 32 byte hex string
+    Hash,
+    /// Hex encoded number
+    Quantity,
+    /// Hex encoded bytes of any length
+    Data,
+    /// Number, block tag, or an EIP-1898 block object
+    Block,
+    Bool,
+    Object,
+}
+
+/// Params a method expects, and how many of them are required.
+pub(super) struct MethodParams {
+    pub(super) params: &'static [Param],
+    required: usize,
+}
+
+const fn params(params: &'static [Param], required: usize) -> Option<MethodParams> {
+    Some(MethodParams { params, required })
+}
+
+pub(super) fn method_params(method: &str) -> Option<MethodParams> {
+    use Param::*;
+
+    match method {
+        "eth_blockNumber" | "eth_chainId" | "eth_gasPrice" | "eth_syncing" | "net_version" => {
+            params(&[], 0)
+        }
+        "eth_getBalance" | "eth_getTransactionCount" | "eth_getCode" => {
+            params(&[Address, Block], 1)
+        }
+        "eth_getStorageAt" => params(&[Address, Quantity, Block], 2),
+        "eth_getBlockByNumber" => params(&[Block, Bool], 2),
+        "eth_getBlockByHash" => params(&[Hash, Bool], 2),
+        "eth_getTransactionByHash"
+        | "eth_getTransactionReceipt"
+        | "eth_getBlockTransactionCountByHash"
+        | "eth_getUncleCountByBlockHash" => params(&[Hash], 1),
+        "eth_getBlockTransactionCountByNumber"
+        | "eth_getUncleCountByBlockNumber"
+        | "eth_getBlockReceipts" => params(&[Block], 1),
+        "eth_getTransactionByBlockHashAndIndex" | "eth_getUncleByBlockHashAndIndex" => {
+            params(&[Hash, Quantity], 2)
+        }
+        "eth_getTransactionByBlockNumberAndIndex" | "eth_getUncleByBlockNumberAndIndex" => {
+            params(&[Block, Quantity], 2)
+        }
+        // State overrides, and block overrides for `eth_call`
+        "eth_call" => params(&[Object, Block, Object, Object], 1),
+        "eth_estimateGas" => params(&[Object, Block, Object], 1),
+        "eth_sendRawTransaction" => params(&[Data], 1),
+        "eth_getLogs" => params(&[Object], 1),
+        _ => None,
+    }
+}
+
+/// Returns true if `s` is `0x` followed by hex digits, with exactly `len` digits if specified.
+pub(super) fn is_hex(s: &str, len: Option<usize>) -> bool {
+    let Some(digits) = s.strip_prefix("0x") else {
+        return false;
+    };
+
+    if len.is_some_and(|len| digits.len() != len) {
+        return false;
+    }
+
+    digits.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_valid(param: Param, value: &Value) -> bool {
+    match param {
+        Param::Address => value.as_str().is_some_and(|s| is_hex(s, Some(40))),
+        Param::Hash => value.as_str().is_some_and(|s| is_hex(s, Some(64))),
+        Param::Quantity => {
+            value
+                .as_str()
+                .is_some_and(|s| s.len() > 2 && is_hex(s, None))
+        }
+        Param::Data => {
+            value
+                .as_str()
+                .is_some_and(|s| s.len() % 2 == 0 && is_hex(s, None))
+        }
+        Param::Block => {
+            match value {
+                Value::String(s) => {
+                    matches!(
+                        s.as_str(),
+                        "latest" | "earliest" | "safe" | "finalized" | "pending"
+                    ) || is_valid(Param::Quantity, value)
+                }
+                // EIP-1898
+                Value::Object(block) => {
+                    block
+                        .get("blockHash")
+                        .is_some_and(|hash| is_valid(Param::Hash, hash))
+                        || block
+                            .get("blockNumber")
+                            .is_some_and(|number| is_valid(Param::Quantity, number))
+                }
+                _ => false,
+            }
+        }
+        Param::Bool => value.is_boolean(),
+        Param::Object => value.is_object(),
+    }
+}
+
+/// Check the params of a request. Returns the reason if they're invalid.
+pub fn validate_request(tx: &Value) -> Result<(), String> {
+    let Some(method) = tx["method"].as_str() else {
+        return Err("missing method".to_string());
+    };
+    let Some(expected) = method_params(method) else {
+        return Ok(());
+    };
+
+    let params: &[Value] = match &tx["params"] {
+        Value::Array(params) => params,
+        Value::Null => &[],
+        _ => return Err("params must be an array".to_string()),
+    };
+
+    if params.len() < expected.required {
+        return Err(format!(
+            "{method} expects at least {} params, got {}",
+            expected.required,
+            params.len()
+        ));
+    }
+    if params.len() > expected.params.len() {
+        return Err(format!(
+            "{method} expects at most {} params, got {}",
+            expected.params.len(),
+            params.len()
+        ));
+    }
+
+    for (index, (param, value)) in expected.params.iter().zip(params).enumerate() {
+        // Optional params can be left out with `null`
+        let omitted = index >= expected.required && value.is_null();
+        if !omitted && !is_valid(*param, value) {
+            return Err(format!(
+                "invalid param at index {index}: expected {param:?}, got {value}"
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ADDRESS: &str = "0x388c818ca8b9251b393131c08a736a67ccb19297";
+    const HASH: &str = "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae";
+
+    fn request(method: &str, params: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params})
+    }
+
+    #[test]
+    fn test_valid_requests() {
+        let requests = [
+            request("eth_blockNumber", json!([])),
+            request("eth_blockNumber", Value::Null),
+            request("eth_getBalance", json!([ADDRESS, "latest"])),
+            request("eth_getBalance", json!([ADDRESS])),
+            request("eth_getBalance", json!([ADDRESS, {"blockHash": HASH}])),
+            request("eth_getStorageAt", json!([ADDRESS, "0x0", "0x1b4"])),
+            request("eth_getBlockByNumber", json!(["finalized", false])),
+            request("eth_getBlockByHash", json!([HASH, true])),
+            request("eth_getTransactionReceipt", json!([HASH])),
+            request("eth_call", json!([{"to": ADDRESS, "data": "0x"}, "latest"])),
+            request(
+                "eth_call",
+                json!([{"to": ADDRESS}, "latest", {ADDRESS: {"balance": "0x1"}}, {"number": "0x1"}]),
+            ),
+            request(
+                "eth_estimateGas",
+                json!([{"to": ADDRESS}, null, {ADDRESS: {"balance": "0x1"}}]),
+            ),
+            request("eth_sendRawTransaction", json!(["0x02f8"])),
+            // Unknown methods are passed through
+            request("debug_traceTransaction", json!(["whatever", 1])),
+        ];
+
+        for request in requests {
+            assert!(validate_request(&request).is_ok(), "{request}");
+        }
+    }
+
+    #[test]
+    fn test_invalid_requests() {
+        let requests = [
+            request("eth_getBalance", json!([])),
+            request("eth_getBalance", json!(["0x388c", "latest"])),
+            request("eth_getBalance", json!([ADDRESS, "newest"])),
+            request("eth_getBalance", json!([ADDRESS, "latest", "extra"])),
+            request("eth_getBlockByNumber", json!(["0x", false])),
+            request("eth_getBlockByNumber", json!(["latest"])),
+            request("eth_getBlockByHash", json!(["0xzz", true])),
+            request("eth_getTransactionReceipt", json!([1])),
+            request("eth_call", json!(["0x", "latest"])),
+            request("eth_call", json!([{"to": ADDRESS}, "latest", "0x"])),
+            request("eth_call", json!([null])),
+            request(
+                "eth_estimateGas",
+                json!([{"to": ADDRESS}, "latest", {}, {}]),
+            ),
+            request("eth_sendRawTransaction", json!(["0x02f"])),
+            request("eth_getLogs", json!({"fromBlock": "latest"})),
+            json!({"jsonrpc": "2.0", "id": 1, "params": []}),
+        ];
+
+        for request in requests {
+            assert!(validate_request(&request).is_err(), "{request}");
+        }
+    }
+}
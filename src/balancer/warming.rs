@@ -0,0 +1,297 @@
+//! Cache warming on new heads.
+//!
+//! Every new block is followed by a burst of clients asking for it. Instead of
+//! passing that burst on to the RPCs, we fetch the block into the cache as soon
+//! as the `newHeads` subscription announces it:
+//!
+//! - `eth_getBlockByNumber`, header-only and optionally with full transactions,
+//! - `eth_getBlockByHash`, reusing the block we just fetched,
+//! - `eth_getBlockReceipts`.
+//!
+//! Warmed responses go through the same rules as any other, so by-hash blocks
+//! and receipts are only warmed if their methods have a cache policy.
+
+use crate::{
+    balancer::{
+        processing::{
+            cache_query,
+            update_rpc_latency,
+            CacheArgs,
+        },
+        selection::{
+            cache_rules::negative_result,
+            select::pick,
+        },
+        singleflight::Flight,
+    },
+    config::types::{
+        CachePolicy,
+        CacheWarmingSettings,
+    },
+    database::types::GenericBytes,
+    rpc::types::Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::watch,
+    time::timeout,
+};
+
+const GET_BLOCK_BY_NUMBER: &str = "eth_getBlockByNumber";
+const GET_BLOCK_BY_HASH: &str = "eth_getBlockByHash";
+const GET_BLOCK_RECEIPTS: &str = "eth_getBlockReceipts";
+
+/// Fetch every new head announced on `blocknum_rx` into the cache.
+pub async fn warm_cache<K, V>(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    mut blocknum_rx: watch::Receiver<u64>,
+    cache_args: CacheArgs<K, V>,
+    settings: CacheWarmingSettings,
+    ttl: u64,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    // Reorged blocks are removed from the cache before their replacement is
+    // announced, so we warm every head we get, even if we've seen its number.
+    while blocknum_rx.changed().await.is_ok() {
+        let number = *blocknum_rx.borrow_and_update();
+        // Sent when we lose track of the head
+        if number == 0 {
+            continue;
+        }
+
+        let start = Instant::now();
+        let warmed = warm_block(number, &rpc_list, &cache_args, settings, ttl).await;
+        tracing::debug!(number, warmed, elapsed = ?start.elapsed(), "Warmed cache for new head");
+        metrics::counter!("cache_warmed_total").increment(warmed as u64);
+    }
+}
+
+/// Fetch block `number` and what else we can warm for it into the cache.
+///
+/// Returns the number of responses cached.
+async fn warm_block<K, V>(
+    number: u64,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs<K, V>,
+    settings: CacheWarmingSettings,
+    ttl: u64,
+) -> usize
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let by_hash = warms(cache_args, GET_BLOCK_BY_HASH);
+    let requests = warm_requests(number, settings, warms(cache_args, GET_BLOCK_RECEIPTS));
+
+    let responses = join_all(requests.into_iter().map(|tx| {
+        async move {
+            // Clients already fetching the block will cache it themselves, and
+            // clients asking while we fetch it get our response
+            let key = cache_args.cache_key(&tx);
+            let leader = match cache_args
+                .in_flight
+                .join(key.as_bytes(), tx["method"].as_str())
+            {
+                Flight::Follower(_) => return (tx, None),
+                Flight::Leader(leader) => Some(leader),
+                Flight::Alone => None,
+            };
+
+            let rx = fetch(rpc_list, &tx, ttl).await;
+            if let (Some(leader), Some(rx)) = (leader, &rx) {
+                leader.complete(rx);
+            }
+            (tx, rx)
+        }
+    }))
+    .await;
+
+    let mut warmed = 0;
+    for (tx, rx) in responses {
+        let Some(mut rx) = rx else {
+            continue;
+        };
+
+        // Blocks by hash are the same block, no need to fetch them again
+        if by_hash && tx["method"] == GET_BLOCK_BY_NUMBER {
+            if let Some(by_hash) = by_hash_request(&tx, &rx) {
+                cache(&mut rx.clone(), by_hash, cache_args).await;
+                warmed += 1;
+            }
+        }
+
+        cache(&mut rx, tx, cache_args).await;
+        warmed += 1;
+    }
+
+    warmed
+}
+
+/// Returns true if responses to `method` are cached, and thus worth warming.
+///
+/// Blocks by hash and receipts don't refer to a block number, so they're only
+/// cached with a policy.
+fn warms<K, V>(cache_args: &CacheArgs<K, V>, method: &str) -> bool
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    cache_args
+        .policies
+        .get(method)
+        .is_some_and(|policy| *policy != CachePolicy::Never)
+}
+
+/// Requests we send to warm block `number`, shaped like the ones that get cached.
+fn warm_requests(number: u64, settings: CacheWarmingSettings, receipts: bool) -> Vec<Value> {
+    let number = format!("{:#x}", number);
+
+    let mut requests = vec![request(GET_BLOCK_BY_NUMBER, json!([number, false]))];
+    if settings.full_transactions {
+        requests.push(request(GET_BLOCK_BY_NUMBER, json!([number, true])));
+    }
+    if receipts {
+        requests.push(request(GET_BLOCK_RECEIPTS, json!([number])));
+    }
+
+    requests
+}
+
+fn request(method: &str, params: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": null,
+        "method": method,
+        "params": params,
+    })
+}
+
+/// Returns the by-hash request the block in `rx`, fetched with `tx`, answers.
+fn by_hash_request(tx: &Value, rx: &str) -> Option<Value> {
+    let rx: Value = serde_json::from_str(rx).ok()?;
+    let hash = rx["result"]["hash"].as_str()?;
+
+    Some(request(GET_BLOCK_BY_HASH, json!([hash, tx["params"][1]])))
+}
+
+/// Send `tx` to the next RPC in line, returning its response if it has one.
+async fn fetch(rpc_list: &Arc<RwLock<Vec<Rpc>>>, tx: &Value, ttl: u64) -> Option<String> {
+    let (rpc, position) = {
+        let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+        pick(&mut rpc_list_guard, tx["method"].as_str())
+    };
+    let position = position?;
+
+    let start = Instant::now();
+    let rx = match timeout(Duration::from_millis(ttl), rpc.send_request(tx.clone())).await {
+        Ok(Ok(rx)) => rx,
+        Ok(Err(err)) => {
+            tracing::debug!(?err, rpc.name, "Couldn't fetch block to warm the cache");
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!(rpc.name, "Timed out fetching block to warm the cache");
+            return None;
+        }
+    };
+    update_rpc_latency(rpc_list, position, start.elapsed());
+
+    // The RPC might not have the block yet, don't let anyone get served that
+    if negative_result(&rx) {
+        tracing::debug!(rpc.name, "RPC doesn't have the block to warm the cache yet");
+        return None;
+    }
+
+    Some(rx)
+}
+
+/// Cache `rx` as the response to `tx`.
+async fn cache<K, V>(rx: &mut str, tx: Value, cache_args: &CacheArgs<K, V>)
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let tx_hash = cache_args.cache_key(&tx);
+    cache_query(rx, tx, tx_hash, cache_args).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::downgrade::cached_response;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_warm_requests() {
+        let settings = CacheWarmingSettings {
+            enabled: true,
+            full_transactions: false,
+        };
+        assert_eq!(
+            warm_requests(16, settings, false),
+            vec![request(GET_BLOCK_BY_NUMBER, json!(["0x10", false]))]
+        );
+
+        let settings = CacheWarmingSettings {
+            enabled: true,
+            full_transactions: true,
+        };
+        let requests = warm_requests(16, settings, true);
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[1]["params"], json!(["0x10", true]));
+        assert_eq!(requests[2], request(GET_BLOCK_RECEIPTS, json!(["0x10"])));
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_warm_by_hash() {
+        let mut cache_args = CacheArgs::default();
+        assert!(!warms(&cache_args, GET_BLOCK_BY_HASH));
+        cache_args.policies = Arc::new(HashMap::from([
+            (GET_BLOCK_BY_HASH.to_string(), CachePolicy::Forever),
+            (GET_BLOCK_RECEIPTS.to_string(), CachePolicy::Never),
+        ]));
+        assert!(warms(&cache_args, GET_BLOCK_BY_HASH));
+        assert!(!warms(&cache_args, GET_BLOCK_RECEIPTS));
+
+        let tx = request(GET_BLOCK_BY_NUMBER, json!(["0x10", false]));
+        let rx = r#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x10","hash":"0xAB"}}"#;
+        let by_hash = by_hash_request(&tx, rx).unwrap();
+        assert_eq!(by_hash["params"], json!(["0xAB", false]));
+
+        // Both requests are answered from the cache
+        cache(&mut rx.to_string(), by_hash, &cache_args).await;
+        cache(&mut rx.to_string(), tx.clone(), &cache_args).await;
+        let by_hash = request(GET_BLOCK_BY_HASH, json!(["0xab", false]));
+        for tx in [tx, by_hash] {
+            let cached = cached_response(&tx, &cache_args).await.unwrap();
+            assert_eq!(cached["result"]["number"], "0x10");
+        }
+
+        // Nothing to reuse without a block
+        let null = r#"{"jsonrpc":"2.0","id":1,"result":null}"#;
+        assert_eq!(
+            by_hash_request(&request(GET_BLOCK_BY_NUMBER, json!(["0x11", false])), null),
+            None
+        );
+    }
+}
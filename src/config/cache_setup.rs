@@ -6,12 +6,126 @@ use crate::{
     database::types::GenericDatabase,
 };
 
+use rust_tracing::deps::metrics;
+
+/// `blutgang_is_lb` is cached as a blake3 cache
+const BLUTGANG_IS_LB_KEY: [u8; 32] = [
+    176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53, 4, 158,
+    140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
+];
+/// `web3_clientVersion` is cached as a blake3 cache
+const WEB3_CLIENT_VERSION_KEY: [u8; 32] = [
+    36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67, 121,
+    225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
+];
+
+/// Version of the cache schema, which covers how keys are derived from requests
+/// and how responses are encoded.
+///
+/// Bump it whenever either changes in a way older entries can't be read with.
+/// If they can be converted, add a migration to `migrate`, otherwise caches
+/// written with older versions get cleared on startup.
+pub const CACHE_SCHEMA_VERSION: u32 = 1;
+/// Key the schema version of the entries in the cache is stored under.
+const SCHEMA_VERSION_KEY: &[u8] = b"blutgang_cache_schema";
+
+/// Keys written by `setup_data` that have to stay in the cache.
+pub const RESERVED_KEYS: [&[u8]; 5] = [
+    &BLUTGANG_IS_LB_KEY,
+    &WEB3_CLIENT_VERSION_KEY,
+    b"xxhash",
+    b"blake3",
+    SCHEMA_VERSION_KEY,
+];
+
+/// Prefix of keys holding state kept across restarts, like quota usage,
+/// rather than cached responses.
+///
+/// Like `RESERVED_KEYS`, they're never evicted, cleared, shared or exported.
+const STATE_KEY_PREFIX: &[u8] = b"blutgang_state:";
+
+/// Key the state called `name` is stored under, see `STATE_KEY_PREFIX`.
+pub fn state_key(name: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    let (prefix, hash) = key.split_at_mut(STATE_KEY_PREFIX.len());
+    prefix.copy_from_slice(STATE_KEY_PREFIX);
+    hash.copy_from_slice(&blake3::hash(name.as_bytes()).as_bytes()[..hash.len()]);
+    key
+}
+
+/// Returns true if `key` doesn't hold a cached response, and has to stay in
+/// the cache.
+pub fn is_reserved(key: &[u8]) -> bool {
+    RESERVED_KEYS.contains(&key) || key.starts_with(STATE_KEY_PREFIX)
+}
+
+/// Returns the schema version the entries in `cache` were written with, or
+/// `None` if the cache is new.
+fn schema_version<DB: GenericDatabase>(cache: &DB) -> Option<u32> {
+    match cache.read(SCHEMA_VERSION_KEY) {
+        Ok(Some(version)) => {
+            Some(
+                version
+                    .try_into()
+                    .map(u32::from_be_bytes)
+                    .unwrap_or_default(),
+            )
+        }
+        // Caches from before we versioned them still have the rest of our keys
+        Ok(None) if matches!(cache.read(BLUTGANG_IS_LB_KEY), Ok(Some(_))) => Some(0),
+        _ => None,
+    }
+}
+
+/// Bring the entries in `cache` up to the current schema.
+///
+/// Entries we can't convert are keyed or encoded differently, and would be
+/// served as answers to the wrong requests, so they get cleared instead.
+fn migrate<DB: GenericDatabase>(cache: &DB) {
+    let Some(version) = schema_version(cache) else {
+        return;
+    };
+    if version == CACHE_SCHEMA_VERSION {
+        return;
+    }
+
+    // There's nothing to migrate from yet. Unversioned caches could have been
+    // keyed in several ways, and we can't know what newer versions changed.
+    tracing::warn!(
+        version,
+        current = CACHE_SCHEMA_VERSION,
+        "Cache was written with a different schema, clearing it."
+    );
+
+    // State isn't a cached response, and outlives schema changes
+    let mut state = Vec::new();
+    let _ = cache.for_each_key(&mut |key| {
+        if key.starts_with(STATE_KEY_PREFIX) {
+            state.push(key.to_vec());
+        }
+    });
+    let state: Vec<(Vec<u8>, Vec<u8>)> = state
+        .into_iter()
+        .filter_map(|key| Some((key.clone(), cache.read(key).ok()??)))
+        .collect();
+
+    match cache.clear() {
+        Ok(_) => metrics::counter!("cache_schema_invalidations_total").increment(1),
+        Err(err) => tracing::error!(?err, "Failed to clear outdated cache!"),
+    }
+    for (key, value) in state {
+        let _ = cache.write(key, value);
+    }
+}
+
 /// Sets up the cache with various basic data about our current blutgang instance.
 pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool) {
     // Clear database if specified
     if do_clear {
         cache.clear().unwrap();
         tracing::warn!("All data cleared from the database.");
+    } else {
+        migrate(cache);
     }
 
     let version_json = format!(
@@ -22,23 +136,11 @@ pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool) {
     tracing::info!("Starting Blutgang {}", VERSION_STR);
 
     // Insert kv pair `blutgang_is_lb` `true` to know what we're interacting with
-    // `blutgang_is_lb` is cached as a blake3 cache
-    let _ = cache.write(
-        [
-            176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53,
-            4, 158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.write(BLUTGANG_IS_LB_KEY, version_json.as_bytes());
     // Insert kv pair `web3_clientVersion` `true` to know what we're interacting with
-    // `web3_clientVersion` is cached as a blake3 cache
-    let _ = cache.write(
-        [
-            36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
-            121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.write(WEB3_CLIENT_VERSION_KEY, version_json.as_bytes());
+    // Everything from here on is written with the current schema
+    let _ = cache.write(SCHEMA_VERSION_KEY, CACHE_SCHEMA_VERSION.to_be_bytes());
 
     // Insert which hashing algo we're using based on the selected features.
     // If `xxhash` is enabled we're using xxhash3, otherwise blake3.
@@ -60,3 +162,67 @@ pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sled::{
+        Config,
+        Db,
+    };
+
+    fn open() -> Db<{ crate::FANOUT }> {
+        Db::open_with_config(&Config::tmp().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_schema_version() {
+        let cache = open();
+        assert_eq!(schema_version(&cache), None);
+
+        setup_data(&cache, false);
+        assert_eq!(schema_version(&cache), Some(CACHE_SCHEMA_VERSION));
+
+        // Entries of the current schema are kept
+        cache.write([1u8; 32], b"{}").unwrap();
+        setup_data(&cache, false);
+        assert!(cache.read([1u8; 32]).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_outdated_schema_cleared() {
+        // Caches from before versioning only have our other keys
+        let cache = open();
+        cache.write(BLUTGANG_IS_LB_KEY, b"{}").unwrap();
+        cache.write([1u8; 32], b"{}").unwrap();
+        assert_eq!(schema_version(&cache), Some(0));
+
+        setup_data(&cache, false);
+        assert!(cache.read([1u8; 32]).unwrap().is_none());
+        assert_eq!(schema_version(&cache), Some(CACHE_SCHEMA_VERSION));
+
+        // So are caches from newer versions
+        cache
+            .write(SCHEMA_VERSION_KEY, (CACHE_SCHEMA_VERSION + 1).to_be_bytes())
+            .unwrap();
+        cache.write([1u8; 32], b"{}").unwrap();
+        setup_data(&cache, false);
+        assert!(cache.read([1u8; 32]).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_state_keys() {
+        let key = state_key("quota:http://localhost:8545");
+        assert!(is_reserved(&key));
+        assert!(is_reserved(b"blake3"));
+        assert!(!is_reserved(&[1u8; 32]));
+        assert_ne!(key, state_key("quota:http://localhost:8546"));
+
+        // State outlives schema changes
+        let cache = open();
+        cache.write(BLUTGANG_IS_LB_KEY, b"{}").unwrap();
+        cache.write(key, b"state").unwrap();
+        setup_data(&cache, false);
+        assert_eq!(cache.read(key).unwrap(), Some(b"state".to_vec()));
+    }
+}
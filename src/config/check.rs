@@ -0,0 +1,333 @@
+//! `blutgang check-config`, to catch misconfigurations before deploying.
+//!
+//! Parses the config like on startup, then checks every RPC in it:
+//!
+//! - its urls use the right schemes,
+//! - it answers `eth_chainId` within `ttl`, and agrees with the others on it,
+//! - it supports the `eth` namespace and the ones of its health check methods,
+//!   including those of its route groups,
+//! - its `ws_url`, if any, accepts connections.
+//!
+//! Prints a report and exits without starting the proxy. Primary and
+//! emergency RPCs are checked against the same requirements, as emergency
+//! ones take over all traffic when no primary is available.
+
+use crate::{
+    config::types::HealthCheckMethod,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    collections::{
+        BTreeSet,
+        HashMap,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use tokio::time::timeout;
+use tokio_tungstenite::connect_async;
+
+/// What we found out about an RPC.
+#[derive(Debug, Default)]
+struct RpcReport {
+    name: String,
+    emergency: bool,
+    chain_id: Option<u64>,
+    problems: Vec<String>,
+    // Worth knowing, but not failing the check over
+    warnings: Vec<String>,
+}
+
+/// Namespaces an RPC with `health_check_methods` has to support.
+fn required_namespaces<'a>(
+    health_check_methods: impl IntoIterator<Item = &'a HealthCheckMethod>,
+) -> BTreeSet<String> {
+    let mut namespaces = BTreeSet::from(["eth".to_string()]);
+    namespaces.extend(
+        health_check_methods
+            .into_iter()
+            .filter_map(|method| method.method.split('_').next())
+            .map(ToString::to_string),
+    );
+    namespaces
+}
+
+/// Returns the problems with the urls of `rpc`.
+fn check_urls(rpc: &Rpc) -> Vec<String> {
+    let mut problems = Vec::new();
+    if !matches!(rpc.scheme(), "http" | "https") {
+        problems.push(format!("url has to be http(s), not {}", rpc.scheme()));
+    }
+    if let Some(ws_url) = &rpc.ws_url {
+        if !matches!(ws_url.scheme(), "ws" | "wss") {
+            problems.push(format!("ws_url has to be ws(s), not {}", ws_url.scheme()));
+        }
+    }
+    problems
+}
+
+/// Check a single RPC, see the module docs.
+async fn check_rpc(rpc: &Rpc, required: &BTreeSet<String>, ttl: Duration) -> RpcReport {
+    let mut report = RpcReport {
+        name: rpc.name.clone(),
+        emergency: rpc.emergency,
+        problems: check_urls(rpc),
+        ..Default::default()
+    };
+
+    match timeout(ttl, rpc.chain_id()).await {
+        Ok(Ok(chain_id)) => report.chain_id = Some(chain_id),
+        Ok(Err(err)) => report.problems.push(format!("eth_chainId failed: {}", err)),
+        Err(_) => {
+            report
+                .problems
+                .push(format!("eth_chainId timed out after {}ms", ttl.as_millis()))
+        }
+    }
+
+    // Not every node answers `rpc_modules`, which isn't a problem by itself
+    match timeout(ttl, rpc.rpc_modules()).await {
+        Ok(Ok(modules)) => {
+            let missing: Vec<&str> = required
+                .iter()
+                .filter(|namespace| !modules.contains(namespace))
+                .map(String::as_str)
+                .collect();
+            if !missing.is_empty() {
+                report
+                    .problems
+                    .push(format!("missing namespaces: {}", missing.join(", ")));
+            }
+        }
+        _ => {
+            report
+                .warnings
+                .push("rpc_modules not supported, can't check namespaces".to_string())
+        }
+    }
+
+    if let Some(ws_url) = &rpc.ws_url {
+        match timeout(ttl, connect_async(ws_url)).await {
+            Ok(Ok((mut ws_stream, _))) => {
+                let _ = ws_stream.close(None).await;
+            }
+            Ok(Err(err)) => report.problems.push(format!("ws_url failed: {}", err)),
+            Err(_) => {
+                report
+                    .problems
+                    .push(format!("ws_url timed out after {}ms", ttl.as_millis()))
+            }
+        }
+    }
+
+    report
+}
+
+/// Flag the RPCs on a different chain than most of them.
+fn check_chain_ids(reports: &mut [RpcReport]) {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for chain_id in reports.iter().filter_map(|report| report.chain_id) {
+        *counts.entry(chain_id).or_default() += 1;
+    }
+    if counts.len() < 2 {
+        return;
+    }
+
+    // Ties go to the lowest chain ID, so the report is the same every time
+    let Some(majority) = counts
+        .iter()
+        .max_by_key(|(chain_id, count)| (**count, std::cmp::Reverse(**chain_id)))
+        .map(|(chain_id, _)| *chain_id)
+    else {
+        return;
+    };
+
+    for report in reports.iter_mut() {
+        if let Some(chain_id) = report.chain_id.filter(|chain_id| *chain_id != majority) {
+            report.problems.push(format!(
+                "on chain {}, while most RPCs are on chain {}",
+                chain_id, majority
+            ));
+        }
+    }
+}
+
+/// Check the RPCs in `settings` and print a report. Returns true if there
+/// are no problems.
+pub async fn check_config(settings: &Settings) -> bool {
+    let rpcs: Vec<&Rpc> = settings
+        .rpc_list
+        .iter()
+        .chain(settings.poverty_list.iter())
+        .collect();
+    if rpcs.is_empty() {
+        println!("No RPCs configured!");
+        return false;
+    }
+
+    let ttl = Duration::from_millis(settings.ttl.try_into().unwrap_or(u64::MAX));
+    let mut reports = join_all(rpcs.iter().map(|rpc| {
+        // RPCs in route groups have to answer the methods of their groups too
+        let required = required_namespaces(settings.health_check_methods.for_rpc(rpc));
+        async move { check_rpc(rpc, &required, ttl).await }
+    }))
+    .await;
+    check_chain_ids(&mut reports);
+
+    let mut ok = true;
+    for report in &reports {
+        let group = if report.emergency {
+            "emergency"
+        } else {
+            "primary"
+        };
+        let chain_id = report
+            .chain_id
+            .map(|chain_id| chain_id.to_string())
+            .unwrap_or_else(|| "?".to_string());
+
+        if report.problems.is_empty() {
+            println!("OK    {} ({}, chain {})", report.name, group, chain_id);
+        } else {
+            ok = false;
+            println!("FAIL  {} ({}, chain {})", report.name, group, chain_id);
+            for problem in &report.problems {
+                println!("      - {}", problem);
+            }
+        }
+        for warning in &report.warnings {
+            println!("      ! {}", warning);
+        }
+    }
+
+    if !reports.iter().any(|report| !report.emergency) {
+        ok = false;
+        println!("No primary RPCs configured, emergency RPCs will serve everything!");
+    }
+
+    let failed = reports
+        .iter()
+        .filter(|report| !report.problems.is_empty())
+        .count();
+    println!(
+        "{} of {} RPCs passed, config at {}",
+        reports.len() - failed,
+        reports.len(),
+        settings
+            .config_path
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "<none, using defaults>".to_string())
+    );
+
+    ok
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::HealthCheckMethods;
+
+    fn report(chain_id: Option<u64>) -> RpcReport {
+        RpcReport {
+            chain_id,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_urls() {
+        let rpc = Rpc::new(
+            "http://node.example".parse().unwrap(),
+            Some("wss://node.example".parse().unwrap()),
+            1,
+            0,
+            1.0,
+        );
+        assert!(check_urls(&rpc).is_empty());
+
+        let rpc = Rpc::new(
+            "ws://node.example".parse().unwrap(),
+            Some("https://node.example".parse().unwrap()),
+            1,
+            0,
+            1.0,
+        );
+        assert_eq!(check_urls(&rpc).len(), 2);
+    }
+
+    #[test]
+    fn test_check_chain_ids() {
+        let mut reports = vec![
+            report(Some(1)),
+            report(Some(1)),
+            report(Some(10)),
+            report(None),
+        ];
+        check_chain_ids(&mut reports);
+        assert!(reports[0].problems.is_empty());
+        assert!(reports[1].problems.is_empty());
+        assert_eq!(reports[2].problems.len(), 1);
+        // Unreachable RPCs are reported on their own
+        assert!(reports[3].problems.is_empty());
+
+        let mut reports = vec![report(Some(5)), report(Some(1))];
+        check_chain_ids(&mut reports);
+        assert_eq!(reports[0].problems.len(), 1);
+        assert!(reports[1].problems.is_empty());
+    }
+
+    #[test]
+    fn test_required_namespaces() {
+        let methods: Vec<HealthCheckMethod> = serde_json::from_value(serde_json::json!([
+            { "method": "net_version", "params": [] },
+            { "method": "eth_blockNumber", "params": [] },
+        ]))
+        .unwrap();
+        assert_eq!(
+            required_namespaces(&methods)
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["eth", "net"]
+        );
+    }
+
+    #[test]
+    fn test_required_namespaces_per_group() {
+        let method = |method: &str| -> HealthCheckMethod {
+            serde_json::from_value(serde_json::json!({ "method": method, "params": [] })).unwrap()
+        };
+        let health_check_methods = HealthCheckMethods {
+            all: vec![method("eth_blockNumber")],
+            groups: HashMap::from([
+                (
+                    "archive".to_string(),
+                    vec![method("debug_traceBlockByNumber")],
+                ),
+                (
+                    "validators".to_string(),
+                    vec![method("engine_exchangeCapabilities")],
+                ),
+            ]),
+        };
+
+        let mut rpc = Rpc::default();
+        assert_eq!(
+            required_namespaces(health_check_methods.for_rpc(&rpc))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["eth"]
+        );
+
+        rpc.groups = vec!["archive".to_string()];
+        assert_eq!(
+            required_namespaces(health_check_methods.for_rpc(&rpc))
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["debug", "eth"]
+        );
+    }
+}
@@ -8,7 +8,10 @@
 
 use clap::builder::styling;
 
-use crate::rpc::types::Rpc;
+use crate::{
+    database::serialization::CacheFormat,
+    rpc::types::Rpc,
+};
 
 /// The terminal output style configuration.
 pub const TERM_STYLE: styling::Styles = styling::Styles::styled()
@@ -51,6 +54,10 @@ pub struct Blutgang {
     #[arg(long, short = 'p', help_heading = CORE_OPTS)]
     pub port: Option<u16>,
 
+    /// Unix socket to also listen to.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub socket: Option<std::path::PathBuf>,
+
     /// Latency moving average length.
     #[arg(long, help_heading = CORE_OPTS)]
     pub ma_length: Option<f64>,
@@ -71,6 +78,42 @@ pub struct Blutgang {
     #[arg(long, help_heading = CORE_OPTS)]
     pub health_check_ttl: Option<u64>,
 
+    /// How many blocks an RPC can fall behind the head before it gets removed.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub max_head_lag: Option<u64>,
+
+    /// Divergent cross-checks in a row before an RPC is quarantined from a method family. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub quarantine_threshold: Option<u32>,
+
+    /// Time between synthetic latency probes in ms. 0 disables probing.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub latency_probe_interval: Option<u64>,
+
+    /// Time in ms an RPC's head can go without advancing before it gets removed. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub head_staleness: Option<u64>,
+
+    /// How many RPC health transitions to keep for `blutgang_health_events`. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub health_event_history: Option<usize>,
+
+    /// How many contracts and functions to track for `blutgang_request_heatmap`. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub request_heatmap: Option<usize>,
+
+    /// Bytes of the most read cache entries to keep in memory. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub hot_cache_size: Option<usize>,
+
+    /// Logs of finalized blocks to keep for serving overlapping `eth_getLogs` ranges. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub log_cache_size: Option<usize>,
+
+    /// Cache entries to remember the method and block of, so admin can purge them. 0 disables.
+    #[arg(long, help_heading = CORE_OPTS)]
+    pub cache_index_size: Option<usize>,
+
     /// Clear cache.
     #[arg(long, help_heading = CORE_OPTS)]
     pub clear_cache: bool,
@@ -108,6 +151,10 @@ pub struct Blutgang {
     #[arg(long, short = 'D', help_heading = CACHE_OPTS)]
     pub db: Option<Db>,
 
+    /// Format in which responses are stored in the cache.
+    #[arg(long, help_heading = CACHE_OPTS)]
+    pub cache_format: Option<CacheFormat>,
+
     // -- Admin Namespace Options
     //
     /// Path to a privileged admin config.
@@ -128,6 +175,10 @@ pub struct Blutgang {
     #[arg(long, help_heading = ADMIN_OPTS)]
     pub admin_port: Option<u16>,
 
+    /// Unix socket to also listen to for the admin namespace.
+    #[arg(long, help_heading = ADMIN_OPTS)]
+    pub admin_socket: Option<std::path::PathBuf>,
+
     /// Make the admin namespace readonly.
     #[arg(long, help_heading = ADMIN_OPTS)]
     pub admin_readonly: bool,
@@ -143,6 +194,15 @@ pub struct Blutgang {
     /// JWT token.
     #[arg(long, help_heading = ADMIN_OPTS)]
     pub admin_key: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+pub enum Command {
+    /// Check the config and the RPCs in it, print a report and exit without starting.
+    CheckConfig,
 }
 
 #[derive(Debug, clap::Args, Clone)]
@@ -200,4 +260,7 @@ pub(crate) enum Db {
 
     #[clap(name = "rocksdb")]
     RocksDb,
+
+    #[cfg(feature = "redb")]
+    Redb,
 }
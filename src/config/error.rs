@@ -24,4 +24,37 @@ pub enum ConfigError {
         config: path::PathBuf,
         err: toml::de::Error,
     },
+
+    #[error("invalid `[blutgang.{section}]` section: {err}")]
+    InvalidSection {
+        section: String,
+        err: toml::de::Error,
+    },
+
+    #[error("invalid cache policy for `{method}`: {policy}")]
+    InvalidCachePolicy { method: String, policy: String },
+
+    #[error("convergence quorum has to be above 0.0 and at most 1.0, got {0}")]
+    InvalidQuorum(f64),
+
+    #[error("slow request sample_percent has to be between 0 and 100, got {0}")]
+    InvalidSamplePercent(f64),
+
+    #[error("environment variable '{0}' is not set")]
+    MissingEnv(String),
+
+    #[error("failed to read secret file '{}': {err:?}", path.display())]
+    SecretFile { path: path::PathBuf, err: io::Error },
+
+    #[error("invalid secret for '{0}', expected a string or a table with `env` or `file`")]
+    InvalidSecret(String),
+
+    #[error("invalid header '{0}'")]
+    InvalidHeader(String),
+
+    #[error("invalid rpc tls settings: {0}")]
+    InvalidTls(String),
+
+    #[error("invalid rpc proxy: {0}")]
+    InvalidProxy(String),
 }
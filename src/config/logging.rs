@@ -0,0 +1,136 @@
+//! Setting up logging in the format picked with `log_format`.
+//!
+//! Text logs go through `rust_tracing`. JSON logs are written to stdout, one
+//! object per line, along with the fields of the spans the event happened in.
+//! Either way, spans are exported over OTLP when the `OTEL_*` environment
+//! variables configure an endpoint. Every log line of a request
+//! carries its method, RPC, cache status and client that way, which makes
+//! them easy to filter and aggregate.
+
+use crate::config::types::LogFormat;
+
+use rust_tracing::utils::otlp::{
+    OtelConfig,
+    OtelGuard,
+};
+use tracing::Subscriber;
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    layer::SubscriberExt,
+    registry::LookupSpan,
+    EnvFilter,
+};
+
+/// Level logged at if `RUST_LOG` isn't set.
+const DEFAULT_LEVEL: &str = "info";
+
+fn init_text() -> Option<OtelGuard> {
+    #[cfg(feature = "journald")]
+    {
+        rust_tracing::trace_with_journald()
+    }
+
+    #[cfg(not(feature = "journald"))]
+    {
+        rust_tracing::trace()
+    }
+}
+
+fn json_subscriber<W>(make_writer: W) -> impl Subscriber + for<'a> LookupSpan<'a> + Send + Sync
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LEVEL));
+
+    tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_current_span(true)
+        .with_span_list(true)
+        .with_env_filter(filter)
+        .with_writer(make_writer)
+        .finish()
+}
+
+/// Install the global subscriber for `format`.
+///
+/// Spans are only exported while the returned guard lives.
+pub fn init_logging(format: LogFormat) -> Option<OtelGuard> {
+    match format {
+        LogFormat::Text => init_text(),
+        LogFormat::Json => {
+            let subscriber = json_subscriber(std::io::stdout);
+            let guard = OtelConfig::load().map(|config| config.provider());
+            let result = match &guard {
+                Some(guard) => {
+                    tracing::subscriber::set_global_default(subscriber.with(guard.layer()))
+                }
+                None => tracing::subscriber::set_global_default(subscriber),
+            };
+            if let Err(err) = result {
+                eprintln!("Failed to set up JSON logging: {err}");
+            }
+            guard
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::{
+        io,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_json_request_fields() {
+        let buffer = Buffer::default();
+        tracing::subscriber::with_default(json_subscriber(buffer.clone()), || {
+            let span = tracing::info_span!(
+                "request",
+                method = tracing::field::Empty,
+                client = "127.0.0.1:5000",
+                cache = tracing::field::Empty,
+            );
+            let _entered = span.enter();
+            span.record("method", "eth_call");
+            span.record("cache", "hit");
+            tracing::info!(latency_ms = 3, "Request served");
+        });
+
+        let output = buffer.0.lock().unwrap();
+        let line: Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(line["message"], "Request served");
+        assert_eq!(line["latency_ms"], 3);
+        assert_eq!(line["span"]["method"], "eth_call");
+        assert_eq!(line["span"]["client"], "127.0.0.1:5000");
+        assert_eq!(line["span"]["cache"], "hit");
+    }
+}
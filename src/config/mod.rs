@@ -1,11 +1,18 @@
 //! # `config` module
 //!
-//! The config module is used on initial startup to configure Blutgang for use.
+//! The config module is used on initial startup to configure Blutgang for use,
+//! and to reload the config while running.
 //! Includes parsing of the TOML config, CLI args, and various system parameters.
 
 pub mod cache_setup;
+pub mod check;
 pub mod cli_args;
 pub mod error;
+pub mod headers;
+pub mod logging;
+pub mod proxy;
+pub mod reload;
 pub mod setup;
 pub mod system;
 pub mod types;
+pub mod upstream_tls;
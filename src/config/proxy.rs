@@ -0,0 +1,68 @@
+//! Outbound proxies for connections to an RPC.
+//!
+//! RPCs behind an egress proxy, or reached over Tor, get a `proxy` url:
+//!
+//! ```toml
+//! [[rpc]]
+//! url = "https://provider.example"
+//! proxy = "socks5h://127.0.0.1:9050"
+//! ```
+//!
+//! `http://` and `https://` proxies are used with CONNECT, `socks5://`
+//! resolves the RPC host locally and `socks5h://` has the proxy resolve it.
+//! Credentials go in the url, which can be read from `env` or `file` like
+//! headers. Only HTTP requests go through the proxy.
+
+use crate::config::{
+    error::ConfigError,
+    headers::resolve_secret,
+};
+
+use toml::Value;
+use url::Url;
+
+const SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// Proxy of the `[[rpc]]` table `rpc`, if it has one.
+pub fn rpc_proxy(rpc: &Value) -> Result<Option<Url>, ConfigError> {
+    let Some(proxy) = rpc.get("proxy") else {
+        return Ok(None);
+    };
+    let proxy = resolve_secret("proxy", proxy)?;
+    if proxy.is_empty() {
+        return Ok(None);
+    }
+
+    let url = Url::parse(&proxy).map_err(|err| ConfigError::InvalidProxy(err.to_string()))?;
+    if !SCHEMES.contains(&url.scheme()) {
+        return Err(ConfigError::InvalidProxy(format!(
+            "unsupported scheme `{}`",
+            url.scheme()
+        )));
+    }
+
+    Ok(Some(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_proxy() {
+        let rpc: Value = toml::from_str(r#"url = "https://node.internal""#).unwrap();
+        assert_eq!(rpc_proxy(&rpc).unwrap(), None);
+
+        let rpc: Value = toml::from_str(r#"proxy = "socks5h://127.0.0.1:9050""#).unwrap();
+        assert_eq!(
+            rpc_proxy(&rpc).unwrap().unwrap().as_str(),
+            "socks5h://127.0.0.1:9050"
+        );
+
+        let rpc: Value = toml::from_str(r#"proxy = "ftp://proxy.internal""#).unwrap();
+        assert!(matches!(rpc_proxy(&rpc), Err(ConfigError::InvalidProxy(_))));
+
+        let rpc: Value = toml::from_str(r#"proxy = "not a url""#).unwrap();
+        assert!(matches!(rpc_proxy(&rpc), Err(ConfigError::InvalidProxy(_))));
+    }
+}
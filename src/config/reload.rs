@@ -0,0 +1,415 @@
+//! Reloading the config without restarting.
+//!
+//! The config is parsed again on SIGHUP, and whenever the file changes if
+//! `config_watch_interval_ms` is set. RPCs are matched to the ones we already
+//! have by their url:
+//!
+//! - ones still in the config keep their status and latency history, and take
+//!   the new limits and `ws_url`, except limits changed through admin,
+//! - new ones get probed, and are added to the active pool if they respond or
+//!   the poverty list if they don't, where health checks can bring them back.
+//!   Their capabilities are discovered too, see `health::capabilities`,
+//! - ones gone from the config are removed from both lists, unless they were
+//!   added through admin. Requests already sent to them finish as usual.
+//!
+//! If any WS endpoints changed, the WS manager reconnects to the new ones.
+//!
+//! Every other setting is replaced, so anything read while serving, like the
+//! TTLs, applies right away. Settings only used on startup, like `address` or
+//! the cache, keep their old values until a restart. TLS certificates reload
+//! on their own, see `balancer::tls`.
+
+use crate::{
+    health::capabilities::probe_capabilities,
+    websocket::types::WsconnMessage,
+    Rpc,
+    Settings,
+};
+
+use std::{
+    path::Path,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+
+use futures::future::join_all;
+use rust_tracing::deps::metrics;
+use tokio::{
+    sync::mpsc,
+    time::{
+        interval,
+        timeout,
+    },
+};
+
+/// What a reload changed about the RPCs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RpcChanges {
+    pub added: Vec<String>,
+    /// Added, but to the poverty list since they didn't respond.
+    pub unreachable: Vec<String>,
+    pub removed: Vec<String>,
+    pub kept: usize,
+    /// Whether any WS endpoints were added, removed or changed.
+    pub ws_changed: bool,
+}
+
+/// Returns the `configured` RPCs that aren't in either list yet.
+fn new_rpcs(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    configured: &[Rpc],
+) -> Vec<Rpc> {
+    let rpc_list = rpc_list.read().unwrap_or_else(|e| e.into_inner());
+    let poverty_list = poverty_list.read().unwrap_or_else(|e| e.into_inner());
+
+    configured
+        .iter()
+        .filter(|rpc| {
+            !rpc_list
+                .iter()
+                .chain(poverty_list.iter())
+                .any(|known| known.same_url(rpc))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Returns the RPCs of `rpcs` that don't respond to `eth_blockNumber` within `ttl` ms.
+async fn unreachable_rpcs(rpcs: Vec<Rpc>, ttl: u128) -> Vec<Rpc> {
+    let ttl = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+    let probes = join_all(rpcs.into_iter().map(|rpc| {
+        async move {
+            match timeout(ttl, rpc.block_number()).await {
+                Ok(Ok(_)) => None,
+                Ok(Err(err)) => {
+                    tracing::warn!(%err, rpc = %rpc.name, "New RPC failed to respond");
+                    Some(rpc)
+                }
+                Err(_) => {
+                    tracing::warn!(rpc = %rpc.name, "New RPC timed out");
+                    Some(rpc)
+                }
+            }
+        }
+    }))
+    .await;
+
+    probes.into_iter().flatten().collect()
+}
+
+/// Apply `configured` RPCs to the active and poverty lists.
+///
+/// New RPCs in `unreachable` go to the poverty list.
+pub fn apply_rpcs(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    configured: &[Rpc],
+    unreachable: &[Rpc],
+) -> RpcChanges {
+    let mut changes = RpcChanges::default();
+    let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+    let mut poverty_list = poverty_list.write().unwrap_or_else(|e| e.into_inner());
+
+    for list in [&mut *rpc_list, &mut *poverty_list] {
+        list.retain_mut(|rpc| {
+            match configured
+                .iter()
+                .find(|configured| configured.same_url(rpc))
+            {
+                Some(configured) => {
+                    changes.ws_changed |= rpc.ws_url != configured.ws_url;
+                    rpc.update_limits(configured);
+                    // It's in the config now
+                    rpc.runtime = false;
+                    changes.kept += 1;
+                    true
+                }
+                None if rpc.runtime => true,
+                None => {
+                    changes.ws_changed |= rpc.ws_url.is_some();
+                    changes.removed.push(rpc.name.clone());
+                    false
+                }
+            }
+        });
+    }
+
+    for rpc in configured {
+        let known = rpc_list
+            .iter()
+            .chain(poverty_list.iter())
+            .any(|known| known.same_url(rpc));
+        if known {
+            continue;
+        }
+
+        changes.ws_changed |= rpc.ws_url.is_some();
+        changes.added.push(rpc.name.clone());
+        if unreachable
+            .iter()
+            .any(|unreachable| unreachable.same_url(rpc))
+        {
+            changes.unreachable.push(rpc.name.clone());
+            poverty_list.push(rpc.clone());
+        } else {
+            rpc_list.push(rpc.clone());
+        }
+    }
+
+    changes
+}
+
+/// Replace `config` with `new`, keeping the settings only used on startup.
+fn apply_settings(config: &RwLock<Settings>, mut new: Settings) {
+    let mut config = config.write().unwrap_or_else(|e| e.into_inner());
+
+    new.addresses = config.addresses.clone();
+    new.socket = config.socket.clone();
+    new.socket_mode = config.socket_mode;
+    new.tls = config.tls.clone();
+    new.cache = config.cache.clone();
+    new.counters = config.counters.clone();
+    new.is_ws = config.is_ws;
+    new.do_clear = config.do_clear;
+    new.admin.enabled = config.admin.enabled;
+    new.admin.address = config.admin.address;
+
+    *config = new;
+}
+
+/// Parse the config again and apply it.
+async fn reload(
+    config: &RwLock<Settings>,
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    incoming_tx: Option<&mpsc::UnboundedSender<WsconnMessage>>,
+) {
+    tracing::info!("Reloading config...");
+
+    // Parsing panics on some invalid values, which shouldn't take us down
+    let mut new = match tokio::task::spawn_blocking(Settings::new).await {
+        Ok(Ok(new)) => new,
+        Ok(Err(err)) => {
+            tracing::error!(%err, "Failed to reload config! Keeping the current one.");
+            metrics::counter!("config_reloads_total", "status" => "failed").increment(1);
+            return;
+        }
+        Err(err) => {
+            tracing::error!(?err, "Invalid config! Keeping the current one.");
+            metrics::counter!("config_reloads_total", "status" => "failed").increment(1);
+            return;
+        }
+    };
+
+    // Probe without holding the locks, new RPCs might take a while to answer
+    let added = new_rpcs(rpc_list, poverty_list, &new.rpc_list);
+    let unreachable = unreachable_rpcs(added.clone(), new.ttl).await;
+    let ttl = new.ttl;
+    probe_capabilities(
+        new.rpc_list.iter_mut().filter(|rpc| {
+            added.iter().any(|added| added.same_url(rpc))
+                && !unreachable
+                    .iter()
+                    .any(|unreachable| unreachable.same_url(rpc))
+        }),
+        ttl,
+    )
+    .await;
+
+    let changes = apply_rpcs(rpc_list, poverty_list, &new.rpc_list, &unreachable);
+    apply_settings(config, new);
+
+    if changes.ws_changed {
+        if let Some(incoming_tx) = incoming_tx {
+            let _ = incoming_tx.send(WsconnMessage::Reconnect());
+        }
+    }
+
+    tracing::info!(
+        added = ?changes.added,
+        unreachable = ?changes.unreachable,
+        removed = ?changes.removed,
+        kept = changes.kept,
+        "Reloaded config"
+    );
+    metrics::counter!("config_reloads_total", "status" => "ok").increment(1);
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Completes on the next SIGHUP.
+#[cfg(unix)]
+async fn hangup(hangup: &mut Option<tokio::signal::unix::Signal>) {
+    match hangup {
+        Some(hangup) => {
+            hangup.recv().await;
+        }
+        None => std::future::pending::<()>().await,
+    }
+}
+
+/// Reload the config on SIGHUP, or when the file changes.
+///
+/// `incoming_tx` is the WS manager, if there's one, told to reconnect when WS endpoints change.
+pub async fn watch_config(
+    config: Arc<RwLock<Settings>>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    incoming_tx: Option<mpsc::UnboundedSender<WsconnMessage>>,
+) {
+    let (path, watch_interval) = {
+        let config = config.read().unwrap_or_else(|e| e.into_inner());
+        (config.config_path.clone(), config.config_watch_interval_ms)
+    };
+
+    #[cfg(unix)]
+    let mut signal = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(err) => {
+            tracing::error!(?err, "Failed to listen for SIGHUP");
+            None
+        }
+    };
+
+    // Only watch if there's a file to watch
+    let mut watch = path.filter(|_| watch_interval != 0).map(|path| {
+        let last_modified = modified(&path);
+        (
+            interval(Duration::from_millis(watch_interval)),
+            path,
+            last_modified,
+        )
+    });
+
+    loop {
+        let changed = async {
+            match watch.as_mut() {
+                Some((ticker, path, last_modified)) => {
+                    loop {
+                        ticker.tick().await;
+                        let modified = modified(path);
+                        if modified != *last_modified {
+                            *last_modified = modified;
+                            break;
+                        }
+                    }
+                }
+                None => std::future::pending::<()>().await,
+            }
+        };
+
+        #[cfg(unix)]
+        tokio::select! {
+            _ = hangup(&mut signal) => {}
+            _ = changed => {}
+        }
+        #[cfg(not(unix))]
+        changed.await;
+
+        reload(&config, &rpc_list, &poverty_list, incoming_tx.as_ref()).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(url: &str, max_consecutive: u32) -> Rpc {
+        Rpc::new(url.parse().unwrap(), None, max_consecutive, 0, 10.0)
+    }
+
+    #[test]
+    fn test_apply_rpcs() {
+        let mut kept = rpc("http://kept.example", 5);
+        kept.status.latency = 42.0;
+        let rpc_list = RwLock::new(vec![kept, rpc("http://removed.example", 5)]);
+        let poverty_list = RwLock::new(vec![rpc("http://poor.example", 5)]);
+
+        let configured = vec![
+            rpc("http://kept.example", 10),
+            rpc("http://poor.example", 1),
+            rpc("http://added.example", 5),
+            rpc("http://down.example", 5),
+        ];
+        let unreachable = vec![rpc("http://down.example", 5)];
+        let changes = apply_rpcs(&rpc_list, &poverty_list, &configured, &unreachable);
+        assert_eq!(
+            changes,
+            RpcChanges {
+                added: vec![
+                    "http://added.example/".to_string(),
+                    "http://down.example/".to_string()
+                ],
+                unreachable: vec!["http://down.example/".to_string()],
+                removed: vec!["http://removed.example/".to_string()],
+                kept: 2,
+                ws_changed: false,
+            }
+        );
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list.len(), 2);
+        // Limits changed, history kept
+        assert_eq!(rpc_list[0].max_consecutive, 10);
+        assert_eq!(rpc_list[0].status.latency, 42.0);
+        assert_eq!(rpc_list[1].name, "http://added.example/");
+
+        let poverty_list = poverty_list.read().unwrap();
+        assert_eq!(poverty_list.len(), 2);
+        assert_eq!(poverty_list[0].max_consecutive, 1);
+        assert_eq!(poverty_list[1].name, "http://down.example/");
+    }
+
+    #[test]
+    fn test_apply_rpcs_keeps_admin_changes() {
+        let mut patched = rpc("http://patched.example", 5);
+        patched.max_consecutive = 20;
+        patched.groups = vec!["archive".to_string()];
+        patched.patched = true;
+        let mut runtime = rpc("http://runtime.example", 5);
+        runtime.runtime = true;
+        let rpc_list = RwLock::new(vec![patched, runtime]);
+        let poverty_list = RwLock::new(Vec::new());
+
+        let mut configured = rpc("http://patched.example", 5);
+        configured.ws_url = Some("ws://patched.example".parse().unwrap());
+        let changes = apply_rpcs(&rpc_list, &poverty_list, &[configured], &[]);
+        assert!(changes.removed.is_empty());
+        assert!(changes.ws_changed);
+
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list.len(), 2);
+        // Admin changes win over the config, the endpoint doesn't
+        assert_eq!(rpc_list[0].max_consecutive, 20);
+        assert_eq!(rpc_list[0].groups, ["archive"]);
+        assert_eq!(
+            rpc_list[0].ws_url,
+            Some("ws://patched.example".parse().unwrap())
+        );
+        assert_eq!(rpc_list[1].name, "http://runtime.example/");
+    }
+
+    #[test]
+    fn test_apply_settings() {
+        let config = RwLock::new(Settings::default());
+        let mut new = Settings::default();
+        new.ttl = 9;
+        new.addresses = vec!["127.0.0.1:1".parse().unwrap()];
+
+        apply_settings(&config, new);
+        let config = config.read().unwrap();
+        assert_eq!(config.ttl, 9);
+        assert_eq!(config.addresses, Settings::default().addresses);
+    }
+}
@@ -87,6 +87,7 @@ pub async fn sort_by_latency(
             StartingLatencyResp::Error(mut rax, e) => {
                 tracing::error!(?e, "Adding to poverty list");
                 rax.status.is_erroring = true;
+                rax.status.is_syncing = matches!(e, ConfigError::Syncing);
                 poverty_list.push(rax);
                 continue;
             }
@@ -1,16 +1,42 @@
 use crate::{
+    admin::auth::{
+        AdminRole,
+        AdminToken,
+    },
+    balancer::{
+        api_keys::ApiKey,
+        blocklist::IpRange,
+        shadow::ShadowRpc,
+    },
     config::{
         cli_args::{
             self,
             Blutgang,
+            Command,
             TERM_STYLE,
         },
         error::ConfigError,
+        headers::{
+            resolve_secret,
+            rpc_headers,
+        },
+        proxy::rpc_proxy,
         setup::sort_by_latency,
         types::{
             rocksdb_config::RocksDbOptionsRepr,
             sled_config::SledConfigRepr,
         },
+        upstream_tls::rpc_tls,
+    },
+    database::serialization::CacheFormat,
+    rpc::{
+        client::{
+            ClientOptions,
+            Http2,
+        },
+        engine::EngineSecret,
+        micro_batch::DEFAULT_MAX_SIZE,
+        quota::ProviderQuota,
     },
     Rpc,
 };
@@ -21,37 +47,77 @@ use clap::{
     ValueEnum,
 };
 use jsonwebtoken::DecodingKey;
+use serde::{
+    de::DeserializeOwned,
+    Deserialize,
+};
 
 use std::{
+    collections::HashMap,
     fmt::{
         self,
         Debug,
     },
-    net::SocketAddr,
+    net::{
+        IpAddr,
+        SocketAddr,
+    },
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
 
 use toml::Value;
 
+#[cfg(feature = "redb")]
+use crate::{
+    config::types::redb_config::RedbConfigRepr,
+    database::types::RedbConfig,
+};
+
+#[cfg(feature = "redb")]
+pub(crate) mod redb_config;
 pub(crate) mod rocksdb_config;
 pub(crate) mod sled_config;
 
 #[derive(Clone)]
 pub struct AdminSettings {
     pub enabled: bool,
+    /// Listen on `address` over TCP.
+    pub tcp: bool,
     pub address: SocketAddr,
+    /// Unix socket to listen on, next to or instead of TCP.
+    pub socket: Option<PathBuf>,
+    /// Permissions the socket gets created with.
+    pub socket_mode: u32,
     pub readonly: bool,
     pub jwt: bool,
     pub key: DecodingKey,
+    /// Bearer tokens admin requests have to present, none if empty.
+    pub tokens: Vec<AdminToken>,
+    /// Validate admin changes before committing them, rolling back ones that fail.
+    pub validate_changes: bool,
+    /// Serve the live status dashboard at `/dashboard`.
+    pub dashboard: bool,
+    /// Directory cache snapshots are written to and read from, disabled if unset.
+    pub snapshot_dir: Option<PathBuf>,
 }
 
 impl Default for AdminSettings {
     fn default() -> Self {
         Self {
             enabled: false,
+            tcp: true,
             address: "127.0.0.1:3001".parse::<SocketAddr>().unwrap(),
+            socket: None,
+            socket_mode: 0o600,
             readonly: false,
             jwt: false,
             key: DecodingKey::from_secret(b""),
+            tokens: Vec::new(),
+            validate_changes: false,
+            dashboard: true,
+            snapshot_dir: None,
         }
     }
 }
@@ -60,28 +126,914 @@ impl Debug for AdminSettings {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "AdminSettings {{")?;
         write!(f, " enabled: {:?}", self.enabled)?;
+        write!(f, ", tcp: {:?}", self.tcp)?;
         write!(f, ", address: {:?}", self.address)?;
+        write!(f, ", socket: {:?}", self.socket)?;
+        write!(f, ", socket_mode: {:o}", self.socket_mode)?;
         write!(f, ", readonly: {:?}", self.readonly)?;
         write!(f, ", jwt: HIDDEN",)?;
+        write!(f, ", tokens: {:?}", self.tokens)?;
+        write!(f, ", validate_changes: {:?}", self.validate_changes)?;
+        write!(f, ", dashboard: {:?}", self.dashboard)?;
+        write!(f, ", snapshot_dir: {:?}", self.snapshot_dir)?;
         write!(f, " }}")
     }
 }
 
+/// Startup gate that holds off on marking Blutgang as ready until
+/// enough RPCs agree on the head of the chain.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ConvergenceSettings {
+    pub enabled: bool,
+    /// Fraction of RPCs that need to agree on the head, above 0.0 and at most 1.0.
+    pub quorum: f64,
+    /// Max distance in blocks between two heads we still consider in agreement.
+    pub max_lag: u64,
+    /// Time between convergence checks in ms.
+    pub interval_ms: u64,
+    /// Mark as ready anyway after this many ms. `0` waits forever.
+    pub timeout_ms: u64,
+}
+
+impl Default for ConvergenceSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            quorum: 0.5,
+            max_lag: 2,
+            interval_ms: 1000,
+            timeout_ms: 60000,
+        }
+    }
+}
+
+/// Extra JSON-RPC call an RPC has to answer successfully to be considered healthy.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HealthCheckMethod {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<serde_json::Value>,
+}
+
+/// Health check methods of every RPC, and of the RPCs in each route group.
+#[derive(Debug, Clone, Default)]
+pub struct HealthCheckMethods {
+    pub all: Vec<HealthCheckMethod>,
+    /// Methods only RPCs in the route group they're under have to answer.
+    pub groups: HashMap<String, Vec<HealthCheckMethod>>,
+}
+
+/// Bounds on JSON payloads we're willing to parse. `0` means unlimited.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct JsonLimits {
+    /// Max nesting depth of objects and arrays.
+    pub max_depth: usize,
+    /// Max number of elements in a single array.
+    pub max_array_len: usize,
+    /// Max number of tokens (strings, scalars, objects and arrays).
+    pub max_tokens: usize,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        Self {
+            max_depth: 128,
+            max_array_len: 1_000_000,
+            max_tokens: 10_000_000,
+        }
+    }
+}
+
+/// Bounds on simultaneous client connections. `0` means unlimited.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct ConnectionLimits {
+    /// Max number of connections open at once.
+    pub max_connections: usize,
+    /// Max number of connections open at once from a single client IP.
+    pub max_connections_per_ip: usize,
+}
+
+/// Serving expired cache entries when no RPC is available, see `stale`.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct StaleServeSettings {
+    pub enabled: bool,
+    /// How long ago in ms entries can have expired to still be served. `0`
+    /// means any time.
+    pub max_stale_ms: u64,
+}
+
+/// Answering trivial methods from the consensus head, see `local_methods`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LocalMethodSettings {
+    pub enabled: bool,
+    /// How old in ms the consensus head can be before `eth_blockNumber` goes
+    /// to the RPCs again.
+    pub max_head_age_ms: u64,
+}
+
+impl Default for LocalMethodSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_head_age_ms: 10_000,
+        }
+    }
+}
+
+/// Logging requests that took too long, see `slow_requests`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct SlowRequestSettings {
+    pub enabled: bool,
+    /// Requests that took longer than this in ms from start to finish are logged.
+    pub threshold_ms: u64,
+    /// Requests RPCs took longer than this in ms to answer are logged. `0`
+    /// only logs by `threshold_ms`.
+    pub upstream_threshold_ms: u64,
+    /// Share of slow requests that get logged, from 0 to 100.
+    pub sample_percent: f64,
+}
+
+impl Default for SlowRequestSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            threshold_ms: 1000,
+            upstream_threshold_ms: 0,
+            sample_percent: 100.0,
+        }
+    }
+}
+
+/// How we wind down on SIGTERM or ctrl-c.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct ShutdownSettings {
+    /// Time in ms in-flight requests get to finish before we exit.
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: 30_000,
+        }
+    }
+}
+
+/// Bounds on the size of client requests. `0` means unlimited.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct RequestLimits {
+    /// Max size of a request body.
+    pub max_body_bytes: usize,
+    /// Max number of requests in a batch.
+    pub max_batch_len: usize,
+    /// Max length of any string in the `params` of a request.
+    pub max_param_bytes: usize,
+    /// Max number of requests in a batch forwarded at the same time.
+    pub max_batch_concurrency: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 5 * 1024 * 1024,
+            max_batch_len: 1000,
+            max_param_bytes: 0,
+            max_batch_concurrency: 16,
+        }
+    }
+}
+
+/// What to do with `eth_getLogs` queries over `max_block_range`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OversizedLogQuery {
+    /// Answer with an error
+    Reject,
+    /// Fetch them in `max_block_range` long chunks
+    #[default]
+    Chunk,
+}
+
+/// Bounds on `eth_getLogs` and `eth_newFilter` queries. `0` means unlimited.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LogLimits {
+    /// Max number of blocks a query can span.
+    pub max_block_range: u64,
+    /// Max number of chunks an oversized `eth_getLogs` query is split into.
+    pub max_chunks: u64,
+    /// Max number of addresses in a filter.
+    pub max_addresses: usize,
+    /// Max number of topics in a filter, across every position.
+    pub max_topics: usize,
+    pub oversized: OversizedLogQuery,
+}
+
+impl Default for LogLimits {
+    fn default() -> Self {
+        Self {
+            max_block_range: 0,
+            max_chunks: 10,
+            max_addresses: 0,
+            max_topics: 0,
+            oversized: OversizedLogQuery::default(),
+        }
+    }
+}
+
+/// Where rate limit and quota counters are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterBackend {
+    /// Per instance, lost on restart
+    #[default]
+    Memory,
+    /// In the cache DB, survives restarts
+    Cache,
+    /// In Redis, shared between instances
+    Redis,
+}
+
+/// Settings for rate limit and quota counter storage.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CounterSettings {
+    pub backend: CounterBackend,
+    /// `redis://[:password@]host[:port][/db]`, only used by the `redis` backend.
+    pub redis_url: String,
+}
+
+impl Default for CounterSettings {
+    fn default() -> Self {
+        Self {
+            backend: CounterBackend::default(),
+            redis_url: "redis://127.0.0.1:6379".to_string(),
+        }
+    }
+}
+
+/// Settings for limiting how many requests each client IP can make.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Requests each client IP can make per second. `0` disables the limit.
+    pub requests_per_second: u64,
+    /// Requests a client can make at once. `0` allows `requests_per_second`.
+    pub burst: u64,
+    /// Proxies in front of us, trusted to tell us who the client is through
+    /// `X-Forwarded-For` or the PROXY protocol.
+    pub trusted_proxies: Vec<IpRange>,
+    /// Read a PROXY protocol header from connections from `trusted_proxies`.
+    pub proxy_protocol: bool,
+}
+
+/// Settings for API keys clients authenticate with.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ApiKeySettings {
+    /// Turn away requests without a key. Otherwise they're served as before.
+    pub required: bool,
+    /// Header clients send their key in. Keys can also be sent as the path.
+    pub header: String,
+    pub keys: Vec<Arc<ApiKey>>,
+}
+
+impl Default for ApiKeySettings {
+    fn default() -> Self {
+        Self {
+            required: false,
+            header: "x-api-key".to_string(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+/// Methods let through by a filter, see `method_filter`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MethodFilter {
+    /// Methods let through, every method if empty.
+    pub allow: Vec<String>,
+    /// Methods blocked, even if they're allowed.
+    pub deny: Vec<String>,
+}
+
+/// Settings for blocking methods, globally and per route group.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MethodFilterSettings {
+    #[serde(flatten)]
+    pub global: MethodFilter,
+    /// Filters only applying to RPCs in the route group they're under.
+    pub groups: HashMap<String, MethodFilter>,
+}
+
+/// Compute unit costs of methods, and budgets of clients, see `compute_units`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ComputeUnitSettings {
+    /// Cost of methods not in `costs`.
+    pub default_cost: u64,
+    /// Cost of methods, with `eth_*` style wildcards.
+    pub costs: HashMap<String, u64>,
+    /// Compute units each client IP can spend every `window_ms`. `0` disables the budget.
+    pub ip_budget: u64,
+    pub window_ms: u64,
+}
+
+impl Default for ComputeUnitSettings {
+    fn default() -> Self {
+        Self {
+            default_cost: 1,
+            costs: HashMap::new(),
+            ip_budget: 0,
+            window_ms: 86_400_000,
+        }
+    }
+}
+
+/// Settings for the cache shared between instances through Redis.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SharedCacheSettings {
+    /// `redis://[:password@]host[:port][/db]`. Empty disables the shared cache.
+    pub url: String,
+    /// Time entries live in Redis. `0` keeps them until Redis evicts them.
+    pub ttl_ms: u64,
+    /// Longest we wait on Redis before treating a lookup as a miss.
+    pub timeout_ms: u64,
+    /// Connections kept open to Redis.
+    pub pool_size: usize,
+    /// Writes that can wait for Redis before new ones get dropped.
+    pub write_queue: usize,
+}
+
+impl Default for SharedCacheSettings {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            ttl_ms: 3600000,
+            timeout_ms: 50,
+            pool_size: 4,
+            write_queue: 4096,
+        }
+    }
+}
+
+/// Settings for TLS on the client-facing listener.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    /// PEM certificate chain. Empty disables TLS.
+    pub cert: PathBuf,
+    /// PEM private key of the certificate.
+    pub key: PathBuf,
+    /// How often to check the files for a new certificate. `0` disables reloading.
+    pub reload_interval_ms: u64,
+    /// Get the certificate from an ACME CA instead of the files.
+    pub acme: AcmeSettings,
+}
+
+impl Default for TlsSettings {
+    fn default() -> Self {
+        Self {
+            cert: PathBuf::new(),
+            key: PathBuf::new(),
+            reload_interval_ms: 60000,
+            acme: AcmeSettings::default(),
+        }
+    }
+}
+
+impl TlsSettings {
+    pub fn enabled(&self) -> bool {
+        !self.cert.as_os_str().is_empty() || self.acme.enabled()
+    }
+}
+
+/// Settings for getting certificates from an ACME CA, like Let's Encrypt.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AcmeSettings {
+    /// Domains on the certificate. Empty disables ACME.
+    pub domains: Vec<String>,
+    /// Contact URLs of the account, like `mailto:ops@example.com`.
+    pub contact: Vec<String>,
+    /// Directory URL of the CA.
+    pub directory: String,
+    /// Where the account and certificate are kept across restarts.
+    pub cache_dir: PathBuf,
+    /// Renew the certificate this many days before it expires.
+    pub renew_before_days: u64,
+}
+
+impl Default for AcmeSettings {
+    fn default() -> Self {
+        Self {
+            domains: Vec::new(),
+            contact: Vec::new(),
+            directory: "https://acme-v02.api.letsencrypt.org/directory".to_string(),
+            cache_dir: PathBuf::from("blutgang-acme"),
+            renew_before_days: 30,
+        }
+    }
+}
+
+impl AcmeSettings {
+    pub fn enabled(&self) -> bool {
+        !self.domains.is_empty()
+    }
+}
+
+/// Settings for hiding what clients query from observers of encrypted traffic.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PrivacySettings {
+    /// Pad response bodies to the next power of two multiple of this many bytes. `0` disables padding.
+    pub pad_bucket_bytes: usize,
+    /// Delay responses to the next multiple of this many ms since the request came in. `0` disables it.
+    pub timing_bucket_ms: u64,
+    /// Methods padding and timing apply to. Empty applies them to every method.
+    pub methods: Vec<String>,
+}
+
+/// Settings for comparing what different RPCs respond, see `response_diff`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResponseDiffSettings {
+    pub enabled: bool,
+    /// Methods to compare, with `eth_*` style wildcards.
+    pub methods: Vec<String>,
+    /// Fields nodes fill in differently, left out of the comparison.
+    pub ignore_fields: Vec<String>,
+    /// Max differences logged per request.
+    pub max_differences: usize,
+    /// Percent of requests for `methods` compared, from 0 to 100.
+    pub sample_percent: f64,
+}
+
+impl Default for ResponseDiffSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            methods: vec!["eth_getLogs".to_string(), "eth_estimateGas".to_string()],
+            ignore_fields: vec![
+                "totalDifficulty".to_string(),
+                "blockTimestamp".to_string(),
+                "yParity".to_string(),
+            ],
+            max_differences: 16,
+            sample_percent: 100.0,
+        }
+    }
+}
+
+/// Settings for answering browsers calling us from other origins.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct CorsSettings {
+    /// Origins allowed to call us, like `https://app.example.com`. `*` allows
+    /// any origin, and `https://*.example.com` any subdomain. Empty disables CORS.
+    pub allowed_origins: Vec<String>,
+    /// Methods preflights are allowed.
+    pub allowed_methods: Vec<String>,
+    /// Request headers preflights are allowed. `*` allows any header.
+    pub allowed_headers: Vec<String>,
+    /// How long browsers can cache preflight responses for in seconds.
+    pub max_age_secs: u64,
+}
+
+impl Default for CorsSettings {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string(), "OPTIONS".to_string()],
+            allowed_headers: vec!["content-type".to_string()],
+            max_age_secs: 86400,
+        }
+    }
+}
+
+/// Encoding responses to clients can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentEncoding {
+    Gzip,
+    #[serde(rename = "br")]
+    Brotli,
+    Zstd,
+}
+
+/// Settings for compressing responses to clients that accept it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ResponseCompressionSettings {
+    /// Encodings we compress with, in order of preference. Empty disables compression.
+    pub encodings: Vec<ContentEncoding>,
+    /// Responses smaller than this many bytes are sent uncompressed.
+    pub min_bytes: usize,
+}
+
+impl Default for ResponseCompressionSettings {
+    fn default() -> Self {
+        Self {
+            encodings: Vec::new(),
+            min_bytes: 1024,
+        }
+    }
+}
+
+/// Settings for the HTTP connections to RPCs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct UpstreamHttpSettings {
+    /// Let the HTTP/2 flow control window grow with the connection's bandwidth.
+    pub http2_adaptive_window: bool,
+    /// Time between HTTP/2 pings keeping idle connections alive in ms. `0` disables pings.
+    pub http2_keep_alive_interval_ms: u64,
+    /// Most idle connections kept open to each RPC.
+    pub pool_max_idle_per_host: usize,
+    /// Time idle connections are kept open for in ms. `0` keeps them open.
+    pub pool_idle_timeout_ms: u64,
+}
+
+impl Default for UpstreamHttpSettings {
+    fn default() -> Self {
+        Self {
+            http2_adaptive_window: true,
+            http2_keep_alive_interval_ms: 0,
+            pool_max_idle_per_host: 32,
+            pool_idle_timeout_ms: 90000,
+        }
+    }
+}
+
+/// Settings for serving cheaper variants of requests when RPCs can't meet a latency budget.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct DowngradeSettings {
+    /// Serve blocks with full transactions as header-only blocks when the fastest RPC is
+    /// slower than this many ms. `0` disables downgrades.
+    pub full_blocks_budget_ms: u64,
+}
+
+/// Settings for fetching new blocks into the cache as soon as they're announced.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default)]
+pub struct CacheWarmingSettings {
+    /// Fetch every new head into the cache. Needs WebSockets and the health check.
+    pub enabled: bool,
+    /// Also fetch new heads with full transactions.
+    pub full_transactions: bool,
+}
+
+/// Settings for reconnecting to RPCs that drop their WebSocket connection.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct WsReconnectSettings {
+    /// Attempts to reconnect before the RPC is considered dropped. `0` drops
+    /// RPCs as soon as they drop their connection.
+    pub max_attempts: u32,
+    /// Time to wait before the first attempt in ms, doubled after every failed one.
+    pub initial_backoff_ms: u64,
+    /// Longest time to wait between attempts in ms.
+    pub max_backoff_ms: u64,
+}
+
+impl Default for WsReconnectSettings {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 250,
+            max_backoff_ms: 10_000,
+        }
+    }
+}
+
+/// Settings for keeping WebSocket connections to clients and RPCs healthy.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct WsConnectionSettings {
+    /// Time between pings we send in ms. `0` disables pings.
+    pub ping_interval_ms: u64,
+    /// Time in ms a connection can go without us receiving anything, pongs
+    /// included, before we close it. `0` disables the timeout.
+    pub idle_timeout_ms: u64,
+    /// Largest message clients can send us, in bytes.
+    pub max_message_size: usize,
+    /// Most notifications waiting to be sent to a client. `0` lets the queue
+    /// grow forever.
+    pub send_queue_size: usize,
+    /// What to do with notifications of `logs` subscriptions once the queue is full.
+    pub logs_overflow: OverflowPolicy,
+    /// What to do with notifications of every other subscription once the
+    /// queue is full.
+    pub overflow: OverflowPolicy,
+    /// Time in ms clients get to finish up on shutdown before we close their connection.
+    pub drain_period_ms: u64,
+}
+
+impl Default for WsConnectionSettings {
+    fn default() -> Self {
+        Self {
+            ping_interval_ms: 30_000,
+            idle_timeout_ms: 90_000,
+            max_message_size: 16 << 20,
+            send_queue_size: 1024,
+            logs_overflow: OverflowPolicy::Disconnect,
+            overflow: OverflowPolicy::DropOldest,
+            drain_period_ms: 5000,
+        }
+    }
+}
+
+/// What to do with a notification for a client whose send queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Drop the oldest notification waiting in the queue. Fine for `newHeads`,
+    /// where only the latest one matters.
+    DropOldest,
+    /// Disconnect the client. For `logs`, where clients can't tell they missed any.
+    Disconnect,
+}
+
+/// Limits on the subscriptions of a single WebSocket client.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct WsSubscriptionLimits {
+    /// Most active subscriptions a client can have. `0` disables the limit.
+    pub max_subscriptions: usize,
+    /// Most addresses a `logs` filter can have. `0` disables the limit.
+    pub max_filter_addresses: usize,
+    /// Most topics a `logs` filter can have, over every position. `0`
+    /// disables the limit.
+    pub max_filter_topics: usize,
+}
+
+impl Default for WsSubscriptionLimits {
+    fn default() -> Self {
+        Self {
+            max_subscriptions: 128,
+            max_filter_addresses: 1000,
+            max_filter_topics: 1000,
+        }
+    }
+}
+
+/// Settings for replaying notifications to clients that reconnect.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct WsReplaySettings {
+    /// Latest `newHeads` notifications kept per subscription. `0` disables replay.
+    pub heads: usize,
+    /// Latest `logs` notifications kept per subscription. `0` disables replay.
+    pub logs: usize,
+    /// Time in ms notifications are kept for, and subscriptions without
+    /// clients kept around for.
+    pub window_ms: u64,
+}
+
+impl Default for WsReplaySettings {
+    fn default() -> Self {
+        Self {
+            heads: 0,
+            logs: 0,
+            window_ms: 60_000,
+        }
+    }
+}
+
+/// Settings for the Prometheus metrics endpoint.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// Serve metrics at `/metrics`.
+    pub enabled: bool,
+    /// Address metrics are served at.
+    pub address: SocketAddr,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:9091".parse::<SocketAddr>().unwrap(),
+        }
+    }
+}
+
+/// Settings for syncing an external blocklist of endpoints and client IP ranges.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BlocklistSettings {
+    /// URL the blocklist JSON is fetched from. Empty disables the blocklist.
+    pub url: String,
+    /// Time between syncs in ms.
+    pub sync_interval_ms: u64,
+}
+
+impl Default for BlocklistSettings {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            sync_interval_ms: 300000,
+        }
+    }
+}
+
+/// Settings for expiring cache entries.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CacheExpirySettings {
+    /// How long cached entries are valid for in ms. `0` means they never expire.
+    pub ttl_ms: u64,
+    /// Randomly stretch or shrink each TTL by up to this fraction, from 0.0 to 1.0.
+    pub jitter: f64,
+    /// Longest entries for blocks above the finalized block are valid for in ms.
+    /// `0` keeps them until they get reorged or expire through `ttl_ms`.
+    pub unfinalized_ttl_ms: u64,
+    /// How long `null` results, like receipts that aren't available yet, are cached for in ms.
+    /// `0` doesn't cache them.
+    pub negative_ttl_ms: u64,
+}
+
+impl Default for CacheExpirySettings {
+    fn default() -> Self {
+        Self {
+            ttl_ms: 0,
+            jitter: 0.1,
+            unfinalized_ttl_ms: 60000,
+            negative_ttl_ms: 0,
+        }
+    }
+}
+
+/// Which entries get evicted first once the cache is over its size cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Least recently used
+    #[default]
+    Lru,
+    /// Least frequently used
+    Lfu,
+}
+
+/// Settings for capping the size of the cache on disk.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CacheEvictionSettings {
+    /// Max size of the cache on disk in MB. `0` lets it grow forever.
+    pub max_size_mb: u64,
+    pub policy: EvictionPolicy,
+    /// Time between size checks in ms.
+    pub check_interval_ms: u64,
+    /// Fraction of the entries evicted every time the cache is over the cap, from 0.0 to 1.0.
+    pub evict_fraction: f64,
+}
+
+impl Default for CacheEvictionSettings {
+    fn default() -> Self {
+        Self {
+            max_size_mb: 0,
+            policy: EvictionPolicy::default(),
+            check_interval_ms: 60000,
+            evict_fraction: 0.1,
+        }
+    }
+}
+
+/// Format logs are written in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines, exported over OTLP if configured
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the request it belongs to
+    Json,
+}
+
+/// Algorithm cached responses are compressed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    /// Store responses as they are
+    #[default]
+    None,
+    /// Better ratio, slower
+    Zstd,
+    /// Faster, worse ratio
+    Lz4,
+}
+
+/// Settings for compressing cached responses.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CacheCompressionSettings {
+    pub algorithm: CompressionAlgorithm,
+    /// zstd compression level, from 1 to 22. `0` uses the zstd default.
+    pub level: i32,
+    /// Responses smaller than this many bytes are stored uncompressed.
+    pub min_bytes: usize,
+}
+
+impl Default for CacheCompressionSettings {
+    fn default() -> Self {
+        Self {
+            algorithm: CompressionAlgorithm::default(),
+            level: 3,
+            min_bytes: 1024,
+        }
+    }
+}
+
+/// Settings for writing responses to the cache in the background.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct CacheWriteSettings {
+    /// Writes waiting to be written before new ones get dropped. `0` writes
+    /// every response on its own, without dropping any.
+    pub queue_size: usize,
+    /// Most writes sent to the DB at once.
+    pub batch_size: usize,
+}
+
+impl Default for CacheWriteSettings {
+    fn default() -> Self {
+        Self {
+            queue_size: 4096,
+            batch_size: 256,
+        }
+    }
+}
+
+/// How responses to a specific method get cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Never cache responses
+    Never,
+    /// Cache responses without them expiring
+    Forever,
+    /// Cache responses until the next block
+    Block,
+    /// Cache responses for this many ms
+    Ttl(u64),
+}
+
+impl CachePolicy {
+    /// Parse `"never"`, `"forever"`, `"block"` or a TTL in ms. A TTL of `0` means never.
+    fn from_toml(value: &Value) -> Option<Self> {
+        match value {
+            Value::String(policy) => {
+                match policy.as_str() {
+                    "never" => Some(CachePolicy::Never),
+                    "forever" => Some(CachePolicy::Forever),
+                    "block" => Some(CachePolicy::Block),
+                    _ => None,
+                }
+            }
+            Value::Integer(0) => Some(CachePolicy::Never),
+            Value::Integer(ttl) => (*ttl).try_into().ok().map(CachePolicy::Ttl),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum CacheSettings {
     Sled(sled::Config),
     RocksDB(rocksdb::Options),
+    #[cfg(feature = "redb")]
+    Redb(RedbConfig),
 }
 
 #[derive(Clone)]
 pub struct Settings {
+    pub config_path: Option<PathBuf>,
+    pub check_config: bool,
     pub rpc_list: Vec<Rpc>,
+    pub shadow_list: Vec<ShadowRpc>,
     pub sort_on_startup: bool,
     pub ma_length: f64,
     pub poverty_list: Vec<Rpc>,
     pub is_ws: bool,
     pub do_clear: bool,
-    pub address: SocketAddr,
+    /// Addresses to listen on for clients.
+    pub addresses: Vec<SocketAddr>,
+    /// Unix socket to listen on for clients, next to or instead of TCP.
+    pub socket: Option<PathBuf>,
+    /// Permissions the socket gets created with.
+    pub socket_mode: u32,
     pub health_check: bool,
     pub header_check: bool,
     pub ttl: u128,
@@ -89,20 +1041,80 @@ pub struct Settings {
     pub supress_rpc_check: bool,
     pub max_retries: u32,
     pub health_check_ttl: u64,
+    pub max_head_lag: u64,
+    pub quarantine_threshold: u32,
+    pub latency_probe_interval_ms: u64,
+    pub head_staleness_ms: u64,
+    pub config_watch_interval_ms: u64,
+    pub aggregate_heads: bool,
+    pub aggregate_pending_txs: bool,
+    pub local_log_filters: bool,
+    pub health_event_history: usize,
+    pub request_heatmap: usize,
+    pub hot_cache_size: usize,
+    pub log_cache_size: usize,
+    pub cache_index_size: usize,
+    pub shared_cache: SharedCacheSettings,
+    pub tls: TlsSettings,
+    pub health_check_methods: HealthCheckMethods,
+    pub validate_requests: bool,
+    pub pin_block_tags: bool,
+    pub debug_checksums: bool,
+    pub allow_no_cache: bool,
+    pub log_format: LogFormat,
+    pub json_limits: JsonLimits,
+    pub connection_limits: ConnectionLimits,
+    pub shutdown: ShutdownSettings,
+    pub stale_serve: StaleServeSettings,
+    pub local_methods: LocalMethodSettings,
+    pub slow_requests: SlowRequestSettings,
+    pub request_limits: RequestLimits,
+    pub log_limits: LogLimits,
+    pub counters: CounterSettings,
+    pub rate_limit: Arc<RateLimitSettings>,
+    pub api_keys: Arc<ApiKeySettings>,
+    pub method_filter: Arc<MethodFilterSettings>,
+    pub response_diff: Arc<ResponseDiffSettings>,
+    pub compute_units: Arc<ComputeUnitSettings>,
+    pub blocklist: BlocklistSettings,
+    pub privacy: Arc<PrivacySettings>,
+    pub cors: Arc<CorsSettings>,
+    pub response_compression: Arc<ResponseCompressionSettings>,
+    pub upstream_http: UpstreamHttpSettings,
+    pub downgrade: Arc<DowngradeSettings>,
+    pub cache_warming: CacheWarmingSettings,
+    pub ws_reconnect: WsReconnectSettings,
+    pub ws_connection: WsConnectionSettings,
+    pub ws_subscription_limits: WsSubscriptionLimits,
+    pub ws_replay: WsReplaySettings,
+    pub metrics: MetricsSettings,
+    pub finalized_divergence_check: bool,
+    pub cache_format: CacheFormat,
+    pub cache_expiry: CacheExpirySettings,
+    pub cache_eviction: CacheEvictionSettings,
+    pub cache_compression: CacheCompressionSettings,
+    pub cache_writes: CacheWriteSettings,
+    pub cache_policies: HashMap<String, CachePolicy>,
     pub cache: CacheSettings,
     pub admin: AdminSettings,
+    pub convergence: ConvergenceSettings,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            config_path: None,
+            check_config: false,
             rpc_list: Vec::new(),
+            shadow_list: Vec::new(),
             sort_on_startup: false,
             ma_length: 100.0,
             poverty_list: Vec::new(),
             is_ws: true,
             do_clear: false,
-            address: "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+            addresses: vec!["127.0.0.1:3000".parse::<SocketAddr>().unwrap()],
+            socket: None,
+            socket_mode: 0o660,
             health_check: false,
             header_check: true,
             ttl: 1000,
@@ -110,12 +1122,87 @@ impl Default for Settings {
             supress_rpc_check: true,
             max_retries: 32,
             health_check_ttl: 1000,
+            max_head_lag: 0,
+            quarantine_threshold: 0,
+            latency_probe_interval_ms: 0,
+            head_staleness_ms: 0,
+            config_watch_interval_ms: 0,
+            aggregate_heads: false,
+            aggregate_pending_txs: false,
+            local_log_filters: false,
+            health_event_history: 256,
+            request_heatmap: 0,
+            hot_cache_size: 33554432,
+            log_cache_size: 10000,
+            cache_index_size: 1000000,
+            shared_cache: SharedCacheSettings::default(),
+            tls: TlsSettings::default(),
+            health_check_methods: HealthCheckMethods::default(),
+            validate_requests: false,
+            pin_block_tags: false,
+            debug_checksums: false,
+            allow_no_cache: true,
+            log_format: LogFormat::default(),
+            json_limits: JsonLimits::default(),
+            connection_limits: ConnectionLimits::default(),
+            shutdown: ShutdownSettings::default(),
+            stale_serve: StaleServeSettings::default(),
+            local_methods: LocalMethodSettings::default(),
+            slow_requests: SlowRequestSettings::default(),
+            request_limits: RequestLimits::default(),
+            log_limits: LogLimits::default(),
+            counters: CounterSettings::default(),
+            rate_limit: Arc::new(RateLimitSettings::default()),
+            api_keys: Arc::new(ApiKeySettings::default()),
+            method_filter: Arc::new(MethodFilterSettings::default()),
+            response_diff: Arc::new(ResponseDiffSettings::default()),
+            compute_units: Arc::new(ComputeUnitSettings::default()),
+            blocklist: BlocklistSettings::default(),
+            privacy: Arc::new(PrivacySettings::default()),
+            cors: Arc::new(CorsSettings::default()),
+            response_compression: Arc::new(ResponseCompressionSettings::default()),
+            upstream_http: UpstreamHttpSettings::default(),
+            downgrade: Arc::new(DowngradeSettings::default()),
+            cache_warming: CacheWarmingSettings::default(),
+            ws_reconnect: WsReconnectSettings::default(),
+            ws_connection: WsConnectionSettings::default(),
+            ws_subscription_limits: WsSubscriptionLimits::default(),
+            ws_replay: WsReplaySettings::default(),
+            metrics: MetricsSettings::default(),
+            finalized_divergence_check: false,
+            cache_format: CacheFormat::default(),
+            cache_expiry: CacheExpirySettings::default(),
+            cache_eviction: CacheEvictionSettings::default(),
+            cache_compression: CacheCompressionSettings::default(),
+            cache_writes: CacheWriteSettings::default(),
+            cache_policies: HashMap::new(),
             cache: CacheSettings::Sled(sled::Config::default()),
             admin: AdminSettings::default(),
+            convergence: ConvergenceSettings::default(),
         }
     }
 }
 
+/// Deserializes the `[blutgang.<name>]` section, if the config has one.
+/// A section that's there but doesn't deserialize is an error rather than
+/// falling back to the defaults.
+fn section<T: DeserializeOwned>(
+    blutgang: Option<&toml::Table>,
+    name: &str,
+) -> Result<Option<T>, ConfigError> {
+    blutgang
+        .and_then(|blutgang| blutgang.get(name))
+        .map(|value| {
+            value.clone().try_into().map_err(|err| {
+                ConfigError::InvalidSection {
+                    section: name.to_string(),
+                    err,
+                }
+            })
+        })
+        .transpose()
+}
+
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
         Self::try_parse(|| Blutgang::command().styles(TERM_STYLE).get_matches())
@@ -145,11 +1232,13 @@ impl Settings {
             Blutgang::from_arg_matches(&matches()).expect("failed to parse command line args");
 
         let mut settings = Self::default();
+        settings.check_config = matches!(args.command, Some(Command::CheckConfig));
 
         let spanned_config = if let Some(config_path) = args
             .config
             .or_else(|| std::fs::canonicalize("./config.toml").ok())
         {
+            settings.config_path = Some(config_path.clone());
             let config_str = std::fs::read_to_string(&config_path).map_err(|err| {
                 ConfigError::ReadError {
                     config: config_path.clone(),
@@ -209,15 +1298,85 @@ impl Settings {
 
                 settings.cache = CacheSettings::RocksDB(rocksdb_config.into());
             }
+            #[cfg(feature = "redb")]
+            cli_args::Db::Redb => {
+                let redb_config: RedbConfigRepr = blutgang
+                    .and_then(|blutgang| blutgang.get("redb"))
+                    .and_then(|config| config.clone().try_into().ok())
+                    .flatten()
+                    .unwrap_or_default();
+
+                settings.cache = CacheSettings::Redb(redb_config.into());
+            }
+        }
+
+        if let Some(cache_format) = args.cache_format.or_else(|| {
+            blutgang.and_then(|blutgang| {
+                blutgang.get("cache_format").and_then(|format| {
+                    format
+                        .as_str()
+                        .and_then(|format| CacheFormat::from_str(format, true).ok())
+                })
+            })
+        }) {
+            settings.cache_format = cache_format;
+        }
+
+        if let Some(cache_expiry) = section(blutgang, "cache_expiry")? {
+            settings.cache_expiry = cache_expiry;
+        }
+
+        if let Some(cache_eviction) = section(blutgang, "cache_eviction")? {
+            settings.cache_eviction = cache_eviction;
+        }
+
+        if let Some(cache_compression) = section(blutgang, "cache_compression")? {
+            settings.cache_compression = cache_compression;
+        }
+
+        if let Some(cache_writes) = section(blutgang, "cache_writes")? {
+            settings.cache_writes = cache_writes;
+        }
+
+        if let Some(cache_policies) = blutgang
+            .and_then(|blutgang| blutgang.get("cache_policy"))
+            .and_then(|cache_policies| cache_policies.as_table())
+        {
+            settings.cache_policies = cache_policies
+                .iter()
+                .map(|(method, policy)| {
+                    CachePolicy::from_toml(policy)
+                        .map(|policy| (method.clone(), policy))
+                        .ok_or_else(|| {
+                            ConfigError::InvalidCachePolicy {
+                                method: method.clone(),
+                                policy: policy.to_string(),
+                            }
+                        })
+                })
+                .collect::<Result<_, _>>()?;
         }
 
         let mut is_ws = true;
 
-        let address = args.address.or(blutgang.and_then(|blutgang| {
-            blutgang
-                .get("address")
-                .and_then(|address| address.as_str().map(ToString::to_string))
-        }));
+        // One address, or a list of them to listen on all
+        let addresses = args
+            .address
+            .map(|address| vec![address])
+            .or(blutgang.and_then(|blutgang| {
+                blutgang.get("address").and_then(|address| {
+                    match address {
+                        Value::String(address) => Some(vec![address.clone()]),
+                        Value::Array(addresses) => {
+                            addresses
+                                .iter()
+                                .map(|address| address.as_str().map(ToString::to_string))
+                                .collect()
+                        }
+                        _ => None,
+                    }
+                })
+            }));
         let port = args.port.or(blutgang.and_then(|blutgang| {
             blutgang.get("port").and_then(|port| {
                 port.as_integer().map(|port| {
@@ -226,10 +1385,37 @@ impl Settings {
                 })
             })
         }));
-        if let Some((addr, port)) = address.zip(port) {
-            settings.address = format!("{addr}:{port}")
-                .parse::<SocketAddr>()
-                .expect("failed to parse socket address");
+        if let Some((addresses, port)) = addresses.zip(port) {
+            settings.addresses = addresses
+                .iter()
+                .map(|addr| {
+                    // IPv6 addresses can be written with or without brackets
+                    let ip = addr
+                        .trim_start_matches('[')
+                        .trim_end_matches(']')
+                        .parse::<IpAddr>()
+                        .expect("failed to parse socket address");
+                    SocketAddr::new(ip, port)
+                })
+                .collect();
+        }
+
+        settings.socket = args.socket.or(blutgang.and_then(|blutgang| {
+            blutgang
+                .get("socket")
+                .and_then(|socket| socket.as_str())
+                .filter(|socket| !socket.is_empty())
+                .map(PathBuf::from)
+        }));
+        if let Some(socket_mode) = blutgang.and_then(|blutgang| {
+            blutgang.get("socket_mode").and_then(|socket_mode| {
+                socket_mode.as_integer().map(|i| {
+                    i.try_into()
+                        .expect("failed to parse socket_mode into `u32`")
+                })
+            })
+        }) {
+            settings.socket_mode = socket_mode;
         }
 
         if let Some(ma_length) = args.ma_length.or(blutgang.and_then(|blutgang| {
@@ -294,6 +1480,353 @@ impl Settings {
             settings.health_check_ttl = health_check_ttl;
         }
 
+        if let Some(max_head_lag) = args.max_head_lag.or(blutgang.and_then(|blutgang| {
+            blutgang.get("max_head_lag").and_then(|lag| {
+                lag.as_integer().map(|lag| {
+                    lag.try_into()
+                        .expect("failed to convert `max_head_lag` into `u64`")
+                })
+            })
+        })) {
+            settings.max_head_lag = max_head_lag;
+        }
+
+        if let Some(quarantine_threshold) =
+            args.quarantine_threshold.or(blutgang.and_then(|blutgang| {
+                blutgang.get("quarantine_threshold").and_then(|threshold| {
+                    threshold.as_integer().map(|threshold| {
+                        threshold
+                            .try_into()
+                            .expect("failed to convert `quarantine_threshold` into `u32`")
+                    })
+                })
+            }))
+        {
+            settings.quarantine_threshold = quarantine_threshold;
+        }
+
+        if let Some(latency_probe_interval) =
+            args.latency_probe_interval
+                .or(blutgang.and_then(|blutgang| {
+                    blutgang
+                        .get("latency_probe_interval_ms")
+                        .and_then(|interval| {
+                            interval.as_integer().map(|interval| {
+                                interval.try_into().expect(
+                                    "failed to convert `latency_probe_interval_ms` into `u64`",
+                                )
+                            })
+                        })
+                }))
+        {
+            settings.latency_probe_interval_ms = latency_probe_interval;
+        }
+
+        if let Some(head_staleness) = args.head_staleness.or(blutgang.and_then(|blutgang| {
+            blutgang.get("head_staleness_ms").and_then(|staleness| {
+                staleness.as_integer().map(|staleness| {
+                    staleness
+                        .try_into()
+                        .expect("failed to convert `head_staleness_ms` into `u64`")
+                })
+            })
+        })) {
+            settings.head_staleness_ms = head_staleness;
+        }
+
+        if let Some(config_watch_interval) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("config_watch_interval_ms")
+                .and_then(|interval| {
+                    interval.as_integer().map(|interval| {
+                        interval
+                            .try_into()
+                            .expect("failed to convert `config_watch_interval_ms` into `u64`")
+                    })
+                })
+        }) {
+            settings.config_watch_interval_ms = config_watch_interval;
+        }
+
+        if let Some(aggregate_heads) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("aggregate_heads")
+                .and_then(|aggregate| aggregate.as_bool())
+        }) {
+            settings.aggregate_heads = aggregate_heads;
+        }
+
+        if let Some(aggregate_pending_txs) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("aggregate_pending_txs")
+                .and_then(|aggregate| aggregate.as_bool())
+        }) {
+            settings.aggregate_pending_txs = aggregate_pending_txs;
+        }
+
+        if let Some(local_log_filters) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("local_log_filters")
+                .and_then(|filters| filters.as_bool())
+        }) {
+            settings.local_log_filters = local_log_filters;
+        }
+
+        if let Some(health_event_history) =
+            args.health_event_history.or(blutgang.and_then(|blutgang| {
+                blutgang.get("health_event_history").and_then(|history| {
+                    history.as_integer().map(|history| {
+                        history
+                            .try_into()
+                            .expect("failed to convert `health_event_history` into `usize`")
+                    })
+                })
+            }))
+        {
+            settings.health_event_history = health_event_history;
+        }
+
+        if let Some(request_heatmap) = args.request_heatmap.or(blutgang.and_then(|blutgang| {
+            blutgang.get("request_heatmap").and_then(|heatmap| {
+                heatmap.as_integer().map(|heatmap| {
+                    heatmap
+                        .try_into()
+                        .expect("failed to convert `request_heatmap` into `usize`")
+                })
+            })
+        })) {
+            settings.request_heatmap = request_heatmap;
+        }
+
+        if let Some(hot_cache_size) = args.hot_cache_size.or(blutgang.and_then(|blutgang| {
+            blutgang.get("hot_cache_size").and_then(|size| {
+                size.as_integer().map(|size| {
+                    size.try_into()
+                        .expect("failed to convert `hot_cache_size` into `usize`")
+                })
+            })
+        })) {
+            settings.hot_cache_size = hot_cache_size;
+        }
+
+        if let Some(log_cache_size) = args.log_cache_size.or(blutgang.and_then(|blutgang| {
+            blutgang.get("log_cache_size").and_then(|size| {
+                size.as_integer().map(|size| {
+                    size.try_into()
+                        .expect("failed to convert `log_cache_size` into `usize`")
+                })
+            })
+        })) {
+            settings.log_cache_size = log_cache_size;
+        }
+
+        if let Some(cache_index_size) = args.cache_index_size.or(blutgang.and_then(|blutgang| {
+            blutgang.get("cache_index_size").and_then(|size| {
+                size.as_integer().map(|size| {
+                    size.try_into()
+                        .expect("failed to convert `cache_index_size` into `usize`")
+                })
+            })
+        })) {
+            settings.cache_index_size = cache_index_size;
+        }
+
+        if let Some(health_check_methods) = blutgang
+            .and_then(|blutgang| blutgang.get("health_check_methods"))
+            .map(|methods| {
+                methods
+                    .clone()
+                    .try_into()
+                    .expect("failed to parse `health_check_methods`")
+            })
+        {
+            settings.health_check_methods.all = health_check_methods;
+        }
+
+        if let Some(groups) = blutgang
+            .and_then(|blutgang| blutgang.get("health_check_groups"))
+            .map(|groups| {
+                groups
+                    .clone()
+                    .try_into()
+                    .expect("failed to parse `health_check_groups`")
+            })
+        {
+            settings.health_check_methods.groups = groups;
+        }
+
+        if let Some(validate_requests) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("validate_requests")
+                .and_then(|validate| validate.as_bool())
+        }) {
+            settings.validate_requests = validate_requests;
+        }
+
+        if let Some(pin_block_tags) = blutgang
+            .and_then(|blutgang| blutgang.get("pin_block_tags").and_then(|pin| pin.as_bool()))
+        {
+            settings.pin_block_tags = pin_block_tags;
+        }
+
+        if let Some(debug_checksums) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("debug_checksums")
+                .and_then(|checksums| checksums.as_bool())
+        }) {
+            settings.debug_checksums = debug_checksums;
+        }
+
+        if let Some(allow_no_cache) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("allow_no_cache")
+                .and_then(|allow| allow.as_bool())
+        }) {
+            settings.allow_no_cache = allow_no_cache;
+        }
+
+        if let Some(log_format) = blutgang
+            .and_then(|blutgang| blutgang.get("log_format"))
+            .map(|log_format| {
+                log_format
+                    .clone()
+                    .try_into()
+                    .expect("`log_format` must be `text` or `json`")
+            })
+        {
+            settings.log_format = log_format;
+        }
+
+        if let Some(shared_cache) = section(blutgang, "shared_cache")? {
+            settings.shared_cache = shared_cache;
+        }
+
+        if let Some(json_limits) = section(blutgang, "json_limits")? {
+            settings.json_limits = json_limits;
+        }
+
+        if let Some(connection_limits) = section(blutgang, "connection_limits")? {
+            settings.connection_limits = connection_limits;
+        }
+
+        if let Some(shutdown) = section(blutgang, "shutdown")? {
+            settings.shutdown = shutdown;
+        }
+
+        if let Some(stale_serve) = section(blutgang, "stale_serve")? {
+            settings.stale_serve = stale_serve;
+        }
+
+        if let Some(local_methods) = section(blutgang, "local_methods")? {
+            settings.local_methods = local_methods;
+        }
+
+        if let Some(slow_requests) = section::<SlowRequestSettings>(blutgang, "slow_requests")? {
+            if !(0.0..=100.0).contains(&slow_requests.sample_percent) {
+                return Err(ConfigError::InvalidSamplePercent(
+                    slow_requests.sample_percent,
+                ));
+            }
+            settings.slow_requests = slow_requests;
+        }
+
+        if let Some(request_limits) = section(blutgang, "request_limits")? {
+            settings.request_limits = request_limits;
+        }
+
+        if let Some(log_limits) = section(blutgang, "log_limits")? {
+            settings.log_limits = log_limits;
+        }
+
+        if let Some(tls) = section(blutgang, "tls")? {
+            settings.tls = tls;
+        }
+
+        if let Some(counters) = section(blutgang, "counters")? {
+            settings.counters = counters;
+        }
+
+        if let Some(rate_limit) = section(blutgang, "rate_limit")? {
+            settings.rate_limit = Arc::new(rate_limit);
+        }
+
+        // Falling back to no keys would open up everything they protect
+        if let Some(api_keys) = blutgang.and_then(|blutgang| blutgang.get("api_keys")) {
+            let api_keys: ApiKeySettings = api_keys
+                .clone()
+                .try_into()
+                .unwrap_or_else(|err| panic!("invalid `api_keys`: {err}"));
+            settings.api_keys = Arc::new(api_keys);
+        }
+
+        if let Some(method_filter) = section(blutgang, "method_filter")? {
+            settings.method_filter = Arc::new(method_filter);
+        }
+
+        if let Some(response_diff) = section(blutgang, "response_diff")? {
+            settings.response_diff = Arc::new(response_diff);
+        }
+
+        if let Some(compute_units) = section(blutgang, "compute_units")? {
+            settings.compute_units = Arc::new(compute_units);
+        }
+
+        if let Some(blocklist) = section(blutgang, "blocklist")? {
+            settings.blocklist = blocklist;
+        }
+
+        if let Some(privacy) = section(blutgang, "privacy")? {
+            settings.privacy = Arc::new(privacy);
+        }
+
+        if let Some(cors) = section(blutgang, "cors")? {
+            settings.cors = Arc::new(cors);
+        }
+
+        if let Some(upstream_http) = section(blutgang, "upstream_http")? {
+            settings.upstream_http = upstream_http;
+        }
+
+        if let Some(response_compression) = section(blutgang, "response_compression")? {
+            settings.response_compression = Arc::new(response_compression);
+        }
+
+        if let Some(downgrade) = section(blutgang, "downgrade")? {
+            settings.downgrade = Arc::new(downgrade);
+        }
+
+        if let Some(cache_warming) = section(blutgang, "cache_warming")? {
+            settings.cache_warming = cache_warming;
+        }
+
+        if let Some(ws_reconnect) = section(blutgang, "ws_reconnect")? {
+            settings.ws_reconnect = ws_reconnect;
+        }
+
+        if let Some(ws_connection) = section(blutgang, "ws_connection")? {
+            settings.ws_connection = ws_connection;
+        }
+
+        if let Some(ws_subscription_limits) = section(blutgang, "ws_subscription_limits")? {
+            settings.ws_subscription_limits = ws_subscription_limits;
+        }
+
+        if let Some(ws_replay) = section(blutgang, "ws_replay")? {
+            settings.ws_replay = ws_replay;
+        }
+
+        if let Some(metrics) = section(blutgang, "metrics")? {
+            settings.metrics = metrics;
+        }
+
+        if let Some(finalized_divergence_check) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("finalized_divergence_check")
+                .and_then(|check| check.as_bool())
+        }) {
+            settings.finalized_divergence_check = finalized_divergence_check;
+        }
+
         if args.clear_cache {
             settings.do_clear = args.clear_cache;
         } else if args.no_clear_cache {
@@ -354,6 +1887,13 @@ impl Settings {
             settings.supress_rpc_check = supress_rpc_check;
         }
 
+        if let Some(convergence) = section::<ConvergenceSettings>(blutgang, "convergence")? {
+            if !(convergence.quorum > 0.0 && convergence.quorum <= 1.0) {
+                return Err(ConfigError::InvalidQuorum(convergence.quorum));
+            }
+            settings.convergence = convergence;
+        }
+
         // TODO: @eureka-cpu -- parse admin.toml
         let admin_table =
             blutgang.and_then(|blutgang| blutgang.get("admin").and_then(|admin| admin.as_table()));
@@ -386,6 +1926,29 @@ impl Settings {
                     .expect("failed to parse socket address");
             }
 
+            if let Some(tcp) = admin_table
+                .and_then(|admin_table| admin_table.get("tcp").and_then(|tcp| tcp.as_bool()))
+            {
+                admin_settings.tcp = tcp;
+            }
+            admin_settings.socket = args.admin_socket.or(admin_table.and_then(|admin_table| {
+                admin_table
+                    .get("socket")
+                    .and_then(|socket| socket.as_str())
+                    .filter(|socket| !socket.is_empty())
+                    .map(PathBuf::from)
+            }));
+            if let Some(socket_mode) = admin_table.and_then(|admin_table| {
+                admin_table.get("socket_mode").and_then(|socket_mode| {
+                    socket_mode.as_integer().map(|i| {
+                        i.try_into()
+                            .expect("failed to parse admin socket_mode into `u32`")
+                    })
+                })
+            }) {
+                admin_settings.socket_mode = socket_mode;
+            }
+
             if let Some(readonly) = (args.admin_readonly)
                 .then_some(args.admin_readonly)
                 .or((args.no_admin_readonly).then_some(args.no_admin_readonly))
@@ -418,11 +1981,63 @@ impl Settings {
                 }
             }
 
+            if let Some(validate_changes) = admin_table.and_then(|admin_table| {
+                admin_table
+                    .get("validate_changes")
+                    .and_then(|validate_changes| validate_changes.as_bool())
+            }) {
+                admin_settings.validate_changes = validate_changes;
+            }
+
+            if let Some(tokens) = admin_table.and_then(|admin_table| {
+                admin_table
+                    .get("tokens")
+                    .and_then(|tokens| tokens.as_array())
+            }) {
+                admin_settings.tokens = tokens
+                    .iter()
+                    .map(|entry| {
+                        let token = entry
+                            .get("token")
+                            .and_then(|token| token.as_str())
+                            .filter(|token| !token.is_empty())
+                            .expect("admin token is missing its `token`");
+                        let role: AdminRole = entry
+                            .get("role")
+                            .cloned()
+                            .and_then(|role| role.try_into().ok())
+                            .expect("admin token role has to be `read` or `write`");
+                        AdminToken::new(token, role)
+                    })
+                    .collect();
+            }
+
+            if let Some(dashboard) = admin_table.and_then(|admin_table| {
+                admin_table
+                    .get("dashboard")
+                    .and_then(|dashboard| dashboard.as_bool())
+            }) {
+                admin_settings.dashboard = dashboard;
+            }
+            admin_settings.snapshot_dir = admin_table.and_then(|admin_table| {
+                admin_table
+                    .get("snapshot_dir")
+                    .and_then(|snapshot_dir| snapshot_dir.as_str())
+                    .filter(|snapshot_dir| !snapshot_dir.is_empty())
+                    .map(PathBuf::from)
+            });
+
             settings.admin = admin_settings;
         }
 
         if let Some(rpc_list) = (!args.rpc_list.is_empty())
-            .then_some(args.rpc_list.into_rpcs(settings.ma_length))
+            .then(|| {
+                args.rpc_list
+                    .into_rpcs(settings.ma_length)
+                    .into_iter()
+                    .map(|rpc| (rpc, 0.0))
+                    .collect()
+            })
             .or(config
                 .as_ref()
                 .and_then(|config| config.get("rpc"))
@@ -466,29 +2081,144 @@ impl Settings {
                                 if delta != 0 {
                                     delta = 1_000_000 / delta;
                                 }
-                                if ws_url.is_none() {
+                                let emergency = rpc
+                                    .get("emergency")
+                                    .and_then(|emergency| emergency.as_bool())
+                                    .unwrap_or(false);
+                                let groups: Vec<String> = rpc
+                                    .get("groups")
+                                    .map(|groups| {
+                                        groups
+                                            .clone()
+                                            .try_into()
+                                            .expect("`groups` has to be a list of strings")
+                                    })
+                                    .unwrap_or_default();
+                                let quota: ProviderQuota = rpc
+                                    .clone()
+                                    .try_into()
+                                    .unwrap_or_else(|err| panic!("invalid rpc quota: {err}"));
+                                let ws_transport = rpc
+                                    .get("ws_transport")
+                                    .and_then(|ws_transport| ws_transport.as_bool())
+                                    .unwrap_or(false);
+                                let batch_window_ms: u64 = rpc
+                                    .get("batch_window_ms")
+                                    .and_then(|window| {
+                                        window.as_integer().map(|i| {
+                                            i.try_into().expect(
+                                                "failed to parse `batch_window_ms` into `u64`",
+                                            )
+                                        })
+                                    })
+                                    .unwrap_or(0);
+                                let batch_max_size: usize = rpc
+                                    .get("batch_max_size")
+                                    .and_then(|size| {
+                                        size.as_integer().map(|i| {
+                                            i.try_into().expect(
+                                                "failed to parse `batch_max_size` into `usize`",
+                                            )
+                                        })
+                                    })
+                                    .unwrap_or(DEFAULT_MAX_SIZE);
+                                let client_options = ClientOptions {
+                                    headers: rpc_headers(rpc)
+                                        .unwrap_or_else(|err| panic!("invalid rpc headers: {err}")),
+                                    tls: rpc_tls(rpc).unwrap_or_else(|err| panic!("{err}")),
+                                    compression: rpc
+                                        .get("compression")
+                                        .and_then(|compression| compression.as_bool())
+                                        .unwrap_or(false),
+                                    proxy: rpc_proxy(rpc).unwrap_or_else(|err| panic!("{err}")),
+                                    http: settings.upstream_http,
+                                    http2: rpc
+                                        .get("http2")
+                                        .map(|http2| {
+                                            http2.as_str().and_then(Http2::parse).expect(
+                                                "`http2` has to be `auto`, `never` or `prior_knowledge`",
+                                            )
+                                        })
+                                        .unwrap_or_default(),
+                                    max_streams: rpc
+                                        .get("max_streams")
+                                        .and_then(|max_streams| {
+                                            max_streams.as_integer().map(|i| {
+                                                i.try_into().expect(
+                                                    "failed to parse `max_streams` into `usize`",
+                                                )
+                                            })
+                                        })
+                                        .unwrap_or(0),
+                                };
+                                let engine = rpc.get("jwt_secret").map(|secret| {
+                                    resolve_secret("jwt_secret", secret)
+                                        .map_err(|err| err.to_string())
+                                        .and_then(|secret| {
+                                            EngineSecret::from_hex(&secret)
+                                                .map_err(|err| err.to_string())
+                                        })
+                                        .unwrap_or_else(|err| panic!("invalid `jwt_secret`: {err}"))
+                                });
+                                // Canaries don't serve subscriptions
+                                let shadow_percent = rpc
+                                    .get("shadow_percent")
+                                    .and_then(|percent| {
+                                        percent
+                                            .as_float()
+                                            .or_else(|| percent.as_integer().map(|i| i as f64))
+                                    })
+                                    .unwrap_or(0.0);
+                                if ws_url.is_none() && shadow_percent == 0.0 {
                                     is_ws = false;
                                 }
 
-                                Rpc::new(
+                                let mut rpc = Rpc::new(
                                     url,
                                     ws_url,
                                     max_consecutive,
                                     delta.into(),
                                     settings.ma_length,
-                                )
+                                );
+                                rpc.emergency = emergency;
+                                rpc.engine = engine;
+                                rpc.groups = groups;
+                                rpc.quota = quota;
+                                if ws_transport {
+                                    rpc.enable_ws_transport();
+                                }
+                                rpc.enable_micro_batching(
+                                    Duration::from_millis(batch_window_ms),
+                                    batch_max_size,
+                                );
+                                if client_options.tls.accept_invalid_certs {
+                                    tracing::warn!(
+                                        rpc = %rpc.name,
+                                        "Accepting invalid TLS certificates from RPC!"
+                                    );
+                                }
+                                if !client_options.is_default() {
+                                    rpc.set_client_options(client_options)
+                                        .unwrap_or_else(|err| {
+                                            panic!("failed to build rpc client: {err}")
+                                        });
+                                }
+                                (rpc, shadow_percent)
                             })
-                            .collect::<Vec<Rpc>>()
+                            .collect::<Vec<(Rpc, f64)>>()
                     })
                 }))
         {
-            settings.rpc_list = rpc_list;
+            let (shadow_list, rpc_list): (Vec<_>, Vec<_>) = rpc_list
+                .into_iter()
+                .partition(|(_, shadow_percent)| *shadow_percent > 0.0);
+            settings.rpc_list = rpc_list.into_iter().map(|(rpc, _)| rpc).collect();
+            settings.shadow_list = shadow_list
+                .into_iter()
+                .map(|(rpc, shadow_percent)| ShadowRpc::new(rpc, shadow_percent))
+                .collect();
         }
 
-        if !is_ws {
-            tracing::warn!("WebSocket endpoints not present for all nodes, or newHeads_ttl is 0.");
-            tracing::warn!("Disabling WS only-features. Please check docs for more info.");
-        }
         settings.is_ws = is_ws;
 
         Ok(settings)
@@ -552,4 +2282,131 @@ mod tests {
             rpc_url
         );
     }
+
+    #[test]
+    fn test_ipv6_address() {
+        let settings = super::Settings::try_parse(|| {
+            command(
+                vec![
+                    "--address".to_string(),
+                    "[::1]".to_string(),
+                    "--port".to_string(),
+                    "3005".to_string(),
+                ],
+                true,
+            )
+        })
+        .unwrap();
+
+        assert_eq!(settings.addresses, vec!["[::1]:3005".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_cache_policy_from_toml() {
+        use super::CachePolicy;
+        use toml::Value;
+
+        assert_eq!(
+            CachePolicy::from_toml(&Value::String("forever".to_string())),
+            Some(CachePolicy::Forever)
+        );
+        assert_eq!(
+            CachePolicy::from_toml(&Value::String("block".to_string())),
+            Some(CachePolicy::Block)
+        );
+        assert_eq!(
+            CachePolicy::from_toml(&Value::Integer(3000)),
+            Some(CachePolicy::Ttl(3000))
+        );
+        assert_eq!(
+            CachePolicy::from_toml(&Value::Integer(0)),
+            Some(CachePolicy::Never)
+        );
+        assert_eq!(CachePolicy::from_toml(&Value::Integer(-1)), None);
+        assert_eq!(
+            CachePolicy::from_toml(&Value::String("sometimes".to_string())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_invalid_section() {
+        use super::{
+            section,
+            ShutdownSettings,
+        };
+        use crate::config::error::ConfigError;
+
+        let config: toml::Table =
+            toml::from_str("[shutdown]\ndrain_timeout_ms = \"soon\"").unwrap();
+        assert!(matches!(
+            section::<ShutdownSettings>(Some(&config), "shutdown"),
+            Err(ConfigError::InvalidSection { .. })
+        ));
+        assert!(section::<ShutdownSettings>(Some(&config), "missing")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_invalid_quorum() {
+        use crate::config::error::ConfigError;
+
+        for quorum in ["0.0", "1.5", "nan"] {
+            let path = std::env::temp_dir().join(format!(
+                "blutgang-quorum-{}-{quorum}.toml",
+                std::process::id()
+            ));
+            std::fs::write(
+                &path,
+                format!("[blutgang.convergence]\nquorum = {quorum}\n"),
+            )
+            .unwrap();
+
+            let settings = super::Settings::try_parse(|| {
+                Blutgang::command().get_matches_from([
+                    "blutgang".to_string(),
+                    "-c".to_string(),
+                    path.display().to_string(),
+                ])
+            });
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(
+                matches!(settings, Err(ConfigError::InvalidQuorum(_))),
+                "quorum {quorum} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_sample_percent() {
+        use crate::config::error::ConfigError;
+
+        for sample_percent in ["-1.0", "100.5", "nan"] {
+            let path = std::env::temp_dir().join(format!(
+                "blutgang-sample-{}-{sample_percent}.toml",
+                std::process::id()
+            ));
+            std::fs::write(
+                &path,
+                format!("[blutgang.slow_requests]\nsample_percent = {sample_percent}\n"),
+            )
+            .unwrap();
+
+            let settings = super::Settings::try_parse(|| {
+                Blutgang::command().get_matches_from([
+                    "blutgang".to_string(),
+                    "-c".to_string(),
+                    path.display().to_string(),
+                ])
+            });
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(
+                matches!(settings, Err(ConfigError::InvalidSamplePercent(_))),
+                "sample_percent {sample_percent} should be rejected"
+            );
+        }
+    }
 }
@@ -0,0 +1,30 @@
+use crate::database::types::RedbConfig;
+
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::path::PathBuf;
+
+/// A list of options that can be applied to [`RedbConfig`].
+#[non_exhaustive]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RedbConfigRepr {
+    /// Path to the database file.
+    pub path: Option<PathBuf>,
+    /// Size of redb's page cache in **bytes**. Defaults to redb's own default.
+    pub cache_size_bytes: Option<usize>,
+}
+
+impl From<RedbConfigRepr> for RedbConfig {
+    fn from(repr: RedbConfigRepr) -> Self {
+        let mut opts = Self::default();
+
+        if let Some(path) = repr.path {
+            opts.path = path;
+        }
+        opts.cache_size_bytes = repr.cache_size_bytes;
+
+        opts
+    }
+}
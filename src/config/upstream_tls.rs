@@ -0,0 +1,110 @@
+//! TLS settings for connections to an RPC.
+//!
+//! Nodes behind mutual TLS, or with certificates from a private CA, are set up
+//! per RPC with a `tls` table:
+//!
+//! ```toml
+//! [[rpc]]
+//! url = "https://node.internal:8545"
+//! tls = { ca = "/etc/blutgang/ca.pem", client_cert = "/etc/blutgang/client.pem", client_key = "/etc/blutgang/client.key" }
+//! ```
+//!
+//! - `ca` is a PEM bundle of CAs trusted on top of the system ones,
+//! - `client_cert` and `client_key` are the PEM certificate chain and PKCS#8
+//!   key presented to the node, and have to be set together,
+//! - `accept_invalid_certs` accepts any certificate the node presents. It's
+//!   meant for lab setups with self-signed certificates, as it leaves the
+//!   connection open to anyone in the middle.
+
+use crate::{
+    config::error::ConfigError,
+    rpc::client::UpstreamTls,
+};
+
+use std::path::PathBuf;
+
+use toml::Value;
+
+fn read(tls: &Value, field: &str) -> Result<Option<Vec<u8>>, ConfigError> {
+    let Some(path) = tls.get(field) else {
+        return Ok(None);
+    };
+    let path = path
+        .as_str()
+        .map(PathBuf::from)
+        .ok_or_else(|| ConfigError::InvalidTls(format!("`{field}` has to be a path")))?;
+
+    match std::fs::read(&path) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) => Err(ConfigError::SecretFile { path, err }),
+    }
+}
+
+/// TLS settings of the `[[rpc]]` table `rpc`, from its `tls` table.
+pub fn rpc_tls(rpc: &Value) -> Result<UpstreamTls, ConfigError> {
+    let Some(tls) = rpc.get("tls") else {
+        return Ok(UpstreamTls::default());
+    };
+    if !tls.is_table() {
+        return Err(ConfigError::InvalidTls(
+            "`tls` has to be a table".to_string(),
+        ));
+    }
+
+    let identity = match (read(tls, "client_cert")?, read(tls, "client_key")?) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => {
+            return Err(ConfigError::InvalidTls(
+                "`client_cert` and `client_key` have to be set together".to_string(),
+            ))
+        }
+    };
+
+    Ok(UpstreamTls {
+        ca: read(tls, "ca")?,
+        identity,
+        accept_invalid_certs: tls
+            .get("accept_invalid_certs")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_tls() {
+        let rpc: Value = toml::from_str(r#"url = "https://node.internal""#).unwrap();
+        assert_eq!(rpc_tls(&rpc).unwrap(), UpstreamTls::default());
+
+        let path = std::env::temp_dir().join(format!("blutgang-ca-{}", std::process::id()));
+        std::fs::write(&path, "ca").unwrap();
+        let rpc: Value = toml::from_str(&format!(
+            r#"tls = {{ ca = "{}", accept_invalid_certs = true }}"#,
+            path.display()
+        ))
+        .unwrap();
+        let tls = rpc_tls(&rpc).unwrap();
+        assert_eq!(tls.ca.as_deref(), Some(&b"ca"[..]));
+        assert!(tls.identity.is_none());
+        assert!(tls.accept_invalid_certs);
+
+        // A certificate without its key
+        let rpc: Value = toml::from_str(&format!(
+            r#"tls = {{ client_cert = "{}" }}"#,
+            path.display()
+        ))
+        .unwrap();
+        assert!(matches!(rpc_tls(&rpc), Err(ConfigError::InvalidTls(_))));
+        std::fs::remove_file(&path).unwrap();
+
+        let rpc: Value = toml::from_str(r#"tls = { ca = "/surely/missing/ca.pem" }"#).unwrap();
+        assert!(matches!(rpc_tls(&rpc), Err(ConfigError::SecretFile { .. })));
+
+        let rpc: Value = toml::from_str(r#"tls = "yes""#).unwrap();
+        assert!(matches!(rpc_tls(&rpc), Err(ConfigError::InvalidTls(_))));
+    }
+}
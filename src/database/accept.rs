@@ -1,33 +1,292 @@
-use crate::database::types::{
-    Batch,
-    DbRequest,
-    GenericBytes,
-    GenericDatabase,
-    RequestKind,
+use crate::{
+    config::cache_setup::is_reserved,
+    database::{
+        eviction::{
+            self,
+            CacheEviction,
+        },
+        hot_cache::HotCache,
+        shared_cache::{
+            SharedCache,
+            SharedWrite,
+        },
+        snapshot::{
+            self,
+            SnapshotError,
+        },
+        types::{
+            gauge_db_size,
+            Batch,
+            DbRequest,
+            GenericBytes,
+            GenericDatabase,
+            RequestKind,
+        },
+    },
+};
+
+use std::{
+    collections::HashSet,
+    path::Path,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+    },
+    time::Duration,
 };
+
 use tokio::sync::{
-    mpsc::UnboundedSender,
+    mpsc::{
+        self,
+        UnboundedSender,
+    },
     oneshot::{
         self,
         Receiver,
     },
 };
 
+/// Number of snapshot entries written to the DB at once while importing.
+const IMPORT_BATCH_SIZE: usize = 1024;
+/// Number of snapshot batches that can wait to be written to the DB.
+const IMPORT_QUEUE: usize = 4;
+/// Time between counts of the keys in the DB, see `eviction::count_keys`.
+const KEY_COUNT_INTERVAL: Duration = Duration::from_secs(300);
+/// Time between checks of the size of the DB on disk, see `gauge_db_size`.
+const DB_SIZE_INTERVAL: Duration = Duration::from_secs(15);
+
 /// Processes incoming requests from clients and returns responses
+///
+/// Reads are served from `hot_cache` when possible, and every write
+/// updates it so it never disagrees with the DB.
+///
+/// If `shared_cache` is set, writes are mirrored to it, and reads that miss
+/// locally are looked up there in the background. Values found there are kept
+/// in `hot_cache`.
+///
+/// If `eviction` is enabled, the size of the DB is checked between requests
+/// and the coldest entries get evicted once it's over the cap. The keys to
+/// evict are listed on a blocking thread, so requests are served meanwhile.
+/// Keys are counted for metrics the same way every `KEY_COUNT_INTERVAL`, and
+/// backends that don't gauge their size on writes have it gauged every `DB_SIZE_INTERVAL`.
+///
+/// Snapshots are exported and imported on blocking threads. Imported entries
+/// are sent back here in batches, so they're applied like any other write.
 pub async fn database_processing<K, V, DB>(
     mut rax: tokio::sync::mpsc::UnboundedReceiver<DbRequest<K, V>>,
     cache: DB,
+    mut hot_cache: HotCache,
+    mut eviction: CacheEviction,
+    shared_cache: Option<Arc<SharedCache>>,
 ) where
-    DB: GenericDatabase,
+    DB: GenericDatabase + Sync + 'static,
     K: GenericBytes,
     V: GenericBytes,
 {
-    while let Some(incoming) = rax.recv().await {
+    let cache = Arc::new(cache);
+    let mut eviction_check = tokio::time::interval(eviction.check_interval());
+    let mut key_count = tokio::time::interval(KEY_COUNT_INTERVAL);
+    let mut db_size = tokio::time::interval(DB_SIZE_INTERVAL);
+    let counting_keys = Arc::new(AtomicBool::new(false));
+    // Keys to evict, listed off this task
+    let (plan_tx, mut plan_rx) = mpsc::unbounded_channel();
+    // Batches read from snapshots, and where to report how applying them went
+    let (import_tx, mut import_rx) = mpsc::channel::<(
+        Batch<Vec<u8>, Vec<u8>>,
+        oneshot::Sender<Result<(), SnapshotError>>,
+    )>(IMPORT_QUEUE);
+
+    // Results of shared cache lookups
+    let (shared_tx, mut shared_rx) = mpsc::unbounded_channel::<(Vec<u8>, Option<Vec<u8>>)>();
+    // Keys being looked up in the shared cache. Writes remove them, so a lookup
+    // that finishes after a write doesn't put an outdated value in the hot cache.
+    let mut pending_lookups = HashSet::new();
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = rax.recv() => incoming,
+            Some((key, val)) = shared_rx.recv() => {
+                if pending_lookups.remove(&key) {
+                    if let Some(val) = val {
+                        hot_cache.insert(key, val);
+                    }
+                }
+                continue;
+            }
+            _ = eviction_check.tick(), if eviction.is_enabled() && !eviction.is_planning() => {
+                if let Some(size) = eviction.check(&*cache) {
+                    let (evict_fraction, tracked) = eviction.plan_args();
+                    let cache = cache.clone();
+                    let plan_tx = plan_tx.clone();
+                    tokio::task::spawn_blocking(move || {
+                        let _ = plan_tx.send(eviction::plan(
+                            &*cache,
+                            size,
+                            evict_fraction,
+                            tracked,
+                        ));
+                    });
+                }
+                continue;
+            }
+            _ = db_size.tick() => {
+                gauge_db_size(&*cache);
+                continue;
+            }
+            _ = key_count.tick(), if !counting_keys.load(Ordering::Relaxed) => {
+                counting_keys.store(true, Ordering::Relaxed);
+                let cache = cache.clone();
+                let counting_keys = counting_keys.clone();
+                tokio::task::spawn_blocking(move || {
+                    if let Err(err) = eviction::count_keys(&*cache) {
+                        tracing::warn!(err, "failed to count cache keys");
+                    }
+                    counting_keys.store(false, Ordering::Relaxed);
+                });
+                continue;
+            }
+            Some(plan) = plan_rx.recv() => {
+                eviction.apply(plan, &*cache, &mut hot_cache);
+                continue;
+            }
+            Some((batch, applied)) = import_rx.recv() => {
+                let result = apply_batch(
+                    batch,
+                    &*cache,
+                    &mut hot_cache,
+                    &mut eviction,
+                    &mut pending_lookups,
+                    &shared_cache,
+                )
+                .map_err(|e| SnapshotError::Database(format!("{:?}", e)));
+                let _ = applied.send(result);
+                continue;
+            }
+        };
+        let Some(incoming) = incoming else {
+            break;
+        };
+
         let result = match incoming.request {
-            RequestKind::Read(k) => cache.read(k),
-            RequestKind::Write(key, val) => cache.write(key, val).map(|_| None),
-            RequestKind::Batch(b) => cache.batch(b).map(|_| None),
+            RequestKind::Read(k) => {
+                let key = k.as_ref().to_vec();
+                let result = match hot_cache.get(&key) {
+                    Some(val) => Ok(Some(val)),
+                    None => {
+                        cache.read(k).inspect(|val| {
+                            if let Some(val) = val {
+                                hot_cache.insert(key.clone(), val.clone());
+                            }
+                        })
+                    }
+                };
+                if matches!(result, Ok(Some(_))) {
+                    eviction.record_access(&key);
+                }
+
+                // Check the shared cache before answering with a miss. The lookup
+                // happens in its own task so it doesn't hold up other requests.
+                if let (Ok(None), Some(shared_cache)) = (&result, &shared_cache) {
+                    if !SharedCache::is_local(&key) {
+                        pending_lookups.insert(key.clone());
+
+                        let shared_cache = shared_cache.clone();
+                        let shared_tx = shared_tx.clone();
+                        let sender = incoming.sender;
+                        tokio::spawn(async move {
+                            let val = shared_cache.get(&key).await;
+                            let _ = sender.send(val.clone());
+                            let _ = shared_tx.send((key, val));
+                        });
+                        continue;
+                    }
+                }
+
+                result
+            }
+            RequestKind::Write(key, val) => {
+                hot_cache.remove(key.as_ref());
+                pending_lookups.remove(key.as_ref());
+                eviction.record_write(key.as_ref(), val.as_ref().len());
+
+                if let Some(shared_cache) = &shared_cache {
+                    if !SharedCache::is_local(key.as_ref()) {
+                        shared_cache.queue(SharedWrite::Set(
+                            key.as_ref().to_vec(),
+                            val.as_ref().to_vec(),
+                        ));
+                    }
+                }
+
+                cache.write(key, val).map(|_| None)
+            }
+            RequestKind::Batch(b) => {
+                apply_batch(
+                    b,
+                    &*cache,
+                    &mut hot_cache,
+                    &mut eviction,
+                    &mut pending_lookups,
+                    &shared_cache,
+                )
+                .map(|_| None)
+            }
             RequestKind::Flush => cache.flush().map(|_| None),
+            RequestKind::Clear => {
+                let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(0);
+                let listed = cache.for_each_key(&mut |key| {
+                    if !is_reserved(key) {
+                        batch.delete(key.to_vec());
+                    }
+                });
+
+                // Drops values we only got from the shared cache too
+                hot_cache.clear();
+                listed
+                    .and_then(|_| {
+                        apply_batch(
+                            batch,
+                            &*cache,
+                            &mut hot_cache,
+                            &mut eviction,
+                            &mut pending_lookups,
+                            &shared_cache,
+                        )
+                    })
+                    .map(|_| None)
+            }
+            RequestKind::Export(path, head_cache) => {
+                let cache = cache.clone();
+                tokio::task::spawn_blocking(move || {
+                    let exported = snapshot::export(&*cache, &path, &head_cache);
+                    let _ = incoming
+                        .sender
+                        .send(snapshot_reply(exported, "export", &path));
+                });
+                continue;
+            }
+            RequestKind::Import(path, head_cache) => {
+                let import_tx = import_tx.clone();
+                tokio::task::spawn_blocking(move || {
+                    let imported =
+                        snapshot::import(&path, IMPORT_BATCH_SIZE, &head_cache, |batch| {
+                            let (applied_tx, applied_rx) = oneshot::channel();
+                            import_tx
+                                .blocking_send((batch, applied_tx))
+                                .map_err(|_| SnapshotError::Database("DB is gone".to_string()))?;
+                            applied_rx
+                                .blocking_recv()
+                                .map_err(|_| SnapshotError::Database("DB is gone".to_string()))?
+                        });
+                    let _ = incoming
+                        .sender
+                        .send(snapshot_reply(imported, "import", &path));
+                });
+                continue;
+            }
         };
 
         if result.is_err() {
@@ -40,6 +299,68 @@ pub async fn database_processing<K, V, DB>(
     }
 }
 
+/// Log the outcome of a snapshot export or import, returning the number of
+/// entries it covered as big endian bytes, or `None` if it failed.
+fn snapshot_reply(
+    result: Result<u64, SnapshotError>,
+    action: &str,
+    path: &Path,
+) -> Option<Vec<u8>> {
+    match result {
+        Ok(entries) => {
+            tracing::info!(entries, ?path, "Cache snapshot {}ed", action);
+            Some(entries.to_be_bytes().to_vec())
+        }
+        Err(err) => {
+            tracing::error!(%err, ?path, "Couldn't {} cache snapshot", action);
+            None
+        }
+    }
+}
+
+/// Apply `batch` to `cache`, and every other place its keys might be held.
+fn apply_batch<DB, K, V>(
+    batch: Batch<K, V>,
+    cache: &DB,
+    hot_cache: &mut HotCache,
+    eviction: &mut CacheEviction,
+    pending_lookups: &mut HashSet<Vec<u8>>,
+    shared_cache: &Option<Arc<SharedCache>>,
+) -> Result<(), DB::Error>
+where
+    DB: GenericDatabase,
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    batch.keys().for_each(|key| {
+        hot_cache.remove(key.as_ref());
+        pending_lookups.remove(key.as_ref());
+        eviction.forget(key.as_ref());
+    });
+    batch
+        .inserts()
+        .for_each(|(key, value)| eviction.record_write(key.as_ref(), value.as_ref().len()));
+
+    if let Some(shared_cache) = shared_cache {
+        let deleted: Vec<Vec<u8>> = batch
+            .deletes()
+            .filter(|key| !SharedCache::is_local(key.as_ref()))
+            .map(|key| key.as_ref().to_vec())
+            .collect();
+        let inserted: Vec<(Vec<u8>, Vec<u8>)> = batch
+            .inserts()
+            .filter(|(key, _)| !SharedCache::is_local(key.as_ref()))
+            .map(|(key, value)| (key.as_ref().to_vec(), value.as_ref().to_vec()))
+            .collect();
+
+        if !deleted.is_empty() || !inserted.is_empty() {
+            shared_cache.queue(SharedWrite::Batch { deleted, inserted });
+        }
+    }
+
+    cache.batch(batch)
+}
+
 /// Macro to abstract getting the data from the DB.
 ///
 /// Returns `Option<V>`, where the result is `None` if
@@ -113,3 +434,69 @@ macro_rules! db_flush {
         rx
     }};
 }
+
+/// Macro for deleting everything cached in the DB, except the entries Blutgang keeps about itself.
+#[macro_export]
+macro_rules! db_clear {
+    ($channel:expr) => {{
+        use $crate::database::types::{
+            DbRequest,
+            RequestKind,
+        };
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let req: DbRequest<_, _> = DbRequest::new(RequestKind::Clear, tx);
+
+        let _ = $channel.send(req);
+
+        rx
+    }};
+}
+
+/// Macro for writing a snapshot of the cache to a file.
+///
+/// Resolves to the number of entries exported as big endian bytes, or `None` if it failed.
+#[macro_export]
+macro_rules! db_export {
+    (
+        $channel:expr,
+        $path:expr,
+        $head_cache:expr
+    ) => {{
+        use $crate::database::types::{
+            DbRequest,
+            RequestKind,
+        };
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let req: DbRequest<_, _> = DbRequest::new(RequestKind::Export($path, $head_cache), tx);
+
+        let _ = $channel.send(req);
+
+        rx
+    }};
+}
+
+/// Macro for inserting every entry of a snapshot file into the cache.
+///
+/// Resolves to the number of entries imported as big endian bytes, or `None` if it failed.
+#[macro_export]
+macro_rules! db_import {
+    (
+        $channel:expr,
+        $path:expr,
+        $head_cache:expr
+    ) => {{
+        use $crate::database::types::{
+            DbRequest,
+            RequestKind,
+        };
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let req: DbRequest<_, _> = DbRequest::new(RequestKind::Import($path, $head_cache), tx);
+
+        let _ = $channel.send(req);
+
+        rx
+    }};
+}
@@ -0,0 +1,200 @@
+//! Compression of cached responses.
+//!
+//! Encoded responses at least `min_bytes` long are compressed before they're
+//! written to the DB, and prefixed with a marker byte saying how. Neither
+//! marker can start a JSON object, a CBOR map, or an expiry header, so
+//! compressed and uncompressed entries can live side by side.
+//!
+//! Decompression does not depend on the configured algorithm, so changing it
+//! does not require clearing the cache. Entries that would decompress to more
+//! than `MAX_DECOMPRESSED_BYTES` are rejected, so a corrupt entry can't make
+//! us run out of memory.
+
+use crate::{
+    config::types::{
+        CacheCompressionSettings,
+        CompressionAlgorithm,
+    },
+    database::serialization::CacheDecodeError,
+};
+
+use std::io::Read;
+
+/// Marks payloads compressed with zstd.
+const ZSTD_MARKER: u8 = 0xfd;
+/// Marks payloads compressed with lz4, prefixed with their uncompressed size.
+const LZ4_MARKER: u8 = 0xfc;
+/// Largest payload we're willing to decompress an entry into.
+const MAX_DECOMPRESSED_BYTES: usize = 256 * 1024 * 1024;
+
+impl CacheCompressionSettings {
+    /// Compress an encoded response, if it's worth it.
+    pub fn compress(&self, payload: Vec<u8>) -> Vec<u8> {
+        if payload.len() < self.min_bytes {
+            return payload;
+        }
+
+        let compressed = match self.algorithm {
+            CompressionAlgorithm::None => return payload,
+            CompressionAlgorithm::Zstd => {
+                let mut out = vec![ZSTD_MARKER];
+                if zstd::stream::copy_encode(payload.as_slice(), &mut out, self.level).is_err() {
+                    return payload;
+                }
+                out
+            }
+            CompressionAlgorithm::Lz4 => {
+                let mut out = vec![LZ4_MARKER];
+                out.extend(lz4_flex::compress_prepend_size(&payload));
+                out
+            }
+        };
+
+        // Random data like signatures doesn't compress, don't make reads pay for it
+        if compressed.len() < payload.len() {
+            compressed
+        } else {
+            payload
+        }
+    }
+}
+
+/// Decompress a payload written by [`CacheCompressionSettings::compress`].
+///
+/// Returns `None` if the payload isn't compressed.
+pub fn decompress(payload: &[u8]) -> Result<Option<Vec<u8>>, CacheDecodeError> {
+    decompress_bounded(payload, MAX_DECOMPRESSED_BYTES)
+}
+
+fn decompress_bounded(
+    payload: &[u8],
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, CacheDecodeError> {
+    let too_large =
+        || CacheDecodeError::Decompression(format!("decompresses to more than {max_bytes} bytes"));
+
+    match payload.split_first() {
+        Some((&ZSTD_MARKER, compressed)) => {
+            let decoder = zstd::stream::read::Decoder::new(compressed)
+                .map_err(|err| CacheDecodeError::Decompression(err.to_string()))?;
+            // Read one byte past the limit to tell if it's over
+            let mut out = Vec::new();
+            decoder
+                .take(max_bytes as u64 + 1)
+                .read_to_end(&mut out)
+                .map_err(|err| CacheDecodeError::Decompression(err.to_string()))?;
+            if out.len() > max_bytes {
+                return Err(too_large());
+            }
+            Ok(Some(out))
+        }
+        Some((&LZ4_MARKER, compressed)) => {
+            // The size is trusted to allocate the output, check it first
+            let size = compressed
+                .get(..4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize);
+            if size.is_some_and(|size| size > max_bytes) {
+                return Err(too_large());
+            }
+            lz4_flex::decompress_size_prepended(compressed)
+                .map(Some)
+                .map_err(|err| CacheDecodeError::Decompression(err.to_string()))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        expiry::with_expiry,
+        serialization::{
+            decode_cached,
+            CacheFormat,
+        },
+    };
+    use serde_json::json;
+
+    fn settings(algorithm: CompressionAlgorithm) -> CacheCompressionSettings {
+        CacheCompressionSettings {
+            algorithm,
+            level: 3,
+            min_bytes: 64,
+        }
+    }
+
+    #[test]
+    fn test_compression_roundtrip() {
+        let logs: Vec<_> = (0..64)
+            .map(|i| {
+                json!({
+                    "address": "0xdac17f958d2ee523a2206206994597c13d831ec7",
+                    "blockNumber": "0x10",
+                    "logIndex": format!("{:#x}", i),
+                    "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+                })
+            })
+            .collect();
+        let value = json!({"id": null, "jsonrpc": "2.0", "result": logs});
+
+        for format in [CacheFormat::Json, CacheFormat::Cbor] {
+            let encoded = format.encode(&value);
+            for algorithm in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+                let compressed = settings(algorithm).compress(encoded.clone());
+                assert!(compressed.len() * 5 < encoded.len());
+
+                // Works both with and without an expiry header
+                assert_eq!(
+                    decode_cached(&mut compressed.clone()).unwrap(),
+                    Some(value.clone())
+                );
+                let mut expiring = with_expiry(compressed, u64::MAX);
+                assert_eq!(decode_cached(&mut expiring).unwrap(), Some(value.clone()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_compression_skipped() {
+        let small =
+            CacheFormat::Json.encode(&json!({"id": null, "jsonrpc": "2.0", "result": "0x1"}));
+        let zstd = settings(CompressionAlgorithm::Zstd);
+        assert_eq!(zstd.compress(small.clone()), small);
+        assert_eq!(decompress(&small).unwrap(), None);
+
+        // Not compressed if it doesn't get any smaller
+        let random: Vec<u8> = (0..8u8)
+            .flat_map(|i| *blake3::hash(&[i]).as_bytes())
+            .collect();
+        assert_eq!(zstd.compress(random.clone()), random);
+        assert_eq!(
+            settings(CompressionAlgorithm::Lz4).compress(random.clone()),
+            random
+        );
+
+        let none = settings(CompressionAlgorithm::None);
+        assert_eq!(none.compress(random.clone()), random);
+
+        assert!(decompress(&[ZSTD_MARKER, 1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn test_decompression_limit() {
+        // Claims to decompress to 4GiB
+        let mut lz4 = vec![LZ4_MARKER];
+        lz4.extend(u32::MAX.to_le_bytes());
+        lz4.extend([0; 8]);
+        assert!(decompress(&lz4).is_err());
+
+        let zeros = vec![0; 1024];
+        for algorithm in [CompressionAlgorithm::Zstd, CompressionAlgorithm::Lz4] {
+            let compressed = settings(algorithm).compress(zeros.clone());
+            assert!(decompress_bounded(&compressed, 1023).is_err());
+            assert_eq!(
+                decompress_bounded(&compressed, 1024).unwrap(),
+                Some(zeros.clone())
+            );
+        }
+    }
+}
@@ -0,0 +1,396 @@
+//! Cache size cap.
+//!
+//! Left alone, the cache grows for as long as Blutgang runs. When a max size
+//! is set, the database task keeps track of when and how often every key gets
+//! accessed, and periodically checks the size of the DB. Once it's over the
+//! cap, a fraction of the entries is evicted, coldest first according to the
+//! eviction policy.
+//!
+//! Backends reclaim the space of deleted entries lazily, so the size on disk
+//! lags behind evictions. We keep our own estimate instead: the size on disk
+//! at the last eviction, minus the share of entries evicted, plus the bytes
+//! written since. Whichever of the estimate and the size on disk is smaller
+//! is checked against the cap.
+//!
+//! Listing the keys to evict walks the whole DB, so it happens on a blocking
+//! thread while the database task keeps serving requests, see `plan`.
+//!
+//! Access metadata is only kept in memory, so keys that haven't been accessed
+//! since startup are considered the coldest.
+
+use crate::{
+    config::{
+        cache_setup::is_reserved,
+        types::{
+            CacheEvictionSettings,
+            EvictionPolicy,
+        },
+    },
+    database::{
+        hot_cache::HotCache,
+        types::{
+            Batch,
+            GenericDatabase,
+        },
+    },
+};
+
+use std::{
+    collections::HashMap,
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+
+/// Tracks key accesses and evicts the coldest entries once the DB is too big.
+#[derive(Debug, Default)]
+pub struct CacheEviction {
+    settings: CacheEvictionSettings,
+    // Bumped on every access, used to order entries by recency
+    tick: u64,
+    // key -> (last access, access count)
+    accesses: HashMap<Vec<u8>, (u64, u64)>,
+    // Estimated size in bytes as of the last eviction, `None` until the first one
+    size: Option<u64>,
+    // Bytes written since `size` was estimated
+    written: u64,
+    // Set while keys to evict are being listed
+    planning: bool,
+}
+
+/// Keys listed for eviction, see `plan`.
+#[derive(Debug)]
+pub struct EvictionPlan {
+    /// Size of the cache in bytes when the plan was made.
+    size: u64,
+    /// Number of keys in the cache.
+    total: usize,
+    /// Number of keys to evict.
+    count: usize,
+    /// Keys to evict first, some of which may have been accessed since.
+    candidates: Vec<Vec<u8>>,
+}
+
+/// Count the keys in `cache`, and export the count as the `cache_keys` gauge.
+///
+/// Walks every key, so it's meant to run on a blocking thread.
+pub fn count_keys<DB: GenericDatabase>(cache: &DB) -> Result<usize, String> {
+    let mut total = 0;
+    cache
+        .for_each_key(&mut |_| total += 1)
+        .map_err(|err| format!("{:?}", err))?;
+    metrics::gauge!("cache_keys").set(total as f64);
+    Ok(total)
+}
+
+/// List the keys to evict a `evict_fraction` of `cache`, which is `size` bytes.
+///
+/// Walks every key, so it's meant to run on a blocking thread. `tracked` is
+/// the number of keys with access data, enough extra keys are listed to find
+/// `count` untracked ones among them.
+pub fn plan<DB: GenericDatabase>(
+    cache: &DB,
+    size: u64,
+    evict_fraction: f64,
+    tracked: usize,
+) -> Result<EvictionPlan, String> {
+    let total = count_keys(cache)?;
+    let count = ((total as f64 * evict_fraction.clamp(0.0, 1.0)).ceil() as usize).max(1);
+
+    let mut candidates = Vec::new();
+    cache
+        .for_each_key(&mut |key| {
+            if candidates.len() < count.saturating_add(tracked) && !is_reserved(key) {
+                candidates.push(key.to_vec());
+            }
+        })
+        .map_err(|err| format!("{:?}", err))?;
+
+    Ok(EvictionPlan {
+        size,
+        total,
+        count,
+        candidates,
+    })
+}
+
+impl CacheEviction {
+    pub fn new(settings: CacheEvictionSettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.settings.max_size_mb != 0
+    }
+
+    /// Time between size checks.
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_millis(self.settings.check_interval_ms.max(1))
+    }
+
+    /// Record a read or write of `key`.
+    pub fn record_access(&mut self, key: &[u8]) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.tick += 1;
+        let (last_access, count) = self.accesses.entry(key.to_vec()).or_insert((0, 0));
+        *last_access = self.tick;
+        *count = count.saturating_add(1);
+    }
+
+    /// Record a write of `len` bytes to `key`.
+    pub fn record_write(&mut self, key: &[u8], len: usize) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        self.record_access(key);
+        self.written = self.written.saturating_add((key.len() + len) as u64);
+    }
+
+    /// Stop tracking `key`, usually because it got deleted.
+    pub fn forget(&mut self, key: &[u8]) {
+        self.accesses.remove(key);
+    }
+
+    /// Pick `count` keys to evict.
+    ///
+    /// `untracked` are keys in the DB that haven't been accessed since startup,
+    /// which go first. After that, tracked keys go in the order of the policy.
+    fn select(&self, untracked: Vec<Vec<u8>>, count: usize) -> Vec<Vec<u8>> {
+        let mut selected = untracked;
+        selected.truncate(count);
+        if selected.len() == count {
+            return selected;
+        }
+
+        let mut tracked: Vec<(&Vec<u8>, &(u64, u64))> = self.accesses.iter().collect();
+        match self.settings.policy {
+            EvictionPolicy::Lru => {
+                tracked.sort_unstable_by_key(|(_, (last_access, _))| *last_access)
+            }
+            EvictionPolicy::Lfu => {
+                tracked.sort_unstable_by_key(|(_, (last_access, count))| (*count, *last_access))
+            }
+        }
+
+        selected.extend(
+            tracked
+                .into_iter()
+                .filter(|(key, _)| !is_reserved(key))
+                .take(count - selected.len())
+                .map(|(key, _)| key.clone()),
+        );
+        selected
+    }
+
+    /// Returns true while keys to evict are being listed.
+    pub fn is_planning(&self) -> bool {
+        self.planning
+    }
+
+    /// Returns the size of `cache` in bytes if it's over the size cap, in
+    /// which case keys to evict should be listed with `plan`, and the plan
+    /// applied with `apply`.
+    pub fn check<DB: GenericDatabase>(&mut self, cache: &DB) -> Option<u64> {
+        let disk_size = match cache.disk_size() {
+            Ok(size) => size,
+            Err(err) => {
+                tracing::warn!(?err, "failed to check cache size for eviction");
+                return None;
+            }
+        };
+        let size = self.estimate(disk_size);
+
+        let max_size = self.settings.max_size_mb.saturating_mul(1024 * 1024);
+        if size <= max_size {
+            return None;
+        }
+
+        self.planning = true;
+        Some(size)
+    }
+
+    /// Size of the cache, given it's `disk_size` bytes on disk.
+    fn estimate(&self, disk_size: u64) -> u64 {
+        match self.size {
+            Some(size) => disk_size.min(size.saturating_add(self.written)),
+            None => disk_size,
+        }
+    }
+
+    /// Arguments to `plan` with.
+    pub fn plan_args(&self) -> (f64, usize) {
+        (self.settings.evict_fraction, self.accesses.len())
+    }
+
+    /// Evict the entries picked from `plan` from `cache`, or give up if
+    /// listing them failed.
+    ///
+    /// Evicted entries are dropped from `hot_cache` too.
+    pub fn apply<DB: GenericDatabase>(
+        &mut self,
+        plan: Result<EvictionPlan, String>,
+        cache: &DB,
+        hot_cache: &mut HotCache,
+    ) {
+        self.planning = false;
+        let plan = match plan {
+            Ok(plan) => plan,
+            Err(err) => {
+                tracing::warn!(err, "failed to list cache keys for eviction");
+                return;
+            }
+        };
+
+        // Keys accessed while the plan was made aren't the coldest anymore
+        let untracked: Vec<Vec<u8>> = plan
+            .candidates
+            .into_iter()
+            .filter(|key| !self.accesses.contains_key(key))
+            .take(plan.count)
+            .collect();
+        let evicted = self.select(untracked, plan.count);
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(evicted.len());
+        for key in &evicted {
+            hot_cache.remove(key);
+            self.forget(key);
+            batch.delete(key.clone());
+        }
+
+        if let Err(err) = cache.batch(batch) {
+            tracing::error!(?err, "failed to evict cache entries");
+            return;
+        }
+        let _ = cache.flush();
+
+        // Assume evicted entries were of average size, whenever the space gets reclaimed
+        let kept = 1.0 - evicted.len() as f64 / plan.total.max(1) as f64;
+        self.size = Some((plan.size as f64 * kept.max(0.0)) as u64);
+        self.written = 0;
+
+        tracing::info!(
+            size_mb = plan.size / (1024 * 1024),
+            max_size_mb = self.settings.max_size_mb,
+            evicted = evicted.len(),
+            "Cache over its size cap, evicted the coldest entries"
+        );
+        metrics::counter!("cache_evictions_total").increment(evicted.len() as u64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::cache_setup::state_key;
+    use sled::{
+        Config,
+        Db,
+    };
+
+    fn eviction(policy: EvictionPolicy) -> CacheEviction {
+        CacheEviction::new(CacheEvictionSettings {
+            max_size_mb: 1,
+            policy,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_select_lru() {
+        let mut eviction = eviction(EvictionPolicy::Lru);
+        eviction.record_access(b"a");
+        eviction.record_access(b"b");
+        eviction.record_access(b"a");
+        eviction.record_access(b"c");
+
+        assert_eq!(
+            eviction.select(Vec::new(), 2),
+            vec![b"b".to_vec(), b"a".to_vec()]
+        );
+
+        // Untracked keys go first
+        assert_eq!(
+            eviction.select(vec![b"x".to_vec()], 2),
+            vec![b"x".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(
+            eviction.select(vec![b"x".to_vec(), b"y".to_vec()], 1),
+            vec![b"x".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_select_lfu() {
+        let mut eviction = eviction(EvictionPolicy::Lfu);
+        eviction.record_access(b"a");
+        eviction.record_access(b"a");
+        eviction.record_access(b"b");
+        eviction.record_access(b"c");
+        eviction.record_access(b"c");
+        eviction.record_access(b"c");
+
+        assert_eq!(
+            eviction.select(Vec::new(), 2),
+            vec![b"b".to_vec(), b"a".to_vec()]
+        );
+
+        // Reserved keys are never evicted, and neither is state
+        eviction.record_access(b"blake3");
+        eviction.record_access(&state_key("quota:http://localhost:8545"));
+        assert_eq!(eviction.select(Vec::new(), 1), vec![b"b".to_vec()]);
+
+        eviction.forget(b"b");
+        assert_eq!(eviction.select(Vec::new(), 1), vec![b"a".to_vec()]);
+    }
+
+    #[test]
+    fn test_estimate() {
+        let mut eviction = eviction(EvictionPolicy::Lru);
+        assert_eq!(eviction.estimate(100), 100);
+
+        // Space of evicted entries isn't reclaimed yet
+        eviction.size = Some(50);
+        eviction.record_write(b"key", 7);
+        assert_eq!(eviction.estimate(100), 60);
+        // But it is eventually
+        assert_eq!(eviction.estimate(40), 40);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut eviction = eviction(EvictionPolicy::Lru);
+        let mut hot_cache = HotCache::default();
+        let cache = Db::open_with_config(&Config::tmp().unwrap()).unwrap();
+        for key in [b"a", b"b", b"c", b"d"] {
+            cache.write(key.to_vec(), b"value".to_vec()).unwrap();
+        }
+        eviction.record_access(b"a");
+
+        let plan = plan(&cache, 1000, 0.5, eviction.accesses.len()).unwrap();
+        assert_eq!((plan.total, plan.count), (4, 2));
+        // Accessed while the plan was made, so it's not evicted
+        eviction.record_access(b"b");
+        eviction.apply(Ok(plan), &cache, &mut hot_cache);
+
+        assert!(cache.read(b"a".to_vec()).unwrap().is_none());
+        assert!(cache.read(b"b".to_vec()).unwrap().is_some());
+        assert!(cache.read(b"c".to_vec()).unwrap().is_none());
+        assert!(cache.read(b"d".to_vec()).unwrap().is_some());
+        assert_eq!(eviction.size, Some(500));
+        assert!(!eviction.is_planning());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut eviction = CacheEviction::default();
+        eviction.record_access(b"a");
+        assert!(!eviction.is_enabled());
+        assert!(eviction.accesses.is_empty());
+    }
+}
@@ -0,0 +1,146 @@
+//! Expiring cache entries.
+//!
+//! Entries that should expire are prefixed with a marker byte and the unix
+//! timestamp in ms after which they're no longer valid. Expired entries are
+//! treated as cache misses and get overwritten by the next insert.
+//!
+//! If a lot of entries get cached at the same time, for example right after a
+//! popular block, giving them all the same TTL would make them expire at the
+//! same time and stampede the RPCs. To avoid this, each TTL is randomly
+//! stretched or shrunk by up to `jitter` of its length.
+
+use crate::config::types::CacheExpirySettings;
+
+use rand::Rng;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// Marks entries that carry an expiry timestamp.
+///
+/// Reserved in CBOR and can never be the start of a JSON object,
+/// so it can't be confused with either cache format.
+const EXPIRY_MARKER: u8 = 0xfe;
+const HEADER_LEN: usize = 9;
+
+/// Current unix time in ms
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl CacheExpirySettings {
+    /// Returns when an entry inserted at `now` should expire, or `None` if entries don't expire.
+    pub fn expires_at(&self, now: u64) -> Option<u64> {
+        if self.ttl_ms == 0 {
+            return None;
+        }
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let ttl = if jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+            (self.ttl_ms as f64 * factor) as u64
+        } else {
+            self.ttl_ms
+        };
+
+        Some(now.saturating_add(ttl))
+    }
+}
+
+/// Prefix an encoded entry with its expiry timestamp.
+pub fn with_expiry(payload: Vec<u8>, expires_at: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.push(EXPIRY_MARKER);
+    out.extend_from_slice(&expires_at.to_be_bytes());
+    out.extend(payload);
+    out
+}
+
+/// Returns when an entry expires, or `None` if it doesn't have an expiry header.
+pub fn expiry(bytes: &[u8]) -> Option<u64> {
+    if bytes.first() != Some(&EXPIRY_MARKER) {
+        return None;
+    }
+
+    Some(u64::from_be_bytes(
+        bytes.get(1..HEADER_LEN)?.try_into().unwrap(),
+    ))
+}
+
+/// Strip the expiry header from an entry.
+///
+/// Returns the encoded payload, or `None` if the entry has expired at `now`.
+pub fn strip_expiry(bytes: &mut [u8], now: u64) -> Option<&mut [u8]> {
+    if bytes.first() != Some(&EXPIRY_MARKER) {
+        return Some(bytes);
+    }
+
+    let expires_at = u64::from_be_bytes(bytes.get(1..HEADER_LEN)?.try_into().unwrap());
+    if now >= expires_at {
+        return None;
+    }
+
+    Some(&mut bytes[HEADER_LEN..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_expiry() {
+        let settings = CacheExpirySettings {
+            ttl_ms: 0,
+            jitter: 0.5,
+            ..Default::default()
+        };
+        assert_eq!(settings.expires_at(1000), None);
+
+        // Entries without a header never expire
+        let mut entry = b"{}".to_vec();
+        assert_eq!(expiry(&entry), None);
+        assert_eq!(strip_expiry(&mut entry, u64::MAX).unwrap(), b"{}");
+    }
+
+    #[test]
+    fn test_expiry_roundtrip() {
+        let mut entry = with_expiry(b"{}".to_vec(), 2000);
+        assert_eq!(expiry(&entry), Some(2000));
+        assert_eq!(strip_expiry(&mut entry, 1999).unwrap(), b"{}");
+        assert!(strip_expiry(&mut entry, 2000).is_none());
+
+        // Truncated headers are never valid
+        assert!(strip_expiry(&mut [EXPIRY_MARKER, 0, 0], 0).is_none());
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let settings = CacheExpirySettings {
+            ttl_ms: 1000,
+            jitter: 0.2,
+            ..Default::default()
+        };
+
+        let mut expiries = Vec::new();
+        for _ in 0..100 {
+            let expires_at = settings.expires_at(10_000).unwrap();
+            assert!((10_800..=11_200).contains(&expires_at));
+            expiries.push(expires_at);
+        }
+
+        // We should not be getting the same expiry every time
+        expiries.dedup();
+        assert!(expiries.len() > 1);
+
+        let settings = CacheExpirySettings {
+            ttl_ms: 1000,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(settings.expires_at(10_000), Some(11_000));
+    }
+}
@@ -0,0 +1,243 @@
+//! In-memory tier in front of the cache DB.
+//!
+//! A handful of keys, like the latest block, `eth_chainId` and `eth_gasPrice`,
+//! make up most of the reads. Keeping the most recently read entries in memory
+//! means those never have to touch the disk.
+//!
+//! The hot cache lives in the database task, so every write goes through it
+//! and it can't serve data the DB no longer has.
+
+use crate::database::types::{
+    CACHE_HITS,
+    CACHE_MISSES,
+};
+
+use std::collections::{
+    BTreeMap,
+    HashMap,
+};
+
+use rust_tracing::deps::metrics;
+
+const TIER: &str = "memory";
+
+/// Bounded LRU of DB entries, sized by the bytes of the keys and values it holds.
+#[derive(Debug, Default)]
+pub struct HotCache {
+    capacity: usize,
+    size: usize,
+    // Bumped on every access, used to order entries by recency
+    tick: u64,
+    // key -> (value, last access)
+    entries: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+    // last access -> key
+    recency: BTreeMap<u64, Vec<u8>>,
+}
+
+impl HotCache {
+    /// Hold up to `capacity` bytes of entries. `0` disables the hot cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Default::default()
+        }
+    }
+
+    fn touch(&mut self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (value, last_access) = self.entries.get_mut(key)?;
+        let key = self.recency.remove(last_access)?;
+        *last_access = tick;
+        self.recency.insert(tick, key);
+
+        Some(value)
+    }
+
+    /// Returns the value of `key` if we hold it.
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        let value = self.touch(key).cloned();
+        if value.is_some() {
+            metrics::counter!(CACHE_HITS, "tier" => TIER).increment(1);
+        } else {
+            metrics::counter!(CACHE_MISSES, "tier" => TIER).increment(1);
+        }
+
+        value
+    }
+
+    /// Hold `value` for `key`, evicting the least recently used entries to make room.
+    ///
+    /// Entries that don't fit in the hot cache on their own are not stored.
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.remove(&key);
+
+        let entry_size = key.len() + value.len();
+        if self.capacity == 0 || entry_size > self.capacity {
+            return;
+        }
+
+        while self.size + entry_size > self.capacity {
+            let Some((_, coldest)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.entries.remove(&coldest) {
+                self.size -= coldest.len() + value.len();
+            }
+        }
+
+        self.tick += 1;
+        self.size += entry_size;
+        self.recency.insert(self.tick, key.clone());
+        self.entries.insert(key, (value, self.tick));
+    }
+
+    /// Drop every entry.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+    }
+
+    /// Drop `key` so the next read goes to the DB.
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some((value, last_access)) = self.entries.remove(key) {
+            self.recency.remove(&last_access);
+            self.size -= key.len() + value.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            accept::{
+                db_batch,
+                db_insert,
+            },
+            eviction::CacheEviction,
+            types::{
+                Batch,
+                DbRequest,
+            },
+        },
+        database_processing,
+        db_clear,
+        db_get,
+    };
+    use sled::{
+        Config,
+        Db,
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_hot_cache_lru() {
+        // Room for three 2 byte entries
+        let mut hot = HotCache::new(6);
+
+        hot.insert(b"a".to_vec(), b"1".to_vec());
+        hot.insert(b"b".to_vec(), b"2".to_vec());
+        hot.insert(b"c".to_vec(), b"3".to_vec());
+
+        // Reading `a` makes `b` the least recently used
+        assert_eq!(hot.get(b"a"), Some(b"1".to_vec()));
+        hot.insert(b"d".to_vec(), b"4".to_vec());
+
+        assert_eq!(hot.entries.len(), 3);
+        assert_eq!(hot.get(b"b"), None);
+        assert_eq!(hot.get(b"a"), Some(b"1".to_vec()));
+        assert_eq!(hot.get(b"d"), Some(b"4".to_vec()));
+
+        // Overwriting replaces the value without growing
+        hot.insert(b"a".to_vec(), b"5".to_vec());
+        assert_eq!(hot.entries.len(), 3);
+        assert_eq!(hot.get(b"a"), Some(b"5".to_vec()));
+
+        hot.remove(b"a");
+        assert_eq!(hot.get(b"a"), None);
+        assert_eq!(hot.size, 4);
+    }
+
+    #[test]
+    fn test_hot_cache_limits() {
+        let mut hot = HotCache::new(4);
+
+        // Too big to ever fit
+        hot.insert(b"a".to_vec(), b"1234".to_vec());
+        assert_eq!(hot.get(b"a"), None);
+
+        // Evicts as many entries as needed
+        hot.insert(b"b".to_vec(), b"1".to_vec());
+        hot.insert(b"c".to_vec(), b"1".to_vec());
+        hot.insert(b"d".to_vec(), b"123".to_vec());
+        assert_eq!(hot.entries.len(), 1);
+        assert_eq!(hot.size, 4);
+
+        // Disabled hot cache holds nothing
+        let mut hot = HotCache::new(0);
+        hot.insert(Vec::new(), Vec::new());
+        assert_eq!(hot.get(b""), None);
+    }
+
+    #[tokio::test]
+    async fn test_hot_cache_follows_writes() {
+        let cache = Db::open_with_config(&Config::tmp().unwrap()).unwrap();
+        let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<[u8; 32], Vec<u8>>>();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::new(1024),
+            CacheEviction::default(),
+            None,
+        ));
+
+        let key = [1u8; 32];
+        let _ = db_insert(&db_tx, key, b"old".to_vec()).await.await;
+        // Populates the hot cache
+        assert_eq!(db_get!(db_tx, key).unwrap(), Some(b"old".to_vec()));
+
+        let _ = db_insert(&db_tx, key, b"new".to_vec()).await.await;
+        assert_eq!(db_get!(db_tx, key).unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db_get!(db_tx, key).unwrap(), Some(b"new".to_vec()));
+
+        let mut batch = Batch::with_capacity(1);
+        batch.delete(key);
+        let _ = db_batch(&db_tx, batch).await.await;
+        assert_eq!(db_get!(db_tx, key).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_hot_cache_follows_clear() {
+        let cache = Db::open_with_config(&Config::tmp().unwrap()).unwrap();
+        let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<Vec<u8>, Vec<u8>>>();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::new(1024),
+            CacheEviction::default(),
+            None,
+        ));
+
+        let key = vec![1u8; 32];
+        let reserved = b"blake3".to_vec();
+        let _ = db_insert(&db_tx, key.clone(), b"val".to_vec()).await.await;
+        let _ = db_insert(&db_tx, reserved.clone(), b"".to_vec())
+            .await
+            .await;
+        // Populates the hot cache
+        assert_eq!(db_get!(db_tx, key.clone()).unwrap(), Some(b"val".to_vec()));
+
+        let _ = db_clear!(db_tx).await;
+        assert_eq!(db_get!(db_tx, key).unwrap(), None);
+        // Entries Blutgang keeps about itself stay
+        assert_eq!(db_get!(db_tx, reserved).unwrap(), Some(Vec::new()));
+    }
+}
@@ -1,3 +1,12 @@
 pub mod accept;
+pub mod compression;
 pub mod error;
+pub mod eviction;
+pub mod expiry;
+pub mod hot_cache;
+pub mod redis;
+pub mod serialization;
+pub mod shared_cache;
+pub mod snapshot;
 pub mod types;
+pub mod writer;
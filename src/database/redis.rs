@@ -0,0 +1,347 @@
+//! Minimal Redis client.
+//!
+//! We only need a handful of commands to share state between Blutgang
+//! instances, so instead of pulling in a full client we speak just enough
+//! RESP over a small pool of connections. Each connection carries one command
+//! at a time and is re-established if it breaks.
+
+use std::{
+    fmt,
+    sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{
+        AsyncBufRead,
+        AsyncBufReadExt,
+        AsyncReadExt,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::TcpStream,
+    sync::{
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        timeout_at,
+        Instant,
+    },
+};
+use url::Url;
+
+const DEFAULT_PORT: u16 = 6379;
+
+/// Connections a client opens unless told otherwise.
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Longest bulk string we accept in a reply, well above any cached response.
+const MAX_BULK_LEN: i64 = 64 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum RedisError {
+    InvalidUrl(String),
+    Io(std::io::Error),
+    Protocol(String),
+    /// Error reply sent by the server
+    Server(String),
+}
+
+impl fmt::Display for RedisError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RedisError::InvalidUrl(url) => write!(f, "invalid Redis url: {}", url),
+            RedisError::Io(e) => write!(f, "Redis connection error: {}", e),
+            RedisError::Protocol(e) => write!(f, "Redis protocol error: {}", e),
+            RedisError::Server(e) => write!(f, "Redis error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RedisError {}
+
+impl From<std::io::Error> for RedisError {
+    fn from(e: std::io::Error) -> Self {
+        RedisError::Io(e)
+    }
+}
+
+/// Reply to a Redis command. Arrays are not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+}
+
+impl Reply {
+    pub fn as_integer(&self) -> Result<i64, RedisError> {
+        match self {
+            Reply::Integer(integer) => Ok(*integer),
+            other => {
+                Err(RedisError::Protocol(format!(
+                    "expected integer, got {:?}",
+                    other
+                )))
+            }
+        }
+    }
+}
+
+/// Encode a command as a RESP array of bulk strings.
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+/// Read a single reply from `reader`.
+async fn read_reply<R>(reader: &mut R) -> Result<Reply, RedisError>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(RedisError::Protocol("connection closed".to_string()));
+    }
+
+    let line = line.trim_end_matches("\r\n");
+    let (kind, rest) = line.split_at(line.len().min(1));
+    let parse_len = || {
+        rest.parse::<i64>()
+            .map_err(|_| RedisError::Protocol(format!("invalid length: {}", rest)))
+    };
+
+    match kind {
+        "+" => Ok(Reply::Simple(rest.to_string())),
+        "-" => Err(RedisError::Server(rest.to_string())),
+        ":" => parse_len().map(Reply::Integer),
+        "$" => {
+            let len = parse_len()?;
+            if len < 0 {
+                return Ok(Reply::Bulk(None));
+            }
+            if len > MAX_BULK_LEN {
+                return Err(RedisError::Protocol(format!(
+                    "bulk string too long: {}",
+                    len
+                )));
+            }
+
+            // Data is followed by a trailing CRLF. Grow the buffer as data comes
+            // in rather than trusting the length up front.
+            let mut data = Vec::new();
+            (&mut *reader)
+                .take(len as u64 + 2)
+                .read_to_end(&mut data)
+                .await?;
+            if data.len() != len as usize + 2 {
+                return Err(RedisError::Protocol("connection closed".to_string()));
+            }
+            data.truncate(len as usize);
+            Ok(Reply::Bulk(Some(data)))
+        }
+        _ => Err(RedisError::Protocol(format!("unsupported reply: {}", line))),
+    }
+}
+
+#[derive(Debug)]
+pub struct RedisClient {
+    address: String,
+    password: Option<String>,
+    db: Option<String>,
+    conns: Vec<Mutex<Option<BufReader<TcpStream>>>>,
+    next: AtomicUsize,
+}
+
+impl RedisClient {
+    /// Create a client for `redis://[:password@]host[:port][/db]`. Connects lazily.
+    pub fn new(url: &str) -> Result<Self, RedisError> {
+        let parsed = Url::parse(url).map_err(|_| RedisError::InvalidUrl(url.to_string()))?;
+        if parsed.scheme() != "redis" {
+            return Err(RedisError::InvalidUrl(url.to_string()));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| RedisError::InvalidUrl(url.to_string()))?;
+        let db = parsed.path().trim_start_matches('/');
+
+        Ok(Self {
+            address: format!("{}:{}", host, parsed.port().unwrap_or(DEFAULT_PORT)),
+            password: parsed.password().map(ToString::to_string),
+            db: (!db.is_empty()).then(|| db.to_string()),
+            conns: (0..DEFAULT_POOL_SIZE).map(|_| Mutex::new(None)).collect(),
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Open up to `size` connections instead of [`DEFAULT_POOL_SIZE`].
+    pub fn with_pool_size(mut self, size: usize) -> Self {
+        self.conns = (0..size.max(1)).map(|_| Mutex::new(None)).collect();
+        self
+    }
+
+    /// Take an idle connection slot, or wait for the next one in turn if
+    /// they're all busy. Slots connect lazily, so a quiet client keeps few
+    /// connections open.
+    async fn slot(&self) -> MutexGuard<'_, Option<BufReader<TcpStream>>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let len = self.conns.len();
+
+        // Prefer idle slots that already hold a connection
+        let mut empty = None;
+        for i in 0..len {
+            if let Ok(guard) = self.conns[(start + i) % len].try_lock() {
+                if guard.is_some() {
+                    return guard;
+                }
+                empty.get_or_insert(guard);
+            }
+        }
+
+        match empty {
+            Some(guard) => guard,
+            None => self.conns[start % len].lock().await,
+        }
+    }
+
+    /// Send a command and wait for its reply.
+    pub async fn command(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        let mut guard = self.slot().await;
+        if guard.is_none() {
+            *guard = Some(self.connect().await?);
+        }
+
+        let reply = send(guard.as_mut().unwrap(), args).await;
+        // Drop broken connections so the next command reconnects
+        if matches!(reply, Err(RedisError::Io(_)) | Err(RedisError::Protocol(_))) {
+            *guard = None;
+        }
+
+        reply
+    }
+
+    /// Same as [`RedisClient::command`], but gives up after `timeout`.
+    ///
+    /// Wrapping `command` in a timeout could leave a reply unread on the connection,
+    /// which the next command would then read as its own. Timed out connections
+    /// get dropped instead.
+    pub async fn command_timeout(
+        &self,
+        args: &[&[u8]],
+        timeout: Duration,
+    ) -> Result<Reply, RedisError> {
+        let deadline = Instant::now() + timeout;
+        let timed_out = |_| RedisError::Io(std::io::ErrorKind::TimedOut.into());
+
+        let mut guard = timeout_at(deadline, self.slot()).await.map_err(timed_out)?;
+        if guard.is_none() {
+            *guard = Some(
+                timeout_at(deadline, self.connect())
+                    .await
+                    .map_err(timed_out)??,
+            );
+        }
+
+        let reply = timeout_at(deadline, send(guard.as_mut().unwrap(), args))
+            .await
+            .unwrap_or_else(|elapsed| Err(timed_out(elapsed)));
+        if matches!(reply, Err(RedisError::Io(_)) | Err(RedisError::Protocol(_))) {
+            *guard = None;
+        }
+
+        reply
+    }
+}
+
+async fn send(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> Result<Reply, RedisError> {
+    conn.get_mut().write_all(&encode_command(args)).await?;
+    read_reply(conn).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_command() {
+        assert_eq!(
+            encode_command(&[b"INCRBY", b"key", b"10"]),
+            b"*3\r\n$6\r\nINCRBY\r\n$3\r\nkey\r\n$2\r\n10\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_reply() {
+        let mut input: &[u8] = b"+OK\r\n:42\r\n$5\r\nhello\r\n$-1\r\n-ERR wrong type\r\n*1\r\n";
+
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            Reply::Simple("OK".to_string())
+        );
+        assert_eq!(read_reply(&mut input).await.unwrap(), Reply::Integer(42));
+        assert_eq!(
+            read_reply(&mut input).await.unwrap(),
+            Reply::Bulk(Some(b"hello".to_vec()))
+        );
+        assert_eq!(read_reply(&mut input).await.unwrap(), Reply::Bulk(None));
+        assert!(matches!(
+            read_reply(&mut input).await,
+            Err(RedisError::Server(e)) if e == "ERR wrong type"
+        ));
+        assert!(matches!(
+            read_reply(&mut input).await,
+            Err(RedisError::Protocol(_))
+        ));
+
+        // Lengths we won't allocate for, and data cut short
+        let mut input = format!("${}\r\n", MAX_BULK_LEN + 1).into_bytes();
+        assert!(matches!(
+            read_reply(&mut input.as_slice()).await,
+            Err(RedisError::Protocol(_))
+        ));
+        input = b"$10\r\nhello\r\n".to_vec();
+        assert!(matches!(
+            read_reply(&mut input.as_slice()).await,
+            Err(RedisError::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_client_url() {
+        let client = RedisClient::new("redis://:hunter2@cache.internal:6380/2").unwrap();
+        assert_eq!(client.address, "cache.internal:6380");
+        assert_eq!(client.password.as_deref(), Some("hunter2"));
+        assert_eq!(client.db.as_deref(), Some("2"));
+
+        let client = RedisClient::new("redis://127.0.0.1").unwrap();
+        assert_eq!(client.address, "127.0.0.1:6379");
+        assert!(client.db.is_none());
+
+        assert!(RedisClient::new("http://127.0.0.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_pool() {
+        let client = RedisClient::new("redis://127.0.0.1").unwrap();
+        assert_eq!(client.conns.len(), DEFAULT_POOL_SIZE);
+        assert_eq!(client.with_pool_size(0).conns.len(), 1);
+
+        // Busy slots are skipped while others are idle
+        let client = RedisClient::new("redis://127.0.0.1")
+            .unwrap()
+            .with_pool_size(2);
+        let first = client.slot().await;
+        let second = client.slot().await;
+        assert!(client.conns.iter().all(|conn| conn.try_lock().is_err()));
+        drop((first, second));
+    }
+}
@@ -0,0 +1,590 @@
+//! Serialization of cached responses.
+//!
+//! By default responses are stored in the cache as JSON text. Most of that
+//! text is hex encoded data, so storing it as a compact binary CBOR encoding
+//! of the parsed response cuts the cache size roughly in half at the cost of
+//! re-serializing on the way out.
+//!
+//! Lowercase, even length hex strings are stored as CBOR byte strings, and
+//! turned back into `0x` prefixed hex when decoding. Everything else maps
+//! directly onto the regular CBOR types.
+//!
+//! Decoding does not depend on the configured format, so switching formats
+//! does not require clearing the cache.
+//!
+//! Single fields of a cached response can be read with `cached_field`, which
+//! skips over the rest of CBOR entries instead of decoding them. This is what
+//! lets `blutgang_purge_cache` invalidate entries by the value of a field.
+
+use crate::database::{
+    compression::decompress,
+    expiry::{
+        now_ms,
+        strip_expiry,
+    },
+};
+
+use serde_json::{
+    Map,
+    Number,
+    Value,
+};
+use std::fmt;
+
+/// Format in which responses are stored in the cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CacheFormat {
+    /// Store responses as JSON text
+    #[default]
+    Json,
+    /// Store responses as CBOR, with hex strings stored as raw bytes
+    Cbor,
+}
+
+#[derive(Debug)]
+pub enum CacheDecodeError {
+    UnexpectedEof,
+    InvalidType(u8),
+    InvalidUtf8,
+    InvalidJson(String),
+    Decompression(String),
+    TooDeep,
+}
+
+impl fmt::Display for CacheDecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CacheDecodeError::UnexpectedEof => write!(f, "Unexpected end of cached value"),
+            CacheDecodeError::InvalidType(byte) => {
+                write!(f, "Unsupported CBOR type in cached value: {:#x}", byte)
+            }
+            CacheDecodeError::InvalidUtf8 => write!(f, "Cached string is not valid UTF-8"),
+            CacheDecodeError::InvalidJson(reason) => {
+                write!(f, "Cached value is not valid JSON: {}", reason)
+            }
+            CacheDecodeError::Decompression(reason) => {
+                write!(f, "Couldn't decompress cached value: {}", reason)
+            }
+            CacheDecodeError::TooDeep => {
+                write!(
+                    f,
+                    "Cached value is nested more than {} levels deep",
+                    MAX_DEPTH
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for CacheDecodeError {}
+
+// CBOR major types
+const MAJOR_UINT: u8 = 0;
+const MAJOR_NINT: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const MAJOR_SIMPLE: u8 = 7;
+
+/// How deep arrays and maps can be nested in a CBOR value, same as the
+/// recursion limit of `serde_json`. Snapshots can be imported from anywhere,
+/// so this keeps decoding from running out of stack.
+const MAX_DEPTH: usize = 128;
+
+const SIMPLE_FALSE: u8 = 20;
+const SIMPLE_TRUE: u8 = 21;
+const SIMPLE_NULL: u8 = 22;
+const SIMPLE_F64: u8 = 27;
+
+impl CacheFormat {
+    /// Serialize a response so it can be inserted into the cache.
+    pub fn encode(&self, value: &Value) -> Vec<u8> {
+        match self {
+            CacheFormat::Json => simd_json::to_vec(value).unwrap(),
+            CacheFormat::Cbor => {
+                let mut out = Vec::new();
+                encode_cbor(value, &mut out);
+                out
+            }
+        }
+    }
+}
+
+/// Deserialize a response read from the cache, regardless of the format it was stored in.
+///
+/// Returns `None` if the entry has expired.
+pub fn decode_cached(bytes: &mut [u8]) -> Result<Option<Value>, CacheDecodeError> {
+    match strip_expiry(bytes, now_ms()) {
+        Some(payload) => decode_value(payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Deserialize a response read from the cache even if it has expired, as long
+/// as it did less than `max_stale_ms` ago, or any time if it's `0`.
+pub fn decode_stale(
+    bytes: &mut [u8],
+    max_stale_ms: u64,
+) -> Result<Option<Value>, CacheDecodeError> {
+    let oldest = match max_stale_ms {
+        0 => 0,
+        max_stale_ms => now_ms().saturating_sub(max_stale_ms),
+    };
+    match strip_expiry(bytes, oldest) {
+        Some(payload) => decode_value(payload).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Read a single field of a response read from the cache, without decoding the
+/// rest of it when it's stored as CBOR.
+///
+/// `path` is a `.` separated list of keys, e.g. `result.blockHash`. Returns
+/// `None` if the entry has expired or doesn't have the field.
+pub fn cached_field(bytes: &mut [u8], path: &str) -> Result<Option<Value>, CacheDecodeError> {
+    match strip_expiry(bytes, now_ms()) {
+        Some(payload) => decode_field(payload, path),
+        None => Ok(None),
+    }
+}
+
+fn decode_field(bytes: &mut [u8], path: &str) -> Result<Option<Value>, CacheDecodeError> {
+    if let Some(mut decompressed) = decompress(bytes)? {
+        return decode_field(&mut decompressed, path);
+    }
+
+    if bytes.first() == Some(&b'{') {
+        let value: Value = simd_json::serde::from_slice(bytes)
+            .map_err(|err| CacheDecodeError::InvalidJson(err.to_string()))?;
+        return Ok(path
+            .split('.')
+            .try_fold(&value, |value, key| value.get(key))
+            .cloned());
+    }
+
+    let mut pos = 0;
+    let mut depth = 0;
+    for key in path.split('.') {
+        depth += 1;
+        let (major, _, len) = read_header(bytes, &mut pos)?;
+        if major != MAJOR_MAP {
+            return Ok(None);
+        }
+
+        let mut found = false;
+        for _ in 0..len {
+            let (key_major, key_info, key_len) = read_header(bytes, &mut pos)?;
+            if key_major != MAJOR_TEXT {
+                return Err(CacheDecodeError::InvalidType((key_major << 5) | key_info));
+            }
+            if take(bytes, &mut pos, key_len as usize)? == key.as_bytes() {
+                found = true;
+                break;
+            }
+            skip_cbor(bytes, &mut pos, depth)?;
+        }
+        if !found {
+            return Ok(None);
+        }
+    }
+
+    decode_cbor(bytes, &mut pos, depth).map(Some)
+}
+
+fn decode_value(bytes: &mut [u8]) -> Result<Value, CacheDecodeError> {
+    if let Some(mut decompressed) = decompress(bytes)? {
+        return decode_value(&mut decompressed);
+    }
+
+    // Responses are always JSON objects, so JSON text starts with `{`.
+    // CBOR maps start with a major type 5 header which can never be `{`.
+    if bytes.first() == Some(&b'{') {
+        return simd_json::serde::from_slice(bytes)
+            .map_err(|err| CacheDecodeError::InvalidJson(err.to_string()));
+    }
+
+    let mut pos = 0;
+    decode_cbor(bytes, &mut pos, 0)
+}
+
+fn write_header(major: u8, len: u64, out: &mut Vec<u8>) {
+    let major = major << 5;
+    if len < 24 {
+        out.push(major | len as u8);
+    } else if len <= u8::MAX as u64 {
+        out.push(major | 24);
+        out.push(len as u8);
+    } else if len <= u16::MAX as u64 {
+        out.push(major | 25);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else if len <= u32::MAX as u64 {
+        out.push(major | 26);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Returns the raw bytes of `s` if it's a lowercase, even length, `0x` prefixed hex string.
+fn hex_to_bytes(s: &str) -> Option<Vec<u8>> {
+    let hex = s.strip_prefix("0x")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let nibble = |c: u8| {
+        match c {
+            b'0'..=b'9' => Some(c - b'0'),
+            b'a'..=b'f' => Some(c - b'a' + 10),
+            _ => None,
+        }
+    };
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| Some((nibble(pair[0])? << 4) | nibble(pair[1])?))
+        .collect()
+}
+
+fn encode_cbor(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push((MAJOR_SIMPLE << 5) | SIMPLE_NULL),
+        Value::Bool(false) => out.push((MAJOR_SIMPLE << 5) | SIMPLE_FALSE),
+        Value::Bool(true) => out.push((MAJOR_SIMPLE << 5) | SIMPLE_TRUE),
+        Value::Number(number) => {
+            if let Some(n) = number.as_u64() {
+                write_header(MAJOR_UINT, n, out);
+            } else if let Some(n) = number.as_i64() {
+                // CBOR negative integers are stored as -1 - n
+                write_header(MAJOR_NINT, !(n as u64), out);
+            } else {
+                out.push((MAJOR_SIMPLE << 5) | SIMPLE_F64);
+                out.extend_from_slice(&number.as_f64().unwrap_or_default().to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            match hex_to_bytes(s) {
+                Some(bytes) => {
+                    write_header(MAJOR_BYTES, bytes.len() as u64, out);
+                    out.extend_from_slice(&bytes);
+                }
+                None => {
+                    write_header(MAJOR_TEXT, s.len() as u64, out);
+                    out.extend_from_slice(s.as_bytes());
+                }
+            }
+        }
+        Value::Array(array) => {
+            write_header(MAJOR_ARRAY, array.len() as u64, out);
+            for item in array {
+                encode_cbor(item, out);
+            }
+        }
+        Value::Object(map) => {
+            write_header(MAJOR_MAP, map.len() as u64, out);
+            for (key, item) in map {
+                write_header(MAJOR_TEXT, key.len() as u64, out);
+                out.extend_from_slice(key.as_bytes());
+                encode_cbor(item, out);
+            }
+        }
+    }
+}
+
+fn take<'a>(bytes: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8], CacheDecodeError> {
+    let end = pos
+        .checked_add(len)
+        .ok_or(CacheDecodeError::UnexpectedEof)?;
+    let slice = bytes
+        .get(*pos..end)
+        .ok_or(CacheDecodeError::UnexpectedEof)?;
+    *pos = end;
+    Ok(slice)
+}
+
+/// Read a CBOR header, returning the major type and the length/value argument.
+fn read_header(bytes: &[u8], pos: &mut usize) -> Result<(u8, u8, u64), CacheDecodeError> {
+    let initial = take(bytes, pos, 1)?[0];
+    let major = initial >> 5;
+    let info = initial & 0x1f;
+
+    let arg = match info {
+        0..=23 => info as u64,
+        24 => take(bytes, pos, 1)?[0] as u64,
+        25 => u16::from_be_bytes(take(bytes, pos, 2)?.try_into().unwrap()) as u64,
+        26 => u32::from_be_bytes(take(bytes, pos, 4)?.try_into().unwrap()) as u64,
+        27 => u64::from_be_bytes(take(bytes, pos, 8)?.try_into().unwrap()),
+        _ => return Err(CacheDecodeError::InvalidType(initial)),
+    };
+
+    Ok((major, info, arg))
+}
+
+fn read_text(bytes: &[u8], pos: &mut usize, len: u64) -> Result<String, CacheDecodeError> {
+    let text = take(bytes, pos, len as usize)?;
+    String::from_utf8(text.to_vec()).map_err(|_| CacheDecodeError::InvalidUtf8)
+}
+
+/// Move past a CBOR value nested `depth` levels deep without decoding it.
+fn skip_cbor(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<(), CacheDecodeError> {
+    if depth > MAX_DEPTH {
+        return Err(CacheDecodeError::TooDeep);
+    }
+    let (major, info, arg) = read_header(bytes, pos)?;
+
+    match major {
+        MAJOR_UINT | MAJOR_NINT | MAJOR_SIMPLE => {}
+        MAJOR_BYTES | MAJOR_TEXT => {
+            take(bytes, pos, arg as usize)?;
+        }
+        MAJOR_ARRAY => {
+            for _ in 0..arg {
+                skip_cbor(bytes, pos, depth + 1)?;
+            }
+        }
+        MAJOR_MAP => {
+            for _ in 0..arg.saturating_mul(2) {
+                skip_cbor(bytes, pos, depth + 1)?;
+            }
+        }
+        _ => return Err(CacheDecodeError::InvalidType((major << 5) | info)),
+    }
+
+    Ok(())
+}
+
+/// Decode a CBOR value nested `depth` levels deep.
+fn decode_cbor(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<Value, CacheDecodeError> {
+    if depth > MAX_DEPTH {
+        return Err(CacheDecodeError::TooDeep);
+    }
+    let (major, info, arg) = read_header(bytes, pos)?;
+
+    let value = match major {
+        MAJOR_UINT => Value::Number(arg.into()),
+        MAJOR_NINT => {
+            match i64::try_from(arg) {
+                Ok(arg) => Value::Number((-1 - arg).into()),
+                // Below `i64::MIN`, which JSON numbers can only hold as floats
+                Err(_) => {
+                    Number::from_f64(-1.0 - arg as f64)
+                        .map(Value::Number)
+                        .unwrap_or(Value::Null)
+                }
+            }
+        }
+        MAJOR_BYTES => {
+            let raw = take(bytes, pos, arg as usize)?;
+            let mut hex = String::with_capacity(2 + raw.len() * 2);
+            hex.push_str("0x");
+            for byte in raw {
+                hex.push_str(&format!("{:02x}", byte));
+            }
+            Value::String(hex)
+        }
+        MAJOR_TEXT => Value::String(read_text(bytes, pos, arg)?),
+        MAJOR_ARRAY => {
+            let mut array = Vec::new();
+            for _ in 0..arg {
+                array.push(decode_cbor(bytes, pos, depth + 1)?);
+            }
+            Value::Array(array)
+        }
+        MAJOR_MAP => {
+            let mut map = Map::new();
+            for _ in 0..arg {
+                let (key_major, key_info, key_len) = read_header(bytes, pos)?;
+                if key_major != MAJOR_TEXT {
+                    return Err(CacheDecodeError::InvalidType((key_major << 5) | key_info));
+                }
+                let key = read_text(bytes, pos, key_len)?;
+                map.insert(key, decode_cbor(bytes, pos, depth + 1)?);
+            }
+            Value::Object(map)
+        }
+        MAJOR_SIMPLE => {
+            match info {
+                SIMPLE_FALSE => Value::Bool(false),
+                SIMPLE_TRUE => Value::Bool(true),
+                SIMPLE_NULL => Value::Null,
+                SIMPLE_F64 => {
+                    Number::from_f64(f64::from_bits(arg))
+                        .map(Value::Number)
+                        .unwrap_or(Value::Null)
+                }
+                _ => return Err(CacheDecodeError::InvalidType((major << 5) | info)),
+            }
+        }
+        _ => return Err(CacheDecodeError::InvalidType((major << 5) | info)),
+    };
+
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn roundtrip(format: CacheFormat, value: &Value) -> Value {
+        let mut encoded = format.encode(value);
+        decode_cached(&mut encoded).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_json_roundtrip() {
+        let value = json!({"id": null, "jsonrpc": "2.0", "result": "0x1b4"});
+        assert_eq!(roundtrip(CacheFormat::Json, &value), value);
+    }
+
+    #[test]
+    fn test_cbor_roundtrip() {
+        let value = json!({
+            "id": null,
+            "jsonrpc": "2.0",
+            "result": {
+                "number": "0x1b4",
+                "hash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                "mixed": "0xDC08",
+                "empty": "0x",
+                "transactions": [],
+                "uncles": ["not hex", 1, -1, 300000, 1.5, true, false, null],
+                "big": u64::MAX,
+                "small": i64::MIN,
+            }
+        });
+        assert_eq!(roundtrip(CacheFormat::Cbor, &value), value);
+    }
+
+    #[test]
+    fn test_cbor_negative_roundtrip() {
+        for n in [-1, -24, -25, -256, -257, -65537, i64::MIN] {
+            let value = json!({ "result": n });
+            assert_eq!(roundtrip(CacheFormat::Cbor, &value), value);
+        }
+
+        // `-1 - u64::MAX` doesn't fit in an i64
+        let mut encoded = Vec::new();
+        write_header(MAJOR_MAP, 1, &mut encoded);
+        write_header(MAJOR_TEXT, 6, &mut encoded);
+        encoded.extend_from_slice(b"result");
+        write_header(MAJOR_NINT, u64::MAX, &mut encoded);
+        let decoded = decode_cached(&mut encoded).unwrap().unwrap();
+        assert_eq!(decoded["result"].as_f64(), Some(-1.0 - u64::MAX as f64));
+    }
+
+    #[test]
+    fn test_cached_field() {
+        let value = json!({
+            "id": null,
+            "jsonrpc": "2.0",
+            "result": {
+                "logs": [{ "data": "0x00", "topics": [] }, -300, 1.5, null],
+                "blockHash": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+                "status": "0x1",
+            }
+        });
+
+        for format in [CacheFormat::Json, CacheFormat::Cbor] {
+            let encoded = format.encode(&value);
+            assert_eq!(
+                cached_field(&mut encoded.clone(), "result.blockHash").unwrap(),
+                Some(value["result"]["blockHash"].clone())
+            );
+            assert_eq!(
+                cached_field(&mut encoded.clone(), "result.logs").unwrap(),
+                Some(value["result"]["logs"].clone())
+            );
+            assert_eq!(
+                cached_field(&mut encoded.clone(), "result.missing").unwrap(),
+                None
+            );
+            assert_eq!(
+                cached_field(&mut encoded.clone(), "result.status.nested").unwrap(),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn test_cbor_is_smaller() {
+        let value = json!({
+            "id": null,
+            "jsonrpc": "2.0",
+            "result": "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae",
+        });
+        let json = CacheFormat::Json.encode(&value);
+        let cbor = CacheFormat::Cbor.encode(&value);
+        assert!(cbor.len() < json.len());
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        let value = json!({"id": null, "jsonrpc": "2.0", "result": "0x1b4"});
+        let mut encoded = CacheFormat::Cbor.encode(&value);
+        encoded.truncate(encoded.len() - 1);
+        assert!(decode_cached(&mut encoded).is_err());
+
+        // Undefined simple value
+        assert!(decode_cached(&mut [0xf7]).is_err());
+        assert!(decode_cached(&mut []).is_err());
+    }
+
+    #[test]
+    fn test_decode_too_deep() {
+        let nested = |depth: usize| {
+            let mut encoded = Vec::new();
+            write_header(MAJOR_MAP, 1, &mut encoded);
+            write_header(MAJOR_TEXT, 6, &mut encoded);
+            encoded.extend_from_slice(b"result");
+            for _ in 0..depth {
+                write_header(MAJOR_ARRAY, 1, &mut encoded);
+            }
+            write_header(MAJOR_UINT, 1, &mut encoded);
+            encoded
+        };
+
+        assert!(decode_cached(&mut nested(MAX_DEPTH - 1)).is_ok());
+        assert!(matches!(
+            decode_cached(&mut nested(MAX_DEPTH)),
+            Err(CacheDecodeError::TooDeep)
+        ));
+        assert!(matches!(
+            cached_field(&mut nested(MAX_DEPTH), "result"),
+            Err(CacheDecodeError::TooDeep)
+        ));
+
+        // Way past the stack, which would overflow without the limit
+        let mut encoded = nested(1_000_000);
+        assert!(matches!(
+            decode_cached(&mut encoded),
+            Err(CacheDecodeError::TooDeep)
+        ));
+    }
+
+    #[test]
+    fn test_decode_stale() {
+        use crate::database::expiry::with_expiry;
+
+        let value = json!({"id": null, "jsonrpc": "2.0", "result": "0x1b4"});
+        let expired = now_ms() - 60_000;
+        let mut encoded = with_expiry(CacheFormat::Cbor.encode(&value), expired);
+        assert_eq!(decode_cached(&mut encoded.clone()).unwrap(), None);
+
+        assert_eq!(
+            decode_stale(&mut encoded.clone(), 0).unwrap(),
+            Some(value.clone())
+        );
+        assert_eq!(
+            decode_stale(&mut encoded.clone(), 120_000).unwrap(),
+            Some(value.clone())
+        );
+        assert_eq!(decode_stale(&mut encoded, 30_000).unwrap(), None);
+
+        // Entries that never expire are never too stale
+        let mut encoded = CacheFormat::Json.encode(&value);
+        assert_eq!(decode_stale(&mut encoded, 1).unwrap(), Some(value));
+    }
+}
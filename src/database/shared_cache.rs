@@ -0,0 +1,270 @@
+//! Cache shared between Blutgang instances through Redis.
+//!
+//! When several instances sit behind a load balancer, each one would otherwise
+//! have to fetch every response on its own. With a shared cache, entries get
+//! written to Redis next to the local DB, and reads that miss locally check
+//! Redis before going to an RPC.
+//!
+//! Redis is only ever a tier below the local caches. Lookups are bounded by a
+//! timeout, and any error is treated as a miss, so a slow or dead Redis
+//! degrades to the behaviour of a standalone instance.
+//!
+//! Writes don't hold up the DB task. They go through a bounded queue drained by
+//! a writer task, which only uses part of the connection pool so lookups always
+//! have a connection to go to. If Redis can't keep up and the queue fills,
+//! writes get dropped, which only costs other instances a miss.
+
+use crate::{
+    config::{
+        cache_setup::{
+            is_reserved,
+            CACHE_SCHEMA_VERSION,
+        },
+        types::SharedCacheSettings,
+    },
+    database::{
+        redis::{
+            RedisClient,
+            RedisError,
+            Reply,
+        },
+        types::{
+            CACHE_HITS,
+            CACHE_MISSES,
+        },
+    },
+};
+
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::StreamExt;
+use rust_tracing::deps::metrics;
+use tokio::sync::mpsc::{
+    self,
+    error::TrySendError,
+};
+use tokio_stream::wrappers::ReceiverStream;
+
+const TIER: &str = "shared";
+const KEY_PREFIX: &[u8] = b"blutgang:cache:";
+
+/// Write queued for the shared cache.
+#[derive(Debug)]
+pub enum SharedWrite {
+    Set(Vec<u8>, Vec<u8>),
+    /// Deletes happen before inserts, same as in a local batch.
+    Batch {
+        deleted: Vec<Vec<u8>>,
+        inserted: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+}
+
+#[derive(Debug)]
+pub struct SharedCache {
+    remote: Arc<Remote>,
+    writes: mpsc::Sender<SharedWrite>,
+}
+
+/// Connection to Redis, shared by lookups and the writer task.
+#[derive(Debug)]
+struct Remote {
+    client: RedisClient,
+    ttl_ms: u64,
+    timeout: Duration,
+}
+
+impl SharedCache {
+    /// Returns `None` if no Redis URL is set. Otherwise starts the writer task.
+    pub fn new(settings: &SharedCacheSettings) -> Result<Option<Self>, RedisError> {
+        if settings.url.is_empty() {
+            return Ok(None);
+        }
+
+        let pool_size = settings.pool_size.max(1);
+        let remote = Arc::new(Remote {
+            client: RedisClient::new(&settings.url)?.with_pool_size(pool_size),
+            ttl_ms: settings.ttl_ms,
+            timeout: Duration::from_millis(settings.timeout_ms.max(1)),
+        });
+
+        // Leave half the pool to lookups, which are on the request path
+        let (writes, queue) = mpsc::channel(settings.write_queue.max(1));
+        tokio::task::spawn(write_queued(queue, remote.clone(), (pool_size / 2).max(1)));
+
+        Ok(Some(Self { remote, writes }))
+    }
+
+    /// Instances with different cache schemas can't share entries, so the
+    /// schema is part of the key.
+    fn key(key: &[u8]) -> Vec<u8> {
+        let schema = format!("v{}:", CACHE_SCHEMA_VERSION);
+        [KEY_PREFIX, schema.as_bytes(), key].concat()
+    }
+
+    /// Returns true if `key` is instance specific and shouldn't be shared.
+    pub fn is_local(key: &[u8]) -> bool {
+        is_reserved(key)
+    }
+
+    /// Returns the value of `key`. Errors count as misses.
+    pub async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.remote.get(key).await
+    }
+
+    /// Queue `write` for the writer task, dropping it if the queue is full.
+    pub fn queue(&self, write: SharedWrite) {
+        if let Err(TrySendError::Full(_)) = self.writes.try_send(write) {
+            metrics::counter!("shared_cache_writes_dropped_total").increment(1);
+        }
+    }
+}
+
+/// Apply writes coming in through `queue`, up to `concurrency` at a time.
+async fn write_queued(queue: mpsc::Receiver<SharedWrite>, remote: Arc<Remote>, concurrency: usize) {
+    ReceiverStream::new(queue)
+        .for_each_concurrent(concurrency, |write| {
+            let remote = remote.clone();
+            async move {
+                match write {
+                    SharedWrite::Set(key, value) => remote.set(&key, &value).await,
+                    SharedWrite::Batch { deleted, inserted } => {
+                        remote.delete(&deleted).await;
+                        for (key, value) in inserted {
+                            remote.set(&key, &value).await;
+                        }
+                    }
+                }
+            }
+        })
+        .await;
+}
+
+impl Remote {
+    async fn command(&self, args: &[&[u8]]) -> Result<Reply, RedisError> {
+        self.client.command_timeout(args, self.timeout).await
+    }
+
+    /// Returns the value of `key`. Errors count as misses.
+    async fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = match self.command(&[b"GET", &SharedCache::key(key)]).await {
+            Ok(Reply::Bulk(value)) => value,
+            Ok(reply) => {
+                tracing::warn!(?reply, "unexpected shared cache reply");
+                None
+            }
+            Err(err) => {
+                tracing::warn!(%err, "shared cache read failed");
+                metrics::counter!("shared_cache_errors_total", "op" => "get").increment(1);
+                None
+            }
+        };
+
+        if value.is_some() {
+            metrics::counter!(CACHE_HITS, "tier" => TIER).increment(1);
+        } else {
+            metrics::counter!(CACHE_MISSES, "tier" => TIER).increment(1);
+        }
+
+        value
+    }
+
+    /// Store `value` for `key`, expiring after the configured TTL.
+    async fn set(&self, key: &[u8], value: &[u8]) {
+        let key = SharedCache::key(key);
+        let ttl_ms = self.ttl_ms.to_string();
+
+        let result = if self.ttl_ms == 0 {
+            self.command(&[b"SET", &key, value]).await
+        } else {
+            self.command(&[b"SET", &key, value, b"PX", ttl_ms.as_bytes()])
+                .await
+        };
+        if let Err(err) = result {
+            tracing::warn!(%err, "shared cache write failed");
+            metrics::counter!("shared_cache_errors_total", "op" => "set").increment(1);
+        }
+    }
+
+    /// Remove `keys`, so other instances don't serve them either.
+    async fn delete(&self, keys: &[Vec<u8>]) {
+        if keys.is_empty() {
+            return;
+        }
+
+        let keys: Vec<Vec<u8>> = keys.iter().map(|key| SharedCache::key(key)).collect();
+        let mut args = vec![b"DEL".as_slice()];
+        args.extend(keys.iter().map(Vec::as_slice));
+
+        if let Err(err) = self.command(&args).await {
+            tracing::warn!(%err, "shared cache delete failed");
+            metrics::counter!("shared_cache_errors_total", "op" => "delete").increment(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shared_cache_setup() {
+        assert!(SharedCache::new(&SharedCacheSettings::default())
+            .unwrap()
+            .is_none());
+
+        let settings = SharedCacheSettings {
+            url: "redis://127.0.0.1:6379".to_string(),
+            ..Default::default()
+        };
+        assert!(SharedCache::new(&settings).unwrap().is_some());
+
+        let settings = SharedCacheSettings {
+            url: "http://127.0.0.1:6379".to_string(),
+            ..Default::default()
+        };
+        assert!(SharedCache::new(&settings).is_err());
+    }
+
+    #[test]
+    fn test_shared_cache_keys() {
+        assert_eq!(SharedCache::key(b"abc"), b"blutgang:cache:v1:abc");
+        assert!(SharedCache::is_local(b"blake3"));
+        assert!(!SharedCache::is_local(&[1u8; 32]));
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache_unreachable() {
+        // Nothing listens on port 1, every operation should fail fast and count as a miss
+        let cache = SharedCache::new(&SharedCacheSettings {
+            url: "redis://127.0.0.1:1".to_string(),
+            ttl_ms: 1000,
+            timeout_ms: 100,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        cache.remote.set(b"key", b"value").await;
+        assert_eq!(cache.get(b"key").await, None);
+        cache.remote.delete(&[b"key".to_vec()]).await;
+    }
+
+    #[tokio::test]
+    async fn test_shared_cache_queue_full() {
+        let cache = SharedCache::new(&SharedCacheSettings {
+            url: "redis://127.0.0.1:1".to_string(),
+            write_queue: 1,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        // Nothing gets to run the writer task, so only the first write fits
+        cache.queue(SharedWrite::Set(b"a".to_vec(), b"1".to_vec()));
+        cache.queue(SharedWrite::Set(b"b".to_vec(), b"2".to_vec()));
+        assert_eq!(cache.writes.capacity(), 0);
+    }
+}
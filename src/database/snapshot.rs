@@ -0,0 +1,412 @@
+//! Snapshots of the cache, for seeding new instances.
+//!
+//! A snapshot is a file holding every cached entry as it's stored in the DB,
+//! so entries keep their expiry and encoding. It starts with a header:
+//!
+//! - the magic bytes `BLUTSNAP`,
+//! - the cache schema version, as a big endian `u32`,
+//! - the name of the hash function keys were derived with, prefixed by its length as a `u8`,
+//!
+//! followed by the entries, each a key and a value prefixed by their lengths as
+//! big endian `u32`s, then the block the entry was cached at as a big endian
+//! `u64`. Entries of finalized blocks have it set to 0, since reorgs can't
+//! touch them.
+//!
+//! Snapshots are only imported by instances with the same schema version and
+//! hash function, since their keys and values would be meaningless otherwise.
+//! The entries Blutgang keeps about itself are never exported or imported.
+//! Fields are capped at `MAX_FIELD_LEN`, so a crafted snapshot can't make us
+//! allocate more than that at once.
+
+use crate::{
+    config::cache_setup::{
+        is_reserved,
+        CACHE_SCHEMA_VERSION,
+    },
+    database::types::{
+        Batch,
+        GenericDatabase,
+    },
+};
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    fs::File,
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::Path,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"BLUTSNAP";
+
+/// Largest key or value a snapshot can hold.
+pub const MAX_FIELD_LEN: usize = 256 * 1024 * 1024;
+
+/// Hash function keys are derived with.
+#[cfg(not(feature = "xxhash"))]
+const KEY_HASH: &str = "blake3";
+#[cfg(feature = "xxhash")]
+const KEY_HASH: &str = "xxhash";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Not a cache snapshot")]
+    NotASnapshot,
+    #[error("Snapshot has schema version {found} and keys hashed with {hash}, expected version {} and {}", CACHE_SCHEMA_VERSION, KEY_HASH)]
+    Incompatible { found: u32, hash: String },
+    #[error("Snapshot is truncated")]
+    Truncated,
+    #[error("Snapshot field of {0} bytes is over the {} byte cap", MAX_FIELD_LEN)]
+    FieldTooLarge(usize),
+    #[error("Database error: {0}")]
+    Database(String),
+}
+
+/// Write every cached entry in `cache` to a snapshot at `path`, along with
+/// the block `head_cache` tracks it under.
+///
+/// Returns the number of entries written.
+pub fn export<DB>(
+    cache: &DB,
+    path: &Path,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>,
+) -> Result<u64, SnapshotError>
+where
+    DB: GenericDatabase,
+{
+    let blocks: HashMap<[u8; 32], u64> = head_cache
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .flat_map(|(block, keys)| keys.iter().map(|key| (*key, *block)))
+        .collect();
+
+    let mut keys = Vec::new();
+    cache
+        .for_each_key(&mut |key| {
+            if !is_reserved(key) {
+                keys.push(key.to_vec());
+            }
+        })
+        .map_err(|e| SnapshotError::Database(format!("{:?}", e)))?;
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&CACHE_SCHEMA_VERSION.to_be_bytes())?;
+    writer.write_all(&[KEY_HASH.len() as u8])?;
+    writer.write_all(KEY_HASH.as_bytes())?;
+
+    let mut exported = 0;
+    for key in keys {
+        let value = cache
+            .read(key.as_slice())
+            .map_err(|e| SnapshotError::Database(format!("{:?}", e)))?;
+        // Deleted since we listed it
+        let Some(value) = value else {
+            continue;
+        };
+        if value.len() > MAX_FIELD_LEN {
+            tracing::warn!(
+                len = value.len(),
+                "Cache entry too large to export, skipping it"
+            );
+            continue;
+        }
+
+        let block = <[u8; 32]>::try_from(key.as_slice())
+            .ok()
+            .and_then(|key| blocks.get(&key).copied())
+            .unwrap_or(0);
+
+        write_field(&mut writer, &key)?;
+        write_field(&mut writer, &value)?;
+        writer.write_all(&block.to_be_bytes())?;
+        exported += 1;
+    }
+    writer.flush()?;
+
+    Ok(exported)
+}
+
+/// Read the snapshot at `path`, passing its entries to `apply` in batches of
+/// up to `batch_size`.
+///
+/// Once a batch is applied, its entries of unfinalized blocks are added to
+/// `head_cache`, so they get dropped if their block is reorged out.
+///
+/// Returns the number of entries imported.
+pub fn import<F>(
+    path: &Path,
+    batch_size: usize,
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>,
+    mut apply: F,
+) -> Result<u64, SnapshotError>
+where
+    F: FnMut(Batch<Vec<u8>, Vec<u8>>) -> Result<(), SnapshotError>,
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    read_header(&mut reader)?;
+
+    let mut imported = 0;
+    let mut batch = Batch::with_capacity(batch_size);
+    let mut batched = 0;
+    // Unfinalized entries of `batch`
+    let mut unfinalized = Vec::new();
+    while let Some(key) = read_field(&mut reader)? {
+        let value = read_field(&mut reader)?.ok_or(SnapshotError::Truncated)?;
+        let mut block = [0; 8];
+        read_exact(&mut reader, &mut block)?;
+        if is_reserved(&key) {
+            continue;
+        }
+
+        let block = u64::from_be_bytes(block);
+        if block != 0 {
+            if let Ok(key) = <[u8; 32]>::try_from(key.as_slice()) {
+                unfinalized.push((block, key));
+            }
+        }
+
+        batch.insert(key, value);
+        batched += 1;
+        if batched == batch_size {
+            apply(std::mem::replace(
+                &mut batch,
+                Batch::with_capacity(batch_size),
+            ))?;
+            register(head_cache, &mut unfinalized);
+            imported += batched as u64;
+            batched = 0;
+        }
+    }
+    if batched > 0 {
+        apply(batch)?;
+        register(head_cache, &mut unfinalized);
+        imported += batched as u64;
+    }
+
+    Ok(imported)
+}
+
+/// Track `unfinalized` entries in `head_cache` under the block they were cached at.
+fn register(
+    head_cache: &Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>,
+    unfinalized: &mut Vec<(u64, [u8; 32])>,
+) {
+    if unfinalized.is_empty() {
+        return;
+    }
+
+    let mut head_cache = head_cache.write().unwrap_or_else(|e| e.into_inner());
+    for (block, key) in unfinalized.drain(..) {
+        head_cache.entry(block).or_default().push(key);
+    }
+}
+
+/// Check the snapshot `reader` reads from can be imported by this instance.
+fn read_header<R: Read>(reader: &mut R) -> Result<(), SnapshotError> {
+    let mut magic = [0; SNAPSHOT_MAGIC.len()];
+    read_exact(reader, &mut magic).map_err(|_| SnapshotError::NotASnapshot)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::NotASnapshot);
+    }
+
+    let mut version = [0; 4];
+    read_exact(reader, &mut version)?;
+    let mut hash_len = [0; 1];
+    read_exact(reader, &mut hash_len)?;
+    let mut hash = [0; u8::MAX as usize];
+    let hash = &mut hash[..hash_len[0] as usize];
+    read_exact(reader, hash)?;
+
+    let found = u32::from_be_bytes(version);
+    if found != CACHE_SCHEMA_VERSION || hash != KEY_HASH.as_bytes() {
+        return Err(SnapshotError::Incompatible {
+            found,
+            hash: String::from_utf8_lossy(hash).into_owned(),
+        });
+    }
+
+    Ok(())
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &[u8]) -> io::Result<()> {
+    writer.write_all(&(field.len() as u32).to_be_bytes())?;
+    writer.write_all(field)
+}
+
+/// Read a length prefixed field, returning `None` at the end of the snapshot.
+///
+/// The field grows as it's read, so a length the snapshot doesn't have the
+/// bytes for can't make us allocate it upfront.
+fn read_field<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>, SnapshotError> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut len = [0; 4];
+    read_exact(reader, &mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FIELD_LEN {
+        return Err(SnapshotError::FieldTooLarge(len));
+    }
+
+    let mut field = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut field)?;
+    if field.len() != len {
+        return Err(SnapshotError::Truncated);
+    }
+
+    Ok(Some(field))
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), SnapshotError> {
+    reader.read_exact(buf).map_err(|e| {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => SnapshotError::Truncated,
+            _ => SnapshotError::Io(e),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sled::{
+        Config,
+        Db,
+    };
+
+    fn tmp_db() -> Db {
+        Db::open_with_config(&Config::tmp().unwrap()).unwrap()
+    }
+
+    fn tmp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("blutgang-{}-{}.snapshot", name, std::process::id()))
+    }
+
+    fn head_cache() -> Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>> {
+        Arc::new(RwLock::new(BTreeMap::new()))
+    }
+
+    fn header() -> Vec<u8> {
+        let mut header = SNAPSHOT_MAGIC.to_vec();
+        header.extend_from_slice(&CACHE_SCHEMA_VERSION.to_be_bytes());
+        header.push(KEY_HASH.len() as u8);
+        header.extend_from_slice(KEY_HASH.as_bytes());
+        header
+    }
+
+    #[test]
+    fn test_export_import() {
+        let source = tmp_db();
+        source
+            .write(b"blake3".as_slice(), b"blake3".as_slice())
+            .unwrap();
+        for i in 0..5u8 {
+            source.write([i; 32].as_slice(), vec![i; 100]).unwrap();
+        }
+        let source_heads = head_cache();
+        source_heads
+            .write()
+            .unwrap()
+            .insert(100, vec![[3; 32], [4; 32]]);
+
+        let path = tmp_path("roundtrip");
+        assert_eq!(export(&source, &path, &source_heads).unwrap(), 5);
+
+        let target = tmp_db();
+        let target_heads = head_cache();
+        let mut batches = 0;
+        let imported = import(&path, 2, &target_heads, |batch| {
+            batches += 1;
+            target
+                .batch(batch)
+                .map_err(|e| SnapshotError::Database(format!("{:?}", e)))
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(imported, 5);
+        assert_eq!(batches, 3);
+        for i in 0..5u8 {
+            assert_eq!(target.read([i; 32].as_slice()).unwrap(), Some(vec![i; 100]));
+        }
+        // Reserved keys stay behind
+        assert_eq!(target.read(b"blake3".as_slice()).unwrap(), None);
+
+        // Unfinalized entries can still be reorged out
+        let mut tracked = target_heads.read().unwrap().get(&100).cloned().unwrap();
+        tracked.sort();
+        assert_eq!(tracked, vec![[3; 32], [4; 32]]);
+        assert_eq!(target_heads.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_import_rejects() {
+        let path = tmp_path("rejects");
+        let heads = head_cache();
+        let apply = |_: Batch<Vec<u8>, Vec<u8>>| Ok(());
+
+        std::fs::write(&path, b"not a snapshot").unwrap();
+        assert!(matches!(
+            import(&path, 1, &heads, apply),
+            Err(SnapshotError::NotASnapshot)
+        ));
+
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend_from_slice(&(CACHE_SCHEMA_VERSION + 1).to_be_bytes());
+        snapshot.push(KEY_HASH.len() as u8);
+        snapshot.extend_from_slice(KEY_HASH.as_bytes());
+        std::fs::write(&path, &snapshot).unwrap();
+        assert!(matches!(
+            import(&path, 1, &heads, apply),
+            Err(SnapshotError::Incompatible { .. })
+        ));
+
+        // Entry cut off after its key
+        let mut snapshot = header();
+        snapshot.extend_from_slice(&1u32.to_be_bytes());
+        snapshot.push(1);
+        std::fs::write(&path, &snapshot).unwrap();
+        assert!(matches!(
+            import(&path, 1, &heads, apply),
+            Err(SnapshotError::Truncated)
+        ));
+
+        // Lengths past what the file holds don't get allocated
+        let mut snapshot = header();
+        snapshot.extend_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&path, &snapshot).unwrap();
+        assert!(matches!(
+            import(&path, 1, &heads, apply),
+            Err(SnapshotError::FieldTooLarge(_))
+        ));
+
+        let mut snapshot = header();
+        snapshot.extend_from_slice(&(MAX_FIELD_LEN as u32).to_be_bytes());
+        snapshot.push(1);
+        std::fs::write(&path, &snapshot).unwrap();
+        assert!(matches!(
+            import(&path, 1, &heads, apply),
+            Err(SnapshotError::Truncated)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
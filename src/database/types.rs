@@ -1,13 +1,27 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+#[cfg(feature = "redb")]
+use redb::ReadableTable;
 use rust_tracing::deps::metrics;
 use tokio::sync::{
     mpsc,
     oneshot,
 };
 
-const CACHE_HITS: &str = "cache_hits";
-const CACHE_MISSES: &str = "cache_misses";
+pub(crate) const CACHE_HITS: &str = "cache_hits";
+pub(crate) const CACHE_MISSES: &str = "cache_misses";
+const DB_TIER: &str = "db";
 const DB_SIZE_MB: &str = "db_size_mb";
 const ROCKSDB_SIZE_PROPERTY: &str = "rocksdb.total-sst-files-size";
+#[cfg(feature = "redb")]
+const REDB_TABLE: redb::TableDefinition<&[u8], &[u8]> = redb::TableDefinition::new("cache");
 
 /// Channel for sending requests to the database thread
 ///
@@ -49,13 +63,44 @@ where
     pub fn with_capacity(capacity: usize) -> Self {
         Self(Vec::with_capacity(capacity))
     }
-    #[allow(dead_code)]
     pub fn insert(&mut self, key: K, value: V) {
         self.0.push(BatchOp::Insert(key, value))
     }
     pub fn delete(&mut self, key: K) {
         self.0.push(BatchOp::Delete(key))
     }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Keys and values written by the batch.
+    pub fn inserts(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.0.iter().filter_map(|op| {
+            match op {
+                BatchOp::Insert(key, value) => Some((key, value)),
+                BatchOp::Delete(_) => None,
+            }
+        })
+    }
+    /// Keys deleted by the batch.
+    pub fn deletes(&self) -> impl Iterator<Item = &K> {
+        self.0.iter().filter_map(|op| {
+            match op {
+                BatchOp::Insert(..) => None,
+                BatchOp::Delete(key) => Some(key),
+            }
+        })
+    }
+    /// Keys written or deleted by the batch.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.0.iter().map(|op| {
+            match op {
+                BatchOp::Insert(key, _) | BatchOp::Delete(key) => key,
+            }
+        })
+    }
 }
 impl<K, V> From<Vec<BatchOp<K, V>>> for Batch<K, V>
 where
@@ -96,6 +141,12 @@ pub trait GenericDatabase: Send {
     fn flush(&self) -> Result<(), Self::Error>;
 
     fn clear(&self) -> Result<(), Self::Error>;
+
+    /// Size of the database on disk in bytes.
+    fn disk_size(&self) -> Result<u64, Self::Error>;
+
+    /// Call `f` with every key in the database.
+    fn for_each_key(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), Self::Error>;
 }
 
 impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
@@ -116,9 +167,9 @@ impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
             .map(|opt| opt.map(|ia| ia.to_vec()))
             .inspect(|opt| {
                 if opt.is_some() {
-                    metrics::counter!(CACHE_HITS).increment(1);
+                    metrics::counter!(CACHE_HITS, "tier" => DB_TIER).increment(1);
                 } else {
-                    metrics::counter!(CACHE_MISSES).increment(1);
+                    metrics::counter!(CACHE_MISSES, "tier" => DB_TIER).increment(1);
                 }
             })
     }
@@ -178,6 +229,18 @@ impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
             }
         })
     }
+
+    fn disk_size(&self) -> Result<u64, Self::Error> {
+        self.size_on_disk()
+    }
+
+    fn for_each_key(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), Self::Error> {
+        for item in self.iter() {
+            let (key, _) = item?;
+            f(key.as_ref());
+        }
+        Ok(())
+    }
 }
 
 // Also important to note, some operations do behave differently between thread modes, such as
@@ -206,9 +269,9 @@ impl<T: rocksdb::ThreadMode + Send> GenericDatabase for rocksdb::DBWithThreadMod
     fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
         self.get(key).inspect(|opt| {
             if opt.is_some() {
-                metrics::counter!(CACHE_HITS).increment(1);
+                metrics::counter!(CACHE_HITS, "tier" => DB_TIER).increment(1);
             } else {
-                metrics::counter!(CACHE_MISSES).increment(1);
+                metrics::counter!(CACHE_MISSES, "tier" => DB_TIER).increment(1);
             }
         })
     }
@@ -273,6 +336,168 @@ impl<T: rocksdb::ThreadMode + Send> GenericDatabase for rocksdb::DBWithThreadMod
                 .collect::<Vec<BatchOp<_, _>>>(),
         ))
     }
+
+    fn disk_size(&self) -> Result<u64, Self::Error> {
+        self.property_int_value(ROCKSDB_SIZE_PROPERTY)
+            .map(|size| size.unwrap_or_default())
+    }
+
+    fn for_each_key(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), Self::Error> {
+        for item in self.iterator(rocksdb::IteratorMode::Start) {
+            let (key, _) = item?;
+            f(&key);
+        }
+        Ok(())
+    }
+}
+
+/// Options for opening a [`RedbCache`].
+#[cfg(feature = "redb")]
+#[derive(Debug, Clone)]
+pub struct RedbConfig {
+    pub path: PathBuf,
+    pub cache_size_bytes: Option<usize>,
+}
+
+#[cfg(feature = "redb")]
+impl Default for RedbConfig {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("./blutgang-cache.redb"),
+            cache_size_bytes: None,
+        }
+    }
+}
+
+/// Cache stored in a single [`redb`] table.
+///
+/// redb is copy-on-write and doesn't need recovery or compaction after a crash,
+/// at the cost of being slower on writes than sled or RocksDB.
+#[cfg(feature = "redb")]
+pub struct RedbCache {
+    db: redb::Database,
+    // redb doesn't report its size, so we check the size of the file instead
+    path: PathBuf,
+}
+
+// Writes are committed with eventual durability so every insert doesn't wait on an fsync.
+// `flush` commits with immediate durability, which persists everything written before it.
+//
+// Checking the size of the file on every write would cost a syscall each, so the
+// size is only gauged periodically, see `gauge_db_size`.
+#[cfg(feature = "redb")]
+impl GenericDatabase for RedbCache {
+    type Error = redb::Error;
+    type Config = RedbConfig;
+
+    fn open(config: &Self::Config) -> Result<Self, Self::Error> {
+        let mut builder = redb::Builder::new();
+        if let Some(cache_size) = config.cache_size_bytes {
+            builder.set_cache_size(cache_size);
+        }
+        let db = builder.create(&config.path)?;
+
+        // Reads fail on tables that haven't been created yet
+        let txn = db.begin_write()?;
+        txn.open_table(REDB_TABLE)?;
+        txn.commit()?;
+
+        Ok(Self {
+            db,
+            path: config.path.clone(),
+        })
+    }
+
+    fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(REDB_TABLE)?;
+        let value = table.get(key.as_ref())?.map(|value| value.value().to_vec());
+
+        if value.is_some() {
+            metrics::counter!(CACHE_HITS, "tier" => DB_TIER).increment(1);
+        } else {
+            metrics::counter!(CACHE_MISSES, "tier" => DB_TIER).increment(1);
+        }
+        Ok(value)
+    }
+
+    fn write<K, V>(&self, key: K, val: V) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(redb::Durability::Eventual);
+        {
+            let mut table = txn.open_table(REDB_TABLE)?;
+            table.insert(key.as_ref(), val.as_ref())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(redb::Durability::Eventual);
+        {
+            let mut table = txn.open_table(REDB_TABLE)?;
+            for op in batch.0 {
+                match op {
+                    BatchOp::Insert(key, value) => {
+                        table.insert(key.as_ref(), value.as_ref())?;
+                    }
+                    BatchOp::Delete(key) => {
+                        table.remove(key.as_ref())?;
+                    }
+                }
+            }
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(redb::Durability::Immediate);
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        let txn = self.db.begin_write()?;
+        txn.delete_table(REDB_TABLE)?;
+        txn.open_table(REDB_TABLE)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn disk_size(&self) -> Result<u64, Self::Error> {
+        std::fs::metadata(&self.path)
+            .map(|metadata| metadata.len())
+            .map_err(redb::Error::Io)
+    }
+
+    fn for_each_key(&self, f: &mut dyn FnMut(&[u8])) -> Result<(), Self::Error> {
+        let txn = self.db.begin_read()?;
+        let table = txn.open_table(REDB_TABLE)?;
+        for item in table.iter()? {
+            let (key, _) = item?;
+            f(key.value());
+        }
+        Ok(())
+    }
+}
+
+/// Set the `db_size_mb` gauge to the size of `cache` on disk.
+pub fn gauge_db_size<DB: GenericDatabase>(cache: &DB) {
+    match cache.disk_size().map(|size| size / (1024 * 1024)) {
+        Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
+        Err(err) => tracing::warn!(?err, "failed to gauge database size"),
+    }
 }
 
 /// Specifies if we are reading or writing to the DB.
@@ -285,6 +510,14 @@ where
     Write(K, V),
     Batch(Batch<K, V>),
     Flush,
+    /// Delete every entry except the ones Blutgang keeps about itself.
+    Clear,
+    /// Write a snapshot of the cache to a file, with the blocks the head cache
+    /// tracks entries under.
+    Export(PathBuf, Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>),
+    /// Insert every entry of a snapshot file, tracking unfinalized ones in
+    /// the head cache.
+    Import(PathBuf, Arc<RwLock<BTreeMap<u64, Vec<[u8; 32]>>>>),
 }
 
 /// Contains data to be sent to the DB thread for processing.
@@ -306,3 +539,42 @@ where
         DbRequest { request, sender }
     }
 }
+
+#[cfg(all(test, feature = "redb"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redb_cache() {
+        let path = std::env::temp_dir().join(format!("blutgang-test-{}.redb", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let cache = RedbCache::open(&RedbConfig {
+            path: path.clone(),
+            cache_size_bytes: None,
+        })
+        .unwrap();
+
+        cache.write(b"a".to_vec(), b"1".to_vec()).unwrap();
+        assert_eq!(cache.read(b"a".to_vec()).unwrap(), Some(b"1".to_vec()));
+        assert_eq!(cache.read(b"b".to_vec()).unwrap(), None);
+
+        let mut batch = Batch::with_capacity(2);
+        batch.insert(b"b".to_vec(), b"2".to_vec());
+        batch.delete(b"a".to_vec());
+        cache.batch(batch).unwrap();
+        cache.flush().unwrap();
+
+        let mut keys = Vec::new();
+        cache
+            .for_each_key(&mut |key| keys.push(key.to_vec()))
+            .unwrap();
+        assert_eq!(keys, vec![b"b".to_vec()]);
+        assert!(cache.disk_size().unwrap() > 0);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.read(b"b".to_vec()).unwrap(), None);
+
+        drop(cache);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,289 @@
+//! Cache writes off the request path.
+//!
+//! Responses get cached after they're fetched, but clients shouldn't have to
+//! wait on the DB for that. Instead of going straight to the DB task, writes
+//! are queued on a bounded channel, and a writer task hands them to the DB task
+//! in batches. While a batch is being written, new writes pile up in the queue
+//! and all go out with the next batch.
+//!
+//! If the DB falls so far behind that the queue fills up, new writes are
+//! dropped. A dropped write only costs a cache miss later, which beats growing
+//! the queue without bound or making clients wait.
+//!
+//! Queued writes could land after a reorg removed their block from the cache,
+//! bringing reorged responses back. Every write is tagged with the reorg epoch
+//! it was queued in, and writes from before the latest reorg are dropped.
+//!
+//! On shutdown, `flush` waits for everything queued so far to be written, so
+//! exiting doesn't lose or tear writes the writer task is still holding.
+
+use crate::{
+    config::types::CacheWriteSettings,
+    database::{
+        accept::db_batch,
+        types::{
+            Batch,
+            DbRequest,
+            GenericBytes,
+            RequestBus,
+            RequestKind,
+        },
+    },
+    health::head_cache::reorg_epoch,
+};
+
+use rust_tracing::deps::metrics;
+use tokio::sync::{
+    mpsc::{
+        self,
+        error::TrySendError,
+    },
+    oneshot,
+};
+
+/// Where cache writes go.
+#[derive(Debug, Clone)]
+pub enum CacheWriter<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    /// Writes go straight to the DB task, in order with everything else.
+    Direct(RequestBus<K, V>),
+    /// Writes are queued for the writer task.
+    Queued(mpsc::Sender<QueuedWrite<K, V>>),
+}
+
+/// What goes through the queue of the writer task.
+#[derive(Debug)]
+pub enum QueuedWrite<K, V> {
+    /// Write `V` under `K`, unless a reorg happened since the epoch.
+    Entry(K, V, u64),
+    /// Acknowledge once everything queued before this was written.
+    Flush(oneshot::Sender<()>),
+}
+
+impl<K, V> CacheWriter<K, V>
+where
+    K: GenericBytes + 'static,
+    V: GenericBytes + 'static,
+{
+    /// Start a writer task for `cache`, unless `settings` disable the queue.
+    pub fn new(cache: RequestBus<K, V>, settings: CacheWriteSettings) -> Self {
+        if settings.queue_size == 0 {
+            return Self::Direct(cache);
+        }
+
+        let (queue_tx, queue_rx) = mpsc::channel(settings.queue_size);
+        tokio::task::spawn(write_batches(queue_rx, cache, settings.batch_size.max(1)));
+        Self::Queued(queue_tx)
+    }
+}
+
+impl<K, V> CacheWriter<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    /// Write `value` under `key` without waiting for it to be written.
+    pub fn write(&self, key: K, value: V) {
+        match self {
+            Self::Direct(cache) => {
+                // Nobody waits on the result
+                let (tx, _) = oneshot::channel();
+                let _ = cache.send(DbRequest::new(RequestKind::Write(key, value), tx));
+            }
+            Self::Queued(queue) => {
+                if let Err(TrySendError::Full(_)) =
+                    queue.try_send(QueuedWrite::Entry(key, value, reorg_epoch()))
+                {
+                    metrics::counter!("cache_writes_dropped_total").increment(1);
+                }
+            }
+        }
+    }
+
+    /// Wait until every write made so far was handed to the DB task.
+    ///
+    /// Direct writes already are, in order with whatever comes after them.
+    pub async fn flush(&self) {
+        if let Self::Queued(queue) = self {
+            let (tx, rx) = oneshot::channel();
+            if queue.send(QueuedWrite::Flush(tx)).await.is_ok() {
+                let _ = rx.await;
+            }
+        }
+    }
+}
+
+/// Write everything that comes in through `queue` to `cache`, up to `batch_size` entries at a time.
+async fn write_batches<K, V>(
+    mut queue: mpsc::Receiver<QueuedWrite<K, V>>,
+    cache: RequestBus<K, V>,
+    batch_size: usize,
+) where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    let mut writes = Vec::with_capacity(batch_size);
+    let mut flushes = Vec::new();
+    while queue.recv_many(&mut writes, batch_size).await > 0 {
+        let mut batch = Batch::with_capacity(writes.len());
+        let epoch = reorg_epoch();
+        for write in writes.drain(..) {
+            match write {
+                QueuedWrite::Entry(key, value, queued_in) => {
+                    if queued_in != epoch {
+                        // Might be from a block that got reorged out
+                        metrics::counter!("cache_writes_stale_total").increment(1);
+                        continue;
+                    }
+                    batch.insert(key, value);
+                }
+                QueuedWrite::Flush(ack) => flushes.push(ack),
+            }
+        }
+
+        if !batch.is_empty() {
+            metrics::histogram!("cache_write_batch_size").record(batch.len() as f64);
+            // Waiting on the DB is what lets writes queue up into the next batch
+            let _ = db_batch(&cache, batch).await.await;
+        }
+        for ack in flushes.drain(..) {
+            let _ = ack.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            eviction::CacheEviction,
+            hot_cache::HotCache,
+        },
+        database_processing,
+        db_get,
+    };
+    use sled::{
+        Config,
+        Db,
+    };
+
+    fn cache() -> RequestBus<Vec<u8>, Vec<u8>> {
+        let (db_tx, db_rx) = mpsc::unbounded_channel();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            Db::open_with_config(&Config::tmp().unwrap()).unwrap(),
+            HotCache::new(1024),
+            CacheEviction::default(),
+            None,
+        ));
+        db_tx
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_queued_writes() {
+        let cache = cache();
+        let writer = CacheWriter::new(
+            cache.clone(),
+            CacheWriteSettings {
+                queue_size: 16,
+                batch_size: 4,
+            },
+        );
+        assert!(matches!(writer, CacheWriter::Queued(_)));
+
+        for i in 0..10u8 {
+            writer.write(vec![i; 32], vec![i]);
+        }
+
+        // Writes land eventually
+        let mut landed = false;
+        for _ in 0..100 {
+            if db_get!(cache, vec![9u8; 32]).unwrap().is_some() {
+                landed = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(landed);
+        for i in 0..10u8 {
+            assert_eq!(db_get!(cache, vec![i; 32]).unwrap(), Some(vec![i]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_direct_writes() {
+        let cache = cache();
+        let writer = CacheWriter::new(
+            cache.clone(),
+            CacheWriteSettings {
+                queue_size: 0,
+                batch_size: 4,
+            },
+        );
+
+        // Ordered with reads that come after
+        writer.write(vec![1u8; 32], vec![1]);
+        assert_eq!(db_get!(cache, vec![1u8; 32]).unwrap(), Some(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_writes() {
+        // Nothing reads the queue, and the DB task is gone
+        let (queue_tx, _queue_rx) = mpsc::channel(1);
+        let writer = CacheWriter::<Vec<u8>, Vec<u8>>::Queued(queue_tx.clone());
+
+        writer.write(vec![1], vec![1]);
+        writer.write(vec![2], vec![2]);
+        assert_eq!(queue_tx.capacity(), 0);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_flush() {
+        let cache = cache();
+        let writer = CacheWriter::new(
+            cache.clone(),
+            CacheWriteSettings {
+                queue_size: 64,
+                batch_size: 4,
+            },
+        );
+
+        for i in 0..10u8 {
+            writer.write(vec![i; 32], vec![i]);
+        }
+        writer.flush().await;
+        for i in 0..10u8 {
+            assert_eq!(db_get!(cache, vec![i; 32]).unwrap(), Some(vec![i]));
+        }
+
+        // Nothing left to write
+        writer.flush().await;
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_stale_writes_dropped() {
+        let cache = cache();
+        let (queue_tx, queue_rx) = mpsc::channel(16);
+        tokio::task::spawn(write_batches(queue_rx, cache.clone(), 4));
+        let writer = CacheWriter::Queued(queue_tx.clone());
+
+        // Queued before a reorg
+        let stale = reorg_epoch().wrapping_sub(1);
+        queue_tx
+            .send(QueuedWrite::Entry(vec![1u8; 32], vec![1], stale))
+            .await
+            .unwrap();
+        writer.write(vec![2u8; 32], vec![2]);
+        writer.flush().await;
+
+        assert_eq!(db_get!(cache, vec![1u8; 32]).unwrap(), None);
+        assert_eq!(db_get!(cache, vec![2u8; 32]).unwrap(), Some(vec![2]));
+    }
+}
@@ -0,0 +1,188 @@
+//! Capability discovery.
+//!
+//! Not every node exposes every namespace. On startup we ask each RPC which
+//! modules it supports via `rpc_modules`, along with its `web3_clientVersion`,
+//! and warn if it doesn't support the methods we're configured to health check.
+//! RPCs added later are asked too: in the background when added through admin,
+//! and before they join the pool on config reloads. Requests only go to RPCs supporting their namespace, see
+//! `Route`. Dormant emergency RPCs aren't asked, see `emergency`.
+
+use crate::{
+    config::types::{
+        HealthCheckMethod,
+        HealthCheckMethods,
+    },
+    health::emergency::{
+        emergency_dormant,
+        query_awake,
+    },
+    rpc::types::Capabilities,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use tokio::time::timeout;
+
+/// Ask a single RPC for its capabilities. Anything it doesn't answer stays `None`.
+async fn query_capabilities(rpc: &Rpc, ttl: Duration) -> Capabilities {
+    let (modules, client_version) = tokio::join!(
+        timeout(ttl, rpc.rpc_modules()),
+        timeout(ttl, rpc.client_version()),
+    );
+
+    Capabilities {
+        modules: modules.ok().and_then(Result::ok),
+        client_version: client_version.ok().and_then(Result::ok),
+    }
+}
+
+/// Returns the health check methods the RPC doesn't support.
+fn unsupported_methods<'a>(
+    capabilities: &Capabilities,
+    health_check_methods: impl IntoIterator<Item = &'a HealthCheckMethod>,
+) -> Vec<&'a str> {
+    health_check_methods
+        .into_iter()
+        .map(|health_check_method| health_check_method.method.as_str())
+        .filter(|method| !capabilities.supports(method))
+        .collect()
+}
+
+/// Discover and record the capabilities of `rpcs` added after startup.
+///
+/// Emergency RPCs aren't asked, they're dormant while the pool is healthy.
+pub async fn probe_capabilities<'a>(rpcs: impl IntoIterator<Item = &'a mut Rpc>, ttl: u128) {
+    let ttl = Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX));
+
+    join_all(rpcs.into_iter().filter(|rpc| !rpc.emergency).map(|rpc| {
+        async move {
+            rpc.capabilities = query_capabilities(rpc, ttl).await;
+            tracing::info!(
+                modules = ?rpc.capabilities.modules,
+                client_version = ?rpc.capabilities.client_version,
+                "Discovered capabilities of {}",
+                rpc.name
+            );
+        }
+    }))
+    .await;
+}
+
+/// Discover the capabilities of `rpc`, just added to `rpc_list`, in the background.
+pub fn spawn_capability_probe(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc: &Rpc, ttl: u128) {
+    let rpc_list = Arc::clone(rpc_list);
+    let mut rpc = rpc.clone();
+
+    tokio::spawn(async move {
+        probe_capabilities([&mut rpc], ttl).await;
+
+        let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = rpc_list_guard.iter_mut().find(|entry| entry.same_url(&rpc)) {
+            entry.capabilities = rpc.capabilities;
+        }
+    });
+}
+
+/// Discover and record the capabilities of every RPC in `rpc_list`.
+pub async fn discover_capabilities(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    health_check_methods: &HealthCheckMethods,
+    ttl: u128,
+) {
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .clone();
+
+    let ttl = Duration::from_millis(ttl.try_into().unwrap());
+    let discovered = query_awake(
+        &rpc_list_clone,
+        emergency_dormant(&rpc_list_clone),
+        |rpcs| {
+            async move {
+                join_all(rpcs.iter().map(|rpc| query_capabilities(rpc, ttl)))
+                    .await
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            }
+        },
+    )
+    .await;
+
+    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+
+    for (index, (rpc, capabilities)) in rpc_list_clone.iter().zip(discovered).enumerate() {
+        let Some(capabilities) = capabilities else {
+            continue;
+        };
+
+        tracing::info!(
+            modules = ?capabilities.modules,
+            client_version = ?capabilities.client_version,
+            "Discovered capabilities of {}",
+            rpc.name
+        );
+
+        let unsupported = unsupported_methods(&capabilities, health_check_methods.for_rpc(rpc));
+        if !unsupported.is_empty() {
+            tracing::warn!(
+                ?unsupported,
+                "{} does not support some of the health check methods! It will be marked as unhealthy.",
+                rpc.name
+            );
+        }
+
+        // The list might have changed while we were waiting on responses
+        if let Some(entry) = rpc_list_guard.get_mut(index) {
+            if entry.name == rpc.name {
+                entry.capabilities = capabilities;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unsupported_methods() {
+        let health_check_methods = [
+            HealthCheckMethod {
+                method: "eth_getBalance".to_string(),
+                params: Vec::new(),
+            },
+            HealthCheckMethod {
+                method: "trace_block".to_string(),
+                params: Vec::new(),
+            },
+        ];
+
+        let capabilities = Capabilities {
+            modules: Some(vec!["eth".to_string(), "net".to_string()]),
+            client_version: Some("Geth/v1.14.0".to_string()),
+        };
+        assert_eq!(
+            unsupported_methods(&capabilities, &health_check_methods),
+            vec!["trace_block"]
+        );
+
+        // Nothing is unsupported if we don't know the modules
+        assert!(unsupported_methods(&Capabilities::default(), &health_check_methods).is_empty());
+    }
+}
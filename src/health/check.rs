@@ -4,24 +4,36 @@ use crate::{
         LiveReadyUpdate,
         LiveReadyUpdateSnd,
     },
+    config::types::{
+        HealthCheckMethod,
+        HealthCheckMethods,
+    },
+    database::expiry::now_ms,
     health::{
+        consensus::{
+            update_consensus,
+            Consensus,
+        },
+        divergence::check_finalized_divergence,
+        emergency::{
+            emergency_dormant,
+            EmergencyState,
+        },
         error::HealthError,
+        events::HealthEvents,
+        head_staleness::exclude_stale,
+        quarantine::cross_check,
         safe_block::{
             get_safe_block,
             NamedBlocknumbers,
         },
     },
-    websocket::{
-        subscription_manager::move_subscriptions,
-        types::{
-            WsChannelErr,
-            WsconnMessage,
-        },
+    websocket::types::{
+        WsChannelErr,
+        WsconnMessage,
     },
-    IncomingResponse,
     Rpc,
     Settings,
-    SubscriptionData,
 };
 
 use std::{
@@ -33,9 +45,12 @@ use std::{
 };
 
 use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
 use tokio::{
     sync::{
-        broadcast,
         mpsc,
         oneshot,
     },
@@ -49,12 +64,15 @@ use tokio::{
 struct HeadResult {
     rpc_list_index: usize,
     is_syncing: bool,
+    // Slow nodes aren't syncing, they're just not answering in time
+    timed_out: bool,
     reported_head: u64,
 }
 
 #[derive(Debug)]
 struct InnerResult {
     is_syncing: bool,
+    timed_out: bool,
     reported_head: u64,
 }
 
@@ -66,11 +84,28 @@ pub async fn health_check(
     liveness_tx: LiveReadyUpdateSnd,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     config: &Arc<RwLock<Settings>>,
+    health_events: &Arc<HealthEvents>,
+    consensus: &Arc<Consensus>,
 ) -> Result<(), HealthError> {
+    // Last finalized block we compared hashes for
+    let mut last_divergence_check = 0;
+    // Last finalized block we cross-checked method families at
+    let mut last_cross_check = 0;
+    let mut emergency = EmergencyState::default();
+
     loop {
         let health_check_ttl = config.read().unwrap().health_check_ttl;
         let ttl = config.read().unwrap().ttl;
         let supress_rpc_check = config.read().unwrap().supress_rpc_check;
+        let max_head_lag = config.read().unwrap().max_head_lag;
+        let health_check_methods = config.read().unwrap().health_check_methods.clone();
+        let finalized_divergence_check = config.read().unwrap().finalized_divergence_check;
+        let head_staleness_ms = config.read().unwrap().head_staleness_ms;
+        let quarantine_threshold = config.read().unwrap().quarantine_threshold;
+        let track_consensus = {
+            let config_guard = config.read().unwrap();
+            config_guard.local_methods.enabled || config_guard.pin_block_tags
+        };
 
         sleep(Duration::from_millis(health_check_ttl)).await;
 
@@ -80,16 +115,44 @@ pub async fn health_check(
             &ttl,
             &liveness_tx,
             supress_rpc_check,
+            max_head_lag,
+            &health_check_methods,
         )
         .await?;
 
-        get_safe_block(
+        if head_staleness_ms != 0 {
+            exclude_stale(&rpc_list, &poverty_list, head_staleness_ms);
+        }
+
+        if track_consensus {
+            update_consensus(&rpc_list, consensus, ttl).await;
+        }
+
+        let finalized = get_safe_block(
             &rpc_list,
             &finalized_tx,
             named_numbers_rwlock,
             health_check_ttl,
         )
         .await?;
+
+        // Only compare hashes once per new finalized block
+        if finalized_divergence_check && finalized != 0 && finalized != last_divergence_check {
+            check_finalized_divergence(&rpc_list, &poverty_list, finalized, ttl).await;
+            last_divergence_check = finalized;
+        }
+
+        if quarantine_threshold != 0 && finalized != 0 && finalized != last_cross_check {
+            cross_check(&rpc_list, finalized, ttl, quarantine_threshold).await;
+            last_cross_check = finalized;
+        }
+
+        health_events.observe(
+            &rpc_list.read().unwrap(),
+            &poverty_list.read().unwrap(),
+            now_ms(),
+        );
+        emergency.update(&rpc_list.read().unwrap());
     }
 }
 
@@ -100,26 +163,38 @@ async fn check(
     ttl: &u128,
     liveness_tx: &LiveReadyUpdateSnd,
     supress_rpc_check: bool,
+    max_head_lag: u64,
+    health_check_methods: &HealthCheckMethods,
 ) -> Result<(), HealthError> {
     if !supress_rpc_check {
         tracing::info!("Checking RPC health... ");
     }
+    // Leave emergency RPCs alone until they have to take over
+    let skip_emergency = emergency_dormant(&rpc_list.read().unwrap_or_else(|e| e.into_inner()));
+
     // Head blocks reported by each RPC, we also use it to mark delinquents
     //
     // If a head is marked at `0` that means that the rpc is delinquent
-    let heads = head_check(rpc_list, *ttl).await?;
+    let heads = head_check(rpc_list, *ttl, health_check_methods, skip_emergency).await?;
 
     // Remove RPCs that are falling behind
-    let agreed_head = make_poverty(rpc_list, poverty_list, heads)?;
+    let agreed_head = make_poverty(rpc_list, poverty_list, heads, max_head_lag)?;
     metrics::gauge!("rpc_head_height").set(agreed_head as f64);
 
     // Check if any rpc nodes made it out
     // Its ok if we call them twice because some might have been accidentally put here
 
     // Do a head check over the current poverty list to see if any nodes are back to normal
-    let poverty_heads = head_check(poverty_list, *ttl).await?;
+    let poverty_heads =
+        head_check(poverty_list, *ttl, health_check_methods, skip_emergency).await?;
 
-    let to_send = escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head)?;
+    let to_send = escape_poverty(
+        rpc_list,
+        poverty_list,
+        poverty_heads,
+        agreed_head,
+        max_head_lag,
+    )?;
 
     // Send the current status of nodes to the liveness monitor
     let _ = liveness_tx.send(to_send).await;
@@ -131,10 +206,66 @@ async fn check(
     Ok(())
 }
 
+/// Returns true if `rx` is a successful JSON-RPC response.
+fn is_successful_response(rx: &str) -> bool {
+    match serde_json::from_str::<Value>(rx) {
+        Ok(response) => {
+            response.get("error").is_none()
+                && response
+                    .get("result")
+                    .is_some_and(|result| !result.is_null())
+        }
+        Err(_) => false,
+    }
+}
+
+impl HealthCheckMethods {
+    /// Methods `rpc` has to answer: the ones of every RPC, then the ones of its groups.
+    pub fn for_rpc<'a>(&'a self, rpc: &'a Rpc) -> impl Iterator<Item = &'a HealthCheckMethod> {
+        self.all.iter().chain(
+            rpc.groups
+                .iter()
+                .filter_map(|group| self.groups.get(group))
+                .flatten(),
+        )
+    }
+}
+
+/// Call each of the `health_check_methods` of `rpc` and return false if any of them fails.
+async fn methods_check(rpc: &Rpc, health_check_methods: &HealthCheckMethods) -> bool {
+    for health_check_method in health_check_methods.for_rpc(rpc) {
+        let request = json!({
+            "method": health_check_method.method,
+            "params": health_check_method.params,
+            "id": 1,
+            "jsonrpc": "2.0",
+        });
+
+        match rpc.send_request(request).await {
+            Ok(rx) if is_successful_response(&rx) => {}
+            _ => {
+                tracing::warn!(
+                    method = %health_check_method.method,
+                    "{} failed a health check call",
+                    rpc.name
+                );
+                return false;
+            }
+        }
+    }
+
+    true
+}
+
 /// Check what heads are reported by each RPC
+///
+/// RPCs that fail any of their `health_check_methods` report a head of `0`.
+/// Emergency RPCs aren't checked if `skip_emergency` is set, and report nothing.
 async fn head_check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ttl: u128,
+    health_check_methods: &HealthCheckMethods,
+    skip_emergency: bool,
 ) -> Result<Vec<HeadResult>, HealthError> {
     let len;
     let rpc_list_clone;
@@ -162,7 +293,11 @@ async fn head_check(
 
     // Iterate over all RPCs
     for (rpc_list_index, rpc) in rpc_list_clone.into_iter().enumerate().take(len) {
+        if skip_emergency && rpc.emergency {
+            continue;
+        }
         let tx = tx.clone(); // Clone the sender for this RPC
+        let health_check_methods = health_check_methods.clone();
 
         // Spawn a future for each RPC
         let rpc_future = async move {
@@ -170,11 +305,16 @@ async fn head_check(
 
             // Check the current block number
             let a = async move {
-                let block_number = rpc.block_number().await.unwrap_or(0);
+                let mut block_number = rpc.block_number().await.unwrap_or(0);
                 let syncing = rpc.syncing().await.unwrap_or(true);
 
+                if !methods_check(&rpc, &health_check_methods).await {
+                    block_number = 0;
+                }
+
                 let rax = InnerResult {
                     is_syncing: syncing,
+                    timed_out: false,
                     reported_head: block_number,
                 };
 
@@ -189,7 +329,8 @@ async fn head_check(
                 // Handle timeout as failiure
                 Err(_) | Ok(Err(_)) => {
                     InnerResult {
-                        is_syncing: true,
+                        is_syncing: false,
+                        timed_out: true,
                         reported_head: 0,
                     }
                 }
@@ -198,6 +339,7 @@ async fn head_check(
             let head_result = HeadResult {
                 rpc_list_index,
                 is_syncing: result.is_syncing,
+                timed_out: result.timed_out,
                 reported_head: result.reported_head,
             };
 
@@ -211,12 +353,13 @@ async fn head_check(
     }
 
     // Wait for all RPC futures concurrently
+    let checked = rpc_futures.len();
     for rpc_future in rpc_futures {
         tokio::spawn(rpc_future);
     }
 
     // Collect the results in order from the channel
-    for _ in 0..len {
+    for _ in 0..checked {
         if let Some(result) = rx.recv().await {
             heads.push(result);
         }
@@ -225,11 +368,29 @@ async fn head_check(
     Ok(heads)
 }
 
-/// Add unresponsive/erroring RPCs to the poverty list
+/// Store the result of a head check in the RPCs status.
+fn record_head(rpc: &mut Rpc, head: &HeadResult) {
+    rpc.status.is_syncing = head.is_syncing;
+    rpc.status.is_timed_out = head.timed_out;
+    rpc.status.head = head.reported_head;
+
+    // A head of `0` means the RPC didn't respond
+    if head.reported_head == 0 {
+        rpc.status.failed_checks = rpc.status.failed_checks.saturating_add(1);
+    } else {
+        rpc.status.failed_checks = 0;
+    }
+}
+
+/// Add unresponsive/erroring RPCs to the poverty list.
+///
+/// RPCs that are syncing, timed out, or more than `max_head_lag` blocks behind
+/// the highest reported head are considered delinquent.
 fn make_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     heads: Vec<HeadResult>,
+    max_head_lag: u64,
 ) -> Result<u64, HealthError> {
     // Get the highest head reported by the RPCs
     let mut highest_head = 0;
@@ -244,7 +405,12 @@ fn make_poverty(
     let mut poverty_list_guard = poverty_list.write().unwrap();
 
     for head in heads {
-        if head.reported_head < highest_head || head.is_syncing {
+        record_head(&mut rpc_list_guard[head.rpc_list_index], &head);
+
+        if head.reported_head.saturating_add(max_head_lag) < highest_head
+            || head.is_syncing
+            || head.timed_out
+        {
             // Mark the RPC as erroring
             rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
             let rpc_name = &rpc_list_guard[head.rpc_list_index].name;
@@ -270,12 +436,16 @@ fn make_poverty(
 
 /// Go over the `poverty_list` to see if any nodes are back to normal.
 ///
+/// Nodes are re-included once they answer in time, report as synced, and their head is
+/// within `max_head_lag` blocks of the `agreed_head`.
+///
 /// Update liveness statuses when done.
 fn escape_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_heads: Vec<HeadResult>,
     agreed_head: u64,
+    max_head_lag: u64,
 ) -> Result<crate::LiveReadyUpdate, HealthError> {
     // Check if any nodes made it 🗣️🔥🔥🔥
     let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
@@ -288,7 +458,18 @@ fn escape_poverty(
     });
 
     for head in poverty_heads {
-        if head.reported_head >= agreed_head && !head.is_syncing {
+        record_head(&mut poverty_list_guard[head.rpc_list_index], &head);
+
+        // Diverged and stale nodes stay out until their monitors clear them
+        let status = &poverty_list_guard[head.rpc_list_index].status;
+        if status.is_diverged || status.is_stale {
+            continue;
+        }
+
+        if head.reported_head.saturating_add(max_head_lag) >= agreed_head
+            && !head.is_syncing
+            && !head.timed_out
+        {
             let mut rpc = poverty_list_guard[head.rpc_list_index].clone();
             rpc.status.is_erroring = false;
             let rpc_name = &rpc.name;
@@ -335,32 +516,24 @@ fn escape_poverty(
 }
 
 /// Remove the RPC that dropped out ws_conn and add it to the poverty list.
-pub async fn send_dropped_to_poverty(
+///
+/// The WS module moves its subscriptions once it notices it left the pool.
+pub fn send_dropped_to_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
-    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
-    rx: broadcast::Receiver<IncomingResponse>,
-    sub_data: &Arc<SubscriptionData>,
-    ws_conn_index: usize,
-) -> Result<(), HealthError> {
-    {
-        let mut rpc_list_guard = rpc_list.write().unwrap();
-        let mut poverty_list_guard = poverty_list.write().unwrap();
+    rpc_position: usize,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap();
+    let mut poverty_list_guard = poverty_list.write().unwrap();
 
-        // Check if the RPC is in the rpc_list
-        if let Some(rpc) = rpc_list_guard.get(ws_conn_index) {
-            // Add the RPC to the poverty list
-            poverty_list_guard.push(rpc.clone());
+    // Check if the RPC is in the rpc_list
+    if let Some(rpc) = rpc_list_guard.get(rpc_position) {
+        // Add the RPC to the poverty list
+        poverty_list_guard.push(rpc.clone());
 
-            // Remove the RPC from the rpc_list
-            rpc_list_guard.remove(ws_conn_index);
-        }
+        // Remove the RPC from the rpc_list
+        rpc_list_guard.remove(rpc_position);
     }
-
-    // Move subscriptions away from that node
-    move_subscriptions(incoming_tx, rx, sub_data, ws_conn_index).await?;
-
-    Ok(())
 }
 
 /// Listen for dropped ws connections and handle them.
@@ -369,24 +542,13 @@ pub async fn dropped_listener(
     poverty_list: Arc<RwLock<Vec<Rpc>>>,
     mut ws_err_rx: mpsc::UnboundedReceiver<WsChannelErr>,
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
-    rx: broadcast::Receiver<IncomingResponse>,
-    sub_data: Arc<SubscriptionData>,
 ) -> Result<(), HealthError> {
     loop {
         let ws_err = ws_err_rx.recv().await;
 
         match ws_err {
-            Some(WsChannelErr::Closed(index)) => {
-                send_dropped_to_poverty(
-                    &rpc_list,
-                    &poverty_list,
-                    &incoming_tx,
-                    rx.resubscribe(),
-                    &sub_data,
-                    index,
-                )
-                .await
-                .unwrap_or(());
+            Some(WsChannelErr::Closed(rpc_position)) => {
+                send_dropped_to_poverty(&rpc_list, &poverty_list, rpc_position);
                 incoming_tx.send(WsconnMessage::Reconnect()).unwrap_or(());
             }
             None => {
@@ -404,6 +566,7 @@ pub async fn dropped_listener(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
 
     // Construct a hypothetical RPC and heads list for testing
     fn dummy_head_check() -> Vec<HeadResult> {
@@ -411,21 +574,73 @@ mod tests {
             HeadResult {
                 rpc_list_index: 0,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 18177557,
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 18193012,
             },
             HeadResult {
                 rpc_list_index: 2,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 0,
             },
         ]
     }
 
+    #[test]
+    fn test_health_check_methods_for_rpc() {
+        let method = |method: &str| {
+            HealthCheckMethod {
+                method: method.to_string(),
+                params: Vec::new(),
+            }
+        };
+        let health_check_methods = HealthCheckMethods {
+            all: vec![method("eth_chainId")],
+            groups: HashMap::from([
+                ("archive".to_string(), vec![method("eth_getBalance")]),
+                ("trace".to_string(), vec![method("trace_block")]),
+            ]),
+        };
+        let methods = |rpc: &Rpc| {
+            health_check_methods
+                .for_rpc(rpc)
+                .map(|method| method.method.clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut rpc = Rpc::default();
+        assert_eq!(methods(&rpc), ["eth_chainId"]);
+
+        rpc.groups = vec!["trace".to_string(), "archive".to_string()];
+        assert_eq!(
+            methods(&rpc),
+            ["eth_chainId", "trace_block", "eth_getBalance"]
+        );
+    }
+
+    #[test]
+    fn test_is_successful_response() {
+        assert!(is_successful_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#
+        ));
+        assert!(is_successful_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":[]}"#
+        ));
+        assert!(!is_successful_response(
+            r#"{"jsonrpc":"2.0","id":1,"result":null}"#
+        ));
+        assert!(!is_successful_response(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"the method trace_block does not exist"}}"#
+        ));
+        assert!(!is_successful_response("Bad Gateway"));
+    }
+
     #[test]
     fn test_poverty() {
         // Create a mock RPC list and poverty list
@@ -440,7 +655,7 @@ mod tests {
         let heads = dummy_head_check();
 
         // Call the make_poverty function
-        let result = make_poverty(&rpc_list, &poverty_list, heads);
+        let result = make_poverty(&rpc_list, &poverty_list, heads, 0);
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -452,6 +667,124 @@ mod tests {
 
         // The poverty list should now contain 2 RPCs
         assert_eq!(poverty_list_guard.len(), 2);
+
+        // Heads and failed checks are recorded
+        assert_ne!(rpc_list_guard[0].status.head, 0);
+        assert_eq!(rpc_list_guard[0].status.failed_checks, 0);
+        assert!(poverty_list_guard
+            .iter()
+            .any(|rpc| rpc.status.failed_checks == 1));
+    }
+
+    #[test]
+    fn test_poverty_with_lag() {
+        let rpc_list = Arc::new(RwLock::new(vec![
+            Rpc::default(),
+            Rpc::default(),
+            Rpc::default(),
+        ]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        let heads = vec![
+            HeadResult {
+                rpc_list_index: 0,
+                is_syncing: false,
+                timed_out: false,
+                reported_head: 100,
+            },
+            HeadResult {
+                rpc_list_index: 1,
+                is_syncing: false,
+                timed_out: false,
+                reported_head: 98,
+            },
+            HeadResult {
+                rpc_list_index: 2,
+                is_syncing: true,
+                timed_out: false,
+                reported_head: 100,
+            },
+        ];
+
+        // RPC at index 1 is within the allowed lag, but 2 is syncing
+        let result = make_poverty(&rpc_list, &poverty_list, heads, 2);
+        assert_eq!(result.unwrap(), 100);
+
+        let rpc_list_guard = rpc_list.read().unwrap();
+        let poverty_list_guard = poverty_list.read().unwrap();
+        assert_eq!(rpc_list_guard.len(), 2);
+        assert_eq!(poverty_list_guard.len(), 1);
+        assert!(poverty_list_guard[0].status.is_syncing);
+    }
+
+    #[test]
+    fn test_timed_out() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(), Rpc::default()]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        let heads = vec![
+            HeadResult {
+                rpc_list_index: 0,
+                reported_head: 100,
+                ..Default::default()
+            },
+            HeadResult {
+                rpc_list_index: 1,
+                timed_out: true,
+                ..Default::default()
+            },
+        ];
+        make_poverty(&rpc_list, &poverty_list, heads, 0).unwrap();
+
+        // Ejected, but not mistaken for syncing
+        {
+            let poverty_list_guard = poverty_list.read().unwrap();
+            assert_eq!(poverty_list_guard.len(), 1);
+            assert!(poverty_list_guard[0].status.is_timed_out);
+            assert!(!poverty_list_guard[0].status.is_syncing);
+        }
+
+        // Stays out while it keeps timing out
+        let heads = vec![HeadResult {
+            timed_out: true,
+            ..Default::default()
+        }];
+        escape_poverty(&rpc_list, &poverty_list, heads, 0, 0).unwrap();
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+
+        let heads = vec![HeadResult {
+            reported_head: 100,
+            ..Default::default()
+        }];
+        escape_poverty(&rpc_list, &poverty_list, heads, 100, 0).unwrap();
+        assert!(poverty_list.read().unwrap().is_empty());
+        assert!(!rpc_list.read().unwrap()[1].status.is_timed_out);
+    }
+
+    #[test]
+    fn test_escape_with_lag() {
+        let mut rpc1 = Rpc::default();
+        rpc1.status.is_erroring = true;
+        rpc1.status.is_syncing = true;
+
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let poverty_list = Arc::new(RwLock::new(vec![rpc1]));
+
+        let heads = vec![HeadResult {
+            rpc_list_index: 0,
+            is_syncing: false,
+            timed_out: false,
+            reported_head: 99,
+        }];
+
+        // Synced and within the allowed lag, so it should be re-included
+        let result = escape_poverty(&rpc_list, &poverty_list, heads, 100, 1);
+        assert!(result.is_ok());
+
+        let rpc_list_guard = rpc_list.read().unwrap();
+        assert_eq!(rpc_list_guard.len(), 1);
+        assert!(!rpc_list_guard[0].status.is_syncing);
+        assert!(poverty_list.read().unwrap().is_empty());
     }
 
     #[test]
@@ -472,17 +805,19 @@ mod tests {
             HeadResult {
                 rpc_list_index: 0,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 18177557,
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 18193012,
             },
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012, 0);
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -513,17 +848,19 @@ mod tests {
             HeadResult {
                 rpc_list_index: 0,
                 is_syncing: false,
+                timed_out: false,
                 reported_head: 18193012,
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: true,
+                timed_out: false,
                 reported_head: 18193012,
             },
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012, 0);
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
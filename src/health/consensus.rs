@@ -0,0 +1,347 @@
+//! Local consensus on the head of the chain.
+//!
+//! After every health check, the heads RPCs in the active pool reported are
+//! combined into a single consensus head: the highest block a strict majority
+//! of them reached, along with the hash a majority of them report for it.
+//! Heads RPCs announced over their own `newHeads` subscriptions count too, if
+//! they're newer (see `head_staleness`).
+//!
+//! The `safe` block is agreed on the same way, from the `safe` block each RPC
+//! reports.
+//!
+//! The chain ID and network ID never change, so we ask for them until a
+//! majority of the RPCs agrees on each, and keep them from then on.
+//!
+//! This is what lets us answer methods like `eth_blockNumber` ourselves, see
+//! `local_methods`, and pin block tags to the same numbers for every item of a
+//! request, see `block_tags`.
+
+use crate::{
+    database::expiry::now_ms,
+    health::{
+        divergence::{
+            collect_hashes,
+            majority_hash,
+        },
+        emergency::emergency_dormant,
+        safe_block::NamedBlocknumbers,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use rust_tracing::deps::metrics;
+use tokio::time::timeout;
+
+/// Head of the chain most RPCs agree on.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConsensusHead {
+    pub number: u64,
+    /// `None` if no majority reported the same hash.
+    pub hash: Option<String>,
+    /// Unix time in ms we last agreed on it.
+    pub updated_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct ChainState {
+    head: Option<ConsensusHead>,
+    safe: Option<u64>,
+    chain_id: Option<u64>,
+    net_version: Option<String>,
+}
+
+/// What the RPCs agree on about the chain.
+#[derive(Debug, Default)]
+pub struct Consensus {
+    state: RwLock<ChainState>,
+}
+
+impl Consensus {
+    pub fn head(&self) -> Option<ConsensusHead> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .head
+            .clone()
+    }
+
+    pub fn safe(&self) -> Option<u64> {
+        self.state.read().unwrap_or_else(|e| e.into_inner()).safe
+    }
+
+    pub fn chain_id(&self) -> Option<u64> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .chain_id
+    }
+
+    pub fn net_version(&self) -> Option<String> {
+        self.state
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .net_version
+            .clone()
+    }
+
+    /// Returns the block numbers `latest`, `safe` and `finalized` refer to
+    /// right now. Tags we didn't agree on fall back to `named_numbers`, and are
+    /// `0` if we know nothing about them.
+    ///
+    /// `latest` is never behind `named_numbers`, and a head we last agreed on
+    /// over `max_head_age_ms` ago is ignored, so pinning can't hold requests
+    /// to an old block while the RPCs can't agree.
+    pub fn block_tags(
+        &self,
+        named_numbers: &NamedBlocknumbers,
+        max_head_age_ms: u64,
+    ) -> NamedBlocknumbers {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        let now = now_ms();
+        NamedBlocknumbers {
+            latest: state
+                .head
+                .as_ref()
+                .filter(|head| now.saturating_sub(head.updated_ms) <= max_head_age_ms)
+                .map_or(named_numbers.latest, |head| {
+                    head.number.max(named_numbers.latest)
+                }),
+            safe: state.safe.unwrap_or(named_numbers.safe),
+            ..*named_numbers
+        }
+    }
+
+    /// Store what the RPCs agreed on. Unlike the IDs, the last head and safe
+    /// block we agreed on are kept if we didn't agree on new ones.
+    pub(crate) fn store(
+        &self,
+        head: Option<ConsensusHead>,
+        safe: Option<u64>,
+        chain_id: Option<u64>,
+        net_version: Option<String>,
+    ) {
+        let mut state = self.state.write().unwrap_or_else(|e| e.into_inner());
+        if head.is_some() {
+            state.head = head;
+        }
+        if safe.is_some() {
+            state.safe = safe;
+        }
+        state.chain_id = chain_id;
+        state.net_version = net_version;
+    }
+}
+
+/// Returns the highest of `heads` a strict majority of them reached. Heads of
+/// `0` come from RPCs that didn't respond, and don't count.
+fn majority_head(heads: &[u64]) -> Option<u64> {
+    let mut heads: Vec<u64> = heads.iter().copied().filter(|head| *head != 0).collect();
+    heads.sort_unstable_by(|a, b| b.cmp(a));
+    heads.get(heads.len() / 2).copied()
+}
+
+/// Returns the value a strict majority of the RPCs that responded reported.
+fn majority<T: Eq + Hash + Clone>(values: &[Option<T>]) -> Option<T> {
+    let mut counts: HashMap<&T, usize> = HashMap::new();
+    let mut responded = 0;
+    for value in values.iter().flatten() {
+        *counts.entry(value).or_default() += 1;
+        responded += 1;
+    }
+
+    counts
+        .into_iter()
+        .find(|(_, count)| *count * 2 > responded)
+        .map(|(value, _)| value.clone())
+}
+
+/// Agree on the head of the chain with the RPCs in `rpc_list`, and on the
+/// chain and network IDs if we haven't yet.
+pub async fn update_consensus(rpc_list: &Arc<RwLock<Vec<Rpc>>>, consensus: &Consensus, ttl: u128) {
+    let rpc_list_clone: Vec<Rpc> = {
+        let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        });
+        // Leave emergency RPCs alone until they have to take over
+        let skip_emergency = emergency_dormant(&rpc_list_guard);
+        rpc_list_guard
+            .iter()
+            .filter(|rpc| !(skip_emergency && rpc.emergency))
+            .cloned()
+            .collect()
+    };
+    let heads: Vec<u64> = rpc_list_clone
+        .iter()
+        .map(|rpc| rpc.status.head.max(rpc.status.ws_head))
+        .collect();
+
+    let previous = consensus.head();
+    let head = match majority_head(&heads) {
+        // Only ask for the hash of heads we don't have one for yet
+        Some(number) => {
+            let hash = match previous {
+                Some(previous) if previous.number == number && previous.hash.is_some() => {
+                    previous.hash
+                }
+                _ => {
+                    let rpcs: Vec<Rpc> = rpc_list_clone
+                        .iter()
+                        .zip(&heads)
+                        .filter(|(_, head)| **head >= number)
+                        .map(|(rpc, _)| rpc.clone())
+                        .collect();
+                    let hashes = collect_hashes(&rpcs, number, ttl).await;
+                    majority_hash(&hashes).map(str::to_string)
+                }
+            };
+            metrics::gauge!("consensus_head").set(number as f64);
+            Some(ConsensusHead {
+                number,
+                hash,
+                updated_ms: now_ms(),
+            })
+        }
+        None => None,
+    };
+
+    let ttl = Duration::from_millis(ttl.try_into().unwrap());
+    let safe_blocks = join_all(rpc_list_clone.iter().map(|rpc| {
+        async move {
+            timeout(ttl, rpc.get_tagged_block("safe"))
+                .await
+                .ok()
+                .and_then(Result::ok)
+                .unwrap_or(0)
+        }
+    }))
+    .await;
+    let safe = majority_head(&safe_blocks);
+
+    let chain_id = match consensus.chain_id() {
+        Some(chain_id) => Some(chain_id),
+        None => {
+            let chain_ids = join_all(rpc_list_clone.iter().map(|rpc| {
+                async move { timeout(ttl, rpc.chain_id()).await.ok().and_then(Result::ok) }
+            }))
+            .await;
+            majority(&chain_ids)
+        }
+    };
+    let net_version = match consensus.net_version() {
+        Some(net_version) => Some(net_version),
+        None => {
+            let net_versions = join_all(rpc_list_clone.iter().map(|rpc| {
+                async move {
+                    timeout(ttl, rpc.net_version())
+                        .await
+                        .ok()
+                        .and_then(Result::ok)
+                }
+            }))
+            .await;
+            majority(&net_versions)
+        }
+    };
+
+    consensus.store(head, safe, chain_id, net_version);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_majority_head() {
+        assert_eq!(majority_head(&[]), None);
+        assert_eq!(majority_head(&[0, 0]), None);
+        assert_eq!(majority_head(&[100]), Some(100));
+        // Both have to be there
+        assert_eq!(majority_head(&[101, 100]), Some(100));
+        assert_eq!(majority_head(&[102, 101, 100]), Some(101));
+        // One RPC far ahead doesn't move the head
+        assert_eq!(majority_head(&[100, 100, 500]), Some(100));
+        // Unresponsive RPCs don't hold it back
+        assert_eq!(majority_head(&[0, 0, 100, 101]), Some(100));
+    }
+
+    #[test]
+    fn test_majority() {
+        assert_eq!(majority(&[Some(1), Some(1), Some(10)]), Some(1));
+        assert_eq!(majority(&[Some(1), None, None]), Some(1));
+        assert_eq!(majority(&[Some(1), Some(10)]), None);
+        assert_eq!(majority::<u64>(&[None, None]), None);
+    }
+
+    #[test]
+    fn test_store() {
+        let consensus = Consensus::default();
+        let head = ConsensusHead {
+            number: 100,
+            hash: Some("0xabc".to_string()),
+            updated_ms: 1,
+        };
+        consensus.store(Some(head.clone()), Some(90), Some(1), Some("1".to_string()));
+        assert_eq!(consensus.head(), Some(head.clone()));
+        assert_eq!(consensus.safe(), Some(90));
+        assert_eq!(consensus.chain_id(), Some(1));
+        assert_eq!(consensus.net_version(), Some("1".to_string()));
+
+        // Not agreeing on a new head keeps the old one
+        consensus.store(None, None, Some(1), Some("1".to_string()));
+        assert_eq!(consensus.head(), Some(head));
+        assert_eq!(consensus.safe(), Some(90));
+    }
+
+    #[test]
+    fn test_block_tags() {
+        let consensus = Consensus::default();
+        let named_numbers = NamedBlocknumbers {
+            latest: 105,
+            finalized: 64,
+            ..Default::default()
+        };
+        // Nothing agreed on yet
+        assert_eq!(consensus.block_tags(&named_numbers, 10_000), named_numbers);
+
+        let head = ConsensusHead {
+            number: 110,
+            hash: None,
+            updated_ms: now_ms(),
+        };
+        consensus.store(Some(head), Some(90), None, None);
+        let tags = consensus.block_tags(&named_numbers, 10_000);
+        assert_eq!(tags.latest, 110);
+        assert_eq!(tags.safe, 90);
+        assert_eq!(tags.finalized, 64);
+
+        // Never behind the latest block we saw
+        let head = ConsensusHead {
+            number: 100,
+            hash: None,
+            updated_ms: now_ms(),
+        };
+        consensus.store(Some(head), None, None, None);
+        assert_eq!(consensus.block_tags(&named_numbers, 10_000).latest, 105);
+
+        // Old heads are ignored
+        let head = ConsensusHead {
+            number: 120,
+            hash: None,
+            updated_ms: now_ms() - 60_000,
+        };
+        consensus.store(Some(head), None, None, None);
+        assert_eq!(consensus.block_tags(&named_numbers, 10_000).latest, 105);
+    }
+}
@@ -0,0 +1,147 @@
+//! Startup convergence gate.
+//!
+//! Right after starting we might be talking to nodes that are lagging badly
+//! or are on a different chain altogether. Before marking Blutgang as ready,
+//! we can optionally wait until enough RPCs report heads that are within
+//! `max_lag` blocks of each other.
+
+use crate::{
+    config::types::ConvergenceSettings,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use tokio::time::{
+    sleep,
+    timeout,
+};
+
+/// Returns the largest fraction of `heads` that are within `max_lag` blocks
+/// of each other. Heads reported as `0` count as failures.
+fn largest_agreeing_fraction(heads: &[u64], max_lag: u64) -> f64 {
+    if heads.is_empty() {
+        return 0.0;
+    }
+
+    let mut sorted: Vec<u64> = heads.iter().copied().filter(|head| *head != 0).collect();
+    sorted.sort_unstable();
+
+    // Slide a window over the sorted heads to find the largest group
+    // that fits within `max_lag` blocks.
+    let mut largest = 0;
+    let mut start = 0;
+    for (end, head) in sorted.iter().enumerate() {
+        while head - sorted[start] > max_lag {
+            start += 1;
+        }
+        largest = largest.max(end - start + 1);
+    }
+
+    largest as f64 / heads.len() as f64
+}
+
+/// Query the head of every RPC in the list. Erroring or timed out RPCs report `0`.
+async fn collect_heads(rpc_list: &Arc<RwLock<Vec<Rpc>>>, ttl: u128) -> Vec<u64> {
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .clone();
+
+    let heads = rpc_list_clone.iter().map(|rpc| {
+        async move {
+            match timeout(
+                Duration::from_millis(ttl.try_into().unwrap()),
+                rpc.block_number(),
+            )
+            .await
+            {
+                Ok(Ok(head)) => head,
+                Err(_) | Ok(Err(_)) => 0,
+            }
+        }
+    });
+
+    join_all(heads).await
+}
+
+/// Wait until `quorum` of RPCs agree on the head within `max_lag` blocks.
+///
+/// Returns `true` if the RPCs converged, and `false` if we gave up after `timeout_ms`.
+pub async fn wait_for_convergence(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    settings: &ConvergenceSettings,
+    ttl: u128,
+) -> bool {
+    tracing::info!(
+        quorum = settings.quorum,
+        max_lag = settings.max_lag,
+        "Waiting for RPCs to converge on the head"
+    );
+    let start = Instant::now();
+
+    loop {
+        let heads = collect_heads(rpc_list, ttl).await;
+        let agreeing = largest_agreeing_fraction(&heads, settings.max_lag);
+
+        if !heads.is_empty() && agreeing >= settings.quorum {
+            tracing::info!(agreeing, "RPCs converged on the head");
+            return true;
+        }
+
+        tracing::warn!(?heads, agreeing, "RPCs have not converged on the head yet");
+
+        if settings.timeout_ms != 0 && start.elapsed() >= Duration::from_millis(settings.timeout_ms)
+        {
+            tracing::warn!("Timed out waiting for RPCs to converge! Marking as ready anyway.");
+            return false;
+        }
+
+        sleep(Duration::from_millis(settings.interval_ms)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_all_agree() {
+        let heads = [100, 101, 100, 102];
+        assert_eq!(largest_agreeing_fraction(&heads, 2), 1.0);
+    }
+
+    #[test]
+    fn test_lagging_node() {
+        let heads = [100, 101, 50];
+        assert_eq!(largest_agreeing_fraction(&heads, 2), 2.0 / 3.0);
+    }
+
+    #[test]
+    fn test_wrong_chain_outlier() {
+        // A single node reporting a far away head should not pull the others down
+        let heads = [100, 100, 9_000_000, 101];
+        assert_eq!(largest_agreeing_fraction(&heads, 1), 0.75);
+    }
+
+    #[test]
+    fn test_failed_heads_dont_count() {
+        let heads = [0, 0, 100];
+        assert_eq!(largest_agreeing_fraction(&heads, 2), 1.0 / 3.0);
+        assert_eq!(largest_agreeing_fraction(&[0, 0], 2), 0.0);
+        assert_eq!(largest_agreeing_fraction(&[], 2), 0.0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Finalized block divergence monitor.
+//!
+//! Comparing heads only tells us if a node is keeping up, not if it is on
+//! the same chain as everyone else. With `finalized_divergence_check` enabled,
+//! every time the finalized block advances, we ask each RPC for the hash of
+//! that block. Nodes whose hash differs from the majority get ejected into the
+//! poverty list and stay there until they agree with the majority again.
+
+use crate::{
+    health::emergency::{
+        emergency_dormant,
+        query_awake,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use rust_tracing::deps::metrics;
+use tokio::time::timeout;
+
+/// Query the hash of block `number` from every RPC. Erroring or timed out RPCs report `None`.
+pub(crate) async fn collect_hashes(rpcs: &[Rpc], number: u64, ttl: u128) -> Vec<Option<String>> {
+    let hashes = rpcs.iter().map(|rpc| {
+        async move {
+            match timeout(
+                Duration::from_millis(ttl.try_into().unwrap()),
+                rpc.get_block_hash(number),
+            )
+            .await
+            {
+                Ok(Ok(hash)) => Some(hash),
+                Err(_) | Ok(Err(_)) => None,
+            }
+        }
+    });
+
+    join_all(hashes).await
+}
+
+/// Returns the hash reported by a strict majority of the RPCs that responded.
+pub(crate) fn majority_hash(hashes: &[Option<String>]) -> Option<&str> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    let mut responded = 0;
+
+    for hash in hashes.iter().flatten() {
+        *counts.entry(hash.as_str()).or_insert(0) += 1;
+        responded += 1;
+    }
+
+    counts
+        .into_iter()
+        .find(|(_, count)| *count * 2 > responded)
+        .map(|(hash, _)| hash)
+}
+
+/// Returns the indices of RPCs that reported a hash different from `majority`.
+pub(crate) fn diverged_indices(hashes: &[Option<String>], majority: &str) -> Vec<usize> {
+    hashes
+        .iter()
+        .enumerate()
+        .filter_map(|(index, hash)| {
+            match hash {
+                Some(hash) if hash != majority => Some(index),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Compare the hash of the `finalized` block across all RPCs.
+///
+/// RPCs in `rpc_list` that disagree with the majority are moved to `poverty_list`
+/// and marked as diverged. Diverged RPCs in `poverty_list` that agree with the
+/// majority again get cleared, so the regular health check can re-include them.
+pub async fn check_finalized_divergence(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    finalized: u64,
+    ttl: u128,
+) {
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .clone();
+    let poverty_list_clone = poverty_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .clone();
+
+    // Leave emergency RPCs alone until they have to take over
+    let skip_emergency = emergency_dormant(&rpc_list_clone);
+    let hashes = query_awake(&rpc_list_clone, skip_emergency, |rpcs| {
+        async move { collect_hashes(&rpcs, finalized, ttl).await }
+    })
+    .await;
+
+    let majority = match majority_hash(&hashes) {
+        Some(majority) => majority.to_owned(),
+        None => {
+            tracing::warn!(
+                finalized,
+                ?hashes,
+                "No majority on the finalized block hash! Can't check for divergence."
+            );
+            return;
+        }
+    };
+
+    // Check if any of the diverged nodes are back on the majority chain
+    let diverged_poverty: Vec<(usize, Rpc)> = poverty_list_clone
+        .into_iter()
+        .enumerate()
+        .filter(|(_, rpc)| rpc.status.is_diverged)
+        .collect();
+    let poverty_rpcs: Vec<Rpc> = diverged_poverty
+        .iter()
+        .map(|(_, rpc)| rpc.clone())
+        .collect();
+    let poverty_hashes = query_awake(&poverty_rpcs, skip_emergency, |rpcs| {
+        async move { collect_hashes(&rpcs, finalized, ttl).await }
+    })
+    .await;
+
+    {
+        let mut poverty_list_guard = poverty_list.write().unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        });
+
+        for ((index, rpc), hash) in diverged_poverty.iter().zip(poverty_hashes) {
+            if hash.as_deref() != Some(majority.as_str()) {
+                continue;
+            }
+
+            // The list might have changed while we were waiting on responses
+            if let Some(entry) = poverty_list_guard.get_mut(*index) {
+                if entry.name == rpc.name {
+                    tracing::info!("{} agrees on the finalized block hash again!", entry.name);
+                    entry.status.is_diverged = false;
+                }
+            }
+        }
+    }
+
+    let diverged = diverged_indices(&hashes, &majority);
+    if diverged.is_empty() {
+        return;
+    }
+
+    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+    let mut poverty_list_guard = poverty_list.write().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+
+    // Remove from the back so the indices stay valid
+    for index in diverged.into_iter().rev() {
+        let matches = rpc_list_guard
+            .get(index)
+            .is_some_and(|rpc| rpc.name == rpc_list_clone[index].name);
+        if !matches {
+            continue;
+        }
+
+        let mut rpc = rpc_list_guard.remove(index);
+        tracing::error!(
+            finalized,
+            expected = %majority,
+            reported = hashes[index].as_deref().unwrap_or_default(),
+            "{} diverged from the majority on the finalized block hash! Removed from active RPC pool.",
+            rpc.name
+        );
+        metrics::counter!(
+            "rpc_finalized_divergence_total",
+            "rpc_name" => rpc.name.clone()
+        )
+        .increment(1);
+        metrics::gauge!(
+            "rpc_health_by_name",
+            "rpc_name" => rpc.name.clone(),
+            "reported_head" => finalized.to_string(),
+            "is_syncing" => rpc.status.is_syncing.to_string()
+        )
+        .set(0.0);
+
+        rpc.status.is_erroring = true;
+        rpc.status.is_diverged = true;
+        poverty_list_guard.push(rpc);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hashes(input: &[Option<&str>]) -> Vec<Option<String>> {
+        input.iter().map(|hash| hash.map(str::to_owned)).collect()
+    }
+
+    #[test]
+    fn test_majority_hash() {
+        let input = hashes(&[Some("0xa"), Some("0xa"), Some("0xb")]);
+        assert_eq!(majority_hash(&input), Some("0xa"));
+
+        // Failed responses don't count towards the total
+        let input = hashes(&[Some("0xa"), None, None]);
+        assert_eq!(majority_hash(&input), Some("0xa"));
+    }
+
+    #[test]
+    fn test_no_majority() {
+        let input = hashes(&[Some("0xa"), Some("0xb")]);
+        assert_eq!(majority_hash(&input), None);
+
+        let input = hashes(&[Some("0xa"), Some("0xa"), Some("0xb"), Some("0xb")]);
+        assert_eq!(majority_hash(&input), None);
+
+        assert_eq!(majority_hash(&[]), None);
+        assert_eq!(majority_hash(&[None, None]), None);
+    }
+
+    #[test]
+    fn test_diverged_indices() {
+        let input = hashes(&[Some("0xa"), Some("0xb"), None, Some("0xa"), Some("0xc")]);
+        assert_eq!(diverged_indices(&input, "0xa"), vec![1, 4]);
+
+        let input = hashes(&[Some("0xa"), Some("0xa")]);
+        assert!(diverged_indices(&input, "0xa").is_empty());
+    }
+}
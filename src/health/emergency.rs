@@ -0,0 +1,145 @@
+//! Emergency RPC reporting.
+//!
+//! RPCs marked as `emergency` get no traffic while any primary RPC can serve
+//! requests, which keeps paid fallbacks from burning through their quota.
+//! Selection takes care of that on its own, this module reports when the
+//! emergency RPCs take over and when the primaries come back.
+//!
+//! Until they take over, emergency RPCs are dormant: health checks and probes
+//! leave them alone, so they don't spend the quota either.
+
+use crate::{
+    balancer::selection::select::primaries_available,
+    Rpc,
+};
+
+use std::future::Future;
+
+use rust_tracing::deps::metrics;
+
+/// Returns true if emergency RPCs are dormant, as `rpc_list` has a primary RPC available.
+pub fn emergency_dormant(rpc_list: &[Rpc]) -> bool {
+    primaries_available(rpc_list)
+}
+
+/// Run `query` on the RPCs in `rpcs`, skipping emergency ones if `skip_emergency` is set.
+///
+/// `query` returns a result for each RPC it gets. Skipped RPCs report `None`,
+/// like ones that didn't respond.
+pub async fn query_awake<T, F, Fut>(rpcs: &[Rpc], skip_emergency: bool, query: F) -> Vec<Option<T>>
+where
+    F: FnOnce(Vec<Rpc>) -> Fut,
+    Fut: Future<Output = Vec<Option<T>>>,
+{
+    let awake: Vec<usize> = (0..rpcs.len())
+        .filter(|&index| !(skip_emergency && rpcs[index].emergency))
+        .collect();
+    let results = query(awake.iter().map(|&index| rpcs[index].clone()).collect()).await;
+
+    let mut all: Vec<Option<T>> = rpcs.iter().map(|_| None).collect();
+    for (index, result) in awake.into_iter().zip(results) {
+        all[index] = result;
+    }
+    all
+}
+
+/// Whether emergency RPCs were serving traffic after the last health check.
+#[derive(Debug, Default)]
+pub struct EmergencyState {
+    active: bool,
+}
+
+impl EmergencyState {
+    /// Check if emergency RPCs in `rpc_list` are serving traffic, and report it if that changed.
+    pub fn update(&mut self, rpc_list: &[Rpc]) -> bool {
+        let active = !primaries_available(rpc_list)
+            && rpc_list
+                .iter()
+                .any(|rpc| rpc.emergency && !rpc.status.is_syncing);
+
+        if active != self.active {
+            if active {
+                let serving: Vec<&str> = rpc_list
+                    .iter()
+                    .filter(|rpc| rpc.emergency && !rpc.status.is_syncing)
+                    .map(|rpc| rpc.name.as_str())
+                    .collect();
+                tracing::warn!(
+                    ?serving,
+                    "No primary RPC available! Falling back to emergency RPCs."
+                );
+                metrics::counter!("emergency_activations_total").increment(1);
+            } else {
+                tracing::info!("Primary RPCs recovered, emergency RPCs deactivated.");
+            }
+            metrics::gauge!("emergency_active").set(if active { 1.0 } else { 0.0 });
+        }
+
+        self.active = active;
+        active
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(url: &str, emergency: bool) -> Rpc {
+        let mut rpc = Rpc::new(url.parse().unwrap(), None, 5, 1000, 10.0);
+        rpc.emergency = emergency;
+        rpc
+    }
+
+    #[test]
+    fn test_emergency_state() {
+        let mut state = EmergencyState::default();
+        let mut primary = rpc("http://127.0.0.1:8545", false);
+        let fallback = rpc("http://127.0.0.2:8545", true);
+
+        assert!(!state.update(&[primary.clone(), fallback.clone()]));
+
+        // Primary ejected to the poverty list
+        assert!(state.update(&[fallback.clone()]));
+
+        // Primary back, but still syncing
+        primary.status.is_syncing = true;
+        assert!(state.update(&[primary.clone(), fallback.clone()]));
+
+        primary.status.is_syncing = false;
+        assert!(!state.update(&[primary, fallback]));
+
+        // Nothing to fall back to
+        assert!(!state.update(&[]));
+    }
+
+    #[tokio::test]
+    async fn test_query_awake() {
+        let rpcs = [
+            rpc("http://127.0.0.1:8545", false),
+            rpc("http://127.0.0.2:8545", true),
+            rpc("http://127.0.0.3:8545", false),
+        ];
+        assert!(emergency_dormant(&rpcs));
+        assert!(!emergency_dormant(&rpcs[1..2]));
+
+        let query = |rpcs: Vec<Rpc>| {
+            async move {
+                rpcs.into_iter()
+                    .map(|rpc| Some(rpc.name))
+                    .collect::<Vec<_>>()
+            }
+        };
+        let results = query_awake(&rpcs, true, query).await;
+        assert_eq!(
+            results,
+            [
+                Some("http://127.0.0.1:8545/".to_string()),
+                None,
+                Some("http://127.0.0.3:8545/".to_string()),
+            ]
+        );
+
+        let results = query_awake(&rpcs, false, query).await;
+        assert!(results.iter().all(Option::is_some));
+    }
+}
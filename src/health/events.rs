@@ -0,0 +1,286 @@
+//! Health event history.
+//!
+//! Keeps a bounded log of when RPCs changed state and why, so operators can
+//! tell when a node started failing without digging through the logs.
+//!
+//! Instead of hooking every place that can move an RPC between lists, we
+//! compare the state of every RPC after each health check to the state it was
+//! in after the previous one.
+//!
+//! Admin changes that failed validation and got rolled back are logged here
+//! too, with the reason they were rejected.
+
+use crate::Rpc;
+
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    sync::{
+        Mutex,
+        RwLock,
+    },
+};
+
+use rust_tracing::deps::metrics;
+use serde::Serialize;
+
+/// State an RPC can be in from the point of view of the health check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcState {
+    /// In the active pool and responding
+    Healthy,
+    /// In the active pool, but requests to it are failing
+    Erroring,
+    /// Removed from the active pool and in the poverty list
+    Ejected,
+}
+
+impl RpcState {
+    pub const ALL: [RpcState; 3] = [RpcState::Healthy, RpcState::Erroring, RpcState::Ejected];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RpcState::Healthy => "healthy",
+            RpcState::Erroring => "erroring",
+            RpcState::Ejected => "ejected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HealthEvent {
+    pub timestamp_ms: u64,
+    pub rpc_name: String,
+    pub from: RpcState,
+    pub to: RpcState,
+    pub reason: &'static str,
+}
+
+/// An admin change that failed validation and got rolled back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RejectedChange {
+    pub timestamp_ms: u64,
+    /// Admin method or endpoint that tried to make the change.
+    pub change: String,
+    pub reason: String,
+}
+
+/// Best guess at why `rpc` went from `from` to `to`, based on its status.
+fn transition_reason(rpc: &Rpc, from: RpcState, to: RpcState) -> &'static str {
+    match to {
+        RpcState::Healthy if from == RpcState::Ejected => "readmitted",
+        RpcState::Healthy => "recovered",
+        RpcState::Erroring => "requests failing",
+        RpcState::Ejected if rpc.status.is_diverged => "finalized block hash diverged",
+        RpcState::Ejected if rpc.status.is_stale => "head stopped advancing",
+        RpcState::Ejected if rpc.status.is_timed_out => "health check timed out",
+        RpcState::Ejected if rpc.status.is_syncing => "syncing",
+        RpcState::Ejected if rpc.status.failed_checks > 0 => "failed health check",
+        RpcState::Ejected => "falling behind",
+    }
+}
+
+/// Bounded log of RPC state transitions.
+#[derive(Debug, Default)]
+pub struct HealthEvents {
+    capacity: usize,
+    states: Mutex<HashMap<String, RpcState>>,
+    events: RwLock<VecDeque<HealthEvent>>,
+    rejected: RwLock<VecDeque<RejectedChange>>,
+}
+
+impl HealthEvents {
+    /// Keep the last `capacity` events. `0` disables the history.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            states: Mutex::new(HashMap::new()),
+            events: RwLock::new(VecDeque::with_capacity(capacity)),
+            rejected: RwLock::new(VecDeque::new()),
+        }
+    }
+
+    fn push(&self, event: HealthEvent) {
+        let mut events = self.events.write().unwrap_or_else(|e| e.into_inner());
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Record the transitions of every RPC since the last time we observed it at `now`.
+    ///
+    /// RPCs we see for the first time, or that got removed, don't produce events.
+    pub fn observe(&self, rpc_list: &[Rpc], poverty_list: &[Rpc], now: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let current = rpc_list
+            .iter()
+            .map(|rpc| {
+                let state = if rpc.status.is_erroring {
+                    RpcState::Erroring
+                } else {
+                    RpcState::Healthy
+                };
+                (rpc, state)
+            })
+            .chain(poverty_list.iter().map(|rpc| (rpc, RpcState::Ejected)));
+
+        let mut states = self.states.lock().unwrap_or_else(|e| e.into_inner());
+        let mut seen = HashMap::new();
+
+        for (rpc, to) in current {
+            if let Some(&from) = states.get(&rpc.name) {
+                if from != to {
+                    let reason = transition_reason(rpc, from, to);
+                    tracing::info!(
+                        from = from.as_str(),
+                        to = to.as_str(),
+                        reason,
+                        "{} changed health state",
+                        rpc.name
+                    );
+                    metrics::counter!(
+                        "rpc_health_transitions_total",
+                        "rpc_name" => rpc.name.clone(),
+                        "to" => to.as_str()
+                    )
+                    .increment(1);
+
+                    self.push(HealthEvent {
+                        timestamp_ms: now,
+                        rpc_name: rpc.name.clone(),
+                        from,
+                        to,
+                        reason,
+                    });
+                }
+            }
+            seen.insert(rpc.name.clone(), to);
+        }
+
+        *states = seen;
+    }
+
+    /// Record that `change` failed validation for `reason` at `now`.
+    pub fn reject(&self, change: &str, reason: &str, now: u64) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut rejected = self.rejected.write().unwrap_or_else(|e| e.into_inner());
+        if rejected.len() >= self.capacity {
+            rejected.pop_front();
+        }
+        rejected.push_back(RejectedChange {
+            timestamp_ms: now,
+            change: change.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+
+    /// Returns recorded rejected changes, oldest first.
+    pub fn rejected(&self) -> Vec<RejectedChange> {
+        self.rejected
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns recorded events, oldest first, optionally only for the RPC named `rpc_name`.
+    pub fn events(&self, rpc_name: Option<&str>) -> Vec<HealthEvent> {
+        self.events
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|event| rpc_name.is_none_or(|name| event.rpc_name == name))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rpc(url: &str) -> Rpc {
+        Rpc::new(url.parse().unwrap(), None, 5, 1000, 10.0)
+    }
+
+    #[test]
+    fn test_transitions() {
+        let events = HealthEvents::new(16);
+        let mut a = rpc("http://127.0.0.1:8545");
+        let b = rpc("http://127.0.0.2:8545");
+
+        // First observation only sets the baseline
+        events.observe(&[a.clone(), b.clone()], &[], 1000);
+        assert!(events.events(None).is_empty());
+
+        a.status.is_syncing = true;
+        events.observe(&[b.clone()], &[a.clone()], 2000);
+        a.status.is_syncing = false;
+        events.observe(&[b.clone(), a.clone()], &[], 3000);
+
+        let recorded = events.events(None);
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].timestamp_ms, 2000);
+        assert_eq!(recorded[0].from, RpcState::Healthy);
+        assert_eq!(recorded[0].to, RpcState::Ejected);
+        assert_eq!(recorded[0].reason, "syncing");
+        assert_eq!(recorded[1].to, RpcState::Healthy);
+        assert_eq!(recorded[1].reason, "readmitted");
+
+        assert_eq!(events.events(Some(&a.name)).len(), 2);
+        assert!(events.events(Some(&b.name)).is_empty());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let events = HealthEvents::new(1);
+        events.reject("blutgang_set_ttl", "ttl must be greater than 0", 1);
+        events.reject("POST /upstreams", "example timed out", 2);
+        assert_eq!(
+            events.rejected(),
+            vec![RejectedChange {
+                timestamp_ms: 2,
+                change: "POST /upstreams".to_string(),
+                reason: "example timed out".to_string(),
+            }]
+        );
+
+        let events = HealthEvents::new(0);
+        events.reject("blutgang_set_ttl", "ttl must be greater than 0", 1);
+        assert!(events.rejected().is_empty());
+    }
+
+    #[test]
+    fn test_bounded() {
+        let events = HealthEvents::new(2);
+        let mut a = rpc("http://127.0.0.1:8545");
+
+        events.observe(&[a.clone()], &[], 0);
+        for now in 1..=5 {
+            a.status.is_erroring = now % 2 == 1;
+            events.observe(&[a.clone()], &[], now);
+        }
+
+        let recorded = events.events(None);
+        assert_eq!(recorded.len(), 2);
+        assert_eq!(recorded[0].timestamp_ms, 4);
+        assert_eq!(recorded[1].timestamp_ms, 5);
+
+        // Disabled history records nothing
+        let events = HealthEvents::new(0);
+        events.observe(&[a.clone()], &[], 0);
+        events.observe(&[], &[a], 1);
+        assert!(events.events(None).is_empty());
+    }
+}
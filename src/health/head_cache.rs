@@ -10,7 +10,12 @@ use crate::database::{
 
 use std::{
     collections::BTreeMap,
+    ops::RangeInclusive,
     sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
         Arc,
         RwLock,
     },
@@ -21,6 +26,70 @@ use tokio_stream::{
     StreamExt,
 };
 
+/// How many recent head hashes we remember to detect reorgs.
+const HEAD_HISTORY_LEN: usize = 128;
+
+/// Bumped by every reorg we handle.
+static REORG_EPOCH: AtomicU64 = AtomicU64::new(0);
+
+/// Returns how many reorgs were handled so far.
+///
+/// Cache writes fetched before a reorg compare this to tell if they're stale.
+pub fn reorg_epoch() -> u64 {
+    REORG_EPOCH.load(Ordering::Acquire)
+}
+
+/// Hashes of recent chain heads, used to detect reorgs that don't
+/// make the head number go backwards.
+#[derive(Debug, Default)]
+pub struct HeadHistory {
+    hashes: BTreeMap<u64, String>,
+}
+
+impl HeadHistory {
+    /// Record a new head and return the range of blocks that got reorged, if any.
+    ///
+    /// A reorg is detected when we already saw a different block at `number`,
+    /// or when `parent_hash` doesn't match the block we saw at `number - 1`.
+    pub fn record(
+        &mut self,
+        number: u64,
+        hash: &str,
+        parent_hash: &str,
+    ) -> Option<RangeInclusive<u64>> {
+        let highest = self.hashes.keys().next_back().copied();
+
+        let reorged_from = if self.hashes.get(&number).is_some_and(|known| known != hash) {
+            Some(number)
+        } else if number > 0
+            && self
+                .hashes
+                .get(&(number - 1))
+                .is_some_and(|known| known != parent_hash)
+        {
+            Some(number - 1)
+        } else {
+            None
+        };
+
+        // Everything above the new head is no longer canonical
+        self.hashes.split_off(&(number + 1));
+        if let Some(from) = reorged_from {
+            self.hashes.split_off(&from);
+        }
+        if number > 0 {
+            self.hashes.insert(number - 1, parent_hash.to_string());
+        }
+        self.hashes.insert(number, hash.to_string());
+
+        while self.hashes.len() > HEAD_HISTORY_LEN {
+            self.hashes.pop_first();
+        }
+
+        reorged_from.map(|from| from..=highest.unwrap_or(number).max(number))
+    }
+}
+
 /// Check if we need to do a reorg or if a new block has finalized.
 pub async fn manage_cache<K, V>(
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
@@ -65,7 +134,7 @@ where
 /// We use the head_cache to store keys of querries we made near the tip
 /// If a reorg happens, we need to remove all queries in the reorg range
 /// from the sled database.
-async fn handle_reorg<K, V>(
+pub async fn handle_reorg<K, V>(
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
     block_number: u64,
     new_block: u64,
@@ -75,22 +144,27 @@ where
     K: GenericBytes,
     V: GenericBytes,
 {
-    let range = block_number..=new_block;
-    let mut batch = Batch::with_capacity(range.clone().count());
+    // Writes queued from here on can't bring back what we're about to remove
+    REORG_EPOCH.fetch_add(1, Ordering::AcqRel);
+
+    // The new block can be on either side of the one we had
+    let range = block_number.min(new_block)..=block_number.max(new_block);
 
     // Go over the head cache and get all the keys from block_number to new_block
-    {
+    let batch = {
         let mut head_cache_guard = head_cache.write().unwrap();
-        for i in range {
-            if let Some(keys) = head_cache_guard.get(&i).cloned() {
+        let reorged: Vec<u64> = head_cache_guard.range(range).map(|(i, _)| *i).collect();
+        let mut batch = Batch::with_capacity(reorged.len());
+        for i in reorged {
+            // Remove the entry from the head_cache
+            if let Some(keys) = head_cache_guard.remove(&i) {
                 for key in keys {
                     batch.delete(key);
                 }
-                // Remove the entry from the head_cache
-                head_cache_guard.remove(&i);
             }
         }
-    }
+        batch
+    };
 
     // Send the batch to the cache
     drop(db_batch(&cache, batch).await);
@@ -126,6 +200,10 @@ fn remove_stale<K: GenericBytes>(
 mod tests {
     use super::*;
     use crate::database::types::DbRequest;
+    use crate::database::{
+        eviction::CacheEviction,
+        hot_cache::HotCache,
+    };
     use crate::database_processing;
     use crate::db_get;
     use sled::{
@@ -155,7 +233,13 @@ mod tests {
         }
 
         let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<&[u8], &[u8]>>();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
 
         // Call handle_reorg
         let result = handle_reorg(&head_cache, 2, 3, db_tx.clone()).await;
@@ -191,6 +275,55 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_handle_reorg_backwards() {
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+        let cache = Db::open_with_config(&Config::tmp().unwrap()).unwrap();
+        let _ = cache.insert("key5", "value5");
+
+        head_cache
+            .write()
+            .unwrap()
+            .insert(5, vec!["key5".as_bytes()]);
+
+        let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<&[u8], &[u8]>>();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            cache,
+            HotCache::default(),
+            CacheEviction::default(),
+            None,
+        ));
+
+        // New head is lower than the one we had
+        handle_reorg(&head_cache, 6, 4, db_tx.clone())
+            .await
+            .unwrap();
+
+        assert!(head_cache.read().unwrap().is_empty());
+        assert!(db_get!(db_tx, "key5".as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_head_history() {
+        let mut history = HeadHistory::default();
+
+        assert_eq!(history.record(10, "0xa10", "0xa9"), None);
+        assert_eq!(history.record(11, "0xa11", "0xa10"), None);
+        assert_eq!(history.record(12, "0xa12", "0xa11"), None);
+        // Same head again
+        assert_eq!(history.record(12, "0xa12", "0xa11"), None);
+
+        // Sibling of 12
+        assert_eq!(history.record(12, "0xb12", "0xa11"), Some(12..=12));
+        // 13 builds on a different 12 than the one we have
+        assert_eq!(history.record(13, "0xc13", "0xc12"), Some(12..=13));
+        // Reorg back below the highest head we saw
+        assert_eq!(history.record(11, "0xd11", "0xa10"), Some(11..=13));
+        assert_eq!(history.record(12, "0xd12", "0xd11"), None);
+    }
+
     #[test]
     fn test_remove_stale() {
         // Create test data and resources
@@ -0,0 +1,378 @@
+//! Head staleness tracking.
+//!
+//! Polling `eth_blockNumber` only tells us where a node was at the time of the
+//! health check. For RPCs that expose a `ws_url`, we instead keep a dedicated
+//! `newHeads` subscription open to each node and note the time its head last
+//! advanced. Nodes whose head hasn't moved in `head_staleness_ms` get moved to
+//! the poverty list, and are let back in once a new head comes through.
+//!
+//! The heads of nodes in the active pool can also be passed on, to serve
+//! `newHeads` subscriptions from every node at once, see `head_aggregation`.
+//!
+//! Subscriptions follow the RPC lists: RPCs added at runtime get one, and
+//! removed RPCs have theirs closed.
+
+use crate::{
+    database::expiry::now_ms,
+    rpc::types::{
+        hex_to_decimal,
+        Status,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{
+        sleep,
+        timeout,
+    },
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+/// Time to wait before reconnecting a dropped `newHeads` subscription.
+const RECONNECT_DELAY: Duration = Duration::from_millis(1000);
+
+/// How often to check for RPCs added to or removed from the RPC lists.
+const WATCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+const NEW_HEADS_SUBSCRIPTION: &str =
+    r#"{"jsonrpc":"2.0","method":"eth_subscribe","params":["newHeads"],"id":1}"#;
+
+/// Returns true if the head of an RPC hasn't advanced in `head_staleness_ms`.
+///
+/// RPCs we haven't seen a head from yet are never stale.
+fn is_stale(status: &Status, now: u64, head_staleness_ms: u64) -> bool {
+    head_staleness_ms != 0
+        && status.ws_head_updated_ms != 0
+        && now.saturating_sub(status.ws_head_updated_ms) > head_staleness_ms
+}
+
+/// Record a head received from the `newHeads` subscription of the RPC named `name`.
+///
+/// Returns false if there is no RPC named `name` in `rpcs`.
+fn record_ws_head(rpcs: &mut [Rpc], name: &str, head: u64, now: u64) -> bool {
+    let Some(rpc) = rpcs.iter_mut().find(|rpc| rpc.name == name) else {
+        return false;
+    };
+
+    if head > rpc.status.ws_head {
+        rpc.status.ws_head = head;
+        rpc.status.ws_head_updated_ms = now;
+
+        if rpc.status.is_stale {
+            tracing::info!(head, "{} is producing new heads again!", rpc.name);
+            rpc.status.is_stale = false;
+        }
+    }
+
+    true
+}
+
+/// Extract the block number out of a `newHeads` notification.
+fn notification_head(notification: &Value) -> Option<u64> {
+    let number = notification["params"]["result"]["number"].as_str()?;
+    hex_to_decimal(number).ok()
+}
+
+/// Returns true if the RPC named `name` is in `rpc_list` or `poverty_list`.
+fn is_listed(rpc_list: &RwLock<Vec<Rpc>>, poverty_list: &RwLock<Vec<Rpc>>, name: &str) -> bool {
+    let listed = |list: &RwLock<Vec<Rpc>>| {
+        list.read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .any(|rpc| rpc.name == name)
+    };
+
+    listed(rpc_list) || listed(poverty_list)
+}
+
+/// Keep a `newHeads` subscription open to `rpc`, reconnecting if it drops.
+///
+/// Notifications are sent to `heads_tx` while `rpc` is in the active pool.
+/// Returns once `rpc` is removed, closing the subscription.
+async fn follow_new_heads(
+    rpc: Rpc,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    heads_tx: Option<mpsc::UnboundedSender<Value>>,
+) {
+    let Some(ws_url) = rpc.ws_url.clone() else {
+        return;
+    };
+
+    while is_listed(&rpc_list, &poverty_list, &rpc.name) {
+        let ws_stream = match connect_async(&ws_url).await {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                tracing::warn!(?e, "Couldn't open newHeads subscription to {}", rpc.name);
+                sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        if ws_sender
+            .send(Message::Text(NEW_HEADS_SUBSCRIPTION.to_string()))
+            .await
+            .is_err()
+        {
+            sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+
+        loop {
+            // Wake up now and then to notice removals of RPCs that went quiet
+            let message = match timeout(WATCH_INTERVAL, ws_receiver.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(_) => break,
+                Err(_) => {
+                    if !is_listed(&rpc_list, &poverty_list, &rpc.name) {
+                        let _ = ws_sender.close().await;
+                        return;
+                    }
+                    continue;
+                }
+            };
+            let Ok(text) = message.into_text() else {
+                continue;
+            };
+            let Ok(notification) = serde_json::from_str::<Value>(&text) else {
+                continue;
+            };
+            let Some(head) = notification_head(&notification) else {
+                continue;
+            };
+
+            let now = now_ms();
+            let recorded = record_ws_head(
+                &mut rpc_list.write().unwrap_or_else(|e| e.into_inner()),
+                &rpc.name,
+                head,
+                now,
+            );
+            if recorded {
+                if let Some(heads_tx) = &heads_tx {
+                    let _ = heads_tx.send(notification);
+                }
+            } else if !record_ws_head(
+                &mut poverty_list.write().unwrap_or_else(|e| e.into_inner()),
+                &rpc.name,
+                head,
+                now,
+            ) {
+                let _ = ws_sender.close().await;
+                return;
+            }
+        }
+
+        tracing::warn!(
+            "newHeads subscription to {} dropped! Reconnecting...",
+            rpc.name
+        );
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Follow the heads of every RPC in `rpc_list` and `poverty_list` that has a
+/// `ws_url`, including ones added later.
+///
+/// `newHeads` notifications from RPCs in the active pool are sent to `heads_tx`.
+pub async fn watch_heads(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    heads_tx: Option<mpsc::UnboundedSender<Value>>,
+) {
+    let mut watchers: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    loop {
+        watchers.retain(|_, watcher| !watcher.is_finished());
+
+        let mut rpcs = rpc_list.read().unwrap_or_else(|e| e.into_inner()).clone();
+        rpcs.extend(
+            poverty_list
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .cloned(),
+        );
+        for rpc in rpcs.into_iter().filter(|rpc| rpc.ws_url.is_some()) {
+            if watchers.contains_key(&rpc.name) {
+                continue;
+            }
+            let name = rpc.name.clone();
+            let watcher = tokio::spawn(follow_new_heads(
+                rpc,
+                Arc::clone(&rpc_list),
+                Arc::clone(&poverty_list),
+                heads_tx.clone(),
+            ));
+            watchers.insert(name, watcher);
+        }
+
+        sleep(WATCH_INTERVAL).await;
+    }
+}
+
+/// Move RPCs whose head hasn't advanced in `head_staleness_ms` at `now` to `poverty_list`.
+fn exclude_stale_at(
+    rpc_list: &mut Vec<Rpc>,
+    poverty_list: &mut Vec<Rpc>,
+    head_staleness_ms: u64,
+    now: u64,
+) {
+    // Remove from the back so the indices stay valid
+    for index in (0..rpc_list.len()).rev() {
+        if !is_stale(&rpc_list[index].status, now, head_staleness_ms) {
+            continue;
+        }
+
+        let mut rpc = rpc_list.remove(index);
+        tracing::warn!(
+            head = rpc.status.ws_head,
+            last_advanced_ms = now.saturating_sub(rpc.status.ws_head_updated_ms),
+            "{}'s head is stale! Removing from active RPC pool.",
+            rpc.name
+        );
+        metrics::counter!("rpc_stale_head_total", "rpc_name" => rpc.name.clone()).increment(1);
+        metrics::gauge!(
+            "rpc_health_by_name",
+            "rpc_name" => rpc.name.clone(),
+            "reported_head" => rpc.status.ws_head.to_string(),
+            "is_syncing" => rpc.status.is_syncing.to_string()
+        )
+        .set(0.0);
+
+        rpc.status.is_erroring = true;
+        rpc.status.is_stale = true;
+        poverty_list.push(rpc);
+    }
+}
+
+/// Move RPCs whose head hasn't advanced in `head_staleness_ms` to `poverty_list`.
+pub fn exclude_stale(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: &Arc<RwLock<Vec<Rpc>>>,
+    head_staleness_ms: u64,
+) {
+    let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+    let mut poverty_list_guard = poverty_list.write().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+
+    exclude_stale_at(
+        &mut rpc_list_guard,
+        &mut poverty_list_guard,
+        head_staleness_ms,
+        now_ms(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rpc(url: &str) -> Rpc {
+        Rpc::new(url.parse().unwrap(), None, 5, 1000, 10.0)
+    }
+
+    #[test]
+    fn test_notification_head() {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "eth_subscription",
+            "params": {
+                "subscription": "0x9ce59a13059e417087c02d3236a0b1cc",
+                "result": {"number": "0x1b4", "hash": "0x00"}
+            }
+        });
+        assert_eq!(notification_head(&notification), Some(436));
+
+        // Subscription confirmations don't carry a head
+        let confirmation = json!({"jsonrpc": "2.0", "id": 1, "result": "0x9ce59a13"});
+        assert_eq!(notification_head(&confirmation), None);
+    }
+
+    #[test]
+    fn test_record_ws_head() {
+        let mut rpcs = vec![rpc("http://127.0.0.1:8545")];
+        let name = rpcs[0].name.clone();
+
+        assert!(record_ws_head(&mut rpcs, &name, 10, 1000));
+        assert_eq!(rpcs[0].status.ws_head, 10);
+        assert_eq!(rpcs[0].status.ws_head_updated_ms, 1000);
+
+        // Repeated or older heads don't count as advancing
+        assert!(record_ws_head(&mut rpcs, &name, 10, 2000));
+        assert!(record_ws_head(&mut rpcs, &name, 9, 3000));
+        assert_eq!(rpcs[0].status.ws_head_updated_ms, 1000);
+
+        // A new head clears staleness
+        rpcs[0].status.is_stale = true;
+        assert!(record_ws_head(&mut rpcs, &name, 11, 4000));
+        assert!(!rpcs[0].status.is_stale);
+
+        assert!(!record_ws_head(&mut rpcs, "unknown", 12, 5000));
+    }
+
+    #[test]
+    fn test_is_listed() {
+        let active = rpc("http://127.0.0.1:8545");
+        let poor = rpc("http://127.0.0.2:8545");
+        let rpc_list = RwLock::new(vec![active.clone()]);
+        let poverty_list = RwLock::new(vec![poor.clone()]);
+
+        assert!(is_listed(&rpc_list, &poverty_list, &active.name));
+        assert!(is_listed(&rpc_list, &poverty_list, &poor.name));
+        assert!(!is_listed(&rpc_list, &poverty_list, "removed"));
+    }
+
+    #[test]
+    fn test_exclude_stale() {
+        let mut fresh = rpc("http://127.0.0.1:8545");
+        fresh.status.ws_head_updated_ms = 9_500;
+        let mut stale = rpc("http://127.0.0.2:8545");
+        stale.status.ws_head_updated_ms = 5_000;
+        // Never seen a head, so we can't tell
+        let unknown = rpc("http://127.0.0.3:8545");
+
+        let mut rpc_list = vec![fresh.clone(), stale.clone(), unknown];
+        let mut poverty_list = Vec::new();
+
+        // Disabled
+        exclude_stale_at(&mut rpc_list, &mut poverty_list, 0, 10_000);
+        assert_eq!(rpc_list.len(), 3);
+
+        exclude_stale_at(&mut rpc_list, &mut poverty_list, 1_000, 10_000);
+        assert_eq!(rpc_list.len(), 2);
+        assert_eq!(rpc_list[0].name, fresh.name);
+        assert_eq!(poverty_list.len(), 1);
+        assert_eq!(poverty_list[0].name, stale.name);
+        assert!(poverty_list[0].status.is_stale);
+        assert!(poverty_list[0].status.is_erroring);
+    }
+}
@@ -11,7 +11,16 @@
 //! be rewritten to the block number `latest` represents, caching them or querying
 //! them from the cache.
 
+pub mod capabilities;
 pub mod check;
+pub mod consensus;
+pub mod convergence;
+pub mod divergence;
+pub mod emergency;
 pub mod error;
+pub mod events;
 pub mod head_cache;
+pub mod head_staleness;
+pub mod probe;
+pub mod quarantine;
 pub mod safe_block;
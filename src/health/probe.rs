@@ -0,0 +1,150 @@
+//! Synthetic latency probes.
+//!
+//! Latency is normally only measured from live requests. On deployments with
+//! little traffic that means selection could be working with very old numbers.
+//! When enabled, we periodically send a cheap `eth_blockNumber` to every RPC
+//! and feed the time it took into its latency moving average.
+
+use crate::{
+    health::emergency::{
+        emergency_dormant,
+        query_awake,
+    },
+    Rpc,
+    Settings,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::future::join_all;
+use tokio::time::{
+    sleep,
+    timeout,
+};
+
+/// Measure how long `eth_blockNumber` takes on each RPC, in ns.
+///
+/// Errors and timeouts are counted as taking the full `ttl`.
+async fn probe_latencies(rpcs: &[Rpc], ttl: u64) -> Vec<f64> {
+    let probes = rpcs.iter().map(|rpc| {
+        async move {
+            let start = Instant::now();
+            match timeout(Duration::from_millis(ttl), rpc.block_number()).await {
+                Ok(Ok(_)) => start.elapsed().as_nanos() as f64,
+                Err(_) | Ok(Err(_)) => Duration::from_millis(ttl).as_nanos() as f64,
+            }
+        }
+    });
+
+    join_all(probes).await
+}
+
+/// Apply probed latencies to the RPCs they were measured on, `None` for ones that weren't probed.
+fn apply_latencies(rpc_list: &mut [Rpc], probed: &[Rpc], latencies: &[Option<f64>]) {
+    for (index, (rpc, latency)) in probed.iter().zip(latencies).enumerate() {
+        let Some(latency) = latency else {
+            continue;
+        };
+
+        // The list might have changed while we were probing
+        if let Some(entry) = rpc_list.get_mut(index) {
+            if entry.name == rpc.name {
+                entry.update_latency(*latency);
+            }
+        }
+    }
+}
+
+/// Probe the latency of all active RPCs every `latency_probe_interval_ms`.
+///
+/// Emergency RPCs are only probed while they're serving traffic.
+pub async fn latency_probe(rpc_list: Arc<RwLock<Vec<Rpc>>>, config: Arc<RwLock<Settings>>) {
+    loop {
+        let (interval, ttl) = {
+            let config_guard = config.read().unwrap();
+            (
+                config_guard.latency_probe_interval_ms,
+                config_guard.ttl as u64,
+            )
+        };
+
+        if interval == 0 {
+            tracing::info!("Latency probes disabled");
+            return;
+        }
+
+        sleep(Duration::from_millis(interval)).await;
+
+        let probed = rpc_list
+            .read()
+            .unwrap_or_else(|e| {
+                // Handle the case where the RwLock is poisoned
+                e.into_inner()
+            })
+            .clone();
+
+        let latencies = query_awake(&probed, emergency_dormant(&probed), |rpcs| {
+            async move {
+                probe_latencies(&rpcs, ttl)
+                    .await
+                    .into_iter()
+                    .map(Some)
+                    .collect()
+            }
+        })
+        .await;
+
+        let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        });
+        apply_latencies(&mut rpc_list_guard, &probed, &latencies);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_latencies() {
+        let rpc1 = Rpc::new(
+            "http://127.0.0.1:8545".parse().unwrap(),
+            None,
+            5,
+            1000,
+            10.0,
+        );
+        let rpc2 = Rpc::new(
+            "http://127.0.0.2:8545".parse().unwrap(),
+            None,
+            5,
+            1000,
+            10.0,
+        );
+        let probed = [rpc1.clone(), rpc2.clone()];
+
+        // rpc1 got removed from the list while probing
+        let mut rpc_list = vec![rpc2];
+        apply_latencies(&mut rpc_list, &probed, &[Some(100.0), Some(200.0)]);
+        assert_eq!(rpc_list[0].status.latency, 0.0);
+
+        let mut rpc_list = vec![rpc1, rpc_list.remove(0)];
+        apply_latencies(&mut rpc_list, &probed, &[Some(100.0), Some(200.0)]);
+        assert_eq!(rpc_list[0].status.latency, 100.0);
+        assert_eq!(rpc_list[1].status.latency, 200.0);
+
+        // Dormant emergency RPCs aren't probed
+        apply_latencies(&mut rpc_list, &probed, &[None, Some(300.0)]);
+        assert_eq!(rpc_list[0].status.latency, 100.0);
+    }
+}
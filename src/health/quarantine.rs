@@ -0,0 +1,292 @@
+//! Per method family quarantine.
+//!
+//! A node can agree with everyone on the head and the finalized block hash
+//! while still serving wrong data, like stale balances or missing logs.
+//! Every time the finalized block advances, we send the same canary request
+//! for each method family to every active RPC and compare the results.
+//!
+//! RPCs that disagree with the majority `quarantine_threshold` checks in a row
+//! stop getting picked for methods of that family, but keep serving everything
+//! else. They're let back in after a check where they agree with the majority.
+
+use crate::{
+    health::{
+        divergence::{
+            diverged_indices,
+            majority_hash,
+        },
+        emergency::{
+            emergency_dormant,
+            query_awake,
+        },
+    },
+    rpc::types::Status,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::future::join_all;
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+/// Group of methods that read the same kind of data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MethodFamily {
+    /// Account state, like balances, nonces, code and storage
+    State,
+    /// Logs and receipts
+    Logs,
+}
+
+impl MethodFamily {
+    pub const ALL: [MethodFamily; 2] = [MethodFamily::State, MethodFamily::Logs];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MethodFamily::State => "state",
+            MethodFamily::Logs => "logs",
+        }
+    }
+
+    /// Returns the family `method` belongs to, if we cross-check it.
+    pub fn from_method(method: &str) -> Option<Self> {
+        match method {
+            "eth_getBalance"
+            | "eth_getCode"
+            | "eth_getStorageAt"
+            | "eth_getTransactionCount"
+            | "eth_getProof"
+            | "eth_call" => Some(MethodFamily::State),
+            "eth_getLogs"
+            | "eth_getFilterLogs"
+            | "eth_getTransactionReceipt"
+            | "eth_getBlockReceipts" => Some(MethodFamily::Logs),
+            _ => None,
+        }
+    }
+
+    /// Request every healthy RPC should answer the same way at the finalized block `number`.
+    fn canary(&self, number: u64) -> Value {
+        let number = format!("0x{:x}", number);
+        let (method, params) = match self {
+            MethodFamily::State => {
+                (
+                    "eth_getBalance",
+                    json!(["0x0000000000000000000000000000000000000000", number]),
+                )
+            }
+            MethodFamily::Logs => {
+                (
+                    "eth_getLogs",
+                    json!([{"fromBlock": number, "toBlock": number}]),
+                )
+            }
+        };
+
+        json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        })
+    }
+}
+
+/// Send `request` to every RPC and return a digest of each result.
+///
+/// Erroring or timed out RPCs report `None`, so they don't count as disagreeing.
+async fn collect_results(rpcs: &[Rpc], request: &Value, ttl: u128) -> Vec<Option<String>> {
+    let results = rpcs.iter().map(|rpc| {
+        async move {
+            let response = timeout(
+                Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+                rpc.send_request(request.clone()),
+            )
+            .await
+            .ok()?
+            .ok()?;
+
+            let response: Value = serde_json::from_str(&response).ok()?;
+            if response.get("error").is_some() || response.get("result").is_none() {
+                return None;
+            }
+
+            Some(
+                blake3::hash(response["result"].to_string().as_bytes())
+                    .to_hex()
+                    .to_string(),
+            )
+        }
+    });
+
+    join_all(results).await
+}
+
+/// Record the outcome of a cross-check of `family` in `status`.
+///
+/// Returns true if the quarantine state of the RPC changed.
+fn record_check(status: &mut Status, family: MethodFamily, diverged: bool, threshold: u32) -> bool {
+    if !diverged {
+        status.divergent_checks.remove(&family);
+        let was_quarantined = status.quarantined.contains(&family);
+        status
+            .quarantined
+            .retain(|quarantined| *quarantined != family);
+        return was_quarantined;
+    }
+
+    let strikes = status.divergent_checks.entry(family).or_insert(0);
+    *strikes += 1;
+
+    if *strikes >= threshold && !status.quarantined.contains(&family) {
+        status.quarantined.push(family);
+        return true;
+    }
+
+    false
+}
+
+/// Cross-check every method family at the `finalized` block across the RPCs in `rpc_list`.
+pub async fn cross_check(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    finalized: u64,
+    ttl: u128,
+    threshold: u32,
+) {
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .clone();
+
+    // Need at least 3 nodes for a majority to mean something
+    if rpc_list_clone.len() < 3 {
+        return;
+    }
+
+    // Leave emergency RPCs alone until they have to take over
+    let skip_emergency = emergency_dormant(&rpc_list_clone);
+
+    for family in MethodFamily::ALL {
+        let request = family.canary(finalized);
+        let results = query_awake(&rpc_list_clone, skip_emergency, |rpcs| {
+            async move { collect_results(&rpcs, &request, ttl).await }
+        })
+        .await;
+
+        let Some(majority) = majority_hash(&results) else {
+            tracing::warn!(
+                finalized,
+                family = family.as_str(),
+                "No majority on the cross-check result! Skipping."
+            );
+            continue;
+        };
+        let diverged = diverged_indices(&results, majority);
+
+        let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        });
+
+        for (index, rpc) in rpc_list_clone.iter().enumerate() {
+            // RPCs that didn't answer weren't checked
+            if results[index].is_none() {
+                continue;
+            }
+
+            // The list might have changed while we were waiting on responses
+            let Some(entry) = rpc_list_guard.get_mut(index) else {
+                continue;
+            };
+            if entry.name != rpc.name {
+                continue;
+            }
+
+            let is_diverged = diverged.contains(&index);
+            if !record_check(&mut entry.status, family, is_diverged, threshold) {
+                continue;
+            }
+
+            if is_diverged {
+                tracing::error!(
+                    finalized,
+                    family = family.as_str(),
+                    "{} keeps returning data that diverges from the majority! Quarantined.",
+                    entry.name
+                );
+                metrics::counter!(
+                    "rpc_quarantine_total",
+                    "rpc_name" => entry.name.clone(),
+                    "family" => family.as_str()
+                )
+                .increment(1);
+            } else {
+                tracing::info!(
+                    family = family.as_str(),
+                    "{} agrees with the majority again! Lifting quarantine.",
+                    entry.name
+                );
+            }
+            metrics::gauge!(
+                "rpc_quarantined",
+                "rpc_name" => entry.name.clone(),
+                "family" => family.as_str()
+            )
+            .set(if is_diverged { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_method() {
+        assert_eq!(
+            MethodFamily::from_method("eth_getBalance"),
+            Some(MethodFamily::State)
+        );
+        assert_eq!(
+            MethodFamily::from_method("eth_getTransactionReceipt"),
+            Some(MethodFamily::Logs)
+        );
+        assert_eq!(MethodFamily::from_method("eth_blockNumber"), None);
+    }
+
+    #[test]
+    fn test_record_check() {
+        let mut status = Status::default();
+
+        assert!(!record_check(&mut status, MethodFamily::Logs, true, 2));
+        assert!(status.quarantined.is_empty());
+
+        // Second strike in a row quarantines
+        assert!(record_check(&mut status, MethodFamily::Logs, true, 2));
+        assert_eq!(status.quarantined, vec![MethodFamily::Logs]);
+        assert!(!record_check(&mut status, MethodFamily::Logs, true, 2));
+
+        // Other families are unaffected
+        assert!(!record_check(&mut status, MethodFamily::State, false, 2));
+        assert_eq!(status.quarantined, vec![MethodFamily::Logs]);
+
+        // A clean check lifts it and resets the strikes
+        assert!(record_check(&mut status, MethodFamily::Logs, false, 2));
+        assert!(status.quarantined.is_empty());
+        assert!(!record_check(&mut status, MethodFamily::Logs, true, 2));
+    }
+}
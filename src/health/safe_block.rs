@@ -2,6 +2,13 @@ use crate::{
     balancer::processing::CacheArgs,
     config::system::WS_HEALTH_CHECK_USER_ID,
     database::types::GenericBytes,
+    health::{
+        emergency::emergency_dormant,
+        head_cache::{
+            handle_reorg,
+            HeadHistory,
+        },
+    },
     rpc::{
         error::RpcError,
         method::EthRpcMethod,
@@ -18,6 +25,7 @@ use crate::{
             RequestResult,
             SubscriptionData,
             WsconnMessage,
+            AGGREGATED_NODE_ID,
         },
     },
 };
@@ -27,6 +35,7 @@ use std::sync::{
     RwLock,
 };
 
+use rust_tracing::deps::metrics;
 use serde_json::Value;
 
 use tokio::{
@@ -78,8 +87,14 @@ pub async fn get_safe_block(
             e.into_inner()
         });
 
-        len = rpc_list_guard.len();
-        rpc_list_clone = rpc_list_guard.clone();
+        // Leave emergency RPCs alone until they have to take over
+        let skip_emergency = emergency_dormant(&rpc_list_guard);
+        rpc_list_clone = rpc_list_guard
+            .iter()
+            .filter(|rpc| !(skip_emergency && rpc.emergency))
+            .cloned()
+            .collect::<Vec<Rpc>>();
+        len = rpc_list_clone.len();
     }
 
     let mut safe = 0;
@@ -226,20 +241,43 @@ pub async fn subscribe_to_new_heads<K, V>(
     // New message == new head received. We can then update and process
     // everything associated with a new head block.
     let mut subscription_id: String = "".to_string();
+    let mut head_history = HeadHistory::default();
     loop {
         match timeout(Duration::from_millis(expected_block_time), rx.recv()).await {
             Ok(Some(msg)) => {
                 if let RequestResult::Subscription(sub) = msg {
-                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
-                    let a = hex_to_decimal(sub["params"]["result"]["number"].as_str().unwrap())
-                        .unwrap();
+                    let head = &sub["params"]["result"];
+                    let a = hex_to_decimal(head["number"].as_str().unwrap()).unwrap();
                     sub["params"]["subscription"]
                         .as_str()
                         .unwrap()
                         .clone_into(&mut subscription_id);
                     tracing::info!(a, "New chain head");
+
+                    // Drop cached responses from blocks that are no longer canonical
+                    // before anyone can be served them against the new head
+                    if let (Some(hash), Some(parent_hash)) =
+                        (head["hash"].as_str(), head["parentHash"].as_str())
+                    {
+                        if let Some(reorged) = head_history.record(a, hash, parent_hash) {
+                            tracing::warn!(
+                                from = reorged.start(),
+                                to = reorged.end(),
+                                "Reorg detected! Removing stale entries from the cache."
+                            );
+                            metrics::counter!("cache_reorgs_total").increment(1);
+                            let _ = handle_reorg(
+                                &cache_args.head_cache,
+                                *reorged.start(),
+                                *reorged.end(),
+                                cache_args.cache.clone(),
+                            )
+                            .await;
+                        }
+                    }
+
                     let _ = blocknum_tx.send(a);
-                    nn_rwlock.latest = a;
+                    cache_args.named_numbers.write().unwrap().latest = a;
                 }
             }
             Ok(None) => {
@@ -273,6 +311,10 @@ pub async fn subscribe_to_new_heads<K, V>(
                         continue;
                     }
                 };
+                // Heads come from every node, there's nowhere to move them
+                if node_id == AGGREGATED_NODE_ID {
+                    continue;
+                }
                 match move_subscriptions(
                     &incoming_tx,
                     outgoing_rx.resubscribe(),
@@ -16,6 +16,11 @@ use crate::{
             LiveReadyUpdate,
             ReadinessState,
         },
+        prometheus::{
+            install_recorder,
+            serve_metrics,
+        },
+        AdminState,
     },
     balancer::{
         accept_http::{
@@ -23,10 +28,38 @@ use crate::{
             ConnectionParams,
             RequestChannels,
         },
+        acme::{
+            initial_certified_key,
+            manage_certificates,
+        },
+        blocklist::{
+            sync_blocklist,
+            Blocklist,
+        },
+        cache_index::CacheIndex,
+        connection_limits::ConnectionTracker,
+        counters::CounterStore,
+        heatmap::RequestHeatmap,
+        listeners::bind_listeners,
+        logs::LogCache,
         processing::CacheArgs,
+        proxy_protocol::read_proxy_header,
+        singleflight::InFlight,
+        tls::{
+            acceptor,
+            load_certified_key,
+            watch_certificates,
+            CertResolver,
+            HANDSHAKE_TIMEOUT,
+        },
+        trace_context::install_propagator,
+        warming::warm_cache,
     },
     config::{
         cache_setup::setup_data,
+        check::check_config,
+        logging::init_logging,
+        reload::watch_config,
         system::FANOUT,
         types::{
             CacheSettings,
@@ -35,22 +68,58 @@ use crate::{
     },
     database::{
         accept::database_processing,
+        eviction::CacheEviction,
+        hot_cache::HotCache,
+        shared_cache::SharedCache,
         types::GenericDatabase,
+        writer::CacheWriter,
     },
     health::{
+        capabilities::discover_capabilities,
         check::{
             dropped_listener,
             health_check,
         },
+        consensus::Consensus,
+        convergence::wait_for_convergence,
+        events::HealthEvents,
         head_cache::manage_cache,
+        head_staleness::watch_heads,
+        probe::latency_probe,
         safe_block::{
             subscribe_to_new_heads,
             NamedBlocknumbers,
         },
     },
-    rpc::types::Rpc,
+    rpc::{
+        quota::{
+            load_quota_usage,
+            persist_quota_usage,
+            save_quota_usage,
+        },
+        stats::{
+            load_upstream_stats,
+            persist_upstream_stats,
+            save_upstream_stats,
+        },
+        types::Rpc,
+    },
     websocket::{
         client::ws_conn_manager,
+        connections::WsConnections,
+        head_aggregation::{
+            aggregate_heads,
+            register_aggregated_heads,
+        },
+        pending_aggregation::{
+            aggregate_pending,
+            register_aggregated_pending,
+            watch_pending,
+        },
+        shutdown::{
+            drain_websockets,
+            shutdown_signal,
+        },
         subscription_manager::subscription_dispatcher,
         types::{
             IncomingResponse,
@@ -67,15 +136,13 @@ use std::{
         Arc,
         RwLock,
     },
+    time::Duration,
 };
 
-use tokio::{
-    net::TcpListener,
-    sync::{
-        broadcast,
-        mpsc,
-        watch,
-    },
+use tokio::sync::{
+    broadcast,
+    mpsc,
+    watch,
 };
 
 use hyper::{
@@ -83,33 +150,55 @@ use hyper::{
     service::service_fn,
 };
 use hyper_util_blutgang::rt::TokioIo;
+use rust_tracing::deps::metrics;
+
+#[cfg(feature = "redb")]
+use crate::database::types::RedbCache;
 
 /// `jemalloc` offers faster mallocs when dealing with lots of threads which is what we're doing
 #[global_allocator]
 static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-fn init_tracing_subscriber() -> Option<rust_tracing::utils::otlp::OtelGuard> {
-    #[cfg(feature = "journald")]
-    {
-        rust_tracing::trace_with_journald()
-    }
-
-    #[cfg(not(feature = "journald"))]
-    {
-        rust_tracing::trace()
-    }
-}
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    init_tracing_subscriber();
-
     // Get all the cli args and set them
+    //
+    // This happens before logging is set up, as the config picks the format
     let mut settings = Settings::new()?;
+
+    // Spans are only exported while the guard lives
+    let _otel_guard = init_logging(settings.log_format);
+    install_propagator();
+
+    if !settings.is_ws {
+        tracing::warn!(
+            "WebSocket endpoints not present for all nodes, or expected_block_time is 0."
+        );
+        tracing::warn!("Disabling WS only-features. Please check docs for more info.");
+    }
+
+    // `blutgang check-config` only checks the config
+    if settings.check_config {
+        let ok = check_config(&settings).await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     if settings.sort_on_startup {
         settings = settings.sort_on_startup().await?;
     }
     let cache_settings = settings.cache.clone();
+
+    // Serve metrics to Prometheus
+    if settings.metrics.enabled {
+        if let Some(handle) = install_recorder() {
+            let address = settings.metrics.address;
+            tokio::task::spawn(async move {
+                if let Err(err) = serve_metrics(handle, address).await {
+                    tracing::error!(?err, "Failed to serve metrics");
+                }
+            });
+        }
+    }
     let config = Arc::new(RwLock::new(settings));
 
     // Create/Open DB
@@ -128,23 +217,48 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .expect("Can't open/create database!");
             run(cache, config).await
         }
+        #[cfg(feature = "redb")]
+        CacheSettings::Redb(redb) => {
+            let cache =
+                <RedbCache as GenericDatabase>::open(&redb).expect("Can't open/create database!");
+            run(cache, config).await
+        }
     }
 }
 
-async fn run<DB: GenericDatabase + 'static>(
+async fn run<DB: GenericDatabase + Sync + 'static>(
     cache: DB,
     config: Arc<RwLock<Settings>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Copy the configuration values we need
-    let (addr, do_clear, do_health_check, admin_enabled, is_ws, expected_block_time) = {
+    let (
+        addresses,
+        socket,
+        socket_mode,
+        do_clear,
+        do_health_check,
+        admin_enabled,
+        is_ws,
+        expected_block_time,
+        cache_format,
+        cache_compression,
+        cache_expiry,
+        cache_policies,
+    ) = {
         let config_guard = config.read().unwrap();
         (
-            config_guard.address,
+            config_guard.addresses.clone(),
+            config_guard.socket.clone(),
+            config_guard.socket_mode,
             config_guard.do_clear,
             config_guard.health_check,
             config_guard.admin.enabled,
             config_guard.is_ws,
             config_guard.expected_block_time,
+            config_guard.cache_format,
+            config_guard.cache_compression,
+            config_guard.cache_expiry,
+            Arc::new(config_guard.cache_policies.clone()),
         )
     };
 
@@ -161,11 +275,60 @@ async fn run<DB: GenericDatabase + 'static>(
 
     // Starts the database task.
     let (db_tx, db_rx) = mpsc::unbounded_channel();
-    tokio::task::spawn(database_processing::<[u8; 32], Vec<u8>, DB>(db_rx, cache));
-
-    // We create a TcpListener and bind it to 127.0.0.1:3000
-    let listener = TcpListener::bind(addr).await?;
-    tracing::info!(?addr, "Bound to");
+    let (hot_cache, eviction, shared_cache) = {
+        let config_guard = config.read().unwrap();
+        (
+            HotCache::new(config_guard.hot_cache_size),
+            CacheEviction::new(config_guard.cache_eviction),
+            SharedCache::new(&config_guard.shared_cache)
+                .expect("failed to parse shared cache `url`")
+                .map(Arc::new),
+        )
+    };
+    if shared_cache.is_some() {
+        tracing::info!("Sharing cache through Redis");
+    }
+    tokio::task::spawn(database_processing::<[u8; 32], Vec<u8>, DB>(
+        db_rx,
+        cache,
+        hot_cache,
+        eviction,
+        shared_cache,
+    ));
+    // Writes responses to the cache in the background
+    let cache_writer = CacheWriter::new(db_tx.clone(), config.read().unwrap().cache_writes);
+    // Counts requests for rate limits
+    let request_counters = Arc::new(
+        CounterStore::new(&config.read().unwrap().counters, db_tx.clone())
+            .expect("failed to parse counters `redis_url`"),
+    );
+
+    // Bind every address we listen on, and the socket if there is one
+    let mut listeners = bind_listeners(&addresses, socket, socket_mode)?;
+
+    // Terminate TLS ourselves if we have a certificate
+    let tls_settings = config.read().unwrap().tls.clone();
+    let tls_acceptor = if tls_settings.enabled() {
+        let resolver = if tls_settings.acme.enabled() {
+            let resolver = Arc::new(CertResolver::new(initial_certified_key(
+                &tls_settings.acme,
+            )?));
+            tokio::task::spawn(manage_certificates(
+                tls_settings.acme,
+                Arc::clone(&resolver),
+            ));
+            resolver
+        } else {
+            let resolver = Arc::new(CertResolver::new(load_certified_key(&tls_settings)?));
+            tokio::task::spawn(watch_certificates(tls_settings, Arc::clone(&resolver)));
+            resolver
+        };
+        let tls_acceptor = acceptor(resolver)?;
+        tracing::info!("Serving HTTP and WS over TLS");
+        Some(tls_acceptor)
+    } else {
+        None
+    };
 
     let (blocknum_tx, blocknum_rx) = watch::channel(0);
     let (finalized_tx, finalized_rx) = watch::channel(0);
@@ -173,6 +336,52 @@ async fn run<DB: GenericDatabase + 'static>(
     let finalized_rx_arc = Arc::new(finalized_rx.clone());
     let rpc_poverty_list = Arc::new(RwLock::new(config.read().unwrap().poverty_list.clone()));
 
+    // Pick up the quota usage of paid providers and what we learned about RPCs
+    // from before the restart
+    load_quota_usage(&rpc_list_rwlock, &rpc_poverty_list, &db_tx).await;
+    load_upstream_stats(&rpc_list_rwlock, &rpc_poverty_list, &db_tx).await;
+    tokio::task::spawn(persist_quota_usage(
+        Arc::clone(&rpc_list_rwlock),
+        Arc::clone(&rpc_poverty_list),
+        db_tx.clone(),
+    ));
+    tokio::task::spawn(persist_upstream_stats(
+        Arc::clone(&rpc_list_rwlock),
+        Arc::clone(&rpc_poverty_list),
+        db_tx.clone(),
+    ));
+
+    // History of RPC health transitions, shared between the health check and admin
+    let health_events = Arc::new(HealthEvents::new(
+        config.read().unwrap().health_event_history,
+    ));
+    // Most queried contracts, counted while serving requests and reported by admin
+    let heatmap = Arc::new(RequestHeatmap::new(config.read().unwrap().request_heatmap));
+    // Head and IDs the RPCs agree on, kept by the health check to answer trivial
+    // methods and pin block tags
+    let consensus = Arc::new(Consensus::default());
+    // Entries being fetched, so HTTP, WS and cache warming don't fetch them twice
+    let in_flight = Arc::new(InFlight::default());
+    // Logs of finalized blocks, reused across overlapping `eth_getLogs` ranges
+    let log_cache = Arc::new(LogCache::new(config.read().unwrap().log_cache_size));
+    // Open WebSocket connections, tracked while serving them and listed by admin
+    let ws_connections = Arc::new(WsConnections::default());
+    // Open client connections, counted against connection limits
+    let connections = Arc::new(ConnectionTracker::default());
+    // Methods and blocks of cached entries, so admin can purge them
+    let cache_index = Arc::new(CacheIndex::new(config.read().unwrap().cache_index_size));
+    // Tracked by the health check, and used to key the cache
+    let named_blocknumbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+    // Canaries that read requests get mirrored to, reported on by admin
+    let shadow_list = Arc::new(config.read().unwrap().shadow_list.clone());
+
+    // Known-bad endpoints and client ranges, kept in sync with the configured URL
+    let blocklist = Arc::new(Blocklist::default());
+    let blocklist_settings = config.read().unwrap().blocklist.clone();
+    if !blocklist_settings.url.is_empty() {
+        tokio::task::spawn(sync_blocklist(Arc::clone(&blocklist), blocklist_settings));
+    }
+
     // We need liveness status channels even if admin is unused
     let (liveness_tx, liveness_rx) = mpsc::channel(16);
 
@@ -182,16 +391,31 @@ async fn run<DB: GenericDatabase + 'static>(
         let poverty_list_admin = Arc::clone(&rpc_poverty_list);
         let config_admin = Arc::clone(&config);
         let db_admin = db_tx.clone();
+        let admin_state = AdminState {
+            health_events: Arc::clone(&health_events),
+            heatmap: Arc::clone(&heatmap),
+            ws_connections: Arc::clone(&ws_connections),
+            blocklist: Arc::clone(&blocklist),
+            log_cache: Arc::clone(&log_cache),
+            cache_index: Arc::clone(&cache_index),
+            named_numbers: Arc::clone(&named_blocknumbers),
+            shadow_list: Arc::clone(&shadow_list),
+            head_cache: Arc::clone(&head_cache),
+        };
         tokio::task::spawn(async move {
             tracing::info!("Admin namespace enabled, accepting admin methods at admin port");
-            let _ = listen_for_admin_requests(
+            if let Err(err) = listen_for_admin_requests(
                 rpc_list_admin,
                 poverty_list_admin,
                 db_admin,
                 config_admin,
+                admin_state,
                 liveness_rx,
             )
-            .await;
+            .await
+            {
+                tracing::error!(?err, "Admin API stopped");
+            }
         });
     } else {
         // dont want to deal with potentially dropped channels if admin is disabled?
@@ -199,6 +423,14 @@ async fn run<DB: GenericDatabase + 'static>(
         tokio::task::spawn(liveness_update_sink(liveness_rx));
     }
 
+    // Heads from the `newHeads` subscription, for warming the cache
+    let warming_blocknum_rx = blocknum_rx.clone();
+    if config.read().unwrap().cache_warming.enabled && !(is_ws && do_health_check) {
+        tracing::warn!(
+            "Cache warming needs WebSockets and the health check, not warming the cache."
+        );
+    }
+
     // Spawn a thread for the head cache
     let head_cache_clone = Arc::clone(&head_cache);
     let finalized_rxclone = Arc::clone(&finalized_rx_arc);
@@ -213,11 +445,16 @@ async fn run<DB: GenericDatabase + 'static>(
         .await;
     });
 
+    // Find out which namespaces each RPC supports
+    let (health_check_methods, ttl) = {
+        let config_guard = config.read().unwrap();
+        (config_guard.health_check_methods.clone(), config_guard.ttl)
+    };
+    discover_capabilities(&rpc_list_rwlock, &health_check_methods, ttl).await;
+
     // Spawn a thread for the health check
     //
     // Also handle the finalized block tracking in this thread
-    let named_blocknumbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
-
     if do_health_check {
         let poverty_list_health = Arc::clone(&rpc_poverty_list);
         let config_health = Arc::clone(&config);
@@ -225,6 +462,8 @@ async fn run<DB: GenericDatabase + 'static>(
         let rpc_list_health = Arc::clone(&rpc_list_rwlock);
         let named_blocknumbers_health = Arc::clone(&named_blocknumbers);
         let liveness_tx_health = liveness_tx.clone();
+        let health_events_health = Arc::clone(&health_events);
+        let consensus_health = Arc::clone(&consensus);
 
         tokio::task::spawn(async move {
             let _ = health_check(
@@ -234,28 +473,93 @@ async fn run<DB: GenericDatabase + 'static>(
                 liveness_tx_health,
                 &named_blocknumbers_health,
                 &config_health,
+                &health_events_health,
+                &consensus_health,
             )
             .await;
         });
     }
 
+    // Keep latency data fresh even without traffic
+    if config.read().unwrap().latency_probe_interval_ms != 0 {
+        let rpc_list_probe = Arc::clone(&rpc_list_rwlock);
+        let config_probe = Arc::clone(&config);
+
+        tokio::task::spawn(latency_probe(rpc_list_probe, config_probe));
+    }
+
     // WebSocket connection + health check setup. Only runs when every node has a WS endpoint.
     let (incoming_tx, incoming_rx) = mpsc::unbounded_channel::<WsconnMessage>();
     let (outgoing_tx, outgoing_rx) = broadcast::channel::<IncomingResponse>(2048);
-    let sub_data = Arc::new(SubscriptionData::new());
+
+    // Apply config changes on SIGHUP, or when the file changes
+    tokio::task::spawn(watch_config(
+        Arc::clone(&config),
+        Arc::clone(&rpc_list_rwlock),
+        Arc::clone(&rpc_poverty_list),
+        is_ws.then(|| incoming_tx.clone()),
+    ));
+
+    let sub_data = Arc::new(
+        SubscriptionData::new()
+            .with_local_log_filters(config.read().unwrap().local_log_filters)
+            .with_subscription_limits(config.read().unwrap().ws_subscription_limits)
+            .with_replay(config.read().unwrap().ws_replay),
+    );
+
+    // Serve newHeads subscriptions from every RPC at once
+    let heads_tx = if is_ws && config.read().unwrap().aggregate_heads {
+        let (heads_tx, heads_rx) = mpsc::unbounded_channel();
+        register_aggregated_heads(&sub_data);
+        tokio::task::spawn(aggregate_heads(heads_rx, Arc::clone(&sub_data)));
+        Some(heads_tx)
+    } else {
+        None
+    };
+
+    // Follow the heads of RPCs with a WS endpoint to catch ones that stop advancing
+    if config.read().unwrap().head_staleness_ms != 0 || heads_tx.is_some() {
+        tokio::task::spawn(watch_heads(
+            Arc::clone(&rpc_list_rwlock),
+            Arc::clone(&rpc_poverty_list),
+            heads_tx,
+        ));
+    }
+
+    // Serve newPendingTransactions subscriptions from every RPC at once
+    if is_ws && config.read().unwrap().aggregate_pending_txs {
+        let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+        register_aggregated_pending(&sub_data);
+        tokio::task::spawn(aggregate_pending(
+            pending_rx,
+            Arc::clone(&sub_data),
+            Arc::clone(&rpc_list_rwlock),
+        ));
+        tokio::task::spawn(watch_pending(
+            Arc::clone(&rpc_list_rwlock),
+            Arc::clone(&sub_data),
+            pending_tx,
+        ));
+    }
+    // Connections to RPCs over WebSockets, closed on shutdown
+    // TODO: make this more ergonomic
+    let ws_handles = Arc::new(RwLock::new(Vec::<
+        Option<mpsc::UnboundedSender<serde_json::Value>>,
+    >::new()));
     if is_ws {
         let (ws_error_tx, ws_error_rx) = mpsc::unbounded_channel::<WsChannelErr>();
 
         let rpc_list_ws = Arc::clone(&rpc_list_rwlock);
-        // TODO: make this more ergonomic
-        let ws_handle = Arc::new(RwLock::new(Vec::<
-            Option<mpsc::UnboundedSender<serde_json::Value>>,
-        >::new()));
+        let ws_handle = Arc::clone(&ws_handles);
         let outgoing_rx_ws = outgoing_rx.resubscribe();
         let incoming_tx_ws = incoming_tx.clone();
+        let incoming_tx_manager = incoming_tx.clone();
         let ws_error_tx_ws = ws_error_tx.clone();
 
         let sub_dispatcher = Arc::clone(&sub_data);
+        let sub_data_ws = Arc::clone(&sub_data);
+        let ws_reconnect = config.read().unwrap().ws_reconnect;
+        let ws_keepalive = config.read().unwrap().ws_connection;
 
         tokio::task::spawn(async move {
             tokio::task::spawn(async move {
@@ -266,9 +570,13 @@ async fn run<DB: GenericDatabase + 'static>(
             let _ = ws_conn_manager(
                 rpc_list_ws,
                 ws_handle,
+                incoming_tx_manager,
                 incoming_rx,
                 outgoing_tx,
                 ws_error_tx_ws,
+                sub_data_ws,
+                ws_reconnect,
+                ws_keepalive,
             )
             .await;
         });
@@ -277,19 +585,9 @@ async fn run<DB: GenericDatabase + 'static>(
             let dropped_rpc = Arc::clone(&rpc_list_rwlock);
             let dropped_povrty = Arc::clone(&rpc_poverty_list);
             let dropped_inc = incoming_tx.clone();
-            let dropped_rx = outgoing_rx.resubscribe();
-            let dropped_sub_data = Arc::clone(&sub_data);
 
             tokio::task::spawn(async move {
-                dropped_listener(
-                    dropped_rpc,
-                    dropped_povrty,
-                    ws_error_rx,
-                    dropped_inc,
-                    dropped_rx,
-                    dropped_sub_data,
-                )
-                .await
+                dropped_listener(dropped_rpc, dropped_povrty, ws_error_rx, dropped_inc).await
             });
 
             let heads_inc = incoming_tx.clone();
@@ -298,10 +596,34 @@ async fn run<DB: GenericDatabase + 'static>(
 
             let cache_args = CacheArgs {
                 cache: db_tx.clone(),
+                writer: cache_writer.clone(),
                 finalized_rx: finalized_rx.clone(),
                 named_numbers: named_blocknumbers.clone(),
                 head_cache: head_cache.clone(),
+                format: cache_format,
+                compression: cache_compression,
+                expiry: cache_expiry,
+                policies: cache_policies.clone(),
+                block_time_ms: expected_block_time,
+                log_cache: log_cache.clone(),
+                index: cache_index.clone(),
+                in_flight: in_flight.clone(),
+            };
+
+            // Fetch new heads into the cache before clients ask for them
+            let (cache_warming, ttl) = {
+                let config_guard = config.read().unwrap();
+                (config_guard.cache_warming, config_guard.ttl)
             };
+            if cache_warming.enabled {
+                tokio::task::spawn(warm_cache(
+                    Arc::clone(&rpc_list_rwlock),
+                    warming_blocknum_rx,
+                    cache_args.clone(),
+                    cache_warming,
+                    ttl,
+                ));
+            }
 
             tokio::task::spawn(async move {
                 subscribe_to_new_heads(
@@ -317,20 +639,40 @@ async fn run<DB: GenericDatabase + 'static>(
         }
     }
 
+    // Hold off on becoming ready until enough RPCs agree on the head
+    let (convergence, ttl) = {
+        let config_guard = config.read().unwrap();
+        (config_guard.convergence, config_guard.ttl)
+    };
+    if convergence.enabled {
+        wait_for_convergence(&rpc_list_rwlock, &convergence, ttl).await;
+    }
+
     // Send an update to change the state to ready
     let _ = liveness_tx
         .send(LiveReadyUpdate::Readiness(ReadinessState::Ready))
         .await;
 
-    // We start a loop to continuously accept incoming connections
+    // We start a loop to continuously accept incoming connections, until we're told to shut down
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    // Tells connections to finish what they're serving and close
+    let (draining_tx, draining_rx) = watch::channel(false);
     loop {
-        let (stream, socketaddr) = listener.accept().await?;
+        let (stream, socketaddr) = tokio::select! {
+            accepted = listeners.recv() => match accepted {
+                Some(accepted) => accepted?,
+                None => break,
+            },
+            _ = &mut shutdown => break,
+        };
+        if socketaddr.is_some_and(|socketaddr| blocklist.blocks_client(socketaddr.ip())) {
+            tracing::debug!(?socketaddr, "Dropping connection from blocklisted client");
+            metrics::counter!("blocklist_rejected_total", "kind" => "client").increment(1);
+            continue;
+        }
         tracing::info!(?socketaddr, "Connection from");
 
-        // Use an adapter to access something implementing `tokio::io` traits as if they implement
-        // `hyper::rt` IO traits.
-        let io = TokioIo::new(stream);
-
         let channels = RequestChannels::new(
             finalized_rx_arc.clone(),
             incoming_tx.clone(),
@@ -341,15 +683,165 @@ async fn run<DB: GenericDatabase + 'static>(
             finalized_rx: channels.finalized_rx.as_ref().clone(),
             named_numbers: named_blocknumbers.clone(),
             cache: db_tx.clone(),
+            writer: cache_writer.clone(),
             head_cache: head_cache.clone(),
+            format: cache_format,
+            compression: cache_compression,
+            expiry: cache_expiry,
+            policies: cache_policies.clone(),
+            block_time_ms: expected_block_time,
+            log_cache: log_cache.clone(),
+            index: cache_index.clone(),
+            in_flight: in_flight.clone(),
         };
 
-        let connection_params =
-            ConnectionParams::new(&rpc_list_rwlock, channels, &sub_data, &config);
+        let mut connection_params = ConnectionParams::new(
+            &rpc_list_rwlock,
+            channels,
+            &sub_data,
+            &config,
+            &heatmap,
+            &ws_connections,
+        )
+        .with_counters(&request_counters)
+        .with_consensus(&consensus)
+        .with_shadow_list(&shadow_list);
+        if let Some(socketaddr) = socketaddr {
+            connection_params = connection_params.with_peer(socketaddr);
+        }
+
+        // Trusted proxies send the address of the client first
+        let (rate_limit, connection_limits) = {
+            let config_guard = config.read().unwrap();
+            (
+                config_guard.rate_limit.clone(),
+                config_guard.connection_limits,
+            )
+        };
+        let proxy_protocol = rate_limit.proxy_protocol
+            && socketaddr.is_some_and(|socketaddr| rate_limit.is_trusted_proxy(socketaddr.ip()));
 
         // Spawn a tokio task to serve multiple connections concurrently
+        let tls_acceptor = tls_acceptor.clone();
+        let blocklist = Arc::clone(&blocklist);
+        let connections = Arc::clone(&connections);
+        let draining_rx = draining_rx.clone();
         tokio::task::spawn(async move {
-            accept!(io, connection_params.clone(), cache_args.clone());
+            let mut stream = stream;
+            let mut peer = socketaddr;
+            if proxy_protocol {
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_proxy_header(&mut stream)).await
+                {
+                    Ok(Ok(Some(client))) => {
+                        if blocklist.blocks_client(client.ip()) {
+                            tracing::debug!(?client, "Dropping connection from blocklisted client");
+                            metrics::counter!("blocklist_rejected_total", "kind" => "client")
+                                .increment(1);
+                            return;
+                        }
+                        connection_params = connection_params.with_peer(client);
+                        peer = Some(client);
+                    }
+                    Ok(Ok(None)) => {}
+                    Ok(Err(err)) => {
+                        tracing::debug!(%err, ?socketaddr, "Dropping connection from proxy");
+                        return;
+                    }
+                    Err(_) => {
+                        tracing::debug!(?socketaddr, "PROXY protocol header timed out");
+                        return;
+                    }
+                }
+            }
+
+            // Trusted proxies carry connections from many clients, so only
+            // the global cap applies to them
+            let client_ip = peer
+                .map(|peer| peer.ip())
+                .filter(|ip| !rate_limit.is_trusted_proxy(*ip));
+            match connections.acquire(&connection_limits, client_ip) {
+                Ok(permit) => connection_params = connection_params.with_permit(permit),
+                Err(err) => {
+                    tracing::debug!(%err, ?peer, "Dropping connection over connection limits");
+                    return;
+                }
+            }
+
+            // Use an adapter to access something implementing `tokio::io` traits as if they implement
+            // `hyper::rt` IO traits.
+            match tls_acceptor {
+                Some(tls_acceptor) => {
+                    match tokio::time::timeout(HANDSHAKE_TIMEOUT, tls_acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            let io = TokioIo::new(stream);
+                            accept!(
+                                io,
+                                connection_params.clone(),
+                                cache_args.clone(),
+                                draining_rx
+                            );
+                        }
+                        Ok(Err(err)) => {
+                            tracing::debug!(?err, ?socketaddr, "TLS handshake failed");
+                            metrics::counter!("tls_handshake_failures_total").increment(1);
+                        }
+                        Err(_) => {
+                            tracing::debug!(?socketaddr, "TLS handshake timed out");
+                            metrics::counter!("tls_handshake_failures_total").increment(1);
+                        }
+                    }
+                }
+                None => {
+                    let io = TokioIo::new(stream);
+                    accept!(
+                        io,
+                        connection_params.clone(),
+                        cache_args.clone(),
+                        draining_rx
+                    );
+                }
+            }
         });
     }
+
+    tracing::info!("Shutting down");
+    drop(listeners);
+    let _ = draining_tx.send(true);
+
+    // Let in-flight requests finish while WebSocket clients are drained
+    let (drain_period, drain_timeout) = {
+        let config_guard = config.read().unwrap();
+        (
+            Duration::from_millis(config_guard.ws_connection.drain_period_ms),
+            Duration::from_millis(config_guard.shutdown.drain_timeout_ms),
+        )
+    };
+    let (_, drained) = tokio::join!(
+        drain_websockets(
+            &ws_connections,
+            &sub_data,
+            &incoming_tx,
+            &ws_handles,
+            drain_period
+        ),
+        tokio::time::timeout(drain_timeout, connections.closed()),
+    );
+    if drained.is_err() {
+        tracing::warn!(
+            open = connections.len(),
+            "Connections didn't finish in time, cutting them off"
+        );
+    }
+
+    // Keep what we learned about RPCs for the next start
+    save_upstream_stats(&rpc_list_rwlock, &rpc_poverty_list, &db_tx).await;
+    save_quota_usage(&rpc_list_rwlock, &rpc_poverty_list, &db_tx).await;
+
+    // Don't leave cache writes behind, or half written
+    cache_writer.flush().await;
+    let _ = db_flush!(db_tx).await;
+    tracing::info!("Flushed cache writes and RPC statistics");
+
+    Ok(())
 }
@@ -0,0 +1,249 @@
+//! Proxying the authenticated Engine API.
+//!
+//! Execution clients only serve `engine_*` methods to callers with a JWT signed
+//! with a secret shared with them, usually the `jwt.hex` file passed to the
+//! consensus client. RPCs with a `jwt_secret` form the engine group:
+//!
+//! - `engine_*` methods only go to them, and they only get `engine_*` methods,
+//!   so a consensus client can be pointed at Blutgang instead of a single
+//!   execution client,
+//! - all of them go to the primary, the first healthy RPC of the group in
+//!   config order, so a single execution client sees every payload and
+//!   forkchoice update. The next one only takes over if the primary is down,
+//! - every HTTP request to them carries a freshly minted HS256 token, as the
+//!   spec requires its `iat` claim to be within 60 seconds of the request,
+//! - their responses are never cached or shared between requests.
+//!
+//! Clients have to authenticate `engine_*` calls the same way, with a token
+//! signed with the secret of one of the RPCs in the group. Calls without one
+//! are turned away like blocked methods. Over WebSockets, the token has to
+//! come with the upgrade request.
+//!
+//! The Engine API is served over HTTP, so `ws_transport` is ignored for them.
+
+use crate::Rpc;
+
+use std::{
+    fmt,
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+
+use hyper::header::{
+    HeaderMap,
+    AUTHORIZATION,
+};
+use jsonwebtoken::{
+    decode,
+    encode,
+    Algorithm,
+    DecodingKey,
+    EncodingKey,
+    Header,
+    Validation,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Prefix of the methods that make up the Engine API.
+pub const ENGINE_PREFIX: &str = "engine_";
+/// How far in seconds the `iat` claim of a token can be from now.
+const MAX_IAT_DRIFT_SECS: u64 = 60;
+
+/// Returns true if `method` is part of the Engine API.
+pub fn is_engine_method(method: Option<&str>) -> bool {
+    method.is_some_and(|method| method.starts_with(ENGINE_PREFIX))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EngineSecretError {
+    #[error("JWT secret is not hex")]
+    NotHex,
+    #[error("JWT secret has to be 32 bytes, got {0}")]
+    InvalidLength(usize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    iat: u64,
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// JWT secret shared with an execution client.
+#[derive(Clone)]
+pub struct EngineSecret {
+    key: EncodingKey,
+    decoding_key: DecodingKey,
+}
+
+impl EngineSecret {
+    /// Parse the 32 byte hex secret of a `jwt.hex` file, with or without `0x`.
+    pub fn from_hex(secret: &str) -> Result<Self, EngineSecretError> {
+        let secret = secret.trim();
+        let secret = secret.strip_prefix("0x").unwrap_or(secret);
+        if secret.len() % 2 != 0 || !secret.is_ascii() {
+            return Err(EngineSecretError::NotHex);
+        }
+
+        let bytes = (0..secret.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&secret[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| EngineSecretError::NotHex)?;
+        if bytes.len() != 32 {
+            return Err(EngineSecretError::InvalidLength(bytes.len()));
+        }
+
+        Ok(Self {
+            key: EncodingKey::from_secret(&bytes),
+            decoding_key: DecodingKey::from_secret(&bytes),
+        })
+    }
+
+    /// Mint a token issued now.
+    pub fn token(&self) -> Result<String, jsonwebtoken::errors::Error> {
+        encode(
+            &Header::new(Algorithm::HS256),
+            &Claims { iat: now_secs() },
+            &self.key,
+        )
+    }
+
+    /// Returns true if `token` is signed with this secret and was issued
+    /// within 60 seconds of now.
+    pub fn verify(&self, token: &str) -> bool {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+
+        decode::<Claims>(token, &self.decoding_key, &validation)
+            .is_ok_and(|token| token.claims.iat.abs_diff(now_secs()) <= MAX_IAT_DRIFT_SECS)
+    }
+}
+
+/// Returns true if `headers` carry an engine token signed with the secret of
+/// any RPC in the engine group of `rpc_list`.
+pub fn engine_authorized(headers: &HeaderMap, rpc_list: &[Rpc]) -> bool {
+    let Some(token) = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    else {
+        return false;
+    };
+
+    rpc_list
+        .iter()
+        .filter_map(|rpc| rpc.engine.as_ref())
+        .any(|secret| secret.verify(token.trim()))
+}
+
+impl fmt::Debug for EngineSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EngineSecret(HIDDEN)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0x7365637265747365637265747365637265747365637265747365637265747365";
+
+    #[test]
+    fn test_is_engine_method() {
+        assert!(is_engine_method(Some("engine_newPayloadV3")));
+        assert!(!is_engine_method(Some("eth_blockNumber")));
+        assert!(!is_engine_method(None));
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert!(EngineSecret::from_hex(SECRET).is_ok());
+        assert!(EngineSecret::from_hex(&format!("{}\n", &SECRET[2..])).is_ok());
+        assert!(matches!(
+            EngineSecret::from_hex("0xzz"),
+            Err(EngineSecretError::NotHex)
+        ));
+        assert!(matches!(
+            EngineSecret::from_hex("0xabcd"),
+            Err(EngineSecretError::InvalidLength(2))
+        ));
+        assert_eq!(
+            format!("{:?}", EngineSecret::from_hex(SECRET).unwrap()),
+            "EngineSecret(HIDDEN)"
+        );
+    }
+
+    #[test]
+    fn test_token() {
+        #[derive(Deserialize)]
+        struct Decoded {
+            iat: u64,
+        }
+
+        let token = EngineSecret::from_hex(SECRET).unwrap().token().unwrap();
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.required_spec_claims.clear();
+        validation.validate_exp = false;
+        let decoded = decode::<Decoded>(
+            &token,
+            &DecodingKey::from_secret(b"secretsecretsecretsecretsecretse"),
+            &validation,
+        )
+        .unwrap();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now.abs_diff(decoded.claims.iat) < 5);
+    }
+
+    #[test]
+    fn test_verify() {
+        let secret = EngineSecret::from_hex(SECRET).unwrap();
+        let other = EngineSecret::from_hex(&"ab".repeat(32)).unwrap();
+        assert!(secret.verify(&secret.token().unwrap()));
+        assert!(!secret.verify(&other.token().unwrap()));
+        assert!(!secret.verify("not a token"));
+
+        // Tokens issued too long ago
+        let stale = encode(
+            &Header::new(Algorithm::HS256),
+            &Claims {
+                iat: now_secs() - 120,
+            },
+            &secret.key,
+        )
+        .unwrap();
+        assert!(!secret.verify(&stale));
+    }
+
+    #[test]
+    fn test_engine_authorized() {
+        let secret = EngineSecret::from_hex(SECRET).unwrap();
+        let mut engine_rpc = Rpc::default();
+        engine_rpc.engine = Some(secret.clone());
+        let rpc_list = vec![Rpc::default(), engine_rpc];
+
+        let mut headers = HeaderMap::new();
+        assert!(!engine_authorized(&headers, &rpc_list));
+
+        let bearer = format!("Bearer {}", secret.token().unwrap());
+        headers.insert(AUTHORIZATION, bearer.parse().unwrap());
+        assert!(engine_authorized(&headers, &rpc_list));
+        assert!(!engine_authorized(&headers, &rpc_list[..1]));
+    }
+}
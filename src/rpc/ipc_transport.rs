@@ -0,0 +1,468 @@
+//! JSON-RPC calls over a node's IPC socket.
+//!
+//! RPCs with an `ipc://` url, like `ipc:///var/lib/geth/geth.ipc`, are sent
+//! every call over a persistent connection to that Unix socket instead of
+//! HTTP, which saves co-located nodes the HTTP and TCP overhead. Like with
+//! `ws_transport`, calls are pipelined with ids of our own, so responses can
+//! come back in any order.
+//!
+//! The socket carries a stream of JSON values without any framing, so
+//! responses can span several reads. Reads are scanned once for where values
+//! end, and only complete values get parsed, so large responses don't get
+//! parsed over and over as their reads come in.
+//!
+//! The connection is opened on the first call, and again on the next one
+//! after it drops. There's nothing to fall back to, so calls fail while the
+//! socket is unreachable, which the health checks pick up on as usual.
+//! Batches are split into their calls, which get pipelined.
+
+use crate::rpc::error::RpcError;
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use serde_json::Value;
+use tokio::sync::{
+    mpsc,
+    oneshot,
+};
+
+/// Scheme of IPC urls.
+pub const IPC_SCHEME: &str = "ipc";
+
+/// Time we wait after failing to connect before trying again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Size of reads from the socket.
+const READ_SIZE: usize = 64 * 1024;
+
+// Calls waiting for their response, by the id we sent them with
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+#[derive(Debug, Default)]
+struct Connection {
+    // Sends calls to the open connection, if any
+    calls: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    failed_at: Option<Instant>,
+}
+
+/// Persistent IPC connection calls to an RPC are sent over.
+#[derive(Debug)]
+pub struct IpcTransport {
+    path: PathBuf,
+    next_id: AtomicU64,
+    pending: Pending,
+    connection: tokio::sync::Mutex<Connection>,
+}
+
+/// Removes a call from the pending ones once it's done waiting.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Splits the stream read from the socket into complete JSON values.
+///
+/// Keeps track of how far it scanned and where it is in the value being
+/// read, so every byte is only scanned once however many reads a value spans.
+#[derive(Debug, Default)]
+struct Framer {
+    buffer: Vec<u8>,
+    // Bytes of `buffer` already scanned
+    scanned: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Framer {
+    /// Add `read` to the stream, returning the values it completed, or `None`
+    /// if the stream is malformed.
+    fn feed(&mut self, read: &[u8]) -> Option<Vec<Value>> {
+        self.buffer.extend_from_slice(read);
+
+        let mut values = Vec::new();
+        // Start of the value being scanned
+        let mut start = 0;
+        while self.scanned < self.buffer.len() {
+            let byte = self.buffer[self.scanned];
+            self.scanned += 1;
+
+            if self.in_string {
+                match byte {
+                    _ if self.escaped => self.escaped = false,
+                    b'\\' => self.escaped = true,
+                    b'"' => self.in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+
+            match byte {
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    if self.depth == 0 {
+                        values
+                            .push(serde_json::from_slice(&self.buffer[start..self.scanned]).ok()?);
+                        start = self.scanned;
+                    }
+                }
+                // Responses are objects or arrays, with nothing but whitespace between them
+                _ if self.depth == 0 && !byte.is_ascii_whitespace() => return None,
+                b'"' => self.in_string = true,
+                _ => {}
+            }
+        }
+
+        self.buffer.drain(..start);
+        self.scanned -= start;
+        Some(values)
+    }
+}
+
+/// Pass every response `read` completes to the call waiting for it. Returns
+/// false if the stream is malformed.
+fn respond(pending: &Pending, framer: &mut Framer, read: &[u8]) -> bool {
+    let Some(responses) = framer.feed(read) else {
+        return false;
+    };
+
+    for response in responses {
+        let Some(id) = response["id"].as_u64() else {
+            continue;
+        };
+        let waiting = pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&id);
+        if let Some(waiting) = waiting {
+            let _ = waiting.send(response);
+        }
+    }
+
+    true
+}
+
+impl IpcTransport {
+    /// Returns the transport for `url` if it's an IPC url.
+    pub fn from_url(url: &url::Url) -> Option<Self> {
+        (url.scheme() == IPC_SCHEME).then(|| {
+            Self {
+                path: PathBuf::from(url.path()),
+                next_id: AtomicU64::new(0),
+                pending: Arc::new(Mutex::new(HashMap::new())),
+                connection: tokio::sync::Mutex::new(Connection::default()),
+            }
+        })
+    }
+
+    /// Send `call` and wait for its response, which gets the id of `call`.
+    ///
+    /// Batches get the responses of their calls, and notifications an empty
+    /// response right away.
+    pub async fn request(&self, call: Value) -> Result<String, RpcError> {
+        match call {
+            Value::Array(calls) => {
+                let responses = futures::future::join_all(
+                    calls.into_iter().map(|call| self.request_value(call)),
+                )
+                .await
+                .into_iter()
+                .filter_map(Result::transpose)
+                .collect::<Result<Vec<Value>, RpcError>>()?;
+
+                Ok(Value::Array(responses).to_string())
+            }
+            call => {
+                Ok(self
+                    .request_value(call)
+                    .await?
+                    .map(|response| response.to_string())
+                    .unwrap_or_default())
+            }
+        }
+    }
+
+    /// Send a single call, returning its response if it's not a notification.
+    async fn request_value(&self, mut call: Value) -> Result<Option<Value>, RpcError> {
+        let calls = self.connect().await?;
+
+        if call.get("id").is_none() {
+            calls
+                .send(call.to_string().into_bytes())
+                .map_err(|_| RpcError::SendError("IPC connection closed".to_string()))?;
+            return Ok(None);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, tx);
+        // Stop waiting if we're dropped, like when the call times out
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+
+        let original_id = std::mem::replace(&mut call["id"], id.into());
+        if calls.send(call.to_string().into_bytes()).is_err() {
+            return Err(RpcError::SendError("IPC connection closed".to_string()));
+        }
+
+        let mut response = rx.await.map_err(|_| {
+            RpcError::InvalidResponse("IPC connection closed before responding".to_string())
+        })?;
+        response["id"] = original_id;
+
+        Ok(Some(response))
+    }
+
+    /// Returns the sender of the open connection, opening one if there's none.
+    async fn connect(&self) -> Result<mpsc::UnboundedSender<Vec<u8>>, RpcError> {
+        let mut connection = self.connection.lock().await;
+        if let Some(calls) = connection.calls.as_ref().filter(|calls| !calls.is_closed()) {
+            return Ok(calls.clone());
+        }
+        if connection
+            .failed_at
+            .is_some_and(|failed_at| failed_at.elapsed() < RECONNECT_DELAY)
+        {
+            return Err(RpcError::SendError("IPC socket is unreachable".to_string()));
+        }
+
+        match self.open().await {
+            Ok(calls) => {
+                *connection = Connection {
+                    calls: Some(calls.clone()),
+                    failed_at: None,
+                };
+                Ok(calls)
+            }
+            Err(err) => {
+                connection.failed_at = Some(Instant::now());
+                Err(err)
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    async fn open(&self) -> Result<mpsc::UnboundedSender<Vec<u8>>, RpcError> {
+        use tokio::io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        };
+
+        let stream = tokio::net::UnixStream::connect(&self.path)
+            .await
+            .map_err(|err| {
+                RpcError::SendError(format!("Failed to connect to IPC socket: {err}"))
+            })?;
+        let (mut reader, mut writer) = stream.into_split();
+        let (calls, mut calls_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+        let pending = Arc::clone(&self.pending);
+        tokio::spawn(async move {
+            let mut framer = Framer::default();
+            let mut read = vec![0; READ_SIZE];
+            loop {
+                tokio::select! {
+                    call = calls_rx.recv() => {
+                        let Some(call) = call else {
+                            break;
+                        };
+                        if writer.write_all(&call).await.is_err() {
+                            break;
+                        }
+                    }
+                    bytes = reader.read(&mut read) => {
+                        let bytes = match bytes {
+                            Ok(0) | Err(_) => break,
+                            Ok(bytes) => bytes,
+                        };
+                        if !respond(&pending, &mut framer, &read[..bytes]) {
+                            tracing::warn!("Received malformed response over IPC");
+                            break;
+                        }
+                    }
+                }
+            }
+
+            // Fail everything still in flight
+            calls_rx.close();
+            pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        });
+
+        Ok(calls)
+    }
+
+    #[cfg(not(unix))]
+    async fn open(&self) -> Result<mpsc::UnboundedSender<Vec<u8>>, RpcError> {
+        Err(RpcError::SendError(
+            "IPC is only supported on unix".to_string(),
+        ))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::{
+        io::{
+            AsyncReadExt,
+            AsyncWriteExt,
+        },
+        net::UnixListener,
+    };
+
+    // Answers every two calls in reverse order, with the method as result,
+    // writing both responses in pieces
+    async fn serve(listener: UnixListener) {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let mut buffer = Vec::new();
+            let mut read = vec![0; 1024];
+            let mut held: Vec<Value> = Vec::new();
+            while let Ok(bytes) = stream.read(&mut read).await {
+                if bytes == 0 {
+                    break;
+                }
+                buffer.extend_from_slice(&read[..bytes]);
+                let mut calls = serde_json::Deserializer::from_slice(&buffer).into_iter::<Value>();
+                for call in calls.by_ref() {
+                    let Ok(call) = call else {
+                        break;
+                    };
+                    held.push(
+                        json!({"jsonrpc": "2.0", "id": call["id"], "result": call["method"]}),
+                    );
+                }
+                let parsed = calls.byte_offset();
+                buffer.drain(..parsed);
+
+                if held.len() >= 2 {
+                    let out: String = held.drain(..).rev().map(|r| r.to_string()).collect();
+                    let (first, second) = out.as_bytes().split_at(out.len() / 2);
+                    stream.write_all(first).await.unwrap();
+                    stream.flush().await.unwrap();
+                    stream.write_all(second).await.unwrap();
+                }
+            }
+        }
+    }
+
+    fn socket_path() -> PathBuf {
+        std::env::temp_dir().join(format!("blutgang-ipc-{}.ipc", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let path = socket_path();
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(serve(listener));
+
+        let url: url::Url = format!("ipc://{}", path.display()).parse().unwrap();
+        let transport = IpcTransport::from_url(&url).unwrap();
+        let first = json!({"jsonrpc": "2.0", "id": "a", "method": "eth_blockNumber", "params": []});
+        let second = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId", "params": []});
+        let (first, second) = tokio::join!(transport.request(first), transport.request(second));
+
+        let first: Value = serde_json::from_str(&first.unwrap()).unwrap();
+        let second: Value = serde_json::from_str(&second.unwrap()).unwrap();
+        assert_eq!(first["id"], "a");
+        assert_eq!(first["result"], "eth_blockNumber");
+        assert_eq!(second["id"], 7);
+        assert_eq!(second["result"], "eth_chainId");
+        assert!(transport.pending.lock().unwrap().is_empty());
+
+        // Batches get split into pipelined calls
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "method": "eth_gasPrice", "params": []},
+            {"jsonrpc": "2.0", "id": 2, "method": "net_version", "params": []},
+        ]);
+        let batch: Value = serde_json::from_str(&transport.request(batch).await.unwrap()).unwrap();
+        assert_eq!(batch[0]["id"], 1);
+        assert_eq!(batch[0]["result"], "eth_gasPrice");
+        assert_eq!(batch[1]["result"], "net_version");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let url: url::Url = "ipc:///surely/missing/geth.ipc".parse().unwrap();
+        let transport = IpcTransport::from_url(&url).unwrap();
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        assert!(transport.request(call.clone()).await.is_err());
+        // Not retried right away
+        assert!(transport.connection.lock().await.failed_at.is_some());
+        assert!(transport.request(call).await.is_err());
+
+        assert!(IpcTransport::from_url(&"http://localhost:8545".parse().unwrap()).is_none());
+    }
+
+    #[test]
+    fn test_respond() {
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let (tx, mut rx) = oneshot::channel();
+        pending.lock().unwrap().insert(3, tx);
+
+        let mut framer = Framer::default();
+        assert!(respond(
+            &pending,
+            &mut framer,
+            br#"{"id":3,"result":"0x1"}{"id":4,"res"#
+        ));
+        assert_eq!(rx.try_recv().unwrap()["result"], "0x1");
+        assert_eq!(framer.buffer, br#"{"id":4,"res"#);
+
+        assert!(!respond(&pending, &mut Framer::default(), b"}{"));
+    }
+
+    #[test]
+    fn test_framer() {
+        let mut framer = Framer::default();
+        // Brackets and quotes in strings don't count
+        let response = br#"{"id":1,"result":["}\"{", "\\"]} "#;
+        for byte in response.chunks(1) {
+            let values = framer.feed(byte).unwrap();
+            if !values.is_empty() {
+                assert_eq!(values, vec![json!({"id": 1, "result": ["}\"{", "\\"]})]);
+            }
+        }
+        assert!(framer.buffer.iter().all(u8::is_ascii_whitespace));
+
+        let values = framer.feed(br#"[{"id":2}] {"id":3}"#).unwrap();
+        assert_eq!(values, vec![json!([{"id": 2}]), json!({"id": 3})]);
+        assert!(framer.buffer.is_empty());
+
+        assert!(Framer::default().feed(b"null").is_none());
+        assert!(Framer::default().feed(br#"{"id":1,}"#).is_none());
+    }
+}
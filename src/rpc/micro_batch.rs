@@ -0,0 +1,346 @@
+//! Merging concurrent calls to an RPC into JSON-RPC batches.
+//!
+//! Rate limited providers often count HTTP requests rather than calls. With
+//! `batch_window_ms` set for an RPC, the first call to it opens a batch, and
+//! calls arriving in the next `batch_window_ms` join it. The batch is sent as
+//! one request when the window closes, or as soon as it holds `batch_max_size`
+//! calls. Calls get ids of our own in the batch, and their original ones back
+//! in their responses.
+//!
+//! A batch of one is sent as a plain call. Calls that are already batches, or
+//! notifications, are sent as they are. If the RPC answers the batch with
+//! something other than a batch, like when it doesn't support them, the calls
+//! are sent on their own instead. Calls that might have reached the RPC are
+//! never sent again: if the batch fails to send or times out, every call in
+//! it fails, and calls missing from the response get an error.
+
+use crate::{
+    balancer::batch::error_response,
+    rpc::error::RpcError,
+};
+
+use std::{
+    future::Future,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+use tokio::{
+    sync::{
+        oneshot,
+        Notify,
+    },
+    time::sleep,
+};
+
+/// Max calls in a batch if `batch_max_size` isn't set.
+pub const DEFAULT_MAX_SIZE: usize = 20;
+
+/// Call waiting for its response in a batch, or why the batch failed.
+#[derive(Debug)]
+struct Pending {
+    call: Value,
+    respond: oneshot::Sender<Result<Value, String>>,
+}
+
+/// Batch calls can still join.
+#[derive(Debug)]
+struct Open {
+    calls: Vec<Pending>,
+    full: Arc<Notify>,
+}
+
+/// Merges concurrent calls into batches, see the module docs.
+#[derive(Debug)]
+pub struct MicroBatcher {
+    pub window: Duration,
+    pub max_size: usize,
+    open: Mutex<Option<Open>>,
+}
+
+/// Closes the open batch if the call that opened it stops waiting before
+/// sending it, so the other calls in it don't wait forever.
+struct Leader<'a> {
+    open: &'a Mutex<Option<Open>>,
+    armed: bool,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            // Dropping the senders makes the other calls go on their own
+            self.open.lock().unwrap_or_else(|e| e.into_inner()).take();
+        }
+    }
+}
+
+/// Split `response` to the batch of `calls` by call, giving every response the
+/// original id of its call. Missing responses are `None`.
+fn split_responses(calls: &[Value], response: &str) -> Option<Vec<Option<Value>>> {
+    let Ok(Value::Array(responses)) = serde_json::from_str::<Value>(response) else {
+        return None;
+    };
+
+    let mut split: Vec<Option<Value>> = vec![None; calls.len()];
+    for mut response in responses {
+        let Some(index) = response["id"].as_u64().map(|id| id as usize) else {
+            continue;
+        };
+        if let Some(call) = calls.get(index) {
+            response["id"] = call["id"].clone();
+            split[index] = Some(response);
+        }
+    }
+
+    Some(split)
+}
+
+impl MicroBatcher {
+    pub fn new(window: Duration, max_size: usize) -> Self {
+        Self {
+            window,
+            max_size: max_size.max(1),
+            open: Mutex::new(None),
+        }
+    }
+
+    /// Send `call` through `send`, in a batch with other calls if any join it.
+    pub async fn request<F, Fut>(&self, call: Value, send: F) -> Result<String, RpcError>
+    where
+        F: Fn(Value) -> Fut,
+        Fut: Future<Output = Result<String, RpcError>>,
+    {
+        if !call.is_object() || call.get("id").is_none() {
+            return send(call).await;
+        }
+
+        let (respond, response) = oneshot::channel();
+        let pending = Pending {
+            call: call.clone(),
+            respond,
+        };
+
+        let full = {
+            let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+            match open.as_mut() {
+                Some(batch) => {
+                    batch.calls.push(pending);
+                    if batch.calls.len() >= self.max_size {
+                        batch.full.notify_one();
+                    }
+                    None
+                }
+                None => {
+                    let full = Arc::new(Notify::new());
+                    *open = Some(Open {
+                        calls: vec![pending],
+                        full: Arc::clone(&full),
+                    });
+                    Some(full)
+                }
+            }
+        };
+
+        let Some(full) = full else {
+            // Someone else sends the batch
+            return match response.await {
+                Ok(Ok(response)) => Ok(response.to_string()),
+                Ok(Err(err)) => Err(RpcError::SendError(err)),
+                Err(_) => send(call).await,
+            };
+        };
+
+        let mut leader = Leader {
+            open: &self.open,
+            armed: true,
+        };
+        if self.max_size > 1 {
+            tokio::select! {
+                _ = sleep(self.window) => {},
+                _ = full.notified() => {},
+            }
+        }
+        let batch = self.open.lock().unwrap_or_else(|e| e.into_inner()).take();
+        leader.armed = false;
+
+        let Some(batch) = batch.filter(|batch| batch.calls.len() > 1) else {
+            return send(call).await;
+        };
+        self.send_batch(batch.calls, &send).await
+    }
+
+    /// Send `calls` as a batch, and respond to all of them but the first,
+    /// which is ours and gets returned.
+    async fn send_batch<F, Fut>(&self, calls: Vec<Pending>, send: &F) -> Result<String, RpcError>
+    where
+        F: Fn(Value) -> Fut,
+        Fut: Future<Output = Result<String, RpcError>>,
+    {
+        metrics::histogram!("rpc_micro_batch_size").record(calls.len() as f64);
+
+        let originals: Vec<Value> = calls.iter().map(|pending| pending.call.clone()).collect();
+        let batch: Vec<Value> = originals
+            .iter()
+            .enumerate()
+            .map(|(index, call)| {
+                let mut call = call.clone();
+                call["id"] = index.into();
+                call
+            })
+            .collect();
+
+        let response = match send(Value::Array(batch)).await {
+            Ok(response) => response,
+            Err(err) => {
+                // The calls might have reached the RPC, sending them again could apply them twice
+                tracing::debug!(?err, "Batch failed");
+                let message = err.to_string();
+                for pending in calls.into_iter().skip(1) {
+                    let _ = pending.respond.send(Err(message.clone()));
+                }
+                return Err(err);
+            }
+        };
+        let Some(responses) = split_responses(&originals, &response) else {
+            tracing::debug!("RPC didn't answer with a batch, sending its calls on their own");
+            // Dropping the senders makes every call go on its own, including ours
+            drop(calls);
+            return send(originals.into_iter().next().unwrap_or_default()).await;
+        };
+
+        let mut ours = None;
+        for (index, (pending, response)) in calls.into_iter().zip(responses).enumerate() {
+            let response = response.unwrap_or_else(|| {
+                error_response(
+                    pending.call["id"].clone(),
+                    -32000,
+                    "response missing from batch",
+                )
+            });
+            if index == 0 {
+                ours = Some(response);
+            } else {
+                let _ = pending.respond.send(Ok(response));
+            }
+        }
+
+        Ok(ours.unwrap_or_default().to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{
+        AtomicUsize,
+        Ordering,
+    };
+
+    /// Answers every call with its method, counting requests.
+    async fn echo(call: Value, requests: &AtomicUsize) -> Result<String, RpcError> {
+        requests.fetch_add(1, Ordering::Relaxed);
+        let answer =
+            |call: &Value| json!({"jsonrpc": "2.0", "id": call["id"], "result": call["method"]});
+        Ok(match call {
+            Value::Array(calls) => Value::Array(calls.iter().map(answer).collect()),
+            call => answer(&call),
+        }
+        .to_string())
+    }
+
+    #[test]
+    fn test_split_responses() {
+        let calls = vec![json!({"id": "a"}), json!({"id": 7}), json!({"id": null})];
+        let response =
+            r#"[{"id":1,"result":"0x1"},{"id":0,"result":"0x0"},{"id":9,"result":"0x9"}]"#;
+        assert_eq!(
+            split_responses(&calls, response).unwrap(),
+            vec![
+                Some(json!({"id": "a", "result": "0x0"})),
+                Some(json!({"id": 7, "result": "0x1"})),
+                None,
+            ]
+        );
+
+        // Providers without batch support answer with a single error
+        assert!(split_responses(&calls, r#"{"id":null,"error":{}}"#).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_micro_batching() {
+        let batcher = MicroBatcher::new(Duration::from_secs(60), 3);
+        let requests = AtomicUsize::new(0);
+        let send = |call| echo(call, &requests);
+
+        // A full batch goes out without waiting for the window
+        let responses = futures::future::join_all(
+            (0..3).map(|i| batcher.request(json!({"id": format!("call-{i}"), "method": i}), send)),
+        )
+        .await;
+        assert_eq!(requests.load(Ordering::Relaxed), 1);
+        for (i, response) in responses.into_iter().enumerate() {
+            let response: Value = serde_json::from_str(&response.unwrap()).unwrap();
+            assert_eq!(response["id"], format!("call-{i}"));
+            assert_eq!(response["result"], i);
+        }
+
+        // Notifications and batches go out as they are
+        let batcher = MicroBatcher::new(Duration::from_secs(60), 3);
+        batcher
+            .request(json!({"method": "eth_sendRawTransaction"}), send)
+            .await
+            .unwrap();
+        batcher.request(json!([]), send).await.unwrap();
+        assert_eq!(requests.load(Ordering::Relaxed), 3);
+
+        // Failed batches aren't sent again call by call
+        let batcher = MicroBatcher::new(Duration::from_secs(60), 2);
+        let failures = AtomicUsize::new(0);
+        let fail = |_| {
+            failures.fetch_add(1, Ordering::Relaxed);
+            async { Err(RpcError::SendError("timed out".to_string())) }
+        };
+        let responses = futures::future::join_all(
+            (0..2).map(|i| batcher.request(json!({"id": i, "method": i}), fail)),
+        )
+        .await;
+        assert!(responses.iter().all(Result::is_err));
+        assert_eq!(failures.load(Ordering::Relaxed), 1);
+
+        // RPCs without batch support get the calls on their own
+        let batcher = MicroBatcher::new(Duration::from_secs(60), 2);
+        let requests = &requests;
+        let unbatched = |call: Value| {
+            let rejected = call.is_array();
+            async move {
+                if rejected {
+                    return Ok(r#"{"id":null,"error":{"code":-32600}}"#.to_string());
+                }
+                echo(call, requests).await
+            }
+        };
+        let responses = futures::future::join_all(
+            (0..2).map(|i| batcher.request(json!({"id": i, "method": i}), unbatched)),
+        )
+        .await;
+        for (i, response) in responses.into_iter().enumerate() {
+            let response: Value = serde_json::from_str(&response.unwrap()).unwrap();
+            assert_eq!(response["result"], i);
+        }
+        assert_eq!(requests.load(Ordering::Relaxed), 5);
+
+        // A batch of one is a plain call
+        let batcher = MicroBatcher::new(Duration::from_millis(1), 3);
+        let response = batcher.request(json!({"id": 1, "method": 0}), send).await;
+        assert_eq!(
+            response.unwrap(),
+            json!({"jsonrpc": "2.0", "id": 1, "result": 0}).to_string()
+        );
+    }
+}
@@ -1,3 +1,10 @@
+pub mod client;
+pub mod engine;
 pub mod error;
+pub mod ipc_transport;
 pub mod method;
+pub mod micro_batch;
+pub mod quota;
+pub mod stats;
 pub mod types;
+pub mod ws_transport;
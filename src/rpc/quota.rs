@@ -0,0 +1,487 @@
+//! Monthly quotas of paid providers.
+//!
+//! RPCs with a `monthly_quota` count the compute units they serve (see
+//! `compute_units`) over billing cycles starting on their `billing_day`. Once
+//! the quota is used up, the RPC is either only used when no other RPC can
+//! serve a request, like emergency RPCs, or not used at all until the next
+//! cycle, depending on `quota_exhausted`.
+//!
+//! Usage is kept in the cache DB, saved every `PERSIST_INTERVAL` and on
+//! shutdown, and loaded on startup, so restarts don't reset it. Entries are keyed by a hash of the url
+//! of the RPC, so they follow it around even if it gets renamed. They're
+//! state keys (see `cache_setup::state_key`), which are never evicted or
+//! flushed along with cached responses.
+//!
+//! Whether an RPC used up its quota is checked on every pick, so it's read
+//! from atomics updated as units are charged, rather than working out the
+//! current cycle every time.
+
+use crate::{
+    database::{
+        accept::db_insert,
+        expiry::now_ms,
+        types::{
+            GenericBytes,
+            RequestBus,
+        },
+    },
+    db_get,
+    rpc::stats::distinct_rpcs,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use chrono::{
+    DateTime,
+    Datelike,
+    NaiveDate,
+    Utc,
+};
+use rust_tracing::deps::metrics;
+use serde::Deserialize;
+
+/// How often quota usage, and RPC statistics (see `stats`), get saved to the DB.
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What to do with an RPC once it used up its quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QuotaExhausted {
+    /// Only use it when no other RPC can serve a request
+    #[default]
+    Deprioritize,
+    /// Stop using it until the next billing cycle
+    Stop,
+}
+
+/// Quota of an RPC, read from its `[[rpc]]` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct ProviderQuota {
+    /// Compute units every billing cycle. `0` disables the quota.
+    pub monthly_quota: u64,
+    /// Day of the month billing cycles start on. Past the end of shorter
+    /// months, cycles start on their last day.
+    pub billing_day: u32,
+    pub quota_exhausted: QuotaExhausted,
+}
+
+impl Default for ProviderQuota {
+    fn default() -> Self {
+        Self {
+            monthly_quota: 0,
+            billing_day: 1,
+            quota_exhausted: QuotaExhausted::default(),
+        }
+    }
+}
+
+/// `day` of `month`, or its last day if it's shorter.
+fn billing_date(year: i32, month: u32, day: u32) -> NaiveDate {
+    (1..=day.clamp(1, 31))
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .expect("every month has a first day")
+}
+
+impl ProviderQuota {
+    pub fn enabled(&self) -> bool {
+        self.monthly_quota != 0
+    }
+
+    /// Start of the billing cycle `now_ms` falls in, in ms.
+    pub fn cycle_start(&self, now_ms: u64) -> u64 {
+        let now = DateTime::<Utc>::from_timestamp_millis(now_ms as i64).unwrap_or_default();
+        let (year, month) = (now.year(), now.month());
+        let mut start = billing_date(year, month, self.billing_day);
+        if now.date_naive() < start {
+            let (year, month) = match month {
+                1 => (year - 1, 12),
+                month => (year, month - 1),
+            };
+            start = billing_date(year, month, self.billing_day);
+        }
+
+        start
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight exists")
+            .and_utc()
+            .timestamp_millis() as u64
+    }
+
+    /// Start of the billing cycle after the one starting at `cycle_start`, in ms.
+    pub fn next_cycle_start(&self, cycle_start: u64) -> u64 {
+        let start = DateTime::<Utc>::from_timestamp_millis(cycle_start as i64).unwrap_or_default();
+        let (year, month) = match start.month() {
+            12 => (start.year() + 1, 1),
+            month => (start.year(), month + 1),
+        };
+
+        billing_date(year, month, self.billing_day)
+            .and_hms_opt(0, 0, 0)
+            .expect("midnight exists")
+            .and_utc()
+            .timestamp_millis() as u64
+    }
+}
+
+/// Compute units spent in a billing cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    /// Start of the cycle in ms, see `ProviderQuota::cycle_start`
+    pub cycle_start: u64,
+    pub used: u64,
+}
+
+impl QuotaUsage {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.cycle_start.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.used.to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (cycle_start, used) = bytes.split_at_checked(8)?;
+        Some(Self {
+            cycle_start: u64::from_be_bytes(cycle_start.try_into().ok()?),
+            used: u64::from_be_bytes(used.try_into().ok()?),
+        })
+    }
+}
+
+/// Usage of the quota of an RPC, shared by every copy of it.
+#[derive(Debug, Default)]
+pub struct QuotaCounter {
+    usage: Mutex<QuotaUsage>,
+    // Copy of `usage.used` for `exhausted`
+    used: AtomicU64,
+    // End of the cycle `used` is counted in, in ms
+    cycle_end: AtomicU64,
+}
+
+impl QuotaCounter {
+    /// Add `units` to the cycle starting at `cycle_start`, returning what's
+    /// been used in it.
+    pub fn add(&self, cycle_start: u64, units: u64) -> u64 {
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.cycle_start != cycle_start {
+            *usage = QuotaUsage {
+                cycle_start,
+                used: 0,
+            };
+        }
+        usage.used = usage.used.saturating_add(units);
+        self.used.store(usage.used, Ordering::Relaxed);
+        usage.used
+    }
+
+    /// Set the end of the cycle usage is being counted in, in ms.
+    pub fn set_cycle_end(&self, cycle_end: u64) {
+        self.cycle_end.store(cycle_end, Ordering::Relaxed);
+    }
+
+    /// Returns true if at least `limit` units were used in the cycle `now_ms`
+    /// falls in, as of the last time units were added.
+    pub fn exhausted(&self, limit: u64, now_ms: u64) -> bool {
+        now_ms < self.cycle_end.load(Ordering::Relaxed)
+            && self.used.load(Ordering::Relaxed) >= limit
+    }
+
+    /// Compute units used in the cycle starting at `cycle_start`.
+    pub fn used(&self, cycle_start: u64) -> u64 {
+        let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        match usage.cycle_start == cycle_start {
+            true => usage.used,
+            false => 0,
+        }
+    }
+
+    pub fn usage(&self) -> QuotaUsage {
+        *self.usage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Pick up `stored` usage, on top of what's been used since.
+    fn restore(&self, stored: QuotaUsage) {
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if usage.cycle_start == stored.cycle_start {
+            usage.used = usage.used.saturating_add(stored.used);
+        } else if usage.cycle_start < stored.cycle_start {
+            *usage = stored;
+        }
+        self.used.store(usage.used, Ordering::Relaxed);
+    }
+}
+
+impl Rpc {
+    /// Charge `units` compute units to the quota of the RPC, if it has one.
+    pub fn charge_quota(&self, units: u64) {
+        if !self.quota.enabled() {
+            return;
+        }
+        let cycle_start = self.quota.cycle_start(now_ms());
+        let used = self.stats.quota().add(cycle_start, units);
+        self.stats
+            .quota()
+            .set_cycle_end(self.quota.next_cycle_start(cycle_start));
+        metrics::gauge!("upstream_quota_used", "rpc_name" => self.name.clone()).set(used as f64);
+
+        let limit = self.quota.monthly_quota;
+        if used >= limit && used.saturating_sub(units) < limit {
+            tracing::warn!(
+                rpc = %self.name,
+                limit,
+                action = ?self.quota.quota_exhausted,
+                "RPC used up its monthly quota"
+            );
+            metrics::counter!("upstream_quota_exhausted_total", "rpc_name" => self.name.clone())
+                .increment(1);
+        }
+    }
+
+    /// Returns true if the RPC used up its quota for this billing cycle.
+    pub fn quota_exhausted(&self) -> bool {
+        self.quota.enabled()
+            && self
+                .stats
+                .quota()
+                .exhausted(self.quota.monthly_quota, now_ms())
+    }
+
+    /// Returns true if the RPC is out of rotation for using up its quota.
+    pub fn quota_stopped(&self) -> bool {
+        self.quota.quota_exhausted == QuotaExhausted::Stop && self.quota_exhausted()
+    }
+
+    /// Returns true if the RPC only gets traffic when no primary RPC is
+    /// available, for being an emergency RPC or for using up its quota.
+    pub fn is_fallback(&self) -> bool {
+        self.emergency
+            || (self.quota.quota_exhausted == QuotaExhausted::Deprioritize
+                && self.quota_exhausted())
+    }
+}
+
+/// RPCs with a quota in `lists`, deduplicated as copies share their usage.
+fn quota_rpcs(lists: &[&RwLock<Vec<Rpc>>]) -> Vec<Rpc> {
+    distinct_rpcs(lists)
+        .into_iter()
+        .filter(|rpc| rpc.quota.enabled())
+        .collect()
+}
+
+/// Load the quota usage of the RPCs in `rpc_list` and `poverty_list` from `cache`.
+pub async fn load_quota_usage<K, V>(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    cache: &RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    for rpc in quota_rpcs(&[rpc_list, poverty_list]) {
+        let stored = match db_get!(cache, rpc.quota_key().into()) {
+            Ok(stored) => stored,
+            Err(_) => {
+                tracing::warn!(rpc = %rpc.name, "Failed to load quota usage");
+                continue;
+            }
+        };
+        if let Some(usage) = stored.as_deref().and_then(QuotaUsage::from_bytes) {
+            tracing::info!(rpc = %rpc.name, used = usage.used, "Loaded quota usage");
+            rpc.stats.quota().restore(usage);
+            // Picks up whether it's used up, or resets it if the cycle is over
+            rpc.charge_quota(0);
+        }
+    }
+}
+
+/// Save the quota usage of the RPCs in `rpc_list` and `poverty_list` to
+/// `cache` every `PERSIST_INTERVAL`.
+pub async fn persist_quota_usage<K, V>(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    cache: RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        save_quota_usage(&rpc_list, &poverty_list, &cache).await;
+    }
+}
+
+/// Save the quota usage of the RPCs in `rpc_list` and `poverty_list` to `cache`.
+pub async fn save_quota_usage<K, V>(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    cache: &RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    for rpc in quota_rpcs(&[rpc_list, poverty_list]) {
+        let usage = rpc.stats.quota().usage();
+        drop(db_insert(cache, rpc.quota_key().into(), usage.to_bytes().into()).await);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str) -> u64 {
+        format!("{}T12:00:00Z", date)
+            .parse::<DateTime<Utc>>()
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+
+    fn midnight(date: &str) -> u64 {
+        format!("{}T00:00:00Z", date)
+            .parse::<DateTime<Utc>>()
+            .unwrap()
+            .timestamp_millis() as u64
+    }
+
+    #[test]
+    fn test_cycle_start() {
+        let quota = |billing_day| {
+            ProviderQuota {
+                monthly_quota: 100,
+                billing_day,
+                ..Default::default()
+            }
+        };
+
+        assert_eq!(
+            quota(1).cycle_start(at("2026-03-15")),
+            midnight("2026-03-01")
+        );
+        assert_eq!(
+            quota(15).cycle_start(at("2026-03-15")),
+            midnight("2026-03-15")
+        );
+        assert_eq!(
+            quota(20).cycle_start(at("2026-03-15")),
+            midnight("2026-02-20")
+        );
+        assert_eq!(
+            quota(20).cycle_start(at("2026-01-15")),
+            midnight("2025-12-20")
+        );
+        // Shorter months start on their last day
+        assert_eq!(
+            quota(31).cycle_start(at("2026-03-15")),
+            midnight("2026-02-28")
+        );
+        assert_eq!(
+            quota(31).cycle_start(at("2026-02-28")),
+            midnight("2026-02-28")
+        );
+    }
+
+    #[test]
+    fn test_next_cycle_start() {
+        let quota = |billing_day| {
+            ProviderQuota {
+                monthly_quota: 100,
+                billing_day,
+                ..Default::default()
+            }
+        };
+
+        assert_eq!(
+            quota(15).next_cycle_start(midnight("2026-03-15")),
+            midnight("2026-04-15")
+        );
+        assert_eq!(
+            quota(20).next_cycle_start(midnight("2025-12-20")),
+            midnight("2026-01-20")
+        );
+        assert_eq!(
+            quota(31).next_cycle_start(midnight("2026-01-31")),
+            midnight("2026-02-28")
+        );
+        assert_eq!(
+            quota(31).next_cycle_start(midnight("2026-02-28")),
+            midnight("2026-03-31")
+        );
+    }
+
+    #[test]
+    fn test_quota_counter() {
+        let counter = QuotaCounter::default();
+        assert_eq!(counter.add(1, 10), 10);
+        assert_eq!(counter.add(1, 5), 15);
+        assert_eq!(counter.used(1), 15);
+        assert_eq!(counter.used(2), 0);
+
+        // New cycles start from scratch
+        assert_eq!(counter.add(2, 3), 3);
+        assert_eq!(counter.used(1), 0);
+
+        // Stored usage adds up with usage since startup, unless it's outdated
+        counter.restore(QuotaUsage {
+            cycle_start: 2,
+            used: 40,
+        });
+        assert_eq!(counter.used(2), 43);
+        counter.restore(QuotaUsage {
+            cycle_start: 1,
+            used: 100,
+        });
+        assert_eq!(counter.used(2), 43);
+
+        let usage = counter.usage();
+        assert_eq!(QuotaUsage::from_bytes(&usage.to_bytes()), Some(usage));
+        assert_eq!(QuotaUsage::from_bytes(&[0; 4]), None);
+    }
+
+    #[test]
+    fn test_exhausted() {
+        let mut rpc = Rpc::default();
+        rpc.charge_quota(1000);
+        assert!(!rpc.quota_exhausted());
+
+        rpc.quota = ProviderQuota {
+            monthly_quota: 100,
+            ..Default::default()
+        };
+        rpc.charge_quota(99);
+        assert!(!rpc.quota_exhausted());
+        assert!(!rpc.is_fallback());
+        rpc.charge_quota(1);
+        assert!(rpc.quota_exhausted());
+        assert!(rpc.is_fallback());
+        assert!(!rpc.quota_stopped());
+
+        rpc.quota.quota_exhausted = QuotaExhausted::Stop;
+        assert!(!rpc.is_fallback());
+        assert!(rpc.quota_stopped());
+
+        // Raising the quota applies right away
+        rpc.quota.monthly_quota = 200;
+        assert!(!rpc.quota_exhausted());
+
+        // And it's all used up until the end of the cycle only
+        let counter = QuotaCounter::default();
+        counter.add(1, 100);
+        counter.set_cycle_end(10);
+        assert!(counter.exhausted(100, 5));
+        assert!(!counter.exhausted(100, 10));
+    }
+}
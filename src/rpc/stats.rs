@@ -0,0 +1,641 @@
+//! Statistics of the calls sent to an RPC.
+//!
+//! The status of an `Rpc` only has what selection needs, and gets copied
+//! around with it. These are shared between every copy instead, so calls
+//! sent through any of them count, and are only read to debug selection
+//! through `blutgang_rpc_stats`.
+//!
+//! The HTTP statuses and JSON-RPC error codes RPCs respond with are counted
+//! too, and summed up per RPC by `blutgang_sla_report`. Error codes outside of
+//! the standard ones count as `other`, so providers can't blow up the number of
+//! labels of the `rpc_jsonrpc_errors_total` metric.
+//!
+//! So restarts don't start from a blank slate and send traffic to slow or
+//! broken RPCs while we find out about them again, statistics are saved to the
+//! cache DB every `PERSIST_INTERVAL` and on shutdown, and loaded on startup,
+//! along with the latencies selection goes by. Entries are state keys keyed by
+//! a hash of the url of the RPC, like the usage of quotas (see `quota`), so
+//! they're never evicted or flushed.
+
+use crate::{
+    database::{
+        accept::db_insert,
+        expiry::now_ms,
+        types::{
+            GenericBytes,
+            RequestBus,
+        },
+    },
+    db_get,
+    rpc::quota::{
+        QuotaCounter,
+        PERSIST_INTERVAL,
+    },
+    Rpc,
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use memchr::memmem;
+use rust_tracing::deps::metrics;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+
+/// Upper bounds of the latency histogram buckets in ms, from 1ms to 30s.
+/// Slower calls go in one more bucket after the last.
+pub const LATENCY_BUCKETS_MS: [f64; 14] = [
+    1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0, 10000.0, 30000.0,
+];
+
+/// JSON-RPC error codes that get their own label, see `error_code_label`.
+/// These are the ones from the JSON-RPC spec, EIP-1474, and reverts.
+const KNOWN_ERROR_CODES: [i64; 13] = [
+    3, -32000, -32001, -32002, -32003, -32004, -32005, -32006, -32600, -32601, -32602, -32603,
+    -32700,
+];
+
+/// Label of a JSON-RPC error `code` in metrics and statistics.
+pub fn error_code_label(code: i64) -> String {
+    match KNOWN_ERROR_CODES.contains(&code) {
+        true => code.to_string(),
+        false => "other".to_string(),
+    }
+}
+
+/// Outcomes of calls to a method.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodCounts {
+    pub success: u64,
+    pub error: u64,
+}
+
+/// Last call that failed, or got an error back.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastError {
+    pub message: String,
+    /// Unix time in ms
+    pub at_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Recorded {
+    latency_buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    methods: BTreeMap<String, MethodCounts>,
+    last_error: Option<LastError>,
+    http_statuses: BTreeMap<String, u64>,
+    jsonrpc_errors: BTreeMap<String, u64>,
+}
+
+/// Statistics shared by every copy of an RPC.
+#[derive(Debug, Default)]
+pub struct RpcStats {
+    in_flight: AtomicU64,
+    compute_units: AtomicU64,
+    quota: QuotaCounter,
+    recorded: Mutex<Recorded>,
+}
+
+/// One bucket of the latency histogram. `le_ms` is `None` for the last one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct LatencyBucket {
+    pub le_ms: Option<f64>,
+    pub count: u64,
+}
+
+/// Statistics of an RPC at some point in time.
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub in_flight: u64,
+    pub compute_units: u64,
+    pub latency_histogram: Vec<LatencyBucket>,
+    pub methods: BTreeMap<String, MethodCounts>,
+    pub last_error: Option<LastError>,
+    /// Responses per HTTP status, `error` for requests that got none
+    pub http_statuses: BTreeMap<String, u64>,
+    /// Responses per JSON-RPC error code label, see `error_code_label`
+    pub jsonrpc_errors: BTreeMap<String, u64>,
+}
+
+/// How reliably an RPC has been serving requests, see `blutgang_sla_report`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SlaReport {
+    /// HTTP requests sent
+    pub requests: u64,
+    /// Requests that got no response, or a 5xx one
+    pub failures: u64,
+    /// Share of requests that didn't fail, `None` until one is sent
+    pub availability: Option<f64>,
+    pub http_statuses: BTreeMap<String, u64>,
+    pub jsonrpc_errors: BTreeMap<String, u64>,
+}
+
+/// What we keep of the statistics of an RPC across restarts.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StoredStats {
+    /// Latencies of the last calls selection goes by, see `update_latency`
+    pub latency_data: Vec<f64>,
+    pub latency_buckets: Vec<u64>,
+    pub methods: BTreeMap<String, MethodCounts>,
+    pub last_error: Option<LastError>,
+    pub compute_units: u64,
+    pub http_statuses: BTreeMap<String, u64>,
+    pub jsonrpc_errors: BTreeMap<String, u64>,
+}
+
+/// Counts a call as in flight until dropped.
+pub struct InFlightGuard<'a>(&'a AtomicU64);
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the message of the first JSON-RPC error in `response`, if any.
+fn error_message(response: &str) -> Option<String> {
+    // Skip parsing responses that can't contain an error
+    memmem::find(response.as_bytes(), b"\"error\"")?;
+
+    let message = |response: &Value| {
+        let error = response.get("error")?;
+        Some(
+            error["message"]
+                .as_str()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| error.to_string()),
+        )
+    };
+
+    match serde_json::from_str::<Value>(response).ok()? {
+        Value::Array(responses) => responses.iter().find_map(message),
+        response => message(&response),
+    }
+}
+
+/// Record a call to `method` on the RPC named `rpc_name` in metrics, so
+/// latencies and errors can be broken down by method, and by method and RPC.
+///
+/// Calls that fail count as `transport` errors, and calls the RPC answers with
+/// an error as `jsonrpc` errors.
+pub fn record_method_metrics<E>(
+    rpc_name: &str,
+    method: &str,
+    latency: Duration,
+    result: &Result<String, E>,
+) {
+    metrics::histogram!(
+        "rpc_method_duration_secs",
+        "method" => method.to_string(),
+        "rpc_name" => rpc_name.to_string()
+    )
+    .record(latency.as_secs_f64());
+
+    let kind = match result {
+        Ok(response) if error_message(response).is_some() => "jsonrpc",
+        Ok(_) => return,
+        Err(_) => "transport",
+    };
+    metrics::counter!(
+        "rpc_method_errors_total",
+        "method" => method.to_string(),
+        "rpc_name" => rpc_name.to_string(),
+        "kind" => kind
+    )
+    .increment(1);
+}
+
+impl RpcStats {
+    /// Count a call as in flight while the returned guard lives.
+    pub fn start_call(&self) -> InFlightGuard<'_> {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(&self.in_flight)
+    }
+
+    /// Record a call to `method` that took `latency` and returned `result`.
+    pub fn record<E: ToString>(&self, method: &str, latency: Duration, result: &Result<String, E>) {
+        let error = match result {
+            Ok(response) => error_message(response),
+            Err(err) => Some(err.to_string()),
+        };
+
+        let latency_ms = latency.as_secs_f64() * 1000.0;
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|le| latency_ms <= *le)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        let mut guard = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let recorded = &mut *guard;
+        recorded.latency_buckets[bucket] += 1;
+
+        if !recorded.methods.contains_key(method) {
+            recorded
+                .methods
+                .insert(method.to_string(), MethodCounts::default());
+        }
+        let counts = recorded.methods.get_mut(method).unwrap();
+        match error {
+            Some(message) => {
+                counts.error += 1;
+                recorded.last_error = Some(LastError {
+                    message,
+                    at_ms: now_ms(),
+                });
+            }
+            None => counts.success += 1,
+        }
+    }
+
+    /// Count an HTTP response with `status`, or `error` if there was none.
+    pub fn record_http_status(&self, status: &str) {
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        *recorded
+            .http_statuses
+            .entry(status.to_string())
+            .or_default() += 1;
+    }
+
+    /// Count a JSON-RPC error labeled `label`, see `error_code_label`.
+    pub fn record_jsonrpc_error(&self, label: &str) {
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        *recorded
+            .jsonrpc_errors
+            .entry(label.to_string())
+            .or_default() += 1;
+    }
+
+    /// Count `units` compute units spent on the RPC, see `compute_units`.
+    pub fn add_compute_units(&self, units: u64) {
+        self.compute_units.fetch_add(units, Ordering::Relaxed);
+    }
+
+    /// Usage of the monthly quota of the RPC, see `quota`.
+    pub fn quota(&self) -> &QuotaCounter {
+        &self.quota
+    }
+
+    /// Statistics to store, along with the `latency_data` of the RPC.
+    fn to_stored(&self, latency_data: Vec<f64>) -> StoredStats {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        StoredStats {
+            latency_data,
+            latency_buckets: recorded.latency_buckets.to_vec(),
+            methods: recorded.methods.clone(),
+            last_error: recorded.last_error.clone(),
+            compute_units: self.compute_units.load(Ordering::Relaxed),
+            http_statuses: recorded.http_statuses.clone(),
+            jsonrpc_errors: recorded.jsonrpc_errors.clone(),
+        }
+    }
+
+    /// Pick up `stored` statistics, on top of what's been recorded since startup.
+    fn restore(&self, stored: &StoredStats) {
+        self.compute_units
+            .fetch_add(stored.compute_units, Ordering::Relaxed);
+
+        let mut recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        // Buckets are only comparable if they haven't changed since
+        if stored.latency_buckets.len() == recorded.latency_buckets.len() {
+            for (bucket, count) in recorded
+                .latency_buckets
+                .iter_mut()
+                .zip(&stored.latency_buckets)
+            {
+                *bucket += count;
+            }
+        }
+        for (method, stored) in &stored.methods {
+            let counts = recorded.methods.entry(method.clone()).or_default();
+            counts.success += stored.success;
+            counts.error += stored.error;
+        }
+        if recorded.last_error.is_none() {
+            recorded.last_error = stored.last_error.clone();
+        }
+        for (status, count) in &stored.http_statuses {
+            *recorded.http_statuses.entry(status.clone()).or_default() += count;
+        }
+        for (label, count) in &stored.jsonrpc_errors {
+            *recorded.jsonrpc_errors.entry(label.clone()).or_default() += count;
+        }
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let latency_histogram = recorded
+            .latency_buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                LatencyBucket {
+                    le_ms: LATENCY_BUCKETS_MS.get(i).copied(),
+                    count: *count,
+                }
+            })
+            .collect();
+
+        StatsSnapshot {
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            compute_units: self.compute_units.load(Ordering::Relaxed),
+            latency_histogram,
+            methods: recorded.methods.clone(),
+            last_error: recorded.last_error.clone(),
+            http_statuses: recorded.http_statuses.clone(),
+            jsonrpc_errors: recorded.jsonrpc_errors.clone(),
+        }
+    }
+
+    pub fn sla_report(&self) -> SlaReport {
+        let recorded = self.recorded.lock().unwrap_or_else(|e| e.into_inner());
+        let requests = recorded.http_statuses.values().sum();
+        let failures = recorded
+            .http_statuses
+            .iter()
+            .filter(|(status, _)| *status == "error" || status.starts_with('5'))
+            .map(|(_, count)| count)
+            .sum();
+
+        SlaReport {
+            requests,
+            failures,
+            availability: (requests > 0).then(|| 1.0 - failures as f64 / requests as f64),
+            http_statuses: recorded.http_statuses.clone(),
+            jsonrpc_errors: recorded.jsonrpc_errors.clone(),
+        }
+    }
+}
+
+/// Every RPC in `lists`, deduplicated as copies share their statistics.
+pub(crate) fn distinct_rpcs(lists: &[&RwLock<Vec<Rpc>>]) -> Vec<Rpc> {
+    let mut rpcs: Vec<Rpc> = Vec::new();
+    for list in lists {
+        let list = list.read().unwrap_or_else(|e| e.into_inner());
+        for rpc in list.iter() {
+            if !rpcs
+                .iter()
+                .any(|other| Arc::ptr_eq(&other.stats, &rpc.stats))
+            {
+                rpcs.push(rpc.clone());
+            }
+        }
+    }
+    rpcs
+}
+
+/// Load the statistics of the RPCs in `rpc_list` and `poverty_list` from `cache`.
+pub async fn load_upstream_stats<K, V>(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    cache: &RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes,
+{
+    for rpc in distinct_rpcs(&[rpc_list, poverty_list]) {
+        let stored = match db_get!(cache, rpc.stats_key().into()) {
+            Ok(stored) => stored,
+            Err(_) => {
+                tracing::warn!(rpc = %rpc.name, "Failed to load RPC statistics");
+                continue;
+            }
+        };
+        let Some(stored) =
+            stored.and_then(|stored| serde_json::from_slice::<StoredStats>(&stored).ok())
+        else {
+            continue;
+        };
+
+        rpc.stats.restore(&stored);
+        for list in [rpc_list, poverty_list] {
+            let mut list = list.write().unwrap_or_else(|e| e.into_inner());
+            for copy in list
+                .iter_mut()
+                .filter(|copy| Arc::ptr_eq(&copy.stats, &rpc.stats))
+            {
+                copy.restore_latency(&stored.latency_data);
+            }
+        }
+        tracing::info!(
+            rpc = %rpc.name,
+            samples = stored.latency_data.len(),
+            "Loaded RPC statistics"
+        );
+    }
+}
+
+/// Save the statistics of the RPCs in `rpc_list` and `poverty_list` to
+/// `cache` every `PERSIST_INTERVAL`, so a crash loses little of them.
+pub async fn persist_upstream_stats<K, V>(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    poverty_list: Arc<RwLock<Vec<Rpc>>>,
+    cache: RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+    // Nothing new to save right after loading them
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_upstream_stats(&rpc_list, &poverty_list, &cache).await;
+    }
+}
+
+/// Save the statistics of the RPCs in `rpc_list` and `poverty_list` to `cache`.
+pub async fn save_upstream_stats<K, V>(
+    rpc_list: &RwLock<Vec<Rpc>>,
+    poverty_list: &RwLock<Vec<Rpc>>,
+    cache: &RequestBus<K, V>,
+) where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    for rpc in distinct_rpcs(&[rpc_list, poverty_list]) {
+        let stored = rpc.stats.to_stored(rpc.status.latency_data.clone());
+        let Ok(bytes) = serde_json::to_vec(&stored) else {
+            continue;
+        };
+        drop(db_insert(cache, rpc.stats_key().into(), bytes.into()).await);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        database::{
+            eviction::CacheEviction,
+            hot_cache::HotCache,
+            types::DbRequest,
+        },
+        database_processing,
+    };
+    use sled::{
+        Config,
+        Db,
+    };
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_record() {
+        let stats = RpcStats::default();
+
+        let guard = stats.start_call();
+        assert_eq!(stats.snapshot().in_flight, 1);
+        drop(guard);
+        assert_eq!(stats.snapshot().in_flight, 0);
+
+        stats.add_compute_units(20);
+        stats.add_compute_units(5);
+        assert_eq!(stats.snapshot().compute_units, 25);
+
+        let ok: Result<String, String> = Ok(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#.into());
+        stats.record("eth_blockNumber", Duration::from_millis(3), &ok);
+        let reverted: Result<String, String> = Ok(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":3,"message":"execution reverted"}}"#.into(),
+        );
+        stats.record("eth_call", Duration::from_millis(40), &reverted);
+        let failed: Result<String, String> = Err("connection refused".into());
+        stats.record("eth_call", Duration::from_secs(60), &failed);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.methods["eth_blockNumber"],
+            MethodCounts {
+                success: 1,
+                error: 0
+            }
+        );
+        assert_eq!(
+            snapshot.methods["eth_call"],
+            MethodCounts {
+                success: 0,
+                error: 2
+            }
+        );
+        assert_eq!(
+            snapshot.last_error.map(|error| error.message),
+            Some("connection refused".to_string())
+        );
+
+        let histogram = snapshot.latency_histogram;
+        assert_eq!(histogram.len(), LATENCY_BUCKETS_MS.len() + 1);
+        // 3ms, 40ms and over 30s
+        assert_eq!(histogram[2].count, 1);
+        assert_eq!(histogram[5].count, 1);
+        assert_eq!(histogram.last().unwrap().le_ms, None);
+        assert_eq!(histogram.last().unwrap().count, 1);
+    }
+
+    #[test]
+    fn test_sla_report() {
+        let stats = RpcStats::default();
+        assert_eq!(stats.sla_report().availability, None);
+
+        for status in ["200", "200", "200", "503", "error"] {
+            stats.record_http_status(status);
+        }
+        stats.record_jsonrpc_error(&error_code_label(-32005));
+        stats.record_jsonrpc_error(&error_code_label(-1234));
+        stats.record_jsonrpc_error(&error_code_label(42));
+
+        let report = stats.sla_report();
+        assert_eq!(report.requests, 5);
+        assert_eq!(report.failures, 2);
+        assert_eq!(report.availability, Some(0.6));
+        assert_eq!(report.http_statuses["200"], 3);
+        assert_eq!(
+            report.jsonrpc_errors,
+            BTreeMap::from([("-32005".to_string(), 1), ("other".to_string(), 2)])
+        );
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(error_message(r#"{"result":"0x1"}"#), None);
+        assert_eq!(
+            error_message(r#"[{"result":"0x1"},{"error":{"code":-32000,"message":"limit"}}]"#),
+            Some("limit".to_string())
+        );
+        assert_eq!(
+            error_message(r#"{"error":"rate limited"}"#),
+            Some(r#""rate limited""#.to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_persist_stats() {
+        let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<Vec<u8>, Vec<u8>>>();
+        tokio::task::spawn(database_processing(
+            db_rx,
+            Db::open_with_config(&Config::tmp().unwrap()).unwrap(),
+            HotCache::new(1024),
+            CacheEviction::default(),
+            None,
+        ));
+
+        let url = "https://rpc.example.com".parse().unwrap();
+        let mut rpc = Rpc::new(url, None, 0, 0, 3.0);
+        for latency in [10.0, 20.0, 30.0] {
+            rpc.update_latency(latency);
+        }
+        let ok: Result<String, String> = Ok(r#"{"result":"0x1"}"#.into());
+        rpc.stats
+            .record("eth_blockNumber", Duration::from_millis(3), &ok);
+        let failed: Result<String, String> = Err("connection refused".into());
+        rpc.stats
+            .record("eth_call", Duration::from_millis(40), &failed);
+        rpc.stats.add_compute_units(7);
+        rpc.stats.record_http_status("502");
+        rpc.stats.record_jsonrpc_error("-32005");
+
+        let rpc_list = RwLock::new(vec![rpc.clone()]);
+        let poverty_list = RwLock::new(Vec::new());
+        save_upstream_stats(&rpc_list, &poverty_list, &db_tx).await;
+
+        // After a restart, with a latency measured on startup
+        let mut restarted = Rpc::new(rpc.get_url(), None, 0, 0, 3.0);
+        restarted.update_latency(60.0);
+        let rpc_list = RwLock::new(vec![restarted.clone()]);
+        load_upstream_stats(&rpc_list, &poverty_list, &db_tx).await;
+
+        let restarted = rpc_list.read().unwrap()[0].clone();
+        assert_eq!(restarted.status.latency_data, vec![20.0, 30.0, 60.0]);
+        assert_eq!(restarted.status.latency, 110.0 / 3.0);
+
+        let snapshot = restarted.stats.snapshot();
+        assert_eq!(snapshot.compute_units, 7);
+        assert_eq!(snapshot.methods["eth_blockNumber"].success, 1);
+        assert_eq!(snapshot.methods["eth_call"].error, 1);
+        assert_eq!(
+            snapshot.last_error.map(|error| error.message),
+            Some("connection refused".to_string())
+        );
+        assert_eq!(snapshot.latency_histogram[2].count, 1);
+        assert_eq!(snapshot.latency_histogram[5].count, 1);
+        assert_eq!(snapshot.http_statuses["502"], 1);
+        assert_eq!(snapshot.jsonrpc_errors["-32005"], 1);
+
+        // Nothing stored for other RPCs
+        let other = RwLock::new(vec![Rpc::default()]);
+        load_upstream_stats(&other, &poverty_list, &db_tx).await;
+        assert!(other.read().unwrap()[0].stats.snapshot().methods.is_empty());
+    }
+}
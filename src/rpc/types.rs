@@ -1,15 +1,58 @@
-use crate::rpc::{
-    error::RpcError,
-    method::EthRpcMethod,
+use crate::{
+    balancer::{
+        cache_metrics::method_label,
+        checksum::REQUEST_CHECKSUM_HEADER,
+        content_encoding::record_upstream_body,
+        selection::cache_rules::is_read_method,
+        trace_context::trace_headers,
+    },
+    config::cache_setup::state_key,
+    database::expiry::now_ms,
+    health::quarantine::MethodFamily,
+    rpc::{
+        client::{
+            decompress,
+            plain_client,
+            ClientOptions,
+        },
+        engine::EngineSecret,
+        error::RpcError,
+        ipc_transport::{
+            IpcTransport,
+            IPC_SCHEME,
+        },
+        method::EthRpcMethod,
+        micro_batch::MicroBatcher,
+        quota::ProviderQuota,
+        stats::{
+            error_code_label,
+            record_method_metrics,
+            RpcStats,
+        },
+        ws_transport::WsTransport,
+    },
+};
+use memchr::memmem;
+use reqwest::{
+    header::CONTENT_ENCODING,
+    Client,
 };
-use reqwest::Client;
 use rust_tracing::deps::metrics;
+use tokio::sync::Semaphore;
 use url::Url;
 
 use serde_json::{
     json,
     Value,
 };
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
+    },
+};
 
 // All as floats so we have an easier time getting averages, stats and terminology copied from flood.
 #[derive(Debug, Clone, Default)]
@@ -18,6 +61,32 @@ pub struct Status {
     // Also set the last time it was called, so we can check again later
     pub is_erroring: bool,
     pub last_error: u64,
+    // Set by the health check if the node reports that it's still syncing.
+    // Syncing nodes are not eligible for selection.
+    pub is_syncing: bool,
+    // Set if the last health check timed out. Slow nodes get ejected
+    // like syncing ones, but they're not reported as syncing.
+    pub is_timed_out: bool,
+    // Set if the node reported a finalized block hash that diverges
+    // from the majority. Diverged nodes are kept out of the active pool.
+    pub is_diverged: bool,
+    // Last head reported during a health check, and how many
+    // health checks in a row the node failed to respond to.
+    pub head: u64,
+    pub failed_checks: u32,
+    // Head observed through the node's own `newHeads` subscription, the unix
+    // time in ms it last advanced at, and if it stopped advancing.
+    pub ws_head: u64,
+    pub ws_head_updated_ms: u64,
+    pub is_stale: bool,
+    // How many cross-checks in a row the node returned divergent data for
+    // each method family, and the families it's quarantined from.
+    pub divergent_checks: HashMap<MethodFamily, u32>,
+    pub quarantined: Vec<MethodFamily>,
+    // Set through the admin API. Drained nodes are out of rotation until
+    // restored, banned ones until the unix time in ms.
+    pub drained: bool,
+    pub banned_until_ms: u64,
 
     // The latency is a moving average of the last n calls
     pub latency: f64,
@@ -27,13 +96,60 @@ pub struct Status {
     // pub throughput: f64,
 }
 
+impl Status {
+    /// Returns true if the node is banned right now.
+    pub fn is_banned(&self) -> bool {
+        self.banned_until_ms != 0 && self.banned_until_ms > now_ms()
+    }
+
+    /// Returns true if the node can be picked for requests.
+    pub fn is_selectable(&self) -> bool {
+        !self.is_syncing && !self.drained && !self.is_banned()
+    }
+}
+
+/// Namespaces and client version reported by the node.
+///
+/// `None` if the node didn't tell us, or we haven't asked yet.
+#[derive(Debug, Clone, Default)]
+pub struct Capabilities {
+    pub modules: Option<Vec<String>>,
+    pub client_version: Option<String>,
+}
+
+impl Capabilities {
+    /// Returns true if the node supports the namespace of `method`.
+    ///
+    /// Nodes with unknown modules are assumed to support everything.
+    pub fn supports(&self, method: &str) -> bool {
+        let namespace = method.split('_').next().unwrap_or_default();
+        match &self.modules {
+            Some(modules) => modules.iter().any(|module| module == namespace),
+            None => true,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Rpc {
-    pub name: String,             // sanitized name for appearing in logs
-    url: url::Url,                // url of the rpc we're forwarding requests to.
-    client: Client,               // Reqwest client
-    pub ws_url: Option<url::Url>, // url of the websocket we're forwarding requests to.
-    pub status: Status,           // stores stats related to the rpc.
+    pub name: String,                       // sanitized name for appearing in logs
+    url: url::Url,                          // url of the rpc we're forwarding requests to.
+    client: Client,                         // Reqwest client
+    client_options: ClientOptions,          // what `client` is built from, see `client`
+    pub ws_url: Option<url::Url>,           // url of the websocket we're forwarding requests to.
+    pub status: Status,                     // stores stats related to the rpc.
+    pub capabilities: Capabilities,         // namespaces reported by `rpc_modules`
+    pub emergency: bool,                    // only gets traffic when no primary rpc is available
+    pub engine: Option<EngineSecret>,       // serves the Engine API, see `engine`
+    pub groups: Vec<String>,                // route groups, see `Route`
+    pub quota: ProviderQuota,               // monthly quota of the provider, see `quota`
+    pub runtime: bool,                      // added through admin, outlives config reloads
+    pub patched: bool, // limits and groups changed through admin, kept over the configured ones
+    ws_transport: Option<Arc<WsTransport>>, // sends calls over `ws_url` instead of HTTP
+    ipc: Option<Arc<IpcTransport>>, // sends calls over the IPC socket of `ipc://` urls
+    batcher: Option<Arc<MicroBatcher>>, // merges concurrent calls into batches
+    streams: Option<Arc<Semaphore>>, // caps HTTP requests in flight, see `client`
+    pub stats: Arc<RpcStats>, // shared by every copy, see `stats`
     // For max_consecutive
     pub max_consecutive: u32, // max times we can call an rpc in a row
     pub consecutive: u32,
@@ -42,11 +158,27 @@ pub struct Rpc {
     pub min_time_delta: u128, // microseconds
 }
 
+/// Decompress a response body sent with `encoding` into text.
+fn decompressed_text(encoding: &str, body: &[u8]) -> Result<String, RpcError> {
+    let text = decompress(encoding, body).and_then(|text| {
+        String::from_utf8(text)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    });
+    text.map_err(|err| {
+        RpcError::InvalidResponse(format!("failed to decompress {encoding} response: {err}"))
+    })
+}
+
 /// Sanitizes URLs so secrets don't get outputed.
 ///
 /// For example, if we have a URL: https://eth-mainnet.g.alchemy.com/v2/api-key
 // as input, we output: https://eth-mainnet.g.alchemy.com/
 fn sanitize_url(url: &url::Url) -> Result<String, url::ParseError> {
+    // Socket paths are no secret, and tell IPC RPCs apart
+    if url.scheme() == IPC_SCHEME {
+        return Ok(url.to_string());
+    }
+
     // Build a new URL with the scheme, host, and port (if any), but without the path or query
     let sanitized = Url::parse(&format!(
         "{}://{}{}",
@@ -67,8 +199,21 @@ impl Default for Rpc {
             name: "".to_string(),
             url: "https://eth.merkle.io".parse().unwrap(),
             ws_url: None,
-            client: Client::new(),
+            client: plain_client(),
+            client_options: ClientOptions::default(),
             status: Status::default(),
+            capabilities: Capabilities::default(),
+            emergency: false,
+            engine: None,
+            groups: Vec::new(),
+            quota: ProviderQuota::default(),
+            runtime: false,
+            patched: false,
+            ws_transport: None,
+            ipc: None,
+            batcher: None,
+            streams: None,
+            stats: Arc::default(),
             max_consecutive: 0,
             consecutive: 0,
             last_used: 0,
@@ -88,13 +233,26 @@ impl Rpc {
     ) -> Self {
         Self {
             name: sanitize_url(&url).unwrap_or(url.to_string()),
+            ipc: IpcTransport::from_url(&url).map(Arc::new),
             url,
-            client: Client::new(),
+            client: plain_client(),
+            client_options: ClientOptions::default(),
             ws_url,
             status: Status {
                 ma_length,
                 ..Default::default()
             },
+            capabilities: Capabilities::default(),
+            emergency: false,
+            engine: None,
+            groups: Vec::new(),
+            quota: ProviderQuota::default(),
+            runtime: false,
+            patched: false,
+            ws_transport: None,
+            batcher: None,
+            streams: None,
+            stats: Arc::default(),
             max_consecutive,
             consecutive: 0,
             last_used: 0,
@@ -108,19 +266,272 @@ impl Rpc {
         self.url.clone()
     }
 
+    /// Scheme of the url, safe to show unlike the url itself.
+    pub fn scheme(&self) -> &str {
+        self.url.scheme()
+    }
+
+    /// Id of the RPC in the admin API, stable across removals and reorders.
+    ///
+    /// Hashed from the url, as the url can contain secrets.
+    pub fn id(&self) -> String {
+        blake3::hash(self.url.as_str().as_bytes()).to_hex()[..16].to_string()
+    }
+
+    /// Key the usage of the quota of the RPC is stored under, see `quota`.
+    ///
+    /// Hashed, as the url can contain secrets.
+    pub fn quota_key(&self) -> [u8; 32] {
+        state_key(&format!("quota:{}", self.url))
+    }
+
+    /// Key the statistics of the RPC are stored under across restarts, see
+    /// `stats`.
+    pub fn stats_key(&self) -> [u8; 32] {
+        state_key(&format!("stats:{}", self.url))
+    }
+
+    /// Build the HTTP client from `options`, see `client`.
+    pub fn set_client_options(&mut self, options: ClientOptions) -> Result<(), RpcError> {
+        self.client = options.build()?;
+        self.streams =
+            (options.max_streams != 0).then(|| Arc::new(Semaphore::new(options.max_streams)));
+        self.client_options = options;
+        Ok(())
+    }
+
+    /// Returns true if the RPC is in the engine group, see `engine`.
+    pub fn is_engine(&self) -> bool {
+        self.engine.is_some()
+    }
+
+    /// Send calls over a persistent connection to `ws_url`, see `ws_transport`.
+    ///
+    /// Does nothing if the RPC has no `ws_url`.
+    pub fn enable_ws_transport(&mut self) {
+        self.ws_transport = self
+            .ws_url
+            .clone()
+            .map(|ws_url| Arc::new(WsTransport::new(ws_url)));
+    }
+
+    /// Merge calls sent within `window` of each other into batches of up to
+    /// `max_size`, see `micro_batch`. A zero `window` disables batching.
+    pub fn enable_micro_batching(&mut self, window: Duration, max_size: usize) {
+        self.batcher = (!window.is_zero()).then(|| Arc::new(MicroBatcher::new(window, max_size)));
+    }
+
+    /// Returns true if `other` is the same RPC as this one, i.e. has the same url.
+    pub fn same_url(&self, other: &Rpc) -> bool {
+        self.url == other.url
+    }
+
+    /// Take the configured limits and WS endpoint of `other`, keeping our own status.
+    ///
+    /// Limits and groups changed through admin stay as they are.
+    pub fn update_limits(&mut self, other: &Rpc) {
+        if !self.patched {
+            self.max_consecutive = other.max_consecutive;
+            self.min_time_delta = other.min_time_delta;
+            self.emergency = other.emergency;
+            self.groups = other.groups.clone();
+        }
+        self.engine = other.engine.clone();
+        self.quota = other.quota;
+        if self.client_options != other.client_options {
+            self.client = other.client.clone();
+            self.streams = other.streams.clone();
+            self.client_options = other.client_options.clone();
+        }
+        // A connection to the old WS endpoint is no use
+        if self.ws_url != other.ws_url {
+            self.ws_url = other.ws_url.clone();
+            self.ws_transport = None;
+        }
+        // Keep our connection if we already have one
+        match (&self.ws_transport, &other.ws_transport) {
+            (None, Some(_)) => self.enable_ws_transport(),
+            (Some(_), None) => self.ws_transport = None,
+            _ => {}
+        }
+        let batching = |rpc: &Rpc| {
+            rpc.batcher
+                .as_ref()
+                .map(|batcher| (batcher.window, batcher.max_size))
+        };
+        if batching(self) != batching(other) {
+            self.batcher = other.batcher.clone();
+        }
+    }
+
     /// Generic fn to send rpc
     pub async fn send_request(&self, tx: Value) -> Result<String, crate::rpc::types::RpcError> {
-        tracing::debug!("Sending request: {}", tx.clone());
+        self.send_request_traced(tx, None).await
+    }
+
+    /// Send rpc, with the checksum of the request in a header so it can be
+    /// matched up with the RPC's own logs.
+    pub async fn send_request_traced(
+        &self,
+        tx: Value,
+        request_checksum: Option<&str>,
+    ) -> Result<String, crate::rpc::types::RpcError> {
+        let method = method_label(tx["method"].as_str());
+        let _in_flight = self.stats.start_call();
+        let start = Instant::now();
+
+        let result = self.send(tx, request_checksum).await;
+        let latency = start.elapsed();
+        self.stats.record(&method, latency, &result);
+        record_method_metrics(&self.name, &method, latency, &result);
+
+        result
+    }
+
+    async fn send(
+        &self,
+        tx: Value,
+        request_checksum: Option<&str>,
+    ) -> Result<String, crate::rpc::types::RpcError> {
+        tracing::debug!(rpc = %self.name, %tx, "Sending request");
 
-        let response = match self.client.post(self.url.clone()).json(&tx).send().await {
+        if let Some(ipc) = &self.ipc {
+            let result = ipc.request(tx).await;
+            let status = if result.is_ok() { "ok" } else { "error" };
+            metrics::counter!("rpc_ipc_total", "rpc_name" => self.name.clone(), "status" => status)
+                .increment(1);
+            if let Ok(resp_text) = &result {
+                self.count_jsonrpc_errors(resp_text);
+            }
+            return result;
+        }
+
+        if let Some(ws_transport) = self
+            .ws_transport
+            .as_ref()
+            .filter(|_| tx.is_object() && !self.is_engine())
+        {
+            match ws_transport.request(tx.clone()).await {
+                Ok(resp_text) => {
+                    metrics::counter!("rpc_ws_transport_total", "rpc_name" => self.name.clone(), "status" => "ok").increment(1);
+                    self.count_jsonrpc_errors(&resp_text);
+                    return Ok(resp_text);
+                }
+                Err(err) => {
+                    metrics::counter!("rpc_ws_transport_total", "rpc_name" => self.name.clone(), "status" => "error").increment(1);
+                    // Calls that might have reached the RPC can only be sent again if that's harmless
+                    let sent = !matches!(err, RpcError::SendError(_));
+                    if sent && !tx["method"].as_str().is_some_and(is_read_method) {
+                        return Err(err);
+                    }
+                    tracing::debug!(
+                        ?err,
+                        name = self.name,
+                        "WS transport failed, falling back to HTTP"
+                    );
+                }
+            }
+        }
+
+        match &self.batcher {
+            Some(batcher) => {
+                batcher
+                    .request(tx, |call: Value| {
+                        // The checksum is of our call, not of a batch it's in
+                        let request_checksum = request_checksum.filter(|_| call.is_object());
+                        self.send_http(call, request_checksum)
+                    })
+                    .await
+            }
+            None => self.send_http(tx, request_checksum).await,
+        }
+    }
+
+    async fn send_http(
+        &self,
+        tx: Value,
+        request_checksum: Option<&str>,
+    ) -> Result<String, crate::rpc::types::RpcError> {
+        let mut request = self.client.post(self.url.clone()).json(&tx);
+        for (key, value) in trace_headers().iter() {
+            request = request.header(key.as_str(), value.as_str());
+        }
+        if let Some(request_checksum) = request_checksum {
+            request = request.header(REQUEST_CHECKSUM_HEADER, request_checksum);
+        }
+        if let Some(engine) = &self.engine {
+            let token = engine.token().map_err(|err| {
+                RpcError::SendError(format!("failed to mint engine API token: {err}"))
+            })?;
+            request = request.bearer_auth(token);
+        }
+
+        // Wait for a stream rather than opening more connections
+        let _stream = match &self.streams {
+            Some(streams) => streams.acquire().await.ok(),
+            None => None,
+        };
+
+        let response = match request.send().await {
             Ok(response) => response,
-            Err(err) => return Err(RpcError::InvalidResponse(err.to_string())),
+            Err(err) => {
+                self.count_http_status("error".to_string());
+                return Err(RpcError::InvalidResponse(err.to_string()));
+            }
         };
+        self.count_http_status(response.status().as_u16().to_string());
 
-        let resp_text = response.text().await;
-        tracing::debug!("response: {:?}", resp_text);
+        let content_encoding = response
+            .headers()
+            .get(CONTENT_ENCODING)
+            .and_then(|encoding| encoding.to_str().ok())
+            .map(str::to_ascii_lowercase)
+            .filter(|encoding| encoding != "identity");
+        let resp_text = match content_encoding {
+            Some(encoding) => {
+                response
+                    .bytes()
+                    .await
+                    .map_err(RpcError::from)
+                    .and_then(|body| {
+                        let text = decompressed_text(&encoding, &body)?;
+                        // Kept so it can be passed through to the client
+                        record_upstream_body(&encoding, body, &text);
+                        Ok(text)
+                    })
+            }
+            None => response.text().await.map_err(RpcError::from),
+        };
+        tracing::debug!(rpc = %self.name, response = ?resp_text, "Received response");
 
-        resp_text.map_err(From::from)
+        if let Ok(resp_text) = &resp_text {
+            self.count_jsonrpc_errors(resp_text);
+        }
+
+        resp_text
+    }
+
+    fn count_http_status(&self, status: String) {
+        self.stats.record_http_status(&status);
+        metrics::counter!(
+            "rpc_http_status_total",
+            "rpc_name" => self.name.clone(),
+            "status" => status
+        )
+        .increment(1);
+    }
+
+    fn count_jsonrpc_errors(&self, resp_text: &str) {
+        for code in jsonrpc_error_codes(resp_text) {
+            let label = error_code_label(code);
+            self.stats.record_jsonrpc_error(&label);
+            metrics::counter!(
+                "rpc_jsonrpc_errors_total",
+                "rpc_name" => self.name.clone(),
+                "code" => label
+            )
+            .increment(1);
+        }
     }
 
     /// Request blocknumber and return its value
@@ -175,10 +586,15 @@ impl Rpc {
 
     /// Get the latest finalized block
     pub async fn get_finalized_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        self.get_tagged_block("finalized").await
+    }
+
+    /// Get the number of the block `tag` refers to, like `safe`
+    pub async fn get_tagged_block(&self, tag: &str) -> Result<u64, crate::rpc::types::RpcError> {
         let method = EthRpcMethod::GetBlockByNumber;
         let request = json!({
             "method": method,
-            "params": ["finalized", false],
+            "params": [tag, false],
             "id": 1,
             "jsonrpc": "2.0".to_string(),
         });
@@ -199,9 +615,10 @@ impl Rpc {
         let number = match number.as_str() {
             Some(number) => number,
             None => {
-                return Err(RpcError::InvalidResponse(
-                    "error: Can't get finalized block!".to_string(),
-                ))
+                return Err(RpcError::InvalidResponse(format!(
+                    "error: Can't get {} block!",
+                    tag
+                )))
             }
         };
 
@@ -213,6 +630,127 @@ impl Rpc {
         Ok(return_number)
     }
 
+    /// Get the chain ID via `eth_chainId`
+    pub async fn chain_id(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "eth_chainId",
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let mut resp = self.send_request(request).await?;
+        let json: Value = unsafe { simd_json::serde::from_str(&mut resp)? };
+
+        match json["result"]
+            .as_str()
+            .and_then(|chain_id| u64::from_str_radix(chain_id.trim_start_matches("0x"), 16).ok())
+        {
+            Some(chain_id) => Ok(chain_id),
+            None => {
+                Err(RpcError::InvalidResponse(
+                    "error: Can't get chain ID!".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Get the network ID via `net_version`
+    pub async fn net_version(&self) -> Result<String, crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "net_version",
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let mut resp = self.send_request(request).await?;
+        let json: Value = unsafe { simd_json::serde::from_str(&mut resp)? };
+
+        match json["result"].as_str() {
+            Some(net_version) => Ok(net_version.to_string()),
+            None => {
+                Err(RpcError::InvalidResponse(
+                    "error: Can't get network ID!".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Get the namespaces the node supports via `rpc_modules`
+    pub async fn rpc_modules(&self) -> Result<Vec<String>, crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "rpc_modules",
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let resp = self.send_request(request).await?;
+        extract_modules(&resp)
+    }
+
+    /// Get the client version via `web3_clientVersion`
+    pub async fn client_version(&self) -> Result<String, crate::rpc::types::RpcError> {
+        let request = json!({
+            "method": "web3_clientVersion",
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        let mut resp = self.send_request(request).await?;
+        let json: Value = unsafe { simd_json::serde::from_str(&mut resp)? };
+
+        match json["result"].as_str() {
+            Some(version) => Ok(version.to_string()),
+            None => {
+                Err(RpcError::InvalidResponse(
+                    "error: Can't get client version!".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Get the hash of the block at `number`
+    pub async fn get_block_hash(&self, number: u64) -> Result<String, crate::rpc::types::RpcError> {
+        let method = EthRpcMethod::GetBlockByNumber;
+        let request = json!({
+            "method": method,
+            "params": [format!("0x{:x}", number), false],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).increment(1);
+        metrics::counter!("rpc_requests_total", "method" => method.as_str()).increment(1);
+
+        let req_start = std::time::Instant::now();
+        let resp = self.send_request(request).await?;
+
+        metrics::histogram!("rpc_response_time_secs", "method" => method.as_str())
+            .record(req_start.elapsed().as_secs_f64());
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).decrement(1);
+
+        extract_hash(&resp)
+    }
+
+    /// Put `stored` latencies of calls before the ones we measured since,
+    /// keeping the last n.
+    pub fn restore_latency(&mut self, stored: &[f64]) {
+        let mut latency_data = stored.to_vec();
+        latency_data.append(&mut self.status.latency_data);
+        let excess = latency_data
+            .len()
+            .saturating_sub((self.status.ma_length as usize).max(1));
+        latency_data.drain(..excess);
+
+        if !latency_data.is_empty() {
+            self.status.latency = latency_data.iter().sum::<f64>() / latency_data.len() as f64;
+        }
+        self.status.latency_data = latency_data;
+    }
+
     /// Update the latency of the last n calls.
     /// We don't do it within send_request because we might kill it if it times out.
     pub fn update_latency(&mut self, latest: f64) {
@@ -268,6 +806,84 @@ fn extract_number(rx: &str) -> Result<u64, RpcError> {
     Ok(number)
 }
 
+/// Take in the result of `rpc_modules`, and extract the supported namespaces
+fn extract_modules(rx: &str) -> Result<Vec<String>, RpcError> {
+    let mut rx = rx.to_string();
+
+    let json: Value = unsafe { simd_json::serde::from_str(&mut rx)? };
+
+    match json["result"].as_object() {
+        Some(modules) => Ok(modules.keys().cloned().collect()),
+        None => {
+            Err(RpcError::InvalidResponse(
+                "error: Can't get rpc modules!".to_string(),
+            ))
+        }
+    }
+}
+
+/// Returns the JSON-RPC error codes contained in a response, or batch of responses.
+///
+/// This runs on every response, so instead of parsing it we look for the
+/// `code` of every `"error"` object, up to the next one.
+pub fn jsonrpc_error_codes(rx: &str) -> Vec<i64> {
+    const ERROR: &str = "\"error\"";
+    const CODE: &str = "\"code\"";
+
+    let mut codes = Vec::new();
+    let mut rest = rx;
+    while let Some(found) = memmem::find(rest.as_bytes(), ERROR.as_bytes()) {
+        rest = &rest[found + ERROR.len()..];
+        let Some(error) = rest
+            .trim_start()
+            .strip_prefix(':')
+            .map(str::trim_start)
+            .filter(|error| error.starts_with('{'))
+        else {
+            continue;
+        };
+        let error = match memmem::find(error.as_bytes(), ERROR.as_bytes()) {
+            Some(next) => &error[..next],
+            None => error,
+        };
+
+        let Some(code) = memmem::find(error.as_bytes(), CODE.as_bytes()) else {
+            continue;
+        };
+        let Some(code) = error[code + CODE.len()..]
+            .trim_start()
+            .strip_prefix(':')
+            .map(str::trim_start)
+        else {
+            continue;
+        };
+        let end = code
+            .char_indices()
+            .find(|(i, c)| !(c.is_ascii_digit() || (*i == 0 && *c == '-')))
+            .map_or(code.len(), |(i, _)| i);
+        if let Ok(code) = code[..end].parse() {
+            codes.push(code);
+        }
+    }
+    codes
+}
+
+/// Take in the result of `eth_getBlockByNumber`, and extract the block hash
+fn extract_hash(rx: &str) -> Result<String, RpcError> {
+    let mut rx = rx.to_string();
+
+    let json: Value = unsafe { simd_json::serde::from_str(&mut rx)? };
+
+    match json["result"]["hash"].as_str() {
+        Some(hash) => Ok(hash.to_lowercase()),
+        None => {
+            Err(RpcError::InvalidResponse(
+                "error: Can't get block hash!".to_string(),
+            ))
+        }
+    }
+}
+
 pub fn hex_to_decimal(hex_string: &str) -> Result<u64, std::num::ParseIntError> {
     // TODO: theres a bizzare edge case where the last " isnt removed in the
     // previou step so check for that here and remove it if necessary
@@ -339,6 +955,79 @@ mod tests {
         assert_eq!(result.unwrap(), 436); // 0x1b4 in decimal
     }
 
+    #[test]
+    fn test_extract_modules() {
+        let input = r#"{"jsonrpc":"2.0","id":1,"result":{"eth":"1.0","net":"1.0","trace":"1.0"}}"#;
+        let mut modules = extract_modules(input).unwrap();
+        modules.sort();
+        assert_eq!(modules, vec!["eth", "net", "trace"]);
+
+        let input = r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601}}"#;
+        assert!(extract_modules(input).is_err());
+    }
+
+    #[test]
+    fn test_capabilities_supports() {
+        let unknown = Capabilities::default();
+        assert!(unknown.supports("trace_block"));
+
+        let capabilities = Capabilities {
+            modules: Some(vec!["eth".to_string(), "net".to_string()]),
+            client_version: None,
+        };
+        assert!(capabilities.supports("eth_getBalance"));
+        assert!(!capabilities.supports("trace_block"));
+        assert!(!capabilities.supports("debug_traceTransaction"));
+    }
+
+    #[test]
+    fn test_jsonrpc_error_codes() {
+        assert!(jsonrpc_error_codes(r#"{"jsonrpc":"2.0","id":1,"result":"0x1"}"#).is_empty());
+        assert_eq!(
+            jsonrpc_error_codes(
+                r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32005,"message":"limit exceeded"}}"#
+            ),
+            vec![-32005]
+        );
+        assert_eq!(
+            jsonrpc_error_codes(
+                r#"[{"id":1,"result":"0x1"},{"id":2,"error":{"code":-32601}},{"id":3,"error":{"code":3}}]"#
+            ),
+            vec![-32601, 3]
+        );
+        assert!(jsonrpc_error_codes(r#"{"error": not json"#).is_empty());
+        // Codes of other errors don't count for ones without
+        assert_eq!(
+            jsonrpc_error_codes(
+                r#"[{"id":1,"error":{"message":"no code"}},{"id":2,"error" : { "code" : -32000 }}]"#
+            ),
+            vec![-32000]
+        );
+        // Nor do errors inside results
+        assert!(jsonrpc_error_codes(r#"{"id":1,"result":{"error":"0x","code":5}}"#).is_empty());
+    }
+
+    #[test]
+    fn test_extract_hash() {
+        let input = json!({
+            "result": {
+                "number": "0x1b4",
+                "hash": "0xDC0818CF78F21A8E70579CB46A43643F78291264DDA342AE31049421C82D21AE"
+            }
+        });
+        let input_str = to_string(&input).unwrap();
+        assert_eq!(
+            extract_hash(&input_str).unwrap(),
+            "0xdc0818cf78f21a8e70579cb46a43643f78291264dda342ae31049421c82d21ae"
+        );
+
+        let input = json!({
+            "result": null
+        });
+        let input_str = to_string(&input).unwrap();
+        assert!(extract_hash(&input_str).is_err());
+    }
+
     #[test]
     fn test_extract_number_failure() {
         let input = json!({
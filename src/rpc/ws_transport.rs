@@ -0,0 +1,352 @@
+//! Ordinary JSON-RPC calls over an RPC's WebSocket.
+//!
+//! With `ws_transport` enabled for an RPC, calls are sent over a persistent
+//! connection to its `ws_url` instead of a new HTTP request each. Calls are
+//! pipelined: every call gets an id of our own, so responses can come back
+//! in any order and still reach the call they answer.
+//!
+//! The connection is opened on the first call, and again on the next one
+//! after it drops. Calls made while it's being opened, or while it can't be,
+//! go over HTTP instead. Calls in flight when it drops fail, and only ones
+//! that read chain data are sent again over HTTP, as the rest might have
+//! reached the RPC already. Batches always go over HTTP.
+
+use crate::rpc::error::RpcError;
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use futures::{
+    SinkExt,
+    StreamExt,
+};
+use serde_json::Value;
+use tokio::{
+    sync::{
+        mpsc,
+        oneshot,
+    },
+    time::timeout,
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+/// Time we wait after failing to connect before trying again, going over HTTP meanwhile.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Time we give the handshake before giving up on connecting.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Calls waiting for their response, by the id we sent them with
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Value>>>>;
+
+#[derive(Debug, Default)]
+struct Connection {
+    // Sends frames to the open connection, if any
+    frames: Option<mpsc::UnboundedSender<Message>>,
+    failed_at: Option<Instant>,
+    // A call is opening the connection
+    connecting: bool,
+}
+
+/// Marks the connection as no longer being opened, even if the call opening
+/// it stops waiting.
+struct Connecting<'a> {
+    connection: &'a Mutex<Connection>,
+}
+
+impl Drop for Connecting<'_> {
+    fn drop(&mut self) {
+        self.connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .connecting = false;
+    }
+}
+
+/// Persistent WebSocket connection calls to an RPC are sent over.
+#[derive(Debug)]
+pub struct WsTransport {
+    ws_url: url::Url,
+    next_id: AtomicU64,
+    pending: Pending,
+    connection: Mutex<Connection>,
+}
+
+impl WsTransport {
+    pub fn new(ws_url: url::Url) -> Self {
+        Self {
+            ws_url,
+            next_id: AtomicU64::new(0),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            connection: Mutex::new(Connection::default()),
+        }
+    }
+
+    /// Send `call` and wait for its response, which gets the id of `call`.
+    ///
+    /// Fails with `RpcError::SendError` if `call` wasn't sent, and with
+    /// `RpcError::InvalidResponse` if it was but got no response.
+    pub async fn request(&self, mut call: Value) -> Result<String, RpcError> {
+        if !call.is_object() {
+            return Err(RpcError::SendError(
+                "Only single calls can go over WS".to_string(),
+            ));
+        }
+
+        let frames = self.connect().await?;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, tx);
+        // Stop waiting if we're dropped, like when the call times out
+        let _waiting = Waiting {
+            pending: &self.pending,
+            id,
+        };
+
+        let original_id = std::mem::replace(&mut call["id"], id.into());
+        if frames.send(Message::Text(call.to_string())).is_err() {
+            return Err(RpcError::SendError("WS connection closed".to_string()));
+        }
+
+        let mut response = rx.await.map_err(|_| {
+            RpcError::InvalidResponse("WS connection closed before responding".to_string())
+        })?;
+        response["id"] = original_id;
+
+        Ok(response.to_string())
+    }
+
+    /// Returns the sender of the open connection, opening one if there's none.
+    ///
+    /// Only one call opens the connection, the others fail until it's open
+    /// rather than waiting on the handshake.
+    async fn connect(&self) -> Result<mpsc::UnboundedSender<Message>, RpcError> {
+        {
+            let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(frames) = connection
+                .frames
+                .as_ref()
+                .filter(|frames| !frames.is_closed())
+            {
+                return Ok(frames.clone());
+            }
+            if connection
+                .failed_at
+                .is_some_and(|failed_at| failed_at.elapsed() < RECONNECT_DELAY)
+            {
+                return Err(RpcError::SendError("WS is unreachable".to_string()));
+            }
+            if connection.connecting {
+                return Err(RpcError::SendError("WS is connecting".to_string()));
+            }
+            connection.connecting = true;
+        }
+        let _connecting = Connecting {
+            connection: &self.connection,
+        };
+
+        let connected = match timeout(CONNECT_TIMEOUT, connect_async(&self.ws_url)).await {
+            Ok(Ok((ws_stream, _))) => Ok(ws_stream),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(_) => Err("timed out".to_string()),
+        };
+        let ws_stream = match connected {
+            Ok(ws_stream) => ws_stream,
+            Err(err) => {
+                self.connection
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .failed_at = Some(Instant::now());
+                return Err(RpcError::SendError(format!(
+                    "Failed to connect to WS: {}",
+                    err
+                )));
+            }
+        };
+        let (mut sink, mut stream) = ws_stream.split();
+        let (frames, mut frames_rx) = mpsc::unbounded_channel::<Message>();
+
+        let pending = Arc::clone(&self.pending);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    frame = frames_rx.recv() => {
+                        let Some(frame) = frame else {
+                            break;
+                        };
+                        if sink.send(frame).await.is_err() {
+                            break;
+                        }
+                    }
+                    message = stream.next() => {
+                        let response = match message {
+                            Some(Ok(Message::Text(text))) => text,
+                            Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                            Some(Ok(_)) => continue,
+                        };
+                        respond(&pending, &response);
+                    }
+                }
+            }
+
+            // Fail everything still in flight, so callers can fall back to HTTP
+            frames_rx.close();
+            pending.lock().unwrap_or_else(|e| e.into_inner()).clear();
+        });
+
+        let mut connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection.frames = Some(frames.clone());
+        connection.failed_at = None;
+        Ok(frames)
+    }
+}
+
+/// Removes a call from the pending ones once it's done waiting.
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+    }
+}
+
+/// Pass `response` to the call waiting for it.
+fn respond(pending: &Pending, response: &str) {
+    let Ok(response) = serde_json::from_str::<Value>(response) else {
+        tracing::warn!("Received malformed response over WS transport");
+        return;
+    };
+    let Some(id) = response["id"].as_u64() else {
+        return;
+    };
+
+    let waiting = pending
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(&id);
+    if let Some(waiting) = waiting {
+        let _ = waiting.send(response);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    // Answers every two calls in reverse order, with the method as result
+    async fn serve(listener: TcpListener) {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut ws = accept_async(stream).await.unwrap();
+            let mut held = None;
+            while let Some(Ok(Message::Text(call))) = ws.next().await {
+                let call: Value = serde_json::from_str(&call).unwrap();
+                let response =
+                    json!({"jsonrpc": "2.0", "id": call["id"], "result": call["method"]});
+                match held.take() {
+                    None => held = Some(response),
+                    Some(first) => {
+                        ws.send(Message::Text(response.to_string())).await.unwrap();
+                        ws.send(Message::Text(first.to_string())).await.unwrap();
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(serve(listener));
+
+        let transport = WsTransport::new(ws_url.parse().unwrap());
+        let first = json!({"jsonrpc": "2.0", "id": "a", "method": "eth_blockNumber", "params": []});
+        let second = json!({"jsonrpc": "2.0", "id": 7, "method": "eth_chainId", "params": []});
+        let (first, second) = tokio::join!(transport.request(first), transport.request(second));
+
+        let first: Value = serde_json::from_str(&first.unwrap()).unwrap();
+        let second: Value = serde_json::from_str(&second.unwrap()).unwrap();
+        assert_eq!(first["id"], "a");
+        assert_eq!(first["result"], "eth_blockNumber");
+        assert_eq!(second["id"], 7);
+        assert_eq!(second["result"], "eth_chainId");
+        assert!(transport.pending.lock().unwrap().is_empty());
+
+        assert!(transport.request(json!([first])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_url = format!("ws://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let transport = WsTransport::new(ws_url.parse().unwrap());
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        assert!(transport.request(call.clone()).await.is_err());
+        // Not retried right away
+        assert!(transport.connection.lock().unwrap().failed_at.is_some());
+        assert!(transport.request(call).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_doesnt_block() {
+        // Accepts connections, but never completes the handshake
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ws_url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+
+        let transport = Arc::new(WsTransport::new(ws_url.parse().unwrap()));
+        let call = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+        let connecting = tokio::spawn({
+            let transport = Arc::clone(&transport);
+            let call = call.clone();
+            async move { transport.request(call).await }
+        });
+        while !transport.connection.lock().unwrap().connecting {
+            tokio::task::yield_now().await;
+        }
+
+        // Other calls go elsewhere instead of waiting on the handshake
+        let result = timeout(Duration::from_millis(100), transport.request(call)).await;
+        assert!(matches!(result, Ok(Err(RpcError::SendError(_)))));
+
+        // Giving up on the handshake lets the next call try again
+        connecting.abort();
+        let _ = connecting.await;
+        assert!(!transport.connection.lock().unwrap().connecting);
+    }
+}
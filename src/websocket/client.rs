@@ -1,14 +1,34 @@
 use crate::{
     balancer::{
+        cache_metrics::record_lookup,
         format::replace_block_tags,
+        logs::LogQuery,
         processing::{
             cache_query,
             update_rpc_latency,
             CacheArgs,
         },
         selection::select::pick,
+        singleflight::{
+            with_id,
+            Flight,
+        },
+    },
+    config::{
+        system::{
+            MAGIC,
+            WS_RESUBSCRIBE_ID,
+            WS_SUB_MANAGER_ID,
+        },
+        types::{
+            WsConnectionSettings,
+            WsReconnectSettings,
+        },
+    },
+    database::{
+        serialization::decode_cached,
+        types::GenericBytes,
     },
-    database::types::GenericBytes,
     db_get,
     rpc::{
         method::EthRpcMethod,
@@ -16,6 +36,13 @@ use crate::{
     },
     websocket::{
         error::WsError,
+        keepalive::next_ping,
+        replay::{
+            ReplayError,
+            RESUME_METHOD,
+            RESUME_TOKEN_METHOD,
+        },
+        subscription_manager::move_subscriptions,
         types::{
             IncomingResponse,
             SubscriptionData,
@@ -26,188 +53,350 @@ use crate::{
 };
 
 use std::{
+    collections::HashMap,
     sync::{
         Arc,
         RwLock,
     },
-    time::Instant,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use futures_util::{
     SinkExt,
     StreamExt,
 };
-use serde_json::Value;
-use simd_json::{
-    from_slice,
-    from_str,
+use serde_json::{
+    json,
+    Value,
 };
+use simd_json::from_str;
 
-use tokio::sync::{
-    broadcast,
-    mpsc,
+use rust_tracing::deps::metrics;
+use tokio::{
+    net::TcpStream,
+    sync::{
+        broadcast,
+        mpsc,
+    },
 };
 use tokio_tungstenite::{
     connect_async,
     tungstenite::protocol::Message,
+    MaybeTlsStream,
+    WebSocketStream,
 };
 
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
+/// How often we check for nodes that left the pool.
+const FAILOVER_INTERVAL: Duration = Duration::from_secs(1);
+/// Time an RPC gets to answer our close frame.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
 
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
+/// JSON-RPC error code for subscriptions past a client's limits, as in EIP-1474.
+const SUBSCRIPTION_LIMIT_CODE: i64 = -32005;
+/// JSON-RPC error code for subscriptions that can't be resumed.
+const REPLAY_ERROR_CODE: i64 = -32000;
 
 /// Accepts incoming internal WS messages.
 ///
-/// Upon receiving a `WsconnMessage::Reconnect()` it will drop all current WS
-/// connections and initiate new ones from the `rpc_list`.
+/// WS connections are known by their node id, which stays the same for as
+/// long as the connection lives. Upon receiving a `WsconnMessage::Reconnect()`
+/// it will connect to every RPC in the `rpc_list` we don't have a connection to.
+///
+/// When an RPC leaves the `rpc_list`, e.g. because it went unhealthy, or its
+/// connection drops for good, we close its connection and move its
+/// subscriptions to the RPCs left.
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_conn_manager(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     ws_handles: Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     mut incoming_rx: mpsc::UnboundedReceiver<WsconnMessage>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
+    sub_data: Arc<SubscriptionData>,
+    reconnect: WsReconnectSettings,
+    keepalive: WsConnectionSettings,
 ) {
+    // WS URL of the RPC every node is connected to, by node id
+    let mut ws_nodes: Vec<url::Url> = Vec::new();
+
     // Initialize WebSocket connections
-    update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
+    connect_ws_nodes(
+        &rpc_list,
+        &ws_handles,
+        &mut ws_nodes,
+        &broadcast_tx,
+        &ws_error_tx,
+        &sub_data,
+        reconnect,
+        keepalive,
+    )
+    .await;
 
     // Buffer for WS subscriptions when all nodes are ded
     let mut ws_buffer: Vec<Value> = Vec::new();
 
-    while let Some(message) = incoming_rx.recv().await {
-        match message {
-            WsconnMessage::Message(incoming, specified_index) => {
-                handle_incoming_message(
-                    &ws_handles,
-                    &rpc_list,
-                    incoming,
-                    specified_index,
-                    &mut ws_buffer,
-                )
-                .await;
+    let mut failover = tokio::time::interval(FAILOVER_INTERVAL);
+    loop {
+        tokio::select! {
+            message = incoming_rx.recv() => {
+                let Some(message) = message else {
+                    return;
+                };
+
+                match message {
+                    WsconnMessage::Message(incoming, specified_index) => {
+                        handle_incoming_message(
+                            &ws_handles,
+                            &ws_nodes,
+                            &rpc_list,
+                            incoming,
+                            specified_index,
+                            &mut ws_buffer,
+                        )
+                        .await;
+                    }
+                    WsconnMessage::Reconnect() => {
+                        fail_over(&rpc_list, &ws_handles, &ws_nodes, &incoming_tx, &broadcast_tx, &sub_data);
+                        connect_ws_nodes(
+                            &rpc_list,
+                            &ws_handles,
+                            &mut ws_nodes,
+                            &broadcast_tx,
+                            &ws_error_tx,
+                            &sub_data,
+                            reconnect,
+                            keepalive,
+                        )
+                        .await;
+                        unload_buffer(&rpc_list, &ws_handles, &ws_nodes, &mut ws_buffer).await;
+                    }
+                }
             }
-            WsconnMessage::Reconnect() => {
-                update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
-                unload_buffer(&rpc_list, &ws_handles, &mut ws_buffer).await;
+            _ = failover.tick() => {
+                fail_over(&rpc_list, &ws_handles, &ws_nodes, &incoming_tx, &broadcast_tx, &sub_data);
             }
         }
     }
 }
 
-/// Updates the active WS handles to match the active connections.
-async fn update_ws_connections(
+/// Connects to every RPC in `rpc_list` we don't have an open connection to.
+#[allow(clippy::too_many_arguments)]
+async fn connect_ws_nodes(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    ws_nodes: &mut Vec<url::Url>,
     broadcast_tx: &broadcast::Sender<IncomingResponse>,
     ws_error_tx: &mpsc::UnboundedSender<WsChannelErr>,
+    sub_data: &Arc<SubscriptionData>,
+    reconnect: WsReconnectSettings,
+    keepalive: WsConnectionSettings,
 ) {
-    let ws_vec = create_ws_vec(rpc_list, broadcast_tx, ws_error_tx).await;
-    let mut ws_handle_guard = ws_handles.write().unwrap_or_else(|e| {
-        // Handle the case where the ws_handles RwLock is poisoned
-        tracing::error!(?e);
-        e.into_inner()
-    });
-    *ws_handle_guard = ws_vec;
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the rpc_list RwLock is poisoned
+            tracing::error!(?e);
+            e.into_inner()
+        })
+        .clone();
+
+    for rpc in rpc_list_clone {
+        let Some(ws_url) = rpc.ws_url.clone() else {
+            continue;
+        };
+        if ws_node(&ws_handles.read().unwrap(), ws_nodes, &ws_url).is_some() {
+            continue;
+        }
+
+        let node_id = ws_nodes.len();
+        let (ws_conn_incoming_tx, ws_conn_incoming_rx) = mpsc::unbounded_channel();
+        ws_conn(
+            rpc,
+            rpc_list.clone(),
+            ws_conn_incoming_rx,
+            broadcast_tx.clone(),
+            ws_error_tx.clone(),
+            sub_data.clone(),
+            reconnect,
+            keepalive,
+            node_id,
+        )
+        .await;
+
+        ws_handles
+            .write()
+            .unwrap_or_else(|e| {
+                // Handle the case where the ws_handles RwLock is poisoned
+                tracing::error!(?e);
+                e.into_inner()
+            })
+            .push(Some(ws_conn_incoming_tx));
+        ws_nodes.push(ws_url);
+    }
+}
+
+/// Returns the node with an open connection to the RPC at `ws_url`.
+fn ws_node(
+    ws_handles: &[Option<mpsc::UnboundedSender<Value>>],
+    ws_nodes: &[url::Url],
+    ws_url: &url::Url,
+) -> Option<usize> {
+    ws_nodes
+        .iter()
+        .enumerate()
+        .rev()
+        .find_map(|(node_id, url)| {
+            let open = ws_handles
+                .get(node_id)
+                .and_then(|handle| handle.as_ref())
+                .is_some_and(|handle| !handle.is_closed());
+            (open && url == ws_url).then_some(node_id)
+        })
+}
+
+/// Closes the connections to RPCs that left `rpc_list` or dropped, and moves
+/// their subscriptions to the RPCs left.
+fn fail_over(
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    ws_nodes: &[url::Url],
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    broadcast_tx: &broadcast::Sender<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+) {
+    let active: Vec<url::Url> = rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter_map(|rpc| rpc.ws_url.clone())
+        .collect();
+
+    let failed: Vec<usize> = {
+        let mut ws_handle_guard = ws_handles.write().unwrap_or_else(|e| e.into_inner());
+        ws_handle_guard
+            .iter_mut()
+            .zip(ws_nodes)
+            .enumerate()
+            .filter_map(|(node_id, (handle, ws_url))| {
+                let sender = handle.as_ref()?;
+                if !sender.is_closed() && active.contains(ws_url) {
+                    return None;
+                }
+                // Dropping the sender closes the connection
+                *handle = None;
+                Some(node_id)
+            })
+            .collect()
+    };
+
+    for node_id in failed {
+        if sub_data.get_subscription_by_node(node_id).is_empty() {
+            continue;
+        }
+
+        tracing::warn!(
+            node_id,
+            ws_url = %ws_nodes[node_id],
+            "Node left the pool, moving its subscriptions"
+        );
+        metrics::counter!("ws_failovers_total").increment(1);
+
+        // Moving waits on responses that go through the manager, so it can't block it
+        let incoming_tx = incoming_tx.clone();
+        let rx = broadcast_tx.subscribe();
+        let sub_data = sub_data.clone();
+        tokio::spawn(async move {
+            if let Err(err) = move_subscriptions(&incoming_tx, rx, &sub_data, node_id).await {
+                tracing::error!(?err, node_id, "Failed to move subscriptions off node");
+            }
+        });
+    }
 }
 
 /// Dispatches buffered WS subscriptions out to nodes.
 async fn unload_buffer(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    ws_nodes: &[url::Url],
     ws_buffer: &mut Vec<Value>,
 ) {
-    for i in 0..ws_buffer.len() {
-        let incoming = ws_buffer[i].clone();
-        handle_incoming_message(ws_handles, rpc_list, incoming, None, ws_buffer).await;
+    for incoming in std::mem::take(ws_buffer) {
+        handle_incoming_message(ws_handles, ws_nodes, rpc_list, incoming, None, ws_buffer).await;
     }
-    ws_buffer.clear();
 }
 
 /// Sends an incoming request to a WS connection.
 ///
-/// Indexes can be specified via the `specified_index` param.
+/// Node ids can be specified via the `specified_index` param. Otherwise, it
+/// goes to the node connected to the RPC we pick, or any node still in the
+/// pool if we aren't connected to that one yet.
 async fn handle_incoming_message(
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    ws_nodes: &[url::Url],
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     incoming: Value,
     specified_index: Option<usize>,
     ws_buffer: &mut Vec<Value>,
 ) {
-    let rpc_position = if let Some(index) = specified_index {
-        index
+    let node_id = if let Some(index) = specified_index {
+        Some(index)
     } else {
         let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
             // Handle the case where the rpc_list RwLock is poisoned
             tracing::error!(?e);
             e.into_inner()
         });
+        let ws_handle_guard = ws_handles.read().unwrap_or_else(|e| e.into_inner());
+
+        pick(&mut rpc_list_guard, incoming["method"].as_str())
+            .1
+            .and_then(|position| {
+                let picked = rpc_list_guard[position].ws_url.iter();
+                let others = rpc_list_guard.iter().filter_map(|rpc| rpc.ws_url.as_ref());
+                picked
+                    .chain(others)
+                    .find_map(|ws_url| ws_node(&ws_handle_guard, ws_nodes, ws_url))
+            })
+    };
 
-        match pick(&mut rpc_list_guard).1 {
-            Some(position) => position,
-            None => {
-                // Check if the incoming content is a subscription.
-                //
-                // We do this because we want to send it to a buffer
-                // in case we have no available RPCs.
-                let method = &incoming["method"];
-                if method.eq(&EthRpcMethod::Subscription) || method.eq(&EthRpcMethod::Subscribe) {
-                    ws_buffer.push(incoming);
-                }
-                tracing::error!("No RPC position available");
-                return;
-            }
+    let Some(node_id) = node_id else {
+        // Check if the incoming content is a subscription.
+        //
+        // We do this because we want to send it to a buffer
+        // in case we have no available RPCs.
+        let method = &incoming["method"];
+        if method.eq(&EthRpcMethod::Subscription) || method.eq(&EthRpcMethod::Subscribe) {
+            ws_buffer.push(incoming);
         }
+        tracing::error!("No RPC position available");
+        return;
     };
 
     if let Some(ws) = ws_handles
         .read()
         .unwrap()
-        .get(rpc_position)
+        .get(node_id)
         .and_then(|handle| handle.as_ref())
     {
         if ws.send(incoming).is_err() {
             tracing::error!("ws_conn_manager error: failed to send message");
         }
     } else {
-        tracing::error!(rpc_position, "No WS connection at index");
+        tracing::error!(node_id, "No WS connection at index");
     }
 }
 
-/// Creates new WS connections off of RPCs in `rpc_list`.
-///
-/// Returns a Vec of channels that can be used to send values
-/// to different individual WS connections.
-pub async fn create_ws_vec(
-    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
-    broadcast_tx: &broadcast::Sender<IncomingResponse>,
-    ws_error_tx: &mpsc::UnboundedSender<WsChannelErr>,
-) -> Vec<Option<mpsc::UnboundedSender<Value>>> {
-    let rpc_list_clone = rpc_list
+/// Returns the position in `rpc_list` of the RPC at `ws_url`.
+fn rpc_position(rpc_list: &Arc<RwLock<Vec<Rpc>>>, ws_url: &url::Url) -> Option<usize> {
+    rpc_list
         .read()
-        .unwrap_or_else(|e| {
-            // Handle the case where the rpc_list RwLock is poisoned
-            tracing::error!(?e);
-            e.into_inner()
-        })
-        .clone();
-    let mut ws_handles = Vec::new();
-
-    for (index, rpc) in rpc_list_clone.iter().enumerate() {
-        let (ws_conn_incoming_tx, ws_conn_incoming_rx) = mpsc::unbounded_channel();
-        ws_handles.push(Some(ws_conn_incoming_tx));
-        ws_conn(
-            rpc.clone(),
-            rpc_list.clone(),
-            ws_conn_incoming_rx,
-            broadcast_tx.clone(),
-            ws_error_tx.clone(),
-            index,
-        )
-        .await;
-    }
-
-    ws_handles
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .position(|rpc| rpc.ws_url.as_ref() == Some(ws_url))
 }
 
 /// Represents a single WS connection to an RPC.
@@ -216,18 +405,29 @@ pub async fn create_ws_vec(
 /// via `broadcast_tx`. Messages are *discovered* by their respective
 /// senders via the `"id"` field.
 ///
-/// In case of an error where the connection is forced to close,
-/// a message will be sent via the `ws_error_tx` channel alerting
-/// the health check module.
+/// If the RPC drops the connection, we reconnect with exponential backoff and
+/// subscribe to everything we were subscribed to again. Users are moved over
+/// to the new subscriptions and keep their subscription ids.
+///
+/// If we can't reconnect, a message will be sent via the `ws_error_tx`
+/// channel alerting the health check module.
+///
+/// `index` is the node id of the connection, which isn't the position of `rpc`
+/// in the `rpc_list`.
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_conn(
     rpc: Rpc,
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     mut incoming_rx: mpsc::UnboundedReceiver<Value>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
+    sub_data: Arc<SubscriptionData>,
+    reconnect: WsReconnectSettings,
+    keepalive: WsConnectionSettings,
     index: usize,
 ) {
-    let ws_stream = match connect_async(&rpc.ws_url.unwrap()).await {
+    let ws_url = rpc.ws_url.clone().unwrap();
+    let mut ws_stream = match connect_async(&ws_url).await {
         Ok((ws_stream, _)) => ws_stream,
         Err(_) => {
             tracing::error!(
@@ -238,70 +438,260 @@ pub async fn ws_conn(
         }
     };
 
-    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
-
-    // Thread for sending messages
-    let sender_error_tx = ws_error_tx.clone();
     tokio::spawn(async move {
-        while let Some(incoming) = incoming_rx.recv().await {
-            tracing::debug!("ws_conn[{}], send: {:?}", index, incoming);
-
-            if ws_sender
-                .send(Message::Text(incoming.to_string()))
-                .await
-                .is_err()
-            {
-                let _ = sender_error_tx.send(WsChannelErr::Closed(index));
-                break;
+        // Subscriptions we're making again after reconnecting, by request id
+        let mut resubscribing = HashMap::new();
+        loop {
+            let end = serve_ws_conn(
+                ws_stream,
+                &ws_url,
+                &rpc_list,
+                &mut incoming_rx,
+                &broadcast_tx,
+                &sub_data,
+                &mut resubscribing,
+                keepalive,
+                index,
+            )
+            .await;
+            if let WsConnEnd::Replaced = end {
+                return;
             }
+
+            tracing::warn!(rpc.name, "WS connection dropped, reconnecting");
+            ws_stream = match reconnect_ws(&ws_url, &rpc.name, reconnect).await {
+                Some(ws_stream) => ws_stream,
+                None => {
+                    // Already out of the pool otherwise
+                    if let Some(position) = rpc_position(&rpc_list, &ws_url) {
+                        let _ = ws_error_tx.send(WsChannelErr::Closed(position));
+                    }
+                    return;
+                }
+            };
+            resubscribing = resubscribe_requests(&sub_data, index);
         }
     });
+}
 
-    // Thread for receiving messages
-    tokio::spawn(async move {
-        while let Some(message) = ws_receiver.next().await {
-            match message {
-                Ok(message) => {
-                    let time = Instant::now();
-                    tracing::debug!("ws_conn[{}], recv: {:?}", index, message);
-
-                    let mut ws_message = match message.into_text() {
-                        Ok(rax) => rax,
-                        Err(e) => {
-                            tracing::error!(?e, "Received malformed message from ws_conn");
-                            let _ = ws_error_tx.send(WsChannelErr::Closed(index));
-                            break;
-                        }
-                    };
+/// Why we stopped serving a WS connection.
+enum WsConnEnd {
+    /// Nothing will be sent through it anymore, the connection was replaced.
+    Replaced,
+    /// The RPC dropped the connection.
+    Dropped,
+}
 
-                    let rax = match unsafe { from_str(&mut ws_message) } {
-                        Ok(rax) => rax,
-                        Err(_e) => {
-                            {
-                                tracing::warn!(?_e, "Couldn't deserialize ws_conn response");
-                            }
+/// Send requests from `incoming_rx` over `ws_stream` and broadcast what we
+/// receive, until either side is done.
+///
+/// The requests in `resubscribing` are sent first, and their responses are
+/// used to move users over instead of being broadcast.
+#[allow(clippy::too_many_arguments)]
+async fn serve_ws_conn(
+    ws_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ws_url: &url::Url,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    incoming_rx: &mut mpsc::UnboundedReceiver<Value>,
+    broadcast_tx: &broadcast::Sender<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    resubscribing: &mut HashMap<u64, (String, Value)>,
+    keepalive: WsConnectionSettings,
+    index: usize,
+) -> WsConnEnd {
+    let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+    let mut pings = keepalive.pings();
+    let mut last_received = tokio::time::Instant::now();
+
+    for (_, request) in resubscribing.values() {
+        if ws_sender
+            .send(Message::Text(request.to_string()))
+            .await
+            .is_err()
+        {
+            return WsConnEnd::Dropped;
+        }
+    }
 
-                            continue;
+    loop {
+        tokio::select! {
+            incoming = incoming_rx.recv() => {
+                let Some(incoming) = incoming else {
+                    // Let the RPC know we're done instead of just going away
+                    let _ = tokio::time::timeout(CLOSE_TIMEOUT, ws_sender.close()).await;
+                    return WsConnEnd::Replaced;
+                };
+                tracing::debug!("ws_conn[{}], send: {:?}", index, incoming);
+
+                if ws_sender
+                    .send(Message::Text(incoming.to_string()))
+                    .await
+                    .is_err()
+                {
+                    return WsConnEnd::Dropped;
+                }
+            }
+            _ = next_ping(&mut pings) => {
+                if ws_sender.send(Message::Ping(Vec::new())).await.is_err() {
+                    return WsConnEnd::Dropped;
+                }
+            }
+            _ = keepalive.idle(last_received) => {
+                tracing::warn!(index, "WS connection went idle");
+                return WsConnEnd::Dropped;
+            }
+            message = ws_receiver.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    Some(Err(_)) | None => return WsConnEnd::Dropped,
+                };
+                last_received = tokio::time::Instant::now();
+                // Answered by tungstenite, or answering our pings
+                if message.is_ping() || message.is_pong() {
+                    continue;
+                }
+                let time = Instant::now();
+                tracing::debug!("ws_conn[{}], recv: {:?}", index, message);
+
+                let mut ws_message = match message.into_text() {
+                    Ok(rax) => rax,
+                    Err(e) => {
+                        tracing::error!(?e, "Received malformed message from ws_conn");
+                        return WsConnEnd::Dropped;
+                    }
+                };
+
+                let rax: Value = match unsafe { from_str(&mut ws_message) } {
+                    Ok(rax) => rax,
+                    Err(_e) => {
+                        {
+                            tracing::warn!(?_e, "Couldn't deserialize ws_conn response");
                         }
-                    };
 
-                    let incoming = IncomingResponse {
-                        node_id: index,
-                        content: rax,
-                    };
-
-                    let _ = broadcast_tx.send(incoming);
-                    let time = time.elapsed();
-                    update_rpc_latency(&rpc_list, index, time);
-                    tracing::info!(?time, "WS request time");
+                        continue;
+                    }
+                };
+
+                let resubscribed = rax["id"]
+                    .as_u64()
+                    .and_then(|id| resubscribing.remove(&id));
+                if let Some((subscription, _)) = resubscribed {
+                    let unsubscribe = move_resubscribed(sub_data, index, subscription, &rax);
+                    if let Some(unsubscribe) = unsubscribe {
+                        if ws_sender
+                            .send(Message::Text(unsubscribe.to_string()))
+                            .await
+                            .is_err()
+                        {
+                            return WsConnEnd::Dropped;
+                        }
+                    }
+                    continue;
                 }
-                Err(_) => {
-                    let _ = ws_error_tx.send(WsChannelErr::Closed(index));
-                    break;
+
+                let incoming = IncomingResponse {
+                    node_id: index,
+                    content: rax,
+                };
+
+                let _ = broadcast_tx.send(incoming);
+                let time = time.elapsed();
+                if let Some(position) = rpc_position(rpc_list, ws_url) {
+                    update_rpc_latency(rpc_list, position, time);
                 }
+                tracing::info!(?time, "WS request time");
             }
         }
-    });
+    }
+}
+
+/// Connect to `ws_url` again, waiting longer after every failed attempt.
+///
+/// Returns `None` if we ran out of attempts.
+async fn reconnect_ws(
+    ws_url: &url::Url,
+    name: &str,
+    reconnect: WsReconnectSettings,
+) -> Option<WebSocketStream<MaybeTlsStream<TcpStream>>> {
+    let mut backoff = Duration::from_millis(reconnect.initial_backoff_ms);
+    for attempt in 1..=reconnect.max_attempts {
+        tokio::time::sleep(backoff).await;
+
+        match connect_async(ws_url).await {
+            Ok((ws_stream, _)) => {
+                tracing::info!(name, attempt, "Reconnected to WS");
+                metrics::counter!("ws_reconnects_total", "rpc_name" => name.to_string())
+                    .increment(1);
+                return Some(ws_stream);
+            }
+            Err(err) => {
+                tracing::warn!(name, attempt, ?err, "Failed to reconnect to WS");
+            }
+        }
+        backoff = (backoff * 2).min(Duration::from_millis(reconnect.max_backoff_ms));
+    }
+
+    tracing::error!(name, "Giving up on reconnecting to WS");
+    None
+}
+
+/// Requests subscribing to everything we were subscribed to on node `index`,
+/// along with the subscriptions they're for, by request id.
+fn resubscribe_requests(
+    sub_data: &SubscriptionData,
+    index: usize,
+) -> HashMap<u64, (String, Value)> {
+    // Kept clear of the ids requests moving subscriptions between nodes use,
+    // which could be sent over this connection at the same time
+    let mut id = ((WS_RESUBSCRIBE_ID + MAGIC) as u64) << 32;
+    sub_data
+        .get_subscription_by_node(index)
+        .into_iter()
+        .filter_map(|subscription| {
+            let params: Value = serde_json::from_str(&subscription).ok()?;
+            id += 1;
+            let request = json!({"jsonrpc": "2.0", "id": id, "method": EthRpcMethod::Subscribe, "params": params});
+            Some((id, (subscription, request)))
+        })
+        .collect()
+}
+
+/// Move the users of `subscription` over to the subscription made again with `response`.
+///
+/// Returns a request unsubscribing from it if it isn't needed anymore.
+fn move_resubscribed(
+    sub_data: &SubscriptionData,
+    index: usize,
+    subscription: String,
+    response: &Value,
+) -> Option<Value> {
+    let Some(subscription_id) = response["result"].as_str() else {
+        tracing::error!(
+            subscription,
+            ?response,
+            "Failed to resubscribe after reconnecting"
+        );
+        return None;
+    };
+
+    match sub_data.move_subscriptions(index, subscription.clone(), subscription_id.to_string()) {
+        Ok(_) => {
+            tracing::info!(
+                subscription,
+                subscription_id,
+                "Resubscribed after reconnecting"
+            );
+            None
+        }
+        // Everyone unsubscribed while we were reconnecting
+        Err(err) => {
+            tracing::debug!(?err, subscription, "Dropping unused subscription");
+            sub_data.unregister_subscription(subscription);
+            Some(
+                json!({"jsonrpc": "2.0", "id": WS_SUB_MANAGER_ID, "method": EthRpcMethod::Unsubscribe, "params": [subscription_id]}),
+            )
+        }
+    }
 }
 
 /// Processes an individual RPC request received via WebSockets.
@@ -327,19 +717,34 @@ where
     );
 
     let id = call["id"].take();
-    let tx_hash = {
-        #[cfg(not(feature = "xxhash"))]
-        {
-            hash(call.to_string().as_bytes())
+
+    // Resuming subscriptions is up to us, RPCs know nothing about it
+    let subscription_id = call["params"][0].as_str().unwrap_or_default();
+    match call["method"].as_str() {
+        Some(RESUME_TOKEN_METHOD) => {
+            let result = sub_data
+                .resume_token(user_id, subscription_id)
+                .map(Value::from);
+            return Ok(replay_response(id, result));
         }
-        #[cfg(feature = "xxhash")]
-        {
-            xxh3_64(call.to_string().as_bytes())
+        Some(RESUME_METHOD) => {
+            let token = call["params"][1].as_str().unwrap_or_default();
+            let result = sub_data
+                .resume(user_id, subscription_id, token)
+                .map(Value::from);
+            return Ok(replay_response(id, result));
         }
-    };
+        _ => {}
+    }
+
+    let tx_hash = cache_args.cache_key(&call);
 
-    if let Ok(Some(mut rax)) = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned().into()) {
-        let mut cached: Value = from_slice(rax.as_mut()).unwrap();
+    let cached = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned().into())
+        .ok()
+        .flatten()
+        .and_then(|mut rax| decode_cached(rax.as_mut()).ok().flatten());
+    record_lookup(call["method"].as_str(), cached.is_some());
+    if let Some(mut cached) = cached {
         cached["id"] = id;
         return Ok(cached.to_string());
     }
@@ -378,6 +783,17 @@ where
 
     let is_subscription = call["method"].eq(&EthRpcMethod::Subscribe);
     if is_subscription {
+        if let Err(err) = sub_data.check_subscription_limits(user_id, &call) {
+            tracing::warn!(user_id, %err, "Rejected subscription");
+            metrics::counter!("ws_subscription_limit_rejections_total").increment(1);
+            return Ok(json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": SUBSCRIPTION_LIMIT_CODE, "message": err.to_string()},
+            })
+            .to_string());
+        }
+
         // Check if we're already subscribed to this
         // if so return the subscription id and add this user to the dispatch
         // if not continue
@@ -393,6 +809,32 @@ where
         call = replace_block_tags(&mut call, &cache_args.named_numbers);
     }
 
+    // Wait on an identical call that's already being fetched instead of sending another
+    let flight = if is_subscription {
+        Flight::Alone
+    } else {
+        cache_args
+            .in_flight
+            .join(tx_hash.as_bytes(), call["method"].as_str())
+    };
+    let leader = match flight {
+        Flight::Leader(leader) => Some(leader),
+        Flight::Follower(mut follower) => {
+            if let Ok(shared) = follower.recv().await {
+                return Ok(with_id(&shared, id));
+            }
+            None
+        }
+        Flight::Alone => None,
+    };
+
+    // Users might subscribe to something narrower than we do upstream
+    let mut subscription = Value::Null;
+    if is_subscription {
+        subscription = call.clone();
+        call = sub_data.upstream_subscription(call);
+    }
+
     call["id"] = user_id.into();
     incoming_tx.send(WsconnMessage::Message(call.clone(), None))?;
     let mut response = listen_for_response(user_id, broadcast_rx).await?;
@@ -412,16 +854,85 @@ where
         };
 
         tracing::info!(sub_id, "sub_id");
-        sub_data.register_subscription(call.clone(), sub_id.clone(), response.node_id);
-        sub_data.subscribe_user(user_id, call)?;
+        // Someone subscribed to the same filter while we were waiting, share theirs
+        if let Some(duplicate) =
+            sub_data.register_subscription(call.clone(), sub_id, response.node_id)
+        {
+            let unsub = json!({"jsonrpc": "2.0", "id": WS_SUB_MANAGER_ID, "method": EthRpcMethod::Unsubscribe, "params": [duplicate.subscription_id]});
+            let _ = incoming_tx.send(WsconnMessage::Message(unsub, Some(duplicate.node_id)));
+        }
+        // Users never see upstream subscription ids
+        response.content["result"] = sub_data.subscribe_user(user_id, subscription)?.into();
     } else {
-        cache_query(&mut response.content.to_string(), call, tx_hash, cache_args).await;
+        let mut content = response.content.to_string();
+        if let Some(leader) = leader {
+            leader.complete(&content);
+        }
+        cache_query(&mut content, call, tx_hash, cache_args).await;
     }
 
     response.content["id"] = id;
     Ok(response.content.to_string())
 }
 
+/// Same as [`execute_ws_call`], for an `eth_getLogs` call fetched in `chunks`.
+///
+/// Chunks are fetched one after another, since responses are told apart by
+/// `user_id`. The first error is passed on as it is.
+pub async fn execute_ws_logs<K, V>(
+    call: Value,
+    chunks: Vec<(u64, u64)>,
+    user_id: u32,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    broadcast_rx: &broadcast::Receiver<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    cache_args: &CacheArgs<K, V>,
+) -> Result<String, WsError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let mut logs = Vec::new();
+    for (from, to) in chunks {
+        let response = execute_ws_call(
+            LogQuery::narrow(&call, from, to),
+            user_id,
+            incoming_tx,
+            broadcast_rx.resubscribe(),
+            sub_data,
+            cache_args,
+        )
+        .await?;
+
+        let mut response: Value = match serde_json::from_str(&response) {
+            Ok(response) => response,
+            Err(_) => return Ok(response),
+        };
+        match response["result"].as_array_mut() {
+            Some(result) => logs.append(result),
+            None => return Ok(response.to_string()),
+        }
+    }
+
+    Ok(json!({
+        "jsonrpc": "2.0",
+        "id": call["id"],
+        "result": logs,
+    })
+    .to_string())
+}
+
+/// Response to a call resuming a subscription.
+fn replay_response(id: Value, result: Result<Value, ReplayError>) -> String {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(err) => {
+            json!({"jsonrpc": "2.0", "id": id, "error": {"code": REPLAY_ERROR_CODE, "message": err.to_string()}})
+        }
+    }
+    .to_string()
+}
+
 /// Listens for a respond corresponding to our internal `user_id`.
 async fn listen_for_response(
     user_id: u32,
@@ -437,7 +948,10 @@ async fn listen_for_response(
 
 #[cfg(test)]
 mod tests {
-    use crate::rpc::method::EthRpcMethod;
+    use crate::{
+        rpc::method::EthRpcMethod,
+        websocket::types::RequestResult,
+    };
 
     use super::*;
     use serde_json::json;
@@ -509,6 +1023,7 @@ mod tests {
 
         handle_incoming_message(
             &ws_handles,
+            &[],
             &rpc_list,
             incoming.clone(),
             Some(0),
@@ -521,6 +1036,74 @@ mod tests {
         assert_eq!(received, Some(incoming));
     }
 
+    #[tokio::test]
+    async fn test_fail_over() {
+        let rpc_list = create_mock_rpc_list().await;
+        let ws_nodes: Vec<url::Url> =
+            vec!["ws://test1".parse().unwrap(), "ws://test2".parse().unwrap()];
+        let (tx0, _rx0) = mpsc::unbounded_channel();
+        let (tx1, _rx1) = mpsc::unbounded_channel();
+        let ws_handles = Arc::new(RwLock::new(vec![Some(tx0), Some(tx1)]));
+        assert_eq!(
+            ws_node(&ws_handles.read().unwrap(), &ws_nodes, &ws_nodes[0]),
+            Some(0)
+        );
+
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        let (broadcast_tx, _) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let (user_tx, _user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+        let subscription = json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        sub_data.register_subscription(subscription.clone(), "0xold".to_string(), 0);
+        let id = sub_data.subscribe_user(1, subscription).unwrap();
+
+        // Nothing to do while everyone is in the pool
+        fail_over(
+            &rpc_list,
+            &ws_handles,
+            &ws_nodes,
+            &incoming_tx,
+            &broadcast_tx,
+            &sub_data,
+        );
+        assert!(ws_handles.read().unwrap().iter().all(Option::is_some));
+
+        // test1 goes unhealthy
+        rpc_list.write().unwrap().remove(0);
+        fail_over(
+            &rpc_list,
+            &ws_handles,
+            &ws_nodes,
+            &incoming_tx,
+            &broadcast_tx,
+            &sub_data,
+        );
+        assert!(ws_handles.read().unwrap()[0].is_none());
+        assert!(ws_handles.read().unwrap()[1].is_some());
+        assert_eq!(
+            ws_node(&ws_handles.read().unwrap(), &ws_nodes, &ws_nodes[0]),
+            None
+        );
+
+        // The subscription gets made again elsewhere, and the user keeps its id
+        let request_id = loop {
+            let Some(WsconnMessage::Message(request, None)) = incoming_rx.recv().await else {
+                continue;
+            };
+            assert_eq!(request["params"], json!(["newHeads"]));
+            break request["id"].clone();
+        };
+        broadcast_tx
+            .send(IncomingResponse {
+                content: json!({"jsonrpc": "2.0", "id": request_id, "result": "0xnew"}),
+                node_id: 1,
+            })
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(sub_data.get_node_from_id(&id), Some(1));
+    }
+
     #[tokio::test]
     async fn test_ws_conn_handling_error() {
         let (_rpc_list, incoming_tx, mut incoming_rx, _broadcast_tx, _ws_error_tx) =
@@ -538,6 +1121,54 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_execute_ws_logs() {
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
+        let sub_data = Arc::new(SubscriptionData::new());
+        let cache_args = CacheArgs::default();
+
+        // Answer every chunk with a log from its first block
+        tokio::spawn(async move {
+            while let Some(WsconnMessage::Message(call, _)) = incoming_rx.recv().await {
+                let response = IncomingResponse {
+                    content: json!({
+                        "jsonrpc": "2.0",
+                        "id": call["id"],
+                        "result": [{"blockNumber": call["params"][0]["fromBlock"]}],
+                    }),
+                    node_id: 0,
+                };
+                broadcast_tx.send(response).unwrap();
+            }
+        });
+
+        let call = json!({
+            "jsonrpc": "2.0",
+            "id": "logs",
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": "0x13"}],
+        });
+        let result = execute_ws_logs(
+            call,
+            vec![(0, 9), (10, 19)],
+            7,
+            &incoming_tx,
+            &broadcast_rx,
+            &sub_data,
+            &cache_args,
+        )
+        .await
+        .unwrap();
+
+        let result: Value = serde_json::from_str(&result).unwrap();
+        assert_eq!(result["id"], "logs");
+        assert_eq!(
+            result["result"],
+            json!([{"blockNumber": "0x0"}, {"blockNumber": "0xa"}])
+        );
+    }
+
     #[tokio::test]
     async fn test_execute_ws_subscription_and_call() {
         //
@@ -555,6 +1186,7 @@ mod tests {
             "method": EthRpcMethod::Subscribe,
             "params": ["newHeads"]
         });
+        let subscription = call.clone();
 
         // Simulate a response
         let b_clone = broadcast_tx.clone();
@@ -581,11 +1213,13 @@ mod tests {
         )
         .await;
 
-        assert!(result.is_ok());
+        // Clients get our id for the subscription, not the upstream one
+        let result: Value = serde_json::from_str(&result.unwrap()).unwrap();
         assert_eq!(
-            result.unwrap(),
-            "{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":\"0x1a2b3c\"}"
+            result["result"],
+            sub_data.subscribe_user(1, subscription).unwrap().as_str()
         );
+        assert_ne!(result["result"], "0x1a2b3c");
 
         //
         // Test calls
@@ -621,6 +1255,46 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_resubscribe_after_reconnect() {
+        let sub_data = SubscriptionData::new();
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+
+        let subscription = json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        sub_data.register_subscription(subscription.clone(), "0xold".to_string(), 0);
+        let id = sub_data.subscribe_user(1, subscription).unwrap();
+
+        let requests = resubscribe_requests(&sub_data, 0);
+        assert_eq!(requests.len(), 1);
+        let (request_id, (key, request)) = requests.into_iter().next().unwrap();
+        assert_eq!(request["id"], request_id);
+        assert_eq!(request["params"], json!(["newHeads"]));
+
+        // The user gets notifications from the new subscription, under the same id
+        let response = json!({"jsonrpc": "2.0", "id": request_id, "result": "0xnew"});
+        assert_eq!(
+            move_resubscribed(&sub_data, 0, key.clone(), &response),
+            None
+        );
+        let notification = json!({"method": EthRpcMethod::Subscription, "params": {"subscription": "0xnew", "result": {}}});
+        sub_data
+            .dispatch_to_subscribers("0xnew", 0, &RequestResult::Subscription(notification))
+            .await
+            .unwrap();
+        let Some(RequestResult::Subscription(msg)) = user_rx.recv().await else {
+            panic!("Expected to receive a subscription message");
+        };
+        assert_eq!(msg["params"]["subscription"], id.as_str());
+
+        // Subscriptions nobody uses anymore are unsubscribed from
+        sub_data.unsubscribe_user(1, id.clone());
+        let response = json!({"jsonrpc": "2.0", "id": request_id, "result": "0xnewer"});
+        let unsubscribe = move_resubscribed(&sub_data, 0, key, &response).unwrap();
+        assert_eq!(unsubscribe["params"], json!(["0xnewer"]));
+        assert_eq!(sub_data.get_node_from_id(&id), None);
+    }
+
     #[tokio::test]
     async fn test_listen_for_response() {
         let (broadcast_tx, broadcast_rx) = broadcast::channel(10);
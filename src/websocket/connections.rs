@@ -0,0 +1,391 @@
+//! Per-connection WebSocket accounting.
+//!
+//! Subscribers that spam calls, hold lots of subscriptions, or keep dropping
+//! their connection are hard to spot in aggregate numbers. We keep message
+//! and byte counts for every open connection, which the admin namespace can
+//! list, and count how connections ended by close code.
+//!
+//! Metrics are only exported in aggregate, labelling them per connection
+//! would create a new series for every client that ever connected.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+    time::Instant,
+};
+
+use rust_tracing::deps::metrics;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+/// Status code for connections that ended without a close frame.
+pub const CLOSE_ABNORMAL: u16 = 1006;
+/// Status code for connections we close because the client went idle.
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+/// Status code for close frames that don't carry one.
+pub const CLOSE_NO_STATUS: u16 = 1005;
+/// Status code for connections we close because the client didn't keep up.
+pub const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+/// Side that ended a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseInitiator {
+    /// The client sent a close frame or went away.
+    Client,
+    /// Blutgang dropped the connection after an error.
+    Server,
+}
+
+impl CloseInitiator {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Client => "client",
+            Self::Server => "server",
+        }
+    }
+}
+
+/// Counters of a single open connection.
+#[derive(Debug)]
+struct ConnectionStats {
+    peer: Option<SocketAddr>,
+    opened: Instant,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    subscriptions: AtomicUsize,
+}
+
+/// Snapshot of an open connection, as listed by the admin namespace.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: Option<String>,
+    pub connected_ms: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Average messages per second since the connection opened.
+    pub messages_in_per_sec: f64,
+    pub messages_out_per_sec: f64,
+    pub subscriptions: usize,
+}
+
+/// How many connections ended with `code`, closed by `initiator`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CloseCount {
+    pub initiator: CloseInitiator,
+    pub code: u16,
+    pub count: u64,
+}
+
+/// Open WebSocket connections, and how past ones were closed.
+#[derive(Debug, Default)]
+pub struct WsConnections {
+    next_id: AtomicU64,
+    open: Mutex<HashMap<u64, Arc<ConnectionStats>>>,
+    closes: Mutex<HashMap<(CloseInitiator, u16), u64>>,
+    // Set once we're shutting down, new connections get refused
+    draining: AtomicBool,
+    // Set once open connections should be closed, see `shutdown`
+    closing: AtomicBool,
+    // Notified when `closing` gets set
+    closing_notify: Notify,
+}
+
+impl WsConnections {
+    /// Start tracking a new connection. It's tracked until the returned handle is dropped.
+    pub fn open(self: &Arc<Self>, peer: Option<SocketAddr>) -> WsConnection {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stats = Arc::new(ConnectionStats {
+            peer,
+            opened: Instant::now(),
+            messages_in: AtomicU64::new(0),
+            messages_out: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            subscriptions: AtomicUsize::new(0),
+        });
+
+        self.open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, stats.clone());
+        metrics::gauge!("ws_connections").increment(1);
+
+        WsConnection {
+            id,
+            stats,
+            registry: self.clone(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    /// Refuse new connections, letting open ones carry on.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Tell every open connection to close, see `WsConnection::shutdown`.
+    pub fn close_all(&self) {
+        self.drain();
+        self.closing.store(true, Ordering::Relaxed);
+        self.closing_notify.notify_waiters();
+    }
+
+    /// Returns true if no connection is open.
+    pub fn is_empty(&self) -> bool {
+        self.open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+    }
+
+    /// Open connections, busiest first.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut connections: Vec<ConnectionInfo> = open
+            .iter()
+            .map(|(&id, stats)| {
+                let elapsed = stats.opened.elapsed();
+                let secs = elapsed.as_secs_f64().max(1.0);
+                let messages_in = stats.messages_in.load(Ordering::Relaxed);
+                let messages_out = stats.messages_out.load(Ordering::Relaxed);
+
+                ConnectionInfo {
+                    id,
+                    peer: stats.peer.map(|peer| peer.to_string()),
+                    connected_ms: elapsed.as_millis() as u64,
+                    messages_in,
+                    messages_out,
+                    bytes_in: stats.bytes_in.load(Ordering::Relaxed),
+                    bytes_out: stats.bytes_out.load(Ordering::Relaxed),
+                    messages_in_per_sec: messages_in as f64 / secs,
+                    messages_out_per_sec: messages_out as f64 / secs,
+                    subscriptions: stats.subscriptions.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+
+        connections.sort_by(|a, b| b.messages_in.cmp(&a.messages_in).then(a.id.cmp(&b.id)));
+        connections
+    }
+
+    /// How connections were closed, most common first.
+    pub fn closes(&self) -> Vec<CloseCount> {
+        let closes = self.closes.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut closes: Vec<CloseCount> = closes
+            .iter()
+            .map(|(&(initiator, code), &count)| {
+                CloseCount {
+                    initiator,
+                    code,
+                    count,
+                }
+            })
+            .collect();
+
+        closes.sort_by(|a, b| b.count.cmp(&a.count).then(a.code.cmp(&b.code)));
+        closes
+    }
+}
+
+/// Handle to a tracked connection.
+#[derive(Debug)]
+pub struct WsConnection {
+    id: u64,
+    stats: Arc<ConnectionStats>,
+    registry: Arc<WsConnections>,
+    closed: AtomicBool,
+}
+
+impl WsConnection {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Count a message received from the client.
+    pub fn received(&self, bytes: usize) {
+        self.stats.messages_in.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_in
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        metrics::counter!("ws_messages_total", "direction" => "in").increment(1);
+        metrics::counter!("ws_bytes_total", "direction" => "in").increment(bytes as u64);
+    }
+
+    /// Count a message sent to the client.
+    pub fn sent(&self, bytes: usize) {
+        self.stats.messages_out.fetch_add(1, Ordering::Relaxed);
+        self.stats
+            .bytes_out
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+        metrics::counter!("ws_messages_total", "direction" => "out").increment(1);
+        metrics::counter!("ws_bytes_total", "direction" => "out").increment(bytes as u64);
+    }
+
+    /// Completes once the connection should be closed because we're shutting down.
+    pub async fn shutdown(&self) {
+        loop {
+            let closing = self.registry.closing_notify.notified();
+            tokio::pin!(closing);
+            closing.as_mut().enable();
+
+            if self.registry.closing.load(Ordering::Relaxed) {
+                return;
+            }
+
+            closing.await;
+        }
+    }
+
+    pub fn set_subscriptions(&self, subscriptions: usize) {
+        self.stats
+            .subscriptions
+            .store(subscriptions, Ordering::Relaxed);
+    }
+
+    /// Record how the connection ended. Only the first call counts.
+    pub fn closed(&self, initiator: CloseInitiator, code: u16) {
+        if self.closed.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        *self
+            .registry
+            .closes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry((initiator, code))
+            .or_insert(0) += 1;
+        metrics::counter!(
+            "ws_closes_total",
+            "initiator" => initiator.as_str(),
+            "code" => code.to_string()
+        )
+        .increment(1);
+    }
+}
+
+impl Drop for WsConnection {
+    fn drop(&mut self) {
+        // Connections dropped without saying why didn't get a close handshake
+        self.closed(CloseInitiator::Client, CLOSE_ABNORMAL);
+
+        self.registry
+            .open
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.id);
+        metrics::gauge!("ws_connections").decrement(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_accounting() {
+        let registry = Arc::new(WsConnections::default());
+
+        let quiet = registry.open(None);
+        let busy = registry.open(Some("127.0.0.1:4000".parse().unwrap()));
+        busy.received(10);
+        busy.received(20);
+        busy.sent(100);
+        busy.set_subscriptions(2);
+
+        let connections = registry.connections();
+        assert_eq!(connections.len(), 2);
+        assert_eq!(connections[0].id, busy.id());
+        assert_eq!(connections[0].peer.as_deref(), Some("127.0.0.1:4000"));
+        assert_eq!(connections[0].messages_in, 2);
+        assert_eq!(connections[0].bytes_in, 30);
+        assert_eq!(connections[0].messages_out, 1);
+        assert_eq!(connections[0].bytes_out, 100);
+        assert_eq!(connections[0].subscriptions, 2);
+        assert_eq!(connections[1].id, quiet.id());
+        assert_eq!(connections[1].messages_in, 0);
+
+        // Closed connections are no longer listed
+        drop(quiet);
+        assert_eq!(registry.connections().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_close_all() {
+        let registry = Arc::new(WsConnections::default());
+        let connection = registry.open(None);
+        assert!(!registry.is_draining());
+
+        registry.drain();
+        assert!(registry.is_draining());
+        let waiting = tokio::spawn(async move {
+            connection.shutdown().await;
+            connection
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        registry.close_all();
+        drop(waiting.await.unwrap());
+        assert!(registry.is_empty());
+    }
+
+    #[test]
+    fn test_close_accounting() {
+        let registry = Arc::new(WsConnections::default());
+
+        let connection = registry.open(None);
+        connection.closed(CloseInitiator::Client, 1000);
+        // Only the first close counts
+        connection.closed(CloseInitiator::Server, 1011);
+        drop(connection);
+
+        registry.open(None).closed(CloseInitiator::Server, 1009);
+        // No close frame at all
+        drop(registry.open(None));
+        drop(registry.open(None));
+
+        assert_eq!(
+            registry.closes(),
+            vec![
+                CloseCount {
+                    initiator: CloseInitiator::Client,
+                    code: CLOSE_ABNORMAL,
+                    count: 2,
+                },
+                CloseCount {
+                    initiator: CloseInitiator::Client,
+                    code: 1000,
+                    count: 1,
+                },
+                CloseCount {
+                    initiator: CloseInitiator::Server,
+                    code: 1009,
+                    count: 1,
+                },
+            ]
+        );
+    }
+}
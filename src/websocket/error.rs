@@ -19,6 +19,7 @@ pub enum WsError {
     FailedParsing(),
     MissingSubscription(),
     EmptyList(String),
+    SubscriptionLimit(String),
     // SubscriptionError(String),
     // RpcError(String),
     NoWsResponse,
@@ -44,6 +45,7 @@ impl fmt::Display for WsError {
                 write!(f, "Tried to Perform Action On Non-Existing Subscription!")
             }
             WsError::EmptyList(msg) => write!(f, "Tried to Access Empty List: {}", msg),
+            WsError::SubscriptionLimit(msg) => write!(f, "Subscription limit exceeded: {}", msg),
             // Error::SubscriptionError(msg) => write!(f, "Subscription Error: {}", msg),
             // Error::RpcError(msg) => write!(f, "RPC Error: {}", msg),
             WsError::NoWsResponse => write!(f, "Failed to Receive Response from WS"),
@@ -0,0 +1,108 @@
+//! `newHeads` aggregation across RPCs.
+//!
+//! A `newHeads` subscription to a single RPC is only as fast as that RPC, and
+//! goes quiet if it misses a head. With `aggregate_heads` enabled, clients
+//! subscribing to `newHeads` are instead served from every healthy RPC with a
+//! `ws_url` at once, followed through their own subscriptions, see
+//! `head_staleness`.
+//!
+//! Every head is sent to clients as soon as the first RPC announces it. The
+//! same head coming from other RPCs later is dropped as a duplicate, by hash,
+//! so reorged heads still go through.
+
+use crate::websocket::types::{
+    RequestResult,
+    SubscriptionData,
+    AGGREGATED_NODE_ID,
+};
+
+use std::sync::Arc;
+
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::sync::mpsc;
+
+/// Upstream id of the `newHeads` subscription clients share.
+pub const AGGREGATED_HEADS_ID: &str = "aggregated_heads";
+
+/// Register the `newHeads` subscription served by `aggregate_heads`.
+///
+/// Has to happen before anyone subscribes to `newHeads`, or they'll get a
+/// subscription to a single RPC.
+pub fn register_aggregated_heads(sub_data: &SubscriptionData) {
+    sub_data.register_subscription(
+        json!({"params": ["newHeads"]}),
+        AGGREGATED_HEADS_ID.to_string(),
+        AGGREGATED_NODE_ID,
+    );
+}
+
+/// Send the `newHeads` notifications we get from every RPC on `heads_rx` to
+/// the clients subscribed to `newHeads`.
+pub async fn aggregate_heads(
+    mut heads_rx: mpsc::UnboundedReceiver<Value>,
+    sub_data: Arc<SubscriptionData>,
+) {
+    while let Some(notification) = heads_rx.recv().await {
+        let result = sub_data
+            .dispatch_to_subscribers(
+                AGGREGATED_HEADS_ID,
+                AGGREGATED_NODE_ID,
+                &RequestResult::Subscription(notification),
+            )
+            .await;
+
+        if let Err(err) = result {
+            tracing::error!(?err, "Failed to send aggregated newHeads notification");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::method::EthRpcMethod;
+
+    fn head(upstream_id: &str, hash: &str) -> Value {
+        json!({"jsonrpc": "2.0", "method": EthRpcMethod::Subscription, "params": {"subscription": upstream_id, "result": {"number": "0x10", "hash": hash}}})
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_heads() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        register_aggregated_heads(&sub_data);
+
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+        let subscription = json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        let id = sub_data.subscribe_user(1, subscription.clone()).unwrap();
+
+        let (heads_tx, heads_rx) = mpsc::unbounded_channel();
+        let aggregator = tokio::spawn(aggregate_heads(heads_rx, Arc::clone(&sub_data)));
+
+        // Two RPCs announce the same head, then one of them a reorged one
+        heads_tx.send(head("0xa", "0x01")).unwrap();
+        heads_tx.send(head("0xb", "0x01")).unwrap();
+        heads_tx.send(head("0xb", "0x02")).unwrap();
+        drop(heads_tx);
+        aggregator.await.unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(RequestResult::Subscription(msg)) = user_rx.try_recv() {
+            assert_eq!(msg["params"]["subscription"], id.as_str());
+            received.push(msg["params"]["result"]["hash"].clone());
+        }
+        assert_eq!(received, vec!["0x01", "0x02"]);
+
+        // The subscription outlives its users
+        sub_data.unsubscribe_user(1, id.clone());
+        let (heads_tx, heads_rx) = mpsc::unbounded_channel();
+        heads_tx.send(head("0xa", "0x03")).unwrap();
+        drop(heads_tx);
+        aggregate_heads(heads_rx, Arc::clone(&sub_data)).await;
+        assert_eq!(sub_data.subscribe_user(1, subscription).unwrap(), id);
+        assert_eq!(sub_data.get_node_from_id(&id), Some(AGGREGATED_NODE_ID));
+    }
+}
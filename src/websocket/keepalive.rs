@@ -0,0 +1,92 @@
+//! Keepalive for WebSocket connections, to clients and to RPCs alike.
+//!
+//! Connections where the other side went away without closing them would
+//! otherwise stay open forever. We ping every `ping_interval_ms`, and close
+//! connections we haven't received anything from, pongs included, in
+//! `idle_timeout_ms`. Either can be disabled by setting it to `0`.
+
+use crate::config::types::WsConnectionSettings;
+
+use std::{
+    future::pending,
+    time::Duration,
+};
+
+use tokio::time::{
+    interval_at,
+    sleep_until,
+    Instant,
+    Interval,
+    MissedTickBehavior,
+};
+
+impl WsConnectionSettings {
+    /// Interval to send pings at, starting one interval from now.
+    ///
+    /// Returns `None` if pings are disabled.
+    pub fn pings(&self) -> Option<Interval> {
+        if self.ping_interval_ms == 0 {
+            return None;
+        }
+
+        let period = Duration::from_millis(self.ping_interval_ms);
+        let mut pings = interval_at(Instant::now() + period, period);
+        pings.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Some(pings)
+    }
+
+    /// Completes once a connection we last received something from at
+    /// `last_received` counts as idle, or never if the timeout is disabled.
+    pub async fn idle(&self, last_received: Instant) {
+        if self.idle_timeout_ms == 0 {
+            return pending().await;
+        }
+
+        sleep_until(last_received + Duration::from_millis(self.idle_timeout_ms)).await
+    }
+}
+
+/// Completes when the next ping is due, or never if pings are disabled.
+pub async fn next_ping(pings: &mut Option<Interval>) {
+    match pings {
+        Some(pings) => {
+            pings.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_keepalive() {
+        let settings = WsConnectionSettings {
+            ping_interval_ms: 20,
+            idle_timeout_ms: 50,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let mut pings = settings.pings();
+        next_ping(&mut pings).await;
+        next_ping(&mut pings).await;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+
+        settings.idle(start).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        // Disabled, nothing ever completes
+        let disabled = WsConnectionSettings {
+            ping_interval_ms: 0,
+            idle_timeout_ms: 0,
+            ..Default::default()
+        };
+        assert!(disabled.pings().is_none());
+        let wait = Duration::from_millis(100);
+        assert!(timeout(wait, next_ping(&mut None)).await.is_err());
+        assert!(timeout(wait, disabled.idle(Instant::now())).await.is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Log filters we evaluate ourselves.
+//!
+//! Clients rarely subscribe to the exact same `logs` filter, so sharing
+//! subscriptions by filter doesn't do much for them. With `local_log_filters`
+//! enabled, every `logs` subscription is instead served from a single upstream
+//! subscription to every log, and we match each log against the filter of
+//! every client before forwarding it, the same way nodes do.
+
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Params of the upstream subscription every `logs` subscription is served from.
+pub fn all_logs_params() -> Value {
+    json!(["logs", {}])
+}
+
+/// Addresses and topics a `logs` subscription is filtered by.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogFilter {
+    // Any of these, or any address if empty
+    addresses: Vec<String>,
+    // Any of the topics at each position, or any topic if empty
+    topics: Vec<Vec<String>>,
+}
+
+impl LogFilter {
+    /// Parse the filter of an `eth_subscribe` request.
+    ///
+    /// Returns `None` if it isn't a `logs` subscription, or its filter is malformed.
+    pub fn from_subscription(subscription: &Value) -> Option<Self> {
+        let params = subscription["params"].as_array()?;
+        if params.first()?.as_str()? != "logs" {
+            return None;
+        }
+
+        match params.as_slice() {
+            [_] => Some(Self::default()),
+            [_, filter] if filter.is_object() || filter.is_null() => Self::parse(filter),
+            _ => None,
+        }
+    }
+
+    /// Parse a filter object, like `{"address": "0x..", "topics": [["0x.."], null]}`.
+    pub fn parse(filter: &Value) -> Option<Self> {
+        let addresses = match &filter["address"] {
+            Value::Null => Vec::new(),
+            Value::String(address) => vec![address.clone()],
+            Value::Array(addresses) => strings(addresses)?,
+            _ => return None,
+        };
+
+        let topics = match &filter["topics"] {
+            Value::Null => Vec::new(),
+            Value::Array(topics) => {
+                topics
+                    .iter()
+                    .map(|topic| {
+                        match topic {
+                            Value::Null => Some(Vec::new()),
+                            Value::String(topic) => Some(vec![topic.clone()]),
+                            Value::Array(topics) => strings(topics),
+                            _ => None,
+                        }
+                    })
+                    .collect::<Option<_>>()?
+            }
+            _ => return None,
+        };
+
+        Some(Self { addresses, topics })
+    }
+
+    /// Number of addresses the filter matches.
+    pub fn address_count(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Number of topics the filter matches, over every position.
+    pub fn topic_count(&self) -> usize {
+        self.topics.iter().map(Vec::len).sum()
+    }
+
+    /// Returns true if `log` passes the filter.
+    ///
+    /// Logs need at least as many topics as the filter has positions, even
+    /// if those allow any topic.
+    pub fn matches(&self, log: &Value) -> bool {
+        if !self.addresses.is_empty() {
+            let Some(address) = log["address"].as_str() else {
+                return false;
+            };
+            if !self
+                .addresses
+                .iter()
+                .any(|wanted| wanted.eq_ignore_ascii_case(address))
+            {
+                return false;
+            }
+        }
+
+        let topics = log["topics"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if self.topics.len() > topics.len() {
+            return false;
+        }
+
+        self.topics.iter().zip(topics).all(|(wanted, topic)| {
+            wanted.is_empty()
+                || topic.as_str().is_some_and(|topic| {
+                    wanted
+                        .iter()
+                        .any(|wanted| wanted.eq_ignore_ascii_case(topic))
+                })
+        })
+    }
+}
+
+fn strings(values: &[Value]) -> Option<Vec<String>> {
+    values
+        .iter()
+        .map(|value| value.as_str().map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(address: &str, topics: &[&str]) -> Value {
+        json!({"address": address, "topics": topics, "data": "0x", "logIndex": "0x0"})
+    }
+
+    #[test]
+    fn test_from_subscription() {
+        let subscription = |params: Value| json!({"method": "eth_subscribe", "params": params});
+
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(json!(["logs"]))),
+            Some(LogFilter::default())
+        );
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(
+                json!(["logs", {"address": "0xAB", "topics": [null, ["0x1", "0x2"]]}])
+            )),
+            Some(LogFilter {
+                addresses: vec!["0xAB".to_string()],
+                topics: vec![vec![], vec!["0x1".to_string(), "0x2".to_string()]],
+            })
+        );
+
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(json!(["newHeads"]))),
+            None
+        );
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(json!(["logs", {"address": 1}]))),
+            None
+        );
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(json!(["logs", {"topics": [1]}]))),
+            None
+        );
+        assert_eq!(
+            LogFilter::from_subscription(&subscription(json!(["logs", "0xAB"]))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let everything = LogFilter::default();
+        assert!(everything.matches(&log("0xab", &[])));
+
+        let filter = LogFilter::parse(&json!({"address": ["0xAB", "0xCD"]})).unwrap();
+        assert!(filter.matches(&log("0xab", &["0x1"])));
+        assert!(filter.matches(&log("0xCD", &[])));
+        assert!(!filter.matches(&log("0xef", &["0x1"])));
+
+        let filter = LogFilter::parse(&json!({"topics": [null, ["0x2", "0x3"]]})).unwrap();
+        assert!(filter.matches(&log("0xab", &["0x1", "0x3"])));
+        assert!(!filter.matches(&log("0xab", &["0x1", "0x4"])));
+        // Not enough topics, even though the first one could be anything
+        assert!(!filter.matches(&log("0xab", &["0x2"])));
+
+        let filter = LogFilter::parse(&json!({"address": "0xab", "topics": ["0xA1"]})).unwrap();
+        assert!(filter.matches(&log("0xAB", &["0xa1", "0x2"])));
+        assert!(!filter.matches(&log("0xcd", &["0xa1"])));
+    }
+}
@@ -17,7 +17,15 @@
 //! All of this happens so that user don't need to take any actions in case of node failiures.
 
 pub mod client;
+pub mod connections;
 pub mod error;
+pub mod head_aggregation;
+pub mod keepalive;
+pub mod log_filter;
+pub mod pending_aggregation;
+pub mod replay;
+pub mod send_queue;
 pub mod server;
+pub mod shutdown;
 pub mod subscription_manager;
 pub mod types;
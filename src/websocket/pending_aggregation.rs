@@ -0,0 +1,428 @@
+//! `newPendingTransactions` aggregation across RPCs.
+//!
+//! Every node only sees the part of the mempool that reached it. With
+//! `aggregate_pending_txs` enabled, clients subscribing to
+//! `newPendingTransactions` are served the pending transactions of every
+//! healthy RPC with a `ws_url` instead, each sent once no matter how many RPCs
+//! announce it.
+//!
+//! Clients subscribing with `["newPendingTransactions", true]` get the full
+//! transactions, which we fetch from the RPC that announced them, and only
+//! while someone is subscribed to them.
+//!
+//! Upstream subscriptions are only kept open while clients are subscribed, and
+//! follow the active pool: RPCs added at runtime get one, and RPCs leaving the
+//! pool have theirs closed.
+
+use crate::{
+    rpc::{
+        method::EthRpcMethod,
+        types::Rpc,
+    },
+    websocket::types::{
+        RequestResult,
+        SubscriptionData,
+        AGGREGATED_NODE_ID,
+    },
+};
+
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures_util::{
+    SinkExt,
+    StreamExt,
+};
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::{
+        mpsc,
+        Semaphore,
+    },
+    task::JoinHandle,
+    time::{
+        sleep,
+        timeout,
+    },
+};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::protocol::Message,
+};
+
+/// Upstream id of the `newPendingTransactions` subscription clients share.
+pub const AGGREGATED_PENDING_ID: &str = "aggregated_pending";
+/// Upstream id of the full transaction variant clients share.
+pub const AGGREGATED_FULL_PENDING_ID: &str = "aggregated_full_pending";
+
+/// Number of transaction hashes we remember to drop ones announced again.
+///
+/// Pending transactions come in much faster than heads, and RPCs can announce
+/// them seconds apart.
+const SEEN_CAPACITY: usize = 16384;
+
+/// Time to wait before reconnecting a dropped subscription.
+const RECONNECT_DELAY: Duration = Duration::from_millis(1000);
+
+/// How often to check for subscribers and changes to the active pool.
+const WATCH_INTERVAL: Duration = Duration::from_millis(1000);
+
+/// Most full transactions we fetch at once, further ones are dropped.
+const MAX_FULL_FETCHES: usize = 64;
+
+const PENDING_SUBSCRIPTION: &str =
+    r#"{"jsonrpc":"2.0","method":"eth_subscribe","params":["newPendingTransactions"],"id":1}"#;
+
+/// Register the subscriptions served by `aggregate_pending`.
+///
+/// Has to happen before anyone subscribes to `newPendingTransactions`, or
+/// they'll get a subscription to a single RPC.
+pub fn register_aggregated_pending(sub_data: &SubscriptionData) {
+    sub_data.register_subscription(
+        json!({"params": ["newPendingTransactions"]}),
+        AGGREGATED_PENDING_ID.to_string(),
+        AGGREGATED_NODE_ID,
+    );
+    sub_data.register_subscription(
+        json!({"params": ["newPendingTransactions", true]}),
+        AGGREGATED_FULL_PENDING_ID.to_string(),
+        AGGREGATED_NODE_ID,
+    );
+}
+
+/// Hashes of the transactions we've already sent.
+#[derive(Debug, Default)]
+struct Seen {
+    hashes: HashSet<String>,
+    order: VecDeque<String>,
+}
+
+impl Seen {
+    /// Returns true if `hash` wasn't seen before, remembering it.
+    fn insert(&mut self, hash: &str) -> bool {
+        let hash = hash.to_lowercase();
+        if !self.hashes.insert(hash.clone()) {
+            return false;
+        }
+
+        if self.order.len() == SEEN_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.hashes.remove(&oldest);
+            }
+        }
+        self.order.push_back(hash);
+        true
+    }
+}
+
+/// Returns true if any client is subscribed to aggregated pending transactions.
+fn has_subscribers(sub_data: &SubscriptionData) -> bool {
+    !sub_data
+        .get_users_for_subscription(AGGREGATED_PENDING_ID)
+        .is_empty()
+        || !sub_data
+            .get_users_for_subscription(AGGREGATED_FULL_PENDING_ID)
+            .is_empty()
+}
+
+fn notification(upstream_id: &str, result: Value) -> RequestResult {
+    RequestResult::Subscription(json!({
+        "jsonrpc": "2.0",
+        "method": EthRpcMethod::Subscription,
+        "params": {"subscription": upstream_id, "result": result},
+    }))
+}
+
+/// Send the pending transaction hashes we get from every RPC on `pending_rx`,
+/// along with the name of the RPC, to the clients subscribed to them.
+pub async fn aggregate_pending(
+    mut pending_rx: mpsc::UnboundedReceiver<(String, String)>,
+    sub_data: Arc<SubscriptionData>,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+) {
+    let mut seen = Seen::default();
+    let fetches = Arc::new(Semaphore::new(MAX_FULL_FETCHES));
+
+    while let Some((name, hash)) = pending_rx.recv().await {
+        if !seen.insert(&hash) {
+            metrics::counter!("ws_duplicate_pending_txs_total").increment(1);
+            continue;
+        }
+
+        let result = sub_data
+            .dispatch_to_subscribers(
+                AGGREGATED_PENDING_ID,
+                AGGREGATED_NODE_ID,
+                &notification(AGGREGATED_PENDING_ID, hash.clone().into()),
+            )
+            .await;
+        if let Err(err) = result {
+            tracing::error!(?err, "Failed to send aggregated pending transaction");
+        }
+
+        if sub_data
+            .get_users_for_subscription(AGGREGATED_FULL_PENDING_ID)
+            .is_empty()
+        {
+            continue;
+        }
+        let rpc = rpc_list
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|rpc| rpc.name == name)
+            .cloned();
+        let Some(rpc) = rpc else {
+            continue;
+        };
+        let Ok(permit) = Arc::clone(&fetches).try_acquire_owned() else {
+            metrics::counter!("ws_pending_fetches_dropped_total").increment(1);
+            continue;
+        };
+        let sub_data = Arc::clone(&sub_data);
+        tokio::spawn(async move {
+            send_full_transaction(rpc, hash, sub_data).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Fetch the transaction with `hash` from `rpc`, and send it to the clients
+/// subscribed to full pending transactions.
+async fn send_full_transaction(rpc: Rpc, hash: String, sub_data: Arc<SubscriptionData>) {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": EthRpcMethod::GetTransactionByHash,
+        "params": [hash],
+    });
+    let Ok(response) = rpc.send_request(request).await else {
+        return;
+    };
+    let Ok(mut response) = serde_json::from_str::<Value>(&response) else {
+        return;
+    };
+
+    // Already mined or dropped
+    let transaction = response["result"].take();
+    if !transaction.is_object() {
+        return;
+    }
+
+    let result = sub_data
+        .dispatch_to_subscribers(
+            AGGREGATED_FULL_PENDING_ID,
+            AGGREGATED_NODE_ID,
+            &notification(AGGREGATED_FULL_PENDING_ID, transaction),
+        )
+        .await;
+    if let Err(err) = result {
+        tracing::error!(?err, "Failed to send aggregated pending transaction");
+    }
+}
+
+/// Returns true if `rpc` is in the active pool.
+fn is_active(rpc_list: &RwLock<Vec<Rpc>>, name: &str) -> bool {
+    rpc_list
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .any(|rpc| rpc.name == name)
+}
+
+/// Keep a `newPendingTransactions` subscription open to `rpc`, reconnecting
+/// if it drops.
+///
+/// Hashes are sent to `pending_tx`. Returns once `rpc` leaves the active pool
+/// or nobody is subscribed anymore, closing the subscription.
+async fn follow_pending(
+    rpc: Rpc,
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    sub_data: Arc<SubscriptionData>,
+    pending_tx: mpsc::UnboundedSender<(String, String)>,
+) {
+    let Some(ws_url) = rpc.ws_url.clone() else {
+        return;
+    };
+    let following =
+        || !pending_tx.is_closed() && has_subscribers(&sub_data) && is_active(&rpc_list, &rpc.name);
+
+    while following() {
+        let ws_stream = match connect_async(&ws_url).await {
+            Ok((ws_stream, _)) => ws_stream,
+            Err(e) => {
+                tracing::warn!(
+                    ?e,
+                    "Couldn't open newPendingTransactions subscription to {}",
+                    rpc.name
+                );
+                sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        let (mut ws_sender, mut ws_receiver) = ws_stream.split();
+
+        if ws_sender
+            .send(Message::Text(PENDING_SUBSCRIPTION.to_string()))
+            .await
+            .is_err()
+        {
+            sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+
+        loop {
+            // Wake up now and then to notice when to stop on quiet RPCs
+            let message = match timeout(WATCH_INTERVAL, ws_receiver.next()).await {
+                Ok(Some(Ok(message))) => Some(message),
+                Ok(_) => break,
+                Err(_) => None,
+            };
+            if !following() {
+                let _ = ws_sender.close().await;
+                return;
+            }
+
+            let Some(Ok(text)) = message.map(Message::into_text) else {
+                continue;
+            };
+            let Ok(notification) = serde_json::from_str::<Value>(&text) else {
+                continue;
+            };
+            let Some(hash) = notification["params"]["result"].as_str() else {
+                continue;
+            };
+            if pending_tx
+                .send((rpc.name.clone(), hash.to_string()))
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        tracing::warn!(
+            "newPendingTransactions subscription to {} dropped! Reconnecting...",
+            rpc.name
+        );
+        sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Follow the pending transactions of every RPC in the active pool that has a
+/// `ws_url`, while anyone is subscribed to them.
+///
+/// Runs until the receiving end of `pending_tx` is dropped.
+pub async fn watch_pending(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
+    sub_data: Arc<SubscriptionData>,
+    pending_tx: mpsc::UnboundedSender<(String, String)>,
+) {
+    let mut watchers: HashMap<String, JoinHandle<()>> = HashMap::new();
+
+    while !pending_tx.is_closed() {
+        watchers.retain(|_, watcher| !watcher.is_finished());
+
+        if has_subscribers(&sub_data) {
+            let rpcs = rpc_list.read().unwrap_or_else(|e| e.into_inner()).clone();
+            for rpc in rpcs.into_iter().filter(|rpc| rpc.ws_url.is_some()) {
+                if watchers.contains_key(&rpc.name) {
+                    continue;
+                }
+                let name = rpc.name.clone();
+                let watcher = tokio::spawn(follow_pending(
+                    rpc,
+                    Arc::clone(&rpc_list),
+                    Arc::clone(&sub_data),
+                    pending_tx.clone(),
+                ));
+                watchers.insert(name, watcher);
+            }
+        }
+
+        sleep(WATCH_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seen() {
+        let mut seen = Seen::default();
+        assert!(seen.insert("0xAB"));
+        assert!(!seen.insert("0xab"));
+
+        for i in 0..SEEN_CAPACITY {
+            assert!(seen.insert(&format!("{:#x}", i)));
+        }
+        // Forgotten to make room
+        assert!(seen.insert("0xab"));
+        assert_eq!(seen.order.len(), SEEN_CAPACITY);
+        assert_eq!(seen.hashes.len(), SEEN_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn test_aggregate_pending() {
+        let sub_data = Arc::new(SubscriptionData::new());
+        register_aggregated_pending(&sub_data);
+
+        let (user_tx, mut user_rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, user_tx);
+        assert!(!has_subscribers(&sub_data));
+        let subscription =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["newPendingTransactions"]});
+        let id = sub_data.subscribe_user(1, subscription).unwrap();
+        assert!(has_subscribers(&sub_data));
+
+        // Two RPCs announce the same transaction, one of them another one
+        let (pending_tx, pending_rx) = mpsc::unbounded_channel();
+        pending_tx
+            .send(("a".to_string(), "0x01".to_string()))
+            .unwrap();
+        pending_tx
+            .send(("b".to_string(), "0x01".to_string()))
+            .unwrap();
+        pending_tx
+            .send(("b".to_string(), "0x02".to_string()))
+            .unwrap();
+        drop(pending_tx);
+        aggregate_pending(
+            pending_rx,
+            Arc::clone(&sub_data),
+            Arc::new(RwLock::new(Vec::new())),
+        )
+        .await;
+
+        let mut received = Vec::new();
+        while let Ok(RequestResult::Subscription(msg)) = user_rx.try_recv() {
+            assert_eq!(msg["params"]["subscription"], id.as_str());
+            received.push(msg["params"]["result"].clone());
+        }
+        assert_eq!(received, vec!["0x01", "0x02"]);
+
+        // The full variant is a subscription of its own
+        let full =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["newPendingTransactions", true]});
+        let full_id = sub_data.subscribe_user(1, full).unwrap();
+        assert_ne!(full_id, id);
+        assert_eq!(
+            sub_data.get_node_from_id(&full_id),
+            Some(AGGREGATED_NODE_ID)
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//! Replaying notifications to clients that reconnect.
+//!
+//! Clients that lose their connection miss every notification sent until
+//! they're back, and have to find out what they missed themselves. With
+//! `ws_replay` enabled, we keep the latest notifications of shared `newHeads`
+//! and `logs` subscriptions, and give each of them a resume token, sent along
+//! as `params.resumeToken`. Tokens can also be requested with
+//! `blutgang_resumeToken`, passing the subscription id.
+//!
+//! After reconnecting and subscribing again, clients call `blutgang_resume`
+//! with the subscription id and the last token they got, and get sent every
+//! notification since. Subscriptions are kept for `window_ms` after their
+//! last client leaves, so they're still there to resume.
+//!
+//! Tokens only resume subscriptions served upstream, logs we filter
+//! ourselves aren't kept.
+
+use std::{
+    collections::VecDeque,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use rand::random;
+use serde_json::Value;
+
+/// Method clients resume a subscription with.
+pub const RESUME_METHOD: &str = "blutgang_resume";
+/// Method clients get the latest resume token of a subscription with.
+pub const RESUME_TOKEN_METHOD: &str = "blutgang_resumeToken";
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ReplayError {
+    #[error("Not subscribed to a resumable subscription")]
+    NotResumable,
+    #[error("Invalid resume token")]
+    InvalidToken,
+    #[error("Resume token expired, notifications were missed")]
+    Expired,
+}
+
+/// Latest notifications of a subscription, for clients to resume from.
+#[derive(Debug)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    window: Duration,
+    // Tells tokens apart from those of earlier buffers of the same subscription
+    epoch: u32,
+    next_seq: u64,
+    entries: VecDeque<(u64, Instant, Value)>,
+    // Since when the subscription has had no users
+    idle_since: Option<Instant>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        Self {
+            capacity,
+            window,
+            epoch: random(),
+            next_seq: 0,
+            entries: VecDeque::with_capacity(capacity),
+            idle_since: None,
+        }
+    }
+
+    fn token(&self, seq: u64) -> String {
+        format!("{:x}-{:x}", self.epoch, seq)
+    }
+
+    // Forget notifications older than the window
+    fn prune(&mut self) {
+        while self
+            .entries
+            .front()
+            .is_some_and(|(_, received, _)| received.elapsed() > self.window)
+        {
+            self.entries.pop_front();
+        }
+    }
+
+    /// Keep `notification`, setting its resume token.
+    pub fn push(&mut self, notification: &mut Value) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        notification["params"]["resumeToken"] = self.token(seq).into();
+
+        self.prune();
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries
+            .push_back((seq, Instant::now(), notification.clone()));
+    }
+
+    /// Token of the latest notification, if there was any.
+    pub fn latest_token(&self) -> Option<String> {
+        self.next_seq.checked_sub(1).map(|seq| self.token(seq))
+    }
+
+    /// Every notification since the one `token` was given with.
+    pub fn since(&mut self, token: &str) -> Result<Vec<Value>, ReplayError> {
+        let (epoch, seq) = token.split_once('-').ok_or(ReplayError::InvalidToken)?;
+        let epoch = u32::from_str_radix(epoch, 16).map_err(|_| ReplayError::InvalidToken)?;
+        let seq = u64::from_str_radix(seq, 16).map_err(|_| ReplayError::InvalidToken)?;
+        if epoch != self.epoch {
+            return Err(ReplayError::Expired);
+        }
+        if seq >= self.next_seq {
+            return Err(ReplayError::InvalidToken);
+        }
+
+        self.prune();
+        // The notification right after the token has to still be here
+        let first = self
+            .entries
+            .front()
+            .map_or(self.next_seq, |(first, _, _)| *first);
+        if seq + 1 < first {
+            return Err(ReplayError::Expired);
+        }
+
+        Ok(self
+            .entries
+            .iter()
+            .filter(|(entry, _, _)| *entry > seq)
+            .map(|(_, _, notification)| notification.clone())
+            .collect())
+    }
+
+    /// Returns true if the subscription should be kept, for users to resume,
+    /// whether it has users or not.
+    pub fn keep(&mut self, has_users: bool) -> bool {
+        if has_users {
+            self.idle_since = None;
+            return true;
+        }
+
+        self.idle_since.get_or_insert_with(Instant::now).elapsed() <= self.window
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn head(number: u64) -> Value {
+        json!({"params": {"subscription": "0x1", "result": {"number": number}}})
+    }
+
+    #[test]
+    fn test_since() {
+        let mut buffer = ReplayBuffer::new(3, Duration::from_secs(60));
+        assert_eq!(buffer.latest_token(), None);
+
+        let mut tokens = Vec::new();
+        for number in 0..5 {
+            let mut notification = head(number);
+            buffer.push(&mut notification);
+            tokens.push(
+                notification["params"]["resumeToken"]
+                    .as_str()
+                    .unwrap()
+                    .to_string(),
+            );
+        }
+        assert_eq!(buffer.latest_token().as_ref(), tokens.last());
+
+        let numbers = |notifications: Vec<Value>| {
+            notifications
+                .iter()
+                .map(|notification| notification["params"]["result"]["number"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(buffer.since(&tokens[1]).unwrap()), vec![2, 3, 4]);
+        assert_eq!(numbers(buffer.since(&tokens[3]).unwrap()), vec![4]);
+        assert!(buffer.since(&tokens[4]).unwrap().is_empty());
+
+        // Head 1 is gone, so the one after token 0 was missed
+        assert_eq!(buffer.since(&tokens[0]), Err(ReplayError::Expired));
+        assert_eq!(buffer.since("nonsense"), Err(ReplayError::InvalidToken));
+        assert_eq!(
+            buffer.since(&format!("{:x}-ff", buffer.epoch)),
+            Err(ReplayError::InvalidToken)
+        );
+
+        // Tokens of an earlier buffer for the same subscription
+        assert_eq!(
+            buffer.since(&format!("{:x}-3", buffer.epoch.wrapping_add(1))),
+            Err(ReplayError::Expired)
+        );
+    }
+
+    #[test]
+    fn test_window() {
+        let mut buffer = ReplayBuffer::new(8, Duration::from_millis(20));
+        let mut notification = head(0);
+        buffer.push(&mut notification);
+        let token = buffer.latest_token().unwrap();
+        buffer.push(&mut head(1));
+
+        assert!(buffer.keep(true));
+        assert!(buffer.keep(false));
+        std::thread::sleep(Duration::from_millis(30));
+
+        // Too old to be resumed from, and kept for too long without users
+        assert_eq!(buffer.since(&token), Err(ReplayError::Expired));
+        assert!(!buffer.keep(false));
+        assert!(buffer.keep(true));
+    }
+}
@@ -0,0 +1,272 @@
+//! Bounded queues of messages waiting to be sent to WS clients.
+//!
+//! A client that stops reading would otherwise have its notifications pile up
+//! in memory forever. Once a queue holds `send_queue_size` notifications,
+//! new ones either push out the oldest one, or get the client disconnected,
+//! depending on the subscription, see `OverflowPolicy`.
+//!
+//! Responses to calls are always queued, since clients wait for them and
+//! can't send calls faster than we read them.
+
+use crate::{
+    config::types::{
+        OverflowPolicy,
+        WsConnectionSettings,
+    },
+    websocket::{
+        error::WsError,
+        types::RequestResult,
+    },
+};
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+use tokio::sync::Notify;
+
+/// Messages waiting to be sent to a single client.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Queued {
+    messages: VecDeque<RequestResult>,
+    // Number of `messages` that are notifications
+    notifications: usize,
+}
+
+impl Queued {
+    fn pop_front(&mut self) -> Option<RequestResult> {
+        let message = self.messages.pop_front()?;
+        if matches!(message, RequestResult::Subscription(_)) {
+            self.notifications -= 1;
+        }
+        Some(message)
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    queued: Mutex<Queued>,
+    // Notified whenever a message is queued, or the queue gets closed
+    changed: Notify,
+    closed: AtomicBool,
+    // Closed because the client didn't keep up
+    overflowed: AtomicBool,
+    capacity: usize,
+    logs_overflow: OverflowPolicy,
+    overflow: OverflowPolicy,
+}
+
+/// Returns true if `notification` is for a `logs` subscription.
+fn is_log(notification: &Value) -> bool {
+    notification["params"]["result"]["logIndex"].is_string()
+}
+
+impl SendQueue {
+    pub fn new(settings: &WsConnectionSettings) -> Self {
+        SendQueue {
+            inner: Arc::new(Inner {
+                queued: Mutex::new(Queued::default()),
+                changed: Notify::new(),
+                closed: AtomicBool::new(false),
+                overflowed: AtomicBool::new(false),
+                capacity: settings.send_queue_size,
+                logs_overflow: settings.logs_overflow,
+                overflow: settings.overflow,
+            }),
+        }
+    }
+
+    /// Queue `message` to be sent to the client.
+    ///
+    /// Returns error if the queue is closed, including when it just got closed
+    /// because it's full and the notification calls for disconnecting.
+    pub fn send(&self, message: RequestResult) -> Result<(), WsError> {
+        if self.is_closed() {
+            return Err(WsError::ChannelClosed());
+        }
+
+        {
+            let mut queued = self.inner.queued.lock().unwrap_or_else(|e| e.into_inner());
+            if let RequestResult::Subscription(notification) = &message {
+                if self.inner.capacity != 0 && queued.notifications >= self.inner.capacity {
+                    let policy = match is_log(notification) {
+                        true => self.inner.logs_overflow,
+                        false => self.inner.overflow,
+                    };
+                    match policy {
+                        OverflowPolicy::DropOldest => {
+                            metrics::counter!("ws_dropped_notifications_total", "policy" => "drop_oldest").increment(1);
+                            if let Some(oldest) = queued.messages.iter().position(|message| {
+                                matches!(message, RequestResult::Subscription(_))
+                            }) {
+                                queued.messages.remove(oldest);
+                                queued.notifications -= 1;
+                            }
+                        }
+                        OverflowPolicy::Disconnect => {
+                            metrics::counter!("ws_dropped_notifications_total", "policy" => "disconnect").increment(1);
+                            drop(queued);
+                            self.inner.overflowed.store(true, Ordering::Relaxed);
+                            self.close();
+                            return Err(WsError::ChannelClosed());
+                        }
+                    }
+                }
+                queued.notifications += 1;
+            }
+            queued.messages.push_back(message);
+        }
+
+        self.inner.changed.notify_waiters();
+        Ok(())
+    }
+
+    /// Wait for the next message to send, or `None` once the queue is closed.
+    pub async fn recv(&self) -> Option<RequestResult> {
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if self.is_closed() {
+                return None;
+            }
+            if let Some(message) = self
+                .inner
+                .queued
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .pop_front()
+            {
+                return Some(message);
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Stop sending to the client, dropping whatever is still queued.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+        *self.inner.queued.lock().unwrap_or_else(|e| e.into_inner()) = Queued::default();
+        self.inner.changed.notify_waiters();
+    }
+
+    /// Completes once the queue is closed.
+    pub async fn closed(&self) {
+        loop {
+            let changed = self.inner.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if self.is_closed() {
+                return;
+            }
+
+            changed.await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.inner.closed.load(Ordering::Relaxed)
+    }
+
+    /// Returns true if the queue got closed because the client didn't keep up.
+    pub fn overflowed(&self) -> bool {
+        self.inner.overflowed.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn head(number: u64) -> RequestResult {
+        RequestResult::Subscription(
+            json!({"params": {"subscription": "0x1", "result": {"number": number, "hash": "0xaa"}}}),
+        )
+    }
+
+    fn log(index: u64) -> RequestResult {
+        RequestResult::Subscription(
+            json!({"params": {"subscription": "0x2", "result": {"blockHash": "0xbb", "logIndex": format!("{:#x}", index)}}}),
+        )
+    }
+
+    fn settings(send_queue_size: usize) -> WsConnectionSettings {
+        WsConnectionSettings {
+            send_queue_size,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let queue = SendQueue::new(&settings(2));
+        queue.send(RequestResult::Call(json!({"id": 1}))).unwrap();
+        for number in 0..4 {
+            queue.send(head(number)).unwrap();
+        }
+
+        // Calls stay, and only the latest heads are left
+        assert!(matches!(queue.recv().await, Some(RequestResult::Call(_))));
+        for number in 2..4 {
+            let Some(RequestResult::Subscription(head)) = queue.recv().await else {
+                panic!("Expected a head");
+            };
+            assert_eq!(head["params"]["result"]["number"], number);
+        }
+        assert!(timeout(Duration::from_millis(50), queue.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_disconnect() {
+        let queue = SendQueue::new(&settings(2));
+        queue.send(log(0)).unwrap();
+        queue.send(log(1)).unwrap();
+        assert!(!queue.overflowed());
+
+        assert!(queue.send(log(2)).is_err());
+        assert!(queue.overflowed());
+        queue.closed().await;
+        assert!(queue.recv().await.is_none());
+        assert!(queue.send(head(0)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unbounded() {
+        let queue = SendQueue::new(&settings(0));
+        for index in 0..2048 {
+            queue.send(log(index)).unwrap();
+        }
+
+        let waiting = {
+            let queue = queue.clone();
+            tokio::spawn(async move { queue.closed().await })
+        };
+        queue.close();
+        waiting.await.unwrap();
+        assert!(!queue.overflowed());
+        assert!(queue.recv().await.is_none());
+    }
+}
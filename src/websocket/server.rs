@@ -1,11 +1,42 @@
 use std::sync::Arc;
 
 use crate::{
-    balancer::processing::CacheArgs,
+    balancer::{
+        batch::error_response,
+        heatmap::RequestHeatmap,
+        local_methods::{
+            local_ws_response,
+            LocalMethods,
+        },
+        logs::LogQuery,
+        processing::CacheArgs,
+        rate_limit::CallLimiter,
+    },
+    config::types::{
+        ComputeUnitSettings,
+        LogLimits,
+        MethodFilterSettings,
+        RequestLimits,
+        WsConnectionSettings,
+    },
     database::types::GenericBytes,
+    rpc::engine::is_engine_method,
     websocket::{
-        client::execute_ws_call,
+        client::{
+            execute_ws_call,
+            execute_ws_logs,
+        },
+        connections::{
+            CloseInitiator,
+            WsConnection,
+            CLOSE_ABNORMAL,
+            CLOSE_GOING_AWAY,
+            CLOSE_NO_STATUS,
+            CLOSE_POLICY_VIOLATION,
+        },
         error::WsError,
+        keepalive::next_ping,
+        send_queue::SendQueue,
         types::{
             IncomingResponse,
             RequestResult,
@@ -17,9 +48,12 @@ use crate::{
 
 use rand::random;
 
-use tokio::sync::{
-    broadcast,
-    mpsc,
+use tokio::{
+    sync::{
+        broadcast,
+        mpsc,
+    },
+    time::Instant,
 };
 
 use simd_json::from_str;
@@ -30,30 +64,68 @@ use futures::{
 };
 
 use hyper_tungstenite::HyperWebsocket;
-use tungstenite::Message;
+use tungstenite::{
+    protocol::{
+        frame::coding::CloseCode,
+        CloseFrame,
+    },
+    Error,
+    Message,
+};
+
+/// Returns who ended a connection that failed with `err`, and the status code describing why.
+fn close_reason(err: &Error) -> (CloseInitiator, u16) {
+    match err {
+        Error::Protocol(_) => (CloseInitiator::Server, 1002),
+        Error::Utf8 => (CloseInitiator::Server, 1007),
+        Error::Capacity(_) => (CloseInitiator::Server, 1009),
+        // The client went away without a close handshake
+        _ => (CloseInitiator::Client, CLOSE_ABNORMAL),
+    }
+}
 
 /// Handle a WebSocket connection request.
 ///
 /// Opens a WebSocket connection between Blutgang and a client,
 /// sending their requests to be processed.
+///
+/// Clients are pinged, and dropped once they go idle or stop reading their
+/// notifications, as set in `settings`. Calls to methods `method_filter`
+/// blocks, to the Engine API unless the upgrade was `engine_authorized`, or
+/// over the client's rate limit or `compute_units` budget in `limiter`, are
+/// answered with an error. Params are held to `request_limits`, and log
+/// queries to `log_limits`, which are chunked like over HTTP. Trivial methods
+/// are answered by `local_methods`, if set.
+#[allow(clippy::too_many_arguments)]
 pub async fn serve_websocket<K, V>(
     websocket: HyperWebsocket,
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     sub_data: Arc<SubscriptionData>,
     cache_args: CacheArgs<K, V>,
+    heatmap: Arc<RequestHeatmap>,
+    connection: WsConnection,
+    settings: WsConnectionSettings,
+    method_filter: Arc<MethodFilterSettings>,
+    local_methods: Option<LocalMethods>,
+    log_limits: LogLimits,
+    request_limits: RequestLimits,
+    engine_authorized: bool,
+    compute_units: Arc<ComputeUnitSettings>,
+    limiter: Option<CallLimiter>,
 ) -> Result<(), WsError>
 where
     K: GenericBytes + From<[u8; 32]> + 'static,
     V: GenericBytes + From<Vec<u8>> + 'static,
 {
     let websocket = websocket.await?;
+    let connection = Arc::new(connection);
 
     // Split the Sink so we can do async send/recv
     let (mut websocket_sink, mut websocket_stream) = websocket.split();
 
-    // Create channels for message send/receiving
-    let (tx, mut rx) = mpsc::unbounded_channel::<RequestResult>();
+    // Queue of messages for the client, bounded so it can't grow forever if they stop reading
+    let queue = SendQueue::new(&settings);
 
     // Generate an id for our user
     //
@@ -62,35 +134,162 @@ where
 
     // Add the user to the sink map
     tracing::info!("Adding user {} to sink map", user_id);
-    let user_data = tx.clone();
-    sub_data.add_user(user_id, user_data);
+    sub_data.add_user(user_id, queue.clone());
 
     let sub_data_clone = sub_data.clone();
+    let connection_clone = connection.clone();
+    let queue_clone = queue.clone();
 
     // Spawn taks for sending messages to the client
     tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+        let mut pings = settings.pings();
+        loop {
+            let msg = tokio::select! {
+                msg = queue_clone.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = next_ping(&mut pings) => {
+                    if let Err(e) = websocket_sink.send(Message::Ping(Vec::new())).await {
+                        // Remove the user from the sink map
+                        sub_data_clone.remove_user(user_id);
+                        tracing::error!(?e, "Error sending ping");
+                        break;
+                    }
+                    continue;
+                }
+                _ = connection_clone.shutdown() => {
+                    tracing::info!(user_id, "Closing WS connection, shutting down");
+                    connection_clone.closed(CloseInitiator::Server, CLOSE_GOING_AWAY);
+                    let close = CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Blutgang is shutting down".into(),
+                    };
+                    let _ = websocket_sink.send(Message::Close(Some(close))).await;
+                    break;
+                }
+            };
+
             // Forward the message to the best available RPC
             //
             // If we received a subscription, just send it to the client
             match msg {
                 RequestResult::Call(call) => {
-                    let resp = match execute_ws_call(
-                        call,
-                        user_id,
-                        &incoming_tx,
-                        outgoing_rx.resubscribe(),
-                        &sub_data_clone,
-                        &cache_args,
-                    )
-                    .await
+                    heatmap.record(&call);
+                    let named_numbers = *cache_args
+                        .named_numbers
+                        .read()
+                        .unwrap_or_else(|e| e.into_inner());
+                    let changes_subscriptions = matches!(
+                        call["method"].as_str(),
+                        Some("eth_subscribe" | "eth_unsubscribe")
+                    );
+                    let limited = match &limiter {
+                        Some(limiter) => {
+                            let calls = call.as_array().map_or(1, |calls| calls.len() as u64);
+                            match limiter.charge(calls).await {
+                                Ok(()) => {
+                                    limiter
+                                        .charge_compute_units(
+                                            &compute_units,
+                                            compute_units.request_cost(&call),
+                                        )
+                                        .await
+                                        .map_err(|retry_after| {
+                                            (retry_after, "compute unit budget exceeded")
+                                        })
+                                }
+                                limited => limited,
+                            }
+                        }
+                        None => Ok(()),
+                    };
+                    let resp = if let Err((retry_after, message)) = limited {
+                        error_response(
+                            call["id"].clone(),
+                            -32005,
+                            format!("{}, retry in {}s", message, retry_after),
+                        )
+                        .to_string()
+                    } else if !method_filter.check(call["method"].as_str())
+                        || (!engine_authorized && is_engine_method(call["method"].as_str()))
+                    {
+                        let method = call["method"].as_str().unwrap_or_default();
+                        error_response(
+                            call["id"].clone(),
+                            -32601,
+                            format!("method not allowed: {}", method),
+                        )
+                        .to_string()
+                    } else if let Err(err) = match call.as_array() {
+                        Some(calls) => {
+                            calls
+                                .iter()
+                                .try_for_each(|call| request_limits.check_params(call))
+                        }
+                        None => request_limits.check_params(&call),
+                    } {
+                        error_response(
+                            call["id"].clone(),
+                            -32602,
+                            format!("invalid params: {}", err),
+                        )
+                        .to_string()
+                    } else if let Err(err) = log_limits.check(&call, &named_numbers) {
+                        error_response(
+                            call["id"].clone(),
+                            -32602,
+                            format!("invalid params: {}", err),
+                        )
+                        .to_string()
+                    } else if let Some(result) = local_methods
+                        .as_ref()
+                        .and_then(|local_methods| local_methods.result(call["method"].as_str()))
                     {
-                        Ok(rax) => rax,
-                        Err(e) => format!("{{\"error\": \"{}\"}}", e),
+                        local_ws_response(call["id"].clone(), result)
+                    } else {
+                        let chunks = LogQuery::parse(&call, &named_numbers)
+                            .filter(|query| log_limits.needs_chunking(query))
+                            .map(|query| log_limits.chunk(query.from, query.to));
+                        let response = match chunks {
+                            Some(chunks) => {
+                                execute_ws_logs(
+                                    call,
+                                    chunks,
+                                    user_id,
+                                    &incoming_tx,
+                                    &outgoing_rx,
+                                    &sub_data_clone,
+                                    &cache_args,
+                                )
+                                .await
+                            }
+                            None => {
+                                execute_ws_call(
+                                    call,
+                                    user_id,
+                                    &incoming_tx,
+                                    outgoing_rx.resubscribe(),
+                                    &sub_data_clone,
+                                    &cache_args,
+                                )
+                                .await
+                            }
+                        };
+                        match response {
+                            Ok(rax) => rax,
+                            Err(e) => format!("{{\"error\": \"{}\"}}", e),
+                        }
                     };
 
+                    if changes_subscriptions {
+                        connection_clone
+                            .set_subscriptions(sub_data_clone.user_subscription_count(user_id));
+                    }
+
+                    let len = resp.len();
                     match websocket_sink.send(Message::text::<String>(resp)).await {
-                        Ok(_) => {}
+                        Ok(_) => connection_clone.sent(len),
                         Err(e) => {
                             // Remove the user from the sink map
                             sub_data_clone.remove_user(user_id);
@@ -100,11 +299,10 @@ where
                     }
                 }
                 RequestResult::Subscription(sub) => {
-                    match websocket_sink
-                        .send(Message::text::<String>(sub.to_string()))
-                        .await
-                    {
-                        Ok(_) => {}
+                    let sub = sub.to_string();
+                    let len = sub.len();
+                    match websocket_sink.send(Message::text::<String>(sub)).await {
+                        Ok(_) => connection_clone.sent(len),
                         Err(e) => {
                             // Remove the user from the sink map
                             sub_data_clone.remove_user(user_id);
@@ -117,19 +315,44 @@ where
         Ok(())
     });
 
-    while let Some(message) = websocket_stream.next().await {
+    let mut last_received = Instant::now();
+    loop {
+        let message = tokio::select! {
+            message = websocket_stream.next() => message,
+            _ = settings.idle(last_received) => {
+                tracing::info!(user_id, "Closing idle WS connection");
+                connection.closed(CloseInitiator::Server, CLOSE_GOING_AWAY);
+                break;
+            }
+            _ = queue.closed() => {
+                if queue.overflowed() {
+                    tracing::warn!(user_id, "Disconnecting WS client that isn't reading its notifications");
+                    connection.closed(CloseInitiator::Server, CLOSE_POLICY_VIOLATION);
+                }
+                break;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+        last_received = Instant::now();
+
         match message {
             Ok(Message::Text(mut msg)) => {
                 tracing::info!(msg, "Received WS text message");
+                connection.received(msg.len());
                 // Send message to the channel
                 let rax = match unsafe { from_str(&mut msg) } {
                     Ok(rax) => rax,
                     Err(_) => continue,
                 };
 
-                tx.send(RequestResult::Call(rax)).unwrap_or(());
+                let _ = queue.send(RequestResult::Call(rax));
             }
             Ok(Message::Close(msg)) => {
+                let code = msg.as_ref().map_or(CLOSE_NO_STATUS, |msg| msg.code.into());
+                connection.closed(CloseInitiator::Client, code);
+
                 if let Some(msg) = &msg {
                     tracing::info!(
                         "Received close message with code {} and message: {}",
@@ -141,6 +364,9 @@ where
                 }
             }
             Err(e) => {
+                let (initiator, code) = close_reason(&e);
+                connection.closed(initiator, code);
+
                 // Remove the user from the sink map
                 sub_data.remove_user(user_id);
                 return Err(WsError::MessageReceptionFailed(e.to_string()));
@@ -149,5 +375,8 @@ where
         }
     }
 
+    // Remove the user from the sink map, which also stops the task sending to them
+    sub_data.remove_user(user_id);
+
     Ok(())
 }
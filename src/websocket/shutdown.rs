@@ -0,0 +1,191 @@
+//! Draining WebSocket connections on shutdown.
+//!
+//! Once we get a SIGTERM or ctrl-c, we stop accepting connections, and give
+//! connected clients `drain_period_ms` to finish what they're doing before
+//! sending them a close frame. Upstream subscriptions are unsubscribed from
+//! once clients are gone, instead of being dropped with the connection, and
+//! then connections to RPCs get closed with a close frame of their own.
+
+use crate::{
+    config::system::WS_SUB_MANAGER_ID,
+    rpc::method::EthRpcMethod,
+    websocket::{
+        connections::WsConnections,
+        types::{
+            SubscriptionData,
+            WsconnMessage,
+            AGGREGATED_NODE_ID,
+        },
+    },
+};
+
+use std::{
+    sync::RwLock,
+    time::Duration,
+};
+
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::{
+    sync::mpsc,
+    time::{
+        sleep,
+        timeout,
+    },
+};
+
+/// Time clients get to answer our close frame before we stop waiting for them.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Time we give unsubscriptions and close frames to reach RPCs.
+const UNSUBSCRIBE_GRACE: Duration = Duration::from_millis(500);
+
+/// Completes once we're asked to shut down, by SIGTERM or ctrl-c.
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(?err, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Close every WebSocket connection after `drain_period`, unsubscribe from
+/// everything upstream, and close the connections to RPCs in `ws_handles`.
+pub async fn drain_websockets(
+    ws_connections: &WsConnections,
+    sub_data: &SubscriptionData,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    ws_handles: &RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>,
+    drain_period: Duration,
+) {
+    ws_connections.drain();
+    if !ws_connections.is_empty() {
+        tracing::info!(?drain_period, "Draining WS connections");
+        sleep(drain_period).await;
+    }
+
+    ws_connections.close_all();
+    let closed = timeout(CLOSE_TIMEOUT, async {
+        while !ws_connections.is_empty() {
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+    if closed.is_err() {
+        tracing::warn!("WS clients didn't close their connections in time");
+    }
+
+    if unsubscribe_all(sub_data, incoming_tx) != 0 {
+        sleep(UNSUBSCRIBE_GRACE).await;
+    }
+    if close_upstream(ws_handles) != 0 {
+        sleep(UNSUBSCRIBE_GRACE).await;
+    }
+}
+
+/// Close every connection to RPCs, returning how many were open.
+fn close_upstream(ws_handles: &RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>) -> usize {
+    let mut ws_handles = ws_handles.write().unwrap_or_else(|e| e.into_inner());
+    // Dropping the sender closes the connection
+    let closed = ws_handles
+        .iter_mut()
+        .filter_map(Option::take)
+        .filter(|handle| !handle.is_closed())
+        .count();
+    tracing::info!(closed, "Closed WS connections to RPCs");
+    closed
+}
+
+/// Unsubscribe from every upstream subscription, returning how many there were.
+fn unsubscribe_all(
+    sub_data: &SubscriptionData,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+) -> usize {
+    let mut unsubscribed = 0;
+    for node_sub_info in sub_data.upstream_subscriptions() {
+        // Not a subscription to any single node
+        if node_sub_info.node_id == AGGREGATED_NODE_ID {
+            continue;
+        }
+
+        let unsub = json!({"jsonrpc": "2.0", "id": WS_SUB_MANAGER_ID, "method": EthRpcMethod::Unsubscribe, "params": [node_sub_info.subscription_id]});
+        if incoming_tx
+            .send(WsconnMessage::Message(unsub, Some(node_sub_info.node_id)))
+            .is_ok()
+        {
+            unsubscribed += 1;
+        }
+    }
+
+    tracing::info!(unsubscribed, "Unsubscribed from upstream subscriptions");
+    unsubscribed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::websocket::head_aggregation::register_aggregated_heads;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_drain_websockets() {
+        let ws_connections = Arc::new(WsConnections::default());
+        let sub_data = SubscriptionData::new();
+        register_aggregated_heads(&sub_data);
+        sub_data.register_subscription(json!({"params": ["syncing"]}), "0xa".to_string(), 0);
+        sub_data.register_subscription(
+            json!({"params": ["newPendingTransactions"]}),
+            "0xb".to_string(),
+            1,
+        );
+        let (incoming_tx, mut incoming_rx) = mpsc::unbounded_channel();
+        let (node_tx, mut node_rx) = mpsc::unbounded_channel();
+        let ws_handles = RwLock::new(vec![Some(node_tx), None]);
+
+        // A client that closes as soon as it's told to
+        let connection = ws_connections.open(None);
+        let client = tokio::spawn(async move { connection.shutdown().await });
+
+        drain_websockets(
+            &ws_connections,
+            &sub_data,
+            &incoming_tx,
+            &ws_handles,
+            Duration::from_millis(10),
+        )
+        .await;
+        client.await.unwrap();
+        assert!(ws_connections.is_draining());
+        assert!(ws_connections.is_empty());
+
+        let mut unsubscribed = Vec::new();
+        while let Ok(WsconnMessage::Message(unsub, node_id)) = incoming_rx.try_recv() {
+            assert_eq!(unsub["method"], "eth_unsubscribe");
+            unsubscribed.push((unsub["params"][0].clone(), node_id));
+        }
+        unsubscribed.sort_by_key(|(_, node_id)| *node_id);
+        assert_eq!(
+            unsubscribed,
+            vec![(json!("0xa"), Some(0)), (json!("0xb"), Some(1))]
+        );
+
+        // Connections to RPCs are closed
+        assert!(ws_handles.read().unwrap().iter().all(Option::is_none));
+        assert!(node_rx.recv().await.is_none());
+    }
+}
@@ -17,7 +17,13 @@ use crate::{
 
 use std::{
     collections::HashMap,
-    sync::Arc,
+    sync::{
+        atomic::{
+            AtomicU32,
+            Ordering,
+        },
+        Arc,
+    },
 };
 
 use tokio::sync::{
@@ -28,7 +34,15 @@ use tokio::sync::{
     mpsc,
 };
 
-use serde_json::json;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Id of the next request moving a subscription.
+///
+/// Ids are never reused, so subscriptions can be moved off several nodes at once.
+static NEXT_MOVE_ID: AtomicU32 = AtomicU32::new(WS_SUB_MANAGER_ID + MAGIC + 1);
 
 /// Sends all subscriptions to their relevant nodes
 pub async fn subscription_dispatcher(
@@ -109,10 +123,9 @@ pub async fn move_subscriptions(
     // We want to send subscription messages to `target`, register them, and move over the users
     let _ = rx; // bind `rx` so we have time to process all messages
     let mut pairs: HashMap<u32, String> = HashMap::new();
-    let mut id = WS_SUB_MANAGER_ID + MAGIC;
     for params in subs {
-        id += 1;
-        let sub = json!({"jsonrpc": "2.0", "id": id, "method": EthRpcMethod::Subscribe, "params": vec![params.clone()]});
+        let id = NEXT_MOVE_ID.fetch_add(1, Ordering::Relaxed);
+        let sub = json!({"jsonrpc": "2.0", "id": id, "method": EthRpcMethod::Subscribe, "params": serde_json::from_str::<Value>(&params).map_err(|_| WsError::FailedParsing())?});
         let message = WsconnMessage::Message(sub, None);
 
         pairs.insert(id, params);
@@ -136,9 +149,17 @@ pub async fn move_subscriptions(
             None => continue,
         };
 
-        let sub_id = match sub_data.get_sub_id_by_params(&params) {
-            Some(rax) => rax,
-            None => return Err(WsError::MissingSubscription()),
+        if sub_data.get_sub_id_by_params(&params).is_none() {
+            return Err(WsError::MissingSubscription());
+        }
+        // The new node gives the subscription a new id
+        let sub_id = match response.content["result"].as_str() {
+            Some(rax) => rax.to_string(),
+            None => {
+                tracing::error!(?response.content, "Failed to move subscription to a new node");
+                pairs.remove(&pair_id);
+                continue;
+            }
         };
         match sub_data.move_subscriptions(response.node_id, params, sub_id) {
             Ok(_) => {}
@@ -178,7 +199,7 @@ mod tests {
             subscription_id.to_string(),
             0,
         );
-        sub_data
+        let client_subscription_id = sub_data
             .subscribe_user(user_id, subscription_request)
             .unwrap();
 
@@ -193,11 +214,11 @@ mod tests {
         };
         tx.send(incoming_response).unwrap();
 
-        // Check if the user receives the message
+        // Check if the user receives the message, under the id they know
         if let Some(RequestResult::Subscription(msg)) = user_rx.recv().await {
             assert_eq!(
                 msg,
-                json!({"method": EthRpcMethod::Subscription, "params": {"subscription": subscription_id}})
+                json!({"method": EthRpcMethod::Subscription, "params": {"subscription": client_subscription_id}})
             );
         } else {
             panic!("User did not receive the expected message.");
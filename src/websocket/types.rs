@@ -2,16 +2,39 @@ use std::{
     collections::{
         HashMap,
         HashSet,
+        VecDeque,
     },
     sync::{
         Arc,
         RwLock,
     },
+    time::Duration,
 };
 
-use crate::websocket::error::WsError;
+use crate::{
+    balancer::canonical::canonical_params,
+    config::types::{
+        WsReplaySettings,
+        WsSubscriptionLimits,
+    },
+    websocket::{
+        error::WsError,
+        log_filter::{
+            all_logs_params,
+            LogFilter,
+        },
+        replay::{
+            ReplayBuffer,
+            ReplayError,
+        },
+        send_queue::SendQueue,
+    },
+};
 use rust_tracing::deps::metrics;
-use serde_json::Value;
+use serde_json::{
+    json,
+    Value,
+};
 use tokio::sync::mpsc;
 
 /// RequestResult enum
@@ -50,10 +73,44 @@ impl From<WsconnMessage> for Value {
 /// WsChannelErr enum
 #[derive(Debug, Clone)]
 pub enum WsChannelErr {
+    // position in the rpc_list of the RPC we couldn't reconnect to
     Closed(usize),
 }
 
-pub type UserData = mpsc::UnboundedSender<RequestResult>;
+/// Where messages for a user go.
+#[derive(Debug, Clone)]
+pub enum UserData {
+    // Blutgang itself, which keeps up with whatever it subscribed to
+    Channel(mpsc::UnboundedSender<RequestResult>),
+    // WS clients, see `send_queue`
+    Queue(SendQueue),
+}
+
+impl UserData {
+    pub fn send(&self, message: RequestResult) -> Result<(), WsError> {
+        match self {
+            UserData::Channel(tx) => Ok(tx.send(message)?),
+            UserData::Queue(queue) => queue.send(message),
+        }
+    }
+}
+
+impl From<mpsc::UnboundedSender<RequestResult>> for UserData {
+    fn from(tx: mpsc::UnboundedSender<RequestResult>) -> Self {
+        UserData::Channel(tx)
+    }
+}
+
+impl From<SendQueue> for UserData {
+    fn from(queue: SendQueue) -> Self {
+        UserData::Queue(queue)
+    }
+}
+
+/// Node id of subscriptions served from every node at once, see `head_aggregation`.
+///
+/// They stay registered without users, since there's no node to unsubscribe from.
+pub const AGGREGATED_NODE_ID: usize = usize::MAX;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct NodeSubInfo {
@@ -67,13 +124,75 @@ pub struct IncomingResponse {
     pub node_id: usize,
 }
 
+/// Key of the upstream subscription `eth_subscribe` requests with the same params share.
+///
+/// Filters that only differ in the case of their hex strings or the order of
+/// their fields are the same filter, so they share a subscription too.
+fn subscription_key(subscription: &Value) -> String {
+    canonical_params(&subscription["params"])
+}
+
+/// ID clients know the subscription under `key` by.
+///
+/// Upstream IDs change whenever we have to resubscribe on another node, so
+/// clients get an ID derived from the filter instead, which never does.
+fn client_id(key: &str) -> String {
+    format!("0x{}", &blake3::hash(key.as_bytes()).to_hex()[..32])
+}
+
+/// Key of the subscription to every log, which we filter logs from ourselves.
+fn all_logs_key() -> String {
+    subscription_key(&json!({"params": all_logs_params()}))
+}
+
+/// Number of notifications we remember per subscription to drop duplicates.
+const DELIVERED_CAPACITY: usize = 64;
+
+/// What tells a notification apart from the other ones of its subscription.
+///
+/// Nodes don't always send the exact same notification, so heads are told
+/// apart by their hash and logs by where they are. Logs get sent again with
+/// `removed` set when they're reorged out, which isn't a duplicate.
+fn notification_fingerprint(content: &Value) -> String {
+    let result = &content["params"]["result"];
+    if let Some(hash) = result["hash"].as_str() {
+        return hash.to_lowercase();
+    }
+    if let (Some(block_hash), Some(log_index)) =
+        (result["blockHash"].as_str(), result["logIndex"].as_str())
+    {
+        return format!(
+            "{}:{}:{}",
+            block_hash.to_lowercase(),
+            log_index.to_lowercase(),
+            result["removed"]
+        );
+    }
+    canonical_params(result)
+}
+
 /// Main struct for storing data related to subscriptions and the associated users
+///
+/// Every distinct filter has a single upstream subscription, whose
+/// notifications get fanned out to every user subscribed to the filter.
+/// Users never see upstream subscription IDs, see `client_id`.
 /// TODO: we should probably store more data for the sake of compute performance
 #[derive(Debug, Clone)]
 pub struct SubscriptionData {
     users: Arc<RwLock<HashMap<u32, UserData>>>,
     subscriptions: Arc<RwLock<HashMap<NodeSubInfo, HashSet<u32>>>>,
     incoming_subscriptions: Arc<RwLock<HashMap<String, NodeSubInfo>>>,
+    // Latest notifications sent for every subscription, see `notification_fingerprint`
+    delivered: Arc<RwLock<HashMap<String, VecDeque<String>>>>,
+    // Users of `logs` subscriptions we filter ourselves, by subscription
+    log_filters: Arc<RwLock<HashMap<String, (LogFilter, HashSet<u32>)>>>,
+    // Serve `logs` subscriptions from one to every log, see `log_filter`
+    filter_logs: bool,
+    // Most subscriptions, and most complex filters, a single user can have
+    limits: WsSubscriptionLimits,
+    // Latest notifications of every subscription we replay, see `replay`
+    replay: Arc<RwLock<HashMap<String, ReplayBuffer>>>,
+    replay_settings: WsReplaySettings,
 }
 
 impl SubscriptionData {
@@ -82,13 +201,122 @@ impl SubscriptionData {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            delivered: Arc::new(RwLock::new(HashMap::new())),
+            log_filters: Arc::new(RwLock::new(HashMap::new())),
+            filter_logs: false,
+            limits: WsSubscriptionLimits::default(),
+            replay: Arc::new(RwLock::new(HashMap::new())),
+            replay_settings: WsReplaySettings::default(),
         }
     }
 
-    pub fn add_user(&self, user_id: u32, user_data: UserData) {
+    // Serve every `logs` subscription from a single one to every log, and filter logs ourselves
+    pub fn with_local_log_filters(mut self, enabled: bool) -> Self {
+        self.filter_logs = enabled;
+        self
+    }
+
+    // Limit the subscriptions every user can have, see `check_subscription_limits`
+    pub fn with_subscription_limits(mut self, limits: WsSubscriptionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Keep the latest notifications of subscriptions for clients to resume, see `replay`
+    pub fn with_replay(mut self, replay_settings: WsReplaySettings) -> Self {
+        self.replay_settings = replay_settings;
+        self
+    }
+
+    // Return error if `user_id` subscribing to `subscription` would go past our limits
+    //
+    // Subscribing to something the user is already subscribed to is always fine.
+    pub fn check_subscription_limits(
+        &self,
+        user_id: u32,
+        subscription: &Value,
+    ) -> Result<(), WsError> {
+        if let Some(filter) = LogFilter::from_subscription(subscription) {
+            let limits = self.limits;
+            if limits.max_filter_addresses != 0
+                && filter.address_count() > limits.max_filter_addresses
+            {
+                return Err(WsError::SubscriptionLimit(format!(
+                    "logs filter has more than {} addresses",
+                    limits.max_filter_addresses
+                )));
+            }
+            if limits.max_filter_topics != 0 && filter.topic_count() > limits.max_filter_topics {
+                return Err(WsError::SubscriptionLimit(format!(
+                    "logs filter has more than {} topics",
+                    limits.max_filter_topics
+                )));
+            }
+        }
+
+        if self.limits.max_subscriptions != 0
+            && self.user_subscription_count(user_id) >= self.limits.max_subscriptions
+            && !self.is_subscribed(user_id, subscription)
+        {
+            return Err(WsError::SubscriptionLimit(format!(
+                "more than {} active subscriptions",
+                self.limits.max_subscriptions
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Returns true if `user_id` is already subscribed to `subscription`
+    fn is_subscribed(&self, user_id: u32, subscription: &Value) -> bool {
+        let key = subscription_key(subscription);
+        let subscribed_to_logs = self
+            .log_filters
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .is_some_and(|(_, subscribers)| subscribers.contains(&user_id));
+        if subscribed_to_logs {
+            return true;
+        }
+
+        let incoming_subscriptions = self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        incoming_subscriptions
+            .get(&key)
+            .is_some_and(|node_sub_info| {
+                self.subscriptions
+                    .read()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .get(node_sub_info)
+                    .is_some_and(|subscribers| subscribers.contains(&user_id))
+            })
+    }
+
+    // Returns the filter we evaluate ourselves for `subscription`
+    fn local_log_filter(&self, subscription: &Value) -> Option<LogFilter> {
+        if !self.filter_logs {
+            return None;
+        }
+        LogFilter::from_subscription(subscription)
+    }
+
+    // Returns the request to subscribe to upstream for `subscription`
+    //
+    // It's `subscription` itself, unless we filter its logs ourselves.
+    pub fn upstream_subscription(&self, mut subscription: Value) -> Value {
+        if self.local_log_filter(&subscription).is_some() {
+            subscription["params"] = all_logs_params();
+        }
+        subscription
+    }
+
+    pub fn add_user(&self, user_id: u32, user_data: impl Into<UserData>) {
         let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
 
-        if users.insert(user_id, user_data).is_none() {
+        if users.insert(user_id, user_data.into()).is_none() {
             metrics::gauge!("ws_users_total").increment(1);
         }
     }
@@ -99,8 +327,12 @@ impl SubscriptionData {
 
         let mut users = self.users.write().unwrap_or_else(|e| e.into_inner());
 
-        if users.remove(&user_id).is_some() {
+        if let Some(user_data) = users.remove(&user_id) {
             metrics::gauge!("ws_users_total").decrement(1);
+            // Stops the task sending to them
+            if let UserData::Queue(queue) = user_data {
+                queue.close();
+            }
             let mut subscriptions = self.subscriptions.write().unwrap();
             for user_subscriptions in subscriptions.values_mut() {
                 user_subscriptions.remove(&user_id);
@@ -110,27 +342,36 @@ impl SubscriptionData {
     }
 
     // Used to add a new subscription to the active subscription list
+    //
+    // If the filter already has a subscription, which happens when users
+    // subscribe to it at the same time, the existing one is kept and the
+    // subscription we were given is returned, so it can be unsubscribed from.
     pub fn register_subscription(
         &self,
         subscription: Value,
         subscription_id: String,
         node_id: usize,
-    ) {
-        // TODO: pepega
-        let subscription = format!("{}", subscription["params"]);
+    ) -> Option<NodeSubInfo> {
+        let subscription = subscription_key(&subscription);
+        let node_sub_info = NodeSubInfo {
+            node_id,
+            subscription_id,
+        };
 
-        // Detect duplicates before inserting
-        {
-            let incoming_subscriptions = self
-                .incoming_subscriptions
-                .read()
-                .unwrap_or_else(|e| e.into_inner());
-            if incoming_subscriptions.contains_key(&subscription) {
-                metrics::counter!("ws_duplicate_node_subs", "subscription_id" => subscription_id.clone(), "node_id" => node_id.to_string()).increment(1);
-            }
+        let mut incoming_subscriptions = self
+            .incoming_subscriptions
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        if incoming_subscriptions.contains_key(&subscription) {
+            metrics::counter!("ws_duplicate_node_subs", "subscription_id" => node_sub_info.subscription_id.clone(), "node_id" => node_id.to_string()).increment(1);
+            return Some(node_sub_info);
         }
 
-        self.raw_register(&subscription, subscription_id, node_id);
+        tracing::info!(subscription, "Register_subscription inserting");
+        incoming_subscriptions.insert(subscription, node_sub_info);
+        metrics::gauge!("ws_node_subs_total").increment(1);
+
+        None
     }
 
     fn raw_register(&self, subscription: &str, subscription_id: String, node_id: usize) {
@@ -166,9 +407,17 @@ impl SubscriptionData {
         {
             metrics::gauge!("ws_node_subs_total").decrement(1);
         }
+        self.delivered
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&subscription_request);
+        self.replay
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&subscription_request);
     }
 
-    // Subscribe user to existing subscription and return the subscription id
+    // Subscribe user to existing subscription and return the id the user knows it by
     //
     // If the subscription does not exist, return error
     pub fn subscribe_user(&self, user_id: u32, subscription: Value) -> Result<String, WsError> {
@@ -178,11 +427,44 @@ impl SubscriptionData {
             return Err(WsError::FailedParsing());
         }
 
-        // TODO: pepega
-        let subscription = format!("{}", subscription["params"]);
+        let filter = self.local_log_filter(&subscription);
+        let subscription = subscription_key(&subscription);
         tracing::info!(subscription, "Subscribe_user finding");
 
-        self.raw_subscribe(user_id, &subscription)
+        match filter {
+            Some(filter) => self.subscribe_to_logs(user_id, subscription, filter),
+            None => self.raw_subscribe(user_id, &subscription),
+        }
+    }
+
+    // Subscribe user to logs passing `filter`, served from the subscription to every log
+    //
+    // If we aren't subscribed to every log, return error
+    fn subscribe_to_logs(
+        &self,
+        user_id: u32,
+        subscription: String,
+        filter: LogFilter,
+    ) -> Result<String, WsError> {
+        if !self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(&all_logs_key())
+        {
+            return Err(WsError::FailedParsing());
+        }
+
+        let id = client_id(&subscription);
+        let mut log_filters = self.log_filters.write().unwrap_or_else(|e| e.into_inner());
+        let (_, subscribers) = log_filters
+            .entry(subscription)
+            .or_insert_with(|| (filter, HashSet::new()));
+        if subscribers.insert(user_id) {
+            metrics::gauge!("ws_user_subs_total").increment(1);
+        }
+
+        Ok(id)
     }
 
     fn raw_subscribe(&self, user_id: u32, subscription: &String) -> Result<String, WsError> {
@@ -205,27 +487,68 @@ impl SubscriptionData {
             metrics::gauge!("ws_user_subs_total").increment(1);
         }
 
-        Ok(node_sub_info.subscription_id.clone())
+        Ok(client_id(subscription))
     }
 
-    // Unsubscribe a user from a subscription
+    // Unsubscribe a user from a subscription, by the id the user knows it by
     pub fn unsubscribe_user(&self, user_id: u32, subscription_id: String) {
+        if self.unsubscribe_from_logs(user_id, &subscription_id) {
+            return;
+        }
+
+        let Some(node_sub_info) = self.get_node_sub_info(&subscription_id) else {
+            return;
+        };
+
         let mut subscriptions = self
             .subscriptions
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
-        // Directly unsubscribing the user within the loop
-        for (node_sub_info, subscribers) in subscriptions.iter_mut() {
-            if node_sub_info.subscription_id == subscription_id && subscribers.contains(&user_id) {
-                subscribers.remove(&user_id);
+        if let Some(subscribers) = subscriptions.get_mut(&node_sub_info) {
+            if subscribers.remove(&user_id) {
+                metrics::gauge!("ws_user_subs_total").decrement(1);
+            }
+        }
+    }
+
+    // Unsubscribe a user from logs we filter ourselves, by the id the user knows them by
+    //
+    // Returns false if it isn't a subscription to logs we filter.
+    fn unsubscribe_from_logs(&self, user_id: u32, subscription_id: &str) -> bool {
+        let mut log_filters = self.log_filters.write().unwrap_or_else(|e| e.into_inner());
+        let Some(subscription) = log_filters
+            .keys()
+            .find(|subscription| client_id(subscription) == subscription_id)
+            .cloned()
+        else {
+            return false;
+        };
+
+        if let Some((_, subscribers)) = log_filters.get_mut(&subscription) {
+            if subscribers.remove(&user_id) {
                 metrics::gauge!("ws_user_subs_total").decrement(1);
             }
+            if subscribers.is_empty() {
+                log_filters.remove(&subscription);
+            }
         }
+
+        true
     }
 
     // Unsubscribe a user from all of their subscriptions
     pub fn unsubscribe_user_from_all(&self, user_id: u32) {
+        self.log_filters
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|_, (_, subscribers)| {
+                if subscribers.remove(&user_id) {
+                    metrics::gauge!("ws_user_subs_total").decrement(1);
+                }
+                !subscribers.is_empty()
+            });
+
         let mut subscriptions = self
             .subscriptions
             .write()
@@ -240,22 +563,69 @@ impl SubscriptionData {
         }
     }
 
-    // Return the node_id for a given subscription_id
-    pub fn get_node_from_id(&self, subscription_id: &str) -> Option<usize> {
+    // Return how many subscriptions a user has
+    pub fn user_subscription_count(&self, user_id: u32) -> usize {
+        let subscriptions = self.subscriptions.read().unwrap_or_else(|e| e.into_inner());
+        let log_filters = self.log_filters.read().unwrap_or_else(|e| e.into_inner());
+
+        subscriptions
+            .values()
+            .chain(log_filters.values().map(|(_, subscribers)| subscribers))
+            .filter(|subscribers| subscribers.contains(&user_id))
+            .count()
+    }
+
+    // Return the upstream subscription users know by `subscription_id`
+    fn get_node_sub_info(&self, subscription_id: &str) -> Option<NodeSubInfo> {
         let incoming_subscriptions = self
             .incoming_subscriptions
             .read()
             .unwrap_or_else(|e| e.into_inner());
 
+        // Logs we filter ourselves come from the subscription to every log
+        let filters_logs = self
+            .log_filters
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .any(|subscription| client_id(subscription) == subscription_id);
+        if filters_logs {
+            return incoming_subscriptions.get(&all_logs_key()).cloned();
+        }
+
         incoming_subscriptions
             .iter()
-            .find_map(|(_, node_sub_info)| {
-                if node_sub_info.subscription_id == subscription_id {
-                    Some(node_sub_info.node_id)
-                } else {
-                    None
-                }
-            })
+            .find(|(subscription, _)| client_id(subscription) == subscription_id)
+            .map(|(_, node_sub_info)| node_sub_info.clone())
+    }
+
+    // Return the filter of an upstream subscription
+    fn get_subscription_by_node_sub_info(&self, node_sub_info: &NodeSubInfo) -> Option<String> {
+        let incoming_subscriptions = self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+
+        incoming_subscriptions
+            .iter()
+            .find(|(_, info)| *info == node_sub_info)
+            .map(|(subscription, _)| subscription.clone())
+    }
+
+    // Return the node_id for the subscription users know by `subscription_id`
+    pub fn get_node_from_id(&self, subscription_id: &str) -> Option<usize> {
+        self.get_node_sub_info(subscription_id)
+            .map(|node_sub_info| node_sub_info.node_id)
+    }
+
+    // Return every upstream subscription we have
+    pub fn upstream_subscriptions(&self) -> Vec<NodeSubInfo> {
+        self.incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .cloned()
+            .collect()
     }
 
     // Return all sub ids for a given node_id
@@ -288,13 +658,7 @@ impl SubscriptionData {
             .iter()
             .filter_map(|(subscription, node_sub_info)| {
                 if node_sub_info.node_id == node_id {
-                    // Parse the subscription string as a JSON array
-                    serde_json::from_str::<Vec<String>>(subscription)
-                        .ok()
-                        .and_then(|v| {
-                            // Serialize each Vec<String> back into a JSON string format
-                            serde_json::to_string(&v).ok()
-                        })
+                    Some(subscription.to_owned())
                 } else {
                     None
                 }
@@ -335,81 +699,361 @@ impl SubscriptionData {
         users
     }
 
-    // Moves all subscription from one node to another
+    // Moves a subscription to `subscription_id` on node `target`, keeping its users
+    //
+    // Users keep the id they know the subscription by, so they don't notice.
     pub fn move_subscriptions(
         &self,
         target: usize,
         request: String,
         subscription_id: String,
     ) -> Result<(), WsError> {
+        let old = {
+            let incoming_subscriptions = self
+                .incoming_subscriptions
+                .read()
+                .unwrap_or_else(|e| e.into_inner());
+            match incoming_subscriptions.get(&request) {
+                Some(rax) => rax.clone(),
+                None => return Err(WsError::MissingSubscription()),
+            }
+        };
+
         // Get all the users that are subscribed to our subscription
-        let users = self.get_users_for_subscription(&subscription_id);
-        if users.is_empty() {
+        let users = self.get_users_for_subscription(&old.subscription_id);
+        // Users of logs we filter ourselves aren't subscribed to it directly
+        let filters_logs = request == all_logs_key()
+            && !self
+                .log_filters
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_empty();
+        if users.is_empty() && !filters_logs {
             return Err(WsError::EmptyList("User list empty!".to_string()));
         }
-        // Unsubscribe everyone from the subscription
-        for user_id in users.iter() {
-            self.unsubscribe_user(*user_id, request.clone());
+
+        // Move the users over to the new subscription
+        let new = NodeSubInfo {
+            node_id: target,
+            subscription_id: subscription_id.clone(),
+        };
+        {
+            let mut subscriptions = self
+                .subscriptions
+                .write()
+                .unwrap_or_else(|e| e.into_inner());
+            let subscribers = subscriptions.remove(&old).unwrap_or_default();
+            subscriptions.entry(new).or_default().extend(subscribers);
         }
 
-        // Unregister/register
-        self.unregister_subscription(request.clone());
         self.raw_register(&request, subscription_id, target);
 
-        // resubscribe all the users now
-        for user_id in users.iter() {
-            self.raw_subscribe(*user_id, &request)?;
+        Ok(())
+    }
+
+    // Returns false if a notification like `content` was already sent for `subscription`
+    //
+    // This happens when a subscription gets moved to another node, which
+    // sends what the old one already did.
+    fn first_delivery(&self, subscription: &str, content: &Value) -> bool {
+        let fingerprint = notification_fingerprint(content);
+        let mut delivered = self.delivered.write().unwrap_or_else(|e| e.into_inner());
+        let delivered = delivered.entry(subscription.to_string()).or_default();
+        if delivered.contains(&fingerprint) {
+            return false;
         }
 
-        Ok(())
+        if delivered.len() == DELIVERED_CAPACITY {
+            delivered.pop_front();
+        }
+        delivered.push_back(fingerprint);
+        true
+    }
+
+    // Return how many notifications of the subscription under `key` we keep for replay
+    fn replay_capacity(&self, key: &str) -> usize {
+        let params = serde_json::from_str::<Value>(key).unwrap_or_default();
+        match params[0].as_str() {
+            Some("newHeads") => self.replay_settings.heads,
+            Some("logs") => self.replay_settings.logs,
+            _ => 0,
+        }
+    }
+
+    // Returns true if the subscription under `key` is kept for replay
+    fn keep_for_replay(&self, key: &str, has_users: bool) -> bool {
+        let capacity = self.replay_capacity(key);
+        if capacity == 0 {
+            return false;
+        }
+
+        let window = Duration::from_millis(self.replay_settings.window_ms);
+        self.replay
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(key.to_string())
+            .or_insert_with(|| ReplayBuffer::new(capacity, window))
+            .keep(has_users)
+    }
+
+    // Keep a notification of the subscription under `key`, setting its resume token
+    fn record_for_replay(&self, key: &str, content: &mut Value) {
+        if let Some(buffer) = self
+            .replay
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(key)
+        {
+            buffer.push(content);
+        }
+    }
+
+    // Return the key of the subscription users know by `subscription_id`, if
+    // `user_id` is subscribed to it and we replay it
+    fn resumable(&self, user_id: u32, subscription_id: &str) -> Result<String, ReplayError> {
+        let incoming_subscriptions = self
+            .incoming_subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner());
+        let (key, node_sub_info) = incoming_subscriptions
+            .iter()
+            .find(|(key, _)| client_id(key) == subscription_id)
+            .ok_or(ReplayError::NotResumable)?;
+
+        let subscribed = self
+            .subscriptions
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(node_sub_info)
+            .is_some_and(|subscribers| subscribers.contains(&user_id));
+        let replayed = self
+            .replay
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(key);
+        if !subscribed || !replayed {
+            return Err(ReplayError::NotResumable);
+        }
+
+        Ok(key.clone())
+    }
+
+    // Return the resume token of the latest notification users know by `subscription_id`
+    pub fn resume_token(
+        &self,
+        user_id: u32,
+        subscription_id: &str,
+    ) -> Result<Option<String>, ReplayError> {
+        let key = self.resumable(user_id, subscription_id)?;
+        Ok(self
+            .replay
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&key)
+            .and_then(ReplayBuffer::latest_token))
+    }
+
+    // Send `user_id` every notification of `subscription_id` since the one `token` came with
+    //
+    // Returns how many were sent.
+    pub fn resume(
+        &self,
+        user_id: u32,
+        subscription_id: &str,
+        token: &str,
+    ) -> Result<usize, ReplayError> {
+        let key = self.resumable(user_id, subscription_id)?;
+        let missed = self
+            .replay
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .get_mut(&key)
+            .ok_or(ReplayError::NotResumable)?
+            .since(token)?;
+
+        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+        let Some(user) = users.get(&user_id) else {
+            return Ok(0);
+        };
+        let mut sent = 0;
+        for notification in missed {
+            if user
+                .send(RequestResult::Subscription(notification))
+                .is_err()
+            {
+                break;
+            }
+            sent += 1;
+        }
+        metrics::counter!("ws_replayed_notifications_total").increment(sent as u64);
+
+        Ok(sent)
     }
 
+    // Send `message` from an upstream subscription to its users
+    //
+    // Notifications that were already sent for the subscription, by the node
+    // it was moved from, are dropped.
+    //
+    // Returns true if nobody is subscribed anymore, and the upstream
+    // subscription should be unsubscribed from.
     pub async fn dispatch_to_subscribers(
         &self,
         subscription_id: &str,
         node_id: usize,
         message: &RequestResult,
     ) -> Result<bool, WsError> {
-        if let RequestResult::Call(_) = message {
+        let RequestResult::Subscription(content) = message else {
             return Err(WsError::InvalidData(
                 "Trying to send a call as a subscription!".to_string(),
             ));
-        }
+        };
 
         let node_sub_info = NodeSubInfo {
             node_id,
             subscription_id: subscription_id.to_string(),
         };
+        let subscription = self.get_subscription_by_node_sub_info(&node_sub_info);
 
-        let users = self.users.read().unwrap_or_else(|e| e.into_inner());
-        if let Some(subscribers) = self.subscriptions.read().unwrap().get(&node_sub_info) {
-            if subscribers.is_empty() {
-                self.unregister_subscription(subscription_id.to_string());
-                tracing::info!(
-                    subscription_id,
-                    "No more users to send subscription to: Unsubscribing from ID",
-                );
-                return Ok(true);
+        let all_logs = all_logs_key();
+        if self.filter_logs && subscription.as_ref() == Some(&all_logs) {
+            return Ok(self.dispatch_logs(all_logs, node_sub_info, content));
+        }
+
+        // Users know the subscription by another id
+        let mut content = content.clone();
+        if let Some(subscription) = &subscription {
+            if content["params"]["subscription"].is_string() {
+                content["params"]["subscription"] = client_id(subscription).into();
             }
+        }
+
+        let mut disconnected = Vec::new();
+        let is_empty = {
+            let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+            let subscriptions = self.subscriptions.read().unwrap_or_else(|e| e.into_inner());
+            let Some(subscribers) = subscriptions.get(&node_sub_info) else {
+                return Ok(false);
+            };
+            let has_users = !subscribers.is_empty();
+            // Kept for users to resume, even once they're all gone
+            let replaying = subscription
+                .as_deref()
+                .is_some_and(|subscription| self.keep_for_replay(subscription, has_users));
+            if let Some(subscription) = subscription.as_deref().filter(|_| has_users || replaying) {
+                if !self.first_delivery(subscription, &content) {
+                    tracing::debug!(subscription, "Dropping duplicate notification");
+                    metrics::counter!("ws_duplicate_notifications_total").increment(1);
+                    return Ok(false);
+                }
+                if replaying {
+                    self.record_for_replay(subscription, &mut content);
+                }
+            }
+            let message = RequestResult::Subscription(content);
+
             for &user_id in subscribers {
                 if let Some(user) = users.get(&user_id) {
                     tracing::debug!("Sending user_id {:?} subscription: {:?}", user_id, message);
-                    match user.send(message.clone()) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            tracing::warn!(
-                                "user_id {} unsubscribed without closing channel! Removing.",
-                                user_id
-                            );
-                            let _ = &self.unsubscribe_user(user_id, subscription_id.to_string());
-                        }
-                    };
+                    if user.send(message.clone()).is_err() {
+                        tracing::warn!(
+                            "user_id {} unsubscribed without closing channel! Removing.",
+                            user_id
+                        );
+                        disconnected.push(user_id);
+                    }
+                }
+            }
+
+            !has_users && !replaying
+        };
+
+        if is_empty && node_id != AGGREGATED_NODE_ID {
+            tracing::info!(
+                subscription_id,
+                "No more users to send subscription to: Unsubscribing from ID",
+            );
+            if let Some(subscription) = subscription {
+                self.unregister_subscription(subscription);
+            }
+            self.subscriptions
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&node_sub_info);
+            return Ok(true);
+        }
+
+        if !disconnected.is_empty() {
+            let mut subscriptions = self
+                .subscriptions
+                .write()
+                .unwrap_or_else(|e| e.into_inner());
+            if let Some(subscribers) = subscriptions.get_mut(&node_sub_info) {
+                for user_id in disconnected {
+                    if subscribers.remove(&user_id) {
+                        metrics::gauge!("ws_user_subs_total").decrement(1);
+                    }
                 }
             }
         }
 
         Ok(false)
     }
+
+    // Send a log from the subscription to every log to the users whose filter it passes
+    //
+    // Returns true if nobody is subscribed to logs anymore.
+    fn dispatch_logs(&self, all_logs: String, node_sub_info: NodeSubInfo, content: &Value) -> bool {
+        let mut disconnected = Vec::new();
+        {
+            let users = self.users.read().unwrap_or_else(|e| e.into_inner());
+            let log_filters = self.log_filters.read().unwrap_or_else(|e| e.into_inner());
+            if log_filters.is_empty() {
+                drop(log_filters);
+                tracing::info!("No more users to send logs to: Unsubscribing from every log");
+                self.unregister_subscription(all_logs);
+                self.subscriptions
+                    .write()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .remove(&node_sub_info);
+                return true;
+            }
+
+            if !self.first_delivery(&all_logs, content) {
+                metrics::counter!("ws_duplicate_notifications_total").increment(1);
+                return false;
+            }
+
+            let log = &content["params"]["result"];
+            for (subscription, (filter, subscribers)) in log_filters.iter() {
+                if !filter.matches(log) {
+                    continue;
+                }
+
+                let mut message = content.clone();
+                message["params"]["subscription"] = client_id(subscription).into();
+                let message = RequestResult::Subscription(message);
+                for &user_id in subscribers {
+                    let sent = users
+                        .get(&user_id)
+                        .is_some_and(|user| user.send(message.clone()).is_ok());
+                    if !sent {
+                        disconnected.push(user_id);
+                    }
+                }
+            }
+        }
+
+        for user_id in disconnected {
+            tracing::warn!(
+                "user_id {} unsubscribed without closing channel! Removing.",
+                user_id
+            );
+            self.unsubscribe_user_from_all(user_id);
+        }
+
+        false
+    }
 }
 
 #[cfg(test)]
@@ -464,10 +1108,12 @@ mod tests {
             subscription_id.clone(),
             node_id,
         );
+        assert_eq!(subscription_data.get_node_from_id(&subscription_id), None);
 
-        // Verify that get_node_from_id returns the correct node_id
+        // Verify that get_node_from_id returns the correct node_id, for the id users know
+        let client_subscription_id = client_id(r#"["newHeads"]"#);
         assert_eq!(
-            subscription_data.get_node_from_id(&subscription_id),
+            subscription_data.get_node_from_id(&client_subscription_id),
             Some(node_id),
             "get_node_from_id should return the correct node_id"
         );
@@ -492,9 +1138,10 @@ mod tests {
             subscription_id.clone(),
             node_id,
         );
-        subscription_data
+        let client_subscription_id = subscription_data
             .subscribe_user(user_id, subscription_request.clone())
             .unwrap();
+        assert_ne!(client_subscription_id, subscription_id);
         assert!(subscription_data
             .subscriptions
             .read()
@@ -503,8 +1150,14 @@ mod tests {
             .any(|(k, v)| {
                 k.node_id == node_id && k.subscription_id == subscription_id && v.contains(&user_id)
             }));
+        assert_eq!(subscription_data.user_subscription_count(user_id), 1);
 
+        // Upstream ids aren't known to users
         subscription_data.unsubscribe_user(user_id, subscription_id.clone());
+        assert_eq!(subscription_data.user_subscription_count(user_id), 1);
+
+        subscription_data.unsubscribe_user(user_id, client_subscription_id);
+        assert_eq!(subscription_data.user_subscription_count(user_id), 0);
         assert!(!subscription_data
             .subscriptions
             .read()
@@ -578,6 +1231,232 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_dispatch_drops_duplicates() {
+        let (subscription_data, user_id, mut rx) = setup_user_and_subscription_data();
+        let subscription_request =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        subscription_data.register_subscription(subscription_request.clone(), "0x1".to_string(), 0);
+        subscription_data
+            .subscribe_user(user_id, subscription_request)
+            .unwrap();
+
+        let head = |subscription: &str, hash: &str| {
+            RequestResult::Subscription(
+                json!({"method": EthRpcMethod::Subscription, "params": {"subscription": subscription, "result": {"hash": hash}}}),
+            )
+        };
+        subscription_data
+            .dispatch_to_subscribers("0x1", 0, &head("0x1", "0xAA"))
+            .await
+            .unwrap();
+
+        // The node we move to sends the head we already got
+        let subscription = subscription_data.get_subscription_by_node(0).remove(0);
+        subscription_data
+            .move_subscriptions(1, subscription, "0x2".to_string())
+            .unwrap();
+        for hash in ["0xaa", "0xBB"] {
+            subscription_data
+                .dispatch_to_subscribers("0x2", 1, &head("0x2", hash))
+                .await
+                .unwrap();
+        }
+
+        let mut received = Vec::new();
+        while let Ok(RequestResult::Subscription(msg)) = rx.try_recv() {
+            received.push(msg["params"]["result"]["hash"].clone());
+        }
+        assert_eq!(received, vec!["0xAA", "0xBB"]);
+
+        // Logs are told apart by where they are, and whether they got reorged out
+        let log = |removed: bool| json!({"params": {"result": {"blockHash": "0xAB", "logIndex": "0x1", "transactionHash": "0xCD", "removed": removed}}});
+        assert_eq!(
+            notification_fingerprint(&log(false)),
+            notification_fingerprint(&log(false))
+        );
+        assert_ne!(
+            notification_fingerprint(&log(false)),
+            notification_fingerprint(&log(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_local_log_filters() {
+        let subscription_data = SubscriptionData::new().with_local_log_filters(true);
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let (tx2, mut rx2) = mpsc::unbounded_channel();
+        subscription_data.add_user(1, tx1);
+        subscription_data.add_user(2, tx2);
+
+        let narrow =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["logs", {"address": "0xAB"}]});
+        let broad =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["logs", {"topics": ["0x1"]}]});
+
+        // Both are served from one subscription to every log
+        let upstream = subscription_data.upstream_subscription(narrow.clone());
+        assert_eq!(upstream["params"], all_logs_params());
+        assert_eq!(
+            subscription_data.upstream_subscription(broad.clone()),
+            upstream
+        );
+        assert!(subscription_data.subscribe_user(1, narrow.clone()).is_err());
+        subscription_data.register_subscription(upstream, "0xall".to_string(), 0);
+        let narrow_id = subscription_data.subscribe_user(1, narrow).unwrap();
+        let broad_id = subscription_data.subscribe_user(2, broad).unwrap();
+        assert_ne!(narrow_id, broad_id);
+        assert_eq!(subscription_data.get_node_from_id(&narrow_id), Some(0));
+        assert_eq!(subscription_data.user_subscription_count(1), 1);
+
+        let log = |address: &str, topic: &str, index: u64| {
+            RequestResult::Subscription(
+                json!({"method": EthRpcMethod::Subscription, "params": {"subscription": "0xall", "result": {"address": address, "topics": [topic], "blockHash": "0xbb", "logIndex": format!("{:#x}", index)}}}),
+            )
+        };
+        for (index, (address, topic)) in [("0xab", "0x1"), ("0xab", "0x2"), ("0xcd", "0x1")]
+            .into_iter()
+            .enumerate()
+        {
+            assert!(!subscription_data
+                .dispatch_to_subscribers("0xall", 0, &log(address, topic, index as u64))
+                .await
+                .unwrap());
+        }
+
+        let received = |rx: &mut mpsc::UnboundedReceiver<RequestResult>, id: &str| {
+            let mut topics = Vec::new();
+            while let Ok(RequestResult::Subscription(msg)) = rx.try_recv() {
+                assert_eq!(msg["params"]["subscription"], id);
+                topics.push(msg["params"]["result"]["topics"][0].clone());
+            }
+            topics
+        };
+        assert_eq!(received(&mut rx1, &narrow_id), vec!["0x1", "0x2"]);
+        assert_eq!(received(&mut rx2, &broad_id), vec!["0x1", "0x1"]);
+
+        // The subscription to every log goes once nobody needs it
+        subscription_data.unsubscribe_user(1, narrow_id);
+        subscription_data.unsubscribe_user_from_all(2);
+        assert_eq!(subscription_data.user_subscription_count(2), 0);
+        assert!(subscription_data
+            .dispatch_to_subscribers("0xall", 0, &log("0xab", "0x3", 3))
+            .await
+            .unwrap());
+        assert!(subscription_data.get_subscription_by_node(0).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_subscription_limits() {
+        let subscription_data =
+            SubscriptionData::new().with_subscription_limits(WsSubscriptionLimits {
+                max_subscriptions: 2,
+                max_filter_addresses: 2,
+                max_filter_topics: 3,
+            });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        subscription_data.add_user(1, tx);
+
+        let logs =
+            |filter: Value| json!({"method": EthRpcMethod::Subscribe, "params": ["logs", filter]});
+        assert!(subscription_data
+            .check_subscription_limits(1, &logs(json!({"address": ["0x1", "0x2"]})))
+            .is_ok());
+        assert!(matches!(
+            subscription_data
+                .check_subscription_limits(1, &logs(json!({"address": ["0x1", "0x2", "0x3"]}))),
+            Err(WsError::SubscriptionLimit(_))
+        ));
+        assert!(matches!(
+            subscription_data.check_subscription_limits(
+                1,
+                &logs(json!({"topics": [["0x1", "0x2"], null, ["0x3", "0x4"]]}))
+            ),
+            Err(WsError::SubscriptionLimit(_))
+        ));
+
+        let heads = json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        let pending =
+            json!({"method": EthRpcMethod::Subscribe, "params": ["newPendingTransactions"]});
+        let syncing = json!({"method": EthRpcMethod::Subscribe, "params": ["syncing"]});
+        for (i, subscription) in [&heads, &pending].into_iter().enumerate() {
+            assert!(subscription_data
+                .check_subscription_limits(1, subscription)
+                .is_ok());
+            subscription_data.register_subscription(subscription.clone(), format!("0x{}", i), 0);
+            subscription_data
+                .subscribe_user(1, subscription.clone())
+                .unwrap();
+        }
+
+        // Full, but subscribing again to the same thing changes nothing
+        assert!(matches!(
+            subscription_data.check_subscription_limits(1, &syncing),
+            Err(WsError::SubscriptionLimit(_))
+        ));
+        assert!(subscription_data
+            .check_subscription_limits(1, &heads)
+            .is_ok());
+        // Other users have their own limit
+        assert!(subscription_data
+            .check_subscription_limits(2, &syncing)
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let sub_data = SubscriptionData::new().with_replay(WsReplaySettings {
+            heads: 4,
+            logs: 0,
+            window_ms: 60_000,
+        });
+        let subscription = json!({"method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        sub_data.register_subscription(subscription.clone(), "0xa".to_string(), 0);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        sub_data.add_user(1, tx);
+        let id = sub_data.subscribe_user(1, subscription.clone()).unwrap();
+
+        let head = |hash: &str| {
+            RequestResult::Subscription(
+                json!({"method": EthRpcMethod::Subscription, "params": {"subscription": "0xa", "result": {"hash": hash}}}),
+            )
+        };
+        sub_data
+            .dispatch_to_subscribers("0xa", 0, &head("0x01"))
+            .await
+            .unwrap();
+        let Ok(RequestResult::Subscription(first)) = rx.try_recv() else {
+            panic!("Expected a head");
+        };
+        let token = first["params"]["resumeToken"].as_str().unwrap().to_string();
+        assert_eq!(sub_data.resume_token(1, &id), Ok(Some(token.clone())));
+
+        // The client drops, and misses heads, but the subscription stays
+        sub_data.remove_user(1);
+        for hash in ["0x02", "0x03"] {
+            assert!(!sub_data
+                .dispatch_to_subscribers("0xa", 0, &head(hash))
+                .await
+                .unwrap());
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        sub_data.add_user(2, tx);
+        assert_eq!(
+            sub_data.resume(2, &id, &token),
+            Err(ReplayError::NotResumable)
+        );
+        assert_eq!(sub_data.subscribe_user(2, subscription).unwrap(), id);
+        assert_eq!(sub_data.resume(2, &id, &token), Ok(2));
+
+        let mut replayed = Vec::new();
+        while let Ok(RequestResult::Subscription(msg)) = rx.try_recv() {
+            assert_eq!(msg["params"]["subscription"], id.as_str());
+            replayed.push(msg["params"]["result"]["hash"].clone());
+        }
+        assert_eq!(replayed, vec!["0x02", "0x03"]);
+    }
+
     #[tokio::test]
     async fn test_remove_nonexistent_user() {
         let (subscription_data, _, _) = setup_user_and_subscription_data();
@@ -689,6 +1568,12 @@ mod tests {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            delivered: Arc::new(RwLock::new(HashMap::new())),
+            log_filters: Arc::new(RwLock::new(HashMap::new())),
+            filter_logs: false,
+            limits: WsSubscriptionLimits::default(),
+            replay: Arc::new(RwLock::new(HashMap::new())),
+            replay_settings: WsReplaySettings::default(),
         };
 
         // Mock subscription data
@@ -898,4 +1783,66 @@ mod tests {
             .await;
         assert!(dispatch_result.is_ok()); // Should succeed as it should handle subscriptions with no users gracefully
     }
+
+    #[tokio::test]
+    async fn test_same_filter_shares_subscription() {
+        let subscription_data = SubscriptionData::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        subscription_data.add_user(1, tx.clone());
+        subscription_data.add_user(2, tx);
+
+        let filter = json!({"method": EthRpcMethod::Subscribe, "params": ["logs", {"topics": ["0xAB"], "address": "0xCD"}]});
+        let same_filter = json!({"method": EthRpcMethod::Subscribe, "params": ["logs", {"address": "0xcd", "topics": ["0xab"]}]});
+
+        assert!(subscription_data
+            .register_subscription(filter.clone(), "0x1".to_string(), 0)
+            .is_none());
+        // Subscribing to a filter we already have upstream hands back the duplicate
+        assert_eq!(
+            subscription_data.register_subscription(same_filter.clone(), "0x2".to_string(), 1),
+            Some(NodeSubInfo {
+                node_id: 1,
+                subscription_id: "0x2".to_string(),
+            })
+        );
+
+        let id = subscription_data.subscribe_user(1, filter).unwrap();
+        assert_eq!(
+            subscription_data.subscribe_user(2, same_filter).unwrap(),
+            id
+        );
+
+        // Both users get notifications, under the id they know
+        let notification = json!({"method": EthRpcMethod::Subscription, "params": {"subscription": "0x1", "result": {}}});
+        assert!(!subscription_data
+            .dispatch_to_subscribers("0x1", 0, &RequestResult::Subscription(notification))
+            .await
+            .unwrap());
+        for _ in 0..2 {
+            let Some(RequestResult::Subscription(msg)) = rx.recv().await else {
+                panic!("Expected to receive a subscription message");
+            };
+            assert_eq!(msg["params"]["subscription"], id.as_str());
+        }
+
+        // Ids stay the same when the subscription moves
+        subscription_data
+            .move_subscriptions(
+                2,
+                subscription_data.get_subscription_by_node(0).remove(0),
+                "0x3".to_string(),
+            )
+            .unwrap();
+        assert_eq!(subscription_data.get_node_from_id(&id), Some(2));
+
+        // Once everyone is gone, the subscription is dropped
+        subscription_data.unsubscribe_user(1, id.clone());
+        subscription_data.unsubscribe_user(2, id.clone());
+        let notification = json!({"method": EthRpcMethod::Subscription, "params": {"subscription": "0x3", "result": {}}});
+        assert!(subscription_data
+            .dispatch_to_subscribers("0x3", 2, &RequestResult::Subscription(notification))
+            .await
+            .unwrap());
+        assert_eq!(subscription_data.get_node_from_id(&id), None);
+    }
 }